@@ -104,6 +104,19 @@ impl NodeAddr {
     pub fn relay_url(&self) -> Option<&RelayUrl> {
         self.info.relay_url.as_ref()
     }
+
+    /// Merges the addressing information of `other` into `self`.
+    ///
+    /// See [`AddrInfo::merge`] for how the information is combined.  Fails if `other` is
+    /// the address of a different node.
+    pub fn merge(&mut self, other: NodeAddr) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.node_id == other.node_id,
+            "can not merge the addresses of different nodes"
+        );
+        self.info.merge(other.info);
+        Ok(())
+    }
 }
 
 impl From<(PublicKey, Option<RelayUrl>, &[SocketAddr])> for NodeAddr {
@@ -146,6 +159,18 @@ impl AddrInfo {
         self.relay_url.is_none() && self.direct_addresses.is_empty()
     }
 
+    /// Merges the addressing information of `other` into `self`.
+    ///
+    /// The direct addresses of both are kept.  A node only has a single home relay, so the
+    /// relay URL of `other` replaces ours if it has one: `other` should be the more recent
+    /// information, e.g. a discovery result merged into the addresses from a ticket.
+    pub fn merge(&mut self, other: AddrInfo) {
+        if other.relay_url.is_some() {
+            self.relay_url = other.relay_url;
+        }
+        self.direct_addresses.extend(other.direct_addresses);
+    }
+
     /// Applies the options to `self`.
     ///
     /// This is used to more tightly control the information stored in ab [`AddrInfo`]
@@ -198,3 +223,32 @@ pub enum AddrInfoOptions {
     /// Includes the Node ID and the direct addresses.
     Addresses,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_node_addr_merge() {
+        let node_id = SecretKey::generate().public();
+        let relay_a: RelayUrl = "https://relay-a.example.com".parse().unwrap();
+        let relay_b: RelayUrl = "https://relay-b.example.com".parse().unwrap();
+        let addr_a: SocketAddr = "192.168.1.5:1000".parse().unwrap();
+        let addr_b: SocketAddr = "10.0.0.7:1000".parse().unwrap();
+
+        let mut addr = NodeAddr::from_parts(node_id, Some(relay_a.clone()), [addr_a]);
+        addr.merge(NodeAddr::from_parts(node_id, None, [addr_b]))
+            .unwrap();
+        assert_eq!(addr.relay_url(), Some(&relay_a));
+        assert_eq!(addr.info.direct_addresses, BTreeSet::from([addr_a, addr_b]));
+
+        addr.merge(NodeAddr::new(node_id).with_relay_url(relay_b.clone()))
+            .unwrap();
+        assert_eq!(addr.relay_url(), Some(&relay_b));
+        assert_eq!(addr.info.direct_addresses.len(), 2);
+
+        let other = SecretKey::generate().public();
+        assert!(addr.merge(NodeAddr::new(other)).is_err());
+    }
+}
@@ -78,6 +78,7 @@ impl RelayMap {
                 stun_only: false,
                 stun_port,
                 quic: Some(QuicConfig::default()),
+                auth_token: None,
             }
             .into(),
         );
@@ -139,23 +140,59 @@ pub struct RelayNode {
     /// with this relay server.
     #[serde(default = "quic_config")]
     pub quic: Option<QuicConfig>,
+    /// The token to present to the relay server when connecting.
+    ///
+    /// Private relay servers may require clients to authenticate with a token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<RelayAuthToken>,
 }
 
 fn quic_config() -> Option<QuicConfig> {
     Some(QuicConfig::default())
 }
 
+/// A bearer token a client presents to a relay server in the connection handshake.
+///
+/// The token is not printed by its [`Debug`](fmt::Debug) implementation.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RelayAuthToken(String);
+
+impl RelayAuthToken {
+    /// Creates a token.
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    /// Returns the token.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for RelayAuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RelayAuthToken(..)")
+    }
+}
+
 /// Configuration for speaking to the QUIC endpoint on the relay
 /// server to do QUIC address discovery.
 #[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq, PartialOrd, Ord)]
 pub struct QuicConfig {
     pub port: u16,
+    /// Whether to relay over QUIC instead of over TLS/TCP.
+    ///
+    /// If the QUIC connection can not be established the relay is used over TLS/TCP.
+    #[serde(default)]
+    pub relay: bool,
 }
 
 impl Default for QuicConfig {
     fn default() -> Self {
         Self {
             port: DEFAULT_RELAY_QUIC_PORT,
+            relay: false,
         }
     }
 }
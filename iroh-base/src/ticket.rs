@@ -8,7 +8,18 @@ mod blob;
 mod node;
 #[cfg(feature = "key")]
 #[cfg_attr(iroh_docsrs, doc(cfg(feature = "key")))]
-pub use self::{blob::BlobTicket, node::NodeTicket};
+mod nodes;
+#[cfg(feature = "key")]
+#[cfg_attr(iroh_docsrs, doc(cfg(feature = "key")))]
+mod short;
+#[cfg(feature = "key")]
+#[cfg_attr(iroh_docsrs, doc(cfg(feature = "key")))]
+pub use self::{
+    blob::BlobTicket,
+    node::{NodeTicket, MAX_APP_DATA_SIZE},
+    nodes::NodesTicket,
+    short::ShortTicket,
+};
 
 /// A ticket is a serializable object combining information required for an operation.
 ///
@@ -25,6 +36,9 @@ pub use self::{blob::BlobTicket, node::NodeTicket};
 /// The serialization format for converting the ticket from and to bytes is left
 /// to the implementer. We recommend using [postcard] for serialization.
 ///
+/// To embed a ticket in another serialized type, use [`as_bytes`] for binary protocols or
+/// [`as_string`] to always use the string representation.
+///
 /// [postcard]: https://docs.rs/postcard/latest/postcard/
 pub trait Ticket: Sized {
     /// String prefix describing the kind of iroh ticket.
@@ -73,3 +87,148 @@ pub enum Error {
     #[error("verification failed: {_0}")]
     Verify(&'static str),
 }
+
+/// Serializes a ticket as its bytes, for use with `#[serde(with = "...")]`.
+///
+/// This uses the bytes of [`Ticket::to_bytes`] instead of the base32 string, so binary
+/// formats like postcard or CBOR embed the ticket without encoding it twice:
+///
+/// ```
+/// # #[cfg(feature = "key")]
+/// # {
+/// use iroh_base::ticket::NodeTicket;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Invite {
+///     #[serde(with = "iroh_base::ticket::as_bytes")]
+///     ticket: NodeTicket,
+/// }
+/// # }
+/// ```
+pub mod as_bytes {
+    use std::fmt;
+
+    use serde::{de, Deserializer, Serializer};
+
+    use super::Ticket;
+
+    /// Serializes the ticket as a byte string.
+    pub fn serialize<T: Ticket, S: Serializer>(
+        ticket: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&ticket.to_bytes())
+    }
+
+    /// Deserializes the ticket from a byte string, or a sequence of bytes for formats
+    /// without byte strings.
+    pub fn deserialize<'de, T: Ticket, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+        T::from_bytes(&bytes).map_err(de::Error::custom)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> de::Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("ticket bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+/// Serializes a ticket as its base32 string, for use with `#[serde(with = "...")]`.
+///
+/// The tickets serialize to their string in human readable formats like JSON or TOML
+/// already, this also uses the string in binary formats.
+pub mod as_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use super::Ticket;
+
+    /// Serializes the ticket as a string.
+    pub fn serialize<T: Ticket, S: Serializer>(
+        ticket: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&ticket.serialize())
+    }
+
+    /// Deserializes the ticket from a string.
+    pub fn deserialize<'de, T: Ticket, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        T::deserialize(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(all(test, feature = "key"))]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{key::SecretKey, node_addr::NodeAddr};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Embedded {
+        #[serde(with = "as_bytes")]
+        bytes: NodeTicket,
+        #[serde(with = "as_string")]
+        string: NodeTicket,
+    }
+
+    fn make_embedded() -> Embedded {
+        let addr = NodeAddr::from_parts(
+            SecretKey::generate().public(),
+            None,
+            ["127.0.0.1:1234".parse().unwrap()],
+        );
+        let ticket = NodeTicket::new(addr);
+        Embedded {
+            bytes: ticket.clone(),
+            string: ticket,
+        }
+    }
+
+    #[test]
+    fn test_embedded_postcard() {
+        let embedded = make_embedded();
+        let bytes = postcard::to_stdvec(&embedded).unwrap();
+        let ticket_bytes = embedded.bytes.to_bytes();
+        // The ticket bytes are embedded as is, after their length.
+        assert_eq!(&bytes[1..1 + ticket_bytes.len()], &ticket_bytes[..]);
+        let embedded2: Embedded = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(embedded2, embedded);
+    }
+
+    #[test]
+    fn test_embedded_json() {
+        let embedded = make_embedded();
+        let json = serde_json::to_value(&embedded).unwrap();
+        assert_eq!(json["string"], embedded.string.to_string());
+        assert!(json["bytes"].is_array());
+        let embedded2: Embedded = serde_json::from_value(json).unwrap();
+        assert_eq!(embedded2, embedded);
+    }
+}
@@ -1,15 +1,25 @@
 //! Tickets for nodes.
 
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    key::{SecretKey, Signature},
     node_addr::NodeAddr,
     ticket::{self, Ticket},
 };
 
+/// Domain separation prefix of the message signed in a signed [`NodeTicket`].
+const SIGNATURE_DOMAIN: &[u8] = b"iroh-node-ticket";
+
+/// The maximum size of the [application data](NodeTicket::with_app_data) in a ticket.
+pub const MAX_APP_DATA_SIZE: usize = 256;
+
 /// A token containing information for establishing a connection to a node.
 ///
 /// Contains
@@ -20,6 +30,21 @@ use crate::{
 /// This allows establishing a connection to the node in most circumstances where it is
 /// possible to do so.
 ///
+/// A ticket can optionally be [signed](Self::signed) by the node, in which case it also
+/// contains an expiration time.  The signature is verified when the ticket is parsed, a
+/// ticket with an invalid signature fails to parse.  Expired tickets still parse so they can
+/// be inspected, check [`NodeTicket::is_expired`] before using one.
+///
+/// Since the signature is optional, anyone can remove it from a ticket and change the
+/// addresses, the result parses as an unsigned ticket.  Only parsing with
+/// [`NodeTicket::parse_signed`], or checking [`NodeTicket::require_signed`] after parsing,
+/// ensures that the addresses were published by the node itself.
+///
+/// A ticket can also name the [ALPN](Self::with_alpn) to connect with and carry some
+/// [application data](Self::with_app_data).  This allows a generic tool to open any ticket
+/// by handing the connection to the protocol handler for the ALPN, without agreeing on the
+/// protocol out of band.
+///
 /// This [`NodeTicket`] is a single item which can be easily serialized and deserialized and
 /// implements the [`Ticket`] trait.  The [`Display`] and [`FromStr`] traits can also be
 /// used to round-trip the ticket to string.
@@ -31,26 +56,47 @@ use crate::{
 #[display("{}", Ticket::serialize(self))]
 pub struct NodeTicket {
     node: NodeAddr,
+    alpn: Option<Vec<u8>>,
+    app_data: Option<Vec<u8>>,
+    signature: Option<TicketSignature>,
+}
+
+/// The expiration time and signature of a signed [`NodeTicket`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct TicketSignature {
+    /// Seconds since the unix epoch after which the ticket is no longer valid.
+    expires_at: u64,
+    signature: Signature,
 }
 
 /// Wire format for [`NodeTicket`].
 #[derive(Serialize, Deserialize)]
 enum TicketWireFormat {
-    Variant0(NodeTicket),
+    Variant0(NodeAddr),
+    /// A signed ticket.
+    Variant1 {
+        node: NodeAddr,
+        signature: TicketSignature,
+    },
+    /// A ticket with an ALPN or application data.
+    Variant2 {
+        node: NodeAddr,
+        alpn: Option<Vec<u8>>,
+        app_data: Option<Vec<u8>>,
+        signature: Option<TicketSignature>,
+    },
 }
 
 impl Ticket for NodeTicket {
     const KIND: &'static str = "node";
 
     fn to_bytes(&self) -> Vec<u8> {
-        let data = TicketWireFormat::Variant0(self.clone());
-        postcard::to_stdvec(&data).expect("postcard serialization failed")
+        postcard::to_stdvec(&self.to_wire_format()).expect("postcard serialization failed")
     }
 
     fn from_bytes(bytes: &[u8]) -> std::result::Result<Self, ticket::Error> {
         let res: TicketWireFormat = postcard::from_bytes(bytes).map_err(ticket::Error::Postcard)?;
-        let TicketWireFormat::Variant0(res) = res;
-        Ok(res)
+        Self::from_wire_format(res)
     }
 }
 
@@ -65,19 +111,217 @@ impl FromStr for NodeTicket {
 impl NodeTicket {
     /// Creates a new ticket.
     pub fn new(node: NodeAddr) -> Self {
-        Self { node }
+        Self {
+            node,
+            alpn: None,
+            app_data: None,
+            signature: None,
+        }
+    }
+
+    /// Creates a new ticket signed by the node, which expires at `expires_at`.
+    ///
+    /// This is a shorthand for [`NodeTicket::new`] followed by [`NodeTicket::sign`].
+    pub fn signed(node: NodeAddr, secret_key: &SecretKey, expires_at: SystemTime) -> Result<Self> {
+        Self::new(node).sign(secret_key, expires_at)
+    }
+
+    /// Sets the ALPN of the protocol to use when connecting to the node.
+    ///
+    /// This removes the signature of a signed ticket, sign the ticket after setting the
+    /// ALPN.
+    pub fn with_alpn(mut self, alpn: impl Into<Vec<u8>>) -> Self {
+        self.alpn = Some(alpn.into());
+        self.signature = None;
+        self
+    }
+
+    /// Sets application data to pass to the protocol handler, of at most
+    /// [`MAX_APP_DATA_SIZE`] bytes.
+    ///
+    /// This removes the signature of a signed ticket, sign the ticket after setting the
+    /// application data.
+    pub fn with_app_data(mut self, app_data: impl Into<Vec<u8>>) -> Result<Self> {
+        let app_data = app_data.into();
+        ensure!(
+            app_data.len() <= MAX_APP_DATA_SIZE,
+            "app data is larger than {MAX_APP_DATA_SIZE} bytes"
+        );
+        self.app_data = Some(app_data);
+        self.signature = None;
+        Ok(self)
+    }
+
+    /// Signs the ticket by its node, the signed ticket expires at `expires_at`.
+    ///
+    /// The `secret_key` must be the secret key of the node in the ticket.  The expiration
+    /// time is stored with a precision of seconds.
+    pub fn sign(mut self, secret_key: &SecretKey, expires_at: SystemTime) -> Result<Self> {
+        ensure!(
+            secret_key.public() == self.node.node_id,
+            "ticket must be signed by the node it points to"
+        );
+        let expires_at = expires_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let signature = secret_key.sign(&self.signed_message(expires_at));
+        self.signature = Some(TicketSignature {
+            expires_at,
+            signature,
+        });
+        Ok(self)
     }
 
     /// The [`NodeAddr`] of the provider for this ticket.
     pub fn node_addr(&self) -> &NodeAddr {
         &self.node
     }
+
+    /// The ALPN of the protocol to use when connecting to the node, if set.
+    pub fn alpn(&self) -> Option<&[u8]> {
+        self.alpn.as_deref()
+    }
+
+    /// The application data for the protocol handler, if set.
+    pub fn app_data(&self) -> Option<&[u8]> {
+        self.app_data.as_deref()
+    }
+
+    /// Returns whether the ticket is signed by its node.
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+
+    /// Returns when the ticket expires, `None` for unsigned tickets which never expire.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.signature
+            .as_ref()
+            .map(|sig| SystemTime::UNIX_EPOCH + Duration::from_secs(sig.expires_at))
+    }
+
+    /// Returns whether the ticket expired, unsigned tickets never expire.
+    ///
+    /// Parsing does not check the expiration time, so expired tickets can still be
+    /// inspected.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+
+    /// Parses a ticket which must be signed by its node.
+    ///
+    /// Unlike [`FromStr`], this rejects unsigned tickets, whose addresses could have been
+    /// changed by anyone.  Like [`FromStr`], it does not reject expired tickets, see
+    /// [`NodeTicket::is_expired`].
+    pub fn parse_signed(s: &str) -> std::result::Result<Self, ticket::Error> {
+        let ticket = Self::from_str(s)?;
+        ticket.require_signed()?;
+        Ok(ticket)
+    }
+
+    /// Checks that the ticket is signed by its node.
+    ///
+    /// The signature of a parsed ticket is already verified, but an unsigned ticket parses
+    /// as well.  Use this to only accept tickets whose addresses were published by the node
+    /// itself.
+    pub fn require_signed(&self) -> std::result::Result<(), ticket::Error> {
+        if self.signature.is_none() {
+            return Err(ticket::Error::Verify("ticket is not signed"));
+        }
+        self.verify_signature()
+    }
+
+    /// Verifies the signature and the expiration time of a signed ticket.
+    ///
+    /// Unsigned tickets are always valid, use [`NodeTicket::require_signed`] to reject
+    /// them.  Parsing a ticket only verifies the signature, use this to also check that the
+    /// ticket did not expire.
+    pub fn verify(&self) -> std::result::Result<(), ticket::Error> {
+        self.verify_signature()?;
+        if self.is_expired() {
+            return Err(ticket::Error::Verify("ticket expired"));
+        }
+        Ok(())
+    }
+
+    /// Verifies the signature of a signed ticket, unsigned tickets are always valid.
+    fn verify_signature(&self) -> std::result::Result<(), ticket::Error> {
+        let Some(sig) = &self.signature else {
+            return Ok(());
+        };
+        self.node
+            .node_id
+            .verify(&self.signed_message(sig.expires_at), &sig.signature)
+            .map_err(|_| ticket::Error::Verify("invalid ticket signature"))
+    }
+
+    /// Returns the message signed by the node for a signed ticket.
+    fn signed_message(&self, expires_at: u64) -> Vec<u8> {
+        let mut message = SIGNATURE_DOMAIN.to_vec();
+        postcard::to_io(&self.node, &mut message).expect("postcard serialization failed");
+        message.extend_from_slice(&expires_at.to_be_bytes());
+        // Tickets without these fields keep the message of the older wire formats.
+        if self.alpn.is_some() || self.app_data.is_some() {
+            postcard::to_io(&(&self.alpn, &self.app_data), &mut message)
+                .expect("postcard serialization failed");
+        }
+        message
+    }
+
+    fn to_wire_format(&self) -> TicketWireFormat {
+        if self.alpn.is_some() || self.app_data.is_some() {
+            return TicketWireFormat::Variant2 {
+                node: self.node.clone(),
+                alpn: self.alpn.clone(),
+                app_data: self.app_data.clone(),
+                signature: self.signature.clone(),
+            };
+        }
+        match &self.signature {
+            None => TicketWireFormat::Variant0(self.node.clone()),
+            Some(signature) => TicketWireFormat::Variant1 {
+                node: self.node.clone(),
+                signature: signature.clone(),
+            },
+        }
+    }
+
+    fn from_wire_format(wire: TicketWireFormat) -> std::result::Result<Self, ticket::Error> {
+        let ticket = match wire {
+            TicketWireFormat::Variant0(node) => Self::new(node),
+            TicketWireFormat::Variant1 { node, signature } => Self {
+                signature: Some(signature),
+                ..Self::new(node)
+            },
+            TicketWireFormat::Variant2 {
+                node,
+                alpn,
+                app_data,
+                signature,
+            } => Self {
+                node,
+                alpn,
+                app_data,
+                signature,
+            },
+        };
+        if ticket
+            .app_data
+            .as_ref()
+            .is_some_and(|data| data.len() > MAX_APP_DATA_SIZE)
+        {
+            return Err(ticket::Error::Verify("app data too large"));
+        }
+        ticket.verify_signature()?;
+        Ok(ticket)
+    }
 }
 
 impl From<NodeAddr> for NodeTicket {
     /// Creates a ticket from given addressing info.
     fn from(addr: NodeAddr) -> Self {
-        Self { node: addr }
+        Self::new(addr)
     }
 }
 
@@ -93,8 +337,7 @@ impl Serialize for NodeTicket {
         if serializer.is_human_readable() {
             serializer.serialize_str(&self.to_string())
         } else {
-            let NodeTicket { node } = self;
-            (node).serialize(serializer)
+            self.to_wire_format().serialize(serializer)
         }
     }
 }
@@ -105,8 +348,8 @@ impl<'de> Deserialize<'de> for NodeTicket {
             let s = String::deserialize(deserializer)?;
             Self::from_str(&s).map_err(serde::de::Error::custom)
         } else {
-            let peer = Deserialize::deserialize(deserializer)?;
-            Ok(Self::new(peer))
+            let wire = Deserialize::deserialize(deserializer)?;
+            Self::from_wire_format(wire).map_err(serde::de::Error::custom)
         }
     }
 }
@@ -127,9 +370,7 @@ mod tests {
         let peer = SecretKey::generate().public();
         let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1234));
         let relay_url = None;
-        NodeTicket {
-            node: NodeAddr::from_parts(peer, relay_url, [addr]),
-        }
+        NodeTicket::new(NodeAddr::from_parts(peer, relay_url, [addr]))
     }
 
     #[test]
@@ -154,13 +395,11 @@ mod tests {
             PublicKey::from_str("ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6")
                 .unwrap();
 
-        let ticket = NodeTicket {
-            node: NodeAddr::from_parts(
-                node_id,
-                Some("http://derp.me./".parse().unwrap()),
-                ["127.0.0.1:1024".parse().unwrap()],
-            ),
-        };
+        let ticket = NodeTicket::new(NodeAddr::from_parts(
+            node_id,
+            Some("http://derp.me./".parse().unwrap()),
+            ["127.0.0.1:1024".parse().unwrap()],
+        ));
         let base32 = base32::parse_vec(ticket.to_string().strip_prefix("node").unwrap()).unwrap();
         let expected = parse_hexdump("
             00 # variant
@@ -173,4 +412,83 @@ mod tests {
         ").unwrap();
         assert_eq_hex!(base32, expected);
     }
+
+    #[test]
+    fn test_ticket_signed() {
+        let secret_key = SecretKey::generate();
+        let node = NodeAddr::from_parts(
+            secret_key.public(),
+            None,
+            ["127.0.0.1:1234".parse().unwrap()],
+        );
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        let ticket = NodeTicket::signed(node.clone(), &secret_key, expires_at).unwrap();
+        assert!(ticket.is_signed());
+        assert!(!ticket.is_expired());
+        let ticket2: NodeTicket = ticket.to_string().parse().unwrap();
+        assert_eq!(ticket2, ticket);
+        let bytes = postcard::to_stdvec(&ticket).unwrap();
+        let ticket2: NodeTicket = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(ticket2, ticket);
+
+        // Only the node itself can sign a ticket.
+        assert!(NodeTicket::signed(node.clone(), &SecretKey::generate(), expires_at).is_err());
+
+        // Changing the addresses invalidates the signature.
+        let mut forged = ticket.clone();
+        forged.node = NodeAddr::from_parts(
+            secret_key.public(),
+            None,
+            ["10.0.0.1:1234".parse().unwrap()],
+        );
+        assert!(NodeTicket::from_str(&forged.to_string()).is_err());
+
+        // Removing the signature is only caught by the strict parse.
+        let mut stripped = forged.clone();
+        stripped.signature = None;
+        let stripped = stripped.to_string();
+        assert!(NodeTicket::from_str(&stripped).is_ok());
+        assert!(matches!(
+            NodeTicket::parse_signed(&stripped),
+            Err(ticket::Error::Verify(_))
+        ));
+        assert_eq!(
+            NodeTicket::parse_signed(&ticket.to_string()).unwrap(),
+            ticket
+        );
+
+        // Expired tickets still parse, but fail to verify.
+        let expires_at = SystemTime::now() - Duration::from_secs(1);
+        let ticket = NodeTicket::signed(node, &secret_key, expires_at).unwrap();
+        let ticket2 = NodeTicket::parse_signed(&ticket.to_string()).unwrap();
+        assert!(ticket2.is_expired());
+        assert!(matches!(ticket2.verify(), Err(ticket::Error::Verify(_))));
+    }
+
+    #[test]
+    fn test_ticket_alpn() {
+        let secret_key = SecretKey::generate();
+        let node = NodeAddr::new(secret_key.public());
+        let ticket = NodeTicket::new(node.clone())
+            .with_alpn(b"/my-app/1".to_vec())
+            .with_app_data(b"room 42".to_vec())
+            .unwrap();
+        let ticket2: NodeTicket = ticket.to_string().parse().unwrap();
+        assert_eq!(ticket2, ticket);
+        assert_eq!(ticket2.alpn(), Some(&b"/my-app/1"[..]));
+        assert_eq!(ticket2.app_data(), Some(&b"room 42"[..]));
+
+        // The signature covers the ALPN.
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        let ticket = ticket.sign(&secret_key, expires_at).unwrap();
+        let ticket2: NodeTicket = ticket.to_string().parse().unwrap();
+        assert_eq!(ticket2, ticket);
+        let mut forged = ticket.clone();
+        forged.alpn = Some(b"/other-app/1".to_vec());
+        assert!(NodeTicket::from_str(&forged.to_string()).is_err());
+        assert!(!ticket.with_alpn(b"/other-app/1".to_vec()).is_signed());
+
+        let res = NodeTicket::new(node).with_app_data(vec![0u8; MAX_APP_DATA_SIZE + 1]);
+        assert!(res.is_err());
+    }
 }
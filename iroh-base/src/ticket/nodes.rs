@@ -0,0 +1,206 @@
+//! Tickets for several nodes.
+
+use std::str::FromStr;
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    node_addr::NodeAddr,
+    ticket::{self, Ticket},
+};
+
+/// A token containing information for establishing a connection to any of several nodes.
+///
+/// This is like a [`NodeTicket`], but contains the [`NodeAddr`]s of several nodes which
+/// provide the same service, e.g. all replicas of it.  The client can connect to whichever
+/// node is reachable first, e.g. using `Endpoint::connect_any` in iroh.
+///
+/// A ticket always contains at least one node, and every node at most once.  The order of
+/// the nodes is kept, it can be used to express a preference.
+///
+/// [`NodeTicket`]: super::NodeTicket
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+#[display("{}", Ticket::serialize(self))]
+pub struct NodesTicket {
+    nodes: Vec<NodeAddr>,
+}
+
+/// Wire format for [`NodesTicket`].
+#[derive(Serialize, Deserialize)]
+enum TicketWireFormat {
+    Variant0(NodesTicket),
+}
+
+impl Ticket for NodesTicket {
+    const KIND: &'static str = "nodes";
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let data = TicketWireFormat::Variant0(self.clone());
+        postcard::to_stdvec(&data).expect("postcard serialization failed")
+    }
+
+    fn from_bytes(bytes: &[u8]) -> std::result::Result<Self, ticket::Error> {
+        let res: TicketWireFormat = postcard::from_bytes(bytes).map_err(ticket::Error::Postcard)?;
+        let TicketWireFormat::Variant0(res) = res;
+        Ok(res)
+    }
+}
+
+impl FromStr for NodesTicket {
+    type Err = ticket::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ticket::Ticket::deserialize(s)
+    }
+}
+
+impl NodesTicket {
+    /// Creates a new ticket.
+    ///
+    /// Fails if `nodes` is empty or contains the same node more than once.
+    pub fn new(nodes: impl IntoIterator<Item = NodeAddr>) -> Result<Self> {
+        let nodes: Vec<NodeAddr> = nodes.into_iter().collect();
+        ensure!(!nodes.is_empty(), "ticket contains no nodes");
+        for (i, node) in nodes.iter().enumerate() {
+            ensure!(
+                nodes[..i].iter().all(|other| other.node_id != node.node_id),
+                "ticket contains node {} more than once",
+                node.node_id
+            );
+        }
+        Ok(Self { nodes })
+    }
+
+    /// The [`NodeAddr`]s of the nodes in this ticket.
+    pub fn node_addrs(&self) -> &[NodeAddr] {
+        &self.nodes
+    }
+
+    /// Get the contents of the ticket, consuming it.
+    pub fn into_node_addrs(self) -> Vec<NodeAddr> {
+        self.nodes
+    }
+}
+
+impl From<NodeAddr> for NodesTicket {
+    /// Creates a ticket for a single node.
+    fn from(addr: NodeAddr) -> Self {
+        Self { nodes: vec![addr] }
+    }
+}
+
+impl From<NodesTicket> for Vec<NodeAddr> {
+    /// Returns the addressing info of all nodes in the ticket.
+    fn from(ticket: NodesTicket) -> Self {
+        ticket.nodes
+    }
+}
+
+impl Serialize for NodesTicket {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            let NodesTicket { nodes } = self;
+            nodes.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NodesTicket {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            Self::from_str(&s).map_err(serde::de::Error::custom)
+        } else {
+            let nodes: Vec<NodeAddr> = Deserialize::deserialize(deserializer)?;
+            Self::new(nodes).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use iroh_test::{assert_eq_hex, hexdump::parse_hexdump};
+
+    use super::*;
+    use crate::{
+        base32,
+        key::{PublicKey, SecretKey},
+    };
+
+    fn make_ticket() -> NodesTicket {
+        let nodes = (0..3).map(|i| {
+            let peer = SecretKey::generate().public();
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1234 + i));
+            NodeAddr::from_parts(peer, None, [addr])
+        });
+        NodesTicket::new(nodes).unwrap()
+    }
+
+    #[test]
+    fn test_ticket_postcard() {
+        let ticket = make_ticket();
+        let bytes = postcard::to_stdvec(&ticket).unwrap();
+        let ticket2: NodesTicket = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(ticket2, ticket);
+    }
+
+    #[test]
+    fn test_ticket_json() {
+        let ticket = make_ticket();
+        let json = serde_json::to_string(&ticket).unwrap();
+        let ticket2: NodesTicket = serde_json::from_str(&json).unwrap();
+        assert_eq!(ticket2, ticket);
+        assert_eq!(ticket2.node_addrs().len(), 3);
+    }
+
+    #[test]
+    fn test_ticket_invalid() {
+        assert!(NodesTicket::new([]).is_err());
+        let node = NodeAddr::new(SecretKey::generate().public());
+        assert!(NodesTicket::new([node.clone(), node.clone()]).is_err());
+
+        // An empty list of nodes is rejected when deserializing as well.
+        let bytes = postcard::to_stdvec(&Vec::<NodeAddr>::new()).unwrap();
+        assert!(postcard::from_bytes::<NodesTicket>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_ticket_base32() {
+        let node_a =
+            PublicKey::from_str("ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6")
+                .unwrap();
+        let node_b =
+            PublicKey::from_str("ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c")
+                .unwrap();
+
+        let ticket = NodesTicket::new([
+            NodeAddr::from_parts(
+                node_a,
+                Some("http://derp.me./".parse().unwrap()),
+                ["127.0.0.1:1024".parse().unwrap()],
+            ),
+            NodeAddr::from_parts(node_b, None, []),
+        ])
+        .unwrap();
+        let base32 = base32::parse_vec(ticket.to_string().strip_prefix("nodes").unwrap()).unwrap();
+        let expected = parse_hexdump("
+            00 # variant
+            02 # two nodes
+            ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6 # first node id, 32 bytes, see above
+            01 # relay url present
+            10 687474703a2f2f646572702e6d652e2f # relay url, 16 bytes, see above
+            01 # one direct address
+            00 # ipv4
+            7f000001 8008 # address, see above
+            ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c # second node id, 32 bytes, see above
+            00 # no relay url
+            00 # no direct addresses
+        ").unwrap();
+        assert_eq_hex!(base32, expected);
+    }
+}
@@ -0,0 +1,274 @@
+//! Short tickets for typing or reading out by hand.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    key::NodeId,
+    node_addr::NodeAddr,
+    ticket::{self, NodeTicket},
+};
+
+/// The [z-base-32] alphabet, which avoids characters which are easily confused.
+///
+/// [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
+const ALPHABET: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+
+/// The number of characters in a group, groups are separated by `-`.
+const GROUP_LEN: usize = 4;
+
+/// The number of characters encoding the node id, without the check character.
+const NODE_ID_LEN: usize = 52;
+
+/// A ticket containing only the [`NodeId`] of a node, in a form which can be read out over
+/// the phone or typed on a remote control.
+///
+/// A [`NodeTicket`] with its addresses is a long base32 string which is impractical to
+/// enter by hand.  The short ticket leaves out the addresses and encodes the node id in
+/// [z-base-32], in groups of four characters separated by dashes and followed by a check
+/// character:
+///
+/// ```text
+/// 4ktn-xisf-...-yy8c-r
+/// ```
+///
+/// Parsing is forgiving: upper case, spaces and missing dashes are accepted, and
+/// characters which are not part of the alphabet but easily confused with one are mapped
+/// to it, e.g. `0` to `o`.  The check character detects any single mistyped character and
+/// most swaps of adjacent characters.
+///
+/// Because the addresses are missing, connecting to the node requires a discovery service
+/// which can resolve its node id, e.g. the DNS discovery in iroh.  A signed [`NodeTicket`]
+/// loses its signature and expiration time when converted to a short ticket.
+///
+/// [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShortTicket {
+    node_id: NodeId,
+}
+
+impl ShortTicket {
+    /// Creates a new ticket.
+    pub fn new(node_id: NodeId) -> Self {
+        Self { node_id }
+    }
+
+    /// The [`NodeId`] of the node this ticket points to.
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Returns a [`NodeAddr`] without any addresses, to be completed by discovery.
+    pub fn node_addr(&self) -> NodeAddr {
+        NodeAddr::new(self.node_id)
+    }
+}
+
+impl fmt::Display for ShortTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut symbols = encode(self.node_id.as_bytes());
+        symbols.push(luhn_check_symbol(&symbols));
+        for (i, group) in symbols.chunks(GROUP_LEN).enumerate() {
+            if i > 0 {
+                f.write_str("-")?;
+            }
+            for symbol in group {
+                write!(f, "{}", ALPHABET[*symbol as usize] as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ShortTicket {
+    type Err = ticket::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut symbols = Vec::with_capacity(NODE_ID_LEN + 1);
+        for c in s.chars() {
+            if c == '-' || c.is_whitespace() {
+                continue;
+            }
+            let c = match c.to_ascii_lowercase() {
+                '0' => 'o',
+                'l' => '1',
+                'v' => 'u',
+                '2' => 'z',
+                c => c,
+            };
+            let symbol = ALPHABET
+                .iter()
+                .position(|a| *a as char == c)
+                .ok_or(ticket::Error::Verify("invalid character"))?;
+            symbols.push(symbol as u8);
+        }
+        if symbols.len() != NODE_ID_LEN + 1 {
+            return Err(ticket::Error::Verify("wrong length"));
+        }
+        if !luhn_is_valid(&symbols) {
+            return Err(ticket::Error::Verify("invalid check character"));
+        }
+        let bytes =
+            decode(&symbols[..NODE_ID_LEN]).ok_or(ticket::Error::Verify("invalid padding"))?;
+        let node_id =
+            NodeId::from_bytes(&bytes).map_err(|_| ticket::Error::Verify("invalid node id"))?;
+        Ok(Self { node_id })
+    }
+}
+
+impl From<NodeId> for ShortTicket {
+    fn from(node_id: NodeId) -> Self {
+        Self::new(node_id)
+    }
+}
+
+impl From<&NodeTicket> for ShortTicket {
+    /// Creates a short ticket for the node of the ticket, leaving out its addresses.
+    fn from(ticket: &NodeTicket) -> Self {
+        Self::new(ticket.node_addr().node_id)
+    }
+}
+
+impl From<ShortTicket> for NodeAddr {
+    /// Returns the addressing info of the ticket, which only contains the node id.
+    fn from(ticket: ShortTicket) -> Self {
+        ticket.node_addr()
+    }
+}
+
+impl Serialize for ShortTicket {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.node_id.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ShortTicket {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            Self::from_str(&s).map_err(serde::de::Error::custom)
+        } else {
+            let node_id = Deserialize::deserialize(deserializer)?;
+            Ok(Self::new(node_id))
+        }
+    }
+}
+
+/// Splits the bytes into 5 bit symbols, the last symbol is padded with zero bits.
+fn encode(bytes: &[u8; 32]) -> Vec<u8> {
+    let mut symbols = Vec::with_capacity(NODE_ID_LEN + 1);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            symbols.push(((buffer >> bits) & 0x1f) as u8);
+        }
+    }
+    if bits > 0 {
+        symbols.push(((buffer << (5 - bits)) & 0x1f) as u8);
+    }
+    symbols
+}
+
+/// Joins 5 bit symbols into bytes, returns `None` if the padding bits are not zero.
+fn decode(symbols: &[u8]) -> Option<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    let mut pos = 0;
+    for symbol in symbols {
+        buffer = (buffer << 5) | *symbol as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes[pos] = (buffer >> bits) as u8;
+            pos += 1;
+        }
+    }
+    let padding = buffer & ((1 << bits) - 1);
+    (pos == bytes.len() && padding == 0).then_some(bytes)
+}
+
+/// Computes the check symbol of the [Luhn mod N algorithm] for base 32.
+///
+/// [Luhn mod N algorithm]: https://en.wikipedia.org/wiki/Luhn_mod_N_algorithm
+fn luhn_check_symbol(symbols: &[u8]) -> u8 {
+    let sum = luhn_sum(symbols, 2);
+    ((32 - sum % 32) % 32) as u8
+}
+
+/// Validates symbols ending in a Luhn mod N check symbol.
+fn luhn_is_valid(symbols: &[u8]) -> bool {
+    luhn_sum(symbols, 1) % 32 == 0
+}
+
+fn luhn_sum(symbols: &[u8], mut factor: u32) -> u32 {
+    let mut sum = 0;
+    for symbol in symbols.iter().rev() {
+        let addend = factor * *symbol as u32;
+        factor = if factor == 2 { 1 } else { 2 };
+        sum += addend / 32 + addend % 32;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_short_ticket_roundtrip() {
+        for _ in 0..10 {
+            let ticket = ShortTicket::new(SecretKey::generate().public());
+            let s = ticket.to_string();
+            assert_eq!(s.len(), NODE_ID_LEN + 1 + NODE_ID_LEN / GROUP_LEN);
+            assert_eq!(s.parse::<ShortTicket>().unwrap(), ticket);
+
+            // Sloppy input is accepted.
+            let sloppy = s.replace('-', " ").to_uppercase().replace('O', "0");
+            assert_eq!(sloppy.parse::<ShortTicket>().unwrap(), ticket);
+
+            let json = serde_json::to_string(&ticket).unwrap();
+            assert_eq!(serde_json::from_str::<ShortTicket>(&json).unwrap(), ticket);
+        }
+    }
+
+    #[test]
+    fn test_short_ticket_typo() {
+        let ticket = ShortTicket::new(SecretKey::generate().public());
+        let s: Vec<char> = ticket.to_string().chars().collect();
+        for (i, c) in s.iter().enumerate() {
+            if *c == '-' {
+                continue;
+            }
+            for replacement in ALPHABET.iter().map(|a| *a as char) {
+                if replacement == *c {
+                    continue;
+                }
+                let mut typo = s.clone();
+                typo[i] = replacement;
+                let typo: String = typo.into_iter().collect();
+                assert!(typo.parse::<ShortTicket>().is_err(), "{typo}");
+            }
+        }
+        assert!("abc".parse::<ShortTicket>().is_err());
+    }
+
+    #[test]
+    fn test_short_ticket_from_node_ticket() {
+        let node_id = SecretKey::generate().public();
+        let addr = NodeAddr::from_parts(node_id, None, ["127.0.0.1:1234".parse().unwrap()]);
+        let ticket = ShortTicket::from(&NodeTicket::new(addr));
+        assert_eq!(ticket.node_id(), node_id);
+        assert_eq!(NodeAddr::from(ticket), NodeAddr::new(node_id));
+    }
+}
@@ -1,10 +1,4 @@
-use std::net::SocketAddr;
-
 use clap::{Parser, ValueEnum};
-use hickory_resolver::{
-    config::{NameServerConfig, Protocol, ResolverConfig},
-    AsyncResolver,
-};
 use iroh::{
     discovery::dns::{N0_DNS_NODE_ORIGIN_PROD, N0_DNS_NODE_ORIGIN_STAGING},
     dns::{node_info::TxtAttrs, DnsResolver},
@@ -54,7 +48,7 @@ async fn main() -> anyhow::Result<()> {
             N0_DNS_NODE_ORIGIN_PROD,
         ),
         Env::Dev => (
-            resolver_with_nameserver(LOCALHOST_DNS.parse()?),
+            DnsResolver::with_nameserver(LOCALHOST_DNS.parse()?),
             EXAMPLE_ORIGIN,
         ),
     };
@@ -74,10 +68,3 @@ async fn main() -> anyhow::Result<()> {
     }
     Ok(())
 }
-
-fn resolver_with_nameserver(nameserver: SocketAddr) -> DnsResolver {
-    let mut config = ResolverConfig::new();
-    let nameserver_config = NameServerConfig::new(nameserver, Protocol::Udp);
-    config.add_name_server(nameserver_config);
-    AsyncResolver::tokio(config, Default::default())
-}
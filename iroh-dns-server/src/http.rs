@@ -225,6 +225,7 @@ pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -
                 get(pkarr::get).put(pkarr::put)
             },
         )
+        .route("/pkarr/topic/:topic", get(pkarr::get_topic))
         .route("/healthcheck", get(|| async { "OK" }))
         .route("/", get(|| async { "Hi!" }))
         .with_state(state);
@@ -1,10 +1,11 @@
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     response::IntoResponse,
 };
 use bytes::Bytes;
-use http::{header, StatusCode};
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use serde::Deserialize;
 use tracing::info;
 
 use super::error::AppError;
@@ -48,3 +49,65 @@ pub async fn get(
     let headers = [(header::CONTENT_TYPE, "application/x-pkarr-signed-packet")];
     Ok((headers, body))
 }
+
+/// The response header containing the cursor to request the next page of a topic.
+const TOPIC_CURSOR_HEADER: &str = "iroh-topic-cursor";
+/// The number of nodes returned for a topic if the request sets no limit.
+const DEFAULT_TOPIC_LIMIT: usize = 20;
+/// The maximum number of nodes returned for a topic.
+const MAX_TOPIC_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct TopicQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Lists the signed packets of the nodes announcing a topic.
+///
+/// Each packet is prefixed by the public key and the length of the packet as a big-endian
+/// `u16`.
+pub async fn get_topic(
+    State(state): State<AppState>,
+    Path(topic): Path<String>,
+    Query(query): Query<TopicQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let topic = PublicKeyBytes::from_z32(&topic)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid topic: {e}"))))?;
+    let cursor = query
+        .cursor
+        .map(|cursor| PublicKeyBytes::from_z32(&cursor))
+        .transpose()
+        .map_err(|e| {
+            AppError::new(
+                StatusCode::BAD_REQUEST,
+                Some(format!("invalid cursor: {e}")),
+            )
+        })?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TOPIC_LIMIT)
+        .clamp(1, MAX_TOPIC_LIMIT);
+    let (packets, next) = state
+        .store
+        .get_topic_packets(topic.as_bytes(), cursor, limit)
+        .await?;
+
+    let mut body = Vec::new();
+    for packet in packets {
+        let payload = packet.to_relay_payload();
+        body.extend_from_slice(&packet.public_key().to_bytes());
+        body.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        body.extend_from_slice(&payload);
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-iroh-topic-packets"),
+    );
+    if let Some(next) = next {
+        let cursor = HeaderValue::from_str(&next.to_z32()).expect("z32 is a valid header value");
+        headers.insert(TOPIC_CURSOR_HEADER, cursor);
+    }
+    Ok((headers, body))
+}
@@ -12,22 +12,25 @@ mod store;
 mod util;
 
 // Re-export to be able to construct your own dns-server
-pub use store::ZoneStore;
+pub use store::{PacketStore, ZoneStore};
+pub use util::PublicKeyBytes;
 
 #[cfg(test)]
 mod tests {
     use std::{
+        collections::HashMap,
         net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Mutex,
         time::Duration,
     };
 
     use anyhow::Result;
-    use hickory_resolver::{
-        config::{NameServerConfig, Protocol, ResolverConfig},
-        AsyncResolver,
-    };
+    use futures_lite::StreamExt;
     use iroh::{
-        discovery::pkarr::PkarrRelayClient,
+        discovery::pkarr::{
+            topic::{Topic, TopicResolver},
+            PkarrRelayClient,
+        },
         dns::{node_info::NodeInfo, DnsResolver, ResolverExt},
         key::SecretKey,
     };
@@ -40,7 +43,7 @@ mod tests {
         server::Server,
         store::{PacketSource, ZoneStoreOptions},
         util::PublicKeyBytes,
-        ZoneStore,
+        PacketStore, ZoneStore,
     };
 
     #[tokio::test]
@@ -119,37 +122,37 @@ mod tests {
         // resolve root record
         let name = Name::from_utf8(format!("{pubkey}."))?;
         let res = resolver.txt_lookup(name).await?;
-        let records = res.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let records = res.map(|t| t.value().to_string()).collect::<Vec<_>>();
         assert_eq!(records, vec!["hi0".to_string()]);
 
         // resolve level one record
         let name = Name::from_utf8(format!("_hello.{pubkey}."))?;
         let res = resolver.txt_lookup(name).await?;
-        let records = res.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let records = res.map(|t| t.value().to_string()).collect::<Vec<_>>();
         assert_eq!(records, vec!["hi1".to_string()]);
 
         // resolve level two record
         let name = Name::from_utf8(format!("_hello.world.{pubkey}."))?;
         let res = resolver.txt_lookup(name).await?;
-        let records = res.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let records = res.map(|t| t.value().to_string()).collect::<Vec<_>>();
         assert_eq!(records, vec!["hi2".to_string()]);
 
         // resolve multiple records for same name
         let name = Name::from_utf8(format!("multiple.{pubkey}."))?;
         let res = resolver.txt_lookup(name).await?;
-        let records = res.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let records = res.map(|t| t.value().to_string()).collect::<Vec<_>>();
         assert_eq!(records, vec!["hi3".to_string(), "hi4".to_string()]);
 
         // resolve A record
         let name = Name::from_utf8(format!("{pubkey}."))?;
         let res = resolver.ipv4_lookup(name).await?;
-        let records = res.iter().map(|t| t.0).collect::<Vec<_>>();
+        let records = res.collect::<Vec<_>>();
         assert_eq!(records, vec![Ipv4Addr::LOCALHOST]);
 
         // resolve AAAA record
         let name = Name::from_utf8(format!("foo.bar.baz.{pubkey}."))?;
         let res = resolver.ipv6_lookup(name).await?;
-        let records = res.iter().map(|t| t.0).collect::<Vec<_>>();
+        let records = res.collect::<Vec<_>>();
         assert_eq!(records, vec![Ipv6Addr::LOCALHOST]);
 
         server.shutdown().await?;
@@ -188,6 +191,70 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn topic_resolve() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let (server, _nameserver, http_url) = Server::spawn_for_tests().await?;
+        let pkarr_relay = {
+            let mut url = http_url.clone();
+            url.set_path("/pkarr");
+            url
+        };
+        let pkarr = PkarrRelayClient::new(pkarr_relay.clone());
+        let topic = Topic::new("swarm");
+        let other_topic = Topic::new("other-swarm");
+
+        let mut secret_keys = Vec::new();
+        for topics in [
+            vec![topic],
+            vec![topic, other_topic],
+            vec![topic],
+            vec![other_topic],
+        ] {
+            let secret_key = SecretKey::generate();
+            let node_info = NodeInfo::new(secret_key.public(), None, Default::default())
+                .with_topics(topics.into_iter().collect());
+            pkarr
+                .publish(&node_info.to_pkarr_signed_packet(&secret_key, 30)?)
+                .await?;
+            secret_keys.push(secret_key);
+        }
+        let mut expected: Vec<_> = secret_keys[..3].iter().map(|k| k.public()).collect();
+        expected.sort_by_key(|node_id| *node_id.as_bytes());
+
+        // Page through the nodes of the topic.
+        let resolver = TopicResolver::new(pkarr_relay);
+        let page = resolver.resolve_page(&topic, None, 2).await?;
+        assert_eq!(page.items.len(), 2);
+        let next = page.next.expect("more nodes");
+        let last = resolver.resolve_page(&topic, Some(&next), 2).await?;
+        assert_eq!(last.items.len(), 1);
+        assert!(last.next.is_none());
+        let found: Vec<_> = page
+            .items
+            .iter()
+            .chain(last.items.iter())
+            .map(|item| item.node_id)
+            .collect();
+        assert_eq!(found, expected);
+
+        // A node which stops announcing the topic is no longer listed.
+        let node_info = NodeInfo::new(secret_keys[0].public(), None, Default::default());
+        pkarr
+            .publish(&node_info.to_pkarr_signed_packet(&secret_keys[0], 30)?)
+            .await?;
+        let items: Vec<_> = resolver.resolve(topic).try_collect().await?;
+        assert_eq!(items.len(), 2);
+        assert!(items
+            .iter()
+            .all(|item| item.node_id != secret_keys[0].public()));
+        let items: Vec<_> = resolver.resolve(other_topic).try_collect().await?;
+        assert_eq!(items.len(), 2);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn store_eviction() -> TestResult<()> {
         iroh_test::logging::setup_multithreaded();
@@ -218,6 +285,44 @@ mod tests {
         panic!("store did not evict packet");
     }
 
+    #[tokio::test]
+    async fn custom_packet_store() -> TestResult<()> {
+        #[derive(Debug, Default)]
+        struct MemoryStore(Mutex<HashMap<PublicKeyBytes, SignedPacket>>);
+
+        #[async_trait::async_trait]
+        impl PacketStore for MemoryStore {
+            async fn upsert(&self, packet: SignedPacket) -> Result<bool> {
+                let key = PublicKeyBytes::from_signed_packet(&packet);
+                let mut packets = self.0.lock().unwrap();
+                match packets.get(&key) {
+                    Some(existing) if existing.more_recent_than(&packet) => Ok(false),
+                    _ => {
+                        packets.insert(key, packet);
+                        Ok(true)
+                    }
+                }
+            }
+
+            async fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+                Ok(self.0.lock().unwrap().get(key).cloned())
+            }
+        }
+
+        let store = ZoneStore::with_packet_store(MemoryStore::default());
+        let signed_packet = random_signed_packet()?;
+        let key = PublicKeyBytes::from_signed_packet(&signed_packet);
+        assert!(
+            store
+                .insert(signed_packet.clone(), PacketSource::PkarrPublish)
+                .await?
+        );
+        let packet = store.get_signed_packet(&key).await?.unwrap();
+        assert_eq!(packet.public_key(), signed_packet.public_key());
+        assert_eq!(packet.timestamp(), signed_packet.timestamp());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn integration_mainline() -> Result<()> {
         iroh_test::logging::setup_multithreaded();
@@ -264,10 +369,7 @@ mod tests {
     }
 
     fn test_resolver(nameserver: SocketAddr) -> DnsResolver {
-        let mut config = ResolverConfig::new();
-        let nameserver_config = NameServerConfig::new(nameserver, Protocol::Udp);
-        config.add_name_server(nameserver_config);
-        AsyncResolver::tokio(config, Default::default())
+        DnsResolver::with_nameserver(nameserver)
     }
 
     fn random_signed_packet() -> Result<SignedPacket> {
@@ -1,8 +1,16 @@
 //! Pkarr packet store used to resolve DNS queries.
 
-use std::{collections::BTreeMap, num::NonZeroUsize, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    num::NonZeroUsize,
+    ops::Bound,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Result;
+use async_trait::async_trait;
 use hickory_proto::rr::{Name, RecordSet, RecordType, RrKey};
 use iroh_metrics::inc;
 use lru::LruCache;
@@ -21,6 +29,11 @@ use crate::{
 mod signed_packets;
 pub use signed_packets::Options as ZoneStoreOptions;
 
+/// The name of the TXT records containing the attributes of iroh nodes.
+const IROH_TXT_NAME: &str = "_iroh";
+/// The attribute of iroh nodes containing an announced topic.
+const IROH_TOPIC_ATTR: &str = "topic";
+
 /// Cache up to 1 million pkarr zones by default
 pub const DEFAULT_CACHE_CAPACITY: usize = 1024 * 1024;
 /// Default TTL for DHT cache entries
@@ -32,14 +45,48 @@ pub enum PacketSource {
     PkarrPublish,
 }
 
+/// Storage backend for the signed packets of a [`ZoneStore`].
+///
+/// [`ZoneStore::persistent`] and [`ZoneStore::in_memory`] keep the packets in a
+/// [redb](https://docs.rs/redb) database.  Implement this trait to keep them elsewhere, e.g.
+/// in a database shared by several DNS server instances, and create the zone store with
+/// [`ZoneStore::with_packet_store`].
+///
+/// Evicting old packets is up to the backend.
+#[async_trait]
+pub trait PacketStore: std::fmt::Debug + Send + Sync + 'static {
+    /// Stores a signed packet, unless a more recent packet for its public key is stored.
+    ///
+    /// Returns whether the packet was stored.
+    async fn upsert(&self, packet: SignedPacket) -> Result<bool>;
+
+    /// Returns the stored packet for a public key.
+    async fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>>;
+}
+
+#[async_trait]
+impl PacketStore for SignedPacketStore {
+    async fn upsert(&self, packet: SignedPacket) -> Result<bool> {
+        SignedPacketStore::upsert(self, packet).await
+    }
+
+    async fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        SignedPacketStore::get(self, key).await
+    }
+}
+
 /// A store for pkarr signed packets.
 ///
-/// Packets are stored in the persistent [`SignedPacketStore`], and cached on-demand in an in-memory LRU
+/// Packets are stored in a [`PacketStore`], and cached on-demand in an in-memory LRU
 /// cache used for resolving DNS queries.
+///
+/// The topics announced by iroh nodes are indexed in memory when their packets are
+/// inserted, so the index is rebuilt from the periodic republishes after a restart.
 #[derive(Debug, Clone)]
 pub struct ZoneStore {
     cache: Arc<Mutex<ZoneCache>>,
-    store: Arc<SignedPacketStore>,
+    topics: Arc<parking_lot::Mutex<TopicIndex>>,
+    store: Arc<dyn PacketStore>,
     pkarr: Option<Arc<PkarrClient>>,
 }
 
@@ -81,10 +128,16 @@ impl ZoneStore {
 
     /// Create a new zone store.
     pub fn new(store: SignedPacketStore) -> Self {
+        Self::with_packet_store(store)
+    }
+
+    /// Create a zone store on top of a custom storage backend.
+    pub fn with_packet_store(store: impl PacketStore) -> Self {
         let zone_cache = ZoneCache::new(DEFAULT_CACHE_CAPACITY);
         Self {
             store: Arc::new(store),
             cache: Arc::new(Mutex::new(zone_cache)),
+            topics: Default::default(),
             pkarr: None,
         }
     }
@@ -146,15 +199,110 @@ impl ZoneStore {
     #[allow(clippy::unused_async)]
     pub async fn insert(&self, signed_packet: SignedPacket, _source: PacketSource) -> Result<bool> {
         let pubkey = PublicKeyBytes::from_signed_packet(&signed_packet);
+        let topics = announced_topics(&signed_packet);
         if self.store.upsert(signed_packet).await? {
             inc!(Metrics, pkarr_publish_update);
             self.cache.lock().await.remove(&pubkey);
+            self.topics.lock().update(pubkey, topics);
             Ok(true)
         } else {
             inc!(Metrics, pkarr_publish_noop);
             Ok(false)
         }
     }
+
+    /// Get the signed packets of the nodes announcing a topic.
+    ///
+    /// Returns up to `limit` packets ordered by public key, starting after the public key
+    /// `after`, together with the public key to continue after if there may be more.
+    pub async fn get_topic_packets(
+        &self,
+        topic: &[u8; 32],
+        after: Option<PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<(Vec<SignedPacket>, Option<PublicKeyBytes>)> {
+        let mut packets = Vec::new();
+        let mut cursor = after;
+        while packets.len() < limit {
+            let Some(pubkey) = self.topics.lock().next(topic, cursor.as_ref()) else {
+                return Ok((packets, None));
+            };
+            cursor = Some(pubkey);
+            match self.store.get(&pubkey).await? {
+                Some(packet) => packets.push(packet),
+                // The packet was evicted from the store.
+                None => self.topics.lock().update(pubkey, Default::default()),
+            }
+        }
+        Ok((packets, cursor))
+    }
+}
+
+/// Index of the nodes announcing a topic.
+#[derive(Debug, Default)]
+struct TopicIndex {
+    nodes: HashMap<[u8; 32], BTreeSet<PublicKeyBytes>>,
+    topics: HashMap<PublicKeyBytes, BTreeSet<[u8; 32]>>,
+}
+
+impl TopicIndex {
+    /// Replaces the topics announced by a node.
+    fn update(&mut self, pubkey: PublicKeyBytes, topics: BTreeSet<[u8; 32]>) {
+        for topic in self.topics.remove(&pubkey).unwrap_or_default() {
+            if let Some(nodes) = self.nodes.get_mut(&topic) {
+                nodes.remove(&pubkey);
+                if nodes.is_empty() {
+                    self.nodes.remove(&topic);
+                }
+            }
+        }
+        for topic in &topics {
+            self.nodes.entry(*topic).or_default().insert(pubkey);
+        }
+        if !topics.is_empty() {
+            self.topics.insert(pubkey, topics);
+        }
+    }
+
+    /// Returns the first node announcing the topic with a public key after `after`.
+    fn next(&self, topic: &[u8; 32], after: Option<&PublicKeyBytes>) -> Option<PublicKeyBytes> {
+        let nodes = self.nodes.get(topic)?;
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        nodes.range((start, Bound::Unbounded)).next().copied()
+    }
+}
+
+/// Returns the topics announced in the iroh node info of a signed packet.
+fn announced_topics(signed_packet: &SignedPacket) -> BTreeSet<[u8; 32]> {
+    use pkarr::dns::rdata::RData;
+    let Ok(zone) = pkarr::dns::Name::new(&signed_packet.public_key().to_z32()) else {
+        return Default::default();
+    };
+    signed_packet
+        .packet()
+        .answers
+        .iter()
+        .filter(|rr| {
+            rr.name
+                .without(&zone)
+                .is_some_and(|name| name.to_string() == IROH_TXT_NAME)
+        })
+        .filter_map(|rr| match &rr.rdata {
+            RData::TXT(txt) => String::try_from(txt.clone()).ok(),
+            _ => None,
+        })
+        .filter_map(|attr| {
+            let (key, value) = attr.split_once('=')?;
+            if key != IROH_TOPIC_ATTR {
+                return None;
+            }
+            let bytes = z32::decode(value.as_bytes()).ok()?;
+            bytes.try_into().ok()
+        })
+        .collect()
 }
 
 #[derive(derive_more::Debug)]
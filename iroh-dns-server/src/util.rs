@@ -16,34 +16,44 @@ use hickory_proto::{
 };
 use pkarr::SignedPacket;
 
+/// The bytes of a pkarr public key, the key of the zone a signed packet belongs to.
+///
+/// Unlike [`pkarr::PublicKey`] this is not checked to be a valid ed25519 key, so that it can
+/// be decoded from any DNS query.
 #[derive(
     derive_more::From, derive_more::Into, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy,
 )]
 pub struct PublicKeyBytes([u8; 32]);
 
 impl PublicKeyBytes {
+    /// Creates the public key from its bytes.
     pub fn new(bytes: [u8; 32]) -> Self {
         Self(bytes)
     }
 
+    /// Decodes the public key from its z-base-32 encoding.
     pub fn from_z32(s: &str) -> Result<Self> {
         let bytes = z32::decode(s.as_bytes())?;
         let bytes: [u8; 32] = bytes.try_into().map_err(|_| anyhow!("invalid length"))?;
         Ok(Self(bytes))
     }
 
+    /// Returns the z-base-32 encoding of the public key.
     pub fn to_z32(self) -> String {
         z32::encode(&self.0)
     }
 
+    /// Returns the bytes of the public key.
     pub fn to_bytes(self) -> [u8; 32] {
         self.0
     }
 
+    /// Returns a reference to the bytes of the public key.
     pub fn as_bytes(&self) -> &[u8; 32] {
         &self.0
     }
 
+    /// Returns the public key of a signed packet.
     pub fn from_signed_packet(packet: &SignedPacket) -> Self {
         Self(packet.public_key().to_bytes())
     }
@@ -0,0 +1,175 @@
+/* C bindings for iroh, see the iroh-ffi crate.  Regenerate with cbindgen, see README.md. */
+
+#ifndef IROH_H
+#define IROH_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/* The number of bytes of secret keys and node IDs. */
+#define IROH_KEY_LEN 32
+
+/* The size of the buffer needed by iroh_node_id_to_string, including the NUL byte. */
+#define IROH_NODE_ID_STR_LEN 53
+
+/* The outcome of a call or an asynchronous operation. */
+typedef enum IrohResult {
+  /* The call succeeded. */
+  IrohResult_Ok = 0,
+  /* An argument was NULL or invalid. */
+  IrohResult_InvalidArgument = 1,
+  /* The operation failed. */
+  IrohResult_Failed = 2,
+  /* The endpoint, connection or stream was closed. */
+  IrohResult_Closed = 3,
+} IrohResult;
+
+/* Which relay servers an endpoint uses.
+ *
+ * Passed as a uint32_t in IrohEndpointConfig::relay_mode, as C does not guarantee
+ * that only declared values are stored in an enum. */
+typedef enum IrohRelayMode {
+  /* Use the default relay servers run by number 0. */
+  IrohRelayMode_Default = 0,
+  /* Do not use any relay servers, nodes can only be reached by their direct addresses. */
+  IrohRelayMode_Disabled = 1,
+} IrohRelayMode;
+
+/* A connection to a remote node. */
+typedef struct IrohConnection IrohConnection;
+
+/* An iroh endpoint. */
+typedef struct IrohEndpoint IrohEndpoint;
+
+/* The receiving half of a stream. */
+typedef struct IrohRecvStream IrohRecvStream;
+
+/* The sending half of a stream. */
+typedef struct IrohSendStream IrohSendStream;
+
+/* The callback of asynchronous operations which do not return a value. */
+typedef void (*IrohCallback)(void *user_data, IrohResult result);
+
+/* The callback of iroh_connection_open_bi and iroh_connection_accept_bi. */
+typedef void (*IrohStreamsCallback)(void *user_data,
+                                    IrohResult result,
+                                    IrohSendStream *send_stream,
+                                    IrohRecvStream *recv_stream);
+
+/* The configuration of an endpoint, see iroh_endpoint_bind. */
+typedef struct IrohEndpointConfig {
+  /* The secret key of IROH_KEY_LEN bytes, or NULL to generate a new one. */
+  const uint8_t *secret_key;
+  /* The ALPN protocol accepted for incoming connections.
+   *
+   * With alpn_len zero no incoming connections are accepted. */
+  const uint8_t *alpn;
+  /* The length of alpn. */
+  size_t alpn_len;
+  /* The relay servers to use, one of the IrohRelayMode values.
+   *
+   * Other values make iroh_endpoint_bind fail with IrohResult::InvalidArgument. */
+  uint32_t relay_mode;
+} IrohEndpointConfig;
+
+/* The callback of iroh_endpoint_bind. */
+typedef void (*IrohEndpointCallback)(void *user_data, IrohResult result, IrohEndpoint *endpoint);
+
+/* The callback of iroh_endpoint_connect and iroh_endpoint_accept. */
+typedef void (*IrohConnectionCallback)(void *user_data,
+                                       IrohResult result,
+                                       IrohConnection *connection);
+
+/* The callback of iroh_recv_stream_read.
+ *
+ * The data of len bytes is only valid for the duration of the callback.  A len of zero
+ * with IrohResult_Ok means the stream was finished by the remote node. */
+typedef void (*IrohReadCallback)(void *user_data,
+                                 IrohResult result,
+                                 const uint8_t *data,
+                                 size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+IrohResult iroh_connection_remote_node_id(const IrohConnection *connection, uint8_t *out);
+
+IrohResult iroh_connection_open_bi(const IrohConnection *connection,
+                                   IrohStreamsCallback callback,
+                                   void *user_data);
+
+IrohResult iroh_connection_accept_bi(const IrohConnection *connection,
+                                     IrohStreamsCallback callback,
+                                     void *user_data);
+
+IrohResult iroh_connection_close(const IrohConnection *connection,
+                                 uint64_t error_code,
+                                 const uint8_t *reason,
+                                 size_t reason_len);
+
+void iroh_connection_free(IrohConnection *connection);
+
+IrohResult iroh_endpoint_bind(const IrohEndpointConfig *config,
+                              IrohEndpointCallback callback,
+                              void *user_data);
+
+IrohResult iroh_endpoint_node_id(const IrohEndpoint *endpoint, uint8_t *out);
+
+IrohResult iroh_endpoint_connect(const IrohEndpoint *endpoint,
+                                 const uint8_t *node_id,
+                                 const char *relay_url,
+                                 const char *const *direct_addrs,
+                                 size_t direct_addrs_len,
+                                 const uint8_t *alpn,
+                                 size_t alpn_len,
+                                 IrohConnectionCallback callback,
+                                 void *user_data);
+
+IrohResult iroh_endpoint_accept(const IrohEndpoint *endpoint,
+                                IrohConnectionCallback callback,
+                                void *user_data);
+
+IrohResult iroh_endpoint_close(const IrohEndpoint *endpoint,
+                               IrohCallback callback,
+                               void *user_data);
+
+void iroh_endpoint_free(IrohEndpoint *endpoint);
+
+const char *iroh_last_error(void);
+
+IrohResult iroh_secret_key_generate(uint8_t *out);
+
+IrohResult iroh_secret_key_public(const uint8_t *secret_key, uint8_t *out);
+
+IrohResult iroh_node_id_to_string(const uint8_t *node_id, char *buf, size_t buf_len);
+
+IrohResult iroh_node_id_from_string(const char *s, uint8_t *out);
+
+IrohResult iroh_send_stream_write(const IrohSendStream *stream,
+                                  const uint8_t *data,
+                                  size_t len,
+                                  IrohCallback callback,
+                                  void *user_data);
+
+IrohResult iroh_send_stream_finish(const IrohSendStream *stream,
+                                   IrohCallback callback,
+                                   void *user_data);
+
+void iroh_send_stream_free(IrohSendStream *stream);
+
+IrohResult iroh_recv_stream_read(const IrohRecvStream *stream,
+                                 size_t max_len,
+                                 IrohReadCallback callback,
+                                 void *user_data);
+
+void iroh_recv_stream_free(IrohRecvStream *stream);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* IROH_H */
@@ -0,0 +1,188 @@
+//! Connections and opening streams on them.
+
+use std::{ffi::c_void, ptr};
+
+use iroh::endpoint::{get_remote_node_id, Connection, RecvStream, SendStream, VarInt};
+
+use crate::{
+    error, read_bytes, spawn, write_key, IrohRecvStream, IrohResult, IrohSendStream, UserData,
+};
+
+/// A connection to a remote node, see [`iroh::endpoint::Connection`].
+///
+/// Created by [`iroh_endpoint_connect`] or [`iroh_endpoint_accept`] and freed by
+/// [`iroh_connection_free`].
+///
+/// [`iroh_endpoint_connect`]: crate::iroh_endpoint_connect
+/// [`iroh_endpoint_accept`]: crate::iroh_endpoint_accept
+#[derive(Debug)]
+pub struct IrohConnection {
+    connection: Connection,
+}
+
+impl IrohConnection {
+    pub(crate) fn new(connection: Connection) -> Self {
+        Self { connection }
+    }
+}
+
+/// The callback of [`iroh_connection_open_bi`] and [`iroh_connection_accept_bi`].
+///
+/// The streams are NULL unless `result` is [`IrohResult::Ok`].
+pub type IrohStreamsCallback = extern "C" fn(
+    user_data: *mut c_void,
+    result: IrohResult,
+    send_stream: *mut IrohSendStream,
+    recv_stream: *mut IrohRecvStream,
+);
+
+/// Writes the node ID of the remote node of `connection` to `out`.
+///
+/// # Safety
+///
+/// `connection` must be a valid connection and `out` must point to
+/// [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_connection_remote_node_id(
+    connection: *const IrohConnection,
+    out: *mut u8,
+) -> IrohResult {
+    let Some(connection) = connection.as_ref() else {
+        return error(IrohResult::InvalidArgument, "connection is NULL");
+    };
+    let node_id = match get_remote_node_id(&connection.connection) {
+        Ok(node_id) => node_id,
+        Err(err) => return error(IrohResult::Failed, err),
+    };
+    match write_key(out, node_id.as_bytes()) {
+        true => IrohResult::Ok,
+        false => error(IrohResult::InvalidArgument, "out is NULL"),
+    }
+}
+
+/// Opens a bi-directional stream.
+///
+/// The `callback` receives the streams, which must be freed with
+/// [`iroh_send_stream_free`] and [`iroh_recv_stream_free`].  The remote node is only
+/// notified of the stream once data is written to it.
+///
+/// # Safety
+///
+/// `connection` must be a valid connection.
+///
+/// [`iroh_send_stream_free`]: crate::iroh_send_stream_free
+/// [`iroh_recv_stream_free`]: crate::iroh_recv_stream_free
+#[no_mangle]
+pub unsafe extern "C" fn iroh_connection_open_bi(
+    connection: *const IrohConnection,
+    callback: IrohStreamsCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(connection) = connection.as_ref() else {
+        return error(IrohResult::InvalidArgument, "connection is NULL");
+    };
+    let connection = connection.connection.clone();
+    let user_data = UserData(user_data);
+    spawn(async move {
+        match connection.open_bi().await {
+            Ok((send, recv)) => streams_callback(callback, user_data, send, recv),
+            Err(err) => {
+                let result = error(IrohResult::Closed, err);
+                callback(user_data.get(), result, ptr::null_mut(), ptr::null_mut());
+            }
+        }
+    });
+    IrohResult::Ok
+}
+
+/// Accepts the next bi-directional stream opened by the remote node.
+///
+/// The `callback` receives the streams, which must be freed with
+/// [`iroh_send_stream_free`] and [`iroh_recv_stream_free`].  If the connection is closed
+/// the `callback` is invoked with [`IrohResult::Closed`].
+///
+/// # Safety
+///
+/// `connection` must be a valid connection.
+///
+/// [`iroh_send_stream_free`]: crate::iroh_send_stream_free
+/// [`iroh_recv_stream_free`]: crate::iroh_recv_stream_free
+#[no_mangle]
+pub unsafe extern "C" fn iroh_connection_accept_bi(
+    connection: *const IrohConnection,
+    callback: IrohStreamsCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(connection) = connection.as_ref() else {
+        return error(IrohResult::InvalidArgument, "connection is NULL");
+    };
+    let connection = connection.connection.clone();
+    let user_data = UserData(user_data);
+    spawn(async move {
+        match connection.accept_bi().await {
+            Ok((send, recv)) => streams_callback(callback, user_data, send, recv),
+            Err(err) => {
+                let result = error(IrohResult::Closed, err);
+                callback(user_data.get(), result, ptr::null_mut(), ptr::null_mut());
+            }
+        }
+    });
+    IrohResult::Ok
+}
+
+/// Invokes `callback` with a new [`IrohSendStream`] and [`IrohRecvStream`].
+fn streams_callback(
+    callback: IrohStreamsCallback,
+    user_data: UserData,
+    send: SendStream,
+    recv: RecvStream,
+) {
+    let send = Box::into_raw(Box::new(IrohSendStream::new(send)));
+    let recv = Box::into_raw(Box::new(IrohRecvStream::new(recv)));
+    callback(user_data.get(), IrohResult::Ok, send, recv);
+}
+
+/// Closes the connection immediately.
+///
+/// The `error_code` and the `reason` of `reason_len` bytes are sent to the remote node.
+/// Streams which are still in use are reset.  The connection still needs to be freed
+/// with [`iroh_connection_free`].
+///
+/// # Safety
+///
+/// `connection` must be a valid connection and `reason` must point to `reason_len`
+/// readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_connection_close(
+    connection: *const IrohConnection,
+    error_code: u64,
+    reason: *const u8,
+    reason_len: usize,
+) -> IrohResult {
+    let Some(connection) = connection.as_ref() else {
+        return error(IrohResult::InvalidArgument, "connection is NULL");
+    };
+    let Some(reason) = read_bytes(reason, reason_len) else {
+        return error(IrohResult::InvalidArgument, "reason is NULL");
+    };
+    let error_code = match VarInt::from_u64(error_code) {
+        Ok(code) => code,
+        Err(err) => return error(IrohResult::InvalidArgument, err),
+    };
+    connection.connection.close(error_code, reason);
+    IrohResult::Ok
+}
+
+/// Frees a connection.
+///
+/// The connection is closed once it is freed and no streams of it are in use anymore.
+///
+/// # Safety
+///
+/// `connection` must be NULL or a valid connection, which must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_connection_free(connection: *mut IrohConnection) {
+    if !connection.is_null() {
+        drop(Box::from_raw(connection));
+    }
+}
@@ -0,0 +1,333 @@
+//! Creating endpoints, connecting and accepting connections.
+
+use std::{
+    ffi::{c_char, c_void},
+    net::SocketAddr,
+    ptr,
+};
+
+use anyhow::Context;
+use iroh::{
+    endpoint::Connection,
+    key::{PublicKey, SecretKey},
+    Endpoint, NodeAddr, RelayMode, RelayUrl,
+};
+
+use crate::{
+    error, read_bytes, read_key, read_str, spawn, write_key, IrohCallback, IrohConnection,
+    IrohResult, UserData,
+};
+
+/// Which relay servers an endpoint uses.
+///
+/// Passed as a `uint32_t` in [`IrohEndpointConfig::relay_mode`], as C does not guarantee
+/// that only declared values are stored in an enum.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrohRelayMode {
+    /// Use the default relay servers run by number 0.
+    Default = 0,
+    /// Do not use any relay servers, nodes can only be reached by their direct addresses.
+    Disabled = 1,
+}
+
+impl TryFrom<u32> for IrohRelayMode {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Default),
+            1 => Ok(Self::Disabled),
+            value => Err(value),
+        }
+    }
+}
+
+/// The configuration of an endpoint, see [`iroh_endpoint_bind`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct IrohEndpointConfig {
+    /// The secret key of [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) bytes, or NULL to generate
+    /// a new one.
+    pub secret_key: *const u8,
+    /// The ALPN protocol accepted for incoming connections.
+    ///
+    /// With `alpn_len` zero no incoming connections are accepted.
+    pub alpn: *const u8,
+    /// The length of `alpn`.
+    pub alpn_len: usize,
+    /// The relay servers to use, one of the [`IrohRelayMode`] values.
+    ///
+    /// Other values make [`iroh_endpoint_bind`] fail with [`IrohResult::InvalidArgument`].
+    pub relay_mode: u32,
+}
+
+/// An iroh endpoint, see [`iroh::Endpoint`].
+///
+/// Created by [`iroh_endpoint_bind`] and freed by [`iroh_endpoint_free`].
+#[derive(Debug)]
+pub struct IrohEndpoint {
+    pub(crate) endpoint: Endpoint,
+}
+
+/// The callback of [`iroh_endpoint_bind`].
+///
+/// `endpoint` is NULL unless `result` is [`IrohResult::Ok`].
+pub type IrohEndpointCallback =
+    extern "C" fn(user_data: *mut c_void, result: IrohResult, endpoint: *mut IrohEndpoint);
+
+/// The callback of [`iroh_endpoint_connect`] and [`iroh_endpoint_accept`].
+///
+/// `connection` is NULL unless `result` is [`IrohResult::Ok`].
+pub type IrohConnectionCallback =
+    extern "C" fn(user_data: *mut c_void, result: IrohResult, connection: *mut IrohConnection);
+
+/// Creates an endpoint.
+///
+/// The `callback` receives the endpoint, which must be freed with [`iroh_endpoint_free`].
+///
+/// # Safety
+///
+/// `config` must point to a valid [`IrohEndpointConfig`], which only needs to be valid
+/// for the duration of the call.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_endpoint_bind(
+    config: *const IrohEndpointConfig,
+    callback: IrohEndpointCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(config) = config.as_ref() else {
+        return error(IrohResult::InvalidArgument, "config is NULL");
+    };
+    let Some(alpn) = read_bytes(config.alpn, config.alpn_len) else {
+        return error(IrohResult::InvalidArgument, "alpn is NULL");
+    };
+    let relay_mode = match IrohRelayMode::try_from(config.relay_mode) {
+        Ok(IrohRelayMode::Default) => RelayMode::Default,
+        Ok(IrohRelayMode::Disabled) => RelayMode::Disabled,
+        Err(value) => {
+            return error(
+                IrohResult::InvalidArgument,
+                format!("invalid relay mode {value}"),
+            );
+        }
+    };
+    let mut builder = Endpoint::builder().relay_mode(relay_mode);
+    if let Some(secret_key) = read_key(config.secret_key) {
+        builder = builder.secret_key(SecretKey::from_bytes(&secret_key));
+    }
+    if !alpn.is_empty() {
+        builder = builder.alpns(vec![alpn.to_vec()]);
+    }
+    let user_data = UserData(user_data);
+    spawn(async move {
+        match builder.bind().await {
+            Ok(endpoint) => {
+                let endpoint = Box::new(IrohEndpoint { endpoint });
+                callback(user_data.get(), IrohResult::Ok, Box::into_raw(endpoint));
+            }
+            Err(err) => {
+                let result = error(IrohResult::Failed, err);
+                callback(user_data.get(), result, ptr::null_mut());
+            }
+        }
+    });
+    IrohResult::Ok
+}
+
+/// Writes the node ID of `endpoint` to `out`.
+///
+/// # Safety
+///
+/// `endpoint` must be a valid endpoint and `out` must point to
+/// [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_endpoint_node_id(
+    endpoint: *const IrohEndpoint,
+    out: *mut u8,
+) -> IrohResult {
+    let Some(endpoint) = endpoint.as_ref() else {
+        return error(IrohResult::InvalidArgument, "endpoint is NULL");
+    };
+    match write_key(out, endpoint.endpoint.node_id().as_bytes()) {
+        true => IrohResult::Ok,
+        false => error(IrohResult::InvalidArgument, "out is NULL"),
+    }
+}
+
+/// Connects to a remote node.
+///
+/// The node is dialed using the `relay_url` and the `direct_addrs`, which are strings like
+/// `"192.0.2.1:1234"`, and any addresses found by discovery.  Either can be NULL.
+///
+/// The `callback` receives the connection, which must be freed with
+/// [`iroh_connection_free`].
+///
+/// # Safety
+///
+/// `endpoint` must be a valid endpoint, `node_id` must point to
+/// [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) readable bytes, `relay_url` must be NULL or a
+/// NUL-terminated string, `direct_addrs` must be NULL or point to `direct_addrs_len`
+/// NUL-terminated strings and `alpn` must point to `alpn_len` readable bytes.  All of them
+/// only need to be valid for the duration of the call.
+///
+/// [`iroh_connection_free`]: crate::iroh_connection_free
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn iroh_endpoint_connect(
+    endpoint: *const IrohEndpoint,
+    node_id: *const u8,
+    relay_url: *const c_char,
+    direct_addrs: *const *const c_char,
+    direct_addrs_len: usize,
+    alpn: *const u8,
+    alpn_len: usize,
+    callback: IrohConnectionCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(endpoint) = endpoint.as_ref() else {
+        return error(IrohResult::InvalidArgument, "endpoint is NULL");
+    };
+    let node_addr = match read_node_addr(node_id, relay_url, direct_addrs, direct_addrs_len) {
+        Ok(node_addr) => node_addr,
+        Err(err) => return error(IrohResult::InvalidArgument, err),
+    };
+    let Some(alpn) = read_bytes(alpn, alpn_len) else {
+        return error(IrohResult::InvalidArgument, "alpn is NULL");
+    };
+    let alpn = alpn.to_vec();
+    let endpoint = endpoint.endpoint.clone();
+    let user_data = UserData(user_data);
+    spawn(async move {
+        match endpoint.connect(node_addr, &alpn).await {
+            Ok(connection) => connection_callback(callback, user_data, connection),
+            Err(err) => {
+                let result = error(IrohResult::Failed, err);
+                callback(user_data.get(), result, ptr::null_mut());
+            }
+        }
+    });
+    IrohResult::Ok
+}
+
+/// Reads the [`NodeAddr`] passed to [`iroh_endpoint_connect`].
+///
+/// # Safety
+///
+/// See [`iroh_endpoint_connect`].
+unsafe fn read_node_addr(
+    node_id: *const u8,
+    relay_url: *const c_char,
+    direct_addrs: *const *const c_char,
+    direct_addrs_len: usize,
+) -> anyhow::Result<NodeAddr> {
+    let node_id = read_key(node_id).context("node_id is NULL")?;
+    let node_id = PublicKey::from_bytes(&node_id)?;
+    let relay_url = read_str(relay_url)?
+        .map(|url| url.parse::<RelayUrl>())
+        .transpose()
+        .context("invalid relay_url")?;
+    let mut addrs = Vec::with_capacity(direct_addrs_len);
+    if direct_addrs_len > 0 {
+        anyhow::ensure!(!direct_addrs.is_null(), "direct_addrs is NULL");
+        for addr in std::slice::from_raw_parts(direct_addrs, direct_addrs_len) {
+            let addr = read_str(*addr)?.context("direct address is NULL")?;
+            let addr: SocketAddr = addr
+                .parse()
+                .with_context(|| format!("invalid direct address {addr:?}"))?;
+            addrs.push(addr);
+        }
+    }
+    Ok(NodeAddr::from_parts(node_id, relay_url, addrs))
+}
+
+/// Accepts the next incoming connection.
+///
+/// The `callback` receives the connection, which must be freed with
+/// [`iroh_connection_free`].  If the endpoint is closed the `callback` is invoked with
+/// [`IrohResult::Closed`].
+///
+/// # Safety
+///
+/// `endpoint` must be a valid endpoint.
+///
+/// [`iroh_connection_free`]: crate::iroh_connection_free
+#[no_mangle]
+pub unsafe extern "C" fn iroh_endpoint_accept(
+    endpoint: *const IrohEndpoint,
+    callback: IrohConnectionCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(endpoint) = endpoint.as_ref() else {
+        return error(IrohResult::InvalidArgument, "endpoint is NULL");
+    };
+    let endpoint = endpoint.endpoint.clone();
+    let user_data = UserData(user_data);
+    spawn(async move {
+        let Some(incoming) = endpoint.accept().await else {
+            let result = error(IrohResult::Closed, "endpoint closed");
+            callback(user_data.get(), result, ptr::null_mut());
+            return;
+        };
+        match incoming.await {
+            Ok(connection) => connection_callback(callback, user_data, connection),
+            Err(err) => {
+                let result = error(IrohResult::Failed, err);
+                callback(user_data.get(), result, ptr::null_mut());
+            }
+        }
+    });
+    IrohResult::Ok
+}
+
+/// Invokes `callback` with a new [`IrohConnection`].
+fn connection_callback(
+    callback: IrohConnectionCallback,
+    user_data: UserData,
+    connection: Connection,
+) {
+    let connection = Box::new(IrohConnection::new(connection));
+    callback(user_data.get(), IrohResult::Ok, Box::into_raw(connection));
+}
+
+/// Closes the endpoint.
+///
+/// All connections are closed, the `callback` is invoked once they are.  The endpoint
+/// still needs to be freed with [`iroh_endpoint_free`].
+///
+/// # Safety
+///
+/// `endpoint` must be a valid endpoint.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_endpoint_close(
+    endpoint: *const IrohEndpoint,
+    callback: IrohCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(endpoint) = endpoint.as_ref() else {
+        return error(IrohResult::InvalidArgument, "endpoint is NULL");
+    };
+    let endpoint = endpoint.endpoint.clone();
+    let user_data = UserData(user_data);
+    spawn(async move {
+        match endpoint.close().await {
+            Ok(()) => callback(user_data.get(), IrohResult::Ok),
+            Err(err) => callback(user_data.get(), error(IrohResult::Failed, err)),
+        }
+    });
+    IrohResult::Ok
+}
+
+/// Frees an endpoint.
+///
+/// Use [`iroh_endpoint_close`] first to close its connections gracefully.
+///
+/// # Safety
+///
+/// `endpoint` must be NULL or a valid endpoint, which must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_endpoint_free(endpoint: *mut IrohEndpoint) {
+    if !endpoint.is_null() {
+        drop(Box::from_raw(endpoint));
+    }
+}
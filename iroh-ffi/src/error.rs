@@ -0,0 +1,48 @@
+//! Reporting errors to the caller.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CString},
+    fmt::Display,
+    ptr,
+};
+
+/// The outcome of a call or an asynchronous operation.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrohResult {
+    /// The call succeeded.
+    Ok = 0,
+    /// An argument was NULL or invalid.
+    InvalidArgument = 1,
+    /// The operation failed.
+    Failed = 2,
+    /// The endpoint, connection or stream was closed.
+    Closed = 3,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Records `err` as the last error of this thread and returns `result`.
+pub(crate) fn error(result: IrohResult, err: impl Display) -> IrohResult {
+    let msg = format!("{err:#}").replace('\0', "");
+    tracing::debug!(?result, "{msg}");
+    let msg = CString::new(msg).expect("NUL bytes removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+    result
+}
+
+/// Returns the message of the last error on this thread, or NULL if there was none.
+///
+/// For asynchronous operations the error is recorded on the thread invoking the callback,
+/// so it must be retrieved from within the callback.  The string is valid until the next
+/// error is recorded on the same thread.
+#[no_mangle]
+pub extern "C" fn iroh_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match *last.borrow() {
+        Some(ref msg) => msg.as_ptr(),
+        None => ptr::null(),
+    })
+}
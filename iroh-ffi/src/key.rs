@@ -0,0 +1,145 @@
+//! Secret keys and node IDs.
+//!
+//! Keys are passed as arrays of [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) bytes.
+
+use std::ffi::c_char;
+
+use iroh::key::{PublicKey, SecretKey};
+
+use crate::{error, read_key, read_str, write_key, IrohResult};
+
+/// The size of the buffer needed by [`iroh_node_id_to_string`], including the NUL byte.
+pub const IROH_NODE_ID_STR_LEN: usize = 53;
+
+/// Generates a new secret key and writes it to `out`.
+///
+/// # Safety
+///
+/// `out` must point to [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_secret_key_generate(out: *mut u8) -> IrohResult {
+    let secret_key = SecretKey::generate();
+    match write_key(out, &secret_key.to_bytes()) {
+        true => IrohResult::Ok,
+        false => error(IrohResult::InvalidArgument, "out is NULL"),
+    }
+}
+
+/// Writes the node ID, i.e. the public key, of `secret_key` to `out`.
+///
+/// # Safety
+///
+/// `secret_key` must point to [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) readable bytes and
+/// `out` to [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_secret_key_public(secret_key: *const u8, out: *mut u8) -> IrohResult {
+    let Some(secret_key) = read_key(secret_key) else {
+        return error(IrohResult::InvalidArgument, "secret_key is NULL");
+    };
+    let node_id = SecretKey::from_bytes(&secret_key).public();
+    match write_key(out, node_id.as_bytes()) {
+        true => IrohResult::Ok,
+        false => error(IrohResult::InvalidArgument, "out is NULL"),
+    }
+}
+
+/// Writes the string representation of `node_id` to `buf`, terminated by a NUL byte.
+///
+/// `buf_len` must be at least [`IROH_NODE_ID_STR_LEN`].
+///
+/// # Safety
+///
+/// `node_id` must point to [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) readable bytes and `buf`
+/// to `buf_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_node_id_to_string(
+    node_id: *const u8,
+    buf: *mut c_char,
+    buf_len: usize,
+) -> IrohResult {
+    let Some(node_id) = read_key(node_id) else {
+        return error(IrohResult::InvalidArgument, "node_id is NULL");
+    };
+    let node_id = match PublicKey::from_bytes(&node_id) {
+        Ok(node_id) => node_id,
+        Err(err) => return error(IrohResult::InvalidArgument, err),
+    };
+    let s = node_id.to_string();
+    if buf.is_null() || buf_len <= s.len() {
+        return error(IrohResult::InvalidArgument, "buf is too small");
+    }
+    std::ptr::copy_nonoverlapping(s.as_ptr().cast::<c_char>(), buf, s.len());
+    *buf.add(s.len()) = 0;
+    IrohResult::Ok
+}
+
+/// Parses the string representation of a node ID and writes the node ID to `out`.
+///
+/// # Safety
+///
+/// `s` must point to a NUL-terminated string and `out` to
+/// [`IROH_KEY_LEN`](crate::IROH_KEY_LEN) writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_node_id_from_string(s: *const c_char, out: *mut u8) -> IrohResult {
+    let s = match read_str(s) {
+        Ok(Some(s)) => s,
+        Ok(None) => return error(IrohResult::InvalidArgument, "s is NULL"),
+        Err(err) => return error(IrohResult::InvalidArgument, err),
+    };
+    let node_id: PublicKey = match s.parse() {
+        Ok(node_id) => node_id,
+        Err(err) => return error(IrohResult::InvalidArgument, err),
+    };
+    match write_key(out, node_id.as_bytes()) {
+        true => IrohResult::Ok,
+        false => error(IrohResult::InvalidArgument, "out is NULL"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+    use crate::IROH_KEY_LEN;
+
+    #[test]
+    fn test_node_id_string() {
+        let mut secret_key = [0u8; IROH_KEY_LEN];
+        let mut node_id = [0u8; IROH_KEY_LEN];
+        unsafe {
+            assert_eq!(
+                iroh_secret_key_generate(secret_key.as_mut_ptr()),
+                IrohResult::Ok
+            );
+            assert_eq!(
+                iroh_secret_key_public(secret_key.as_ptr(), node_id.as_mut_ptr()),
+                IrohResult::Ok
+            );
+        }
+        assert_eq!(
+            &node_id,
+            SecretKey::from_bytes(&secret_key).public().as_bytes()
+        );
+
+        let mut buf = [0 as c_char; IROH_NODE_ID_STR_LEN];
+        let mut parsed = [0u8; IROH_KEY_LEN];
+        unsafe {
+            assert_eq!(
+                iroh_node_id_to_string(node_id.as_ptr(), buf.as_mut_ptr(), buf.len() - 1),
+                IrohResult::InvalidArgument
+            );
+            assert_eq!(
+                iroh_node_id_to_string(node_id.as_ptr(), buf.as_mut_ptr(), buf.len()),
+                IrohResult::Ok
+            );
+            assert_eq!(
+                iroh_node_id_from_string(buf.as_ptr(), parsed.as_mut_ptr()),
+                IrohResult::Ok
+            );
+            let s = CStr::from_ptr(buf.as_ptr()).to_str().unwrap();
+            assert_eq!(s, PublicKey::from_bytes(&node_id).unwrap().to_string());
+        }
+        assert_eq!(parsed, node_id);
+    }
+}
@@ -0,0 +1,421 @@
+//! C bindings for iroh.
+//!
+//! This crate exposes a C ABI for the core of iroh, so that applications written in
+//! other languages, e.g. Swift, Kotlin or C++, can embed an iroh [`Endpoint`] without
+//! writing their own bindings.  The declarations are in `include/iroh.h`.
+//!
+//! The API covers:
+//!
+//! - Generating secret keys and deriving their node IDs, see [`iroh_secret_key_generate`].
+//! - Creating an endpoint, see [`iroh_endpoint_bind`].
+//! - Connecting to and accepting connections from other nodes, see
+//!   [`iroh_endpoint_connect`] and [`iroh_endpoint_accept`].
+//! - Opening bi-directional streams and reading from and writing to them, see
+//!   [`iroh_connection_open_bi`].
+//!
+//! # Asynchronous operations
+//!
+//! Operations which need to wait on the network take a callback and a `user_data` pointer.
+//! They return [`IrohResult::Ok`] if the operation was started, and later invoke the
+//! callback exactly once with the outcome and the unchanged `user_data`.  If they return an
+//! error the callback is never invoked.
+//!
+//! The operations run on a runtime owned by this library, so callbacks are invoked on its
+//! threads and must not block.  The `user_data` must be usable from these threads.
+//!
+//! # Errors
+//!
+//! Failing calls and callbacks report an [`IrohResult`], the error message is returned by
+//! [`iroh_last_error`] on the thread which reported the error.
+//!
+//! # Memory
+//!
+//! Objects returned by the library are owned by the caller and must be freed with the
+//! matching `_free` function, e.g. [`iroh_endpoint_free`].  Freeing an object does not
+//! cancel operations which are in progress, they still invoke their callbacks.
+//!
+//! [`Endpoint`]: iroh::Endpoint
+
+#![deny(missing_docs, rustdoc::broken_intra_doc_links)]
+#![cfg_attr(iroh_docsrs, feature(doc_cfg))]
+
+use std::{
+    ffi::{c_char, c_void, CStr},
+    future::Future,
+    sync::OnceLock,
+};
+
+mod connection;
+mod endpoint;
+mod error;
+mod key;
+mod stream;
+
+pub use self::{connection::*, endpoint::*, error::*, key::*, stream::*};
+
+/// The number of bytes of secret keys and node IDs.
+pub const IROH_KEY_LEN: usize = 32;
+
+/// The callback of asynchronous operations which do not return a value.
+pub type IrohCallback = extern "C" fn(user_data: *mut c_void, result: IrohResult);
+
+/// Returns the runtime all asynchronous operations run on.
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .thread_name("iroh-ffi")
+            .enable_all()
+            .build()
+            .expect("failed to start the runtime")
+    })
+}
+
+/// Runs `future` on the [`runtime`].
+fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    runtime().spawn(future);
+}
+
+/// The `user_data` pointer which is passed back to a callback.
+#[derive(Debug, Clone, Copy)]
+struct UserData(*mut c_void);
+
+// SAFETY: The pointer is never dereferenced, only passed back to the caller.  The caller
+// guarantees that the data it points to can be used from the threads of the runtime.
+unsafe impl Send for UserData {}
+
+impl UserData {
+    /// Returns the pointer.
+    ///
+    /// Closures must call this instead of accessing the field, so that they capture the
+    /// whole `Send` struct.
+    fn get(self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// Reads a key of [`IROH_KEY_LEN`] bytes, `None` if `ptr` is NULL.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to [`IROH_KEY_LEN`] readable bytes.
+unsafe fn read_key(ptr: *const u8) -> Option<[u8; IROH_KEY_LEN]> {
+    if ptr.is_null() {
+        return None;
+    }
+    let mut key = [0u8; IROH_KEY_LEN];
+    std::ptr::copy_nonoverlapping(ptr, key.as_mut_ptr(), IROH_KEY_LEN);
+    Some(key)
+}
+
+/// Writes a key of [`IROH_KEY_LEN`] bytes, returns `false` if `ptr` is NULL.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to [`IROH_KEY_LEN`] writable bytes.
+unsafe fn write_key(ptr: *mut u8, key: &[u8; IROH_KEY_LEN]) -> bool {
+    if ptr.is_null() {
+        return false;
+    }
+    std::ptr::copy_nonoverlapping(key.as_ptr(), ptr, IROH_KEY_LEN);
+    true
+}
+
+/// Returns the bytes at `ptr`, `None` if `ptr` is NULL and `len` is not zero.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to `len` readable bytes which outlive `'a`.
+unsafe fn read_bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match len {
+        0 => Some(&[]),
+        _ if ptr.is_null() => None,
+        _ => Some(std::slice::from_raw_parts(ptr, len)),
+    }
+}
+
+/// Returns the string at `ptr`, `Ok(None)` if `ptr` is NULL.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to a NUL-terminated string which outlives `'a`.
+unsafe fn read_str<'a>(ptr: *const c_char) -> anyhow::Result<Option<&'a str>> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(CStr::from_ptr(ptr).to_str()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CString,
+        net::{Ipv4Addr, SocketAddr},
+        ptr,
+        sync::mpsc,
+        time::Duration,
+    };
+
+    use super::*;
+
+    const TEST_ALPN: &[u8] = b"n0/iroh-ffi/test";
+
+    /// The outcome of an asynchronous operation, as reported to its callback.
+    #[derive(Debug)]
+    enum Event {
+        Done(IrohResult),
+        Endpoint(IrohResult, *mut IrohEndpoint),
+        Connection(IrohResult, *mut IrohConnection),
+        Streams(IrohResult, *mut IrohSendStream, *mut IrohRecvStream),
+        Read(IrohResult, Vec<u8>),
+    }
+
+    // SAFETY: The pointers are only used by the test thread.
+    unsafe impl Send for Event {}
+
+    type Events = mpsc::Sender<Event>;
+
+    fn send(user_data: *mut c_void, event: Event) {
+        let events = unsafe { &*(user_data as *const Events) };
+        events.send(event).unwrap();
+    }
+
+    extern "C" fn on_done(user_data: *mut c_void, result: IrohResult) {
+        send(user_data, Event::Done(result));
+    }
+
+    extern "C" fn on_endpoint(
+        user_data: *mut c_void,
+        result: IrohResult,
+        endpoint: *mut IrohEndpoint,
+    ) {
+        send(user_data, Event::Endpoint(result, endpoint));
+    }
+
+    extern "C" fn on_connection(
+        user_data: *mut c_void,
+        result: IrohResult,
+        connection: *mut IrohConnection,
+    ) {
+        send(user_data, Event::Connection(result, connection));
+    }
+
+    extern "C" fn on_streams(
+        user_data: *mut c_void,
+        result: IrohResult,
+        send_stream: *mut IrohSendStream,
+        recv_stream: *mut IrohRecvStream,
+    ) {
+        send(user_data, Event::Streams(result, send_stream, recv_stream));
+    }
+
+    extern "C" fn on_read(user_data: *mut c_void, result: IrohResult, data: *const u8, len: usize) {
+        let data = unsafe { read_bytes(data, len) }.unwrap().to_vec();
+        send(user_data, Event::Read(result, data));
+    }
+
+    /// Receives the events of the callbacks invoked with its `user_data`.
+    struct Waiter {
+        events: Box<Events>,
+        receiver: mpsc::Receiver<Event>,
+    }
+
+    impl Waiter {
+        fn new() -> Self {
+            let (sender, receiver) = mpsc::channel();
+            Self {
+                events: Box::new(sender),
+                receiver,
+            }
+        }
+
+        fn user_data(&self) -> *mut c_void {
+            &*self.events as *const Events as *mut c_void
+        }
+
+        fn next(&self) -> Event {
+            self.receiver.recv_timeout(Duration::from_secs(10)).unwrap()
+        }
+
+        fn done(&self) {
+            match self.next() {
+                Event::Done(IrohResult::Ok) => (),
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+
+        fn endpoint(&self) -> *mut IrohEndpoint {
+            match self.next() {
+                Event::Endpoint(IrohResult::Ok, endpoint) => endpoint,
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+
+        fn connection(&self) -> *mut IrohConnection {
+            match self.next() {
+                Event::Connection(IrohResult::Ok, connection) => connection,
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+
+        fn streams(&self) -> (*mut IrohSendStream, *mut IrohRecvStream) {
+            match self.next() {
+                Event::Streams(IrohResult::Ok, send, recv) => (send, recv),
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+
+        fn read_to_end(&self, stream: *mut IrohRecvStream) -> Vec<u8> {
+            let mut buf = Vec::new();
+            loop {
+                let res = unsafe { iroh_recv_stream_read(stream, 1024, on_read, self.user_data()) };
+                assert_eq!(res, IrohResult::Ok);
+                match self.next() {
+                    Event::Read(IrohResult::Ok, data) if data.is_empty() => return buf,
+                    Event::Read(IrohResult::Ok, data) => buf.extend(data),
+                    event => panic!("unexpected {event:?}"),
+                }
+            }
+        }
+    }
+
+    fn bind(waiter: &Waiter, alpn: &[u8]) -> *mut IrohEndpoint {
+        let config = IrohEndpointConfig {
+            secret_key: ptr::null(),
+            alpn: alpn.as_ptr(),
+            alpn_len: alpn.len(),
+            relay_mode: IrohRelayMode::Disabled as u32,
+        };
+        let res = unsafe { iroh_endpoint_bind(&config, on_endpoint, waiter.user_data()) };
+        assert_eq!(res, IrohResult::Ok);
+        waiter.endpoint()
+    }
+
+    fn write_all(waiter: &Waiter, stream: *mut IrohSendStream, data: &[u8]) {
+        let res = unsafe {
+            iroh_send_stream_write(
+                stream,
+                data.as_ptr(),
+                data.len(),
+                on_done,
+                waiter.user_data(),
+            )
+        };
+        assert_eq!(res, IrohResult::Ok);
+        waiter.done();
+        let res = unsafe { iroh_send_stream_finish(stream, on_done, waiter.user_data()) };
+        assert_eq!(res, IrohResult::Ok);
+        waiter.done();
+    }
+
+    #[test]
+    fn test_echo() {
+        let _guard = iroh_test::logging::setup();
+        let server_waiter = Waiter::new();
+        let client_waiter = Waiter::new();
+        let server = bind(&server_waiter, TEST_ALPN);
+        let client = bind(&client_waiter, &[]);
+
+        let mut server_id = [0u8; IROH_KEY_LEN];
+        let res = unsafe { iroh_endpoint_node_id(server, server_id.as_mut_ptr()) };
+        assert_eq!(res, IrohResult::Ok);
+        let port = unsafe { &*server }
+            .endpoint
+            .bound_sockets()
+            .0
+            .unwrap()
+            .port();
+        let server_addr =
+            CString::new(SocketAddr::from((Ipv4Addr::LOCALHOST, port)).to_string()).unwrap();
+
+        let res = unsafe { iroh_endpoint_accept(server, on_connection, server_waiter.user_data()) };
+        assert_eq!(res, IrohResult::Ok);
+        let res = unsafe {
+            iroh_endpoint_connect(
+                client,
+                server_id.as_ptr(),
+                ptr::null(),
+                &server_addr.as_ptr(),
+                1,
+                TEST_ALPN.as_ptr(),
+                TEST_ALPN.len(),
+                on_connection,
+                client_waiter.user_data(),
+            )
+        };
+        assert_eq!(res, IrohResult::Ok);
+        let client_conn = client_waiter.connection();
+        let server_conn = server_waiter.connection();
+
+        let mut client_id = [0u8; IROH_KEY_LEN];
+        let mut remote_id = [0u8; IROH_KEY_LEN];
+        unsafe {
+            assert_eq!(
+                iroh_endpoint_node_id(client, client_id.as_mut_ptr()),
+                IrohResult::Ok
+            );
+            assert_eq!(
+                iroh_connection_remote_node_id(server_conn, remote_id.as_mut_ptr()),
+                IrohResult::Ok
+            );
+        }
+        assert_eq!(client_id, remote_id);
+
+        let res =
+            unsafe { iroh_connection_open_bi(client_conn, on_streams, client_waiter.user_data()) };
+        assert_eq!(res, IrohResult::Ok);
+        let (client_send, client_recv) = client_waiter.streams();
+        write_all(&client_waiter, client_send, b"hello");
+
+        let res = unsafe {
+            iroh_connection_accept_bi(server_conn, on_streams, server_waiter.user_data())
+        };
+        assert_eq!(res, IrohResult::Ok);
+        let (server_send, server_recv) = server_waiter.streams();
+        let msg = server_waiter.read_to_end(server_recv);
+        assert_eq!(msg, b"hello");
+        write_all(&server_waiter, server_send, &msg);
+        assert_eq!(client_waiter.read_to_end(client_recv), b"hello");
+
+        unsafe {
+            iroh_send_stream_free(client_send);
+            iroh_recv_stream_free(client_recv);
+            iroh_send_stream_free(server_send);
+            iroh_recv_stream_free(server_recv);
+            iroh_connection_close(client_conn, 0, ptr::null(), 0);
+            iroh_connection_free(client_conn);
+            iroh_connection_free(server_conn);
+            assert_eq!(
+                iroh_endpoint_close(client, on_done, client_waiter.user_data()),
+                IrohResult::Ok
+            );
+            assert_eq!(
+                iroh_endpoint_close(server, on_done, server_waiter.user_data()),
+                IrohResult::Ok
+            );
+        }
+        client_waiter.done();
+        server_waiter.done();
+        unsafe {
+            iroh_endpoint_free(client);
+            iroh_endpoint_free(server);
+        }
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let waiter = Waiter::new();
+        let res = unsafe { iroh_endpoint_bind(ptr::null(), on_endpoint, waiter.user_data()) };
+        assert_eq!(res, IrohResult::InvalidArgument);
+        assert!(!iroh_last_error().is_null());
+
+        let config = IrohEndpointConfig {
+            secret_key: ptr::null(),
+            alpn: TEST_ALPN.as_ptr(),
+            alpn_len: TEST_ALPN.len(),
+            relay_mode: 2,
+        };
+        let res = unsafe { iroh_endpoint_bind(&config, on_endpoint, waiter.user_data()) };
+        assert_eq!(res, IrohResult::InvalidArgument);
+    }
+}
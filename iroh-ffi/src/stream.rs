@@ -0,0 +1,207 @@
+//! Reading from and writing to streams.
+//!
+//! Each stream is driven by a task which performs the operations in the order they were
+//! started.
+
+use std::{ffi::c_void, ptr};
+
+use iroh::endpoint::{RecvStream, SendStream};
+use tokio::sync::mpsc;
+
+use crate::{error, read_bytes, spawn, IrohCallback, IrohResult, UserData};
+
+/// The sending half of a stream, see [`iroh::endpoint::SendStream`].
+///
+/// Created by [`iroh_connection_open_bi`] or [`iroh_connection_accept_bi`] and freed by
+/// [`iroh_send_stream_free`].
+///
+/// [`iroh_connection_open_bi`]: crate::iroh_connection_open_bi
+/// [`iroh_connection_accept_bi`]: crate::iroh_connection_accept_bi
+#[derive(Debug)]
+pub struct IrohSendStream {
+    ops: mpsc::UnboundedSender<SendOp>,
+}
+
+/// An operation on an [`IrohSendStream`].
+#[derive(Debug)]
+enum SendOp {
+    Write(Vec<u8>, IrohCallback, UserData),
+    Finish(IrohCallback, UserData),
+}
+
+impl IrohSendStream {
+    pub(crate) fn new(mut stream: SendStream) -> Self {
+        let (ops, mut ops_rx) = mpsc::unbounded_channel();
+        spawn(async move {
+            while let Some(op) = ops_rx.recv().await {
+                let (res, callback, user_data) = match op {
+                    SendOp::Write(data, callback, user_data) => {
+                        let res = stream.write_all(&data).await.map_err(anyhow::Error::from);
+                        (res, callback, user_data)
+                    }
+                    SendOp::Finish(callback, user_data) => {
+                        let res = stream.finish().map_err(anyhow::Error::from);
+                        (res, callback, user_data)
+                    }
+                };
+                match res {
+                    Ok(()) => callback(user_data.get(), IrohResult::Ok),
+                    Err(err) => callback(user_data.get(), error(IrohResult::Closed, err)),
+                }
+            }
+        });
+        Self { ops }
+    }
+
+    fn send(&self, op: SendOp) {
+        // The task only stops once the stream is freed.
+        self.ops.send(op).ok();
+    }
+}
+
+/// The receiving half of a stream, see [`iroh::endpoint::RecvStream`].
+///
+/// Created by [`iroh_connection_open_bi`] or [`iroh_connection_accept_bi`] and freed by
+/// [`iroh_recv_stream_free`].
+///
+/// [`iroh_connection_open_bi`]: crate::iroh_connection_open_bi
+/// [`iroh_connection_accept_bi`]: crate::iroh_connection_accept_bi
+#[derive(Debug)]
+pub struct IrohRecvStream {
+    reads: mpsc::UnboundedSender<(usize, IrohReadCallback, UserData)>,
+}
+
+impl IrohRecvStream {
+    pub(crate) fn new(mut stream: RecvStream) -> Self {
+        let (reads, mut reads_rx) =
+            mpsc::unbounded_channel::<(usize, IrohReadCallback, UserData)>();
+        spawn(async move {
+            while let Some((max_len, callback, user_data)) = reads_rx.recv().await {
+                match stream.read_chunk(max_len, true).await {
+                    Ok(Some(chunk)) => callback(
+                        user_data.get(),
+                        IrohResult::Ok,
+                        chunk.bytes.as_ptr(),
+                        chunk.bytes.len(),
+                    ),
+                    Ok(None) => callback(user_data.get(), IrohResult::Ok, ptr::null(), 0),
+                    Err(err) => {
+                        let result = error(IrohResult::Closed, err);
+                        callback(user_data.get(), result, ptr::null(), 0);
+                    }
+                }
+            }
+        });
+        Self { reads }
+    }
+}
+
+/// The callback of [`iroh_recv_stream_read`].
+///
+/// The `data` of `len` bytes is only valid for the duration of the callback.  A `len` of
+/// zero with [`IrohResult::Ok`] means the stream was finished by the remote node.
+pub type IrohReadCallback =
+    extern "C" fn(user_data: *mut c_void, result: IrohResult, data: *const u8, len: usize);
+
+/// Writes all of `data` to the stream.
+///
+/// The data is copied, so it only needs to be valid for the duration of the call.  Writes
+/// are performed in the order they are started.  The `callback` is invoked once all data
+/// was handed to the connection.
+///
+/// # Safety
+///
+/// `stream` must be a valid send stream and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_send_stream_write(
+    stream: *const IrohSendStream,
+    data: *const u8,
+    len: usize,
+    callback: IrohCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(stream) = stream.as_ref() else {
+        return error(IrohResult::InvalidArgument, "stream is NULL");
+    };
+    let Some(data) = read_bytes(data, len) else {
+        return error(IrohResult::InvalidArgument, "data is NULL");
+    };
+    stream.send(SendOp::Write(data.to_vec(), callback, UserData(user_data)));
+    IrohResult::Ok
+}
+
+/// Finishes the stream, no more data can be written to it.
+///
+/// The `callback` is invoked once all writes started before are done and the stream is
+/// finished.
+///
+/// # Safety
+///
+/// `stream` must be a valid send stream.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_send_stream_finish(
+    stream: *const IrohSendStream,
+    callback: IrohCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(stream) = stream.as_ref() else {
+        return error(IrohResult::InvalidArgument, "stream is NULL");
+    };
+    stream.send(SendOp::Finish(callback, UserData(user_data)));
+    IrohResult::Ok
+}
+
+/// Frees a send stream.
+///
+/// A stream which is not finished yet is finished when it is freed.
+///
+/// # Safety
+///
+/// `stream` must be NULL or a valid send stream, which must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_send_stream_free(stream: *mut IrohSendStream) {
+    if !stream.is_null() {
+        drop(Box::from_raw(stream));
+    }
+}
+
+/// Reads up to `max_len` bytes from the stream.
+///
+/// The `callback` receives the data as soon as some is available.  Reads are performed in
+/// the order they are started.
+///
+/// # Safety
+///
+/// `stream` must be a valid receive stream.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_recv_stream_read(
+    stream: *const IrohRecvStream,
+    max_len: usize,
+    callback: IrohReadCallback,
+    user_data: *mut c_void,
+) -> IrohResult {
+    let Some(stream) = stream.as_ref() else {
+        return error(IrohResult::InvalidArgument, "stream is NULL");
+    };
+    if max_len == 0 {
+        return error(IrohResult::InvalidArgument, "max_len is zero");
+    }
+    // The task only stops once the stream is freed.
+    stream
+        .reads
+        .send((max_len, callback, UserData(user_data)))
+        .ok();
+    IrohResult::Ok
+}
+
+/// Frees a receive stream.
+///
+/// # Safety
+///
+/// `stream` must be NULL or a valid receive stream, which must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn iroh_recv_stream_free(stream: *mut IrohRecvStream) {
+    if !stream.is_null() {
+        drop(Box::from_raw(stream));
+    }
+}
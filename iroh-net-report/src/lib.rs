@@ -8,17 +8,19 @@
 use std::{
     collections::{BTreeMap, HashMap},
     fmt::{self, Debug},
+    future::Future,
+    io,
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    pin::Pin,
     sync::Arc,
 };
 
 use anyhow::{anyhow, Context as _, Result};
 use bytes::Bytes;
-use hickory_resolver::TokioAsyncResolver as DnsResolver;
 use iroh_base::relay_map::{RelayMap, RelayNode, RelayUrl};
 #[cfg(feature = "metrics")]
 use iroh_metrics::inc;
-use iroh_relay::protos::stun;
+use iroh_relay::{dns::DnsResolver, protos::stun, quic::QuicClient};
 use netwatch::{IpFamily, UdpSocket};
 use tokio::{
     sync::{self, mpsc, oneshot},
@@ -46,12 +48,35 @@ const FULL_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
 /// default which will never be used.
 const DEFAULT_MAX_LATENCY: Duration = Duration::from_millis(100);
 
+/// A UDP socket to send STUN probes from.
+///
+/// The socket is only sent on, responses must be passed to
+/// [`Addr::receive_stun_packet`], see [`Client::get_report`].
+pub trait StunSocket: Debug + Send + Sync + 'static {
+    /// Sends `buf` to `addr`, returning the number of bytes sent.
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = io::Result<usize>> + Send + 'a>>;
+}
+
+impl StunSocket for UdpSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = io::Result<usize>> + Send + 'a>> {
+        Box::pin(UdpSocket::send_to(self, buf, addr))
+    }
+}
+
 /// A net_report report.
 ///
 /// Can be obtained by calling [`Client::get_report`].
 #[derive(Default, Debug, PartialEq, Eq, Clone)]
 pub struct Report {
-    /// A UDP STUN round trip completed.
+    /// A UDP STUN or QUIC address discovery round trip completed.
     pub udp: bool,
     /// An IPv6 STUN round trip completed.
     pub ipv6: bool,
@@ -69,6 +94,13 @@ pub struct Report {
     pub icmpv6: Option<bool>,
     /// Whether STUN results depend on which STUN server you're talking to (on IPv4).
     pub mapping_varies_by_dest_ip: Option<bool>,
+    /// Whether the mapped address depends on the port of the server you're talking to (on
+    /// IPv4).
+    ///
+    /// This compares the addresses observed by the STUN and the QUIC address discovery
+    /// servers of the same relay server, which listen on the same IP address.  `None` if no
+    /// relay server answered both.
+    pub mapping_varies_by_dest_port: Option<bool>,
     /// Whether STUN results depend on which STUN server you're talking to (on IPv6).
     ///
     /// Note that we don't really expect this to happen and are merely logging this if
@@ -93,15 +125,105 @@ pub struct Report {
     pub global_v6: Option<SocketAddrV6>,
     /// CaptivePortal is set when we think there's a captive portal that is
     /// intercepting HTTP traffic.
+    ///
+    /// See [`Report::portal`] for how this is checked.
     pub captive_portal: Option<bool>,
 }
 
+impl Report {
+    /// Classifies the IPv4 NAT mapping behaviour, as described in [RFC 4787].
+    ///
+    /// Returns `None` if the report does not contain enough results to tell, which needs
+    /// responses from at least two relay servers, or from both the STUN and the QUIC
+    /// address discovery server of one relay server.
+    ///
+    /// How the NAT filters incoming packets can not be detected using relay servers, but
+    /// NATs with endpoint-independent mapping are usually traversable by hole punching.
+    /// Whether two devices behind the same NAT can reach each other using their public
+    /// address is reported in [`Report::hair_pinning`].
+    ///
+    /// [RFC 4787]: https://www.rfc-editor.org/rfc/rfc4787#section-4.1
+    pub fn nat_mapping(&self) -> Option<NatMapping> {
+        match (
+            self.mapping_varies_by_dest_ip,
+            self.mapping_varies_by_dest_port,
+        ) {
+            (_, Some(true)) => Some(NatMapping::AddressAndPortDependent),
+            (Some(true), _) => Some(NatMapping::AddressDependent),
+            (Some(false), _) => Some(NatMapping::EndpointIndependent),
+            (None, _) => None,
+        }
+    }
+
+    /// Whether the network requires signing in at a captive portal before it can be used.
+    ///
+    /// Captive portals are detected with a plain HTTP request to the `/generate_204`
+    /// endpoint of a relay server, which answers with `204 No Content` and echoes a
+    /// challenge header.  Any other response means the request was intercepted.  The check
+    /// is only made on full reports, and it is abandoned once UDP probes succeeded, because
+    /// captive portals block UDP as well.
+    ///
+    /// Returns `None` if the check did not complete and UDP did not work either.
+    pub fn portal(&self) -> Option<Portal> {
+        match self.captive_portal {
+            Some(true) => Some(Portal::Detected),
+            Some(false) => Some(Portal::NotDetected),
+            None if self.udp => Some(Portal::NotDetected),
+            None => None,
+        }
+    }
+}
+
 impl fmt::Display for Report {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Debug::fmt(&self, f)
     }
 }
 
+/// How a NAT maps the local address to a public address, see [`Report::nat_mapping`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, derive_more::Display)]
+pub enum NatMapping {
+    /// The same public address is used for all destinations.
+    ///
+    /// This is also the case when there is no NAT at all.
+    #[display("endpoint-independent")]
+    EndpointIndependent,
+    /// The public address depends on the destination IP address.
+    #[display("address-dependent")]
+    AddressDependent,
+    /// The public address depends on the destination IP address and port.
+    ///
+    /// This is often called a symmetric NAT.
+    #[display("address-and-port-dependent")]
+    AddressAndPortDependent,
+}
+
+impl NatMapping {
+    /// Whether hole punching is likely to succeed between NATs with these mappings.
+    ///
+    /// The address a node learns from relay servers is only useful to a peer if its NAT
+    /// uses the same public address for that peer, so at least one of the two NATs must
+    /// have endpoint-independent mapping.  When this returns `false` connections are likely
+    /// to stay relayed.
+    pub fn hole_punching_likely(self, remote: NatMapping) -> bool {
+        self == NatMapping::EndpointIndependent || remote == NatMapping::EndpointIndependent
+    }
+}
+
+/// Whether a captive portal was detected, see [`Report::portal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, derive_more::Display)]
+pub enum Portal {
+    /// Network access is intercepted by a captive portal.
+    ///
+    /// The user usually needs to open a web browser to sign in or accept terms of use
+    /// before relay servers and other nodes can be reached.
+    #[display("detected")]
+    Detected,
+    /// No captive portal is intercepting network access.
+    #[display("not detected")]
+    NotDetected,
+}
+
 /// Latencies per relay node.
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct RelayLatencies(BTreeMap<RelayUrl, Duration>);
@@ -148,15 +270,106 @@ impl RelayLatencies {
         self.0.len()
     }
 
-    fn is_empty(&self) -> bool {
+    /// Returns whether no relay latency was measured.
+    pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
 
-    fn get(&self, url: &RelayUrl) -> Option<Duration> {
+    /// Returns the latency measured for the relay at `url`, if any.
+    pub fn get(&self, url: &RelayUrl) -> Option<Duration> {
         self.0.get(url).copied()
     }
 }
 
+/// Configures which probes are run for a report, and how often.
+///
+/// By default all probes are enabled, a full report is made every 5 minutes and any number
+/// of probes may run at the same time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    stun_ipv4: bool,
+    stun_ipv6: bool,
+    quic: bool,
+    https: bool,
+    icmp: bool,
+    full_report_interval: Duration,
+    max_concurrent_probes: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            stun_ipv4: true,
+            stun_ipv6: true,
+            quic: true,
+            https: true,
+            icmp: true,
+            full_report_interval: FULL_REPORT_INTERVAL,
+            max_concurrent_probes: None,
+        }
+    }
+}
+
+impl Config {
+    /// Sets whether to send STUN probes over IPv4, by default `true`.
+    pub fn stun_ipv4(mut self, enabled: bool) -> Self {
+        self.stun_ipv4 = enabled;
+        self
+    }
+
+    /// Sets whether to send STUN probes over IPv6, by default `true`.
+    pub fn stun_ipv6(mut self, enabled: bool) -> Self {
+        self.stun_ipv6 = enabled;
+        self
+    }
+
+    /// Sets whether to run QUIC address discovery probes, by default `true`.
+    ///
+    /// These are only run if a [`QuicClient`] is passed to [`Client::get_report`].
+    pub fn quic(mut self, enabled: bool) -> Self {
+        self.quic = enabled;
+        self
+    }
+
+    /// Sets whether to measure the latency to relay servers over HTTPS, by default `true`.
+    ///
+    /// The HTTPS probes only start after the UDP probes had a chance to complete, they are
+    /// used when UDP is blocked.
+    pub fn https(mut self, enabled: bool) -> Self {
+        self.https = enabled;
+        self
+    }
+
+    /// Sets whether to ping relay servers with ICMP, by default `true`.
+    ///
+    /// Like the HTTPS probes these are used when UDP is blocked.  ICMP probes need raw or
+    /// ICMP sockets, which some security tooling flags, disabling them ensures no such
+    /// sockets are ever opened.
+    pub fn icmp(mut self, enabled: bool) -> Self {
+        self.icmp = enabled;
+        self
+    }
+
+    /// Sets how often a full report is made, by default every 5 minutes.
+    ///
+    /// A full report probes all relay servers and checks for a captive portal.  Reports in
+    /// between are incremental, they only probe the relay servers which were fastest in
+    /// the previous report.  The [`Client`] only makes reports when asked to, how often
+    /// that happens is up to its user.
+    pub fn full_report_interval(mut self, interval: Duration) -> Self {
+        self.full_report_interval = interval;
+        self
+    }
+
+    /// Sets how many probes may run at the same time, by default this is not limited.
+    ///
+    /// Probes which would exceed the limit wait for a running probe to finish first.
+    pub fn max_concurrent_probes(mut self, max: usize) -> Self {
+        self.max_concurrent_probes = Some(max);
+        self
+    }
+}
+
 /// Client to run net_reports.
 ///
 /// Creating this creates a net_report actor which runs in the background.  Most of the time
@@ -207,7 +420,18 @@ impl Client {
     /// This starts a connected actor in the background.  Once the client is dropped it will
     /// stop running.
     pub fn new(port_mapper: Option<portmapper::Client>, dns_resolver: DnsResolver) -> Result<Self> {
-        let mut actor = Actor::new(port_mapper, dns_resolver)?;
+        Self::with_config(port_mapper, dns_resolver, Config::default())
+    }
+
+    /// Creates a new net_report client with a custom [`Config`].
+    ///
+    /// See [`Client::new`] for details.
+    pub fn with_config(
+        port_mapper: Option<portmapper::Client>,
+        dns_resolver: DnsResolver,
+        config: Config,
+    ) -> Result<Self> {
+        let mut actor = Actor::new(port_mapper, dns_resolver, config)?;
         let addr = actor.addr();
         let task = tokio::spawn(
             async move { actor.run().await }.instrument(info_span!("net_report.actor")),
@@ -240,13 +464,21 @@ impl Client {
     ///
     /// If these are not passed in this will bind sockets for STUN itself, though results
     /// may not be as reliable.
+    ///
+    /// The *quic_client* is used to run QUIC address discovery probes against the relay
+    /// servers which support it.  Like the STUN sockets its endpoint should send from the
+    /// sockets carrying the real traffic.  If it is not passed in no QUIC address discovery
+    /// probes are run.
     pub async fn get_report(
         &mut self,
         dm: RelayMap,
-        stun_conn4: Option<Arc<UdpSocket>>,
-        stun_conn6: Option<Arc<UdpSocket>>,
+        stun_conn4: Option<Arc<dyn StunSocket>>,
+        stun_conn6: Option<Arc<dyn StunSocket>>,
+        quic_client: Option<QuicClient>,
     ) -> Result<Arc<Report>> {
-        let rx = self.get_report_channel(dm, stun_conn4, stun_conn6).await?;
+        let rx = self
+            .get_report_channel(dm, stun_conn4, stun_conn6, quic_client)
+            .await?;
         match rx.await {
             Ok(res) => res,
             Err(_) => Err(anyhow!("channel closed, actor awol")),
@@ -257,8 +489,9 @@ impl Client {
     pub async fn get_report_channel(
         &mut self,
         dm: RelayMap,
-        stun_conn4: Option<Arc<UdpSocket>>,
-        stun_conn6: Option<Arc<UdpSocket>>,
+        stun_conn4: Option<Arc<dyn StunSocket>>,
+        stun_conn6: Option<Arc<dyn StunSocket>>,
+        quic_client: Option<QuicClient>,
     ) -> Result<oneshot::Receiver<Result<Arc<Report>>>> {
         // TODO: consider if RelayMap should be made to easily clone?  It seems expensive
         // right now.
@@ -268,6 +501,7 @@ impl Client {
                 relay_map: dm,
                 stun_sock_v4: stun_conn4,
                 stun_sock_v6: stun_conn6,
+                quic_client,
                 response_tx: tx,
             })
             .await?;
@@ -302,11 +536,15 @@ pub(crate) enum Message {
         /// other packets from in the magicsocket (`MagicSock`).
         ///
         /// If not provided this will attempt to bind a suitable socket itself.
-        stun_sock_v4: Option<Arc<UdpSocket>>,
+        stun_sock_v4: Option<Arc<dyn StunSocket>>,
         /// Socket to send IPv6 STUN probes from.
         ///
         /// Like `stun_sock_v4` but for IPv6.
-        stun_sock_v6: Option<Arc<UdpSocket>>,
+        stun_sock_v6: Option<Arc<dyn StunSocket>>,
+        /// Client to run QUIC address discovery probes with.
+        ///
+        /// If not provided no QUIC address discovery probes are run.
+        quic_client: Option<QuicClient>,
         /// Channel to receive the response.
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     },
@@ -405,6 +643,8 @@ struct Actor {
 
     /// The DNS resolver to use for probes that need to perform DNS lookups
     dns_resolver: DnsResolver,
+    /// Which probes to run and how often to make full reports.
+    config: Config,
 }
 
 impl Actor {
@@ -412,7 +652,11 @@ impl Actor {
     ///
     /// This does not start the actor, see [`Actor::run`] for this.  You should not
     /// normally create this directly but rather create a [`Client`].
-    fn new(port_mapper: Option<portmapper::Client>, dns_resolver: DnsResolver) -> Result<Self> {
+    fn new(
+        port_mapper: Option<portmapper::Client>,
+        dns_resolver: DnsResolver,
+        config: Config,
+    ) -> Result<Self> {
         // TODO: consider an instrumented flume channel so we have metrics.
         let (sender, receiver) = mpsc::channel(32);
         Ok(Self {
@@ -423,6 +667,7 @@ impl Actor {
             in_flight_stun_requests: Default::default(),
             current_report_run: None,
             dns_resolver,
+            config,
         })
     }
 
@@ -446,9 +691,16 @@ impl Actor {
                     relay_map,
                     stun_sock_v4,
                     stun_sock_v6,
+                    quic_client,
                     response_tx,
                 } => {
-                    self.handle_run_check(relay_map, stun_sock_v4, stun_sock_v6, response_tx);
+                    self.handle_run_check(
+                        relay_map,
+                        stun_sock_v4,
+                        stun_sock_v6,
+                        quic_client,
+                        response_tx,
+                    );
                 }
                 Message::ReportReady { report } => {
                     self.handle_report_ready(report);
@@ -474,8 +726,9 @@ impl Actor {
     fn handle_run_check(
         &mut self,
         relay_map: RelayMap,
-        stun_sock_v4: Option<Arc<UdpSocket>>,
-        stun_sock_v6: Option<Arc<UdpSocket>>,
+        stun_sock_v4: Option<Arc<dyn StunSocket>>,
+        stun_sock_v6: Option<Arc<dyn StunSocket>>,
+        quic_client: Option<QuicClient>,
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     ) {
         if self.current_report_run.is_some() {
@@ -499,7 +752,7 @@ impl Actor {
             None => bind_local_stun_socket(IpFamily::V6, self.addr(), cancel_token.clone()),
         };
         let mut do_full = self.reports.next_full
-            || now.duration_since(self.reports.last_full) > FULL_REPORT_INTERVAL;
+            || now.duration_since(self.reports.last_full) > self.config.full_report_interval;
 
         // If the last report had a captive portal and reported no UDP access,
         // it's possible that we didn't get a useful net_report due to the
@@ -526,7 +779,9 @@ impl Actor {
             relay_map,
             stun_sock_v4,
             stun_sock_v6,
+            quic_client,
             self.dns_resolver.clone(),
+            self.config.clone(),
         );
 
         self.current_report_run = Some(ReportRun {
@@ -713,7 +968,7 @@ fn bind_local_stun_socket(
     network: IpFamily,
     actor_addr: Addr,
     cancel_token: CancellationToken,
-) -> Option<Arc<UdpSocket>> {
+) -> Option<Arc<dyn StunSocket>> {
     let sock = match UdpSocket::bind(network, 0) {
         Ok(sock) => Arc::new(sock),
         Err(err) => {
@@ -792,12 +1047,14 @@ mod test_utils {
             .expect("should serve relay");
         let quic = Some(QuicConfig {
             port: server.quic_addr().expect("server should run quic").port(),
+            relay: false,
         });
         let node_desc = RelayNode {
             url: server.https_url().expect("should work as relay"),
             stun_only: false, // the checks above and below guarantee both stun and relay
             stun_port: server.stun_addr().expect("server should serve stun").port(),
             quic,
+            auth_token: None,
         };
 
         (server, Arc::new(node_desc))
@@ -883,6 +1140,7 @@ mod tests {
                     stun_port: port,
                     stun_only,
                     quic: None,
+                    auth_token: None,
                 }
             });
             RelayMap::from_nodes(nodes).expect("generated invalid nodes")
@@ -975,7 +1233,7 @@ mod tests {
         // Note that the ProbePlan will change with each iteration.
         for i in 0..5 {
             println!("--round {}", i);
-            let r = client.get_report(dm.clone(), None, None).await?;
+            let r = client.get_report(dm.clone(), None, None, None).await?;
 
             assert!(r.udp, "want UDP");
             assert_eq!(
@@ -1002,6 +1260,77 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_config() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let (stun_addr, stun_stats, _cleanup_guard) =
+            stun_utils::serve("127.0.0.1".parse().unwrap()).await?;
+        let resolver = crate::dns::tests::resolver();
+        let dm = stun_utils::relay_map_of([stun_addr].into_iter());
+
+        // Without STUN and the fallback probes nothing is sent.
+        let config = Config::default().stun_ipv4(false).https(false).icmp(false);
+        let mut client = Client::with_config(None, resolver.clone(), config)?;
+        let r = client.get_report(dm.clone(), None, None, None).await?;
+        assert!(!r.udp);
+        assert_eq!(r.icmpv4, None);
+        assert!(r.relay_latency.is_empty());
+        assert_eq!(stun_stats.total().await, 0);
+
+        // A single probe at a time still completes the report.
+        let config = Config::default().max_concurrent_probes(1);
+        let mut client = Client::with_config(None, resolver, config)?;
+        let r = client.get_report(dm, None, None, None).await?;
+        assert!(r.udp);
+        assert!(r.global_v4.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nat_mapping() {
+        let report = |by_ip, by_port| Report {
+            mapping_varies_by_dest_ip: by_ip,
+            mapping_varies_by_dest_port: by_port,
+            ..Default::default()
+        };
+        assert_eq!(report(None, None).nat_mapping(), None);
+        assert_eq!(report(None, Some(false)).nat_mapping(), None);
+        assert_eq!(
+            report(Some(false), None).nat_mapping(),
+            Some(NatMapping::EndpointIndependent)
+        );
+        assert_eq!(
+            report(Some(true), Some(false)).nat_mapping(),
+            Some(NatMapping::AddressDependent)
+        );
+        assert_eq!(
+            report(None, Some(true)).nat_mapping(),
+            Some(NatMapping::AddressAndPortDependent)
+        );
+
+        assert!(NatMapping::EndpointIndependent
+            .hole_punching_likely(NatMapping::AddressAndPortDependent));
+        assert!(
+            !NatMapping::AddressDependent.hole_punching_likely(NatMapping::AddressAndPortDependent)
+        );
+    }
+
+    #[test]
+    fn test_portal() {
+        let report = |udp, captive_portal| Report {
+            udp,
+            captive_portal,
+            ..Default::default()
+        };
+        assert_eq!(report(false, None).portal(), None);
+        assert_eq!(report(true, None).portal(), Some(Portal::NotDetected));
+        assert_eq!(
+            report(false, Some(false)).portal(),
+            Some(Portal::NotDetected)
+        );
+        assert_eq!(report(false, Some(true)).portal(), Some(Portal::Detected));
+    }
+
     #[tokio::test]
     async fn test_udp_blocked() -> Result<()> {
         let _guard = iroh_test::logging::setup();
@@ -1016,7 +1345,7 @@ mod tests {
         let resolver = crate::dns::tests::resolver();
         let mut client = Client::new(None, resolver.clone())?;
 
-        let r = client.get_report(dm, None, None).await?;
+        let r = client.get_report(dm, None, None, None).await?;
         let mut r: Report = (*r).clone();
         r.portmap_probe = None;
 
@@ -1055,6 +1384,43 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_quic_probe() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        // A relay server whose STUN port is never answered, so only the QUIC address
+        // discovery probes can discover our address.
+        let (server, relay_node) = test_utils::relay().await;
+        let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let relay_node = RelayNode {
+            stun_port: blackhole.local_addr()?.port(),
+            ..(*relay_node).clone()
+        };
+        let dm = RelayMap::from_nodes([relay_node])?;
+
+        let ep = quinn::Endpoint::client(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))?;
+        let SocketAddr::V4(client_addr) = ep.local_addr()? else {
+            panic!("bound to IPv4");
+        };
+        let quic_client = QuicClient::new(
+            ep.clone(),
+            iroh_relay::client::make_dangerous_client_config(),
+        )?;
+
+        let resolver = crate::dns::tests::resolver();
+        let mut client = Client::new(None, resolver.clone())?;
+        let r = client.get_report(dm, None, None, Some(quic_client)).await?;
+
+        assert!(r.udp, "want UDP");
+        assert!(r.ipv4, "want IPv4");
+        assert_eq!(r.global_v4, Some(client_addr));
+        assert_eq!(r.relay_latency.len(), 1);
+
+        ep.wait_idle().await;
+        server.shutdown().await?;
+        Ok(())
+    }
+
     #[tokio::test(flavor = "current_thread", start_paused = true)]
     async fn test_add_report_history_set_preferred_relay() -> Result<()> {
         fn relay_url(i: u16) -> RelayUrl {
@@ -1217,7 +1583,7 @@ mod tests {
         let resolver = crate::dns::tests::resolver();
         for mut tt in tests {
             println!("test: {}", tt.name);
-            let mut actor = Actor::new(None, resolver.clone()).unwrap();
+            let mut actor = Actor::new(None, resolver.clone(), Config::default()).unwrap();
             for s in &mut tt.steps {
                 // trigger the timer
                 time::advance(Duration::from_secs(s.after)).await;
@@ -1285,7 +1651,7 @@ mod tests {
             )
         };
 
-        let r = client.get_report(dm, Some(sock), None).await?;
+        let r = client.get_report(dm, Some(sock), None, None).await?;
         dbg!(&r);
         assert_eq!(r.hair_pinning, Some(true));
 
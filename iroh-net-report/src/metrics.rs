@@ -12,6 +12,8 @@ pub struct Metrics {
     pub stun_packets_sent_ipv6: Counter,
     pub stun_packets_recv_ipv4: Counter,
     pub stun_packets_recv_ipv6: Counter,
+    pub quic_probes_ipv4: Counter,
+    pub quic_probes_ipv6: Counter,
     pub reports: Counter,
     pub reports_full: Counter,
 }
@@ -26,6 +28,12 @@ impl Default for Metrics {
             stun_packets_sent_ipv6: Counter::new("Number of IPv6 STUN packets sent"),
             stun_packets_recv_ipv4: Counter::new("Number of IPv4 STUN packets received"),
             stun_packets_recv_ipv6: Counter::new("Number of IPv6 STUN packets received"),
+            quic_probes_ipv4: Counter::new(
+                "Number of successful IPv4 QUIC address discovery probes",
+            ),
+            quic_probes_ipv6: Counter::new(
+                "Number of successful IPv6 QUIC address discovery probes",
+            ),
             reports: Counter::new(
                 "Number of reports executed by net_report, including full reports",
             ),
@@ -17,8 +17,9 @@
 //! - Sends the completed report to the net_report actor.
 
 use std::{
+    collections::{hash_map::Entry, HashMap},
     future::Future,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -26,14 +27,13 @@ use std::{
 };
 
 use anyhow::{anyhow, bail, Context as _, Result};
-use hickory_resolver::TokioAsyncResolver as DnsResolver;
 #[cfg(feature = "metrics")]
 use iroh_metrics::inc;
-use iroh_relay::{http::RELAY_PROBE_PATH, protos::stun};
-use netwatch::{interfaces, UdpSocket};
+use iroh_relay::{dns::DnsResolver, http::RELAY_PROBE_PATH, protos::stun, quic::QuicClient};
+use netwatch::interfaces;
 use rand::seq::IteratorRandom;
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{mpsc, oneshot, Semaphore},
     task::JoinSet,
     time::{self, Instant},
 };
@@ -48,7 +48,7 @@ use crate::{
     defaults::DEFAULT_STUN_PORT,
     dns::ResolverExt,
     ping::{PingError, Pinger},
-    RelayMap, RelayNode, RelayUrl, Report,
+    Config, RelayMap, RelayNode, RelayUrl, Report, StunSocket,
 };
 
 mod hairpin;
@@ -81,9 +81,11 @@ impl Client {
         last_report: Option<Arc<Report>>,
         port_mapper: Option<portmapper::Client>,
         relay_map: RelayMap,
-        stun_sock4: Option<Arc<UdpSocket>>,
-        stun_sock6: Option<Arc<UdpSocket>>,
+        stun_sock4: Option<Arc<dyn StunSocket>>,
+        stun_sock6: Option<Arc<dyn StunSocket>>,
+        quic_client: Option<QuicClient>,
         dns_resolver: DnsResolver,
+        config: Config,
     ) -> Self {
         let (msg_tx, msg_rx) = mpsc::channel(32);
         let addr = Addr {
@@ -98,10 +100,13 @@ impl Client {
             relay_map,
             stun_sock4,
             stun_sock6,
+            quic_client,
             report: Report::default(),
             hairpin_actor: hairpin::Client::new(net_report, addr),
+            mapped_addrs_v4: HashMap::new(),
             outstanding_tasks: OutstandingTasks::default(),
             dns_resolver,
+            config,
         };
         let task = tokio::spawn(
             async move { actor.run().await }.instrument(info_span!("reportgen.actor")),
@@ -168,21 +173,29 @@ struct Actor {
     /// The relay configuration.
     relay_map: RelayMap,
     /// Socket to send IPv4 STUN requests from.
-    stun_sock4: Option<Arc<UdpSocket>>,
+    stun_sock4: Option<Arc<dyn StunSocket>>,
     /// Socket so send IPv6 STUN requests from.
-    stun_sock6: Option<Arc<UdpSocket>>,
+    stun_sock6: Option<Arc<dyn StunSocket>>,
+    /// Client to run QUIC address discovery probes with, if enabled.
+    quic_client: Option<QuicClient>,
 
     // Internal state.
     /// The report being built.
     report: Report,
     /// The hairpin actor.
     hairpin_actor: hairpin::Client,
+    /// The first mapped IPv4 address observed by each relay IP address, with the port probed.
+    ///
+    /// Used to detect whether the NAT mapping depends on the destination port.
+    mapped_addrs_v4: HashMap<Ipv4Addr, (u16, SocketAddr)>,
     /// Which tasks the [`Actor`] is still waiting on.
     ///
     /// This is essentially the summary of all the work the [`Actor`] is doing.
     outstanding_tasks: OutstandingTasks,
     /// The DNS resolver to use for probes that need to resolve DNS records.
     dns_resolver: DnsResolver,
+    /// Which probes to run.
+    config: Config,
 }
 
 impl Actor {
@@ -341,6 +354,7 @@ impl Actor {
 
     fn handle_probe_report(&mut self, probe_report: ProbeReport) {
         debug!(?probe_report, "finished probe");
+        self.update_mapping_varies_by_dest_port(&probe_report);
         update_report(&mut self.report, probe_report);
 
         // When we discover the first IPv4 address we want to start the hairpin actor.
@@ -384,6 +398,35 @@ impl Actor {
         }
     }
 
+    /// Compares the mapped address with the one observed on another port of the same relay.
+    ///
+    /// Relay servers serve STUN and QUIC address discovery on different ports of the same
+    /// IP address.  If the mapped addresses differ the NAT mapping depends on the
+    /// destination port.
+    fn update_mapping_varies_by_dest_port(&mut self, probe_report: &ProbeReport) {
+        let (Some(SocketAddr::V4(relay_addr)), Some(addr @ SocketAddr::V4(_))) =
+            (probe_report.relay_addr, probe_report.addr)
+        else {
+            return;
+        };
+        match self.mapped_addrs_v4.entry(*relay_addr.ip()) {
+            Entry::Vacant(entry) => {
+                entry.insert((relay_addr.port(), addr));
+            }
+            Entry::Occupied(entry) => {
+                let (port, first_addr) = *entry.get();
+                if port == relay_addr.port() {
+                    return;
+                }
+                if first_addr != addr {
+                    self.report.mapping_varies_by_dest_port = Some(true);
+                } else if self.report.mapping_varies_by_dest_port.is_none() {
+                    self.report.mapping_varies_by_dest_port = Some(false);
+                }
+            }
+        }
+    }
+
     /// Whether running this probe would still improve our report.
     fn probe_would_help(&mut self, probe: Probe, relay_node: Arc<RelayNode>) -> bool {
         // If the probe is for a relay we don't yet know about, that would help.
@@ -392,7 +435,9 @@ impl Actor {
         }
 
         // If the probe is for IPv6 and we don't yet have an IPv6 report, that would help.
-        if probe.proto() == ProbeProto::StunIpv6 && self.report.relay_v6_latency.is_empty() {
+        if matches!(probe.proto(), ProbeProto::StunIpv6 | ProbeProto::QuicIpv6)
+            && self.report.relay_v6_latency.is_empty()
+        {
             return true;
         }
 
@@ -402,7 +447,16 @@ impl Actor {
         // talking to. If we don't yet have two results yet
         // (`mapping_varies_by_dest_ip` is blank), then another IPv4 probe
         // would be good.
-        if probe.proto() == ProbeProto::StunIpv4 && self.report.mapping_varies_by_dest_ip.is_none()
+        if matches!(probe.proto(), ProbeProto::StunIpv4 | ProbeProto::QuicIpv4)
+            && self.report.mapping_varies_by_dest_ip.is_none()
+        {
+            return true;
+        }
+
+        // A QUIC probe to a relay which answered a STUN probe tells whether the mapping
+        // depends on the destination port.
+        if probe.proto() == ProbeProto::QuicIpv4
+            && self.report.mapping_varies_by_dest_port.is_none()
         {
             return true;
         }
@@ -536,30 +590,51 @@ impl Actor {
         // create it.
         let pinger = Pinger::new();
 
+        let concurrency = self
+            .config
+            .max_concurrent_probes
+            .map(|max| Arc::new(Semaphore::new(max)));
+
         // A collection of futures running probe sets.
         let mut probes = JoinSet::default();
         for probe_set in plan.iter() {
+            if matches!(
+                probe_set.proto(),
+                ProbeProto::QuicIpv4 | ProbeProto::QuicIpv6
+            ) && self.quic_client.is_none()
+            {
+                trace!(proto = %probe_set.proto(), "no QUIC client, skipping probe set");
+                continue;
+            }
+            if !probe_enabled(&self.config, probe_set.proto()) {
+                trace!(proto = %probe_set.proto(), "disabled, skipping probe set");
+                continue;
+            }
             let mut set = JoinSet::default();
             for probe in probe_set {
                 let reportstate = self.addr();
                 let stun_sock4 = self.stun_sock4.clone();
                 let stun_sock6 = self.stun_sock6.clone();
+                let quic_client = self.quic_client.clone();
                 let relay_node = probe.node().clone();
                 let probe = probe.clone();
                 let net_report = self.net_report.clone();
                 let pinger = pinger.clone();
                 let dns_resolver = self.dns_resolver.clone();
+                let concurrency = concurrency.clone();
 
                 set.spawn(
                     run_probe(
                         reportstate,
                         stun_sock4,
                         stun_sock6,
+                        quic_client,
                         relay_node,
                         probe.clone(),
                         net_report,
                         pinger,
                         dns_resolver,
+                        concurrency,
                     )
                     .instrument(debug_span!("run_probe", %probe)),
                 );
@@ -602,6 +677,17 @@ impl Actor {
     }
 }
 
+/// Whether the [`Config`] allows running probes of this protocol.
+fn probe_enabled(config: &Config, proto: ProbeProto) -> bool {
+    match proto {
+        ProbeProto::StunIpv4 => config.stun_ipv4,
+        ProbeProto::StunIpv6 => config.stun_ipv6,
+        ProbeProto::QuicIpv4 | ProbeProto::QuicIpv6 => config.quic,
+        ProbeProto::Https => config.https,
+        ProbeProto::IcmpV4 | ProbeProto::IcmpV6 => config.icmp,
+    }
+}
+
 /// Tasks on which the reportgen [`Actor`] is still waiting.
 ///
 /// There is no particular progression, e.g. hairpin starts `false`, moves to `true` when a
@@ -637,6 +723,8 @@ struct ProbeReport {
     probe: Probe,
     /// The discovered public address.
     addr: Option<SocketAddr>,
+    /// The address of the relay server the probe was sent to.
+    relay_addr: Option<SocketAddr>,
 }
 
 impl ProbeReport {
@@ -649,6 +737,7 @@ impl ProbeReport {
             icmpv6: None,
             latency: None,
             addr: None,
+            relay_addr: None,
         }
     }
 }
@@ -670,22 +759,30 @@ enum ProbeError {
 
 /// Executes a particular [`Probe`], including using a delayed start if needed.
 ///
-/// If *stun_sock4* and *stun_sock6* are `None` the STUN probes are disabled.
+/// If *stun_sock4* and *stun_sock6* are `None` the STUN probes are disabled, likewise
+/// *quic_client* being `None` disables the QUIC address discovery probes.
 #[allow(clippy::too_many_arguments)]
 async fn run_probe(
     reportstate: Addr,
-    stun_sock4: Option<Arc<UdpSocket>>,
-    stun_sock6: Option<Arc<UdpSocket>>,
+    stun_sock4: Option<Arc<dyn StunSocket>>,
+    stun_sock6: Option<Arc<dyn StunSocket>>,
+    quic_client: Option<QuicClient>,
     relay_node: Arc<RelayNode>,
     probe: Probe,
     net_report: net_report::Addr,
     pinger: Pinger,
     dns_resolver: DnsResolver,
+    concurrency: Option<Arc<Semaphore>>,
 ) -> Result<ProbeReport, ProbeError> {
     if !probe.delay().is_zero() {
         trace!("delaying probe");
         tokio::time::sleep(probe.delay()).await;
     }
+    // The semaphore is never closed.
+    let _permit = match concurrency {
+        Some(semaphore) => Some(semaphore.acquire_owned().await.expect("not closed")),
+        None => None,
+    };
     debug!("starting probe");
 
     let (would_help_tx, would_help_rx) = oneshot::channel();
@@ -739,6 +836,17 @@ async fn run_probe(
                 }
             }
         }
+        Probe::QuicIpv4 { .. } | Probe::QuicIpv6 { .. } => match quic_client {
+            Some(ref quic_client) => {
+                result = run_quic_probe(quic_client, relay_addr, probe).await?;
+            }
+            None => {
+                return Err(ProbeError::AbortSet(
+                    anyhow!("No QUIC client for {}, aborting probeset", probe.proto()),
+                    probe.clone(),
+                ));
+            }
+        },
         Probe::IcmpV4 { .. } | Probe::IcmpV6 { .. } => {
             result = run_icmp_probe(probe, relay_addr, pinger).await?
         }
@@ -765,12 +873,13 @@ async fn run_probe(
     }
 
     trace!("probe successful");
+    result.relay_addr = Some(relay_addr);
     Ok(result)
 }
 
 /// Run a STUN IPv4 or IPv6 probe.
 async fn run_stun_probe(
-    sock: &Arc<UdpSocket>,
+    sock: &Arc<dyn StunSocket>,
     relay_addr: SocketAddr,
     net_report: net_report::Addr,
     probe: Probe,
@@ -851,6 +960,56 @@ async fn run_stun_probe(
     }
 }
 
+/// Runs a QUIC address discovery IPv4 or IPv6 probe.
+///
+/// This connects to the QUIC endpoint of the relay server, which reports the address it
+/// observed our packets coming from, like a STUN server does.
+async fn run_quic_probe(
+    quic_client: &QuicClient,
+    relay_addr: SocketAddr,
+    probe: Probe,
+) -> Result<ProbeReport, ProbeError> {
+    match probe.proto() {
+        ProbeProto::QuicIpv4 => debug_assert!(relay_addr.is_ipv4()),
+        ProbeProto::QuicIpv6 => debug_assert!(relay_addr.is_ipv6()),
+        _ => debug_assert!(false, "wrong probe"),
+    }
+    // Unlike `host_str` this does not wrap IPv6 addresses in brackets, which would not be
+    // a valid TLS server name.
+    let host = match probe.node().url.host() {
+        Some(Host::Domain(domain)) => domain.to_string(),
+        Some(Host::Ipv4(addr)) => addr.to_string(),
+        Some(Host::Ipv6(addr)) => addr.to_string(),
+        None => {
+            return Err(ProbeError::AbortSet(
+                anyhow!("No valid hostname in RelayUrl"),
+                probe.clone(),
+            ));
+        }
+    };
+    debug!(%relay_addr, "sending {} probe", probe.proto());
+    let (addr, latency) = quic_client
+        .get_addr_and_latency(relay_addr, &host)
+        .await
+        .map_err(|err| ProbeError::Error(err, probe.clone()))?;
+
+    let mut result = ProbeReport::new(probe.clone());
+    if matches!(probe, Probe::QuicIpv4 { .. }) {
+        result.ipv4_can_send = true;
+        #[cfg(feature = "metrics")]
+        inc!(Metrics, quic_probes_ipv4);
+    } else {
+        result.ipv6_can_send = true;
+        #[cfg(feature = "metrics")]
+        inc!(Metrics, quic_probes_ipv6);
+    }
+    // The QUIC client reports half the round-trip time, while the relay latencies of
+    // the other probes are full round trips.
+    result.latency = Some(latency * 2);
+    result.addr = Some(addr);
+    Ok(result)
+}
+
 /// Reports whether or not we think the system is behind a
 /// captive portal, detected by making a request to a URL that we know should
 /// return a "204 No Content" response and checking if that's what we get.
@@ -864,7 +1023,7 @@ async fn check_captive_portal(
     // If we have a preferred relay node and we can use it for non-STUN requests, try that;
     // otherwise, pick a random one suitable for non-STUN requests.
     let preferred_relay = preferred_relay.and_then(|url| match dm.get_node(&url) {
-        Some(node) if node.stun_only => Some(url),
+        Some(node) if !node.stun_only => Some(url),
         _ => None,
     });
 
@@ -946,10 +1105,13 @@ async fn get_relay_addr(
     relay_node: &RelayNode,
     proto: ProbeProto,
 ) -> Result<SocketAddr> {
-    let port = if relay_node.stun_port == 0 {
-        DEFAULT_STUN_PORT
-    } else {
-        relay_node.stun_port
+    let port = match proto {
+        ProbeProto::QuicIpv4 | ProbeProto::QuicIpv6 => match relay_node.quic {
+            Some(ref quic) => quic.port,
+            None => bail!("Relay node does not support QUIC address discovery"),
+        },
+        _ if relay_node.stun_port == 0 => DEFAULT_STUN_PORT,
+        _ => relay_node.stun_port,
     };
 
     if relay_node.stun_only && !matches!(proto, ProbeProto::StunIpv4 | ProbeProto::StunIpv6) {
@@ -957,39 +1119,43 @@ async fn get_relay_addr(
     }
 
     match proto {
-        ProbeProto::StunIpv4 | ProbeProto::IcmpV4 => match relay_node.url.host() {
-            Some(url::Host::Domain(hostname)) => {
-                debug!(?proto, %hostname, "Performing DNS A lookup for relay addr");
-                match dns_resolver.lookup_ipv4_staggered(hostname).await {
-                    Ok(mut addrs) => addrs
-                        .next()
-                        .map(|ip| ip.to_canonical())
-                        .map(|addr| SocketAddr::new(addr, port))
-                        .ok_or(anyhow!("No suitable relay addr found")),
-                    Err(err) => Err(err.context("No suitable relay addr found")),
+        ProbeProto::StunIpv4 | ProbeProto::IcmpV4 | ProbeProto::QuicIpv4 => {
+            match relay_node.url.host() {
+                Some(url::Host::Domain(hostname)) => {
+                    debug!(?proto, %hostname, "Performing DNS A lookup for relay addr");
+                    match dns_resolver.lookup_ipv4_staggered(hostname).await {
+                        Ok(mut addrs) => addrs
+                            .next()
+                            .map(|ip| ip.to_canonical())
+                            .map(|addr| SocketAddr::new(addr, port))
+                            .ok_or(anyhow!("No suitable relay addr found")),
+                        Err(err) => Err(err.context("No suitable relay addr found")),
+                    }
                 }
+                Some(url::Host::Ipv4(addr)) => Ok(SocketAddr::new(addr.into(), port)),
+                Some(url::Host::Ipv6(_addr)) => Err(anyhow!("No suitable relay addr found")),
+                None => Err(anyhow!("No valid hostname in RelayUrl")),
             }
-            Some(url::Host::Ipv4(addr)) => Ok(SocketAddr::new(addr.into(), port)),
-            Some(url::Host::Ipv6(_addr)) => Err(anyhow!("No suitable relay addr found")),
-            None => Err(anyhow!("No valid hostname in RelayUrl")),
-        },
+        }
 
-        ProbeProto::StunIpv6 | ProbeProto::IcmpV6 => match relay_node.url.host() {
-            Some(url::Host::Domain(hostname)) => {
-                debug!(?proto, %hostname, "Performing DNS AAAA lookup for relay addr");
-                match dns_resolver.lookup_ipv6_staggered(hostname).await {
-                    Ok(mut addrs) => addrs
-                        .next()
-                        .map(|ip| ip.to_canonical())
-                        .map(|addr| SocketAddr::new(addr, port))
-                        .ok_or(anyhow!("No suitable relay addr found")),
-                    Err(err) => Err(err.context("No suitable relay addr found")),
+        ProbeProto::StunIpv6 | ProbeProto::IcmpV6 | ProbeProto::QuicIpv6 => {
+            match relay_node.url.host() {
+                Some(url::Host::Domain(hostname)) => {
+                    debug!(?proto, %hostname, "Performing DNS AAAA lookup for relay addr");
+                    match dns_resolver.lookup_ipv6_staggered(hostname).await {
+                        Ok(mut addrs) => addrs
+                            .next()
+                            .map(|ip| ip.to_canonical())
+                            .map(|addr| SocketAddr::new(addr, port))
+                            .ok_or(anyhow!("No suitable relay addr found")),
+                        Err(err) => Err(err.context("No suitable relay addr found")),
+                    }
                 }
+                Some(url::Host::Ipv4(_addr)) => Err(anyhow!("No suitable relay addr found")),
+                Some(url::Host::Ipv6(addr)) => Ok(SocketAddr::new(addr.into(), port)),
+                None => Err(anyhow!("No valid hostname in RelayUrl")),
             }
-            Some(url::Host::Ipv4(_addr)) => Err(anyhow!("No suitable relay addr found")),
-            Some(url::Host::Ipv6(addr)) => Ok(SocketAddr::new(addr.into(), port)),
-            None => Err(anyhow!("No valid hostname in RelayUrl")),
-        },
+        }
 
         ProbeProto::Https => Err(anyhow!("Not implemented")),
     }
@@ -1114,7 +1280,10 @@ fn update_report(report: &mut Report, probe_report: ProbeReport) {
 
         if matches!(
             probe_report.probe.proto(),
-            ProbeProto::StunIpv4 | ProbeProto::StunIpv6
+            ProbeProto::StunIpv4
+                | ProbeProto::StunIpv6
+                | ProbeProto::QuicIpv4
+                | ProbeProto::QuicIpv6
         ) {
             report.udp = true;
 
@@ -1141,13 +1310,14 @@ fn update_report(report: &mut Report, probe_report: ProbeReport) {
                         report.global_v6 = Some(ipp);
                     } else if report.global_v6 != Some(ipp) {
                         report.mapping_varies_by_dest_ipv6 = Some(true);
-                        warn!("IPv6 Address detected by STUN varies by destination");
+                        warn!("IPv6 Address detected by STUN or QUIC varies by destination");
                     } else if report.mapping_varies_by_dest_ipv6.is_none() {
                         report.mapping_varies_by_dest_ipv6 = Some(false);
                     }
                 }
                 None => {
-                    // If we are here we had a relay server latency reported from a STUN probe.
+                    // If we are here we had a relay server latency reported from a STUN or
+                    // QUIC probe.
                     // Thus we must have a reported address.
                     debug_assert!(probe_report.addr.is_some());
                 }
@@ -1219,6 +1389,7 @@ mod tests {
                 node: relay_a.clone(),
             },
             addr: Some((Ipv4Addr::new(203, 0, 113, 1), 1234).into()),
+            relay_addr: None,
         };
         update_report(&mut report, probe_report_a.clone());
 
@@ -1269,6 +1440,7 @@ mod tests {
                 node: relay_a.clone(),
             },
             addr: Some((Ipv6Addr::new(2001, 0xdb8, 0, 0, 0, 0, 0, 1), 1234).into()),
+            relay_addr: None,
         };
         update_report(&mut report, probe_report_a_ipv6);
 
@@ -1305,6 +1477,7 @@ mod tests {
                 node: relay_a.clone(),
             },
             addr: Some((Ipv4Addr::new(203, 0, 113, 1), 1234).into()),
+            relay_addr: None,
         };
         update_report(&mut report, probe_report_eu.clone());
 
@@ -1324,6 +1497,7 @@ mod tests {
                 node: relay_b.clone(),
             },
             addr: None,
+            relay_addr: None,
         };
         update_report(&mut report, probe_report_na);
 
@@ -1341,6 +1515,7 @@ mod tests {
                 node: relay_a.clone(),
             },
             addr: Some((Ipv4Addr::new(203, 0, 113, 1), 1234).into()),
+            relay_addr: None,
         };
         update_report(&mut report, probe_report_eu_stun);
 
@@ -1,8 +1,8 @@
 //! The relay probes.
 //!
 //! All the probes try and establish the latency to the relay servers.  Preferably the STUN
-//! probes work and we also learn about our public IP addresses and ports.  But fallback
-//! probes for HTTPS and ICMP exist as well.
+//! or QUIC address discovery probes work and we also learn about our public IP addresses and
+//! ports.  But fallback probes for HTTPS and ICMP exist as well.
 
 use std::{collections::BTreeSet, fmt, sync::Arc};
 
@@ -54,6 +54,10 @@ pub(super) enum ProbeProto {
     IcmpV4,
     /// ICMP IPv6
     IcmpV6,
+    /// QUIC address discovery IPv4
+    QuicIpv4,
+    /// QUIC address discovery IPv6
+    QuicIpv6,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, derive_more::Display)]
@@ -88,6 +92,16 @@ pub(super) enum Probe {
         delay: Duration,
         node: Arc<RelayNode>,
     },
+    #[display("QUIC Ipv4 after {delay:?} to {node}")]
+    QuicIpv4 {
+        delay: Duration,
+        node: Arc<RelayNode>,
+    },
+    #[display("QUIC Ipv6 after {delay:?} to {node}")]
+    QuicIpv6 {
+        delay: Duration,
+        node: Arc<RelayNode>,
+    },
 }
 
 impl Probe {
@@ -97,7 +111,9 @@ impl Probe {
             | Probe::StunIpv6 { delay, .. }
             | Probe::Https { delay, .. }
             | Probe::IcmpV4 { delay, .. }
-            | Probe::IcmpV6 { delay, .. } => *delay,
+            | Probe::IcmpV6 { delay, .. }
+            | Probe::QuicIpv4 { delay, .. }
+            | Probe::QuicIpv6 { delay, .. } => *delay,
         }
     }
 
@@ -108,6 +124,8 @@ impl Probe {
             Probe::Https { .. } => ProbeProto::Https,
             Probe::IcmpV4 { .. } => ProbeProto::IcmpV4,
             Probe::IcmpV6 { .. } => ProbeProto::IcmpV6,
+            Probe::QuicIpv4 { .. } => ProbeProto::QuicIpv4,
+            Probe::QuicIpv6 { .. } => ProbeProto::QuicIpv6,
         }
     }
 
@@ -117,7 +135,9 @@ impl Probe {
             | Probe::StunIpv6 { node, .. }
             | Probe::Https { node, .. }
             | Probe::IcmpV4 { node, .. }
-            | Probe::IcmpV6 { node, .. } => node,
+            | Probe::IcmpV6 { node, .. }
+            | Probe::QuicIpv4 { node, .. }
+            | Probe::QuicIpv6 { node, .. } => node,
         }
     }
 }
@@ -159,6 +179,11 @@ impl ProbeSet {
     fn is_empty(&self) -> bool {
         self.probes.is_empty()
     }
+
+    /// Returns the [`ProbeProto`] of all the probes in this set.
+    pub(super) fn proto(&self) -> ProbeProto {
+        self.proto
+    }
 }
 
 impl<'a> IntoIterator for &'a ProbeSet {
@@ -206,6 +231,9 @@ impl ProbePlan {
         for relay_node in relay_map.nodes() {
             let mut stun_ipv4_probes = ProbeSet::new(ProbeProto::StunIpv4);
             let mut stun_ipv6_probes = ProbeSet::new(ProbeProto::StunIpv6);
+            let mut quic_ipv4_probes = ProbeSet::new(ProbeProto::QuicIpv4);
+            let mut quic_ipv6_probes = ProbeSet::new(ProbeProto::QuicIpv6);
+            let do_quic = supports_quic(relay_node);
 
             for attempt in 0..3 {
                 let delay = DEFAULT_INITIAL_RETRANSMIT * attempt as u32;
@@ -217,6 +245,14 @@ impl ProbePlan {
                             node: relay_node.clone(),
                         })
                         .expect("adding StunIpv4 probe to a StunIpv4 probe set");
+                    if do_quic {
+                        quic_ipv4_probes
+                            .push(Probe::QuicIpv4 {
+                                delay,
+                                node: relay_node.clone(),
+                            })
+                            .expect("adding QuicIpv4 probe to a QuicIpv4 probe set");
+                    }
                 }
                 if if_state.have_v6 {
                     stun_ipv6_probes
@@ -225,10 +261,20 @@ impl ProbePlan {
                             node: relay_node.clone(),
                         })
                         .expect("adding StunIpv6 probe to a StunIpv6 probe set");
+                    if do_quic {
+                        quic_ipv6_probes
+                            .push(Probe::QuicIpv6 {
+                                delay,
+                                node: relay_node.clone(),
+                            })
+                            .expect("adding QuicIpv6 probe to a QuicIpv6 probe set");
+                    }
                 }
             }
             plan.add(stun_ipv4_probes);
             plan.add(stun_ipv6_probes);
+            plan.add(quic_ipv4_probes);
+            plan.add(quic_ipv6_probes);
 
             // The HTTP and ICMP probes only start after the STUN probes have had a chance.
             let mut https_probes = ProbeSet::new(ProbeProto::Https);
@@ -327,6 +373,9 @@ impl ProbePlan {
 
             let mut stun_ipv4_probes = ProbeSet::new(ProbeProto::StunIpv4);
             let mut stun_ipv6_probes = ProbeSet::new(ProbeProto::StunIpv6);
+            let mut quic_ipv4_probes = ProbeSet::new(ProbeProto::QuicIpv4);
+            let mut quic_ipv6_probes = ProbeSet::new(ProbeProto::QuicIpv6);
+            let do_quic = supports_quic(relay_node);
 
             for attempt in 0..attempts {
                 let delay = (retransmit_delay * attempt as u32)
@@ -338,6 +387,14 @@ impl ProbePlan {
                             node: relay_node.clone(),
                         })
                         .expect("Pushing StunIpv4 Probe to StunIpv4 ProbeSet");
+                    if do_quic {
+                        quic_ipv4_probes
+                            .push(Probe::QuicIpv4 {
+                                delay,
+                                node: relay_node.clone(),
+                            })
+                            .expect("Pushing QuicIpv4 Probe to QuicIpv4 ProbeSet");
+                    }
                 }
                 if do6 {
                     stun_ipv6_probes
@@ -346,10 +403,20 @@ impl ProbePlan {
                             node: relay_node.clone(),
                         })
                         .expect("Pushing StunIpv6 Probe to StunIpv6 ProbeSet");
+                    if do_quic {
+                        quic_ipv6_probes
+                            .push(Probe::QuicIpv6 {
+                                delay,
+                                node: relay_node.clone(),
+                            })
+                            .expect("Pushing QuicIpv6 Probe to QuicIpv6 ProbeSet");
+                    }
                 }
             }
             plan.add(stun_ipv4_probes);
             plan.add(stun_ipv6_probes);
+            plan.add(quic_ipv4_probes);
+            plan.add(quic_ipv6_probes);
 
             // The HTTP and ICMP probes only start after the STUN probes have had a chance.
             let mut https_probes = ProbeSet::new(ProbeProto::Https);
@@ -433,6 +500,11 @@ impl FromIterator<ProbeSet> for ProbePlan {
     }
 }
 
+/// Whether QUIC address discovery probes can be sent to this relay node.
+fn supports_quic(relay_node: &RelayNode) -> bool {
+    relay_node.quic.is_some() && !relay_node.stun_only
+}
+
 /// Sorts the nodes in the [`RelayMap`] from fastest to slowest.
 ///
 /// This uses the latencies from the last report to determine the order. Relay Nodes with no
@@ -518,6 +590,20 @@ mod tests {
                          Duration::from_millis(100),
                          Duration::from_millis(200)],
             },
+            probeset! {
+                proto: ProbeProto::QuicIpv4,
+                relay: relay_node_1.clone(),
+                delays: [Duration::ZERO,
+                         Duration::from_millis(100),
+                         Duration::from_millis(200)],
+            },
+            probeset! {
+                proto: ProbeProto::QuicIpv6,
+                relay: relay_node_1.clone(),
+                delays: [Duration::ZERO,
+                         Duration::from_millis(100),
+                         Duration::from_millis(200)],
+            },
             probeset! {
                 proto: ProbeProto::Https,
                 relay: relay_node_1.clone(),
@@ -553,6 +639,20 @@ mod tests {
                          Duration::from_millis(100),
                          Duration::from_millis(200)],
             },
+            probeset! {
+                proto: ProbeProto::QuicIpv4,
+                relay: relay_node_2.clone(),
+                delays: [Duration::ZERO,
+                         Duration::from_millis(100),
+                         Duration::from_millis(200)],
+            },
+            probeset! {
+                proto: ProbeProto::QuicIpv6,
+                relay: relay_node_2.clone(),
+                delays: [Duration::ZERO,
+                         Duration::from_millis(100),
+                         Duration::from_millis(200)],
+            },
             probeset! {
                 proto: ProbeProto::Https,
                 relay: relay_node_2.clone(),
@@ -611,6 +711,7 @@ mod tests {
                 icmpv4: None,
                 icmpv6: None,
                 mapping_varies_by_dest_ip: Some(false),
+                mapping_varies_by_dest_port: None,
                 mapping_varies_by_dest_ipv6: Some(false),
                 hair_pinning: Some(true),
                 portmap_probe: None,
@@ -640,6 +741,22 @@ mod tests {
                              Duration::from_micros(104_800),
                              Duration::from_micros(157_200)],
                 },
+                probeset! {
+                    proto: ProbeProto::QuicIpv4,
+                    relay: relay_node_1.clone(),
+                    delays: [Duration::ZERO,
+                             Duration::from_micros(52_400),
+                             Duration::from_micros(104_800),
+                             Duration::from_micros(157_200)],
+                },
+                probeset! {
+                    proto: ProbeProto::QuicIpv6,
+                    relay: relay_node_1.clone(),
+                    delays: [Duration::ZERO,
+                             Duration::from_micros(52_400),
+                             Duration::from_micros(104_800),
+                             Duration::from_micros(157_200)],
+                },
                 probeset! {
                     proto: ProbeProto::Https,
                     relay: relay_node_1.clone(),
@@ -676,6 +793,18 @@ mod tests {
                     delays: [Duration::ZERO,
                              Duration::from_micros(52_400)],
                 },
+                probeset! {
+                    proto: ProbeProto::QuicIpv4,
+                    relay: relay_node_2.clone(),
+                    delays: [Duration::ZERO,
+                             Duration::from_micros(52_400)],
+                },
+                probeset! {
+                    proto: ProbeProto::QuicIpv6,
+                    relay: relay_node_2.clone(),
+                    delays: [Duration::ZERO,
+                             Duration::from_micros(52_400)],
+                },
                 probeset! {
                     proto: ProbeProto::Https,
                     relay: relay_node_2.clone(),
@@ -733,6 +862,7 @@ mod tests {
             icmpv4: None,
             icmpv6: None,
             mapping_varies_by_dest_ip: Some(false),
+            mapping_varies_by_dest_port: None,
             mapping_varies_by_dest_ipv6: Some(false),
             hair_pinning: Some(true),
             portmap_probe: None,
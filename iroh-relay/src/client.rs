@@ -4,8 +4,8 @@
 
 use std::{
     collections::HashMap,
-    future,
-    net::{IpAddr, SocketAddr},
+    future, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
@@ -15,7 +15,6 @@ use bytes::Bytes;
 use conn::{Conn, ConnBuilder, ConnReader, ConnReceiver, ConnWriter, ReceivedMessage};
 use futures_lite::future::Boxed as BoxFuture;
 use futures_util::StreamExt;
-use hickory_resolver::TokioAsyncResolver as DnsResolver;
 use http_body_util::Empty;
 use hyper::{
     body::Incoming,
@@ -24,13 +23,18 @@ use hyper::{
     Request,
 };
 use hyper_util::rt::TokioIo;
-use iroh_base::key::{NodeId, PublicKey, SecretKey};
+use iroh_base::{
+    key::{NodeId, PublicKey, SecretKey},
+    relay_map::RelayAuthToken,
+};
+use quinn::crypto::rustls::QuicClientConfig;
 use rand::Rng;
 use rustls::client::Resumption;
+use socket2::SockRef;
 use streams::{downcast_upgrade, MaybeTlsStream, ProxyStream};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpStream,
+    net::{TcpSocket, TcpStream},
     sync::{mpsc, oneshot},
     task::JoinSet,
     time::Instant,
@@ -44,8 +48,13 @@ use url::Url;
 
 use crate::{
     defaults::timeouts::*,
+    dns::DnsResolver,
     http::{Protocol, RELAY_PATH},
-    protos::relay::DerpCodec,
+    protos::relay::{DerpCodec, MAX_PACKET_SIZE},
+    quic::{
+        streams::{QuicFrameReader, QuicFrameWriter},
+        ALPN_QUIC_RELAY,
+    },
     RelayUrl,
 };
 
@@ -113,6 +122,25 @@ pub enum ClientError {
     /// An error related to websockets, either errors with parsing ws messages or the handshake
     #[error("websocket error: {0}")]
     WebsocketError(#[from] tokio_tungstenite_wasm::Error),
+    /// The QUIC connection to the relay failed
+    #[error("QUIC error: {0}")]
+    Quic(String),
+    /// The packet is larger than the server accepts, see [`Client::max_packet_size`]
+    #[error("packet too large: {0} bytes")]
+    PacketTooLarge(usize),
+}
+
+impl ClientError {
+    /// Whether the server was reached but the relay protocol upgrade did not succeed.
+    ///
+    /// This is what middleboxes which only pass well-formed HTTP and WebSocket traffic
+    /// cause, connecting over WebSockets might still work.
+    fn is_upgrade_failure(&self) -> bool {
+        matches!(
+            self,
+            Self::Hyper(_) | Self::UnexpectedStatusCode(..) | Self::Upgrade(_)
+        )
+    }
 }
 
 /// An HTTP Relay client.
@@ -131,9 +159,16 @@ enum ActorMessage {
     Connect(oneshot::Sender<Result<Conn, ClientError>>),
     NotePreferred(bool),
     LocalAddr(oneshot::Sender<Result<Option<SocketAddr>, ClientError>>),
+    MaxPacketSize(oneshot::Sender<Result<usize, ClientError>>),
     Ping(oneshot::Sender<Result<Duration, ClientError>>),
     Pong([u8; 8], oneshot::Sender<Result<(), ClientError>>),
     Send(PublicKey, Bytes, oneshot::Sender<Result<(), ClientError>>),
+    Forward(
+        PublicKey,
+        PublicKey,
+        Bytes,
+        oneshot::Sender<Result<(), ClientError>>,
+    ),
     Close(oneshot::Sender<Result<(), ClientError>>),
     CloseForReconnect(oneshot::Sender<Result<(), ClientError>>),
     IsConnected(oneshot::Sender<Result<bool, ClientError>>),
@@ -155,12 +190,19 @@ struct Actor {
     address_family_selector: Option<Box<dyn Fn() -> BoxFuture<bool> + Send + Sync + 'static>>,
     url: RelayUrl,
     protocol: Protocol,
+    websocket_fallback: bool,
+    /// The QUIC port and configuration, if relaying over QUIC
+    quic: Option<(u16, quinn::ClientConfig)>,
     #[debug("TlsConnector")]
     tls_connector: tokio_rustls::TlsConnector,
     pings: PingTracker,
     ping_tasks: JoinSet<()>,
     dns_resolver: DnsResolver,
     proxy_url: Option<Url>,
+    auth_token: Option<RelayAuthToken>,
+    pong_timeout: Duration,
+    /// The network interface to bind the sockets to
+    bind_interface: Option<String>,
 }
 
 #[derive(Default, Debug)]
@@ -201,12 +243,22 @@ pub struct ClientBuilder {
     url: RelayUrl,
     /// Relay protocol
     protocol: Protocol,
+    /// Whether to fall back to websockets if the relay protocol upgrade fails
+    websocket_fallback: bool,
+    /// The QUIC port of the relay server, to relay over QUIC
+    quic_port: Option<u16>,
     /// Allow self-signed certificates from relay servers
     #[cfg(any(test, feature = "test-utils"))]
     #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
     insecure_skip_cert_verify: bool,
     /// HTTP Proxy
     proxy_url: Option<Url>,
+    /// Token to authenticate to the server with
+    auth_token: Option<RelayAuthToken>,
+    /// How long to wait for the pong to a ping
+    pong_timeout: Duration,
+    /// The network interface to bind the sockets to
+    bind_interface: Option<String>,
 }
 
 impl ClientBuilder {
@@ -219,9 +271,14 @@ impl ClientBuilder {
             server_public_key: None,
             url: url.into(),
             protocol: Protocol::Relay,
+            websocket_fallback: true,
+            quic_port: None,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify: false,
             proxy_url: None,
+            auth_token: None,
+            pong_timeout: PING_TIMEOUT,
+            bind_interface: None,
         }
     }
 
@@ -238,6 +295,30 @@ impl ClientBuilder {
         self
     }
 
+    /// Sets whether to fall back to websockets if connecting with [`Protocol::Relay`] fails.
+    ///
+    /// Some networks only pass well-formed HTTP and WebSocket traffic and break the HTTP
+    /// upgrade to the relay protocol.  If the relay server is reached but the upgrade fails
+    /// the client connects over websockets instead, and keeps using websockets for later
+    /// reconnects.  No fallback happens when an HTTP proxy is configured.
+    ///
+    /// Enabled by default.
+    pub fn websocket_fallback(mut self, enable: bool) -> Self {
+        self.websocket_fallback = enable;
+        self
+    }
+
+    /// Relays over QUIC to the given UDP port of the relay server.
+    ///
+    /// Relayed packets are sent as QUIC datagrams, so they are not held up behind each
+    /// other like on a TCP connection.  If the QUIC connection can not be established, e.g.
+    /// because UDP is blocked, the client uses the configured [`Protocol`] instead.  QUIC is
+    /// not used when an HTTP proxy is configured.
+    pub fn quic(mut self, port: u16) -> Self {
+        self.quic_port = Some(port);
+        self
+    }
+
     /// Returns if we should prefer ipv6
     /// it replaces the relayhttp.AddressFamilySelector we pass
     /// It provides the hint as to whether in an IPv4-vs-IPv6 race that
@@ -281,6 +362,31 @@ impl ClientBuilder {
         self
     }
 
+    /// Authenticates to the relay server with the given token.
+    ///
+    /// The token is sent in the connection handshake.  Relay servers which do not require a
+    /// token ignore it.
+    pub fn auth_token(mut self, token: RelayAuthToken) -> Self {
+        self.auth_token = Some(token);
+        self
+    }
+
+    /// Sets how long [`Client::ping`] waits for the pong, by default 5 seconds.
+    pub fn pong_timeout(mut self, timeout: Duration) -> Self {
+        self.pong_timeout = timeout;
+        self
+    }
+
+    /// Binds the sockets connecting to the relay server to the named network interface.
+    ///
+    /// Connections then leave through this interface regardless of the routing table, see
+    /// [`bind_to_interface`] for the supported platforms.  Connecting fails if the sockets
+    /// can not be bound.
+    pub fn bind_interface(mut self, name: impl Into<String>) -> Self {
+        self.bind_interface = Some(name.into());
+        self
+    }
+
     /// Build the [`Client`]
     pub fn build(self, key: SecretKey, dns_resolver: DnsResolver) -> (Client, ClientReceiver) {
         // TODO: review TLS config
@@ -304,6 +410,17 @@ impl ClientBuilder {
 
         config.resumption = Resumption::default();
 
+        let quic = self.quic_port.and_then(|port| {
+            let mut config = config.clone();
+            config.alpn_protocols = vec![ALPN_QUIC_RELAY.to_vec()];
+            match QuicClientConfig::try_from(config) {
+                Ok(config) => Some((port, quinn::ClientConfig::new(Arc::new(config)))),
+                Err(err) => {
+                    warn!("can not relay over QUIC: {err:#}");
+                    None
+                }
+            }
+        });
         let tls_connector: tokio_rustls::TlsConnector = Arc::new(config).into();
         let public_key = key.public();
 
@@ -317,9 +434,14 @@ impl ClientBuilder {
             ping_tasks: Default::default(),
             url: self.url,
             protocol: self.protocol,
+            websocket_fallback: self.websocket_fallback,
+            quic,
             tls_connector,
             dns_resolver,
             proxy_url: self.proxy_url,
+            auth_token: self.auth_token,
+            pong_timeout: self.pong_timeout,
+            bind_interface: self.bind_interface,
         };
 
         let (msg_sender, inbox) = mpsc::channel(64);
@@ -345,11 +467,12 @@ impl ClientBuilder {
     }
 }
 
-#[cfg(test)]
 /// Creates a client config that trusts any servers without verifying their TLS certificate.
 ///
 /// Should be used for testing local relay setups only.
-pub(crate) fn make_dangerous_client_config() -> rustls::ClientConfig {
+#[cfg(any(test, feature = "test-utils"))]
+#[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+pub fn make_dangerous_client_config() -> rustls::ClientConfig {
     warn!(
         "Insecure config: SSL certificates from relay servers will be trusted without verification"
     );
@@ -418,6 +541,16 @@ impl Client {
             .flatten()
     }
 
+    /// The largest packet which can currently be sent with [`Client::send`].
+    ///
+    /// This is [`MAX_PACKET_SIZE`] unless the server of the current relay connection
+    /// announced support for packets of up to [`MAX_LARGE_PACKET_SIZE`](crate::MAX_LARGE_PACKET_SIZE).
+    pub async fn max_packet_size(&self) -> usize {
+        self.send_actor(ActorMessage::MaxPacketSize)
+            .await
+            .unwrap_or(MAX_PACKET_SIZE)
+    }
+
     /// Send a ping to the server. Return once we get an expected pong.
     ///
     /// There must be a task polling `recv_detail` to process the `pong` response.
@@ -447,6 +580,20 @@ impl Client {
         self.send_actor(|s| ActorMessage::Send(dst_key, b, s)).await
     }
 
+    /// Forwards a packet to the server, which must be a relay server of the same mesh.
+    ///
+    /// Like [`Client::send`] this connects to the server if needed.
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) async fn forward(
+        &self,
+        src_key: PublicKey,
+        dst_key: PublicKey,
+        b: Bytes,
+    ) -> Result<(), ClientError> {
+        self.send_actor(|s| ActorMessage::Forward(src_key, dst_key, b, s))
+            .await
+    }
+
     /// Close the http relay connection.
     pub async fn close(self) -> Result<(), ClientError> {
         self.send_actor(ActorMessage::Close).await
@@ -511,6 +658,10 @@ impl Actor {
                             let res = self.local_addr();
                             s.send(Ok(res)).ok();
                         },
+                        ActorMessage::MaxPacketSize(s) => {
+                            let res = self.max_packet_size();
+                            s.send(Ok(res)).ok();
+                        },
                         ActorMessage::Ping(s) => {
                             self.ping(s).await;
                         },
@@ -522,6 +673,10 @@ impl Actor {
                             let res = self.send(key, data).await;
                             s.send(res).ok();
                         },
+                        ActorMessage::Forward(src, dst, data, s) => {
+                            let res = self.forward(src, dst, data).await;
+                            s.send(res).ok();
+                        },
                         ActorMessage::Close(s) => {
                             let res = self.close().await;
                             s.send(Ok(res)).ok();
@@ -581,21 +736,51 @@ impl Actor {
         .await
     }
 
-    async fn connect_0(&self) -> Result<(Conn, ConnReceiver), ClientError> {
-        let (reader, writer, local_addr) = match self.protocol {
-            Protocol::Websocket => {
-                let (reader, writer) = self.connect_ws().await?;
-                let local_addr = None;
-                (reader, writer, local_addr)
-            }
-            Protocol::Relay => {
-                let (reader, writer, local_addr) = self.connect_derp().await?;
-                (reader, writer, Some(local_addr))
-            }
+    async fn connect_0(&mut self) -> Result<(Conn, ConnReceiver), ClientError> {
+        let quic = match self.quic.clone().filter(|_| self.proxy_url.is_none()) {
+            Some((port, config)) => match self.connect_quic(port, config).await {
+                Ok(quic) => Some(quic),
+                Err(err) => {
+                    // UDP is likely blocked on this network, do not try again.
+                    warn!(
+                        "failed to relay over QUIC, falling back to {:?}: {err:#}",
+                        self.protocol
+                    );
+                    self.quic = None;
+                    None
+                }
+            },
+            None => None,
+        };
+        let (reader, writer, local_addr) = match quic {
+            Some((reader, writer)) => (reader, writer, None),
+            None => match self.protocol {
+                Protocol::Websocket => {
+                    let (reader, writer) = self.connect_ws().await?;
+                    let local_addr = None;
+                    (reader, writer, local_addr)
+                }
+                Protocol::Relay => match self.connect_derp().await {
+                    Ok((reader, writer, local_addr)) => (reader, writer, Some(local_addr)),
+                    Err(err)
+                        if self.websocket_fallback
+                            && self.proxy_url.is_none()
+                            && err.is_upgrade_failure() =>
+                    {
+                        warn!("relay upgrade failed, falling back to websockets: {err:#}");
+                        let (reader, writer) = self.connect_ws().await?;
+                        // The upgrade is likely to fail again on this network.
+                        self.protocol = Protocol::Websocket;
+                        (reader, writer, None)
+                    }
+                    Err(err) => return Err(err),
+                },
+            },
         };
 
         let (conn, receiver) =
             ConnBuilder::new(self.secret_key.clone(), local_addr, reader, writer)
+                .auth_token(self.auth_token.clone())
                 .build()
                 .await
                 .map_err(|e| ClientError::Build(e.to_string()))?;
@@ -635,6 +820,58 @@ impl Actor {
         Ok((reader, writer))
     }
 
+    async fn connect_quic(
+        &self,
+        port: u16,
+        config: quinn::ClientConfig,
+    ) -> Result<(ConnReader, ConnWriter), ClientError> {
+        let prefer_ipv6 = self.prefer_ipv6().await;
+        let dst_ip = self
+            .dns_resolver
+            .resolve_host(&self.url, prefer_ipv6)
+            .await?;
+        let addr = SocketAddr::new(dst_ip, port);
+        let host = self
+            .url
+            .host_str()
+            .ok_or_else(|| ClientError::InvalidUrl("missing url host".into()))?;
+
+        let bind_addr = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let endpoint = match self.bind_interface {
+            Some(ref interface) => {
+                let socket = std::net::UdpSocket::bind(bind_addr)?;
+                bind_to_interface(SockRef::from(&socket), interface, addr.is_ipv6())?;
+                quinn::Endpoint::new(
+                    quinn::EndpointConfig::default(),
+                    None,
+                    socket,
+                    Arc::new(quinn::TokioRuntime),
+                )?
+            }
+            None => quinn::Endpoint::client(bind_addr)?,
+        };
+        debug!(%addr, "dialing relay over QUIC");
+        let connecting = endpoint
+            .connect_with(config, addr, host)
+            .map_err(|err| ClientError::Quic(err.to_string()))?;
+        let conn = tokio::time::timeout(DIAL_NODE_TIMEOUT, connecting)
+            .await
+            .map_err(|_| ClientError::ConnectTimeout)?
+            .map_err(|err| ClientError::Quic(err.to_string()))?;
+        let (send, recv) = conn
+            .open_bi()
+            .await
+            .map_err(|err| ClientError::Quic(err.to_string()))?;
+        debug!("QUIC connection established");
+
+        let reader = ConnReader::Quic(QuicFrameReader::new(conn.clone(), recv));
+        let writer = ConnWriter::Quic(QuicFrameWriter::new(conn, send));
+        Ok((reader, writer))
+    }
+
     async fn connect_derp(&self) -> Result<(ConnReader, ConnWriter, SocketAddr), ClientError> {
         let url = self.url.clone();
         let tcp_stream = self.dial_url().await?;
@@ -759,10 +996,18 @@ impl Actor {
         }
     }
 
+    fn max_packet_size(&self) -> usize {
+        match self.relay_conn {
+            Some((ref conn, _)) if !self.is_closed => conn.max_packet_size(),
+            _ => MAX_PACKET_SIZE,
+        }
+    }
+
     async fn ping(&mut self, s: oneshot::Sender<Result<Duration, ClientError>>) {
         let connect_res = self.connect("ping").await.map(|(c, _)| c);
         let (ping, recv) = self.pings.register();
         trace!("ping: {}", hex::encode(ping));
+        let pong_timeout = self.pong_timeout;
 
         self.ping_tasks.spawn(async move {
             let res = match connect_res {
@@ -772,7 +1017,7 @@ impl Actor {
                         warn!("failed to send ping: {:?}", err);
                         Err(ClientError::Send)
                     } else {
-                        match tokio::time::timeout(PING_TIMEOUT, recv).await {
+                        match tokio::time::timeout(pong_timeout, recv).await {
                             Ok(Ok(())) => Ok(start.elapsed()),
                             Err(_) => Err(ClientError::PingTimeout),
                             Ok(Err(_)) => Err(ClientError::PingAborted),
@@ -788,6 +1033,9 @@ impl Actor {
     async fn send(&mut self, remote_node: NodeId, payload: Bytes) -> Result<(), ClientError> {
         trace!(remote_node = %remote_node.fmt_short(), len = payload.len(), "send");
         let (conn, _) = self.connect("send").await?;
+        if payload.len() > conn.max_packet_size() {
+            return Err(ClientError::PacketTooLarge(payload.len()));
+        }
         if conn.send(remote_node, payload).await.is_err() {
             self.close_for_reconnect().await;
             return Err(ClientError::Send);
@@ -795,6 +1043,24 @@ impl Actor {
         Ok(())
     }
 
+    async fn forward(
+        &mut self,
+        src: NodeId,
+        dst: NodeId,
+        payload: Bytes,
+    ) -> Result<(), ClientError> {
+        trace!(src = %src.fmt_short(), dst = %dst.fmt_short(), len = payload.len(), "forward");
+        let (conn, _) = self.connect("forward").await?;
+        if payload.len() > conn.max_packet_size() {
+            return Err(ClientError::PacketTooLarge(payload.len()));
+        }
+        if conn.forward(src, dst, payload).await.is_err() {
+            self.close_for_reconnect().await;
+            return Err(ClientError::Send);
+        }
+        Ok(())
+    }
+
     async fn send_pong(&mut self, data: [u8; 8]) -> Result<(), ClientError> {
         debug!("send_pong");
         let (conn, _) = self.connect("send_pong").await?;
@@ -858,11 +1124,7 @@ impl Actor {
         let addr = SocketAddr::new(dst_ip, port);
 
         debug!("connecting to {}", addr);
-        let tcp_stream =
-            tokio::time::timeout(
-                DIAL_NODE_TIMEOUT,
-                async move { TcpStream::connect(addr).await },
-            )
+        let tcp_stream = tokio::time::timeout(DIAL_NODE_TIMEOUT, self.connect_tcp(addr))
             .await
             .map_err(|_| ClientError::ConnectTimeout)?
             .map_err(ClientError::DialIO)?;
@@ -872,6 +1134,19 @@ impl Actor {
         Ok(tcp_stream)
     }
 
+    /// Opens a TCP connection, from the bind interface if one is set.
+    async fn connect_tcp(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let Some(ref interface) = self.bind_interface else {
+            return TcpStream::connect(addr).await;
+        };
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        bind_to_interface(SockRef::from(&socket), interface, addr.is_ipv6())?;
+        socket.connect(addr).await
+    }
+
     async fn dial_url_proxy(
         &self,
         proxy_url: Url,
@@ -891,12 +1166,10 @@ impl Actor {
 
         debug!(%proxy_addr, "connecting to proxy");
 
-        let tcp_stream = tokio::time::timeout(DIAL_NODE_TIMEOUT, async move {
-            TcpStream::connect(proxy_addr).await
-        })
-        .await
-        .map_err(|_| ClientError::ConnectTimeout)?
-        .map_err(ClientError::DialIO)?;
+        let tcp_stream = tokio::time::timeout(DIAL_NODE_TIMEOUT, self.connect_tcp(proxy_addr))
+            .await
+            .map_err(|_| ClientError::ConnectTimeout)?
+            .map_err(ClientError::DialIO)?;
 
         tcp_stream.set_nodelay(true)?;
 
@@ -1054,7 +1327,7 @@ impl DnsExt for DnsResolver {
         host: N,
     ) -> anyhow::Result<Option<IpAddr>> {
         let addrs = tokio::time::timeout(DNS_TIMEOUT, self.ipv4_lookup(host)).await??;
-        Ok(addrs.into_iter().next().map(|ip| IpAddr::V4(ip.0)))
+        Ok(addrs.into_iter().next().map(IpAddr::V4))
     }
 
     async fn lookup_ipv6<N: hickory_resolver::IntoName>(
@@ -1062,7 +1335,7 @@ impl DnsExt for DnsResolver {
         host: N,
     ) -> anyhow::Result<Option<IpAddr>> {
         let addrs = tokio::time::timeout(DNS_TIMEOUT, self.ipv6_lookup(host)).await??;
-        Ok(addrs.into_iter().next().map(|ip| IpAddr::V6(ip.0)))
+        Ok(addrs.into_iter().next().map(IpAddr::V6))
     }
 
     async fn resolve_host(&self, url: &Url, prefer_ipv6: bool) -> Result<IpAddr, ClientError> {
@@ -1134,6 +1407,46 @@ impl rustls::client::danger::ServerCertVerifier for NoCertVerifier {
     }
 }
 
+/// Binds a socket to the network interface named `interface`.
+///
+/// Packets sent on the socket then leave through this interface, regardless of the routing
+/// table.  This uses `SO_BINDTODEVICE` on Linux and Android, and `IP_BOUND_IF` or
+/// `IPV6_BOUND_IF` on macOS and iOS.  Other platforms return an
+/// [`io::ErrorKind::Unsupported`] error.
+pub fn bind_to_interface(socket: SockRef<'_>, interface: &str, ipv6: bool) -> io::Result<()> {
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    {
+        let _ = ipv6;
+        socket.bind_device(Some(interface.as_bytes()))
+    }
+    #[cfg(any(target_os = "ios", target_os = "macos"))]
+    {
+        let name = std::ffi::CString::new(interface)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        // SAFETY: `name` is a valid, NUL terminated string.
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        let index = std::num::NonZeroU32::new(index).ok_or_else(io::Error::last_os_error)?;
+        match ipv6 {
+            true => socket.bind_device_by_index_v6(Some(index)),
+            false => socket.bind_device_by_index_v4(Some(index)),
+        }
+    }
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "fuchsia",
+        target_os = "linux",
+        target_os = "ios",
+        target_os = "macos"
+    )))]
+    {
+        let _ = (socket, interface, ipv6);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binding to a network interface is not supported on this platform",
+        ))
+    }
+}
+
 fn url_port(url: &Url) -> Option<u16> {
     if let Some(port) = url.port() {
         return Some(port);
@@ -1155,6 +1468,19 @@ mod tests {
     use super::*;
     use crate::dns::default_resolver;
 
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_bind_to_interface() -> Result<()> {
+        let socket = TcpSocket::new_v4()?;
+        bind_to_interface(SockRef::from(&socket), "lo", false)?;
+        let device = SockRef::from(&socket).device()?;
+        assert_eq!(device.as_deref(), Some(&b"lo"[..]));
+
+        let res = bind_to_interface(SockRef::from(&socket), "iroh-test-none0", false);
+        assert!(res.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_recv_detail_connect_error() -> Result<()> {
         let _guard = iroh_test::logging::setup();
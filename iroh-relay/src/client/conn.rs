@@ -5,7 +5,10 @@
 use std::{
     net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
     time::Duration,
 };
@@ -18,7 +21,10 @@ use futures_util::{
     stream::{SplitSink, SplitStream, StreamExt},
     SinkExt,
 };
-use iroh_base::key::{NodeId, SecretKey};
+use iroh_base::{
+    key::{NodeId, SecretKey},
+    relay_map::RelayAuthToken,
+};
 use tokio::sync::mpsc;
 use tokio_tungstenite_wasm::WebSocketStream;
 use tokio_util::{
@@ -31,9 +37,10 @@ use crate::{
     client::streams::{MaybeTlsStreamReader, MaybeTlsStreamWriter},
     defaults::timeouts::CLIENT_RECV_TIMEOUT,
     protos::relay::{
-        write_frame, ClientInfo, DerpCodec, Frame, MAX_PACKET_SIZE, PER_CLIENT_READ_QUEUE_DEPTH,
-        PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
+        write_frame, ClientInfo, DerpCodec, Frame, MAX_LARGE_PACKET_SIZE, MAX_PACKET_SIZE,
+        PER_CLIENT_READ_QUEUE_DEPTH, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
     },
+    quic::streams::{QuicFrameReader, QuicFrameWriter},
 };
 
 impl PartialEq for Conn {
@@ -84,6 +91,11 @@ pub struct ConnTasks {
     ///
     /// Is `None` in tests or when using websockets (because we don't control connection establishment in browsers).
     local_addr: Option<SocketAddr>,
+    /// The largest packet the server accepts on this connection.
+    ///
+    /// Starts out at [`MAX_PACKET_SIZE`] and is raised once the server announces support for
+    /// larger packets.
+    max_packet_size: Arc<AtomicUsize>,
     /// Channel on which to communicate to the server. The associated [`mpsc::Receiver`] will close
     /// if there is ever an error writing to the server.
     writer_channel: mpsc::Sender<ConnWriterMessage>,
@@ -95,7 +107,8 @@ pub struct ConnTasks {
 impl Conn {
     /// Sends a packet to the node identified by `dstkey`
     ///
-    /// Errors if the packet is larger than [`MAX_PACKET_SIZE`]
+    /// The packet must not be larger than [`Conn::max_packet_size`], otherwise the server
+    /// will drop the connection.
     pub async fn send(&self, dst: NodeId, packet: Bytes) -> Result<()> {
         trace!(%dst, len = packet.len(), "[RELAY] send");
 
@@ -106,6 +119,20 @@ impl Conn {
         Ok(())
     }
 
+    /// Forwards a packet from `src` to `dst` to a relay server of the same mesh.
+    ///
+    /// The packet must not be larger than [`Conn::max_packet_size`], otherwise the server
+    /// will drop the connection.
+    pub(crate) async fn forward(&self, src: NodeId, dst: NodeId, packet: Bytes) -> Result<()> {
+        trace!(%src, %dst, len = packet.len(), "[RELAY] forward");
+
+        self.inner
+            .writer_channel
+            .send(ConnWriterMessage::Forward { src, dst, packet })
+            .await?;
+        Ok(())
+    }
+
     /// Send a ping with 8 bytes of random data.
     pub async fn send_ping(&self, data: [u8; 8]) -> Result<()> {
         self.inner
@@ -143,6 +170,14 @@ impl Conn {
         self.inner.local_addr
     }
 
+    /// The largest packet which can be sent over this [`Conn`].
+    ///
+    /// This is [`MAX_PACKET_SIZE`] unless the server announced that it accepts packets of up
+    /// to [`MAX_LARGE_PACKET_SIZE`].
+    pub fn max_packet_size(&self) -> usize {
+        self.inner.max_packet_size.load(Ordering::Relaxed)
+    }
+
     /// Whether or not this [`Conn`] is closed.
     ///
     /// The [`Conn`] is considered closed if the write side of the connection is no longer running.
@@ -201,6 +236,10 @@ fn process_incoming_frame(frame: Frame) -> Result<ReceivedMessage> {
                 try_for,
             })
         }
+        Frame::GoAway { close_in } => {
+            let close_in = Duration::from_millis(close_in as u64);
+            Ok(ReceivedMessage::GoAway { close_in })
+        }
         _ => bail!("unexpected packet: {:?}", frame.typ()),
     }
 }
@@ -210,6 +249,12 @@ fn process_incoming_frame(frame: Frame) -> Result<ReceivedMessage> {
 enum ConnWriterMessage {
     /// Send a packet (addressed to the [`NodeId`]) to the server
     Packet((NodeId, Bytes)),
+    /// Forward a packet to a relay server of the same mesh
+    Forward {
+        src: NodeId,
+        dst: NodeId,
+        packet: Bytes,
+    },
     /// Send a pong to the server
     Pong([u8; 8]),
     /// Send a ping to the server
@@ -237,6 +282,9 @@ impl ConnWriterTasks {
                 ConnWriterMessage::Packet((key, bytes)) => {
                     send_packet(&mut self.writer, key, bytes).await?;
                 }
+                ConnWriterMessage::Forward { src, dst, packet } => {
+                    forward_packet(&mut self.writer, src, dst, packet).await?;
+                }
                 ConnWriterMessage::Pong(data) => {
                     write_frame(&mut self.writer, Frame::Pong { data }, None).await?;
                     self.writer.flush().await?;
@@ -266,16 +314,19 @@ pub struct ConnBuilder {
     reader: ConnReader,
     writer: ConnWriter,
     local_addr: Option<SocketAddr>,
+    auth_token: Option<RelayAuthToken>,
 }
 
 pub(crate) enum ConnReader {
     Derp(FramedRead<MaybeTlsStreamReader, DerpCodec>),
     Ws(SplitStream<WebSocketStream>),
+    Quic(QuicFrameReader),
 }
 
 pub(crate) enum ConnWriter {
     Derp(FramedWrite<MaybeTlsStreamWriter, DerpCodec>),
     Ws(SplitSink<WebSocketStream, tokio_tungstenite_wasm::Message>),
+    Quic(QuicFrameWriter),
 }
 
 fn tung_wasm_to_io_err(e: tokio_tungstenite_wasm::Error) -> std::io::Error {
@@ -303,6 +354,7 @@ impl Stream for ConnReader {
                 Poll::Ready(None) => Poll::Ready(None),
                 Poll::Pending => Poll::Pending,
             },
+            Self::Quic(ref mut quic) => Pin::new(quic).poll_next(cx),
         }
     }
 }
@@ -314,6 +366,7 @@ impl Sink<Frame> for ConnWriter {
         match *self {
             Self::Derp(ref mut ws) => Pin::new(ws).poll_ready(cx),
             Self::Ws(ref mut ws) => Pin::new(ws).poll_ready(cx).map_err(tung_wasm_to_io_err),
+            Self::Quic(ref mut quic) => Pin::new(quic).poll_ready(cx),
         }
     }
 
@@ -325,6 +378,7 @@ impl Sink<Frame> for ConnWriter {
                     item.encode_for_ws_msg(),
                 ))
                 .map_err(tung_wasm_to_io_err),
+            Self::Quic(ref mut quic) => Pin::new(quic).start_send(item),
         }
     }
 
@@ -332,6 +386,7 @@ impl Sink<Frame> for ConnWriter {
         match *self {
             Self::Derp(ref mut ws) => Pin::new(ws).poll_flush(cx),
             Self::Ws(ref mut ws) => Pin::new(ws).poll_flush(cx).map_err(tung_wasm_to_io_err),
+            Self::Quic(ref mut quic) => Pin::new(quic).poll_flush(cx),
         }
     }
 
@@ -339,6 +394,7 @@ impl Sink<Frame> for ConnWriter {
         match *self {
             Self::Derp(ref mut ws) => Pin::new(ws).poll_close(cx),
             Self::Ws(ref mut ws) => Pin::new(ws).poll_close(cx).map_err(tung_wasm_to_io_err),
+            Self::Quic(ref mut quic) => Pin::new(quic).poll_close(cx),
         }
     }
 }
@@ -355,13 +411,22 @@ impl ConnBuilder {
             reader,
             writer,
             local_addr,
+            auth_token: None,
         }
     }
 
+    /// Sets the token to authenticate to the server with.
+    pub fn auth_token(mut self, token: Option<RelayAuthToken>) -> Self {
+        self.auth_token = token;
+        self
+    }
+
     async fn server_handshake(&mut self) -> Result<()> {
         debug!("server_handshake: started");
         let client_info = ClientInfo {
             version: PROTOCOL_VERSION,
+            large_packets: true,
+            auth_token: self.auth_token.take(),
         };
         debug!("server_handshake: sending client_key: {:?}", &client_info);
         crate::protos::relay::send_client_key(&mut self.writer, &self.secret_key, &client_info)
@@ -386,13 +451,23 @@ impl ConnBuilder {
             .instrument(info_span!("conn.writer")),
         );
 
+        let max_packet_size = Arc::new(AtomicUsize::new(MAX_PACKET_SIZE));
         let (reader_sender, reader_recv) = mpsc::channel(PER_CLIENT_READ_QUEUE_DEPTH);
         let reader_task = tokio::task::spawn({
             let writer_sender = writer_sender.clone();
+            let max_packet_size = max_packet_size.clone();
             async move {
                 loop {
                     let frame = tokio::time::timeout(CLIENT_RECV_TIMEOUT, self.reader.next()).await;
                     let res = match frame {
+                        Ok(Some(Ok(Frame::MaxPacketSize { size }))) => {
+                            // Only affects what we send, nothing to report to the receiver.
+                            let size =
+                                (size as usize).clamp(MAX_PACKET_SIZE, MAX_LARGE_PACKET_SIZE);
+                            debug!(size, "server accepts large packets");
+                            max_packet_size.store(size, Ordering::Relaxed);
+                            continue;
+                        }
                         Ok(Some(Ok(frame))) => process_incoming_frame(frame),
                         Ok(Some(Err(err))) => {
                             // Error processing incoming messages
@@ -425,6 +500,7 @@ impl ConnBuilder {
         let conn = Conn {
             inner: Arc::new(ConnTasks {
                 local_addr: self.local_addr,
+                max_packet_size,
                 writer_channel: writer_sender,
                 writer_task: AbortOnDropHandle::new(writer_task),
                 reader_task: AbortOnDropHandle::new(reader_task),
@@ -484,6 +560,12 @@ pub enum ReceivedMessage {
         /// than a few seconds.
         try_for: Duration,
     },
+    /// A one-way message from server to client, asking the client to move to another relay
+    /// server because this one is draining.
+    GoAway {
+        /// How long until the server closes the connection.
+        close_in: Duration,
+    },
 }
 
 pub(crate) async fn send_packet<S: Sink<Frame, Error = std::io::Error> + Unpin>(
@@ -492,7 +574,7 @@ pub(crate) async fn send_packet<S: Sink<Frame, Error = std::io::Error> + Unpin>(
     packet: Bytes,
 ) -> Result<()> {
     ensure!(
-        packet.len() <= MAX_PACKET_SIZE,
+        packet.len() <= MAX_LARGE_PACKET_SIZE,
         "packet too big: {}",
         packet.len()
     );
@@ -506,3 +588,26 @@ pub(crate) async fn send_packet<S: Sink<Frame, Error = std::io::Error> + Unpin>(
 
     Ok(())
 }
+
+pub(crate) async fn forward_packet<S: Sink<Frame, Error = std::io::Error> + Unpin>(
+    mut writer: S,
+    src: NodeId,
+    dst: NodeId,
+    packet: Bytes,
+) -> Result<()> {
+    ensure!(
+        packet.len() <= MAX_LARGE_PACKET_SIZE,
+        "packet too big: {}",
+        packet.len()
+    );
+
+    let frame = Frame::ForwardPacket {
+        src_key: src,
+        dst_key: dst,
+        packet,
+    };
+    writer.send(frame).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
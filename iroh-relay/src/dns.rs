@@ -1,14 +1,32 @@
-use std::net::{IpAddr, Ipv6Addr};
+//! DNS resolution used to connect to relay servers and by iroh.
+//!
+//! By default a [hickory] resolver using the system's DNS configuration is used, see
+//! [`default_resolver`].  Other ways to resolve names can be plugged in by implementing the
+//! [`Resolver`] trait and wrapping it in a [`DnsResolver`] using [`DnsResolver::new`].
+//!
+//! With the `encrypted-dns` feature the hickory resolver can also query a nameserver using
+//! DNS-over-HTTPS or DNS-over-TLS, for networks which block or tamper with plaintext DNS.
+//!
+//! [hickory]: hickory_resolver
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
 
 use anyhow::Result;
-use hickory_resolver::{AsyncResolver, TokioAsyncResolver};
+use futures_lite::future::Boxed as BoxFuture;
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig},
+    AsyncResolver, IntoName, TokioAsyncResolver,
+};
 use once_cell::sync::Lazy;
 
-/// The DNS resolver type used throughout `iroh`.
-pub(crate) type DnsResolver = TokioAsyncResolver;
-
-static DNS_RESOLVER: Lazy<TokioAsyncResolver> =
-    Lazy::new(|| create_default_resolver().expect("unable to create DNS resolver"));
+static DNS_RESOLVER: Lazy<DnsResolver> = Lazy::new(|| {
+    let resolver = create_default_resolver().expect("unable to create DNS resolver");
+    DnsResolver::from(resolver)
+});
 
 /// Get a reference to the default DNS resolver.
 ///
@@ -18,6 +36,205 @@ pub fn default_resolver() -> &'static DnsResolver {
     &DNS_RESOLVER
 }
 
+/// Resolves DNS names, for use in a [`DnsResolver`].
+///
+/// Implement this to replace the built-in hickory resolver, e.g. to resolve names using
+/// DNS-over-HTTPS only, to honour split-horizon DNS or to resolve names deterministically in
+/// tests.
+///
+/// The names passed in are as given by the caller, they can be fully qualified with a
+/// trailing dot or not.
+pub trait Resolver: fmt::Debug + Send + Sync + 'static {
+    /// Looks up the IPv4 addresses of `host`, i.e. its `A` records.
+    fn lookup_ipv4(&self, host: String) -> BoxFuture<Result<Vec<Ipv4Addr>>>;
+
+    /// Looks up the IPv6 addresses of `host`, i.e. its `AAAA` records.
+    fn lookup_ipv6(&self, host: String) -> BoxFuture<Result<Vec<Ipv6Addr>>>;
+
+    /// Looks up the `TXT` records of `host`.
+    fn lookup_txt(&self, host: String) -> BoxFuture<Result<Vec<TxtRecord>>>;
+
+    /// Clears any cached lookup results.
+    ///
+    /// This is called when the network changes.  The default implementation does nothing.
+    fn clear_cache(&self) {}
+}
+
+/// A `TXT` record returned from a [`Resolver`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxtRecord {
+    name: String,
+    value: String,
+}
+
+impl TxtRecord {
+    /// Creates a new record.
+    ///
+    /// The `name` is the DNS name the record belongs to, the `value` is the concatenation of
+    /// all character strings of the record.
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Returns the DNS name the record belongs to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the value of the record.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+/// The DNS resolver used throughout iroh.
+///
+/// This is a cheaply cloneable handle to a [`Resolver`].
+#[derive(Debug, Clone)]
+pub struct DnsResolver(Arc<dyn Resolver>);
+
+impl DnsResolver {
+    /// Creates a DNS resolver using a custom [`Resolver`].
+    pub fn new(resolver: impl Resolver) -> Self {
+        Self(Arc::new(resolver))
+    }
+
+    /// Creates a hickory DNS resolver which only queries the given nameserver over UDP.
+    pub fn with_nameserver(nameserver: SocketAddr) -> Self {
+        let mut config = ResolverConfig::new();
+        let nameserver_config = NameServerConfig::new(nameserver, Protocol::Udp);
+        config.add_name_server(nameserver_config);
+        Self::from(AsyncResolver::tokio(config, Default::default()))
+    }
+
+    /// Creates a hickory DNS resolver which only queries the given nameserver using
+    /// DNS-over-HTTPS.
+    ///
+    /// The `tls_dns_name` is the name the TLS certificate of the nameserver is verified
+    /// against, e.g. `cloudflare-dns.com` for `1.1.1.1:443`.  Queries are sent to the
+    /// `/dns-query` path, as defined in [RFC 8484].
+    ///
+    /// [RFC 8484]: https://www.rfc-editor.org/rfc/rfc8484
+    #[cfg(feature = "encrypted-dns")]
+    #[cfg_attr(iroh_docsrs, doc(cfg(feature = "encrypted-dns")))]
+    pub fn with_https_nameserver(nameserver: SocketAddr, tls_dns_name: impl Into<String>) -> Self {
+        Self::with_encrypted_nameserver(nameserver, Protocol::Https, tls_dns_name.into())
+    }
+
+    /// Creates a hickory DNS resolver which only queries the given nameserver using
+    /// DNS-over-TLS.
+    ///
+    /// The `tls_dns_name` is the name the TLS certificate of the nameserver is verified
+    /// against, e.g. `cloudflare-dns.com` for `1.1.1.1:853`.
+    #[cfg(feature = "encrypted-dns")]
+    #[cfg_attr(iroh_docsrs, doc(cfg(feature = "encrypted-dns")))]
+    pub fn with_tls_nameserver(nameserver: SocketAddr, tls_dns_name: impl Into<String>) -> Self {
+        Self::with_encrypted_nameserver(nameserver, Protocol::Tls, tls_dns_name.into())
+    }
+
+    #[cfg(feature = "encrypted-dns")]
+    fn with_encrypted_nameserver(
+        nameserver: SocketAddr,
+        protocol: Protocol,
+        tls_dns_name: String,
+    ) -> Self {
+        let mut config = ResolverConfig::new();
+        let mut nameserver_config = NameServerConfig::new(nameserver, protocol);
+        nameserver_config.tls_dns_name = Some(tls_dns_name);
+        config.add_name_server(nameserver_config);
+        // see [`ResolverExt::lookup_ipv4_ipv6`] for info on why we avoid `LookupIpStrategy::Ipv4AndIpv6`
+        let mut options = hickory_resolver::config::ResolverOpts::default();
+        options.ip_strategy = LookupIpStrategy::Ipv4thenIpv6;
+        Self::from(AsyncResolver::tokio(config, options))
+    }
+
+    /// Looks up the IPv4 addresses of `host`.
+    pub async fn ipv4_lookup<N: IntoName>(
+        &self,
+        host: N,
+    ) -> Result<impl Iterator<Item = Ipv4Addr>> {
+        let host = host.into_name()?.to_utf8();
+        Ok(self.0.lookup_ipv4(host).await?.into_iter())
+    }
+
+    /// Looks up the IPv6 addresses of `host`.
+    pub async fn ipv6_lookup<N: IntoName>(
+        &self,
+        host: N,
+    ) -> Result<impl Iterator<Item = Ipv6Addr>> {
+        let host = host.into_name()?.to_utf8();
+        Ok(self.0.lookup_ipv6(host).await?.into_iter())
+    }
+
+    /// Looks up the `TXT` records of `host`.
+    pub async fn txt_lookup<N: IntoName>(
+        &self,
+        host: N,
+    ) -> Result<impl Iterator<Item = TxtRecord>> {
+        let host = host.into_name()?.to_utf8();
+        Ok(self.0.lookup_txt(host).await?.into_iter())
+    }
+
+    /// Clears any cached lookup results.
+    pub fn clear_cache(&self) {
+        self.0.clear_cache()
+    }
+}
+
+impl From<TokioAsyncResolver> for DnsResolver {
+    fn from(resolver: TokioAsyncResolver) -> Self {
+        Self::new(HickoryResolver(resolver))
+    }
+}
+
+/// The built-in [`Resolver`] using hickory.
+#[derive(Debug, Clone)]
+struct HickoryResolver(TokioAsyncResolver);
+
+impl Resolver for HickoryResolver {
+    fn lookup_ipv4(&self, host: String) -> BoxFuture<Result<Vec<Ipv4Addr>>> {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            let lookup = resolver.ipv4_lookup(host).await?;
+            Ok(lookup.into_iter().map(|ip| ip.0).collect())
+        })
+    }
+
+    fn lookup_ipv6(&self, host: String) -> BoxFuture<Result<Vec<Ipv6Addr>>> {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            let lookup = resolver.ipv6_lookup(host).await?;
+            Ok(lookup.into_iter().map(|ip| ip.0).collect())
+        })
+    }
+
+    fn lookup_txt(&self, host: String) -> BoxFuture<Result<Vec<TxtRecord>>> {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            let lookup = resolver.txt_lookup(host).await?;
+            let records = lookup
+                .as_lookup()
+                .records()
+                .iter()
+                .filter_map(|record| match record.data() {
+                    hickory_proto::rr::RData::TXT(txt) => {
+                        Some(TxtRecord::new(record.name().to_utf8(), txt.to_string()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            Ok(records)
+        })
+    }
+
+    fn clear_cache(&self) {
+        self.0.clear_cache()
+    }
+}
+
 /// Deprecated IPv6 site-local anycast addresses still configured by windows.
 ///
 /// Windows still configures these site-local addresses as soon even as an IPv6 loopback
@@ -57,8 +274,59 @@ fn create_default_resolver() -> Result<TokioAsyncResolver> {
     }
 
     // see [`ResolverExt::lookup_ipv4_ipv6`] for info on why we avoid `LookupIpStrategy::Ipv4AndIpv6`
-    options.ip_strategy = hickory_resolver::config::LookupIpStrategy::Ipv4thenIpv6;
+    options.ip_strategy = LookupIpStrategy::Ipv4thenIpv6;
 
     let resolver = AsyncResolver::tokio(config, options);
     Ok(resolver)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct StaticResolver;
+
+    impl Resolver for StaticResolver {
+        fn lookup_ipv4(&self, host: String) -> BoxFuture<Result<Vec<Ipv4Addr>>> {
+            Box::pin(async move {
+                anyhow::ensure!(host == "relay.example.", "unknown host {host}");
+                Ok(vec![Ipv4Addr::LOCALHOST])
+            })
+        }
+
+        fn lookup_ipv6(&self, _host: String) -> BoxFuture<Result<Vec<Ipv6Addr>>> {
+            Box::pin(async move { Ok(vec![]) })
+        }
+
+        fn lookup_txt(&self, host: String) -> BoxFuture<Result<Vec<TxtRecord>>> {
+            Box::pin(async move { Ok(vec![TxtRecord::new(host, "hello")]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_resolver() {
+        let resolver = DnsResolver::new(StaticResolver);
+        let addrs: Vec<_> = resolver
+            .ipv4_lookup("relay.example.")
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(addrs, vec![Ipv4Addr::LOCALHOST]);
+        assert!(resolver.ipv4_lookup("other.example.").await.is_err());
+
+        let records: Vec<_> = resolver.txt_lookup("txt.example.").await.unwrap().collect();
+        assert_eq!(records, vec![TxtRecord::new("txt.example.", "hello")]);
+    }
+
+    #[cfg(feature = "encrypted-dns")]
+    #[tokio::test]
+    async fn test_encrypted_nameserver_unreachable() {
+        // Nothing listens on this port, the lookups fail instead of falling back to plaintext.
+        let nameserver: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let resolver = DnsResolver::with_https_nameserver(nameserver, "dns.example");
+        assert!(resolver.ipv4_lookup("relay.example.").await.is_err());
+        let resolver = DnsResolver::with_tls_nameserver(nameserver, "dns.example");
+        assert!(resolver.txt_lookup("relay.example.").await.is_err());
+    }
+}
@@ -31,6 +31,7 @@
 
 pub mod client;
 pub mod defaults;
+pub mod dns;
 pub mod http;
 pub mod protos;
 pub mod quic;
@@ -38,11 +39,8 @@ pub mod quic;
 #[cfg_attr(iroh_docsrs, doc(cfg(feature = "server")))]
 pub mod server;
 
-#[cfg(test)]
-mod dns;
-
 pub use iroh_base::node_addr::RelayUrl;
-pub use protos::relay::MAX_PACKET_SIZE;
+pub use protos::relay::{MAX_LARGE_PACKET_SIZE, MAX_PACKET_SIZE};
 
 pub use self::client::{
     conn::{Conn as RelayConn, ReceivedMessage},
@@ -6,21 +6,29 @@
 use std::{
     net::{Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{bail, Context as _, Result};
 use clap::Parser;
+use iroh_base::{
+    key::{NodeId, SecretKey},
+    relay_map::RelayAuthToken,
+};
 use iroh_relay::{
     defaults::{
         DEFAULT_HTTPS_PORT, DEFAULT_HTTP_PORT, DEFAULT_METRICS_PORT, DEFAULT_RELAY_QUIC_PORT,
         DEFAULT_STUN_PORT,
     },
     server::{self as relay, ClientConnRateLimit, QuicConfig},
+    RelayUrl,
 };
 use serde::{Deserialize, Serialize};
-use tokio_rustls_acme::{caches::DirCache, AcmeConfig};
+use tokio_rustls_acme::{caches::DirCache, AcmeConfig, UseChallenge};
 use tracing::debug;
 use tracing_subscriber::{prelude::*, EnvFilter};
+use url::Url;
 
 /// The default `http_bind_port` when using `--dev`.
 const DEV_MODE_HTTP_PORT: u16 = 3340;
@@ -58,6 +66,25 @@ enum CertMode {
     LetsEncrypt,
 }
 
+/// The ACME challenge used to prove control of the hostname to LetsEncrypt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+enum AcmeChallenge {
+    /// Answered by the HTTPS server, only port `443` needs to be reachable.
+    #[default]
+    TlsAlpn01,
+    /// Answered by the HTTP server, port `80` needs to be reachable.
+    Http01,
+}
+
+impl From<AcmeChallenge> for UseChallenge {
+    fn from(challenge: AcmeChallenge) -> Self {
+        match challenge {
+            AcmeChallenge::TlsAlpn01 => UseChallenge::TlsAlpn01,
+            AcmeChallenge::Http01 => UseChallenge::Http01,
+        }
+    }
+}
+
 fn load_certs(
     filename: impl AsRef<Path>,
 ) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>> {
@@ -144,6 +171,14 @@ struct Config {
     ///
     /// Defaults to using the `http_bind_addr` with the port set to [`DEFAULT_STUN_PORT`].
     stun_bind_addr: Option<SocketAddr>,
+    /// Whether to also answer STUN requests on the QUIC port.
+    ///
+    /// Requires `enable_stun` and `enable_quic_addr_discovery`.  Useful when only a single
+    /// UDP port can be exposed, e.g. behind a load balancer.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    enable_stun_on_quic: bool,
     /// Whether to allow QUIC connections for QUIC address discovery
     ///
     /// If no `tls` is set, this will error.
@@ -155,6 +190,19 @@ struct Config {
     ///
     /// Disabled if not present.
     limits: Option<Limits>,
+    /// Which nodes may use the Relay server.
+    ///
+    /// Defaults to `"everyone"`.
+    #[serde(default)]
+    access: AccessConfig,
+    /// Forwarding of packets to other Relay servers.
+    ///
+    /// Disabled if not present.
+    mesh: Option<MeshConfig>,
+    /// Where to export the relayed bytes and duration of each client connection.
+    ///
+    /// Disabled if not present.
+    usage: Option<UsageConfig>,
     /// Whether to run the metrics server.
     ///
     /// Defaults to `true`, when the metrics feature is enabled.
@@ -165,6 +213,15 @@ struct Config {
     /// Defaults to `http_bind_addr` with the port set to [`DEFAULT_METRICS_PORT`]
     /// (`[::]:9090` when `http_bind_addr` is set to the default).
     metrics_bind_addr: Option<SocketAddr>,
+    /// How long connected clients get to move to another relay server, in seconds.
+    ///
+    /// Defaults to `30`.
+    ///
+    /// On `SIGTERM` the server stops accepting new clients, asks the connected clients to
+    /// move and shuts down after this grace period.  On `SIGINT` (Ctrl-C) the server shuts
+    /// down immediately.
+    #[serde(default = "cfg_defaults::drain_grace_period_secs")]
+    drain_grace_period_secs: u64,
 }
 
 impl Config {
@@ -192,10 +249,15 @@ impl Default for Config {
             tls: None,
             enable_stun: cfg_defaults::enable_stun(),
             stun_bind_addr: None,
+            enable_stun_on_quic: false,
             enable_quic_addr_discovery: cfg_defaults::enable_quic_addr_discovery(),
             limits: None,
+            access: AccessConfig::default(),
+            mesh: None,
+            usage: None,
             enable_metrics: cfg_defaults::enable_metrics(),
             metrics_bind_addr: None,
+            drain_grace_period_secs: cfg_defaults::drain_grace_period_secs(),
         }
     }
 }
@@ -221,6 +283,14 @@ mod cfg_defaults {
         true
     }
 
+    pub(crate) fn drain_grace_period_secs() -> u64 {
+        30
+    }
+
+    pub(crate) fn usage_push_interval_secs() -> u64 {
+        60
+    }
+
     pub(crate) mod tls_config {
         pub(crate) fn prod_tls() -> bool {
             true
@@ -286,6 +356,15 @@ struct TlsConfig {
     ///
     /// Used when `cert_mode` is `LetsEncrypt`.
     contact: Option<String>,
+    /// The ACME challenge to use.
+    ///
+    /// Possible options: 'TlsAlpn01', 'Http01'.  Default is `TlsAlpn01`.
+    ///
+    /// Only used when `cert_mode` is `LetsEncrypt`.  `Http01` is answered on the
+    /// `http_bind_addr`, use it when port `443` is not reachable from the internet directly,
+    /// e.g. behind a TLS terminating load balancer.
+    #[serde(default)]
+    acme_challenge: AcmeChallenge,
     /// **This field should never be manually set**
     ///
     /// When `true`, it will force the relay to ignore binding to https. It is only
@@ -325,6 +404,116 @@ impl TlsConfig {
     }
 }
 
+/// Which nodes may use the Relay server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AccessConfig {
+    /// Every node may use the Relay server.
+    #[default]
+    Everyone,
+    /// Only the listed nodes may use the Relay server.
+    Allowlist(Vec<NodeId>),
+    /// Only nodes which present one of the listed auth tokens may use the Relay server.
+    Tokens(Vec<RelayAuthToken>),
+    /// A node may use the Relay server if a `POST` of its node ID to this URL succeeds.
+    ///
+    /// The auth token presented by the node, if any, is sent as bearer token.
+    HttpAuthorizer(Url),
+}
+
+impl From<AccessConfig> for relay::AccessConfig {
+    fn from(access: AccessConfig) -> Self {
+        match access {
+            AccessConfig::Everyone => relay::AccessConfig::Everyone,
+            AccessConfig::Allowlist(nodes) => relay::AccessConfig::allowlist(nodes),
+            AccessConfig::Tokens(tokens) => relay::AccessConfig::tokens(tokens),
+            AccessConfig::HttpAuthorizer(url) => relay::AccessConfig::http_authorizer(url),
+        }
+    }
+}
+
+/// Forwarding of packets to other Relay servers of a mesh.
+///
+/// Every Relay server of the mesh lists all the others as peers.  The `access`
+/// configuration must allow the node IDs of the peers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct MeshConfig {
+    /// The secret key this Relay server connects to its peers with.
+    secret_key: String,
+    /// The other Relay servers of the mesh.
+    peers: Vec<MeshPeer>,
+}
+
+/// Another Relay server of the mesh.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct MeshPeer {
+    /// The URL of the Relay server.
+    url: RelayUrl,
+    /// The node ID of the `secret_key` in the mesh configuration of the Relay server.
+    node_id: NodeId,
+}
+
+impl TryFrom<&MeshConfig> for relay::MeshConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(mesh: &MeshConfig) -> Result<Self> {
+        let secret_key: SecretKey = mesh.secret_key.parse().context("invalid mesh secret_key")?;
+        let peers = mesh
+            .peers
+            .iter()
+            .map(|peer| relay::MeshPeer {
+                url: peer.url.clone(),
+                node_id: peer.node_id,
+            })
+            .collect();
+        Ok(Self { secret_key, peers })
+    }
+}
+
+/// Where to export the usage of the Relay server.
+///
+/// A record of the relayed bytes and the duration is exported for each client connection
+/// when it closes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UsageConfig {
+    /// Log the records.
+    Log,
+    /// Append the records to this CSV file.
+    Csv(PathBuf),
+    /// Periodically `POST` the records as CSV to an HTTP endpoint.
+    Http(HttpUsageConfig),
+}
+
+/// Pushing of usage records to an HTTP endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct HttpUsageConfig {
+    /// The URL to `POST` the records to.
+    url: Url,
+    /// How often to push the records, in seconds.
+    ///
+    /// Defaults to `60`.
+    #[serde(default = "cfg_defaults::usage_push_interval_secs")]
+    push_interval_secs: u64,
+}
+
+impl UsageConfig {
+    fn sink(&self) -> Result<Arc<dyn relay::UsageSink>> {
+        let sink: Arc<dyn relay::UsageSink> = match self {
+            UsageConfig::Log => Arc::new(relay::LogUsageSink),
+            UsageConfig::Csv(path) => Arc::new(
+                relay::CsvUsageSink::open(path)
+                    .with_context(|| format!("failed to open usage file {}", path.display()))?,
+            ),
+            UsageConfig::Http(http) => Arc::new(relay::HttpUsageSink::new(
+                http.url.clone(),
+                Duration::from_secs(http.push_interval_secs),
+            )),
+        };
+        Ok(sink)
+    }
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct Limits {
     /// Rate limit for accepting new connection. Unlimited if not set.
@@ -333,6 +522,19 @@ struct Limits {
     accept_conn_burst: Option<usize>,
     /// Rate limiting configuration per client.
     client: Option<PerClientRateLimitConfig>,
+    /// Rate limiting of STUN requests per source IP address.
+    stun: Option<StunRateLimitConfig>,
+}
+
+/// Rate limit configuration for STUN requests from each source IP address.
+///
+/// Requests exceeding the limit are dropped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StunRateLimitConfig {
+    /// Maximum number of requests per second.
+    requests_per_second: Option<u32>,
+    /// Maximum number of requests in a single burst.
+    max_burst: Option<u32>,
 }
 
 /// Rate limit configuration for each connected client.
@@ -346,13 +548,17 @@ struct Limits {
 struct PerClientRateLimitConfig {
     /// Rate limit configuration for the incoming data from the client.
     rx: Option<RateLimitConfig>,
+    /// Rate limit configuration for the outgoing data to the client.
+    ///
+    /// Packets exceeding the limit are dropped.
+    tx: Option<RateLimitConfig>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct RateLimitConfig {
     /// Maximum number of bytes per second.
     bytes_per_second: Option<u32>,
-    /// Maximum number of bytes to read in a single burst.
+    /// Maximum number of bytes to transfer in a single burst.
     max_burst_bytes: Option<u32>,
 }
 
@@ -410,18 +616,42 @@ async fn main() -> Result<()> {
     if cfg.tls.is_none() && cfg.enable_quic_addr_discovery {
         bail!("If QUIC address discovery is enabled, TLS must also be configured");
     };
+    let drain_grace_period = Duration::from_secs(cfg.drain_grace_period_secs);
     let relay_config = build_relay_config(cfg).await?;
     debug!("{relay_config:#?}");
 
     let mut relay = relay::Server::spawn(relay_config).await?;
 
-    tokio::select! {
+    let drain = tokio::select! {
         biased;
-        _ = tokio::signal::ctrl_c() => (),
-        _ = relay.task_handle() => (),
+        _ = tokio::signal::ctrl_c() => false,
+        res = terminate() => {
+            res?;
+            true
+        }
+        _ = relay.task_handle() => false,
+    };
+
+    if drain {
+        relay.drain(drain_grace_period).await
+    } else {
+        relay.shutdown().await
     }
+}
 
-    relay.shutdown().await
+/// Resolves when the process receives `SIGTERM`.
+#[cfg(unix)]
+async fn terminate() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+/// Never resolves, there is no `SIGTERM` on this platform.
+#[cfg(not(unix))]
+async fn terminate() -> Result<()> {
+    std::future::pending().await
 }
 
 async fn maybe_load_tls(
@@ -462,7 +692,8 @@ async fn maybe_load_tls(
             let config = AcmeConfig::new(vec![hostname.clone()])
                 .contact([format!("mailto:{}", contact)])
                 .cache_option(Some(DirCache::new(tls.cert_dir())))
-                .directory_lets_encrypt(tls.prod_tls);
+                .directory_lets_encrypt(tls.prod_tls)
+                .challenge_type(tls.acme_challenge.into());
             let state = config.state();
             let resolver = state.resolver().clone();
             let server_config = server_config.with_cert_resolver(resolver);
@@ -477,6 +708,44 @@ async fn maybe_load_tls(
     }))
 }
 
+/// Converts a [`RateLimitConfig`], returns `None` if no rate is configured.
+fn client_rate_limit(cfg: &RateLimitConfig) -> Result<Option<ClientConnRateLimit>> {
+    if cfg.bytes_per_second.is_none() && cfg.max_burst_bytes.is_some() {
+        bail!("bytes_per_seconds must be specified to enable the rate-limiter");
+    }
+    match cfg.bytes_per_second {
+        Some(bps) => Ok(Some(ClientConnRateLimit {
+            bytes_per_second: bps
+                .try_into()
+                .context("bytes_per_second must be non-zero u32")?,
+            max_burst_bytes: cfg
+                .max_burst_bytes
+                .map(|v| v.try_into().context("max_burst_bytes must be non-zero u32"))
+                .transpose()?,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Converts a [`StunRateLimitConfig`], returns `None` if no rate is configured.
+fn stun_rate_limit(cfg: &StunRateLimitConfig) -> Result<Option<relay::StunRateLimit>> {
+    if cfg.requests_per_second.is_none() && cfg.max_burst.is_some() {
+        bail!("requests_per_second must be specified to enable the STUN rate-limiter");
+    }
+    match cfg.requests_per_second {
+        Some(rps) => Ok(Some(relay::StunRateLimit {
+            requests_per_second: rps
+                .try_into()
+                .context("requests_per_second must be non-zero u32")?,
+            max_burst: cfg
+                .max_burst
+                .map(|v| v.try_into().context("max_burst must be non-zero u32"))
+                .transpose()?,
+        })),
+        None => Ok(None),
+    }
+}
+
 /// Convert the TOML-loaded config to the [`relay::RelayConfig`] format.
 async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::Error>> {
     // Don't bind to https, even if tls configuration is available.
@@ -498,32 +767,22 @@ async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::
     };
     let limits = match cfg.limits {
         Some(ref limits) => {
-            let client_rx = match &limits.client {
-                Some(PerClientRateLimitConfig { rx: Some(rx) }) => {
-                    if rx.bytes_per_second.is_none() && rx.max_burst_bytes.is_some() {
-                        bail!("bytes_per_seconds must be specified to enable the rate-limiter");
-                    }
-                    match rx.bytes_per_second {
-                        Some(bps) => Some(ClientConnRateLimit {
-                            bytes_per_second: bps
-                                .try_into()
-                                .context("bytes_per_second must be non-zero u32")?,
-                            max_burst_bytes: rx
-                                .max_burst_bytes
-                                .map(|v| {
-                                    v.try_into().context("max_burst_bytes must be non-zero u32")
-                                })
-                                .transpose()?,
-                        }),
-                        None => None,
-                    }
-                }
-                Some(PerClientRateLimitConfig { rx: None }) | None => None,
-            };
+            let client = limits.client.clone().unwrap_or_default();
             relay::Limits {
                 accept_conn_limit: limits.accept_conn_limit,
                 accept_conn_burst: limits.accept_conn_burst,
-                client_rx,
+                client_rx: client
+                    .rx
+                    .as_ref()
+                    .map(client_rate_limit)
+                    .transpose()?
+                    .flatten(),
+                client_tx: client
+                    .tx
+                    .as_ref()
+                    .map(client_rate_limit)
+                    .transpose()?
+                    .flatten(),
             }
         }
         None => Default::default(),
@@ -534,9 +793,28 @@ async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::
         // if `dangerous_http_only` is set, do not pass in any tls configuration
         tls: relay_tls.and_then(|tls| if dangerous_http_only { None } else { Some(tls) }),
         limits,
+        access: cfg.access.clone().into(),
+        mesh: cfg
+            .mesh
+            .as_ref()
+            .map(relay::MeshConfig::try_from)
+            .transpose()?,
+        usage: cfg.usage.as_ref().map(UsageConfig::sink).transpose()?,
     };
+    let stun_rate_limit = cfg
+        .limits
+        .as_ref()
+        .and_then(|limits| limits.stun.as_ref())
+        .map(stun_rate_limit)
+        .transpose()?
+        .flatten();
+    if cfg.enable_stun_on_quic && !cfg.enable_quic_addr_discovery {
+        bail!("enable_stun_on_quic requires enable_quic_addr_discovery");
+    }
     let stun_config = relay::StunConfig {
         bind_addr: cfg.stun_bind_addr(),
+        rate_limit: stun_rate_limit,
+        quic: cfg.enable_stun_on_quic,
     };
     Ok(relay::ServerConfig {
         relay: Some(relay_config),
@@ -626,6 +904,54 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_stun_rate_limit_config() -> TestResult {
+        let config = "
+            [limits.stun]
+            requests_per_second = 10
+            max_burst = 20
+        ";
+        let config = Config::from_str(config)?;
+        let relay_config = build_relay_config(config).await?;
+
+        let rate_limit = relay_config
+            .stun
+            .expect("no stun config")
+            .rate_limit
+            .expect("ratelimit");
+        assert_eq!(
+            rate_limit.requests_per_second,
+            NonZeroU32::try_from(10).unwrap()
+        );
+        assert_eq!(
+            rate_limit.max_burst,
+            Some(NonZeroU32::try_from(20).unwrap())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tx_rate_limit_config() -> TestResult {
+        let config = "
+            [limits.client.tx]
+            bytes_per_second = 1000
+        ";
+        let config = Config::from_str(config)?;
+        let relay_config = build_relay_config(config).await?;
+
+        let relay = relay_config.relay.expect("no relay config");
+        assert!(relay.limits.client_rx.is_none());
+        let client_tx = relay.limits.client_tx.expect("ratelimit");
+        assert_eq!(
+            client_tx.bytes_per_second,
+            NonZeroU32::try_from(1000).unwrap()
+        );
+        assert_eq!(client_tx.max_burst_bytes, None);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_rate_limit_default() -> TestResult {
         let config = Config::from_str("")?;
@@ -636,4 +962,146 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_drain_grace_period_config() -> TestResult {
+        let config = Config::from_str("")?;
+        assert_eq!(config.drain_grace_period_secs, 30);
+
+        let config = Config::from_str("drain_grace_period_secs = 120")?;
+        assert_eq!(config.drain_grace_period_secs, 120);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_usage_config() -> TestResult {
+        let config = Config::from_str("")?;
+        assert_eq!(config.usage, None);
+
+        let config = Config::from_str("usage = \"log\"")?;
+        assert_eq!(config.usage, Some(UsageConfig::Log));
+
+        let config = Config::from_str(
+            "
+            [usage]
+            csv = \"/var/lib/iroh-relay/usage.csv\"
+        ",
+        )?;
+        assert_eq!(
+            config.usage,
+            Some(UsageConfig::Csv("/var/lib/iroh-relay/usage.csv".into()))
+        );
+
+        let config = Config::from_str(
+            "
+            [usage.http]
+            url = \"https://billing.example.com/relay\"
+        ",
+        )?;
+        assert_eq!(
+            config.usage,
+            Some(UsageConfig::Http(HttpUsageConfig {
+                url: "https://billing.example.com/relay".parse()?,
+                push_interval_secs: 60,
+            }))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_config() -> TestResult {
+        let config = Config::from_str("")?;
+        assert_eq!(config.access, AccessConfig::Everyone);
+
+        let node_id = iroh_base::key::SecretKey::generate().public();
+        let config = Config::from_str(&format!(
+            "
+            [access]
+            allowlist = [\"{node_id}\"]
+        "
+        ))?;
+        assert_eq!(config.access, AccessConfig::Allowlist(vec![node_id]));
+
+        let config = Config::from_str(
+            "
+            [access]
+            tokens = [\"secret\"]
+        ",
+        )?;
+        assert_eq!(
+            config.access,
+            AccessConfig::Tokens(vec![RelayAuthToken::new("secret")])
+        );
+
+        let config = Config::from_str(
+            "
+            [access]
+            http_authorizer = \"https://auth.example.com/relay\"
+        ",
+        )?;
+        assert_eq!(
+            config.access,
+            AccessConfig::HttpAuthorizer("https://auth.example.com/relay".parse()?)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_acme_challenge_config() -> TestResult {
+        let config = Config::from_str(
+            "
+            [tls]
+            cert_mode = \"LetsEncrypt\"
+            hostname = \"relay.example.com\"
+            contact = \"ops@example.com\"
+        ",
+        )?;
+        assert_eq!(config.tls.unwrap().acme_challenge, AcmeChallenge::TlsAlpn01);
+
+        let config = Config::from_str(
+            "
+            [tls]
+            cert_mode = \"LetsEncrypt\"
+            hostname = \"relay.example.com\"
+            contact = \"ops@example.com\"
+            acme_challenge = \"Http01\"
+        ",
+        )?;
+        assert_eq!(config.tls.unwrap().acme_challenge, AcmeChallenge::Http01);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mesh_config() -> TestResult {
+        let config = Config::from_str("")?;
+        assert_eq!(config.mesh, None);
+
+        let secret_key = SecretKey::generate();
+        let node_id = SecretKey::generate().public();
+        let config = Config::from_str(&format!(
+            "
+            [mesh]
+            secret_key = \"{secret_key}\"
+
+            [[mesh.peers]]
+            url = \"https://relay2.example.com\"
+            node_id = \"{node_id}\"
+        "
+        ))?;
+        let mesh = relay::MeshConfig::try_from(config.mesh.as_ref().unwrap())?;
+        assert_eq!(mesh.secret_key.public(), secret_key.public());
+        assert_eq!(
+            mesh.peers,
+            vec![relay::MeshPeer {
+                url: "https://relay2.example.com".parse()?,
+                node_id,
+            }]
+        );
+
+        Ok(())
+    }
 }
@@ -6,11 +6,29 @@
 //!  * client connects
 //!  * -> client sends `FrameType::ClientInfo`
 //!
+//!  * if the client announced support for large packets in its `ClientInfo`, the server
+//!    replies with `FrameType::MaxPacketSize`
+//!  * the client may include an auth token in its `ClientInfo`, the server closes the
+//!    connection after sending a `FrameType::Health` problem if it denies access
+//!
 //!  Steady state:
 //!  * server occasionally sends `FrameType::KeepAlive` (or `FrameType::Ping`)
 //!  * client responds to any `FrameType::Ping` with a `FrameType::Pong`
 //!  * clients sends `FrameType::SendPacket`
 //!  * server then sends `FrameType::RecvPacket` to recipient
+//!
+//! Draining:
+//!  * server sends `FrameType::GoAway` to all clients when it is about to shut down
+//!  * clients should move to another relay server before the server closes the connection
+//!  * new clients are refused with `FrameType::GoAway` while the server is draining
+//!
+//! Large packets:
+//!  * packets are at most [`MAX_PACKET_SIZE`] bytes, unless the server allowed larger ones
+//!    with `FrameType::MaxPacketSize`
+//!  * packets larger than [`MAX_PACKET_SIZE`] must consist of datagrams which are each
+//!    prefixed with their length as a little-endian `u16`
+//!  * the server splits them between the datagrams for recipients which do not support
+//!    large packets
 
 use std::time::Duration;
 
@@ -20,8 +38,10 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures_lite::{Stream, StreamExt};
 use futures_sink::Sink;
 use futures_util::SinkExt;
-use iroh_base::key::{PublicKey, SecretKey, Signature, PUBLIC_KEY_LENGTH};
-use postcard::experimental::max_size::MaxSize;
+use iroh_base::{
+    key::{PublicKey, SecretKey, Signature, PUBLIC_KEY_LENGTH},
+    relay_map::RelayAuthToken,
+};
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 
@@ -30,6 +50,13 @@ use tokio_util::codec::{Decoder, Encoder};
 /// including its on-wire framing overhead)
 pub const MAX_PACKET_SIZE: usize = 64 * 1024;
 
+/// The maximum size of a packet sent over relay when both the relay server and the client
+/// support large packets.
+///
+/// This fits a batch of datagrams sent with generic segmentation offload into a single
+/// packet.
+pub const MAX_LARGE_PACKET_SIZE: usize = 256 * 1024;
+
 /// The maximum frame size.
 ///
 /// This is also the minimum burst size that a rate-limiter has to accept.
@@ -63,6 +90,13 @@ pub(crate) const PER_CLIENT_READ_QUEUE_DEPTH: usize = 512;
 /// with nodes running earlier protocol versions.
 pub(crate) const PROTOCOL_VERSION: usize = 3;
 
+/// Appended to the [`ClientInfo`] by clients which support large packets.
+const LARGE_PACKETS: u8 = 1u8;
+
+/// Appended to the [`ClientInfo`] after the large packets byte, followed by the
+/// postcard-encoded auth token.
+const AUTH_TOKEN: u8 = 2u8;
+
 /// Indicates this IS the client's home node
 const PREFERRED: u8 = 1u8;
 /// Indicates this IS NOT the client's home node
@@ -91,8 +125,13 @@ pub(crate) enum FrameType {
     ///
     /// 32B pub key of peer that's gone
     PeerGone = 8,
-    /// Frames 9-11 concern meshing, which we have eliminated from our version of the protocol.
-    /// Messages with these frames will be ignored.
+    // Frames 9 and 11 concerned meshing in the original protocol, which we have eliminated
+    // from our version of the protocol.  Messages with these frames will be ignored.
+    /// Sent from a relay server to another relay server of its mesh, see
+    /// [`MeshConfig`](crate::server::MeshConfig).
+    ///
+    /// 32B src pub key + 32B dst pub key + packet bytes
+    ForwardPacket = 10,
     /// 8 byte ping payload, to be echoed back in FrameType::Pong
     Ping = 12,
     /// 8 byte payload, the contents of ping being replied to
@@ -110,6 +149,16 @@ pub(crate) enum FrameType {
     ///
     /// Handled on the `[relay::Client]`, but currently never sent on the `[relay::Server]`
     Restarting = 15,
+    /// Sent from server to client after the handshake, if the client announced that it
+    /// supports large packets.
+    ///
+    /// 4 byte big endian u32: the largest packet the client may send, and may receive.
+    MaxPacketSize = 16,
+    /// Sent from server to client when the server is draining, the client should move to
+    /// another relay server.
+    ///
+    /// 4 byte big endian u32: milliseconds until the server closes the connection.
+    GoAway = 17,
     #[num_enum(default)]
     Unknown = 255,
 }
@@ -120,10 +169,22 @@ impl std::fmt::Display for FrameType {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, MaxSize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub(crate) struct ClientInfo {
     /// The relay protocol version that the client was built with.
     pub(crate) version: usize,
+    /// Whether the client supports packets up to [`MAX_LARGE_PACKET_SIZE`].
+    ///
+    /// This is encoded as a trailing byte after the serialized struct, which older servers
+    /// ignore.
+    #[serde(skip)]
+    pub(crate) large_packets: bool,
+    /// The token authenticating the client to the relay server.
+    ///
+    /// This is encoded after the large packets byte, which is therefore always present
+    /// when a token is sent.
+    #[serde(skip)]
+    pub(crate) auth_token: Option<RelayAuthToken>,
 }
 
 /// Writes complete frame, errors if it is unable to write within the given `timeout`.
@@ -153,7 +214,16 @@ pub(crate) async fn send_client_key<S: Sink<Frame, Error = std::io::Error> + Unp
     client_secret_key: &SecretKey,
     client_info: &ClientInfo,
 ) -> anyhow::Result<()> {
-    let msg = postcard::to_stdvec(client_info)?;
+    let mut msg = postcard::to_stdvec(client_info)?;
+    if client_info.large_packets {
+        msg.push(LARGE_PACKETS);
+    } else if client_info.auth_token.is_some() {
+        msg.push(0);
+    }
+    if let Some(ref token) = client_info.auth_token {
+        msg.push(AUTH_TOKEN);
+        msg.extend(postcard::to_stdvec(token.as_str())?);
+    }
     let signature = client_secret_key.sign(&msg);
 
     writer
@@ -196,7 +266,13 @@ pub(crate) async fn recv_client_key<S: Stream<Item = anyhow::Result<Frame>> + Un
         client_public_key
             .verify(&message, &signature)
             .context("invalid signature")?;
-        let info: ClientInfo = postcard::from_bytes(&message).context("deserialization")?;
+        let (mut info, rest): (ClientInfo, _) =
+            postcard::take_from_bytes(&message).context("deserialization")?;
+        info.large_packets = rest.first() == Some(&LARGE_PACKETS);
+        if let Some((&AUTH_TOKEN, token)) = rest.get(1..).and_then(|rest| rest.split_first()) {
+            let token: String = postcard::from_bytes(token).context("auth token")?;
+            info.auth_token = Some(RelayAuthToken::new(token));
+        }
         Ok((client_public_key, info))
     } else {
         anyhow::bail!("expected FrameType::ClientInfo");
@@ -226,6 +302,11 @@ pub(crate) enum Frame {
         src_key: PublicKey,
         content: Bytes,
     },
+    ForwardPacket {
+        src_key: PublicKey,
+        dst_key: PublicKey,
+        packet: Bytes,
+    },
     KeepAlive,
     NotePreferred {
         preferred: bool,
@@ -246,6 +327,12 @@ pub(crate) enum Frame {
         reconnect_in: u32,
         try_for: u32,
     },
+    MaxPacketSize {
+        size: u32,
+    },
+    GoAway {
+        close_in: u32,
+    },
 }
 
 impl Frame {
@@ -254,6 +341,7 @@ impl Frame {
             Frame::ClientInfo { .. } => FrameType::ClientInfo,
             Frame::SendPacket { .. } => FrameType::SendPacket,
             Frame::RecvPacket { .. } => FrameType::RecvPacket,
+            Frame::ForwardPacket { .. } => FrameType::ForwardPacket,
             Frame::KeepAlive => FrameType::KeepAlive,
             Frame::NotePreferred { .. } => FrameType::NotePreferred,
             Frame::NodeGone { .. } => FrameType::PeerGone,
@@ -261,6 +349,8 @@ impl Frame {
             Frame::Pong { .. } => FrameType::Pong,
             Frame::Health { .. } => FrameType::Health,
             Frame::Restarting { .. } => FrameType::Restarting,
+            Frame::MaxPacketSize { .. } => FrameType::MaxPacketSize,
+            Frame::GoAway { .. } => FrameType::GoAway,
         }
     }
 
@@ -277,6 +367,7 @@ impl Frame {
                 src_key: _,
                 content,
             } => PUBLIC_KEY_LENGTH + content.len(),
+            Frame::ForwardPacket { packet, .. } => 2 * PUBLIC_KEY_LENGTH + packet.len(),
             Frame::KeepAlive => 0,
             Frame::NotePreferred { .. } => 1,
             Frame::NodeGone { .. } => PUBLIC_KEY_LENGTH,
@@ -284,6 +375,8 @@ impl Frame {
             Frame::Pong { .. } => 8,
             Frame::Health { problem } => problem.len(),
             Frame::Restarting { .. } => 4 + 4,
+            Frame::MaxPacketSize { .. } => 4,
+            Frame::GoAway { .. } => 4,
         }
     }
 
@@ -337,6 +430,15 @@ impl Frame {
                 dst.put(src_key.as_ref());
                 dst.put(content.as_ref());
             }
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            } => {
+                dst.put(src_key.as_ref());
+                dst.put(dst_key.as_ref());
+                dst.put(packet.as_ref());
+            }
             Frame::KeepAlive => {}
             Frame::NotePreferred { preferred } => {
                 if *preferred {
@@ -364,6 +466,12 @@ impl Frame {
                 dst.put_u32(*reconnect_in);
                 dst.put_u32(*try_for);
             }
+            Frame::MaxPacketSize { size } => {
+                dst.put_u32(*size);
+            }
+            Frame::GoAway { close_in } => {
+                dst.put_u32(*close_in);
+            }
         }
     }
 
@@ -403,8 +511,8 @@ impl Frame {
                 );
                 let packet_len = content.len() - PUBLIC_KEY_LENGTH;
                 ensure!(
-                    packet_len <= MAX_PACKET_SIZE,
-                    "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
+                    packet_len <= MAX_LARGE_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_LARGE_PACKET_SIZE}"
                 );
                 let dst_key = PublicKey::try_from(&content[..PUBLIC_KEY_LENGTH])?;
                 let packet = content.slice(PUBLIC_KEY_LENGTH..);
@@ -418,13 +526,34 @@ impl Frame {
                 );
                 let packet_len = content.len() - PUBLIC_KEY_LENGTH;
                 ensure!(
-                    packet_len <= MAX_PACKET_SIZE,
-                    "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
+                    packet_len <= MAX_LARGE_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_LARGE_PACKET_SIZE}"
                 );
                 let src_key = PublicKey::try_from(&content[..PUBLIC_KEY_LENGTH])?;
                 let content = content.slice(PUBLIC_KEY_LENGTH..);
                 Self::RecvPacket { src_key, content }
             }
+            FrameType::ForwardPacket => {
+                ensure!(
+                    content.len() >= 2 * PUBLIC_KEY_LENGTH,
+                    "invalid forward packet frame length: {}",
+                    content.len()
+                );
+                let packet_len = content.len() - 2 * PUBLIC_KEY_LENGTH;
+                ensure!(
+                    packet_len <= MAX_LARGE_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_LARGE_PACKET_SIZE}"
+                );
+                let src_key = PublicKey::try_from(&content[..PUBLIC_KEY_LENGTH])?;
+                let dst_key =
+                    PublicKey::try_from(&content[PUBLIC_KEY_LENGTH..2 * PUBLIC_KEY_LENGTH])?;
+                let packet = content.slice(2 * PUBLIC_KEY_LENGTH..);
+                Self::ForwardPacket {
+                    src_key,
+                    dst_key,
+                    packet,
+                }
+            }
             FrameType::KeepAlive => {
                 anyhow::ensure!(content.is_empty(), "invalid keep alive frame length");
                 Self::KeepAlive
@@ -472,6 +601,24 @@ impl Frame {
                     try_for,
                 }
             }
+            FrameType::MaxPacketSize => {
+                ensure!(
+                    content.len() == 4,
+                    "invalid max packet size frame length: {}",
+                    content.len()
+                );
+                let size = u32::from_be_bytes(content[..].try_into()?);
+                Self::MaxPacketSize { size }
+            }
+            FrameType::GoAway => {
+                ensure!(
+                    content.len() == 4,
+                    "invalid go away frame length: {}",
+                    content.len()
+                );
+                let close_in = u32::from_be_bytes(content[..].try_into()?);
+                Self::GoAway { close_in }
+            }
             _ => {
                 anyhow::bail!("invalid frame type: {:?}", frame_type);
             }
@@ -480,6 +627,38 @@ impl Frame {
     }
 }
 
+/// Splits a packet into packets of at most [`MAX_PACKET_SIZE`], for recipients which do not
+/// support large packets.
+///
+/// The packet is only split between the length-prefixed datagrams it consists of, so each
+/// part is a valid packet on its own.  Returns `None` if the packet is not made of such
+/// datagrams.
+#[cfg(feature = "server")]
+pub(crate) fn split_large_packet(packet: &Bytes) -> Option<Vec<Bytes>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while pos < packet.len() {
+        let len_bytes = packet.get(pos..pos + 2)?;
+        let end = pos + 2 + u16::from_le_bytes([len_bytes[0], len_bytes[1]]) as usize;
+        if end > packet.len() {
+            return None;
+        }
+        if end - start > MAX_PACKET_SIZE {
+            if pos == start {
+                return None;
+            }
+            parts.push(packet.slice(start..pos));
+            start = pos;
+        }
+        pos = end;
+    }
+    if start < pos {
+        parts.push(packet.slice(start..pos));
+    }
+    Some(parts)
+}
+
 const HEADER_LEN: usize = 5;
 
 impl Decoder for DerpCodec {
@@ -607,20 +786,71 @@ mod tests {
         let client_key = SecretKey::generate();
         let client_info = ClientInfo {
             version: PROTOCOL_VERSION,
+            large_packets: false,
+            auth_token: None,
         };
         println!("client_key pub {:?}", client_key.public());
         send_client_key(&mut writer, &client_key, &client_info).await?;
         let (client_pub_key, got_client_info) = recv_client_key(&mut reader).await?;
         assert_eq!(client_key.public(), client_pub_key);
         assert_eq!(client_info, got_client_info);
+
+        let client_info = ClientInfo {
+            version: PROTOCOL_VERSION,
+            large_packets: true,
+            auth_token: None,
+        };
+        send_client_key(&mut writer, &client_key, &client_info).await?;
+        let (_, got_client_info) = recv_client_key(&mut reader).await?;
+        assert_eq!(client_info, got_client_info);
+
+        for large_packets in [false, true] {
+            let client_info = ClientInfo {
+                version: PROTOCOL_VERSION,
+                large_packets,
+                auth_token: Some(RelayAuthToken::new("secret")),
+            };
+            send_client_key(&mut writer, &client_key, &client_info).await?;
+            let (_, got_client_info) = recv_client_key(&mut reader).await?;
+            assert_eq!(client_info, got_client_info);
+        }
         Ok(())
     }
 
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_split_large_packet() {
+        fn packet(datagram_lens: &[usize]) -> Bytes {
+            let mut buf = BytesMut::new();
+            for len in datagram_lens {
+                buf.put_u16_le(*len as u16);
+                buf.put_bytes(0, *len);
+            }
+            buf.freeze()
+        }
+
+        let small = packet(&[1200, 1200]);
+        assert_eq!(split_large_packet(&small), Some(vec![small.clone()]));
+
+        let large = packet(&[40_000, 40_000, 40_000, 100]);
+        let parts = split_large_packet(&large).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.len() <= MAX_PACKET_SIZE));
+        assert_eq!(parts.concat(), large.to_vec());
+        assert_eq!(parts[2], packet(&[40_000, 100]));
+
+        // truncated datagram
+        assert_eq!(split_large_packet(&large.slice(..large.len() - 1)), None);
+        assert_eq!(split_large_packet(&Bytes::from_static(&[1])), None);
+    }
+
     #[test]
     fn test_frame_snapshot() -> anyhow::Result<()> {
         let client_key = SecretKey::from_bytes(&[42u8; 32]);
         let client_info = ClientInfo {
             version: PROTOCOL_VERSION,
+            large_packets: false,
+            auth_token: None,
         };
         let message = postcard::to_stdvec(&client_info)?;
         let signature = client_key.sign(&message);
@@ -690,6 +920,7 @@ mod tests {
                 },
                 "0f 00 00 00 0a 00 00 00 14",
             ),
+            (Frame::GoAway { close_in: 30_000 }, "11 00 00 75 30"),
         ];
 
         for (frame, expected_hex) in frames {
@@ -730,6 +961,8 @@ mod proptests {
         let client_info = (secret_key()).prop_map(|secret_key| {
             let info = ClientInfo {
                 version: PROTOCOL_VERSION,
+                large_packets: false,
+                auth_token: None,
             };
             let msg = postcard::to_stdvec(&info).expect("using default ClientInfo");
             let signature = secret_key.sign(&msg);
@@ -743,6 +976,12 @@ mod proptests {
             (key(), data(32)).prop_map(|(dst_key, packet)| Frame::SendPacket { dst_key, packet });
         let recv_packet =
             (key(), data(32)).prop_map(|(src_key, content)| Frame::RecvPacket { src_key, content });
+        let forward_packet =
+            (key(), key(), data(32)).prop_map(|(src_key, dst_key, packet)| Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            });
         let keep_alive = Just(Frame::KeepAlive);
         let note_preferred = any::<bool>().prop_map(|preferred| Frame::NotePreferred { preferred });
         let peer_gone = key().prop_map(|peer| Frame::NodeGone { node_id: peer });
@@ -754,10 +993,13 @@ mod proptests {
                 reconnect_in,
                 try_for,
             });
+        let max_packet_size = any::<u32>().prop_map(|size| Frame::MaxPacketSize { size });
+        let go_away = any::<u32>().prop_map(|close_in| Frame::GoAway { close_in });
         prop_oneof![
             client_info,
             send_packet,
             recv_packet,
+            forward_packet,
             keep_alive,
             note_preferred,
             peer_gone,
@@ -765,6 +1007,8 @@ mod proptests {
             pong,
             health,
             restarting,
+            max_packet_size,
+            go_away,
         ]
     }
 
@@ -776,11 +1020,14 @@ mod proptests {
                 | FrameType::Ping
                 | FrameType::Pong
                 | FrameType::Restarting
+                | FrameType::MaxPacketSize
+                | FrameType::GoAway
                 | FrameType::PeerGone => true,
                 FrameType::ClientInfo
                 | FrameType::Health
                 | FrameType::SendPacket
                 | FrameType::RecvPacket
+                | FrameType::ForwardPacket
                 | FrameType::Unknown => false,
             }
         }
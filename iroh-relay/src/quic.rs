@@ -1,12 +1,16 @@
 //! Create a QUIC server that accepts connections
-//! for QUIC address discovery.
+//! for QUIC address discovery, and for relaying over QUIC.
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Result;
 use quinn::{crypto::rustls::QuicClientConfig, VarInt};
 
+pub(crate) mod streams;
+
 /// ALPN for our quic addr discovery
 pub const ALPN_QUIC_ADDR_DISC: &[u8] = b"/iroh-qad/0";
+/// ALPN for relaying over QUIC, see [`ClientBuilder::quic`](crate::client::ClientBuilder::quic)
+pub const ALPN_QUIC_RELAY: &[u8] = b"/iroh-relay-quic/0";
 /// Endpoint close error code
 pub const QUIC_ADDR_DISC_CLOSE_CODE: VarInt = VarInt::from_u32(1);
 /// Endpoint close reason
@@ -14,13 +18,17 @@ pub const QUIC_ADDR_DISC_CLOSE_REASON: &[u8] = b"finished";
 
 #[cfg(feature = "server")]
 pub(crate) mod server {
-    use quinn::{crypto::rustls::QuicServerConfig, ApplicationClose};
+    use quinn::{crypto::rustls::QuicServerConfig, ApplicationClose, Runtime};
     use tokio::task::JoinSet;
     use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
     use tracing::{debug, info, info_span, Instrument};
 
     use super::*;
     pub use crate::server::QuicConfig;
+    use crate::server::{
+        stun::{StunQuicSocket, StunResponder},
+        RelayAcceptor,
+    };
 
     pub struct QuicServer {
         bind_addr: SocketAddr,
@@ -56,6 +64,9 @@ pub(crate) mod server {
         /// Spawns a QUIC server that creates and QUIC endpoint and listens
         /// for QUIC connections for address discovery
         ///
+        /// If a `relay` is given, clients can also connect to it over QUIC.  If a `stun`
+        /// responder is given, STUN requests received on the QUIC port are answered.
+        ///
         /// # Errors
         /// If the given `quic_config` contains a [`rustls::ServerConfig`] that cannot
         /// be converted to a [`QuicServerConfig`], usually because it does not support
@@ -65,19 +76,44 @@ pub(crate) mod server {
         /// If there is a panic during a connection, it will be propagated
         /// up here. Any other errors in a connection will be logged as a
         ///  warning.
-        pub(crate) fn spawn(mut quic_config: QuicConfig) -> Result<Self> {
+        pub(crate) fn spawn(
+            mut quic_config: QuicConfig,
+            relay: Option<RelayAcceptor>,
+            stun: Option<Arc<StunResponder>>,
+        ) -> Result<Self> {
             quic_config.server_config.alpn_protocols =
                 vec![crate::quic::ALPN_QUIC_ADDR_DISC.to_vec()];
+            if relay.is_some() {
+                quic_config
+                    .server_config
+                    .alpn_protocols
+                    .push(ALPN_QUIC_RELAY.to_vec());
+            }
             let server_config = QuicServerConfig::try_from(quic_config.server_config)?;
             let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_config));
             let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
+            // Relay clients open a single stream for the relay protocol.
+            let bidi_streams: u8 = if relay.is_some() { 1 } else { 0 };
             transport_config
                 .max_concurrent_uni_streams(0_u8.into())
-                .max_concurrent_bidi_streams(0_u8.into())
+                .max_concurrent_bidi_streams(bidi_streams.into())
                 // enable sending quic address discovery frames
                 .send_observed_address_reports(true);
 
-            let endpoint = quinn::Endpoint::server(server_config, quic_config.bind_addr)?;
+            let endpoint = match stun {
+                Some(stun) => {
+                    let runtime = Arc::new(quinn::TokioRuntime);
+                    let socket = std::net::UdpSocket::bind(quic_config.bind_addr)?;
+                    let socket = runtime.wrap_udp_socket(socket)?;
+                    quinn::Endpoint::new_with_abstract_socket(
+                        quinn::EndpointConfig::default(),
+                        Some(server_config),
+                        Arc::new(StunQuicSocket::new(socket, stun)),
+                        runtime,
+                    )?
+                }
+                None => quinn::Endpoint::server(server_config, quic_config.bind_addr)?,
+            };
             let bind_addr = endpoint.local_addr()?;
 
             info!(?bind_addr, "QUIC server listening on");
@@ -109,7 +145,7 @@ pub(crate) mod server {
                                      debug!("accepting connection");
                                      let remote_addr = conn.remote_address();
                                      set.spawn(
-                                         handle_connection(conn).instrument(info_span!("qad-conn", %remote_addr))
+                                         handle_connection(conn, relay.clone()).instrument(info_span!("qad-conn", %remote_addr))
                                      );                                }
                                 None => {
                                     debug!("endpoint closed");
@@ -168,7 +204,10 @@ pub(crate) mod server {
     }
 
     /// Handle the connection from the client.
-    async fn handle_connection(incoming: quinn::Incoming) -> Result<()> {
+    async fn handle_connection(
+        incoming: quinn::Incoming,
+        relay: Option<RelayAcceptor>,
+    ) -> Result<()> {
         let connection = match incoming.await {
             Ok(conn) => conn,
             Err(e) => {
@@ -176,6 +215,14 @@ pub(crate) mod server {
             }
         };
         debug!("established");
+        let alpn = connection
+            .handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|data| data.protocol);
+        if let Some(relay) = relay.filter(|_| alpn.as_deref() == Some(ALPN_QUIC_RELAY)) {
+            let (send, recv) = connection.accept_bi().await?;
+            return relay.accept_quic(connection, send, recv).await;
+        }
         // wait for the client to close the connection
         let connection_err = connection.closed().await;
         match connection_err {
@@ -190,7 +237,7 @@ pub(crate) mod server {
 }
 
 /// Handles the client side of QUIC address discovery.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct QuicClient {
     /// A QUIC Endpoint.
     ep: quinn::Endpoint,
@@ -287,10 +334,14 @@ mod tests {
         // create a server config with self signed certificates
         let (_, server_config) = super::super::server::testing::self_signed_tls_certs_and_config();
         let bind_addr = SocketAddr::new(host.into(), 0);
-        let quic_server = QuicServer::spawn(QuicConfig {
-            server_config,
-            bind_addr,
-        })?;
+        let quic_server = QuicServer::spawn(
+            QuicConfig {
+                server_config,
+                bind_addr,
+            },
+            None,
+            None,
+        )?;
 
         // create a client-side endpoint
         let client_endpoint = quinn::Endpoint::client(SocketAddr::new(host.into(), 0))?;
@@ -0,0 +1,140 @@
+//! Relay protocol frames over a QUIC connection.
+//!
+//! Relayed packets are sent as QUIC datagrams when they fit, so they are not held up by
+//! each other and are not retransmitted when lost, just like the UDP datagrams they carry.
+//! All other frames, and packets too large for a datagram, are sent on a single
+//! bidirectional stream opened by the client.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use futures_lite::Stream;
+use futures_sink::Sink;
+use tokio::sync::mpsc;
+use tokio_util::{
+    codec::{Decoder, Encoder, FramedRead, FramedWrite},
+    task::AbortOnDropHandle,
+};
+use tracing::{debug, trace};
+
+use crate::protos::relay::{DerpCodec, Frame};
+
+/// The number of received datagrams buffered before the reader is polled.
+const DATAGRAM_CHANNEL_SIZE: usize = 64;
+
+/// Receives the relay frames sent over a QUIC connection.
+#[derive(derive_more::Debug)]
+pub(crate) struct QuicFrameReader {
+    #[debug("FramedRead")]
+    frames: FramedRead<quinn::RecvStream, DerpCodec>,
+    datagrams: mpsc::Receiver<Bytes>,
+    _datagram_task: AbortOnDropHandle<()>,
+}
+
+impl QuicFrameReader {
+    pub(crate) fn new(conn: quinn::Connection, recv: quinn::RecvStream) -> Self {
+        let (sender, datagrams) = mpsc::channel(DATAGRAM_CHANNEL_SIZE);
+        let task = tokio::spawn(async move {
+            loop {
+                let datagram = match conn.read_datagram().await {
+                    Ok(datagram) => datagram,
+                    Err(err) => {
+                        debug!("stopped reading datagrams: {err:#}");
+                        break;
+                    }
+                };
+                if sender.send(datagram).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            frames: FramedRead::new(recv, DerpCodec),
+            datagrams,
+            _datagram_task: AbortOnDropHandle::new(task),
+        }
+    }
+}
+
+impl Stream for QuicFrameReader {
+    type Item = Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Once the connection is gone the stream reports it, so a closed datagram
+        // channel is not an error here.
+        if let Poll::Ready(Some(datagram)) = self.datagrams.poll_recv(cx) {
+            let mut buf = BytesMut::from(&datagram[..]);
+            let frame = match DerpCodec.decode(&mut buf) {
+                Ok(Some(frame)) if buf.is_empty() => Ok(frame),
+                Ok(_) => Err(anyhow::anyhow!("datagram does not contain a single frame")),
+                Err(err) => Err(err),
+            };
+            return Poll::Ready(Some(frame));
+        }
+        Pin::new(&mut self.frames).poll_next(cx)
+    }
+}
+
+/// Sends relay frames over a QUIC connection.
+#[derive(derive_more::Debug)]
+pub(crate) struct QuicFrameWriter {
+    #[debug("FramedWrite")]
+    frames: FramedWrite<quinn::SendStream, DerpCodec>,
+    conn: quinn::Connection,
+}
+
+impl QuicFrameWriter {
+    pub(crate) fn new(conn: quinn::Connection, send: quinn::SendStream) -> Self {
+        Self {
+            frames: FramedWrite::new(send, DerpCodec),
+            conn,
+        }
+    }
+
+    /// Sends the frame as a datagram if it is a packet which fits into one.
+    ///
+    /// Returns the frame back if it needs to be sent on the stream instead.
+    fn try_send_datagram(&self, frame: Frame) -> Result<Option<Frame>, std::io::Error> {
+        if !matches!(frame, Frame::SendPacket { .. } | Frame::RecvPacket { .. }) {
+            return Ok(Some(frame));
+        }
+        match self.conn.max_datagram_size() {
+            Some(max) if frame.len_with_header() <= max => {}
+            _ => return Ok(Some(frame)),
+        }
+        let mut buf = BytesMut::with_capacity(frame.len_with_header());
+        DerpCodec.encode(frame, &mut buf)?;
+        if let Err(err) = self.conn.send_datagram(buf.freeze()) {
+            // Like a lost packet, the connection itself fails on the stream.
+            trace!("failed to send datagram: {err:#}");
+        }
+        Ok(None)
+    }
+}
+
+impl Sink<Frame> for QuicFrameWriter {
+    type Error = std::io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.frames).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Frame) -> Result<(), Self::Error> {
+        match self.try_send_datagram(item)? {
+            Some(frame) => Pin::new(&mut self.frames).start_send(frame),
+            None => Ok(()),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.frames).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.frames).poll_close(cx)
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! This module provides an API to run a full fledged iroh-relay server.  It is primarily
 //! used by the `iroh-relay` binary in this crate.  It can be used to run a relay server in
-//! other locations however.
+//! other locations however, [`Server::builder`] allows embedding it in another application.
 //!
 //! This code is fully written in a form of structured-concurrency: every spawned task is
 //! always attached to a handle and when the handle is dropped the tasks abort.  So tasks
@@ -16,7 +16,9 @@
 //! - HTTPS `/generate_204`: Used for net_report probes.
 //! - STUN: UDP port for STUN requests/responses.
 
-use std::{fmt, future::Future, net::SocketAddr, num::NonZeroU32, pin::Pin, sync::Arc};
+use std::{
+    fmt, future::Future, net::SocketAddr, num::NonZeroU32, pin::Pin, sync::Arc, time::Duration,
+};
 
 use anyhow::{anyhow, bail, Context, Result};
 use derive_more::Debug;
@@ -27,37 +29,46 @@ use http::{
 use hyper::body::Incoming;
 #[cfg(feature = "test-utils")]
 use iroh_base::node_addr::RelayUrl;
-use iroh_metrics::inc;
 use tokio::{
     net::{TcpListener, UdpSocket},
     task::JoinSet,
 };
+use tokio_rustls_acme::ResolvesServerCertAcme;
 use tokio_util::task::AbortOnDropHandle;
-use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument};
+use tracing::{debug, error, info, info_span, instrument, Instrument};
 
 use crate::{
     http::RELAY_PROBE_PATH,
-    protos,
     quic::server::{QuicServer, ServerHandle as QuicServerHandle},
 };
 
+mod access;
 pub(crate) mod actor;
 pub(crate) mod client_conn;
 mod clients;
 mod http_server;
+mod mesh;
 mod metrics;
 pub(crate) mod streams;
+mod stun;
 #[cfg(feature = "test-utils")]
 pub mod testing;
+mod usage;
 
+pub(crate) use self::http_server::RelayAcceptor;
 pub use self::{
+    access::{Access, AccessConfig},
+    mesh::{MeshConfig, MeshPeer},
     metrics::{Metrics, StunMetrics},
     streams::MaybeTlsStream as MaybeTlsStreamServer,
+    usage::{CsvUsageSink, HttpUsageSink, LogUsageSink, UsageRecord, UsageSink},
 };
 
 const NO_CONTENT_CHALLENGE_HEADER: &str = "X-Tailscale-Challenge";
 const NO_CONTENT_RESPONSE_HEADER: &str = "X-Tailscale-Response";
 const NOTFOUND: &[u8] = b"Not Found";
+/// The path prefix of ACME HTTP-01 challenge requests, see RFC 8555 section 8.3.
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
 const ROBOTS_TXT: &[u8] = b"User-agent: *\nDisallow: /\n";
 const INDEX: &[u8] = br#"<html><body>
 <h1>Iroh Relay</h1>
@@ -118,6 +129,15 @@ pub struct RelayConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     pub tls: Option<TlsConfig<EC, EA>>,
     /// Rate limits.
     pub limits: Limits,
+    /// Which nodes may use the relay server.
+    pub access: AccessConfig,
+    /// Forwarding of packets to other relay servers, disabled if `None`.
+    ///
+    /// Packets for nodes which are not connected to this relay server are forwarded to
+    /// the other relay servers of the mesh.
+    pub mesh: Option<MeshConfig>,
+    /// Where to export the usage of each client connection, not recorded if `None`.
+    pub usage: Option<Arc<dyn UsageSink>>,
 }
 
 /// Configuration for the STUN server.
@@ -127,9 +147,31 @@ pub struct StunConfig {
     ///
     /// Normally you'd chose port `3478`, see [`crate::defaults::DEFAULT_STUN_PORT`].
     pub bind_addr: SocketAddr,
+    /// Rate limit for the requests from each source IP address, unlimited if `None`.
+    ///
+    /// Requests exceeding the limit are dropped.
+    pub rate_limit: Option<StunRateLimit>,
+    /// Whether to also answer STUN requests on the UDP port of the QUIC server.
+    ///
+    /// This allows serving STUN and QUIC from a single UDP port, e.g. when only one port
+    /// can be forwarded through a load balancer.  Clients need to be configured with the
+    /// QUIC port as STUN port.  Has no effect if the QUIC server is not enabled.
+    pub quic: bool,
+}
+
+/// Rate limit for STUN requests.
+#[derive(Debug, Copy, Clone)]
+pub struct StunRateLimit {
+    /// Max number of requests per second from a single IP address.
+    pub requests_per_second: NonZeroU32,
+    /// Max number of requests from a single IP address in a single burst.
+    pub max_burst: Option<NonZeroU32>,
 }
 
 /// Configuration for the QUIC server.
+///
+/// The QUIC server serves QUIC address discovery.  If the relay server is enabled clients
+/// can also relay over QUIC, see [`ClientBuilder::quic`](crate::client::ClientBuilder::quic).
 #[derive(Debug)]
 pub struct QuicConfig {
     /// The socket address on which the QUIC server should bind.
@@ -165,6 +207,9 @@ pub struct TlsConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
 }
 
 /// Rate limits.
+///
+/// The client rate limits apply to each client connection separately, so a single node can
+/// not use up the bandwidth of the relay server.
 // TODO: accept_conn_limit and accept_conn_burst are not currently implemented.
 #[derive(Debug, Default)]
 pub struct Limits {
@@ -173,15 +218,22 @@ pub struct Limits {
     /// Burst limit for accepting new connection. Unlimited if not set.
     pub accept_conn_burst: Option<usize>,
     /// Rate limits for incoming traffic from a client connection.
+    ///
+    /// Reading from the client is paused while it exceeds the limit.
     pub client_rx: Option<ClientConnRateLimit>,
+    /// Rate limits for outgoing traffic to a client connection.
+    ///
+    /// Packets relayed to the client while it exceeds the limit are dropped.  Disco packets
+    /// are not limited, so that holepunching keeps working.
+    pub client_tx: Option<ClientConnRateLimit>,
 }
 
 /// Per-client rate limit configuration.
 #[derive(Debug, Copy, Clone)]
 pub struct ClientConnRateLimit {
-    /// Max number of bytes per second to read from the client connection.
+    /// Max number of bytes per second to transfer on the client connection.
     pub bytes_per_second: NonZeroU32,
-    /// Max number of bytes to read in a single burst.
+    /// Max number of bytes to transfer in a single burst.
     pub max_burst_bytes: Option<NonZeroU32>,
 }
 
@@ -189,6 +241,13 @@ pub struct ClientConnRateLimit {
 #[derive(derive_more::Debug)]
 pub enum CertConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     /// Use Let's Encrypt.
+    ///
+    /// Certificates are provisioned and renewed automatically.  Both the TLS-ALPN-01 and
+    /// the HTTP-01 challenges are supported, whichever the [`AcmeState`] was configured
+    /// with.  HTTP-01 challenges are answered on the [`RelayConfig::http_bind_addr`], which
+    /// must therefore be reachable on port `80`.
+    ///
+    /// [`AcmeState`]: tokio_rustls_acme::AcmeState
     LetsEncrypt {
         /// State for Let's Encrypt certificates.
         #[debug("AcmeConfig")]
@@ -201,6 +260,210 @@ pub enum CertConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     },
 }
 
+/// Builder for a [`Server`].
+///
+/// Allows running a relay server from within another application, e.g. next to the
+/// application's own services:
+///
+/// ```no_run
+/// # use std::net::Ipv4Addr;
+/// # async fn run() -> anyhow::Result<()> {
+/// let server = iroh_relay::server::Server::builder()
+///     .bind((Ipv4Addr::UNSPECIFIED, 8080).into())
+///     .stun((Ipv4Addr::UNSPECIFIED, 3478).into())
+///     .spawn()
+///     .await?;
+/// // ...
+/// server.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The relay HTTP(S) server is only started if [`Builder::bind`] is called, the STUN and
+/// QUIC servers only if configured.  The generic parameters are those of the
+/// [`CertConfig::LetsEncrypt`] configuration, see [`ServerConfig`].
+#[derive(Debug)]
+pub struct Builder<EC: fmt::Debug = (), EA: fmt::Debug = EC> {
+    http_bind_addr: Option<SocketAddr>,
+    tls: Option<TlsConfig<EC, EA>>,
+    limits: Limits,
+    access: AccessConfig,
+    mesh: Option<MeshConfig>,
+    usage: Option<Arc<dyn UsageSink>>,
+    stun: Option<StunConfig>,
+    stun_rate_limit: Option<StunRateLimit>,
+    stun_on_quic: bool,
+    quic: Option<QuicConfig>,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<SocketAddr>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            http_bind_addr: None,
+            tls: None,
+            limits: Limits::default(),
+            access: AccessConfig::Everyone,
+            mesh: None,
+            usage: None,
+            stun: None,
+            stun_rate_limit: None,
+            stun_on_quic: false,
+            quic: None,
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+        }
+    }
+}
+
+impl<EC: fmt::Debug + 'static, EA: fmt::Debug + 'static> Builder<EC, EA> {
+    /// Enables the relay server, serving HTTP on the given socket address.
+    ///
+    /// If [`Builder::tls`] is configured the relay is served over HTTPS instead and this
+    /// socket only serves the captive portal probe, see [`RelayConfig::http_bind_addr`].
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.http_bind_addr = Some(addr);
+        self
+    }
+
+    /// Serves the relay over HTTPS.
+    ///
+    /// Requires the relay server to be enabled using [`Builder::bind`].
+    pub fn tls<EC2: fmt::Debug, EA2: fmt::Debug>(
+        self,
+        tls: TlsConfig<EC2, EA2>,
+    ) -> Builder<EC2, EA2> {
+        Builder {
+            http_bind_addr: self.http_bind_addr,
+            tls: Some(tls),
+            limits: self.limits,
+            access: self.access,
+            mesh: self.mesh,
+            usage: self.usage,
+            stun: self.stun,
+            stun_rate_limit: self.stun_rate_limit,
+            stun_on_quic: self.stun_on_quic,
+            quic: self.quic,
+            #[cfg(feature = "metrics")]
+            metrics_addr: self.metrics_addr,
+        }
+    }
+
+    /// Sets the rate limits of the relay server.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets which nodes may use the relay server.
+    ///
+    /// By default every node may use it.
+    pub fn access(mut self, access: AccessConfig) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Forwards packets to the other relay servers of a mesh.
+    ///
+    /// The [`AccessConfig`] must allow the mesh peers to connect.
+    pub fn mesh(mut self, mesh: MeshConfig) -> Self {
+        self.mesh = Some(mesh);
+        self
+    }
+
+    /// Records the relayed bytes and duration of each client connection.
+    ///
+    /// A [`UsageRecord`] is passed to `sink` whenever a client disconnects.
+    pub fn usage(mut self, sink: impl UsageSink) -> Self {
+        self.usage = Some(Arc::new(sink));
+        self
+    }
+
+    /// Enables the STUN server on the given socket address.
+    pub fn stun(mut self, addr: SocketAddr) -> Self {
+        self.stun = Some(StunConfig {
+            bind_addr: addr,
+            rate_limit: None,
+            quic: false,
+        });
+        self
+    }
+
+    /// Rate limits the STUN requests from each source IP address.
+    ///
+    /// Requires the STUN server to be enabled using [`Builder::stun`].
+    pub fn stun_rate_limit(mut self, limit: StunRateLimit) -> Self {
+        self.stun_rate_limit = Some(limit);
+        self
+    }
+
+    /// Also answers STUN requests on the UDP port of the QUIC server.
+    ///
+    /// Requires the STUN server to be enabled using [`Builder::stun`], see
+    /// [`StunConfig::quic`].
+    pub fn stun_on_quic(mut self, enable: bool) -> Self {
+        self.stun_on_quic = enable;
+        self
+    }
+
+    /// Enables the QUIC server for QUIC address discovery.
+    pub fn quic(mut self, config: QuicConfig) -> Self {
+        self.quic = Some(config);
+        self
+    }
+
+    /// Serves the metrics of the server on the given socket address.
+    ///
+    /// Metrics are also collected without this if the application initialized the
+    /// [`iroh_metrics::core::Core`] with the relay [`Metrics`], see [`Server::metrics`].
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(iroh_docsrs, doc(cfg(feature = "metrics")))]
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Returns the [`ServerConfig`] for these settings.
+    pub fn build(self) -> Result<ServerConfig<EC, EA>> {
+        let relay = match (self.http_bind_addr, self.tls) {
+            (Some(http_bind_addr), tls) => Some(RelayConfig {
+                http_bind_addr,
+                tls,
+                limits: self.limits,
+                access: self.access,
+                mesh: self.mesh,
+                usage: self.usage,
+            }),
+            (None, Some(_)) => bail!("TLS configured but the relay server is not bound"),
+            (None, None) => None,
+        };
+        let stun = match self.stun {
+            Some(stun) => Some(StunConfig {
+                rate_limit: self.stun_rate_limit,
+                quic: self.stun_on_quic,
+                ..stun
+            }),
+            None if self.stun_rate_limit.is_some() || self.stun_on_quic => {
+                bail!("STUN configured but the STUN server is not bound")
+            }
+            None => None,
+        };
+        Ok(ServerConfig {
+            relay,
+            stun,
+            quic: self.quic,
+            #[cfg(feature = "metrics")]
+            metrics_addr: self.metrics_addr,
+        })
+    }
+
+    /// Starts the server.
+    pub async fn spawn(self) -> Result<Server> {
+        Server::spawn(self.build()?).await
+    }
+}
+
 /// A running Relay + STUN server.
 ///
 /// This is a full Relay server, including STUN, Relay and various associated HTTP services.
@@ -233,6 +496,11 @@ pub struct Server {
 }
 
 impl Server {
+    /// Returns a [`Builder`] to configure and start a server.
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
     /// Starts the server.
     pub async fn spawn<EC, EA>(config: ServerConfig<EC, EA>) -> Result<Self>
     where
@@ -246,10 +514,14 @@ impl Server {
             debug!("Starting metrics server");
             use iroh_metrics::core::Metric;
 
-            iroh_metrics::core::Core::init(|reg, metrics| {
+            // The application embedding the server may have initialized the metrics already.
+            let res = iroh_metrics::core::Core::try_init(|reg, metrics| {
                 metrics.insert(metrics::Metrics::new(reg));
                 metrics.insert(StunMetrics::new(reg));
             });
+            if res.is_err() {
+                debug!("metrics already initialized");
+            }
             tasks.spawn(
                 iroh_metrics::metrics::start_metrics_server(addr)
                     .instrument(info_span!("metrics-server")),
@@ -257,15 +529,21 @@ impl Server {
         }
 
         // Start the STUN server.
+        let mut stun_on_quic = None;
         let stun_addr = match config.stun {
             Some(stun) => {
                 debug!("Starting STUN server");
+                let responder = Arc::new(stun::StunResponder::new(&stun));
+                if stun.quic {
+                    stun_on_quic = Some(responder.clone());
+                }
                 match UdpSocket::bind(stun.bind_addr).await {
                     Ok(sock) => {
                         let addr = sock.local_addr()?;
                         info!("STUN server listening on {addr}");
                         tasks.spawn(
-                            server_stun_listener(sock).instrument(info_span!("stun-server", %addr)),
+                            stun::server_stun_listener(sock, responder)
+                                .instrument(info_span!("stun-server", %addr)),
                         );
                         Some(addr)
                     }
@@ -283,16 +561,6 @@ impl Server {
             })
         });
 
-        let quic_server = match config.quic {
-            Some(quic_config) => {
-                debug!("Starting QUIC server {}", quic_config.bind_addr);
-                Some(QuicServer::spawn(quic_config)?)
-            }
-            None => None,
-        };
-        let quic_addr = quic_server.as_ref().map(|srv| srv.bind_addr());
-        let quic_handle = quic_server.as_ref().map(|srv| srv.handle());
-
         let (relay_server, http_addr) = match config.relay {
             Some(relay_config) => {
                 debug!("Starting Relay server");
@@ -313,10 +581,22 @@ impl Server {
                 if let Some(cfg) = relay_config.limits.client_rx {
                     builder = builder.client_rx_ratelimit(cfg);
                 }
+                if let Some(cfg) = relay_config.limits.client_tx {
+                    builder = builder.client_tx_ratelimit(cfg);
+                }
+                builder = builder.access(relay_config.access);
+                if let Some(mesh) = relay_config.mesh {
+                    builder = builder.mesh(mesh);
+                }
+                if let Some(usage) = relay_config.usage {
+                    builder = builder.usage(usage);
+                }
                 let http_addr = match relay_config.tls {
                     Some(tls_config) => {
+                        let mut acme_resolver = None;
                         let server_tls_config = match tls_config.cert {
                             CertConfig::LetsEncrypt { mut state } => {
+                                acme_resolver = Some(state.resolver().clone());
                                 let acceptor =
                                     http_server::TlsAcceptor::LetsEncrypt(state.acceptor());
                                 tasks.spawn(
@@ -356,7 +636,7 @@ impl Server {
                             .context("failed to bind http")?;
                         let http_addr = http_listener.local_addr()?;
                         tasks.spawn(
-                            run_captive_portal_service(http_listener)
+                            run_captive_portal_service(http_listener, acme_resolver)
                                 .instrument(info_span!("http-service", addr = %http_addr)),
                         );
                         Some(http_addr)
@@ -377,6 +657,18 @@ impl Server {
             }
             None => (None, None),
         };
+        // The QUIC server also serves the relay, if it is enabled.
+        let quic_server = match config.quic {
+            Some(quic_config) => {
+                debug!("Starting QUIC server {}", quic_config.bind_addr);
+                let relay = relay_server.as_ref().map(|srv| srv.relay_acceptor());
+                Some(QuicServer::spawn(quic_config, relay, stun_on_quic)?)
+            }
+            None => None,
+        };
+        let quic_addr = quic_server.as_ref().map(|srv| srv.bind_addr());
+        let quic_handle = quic_server.as_ref().map(|srv| srv.handle());
+
         // If http_addr is Some then relay_server is serving HTTPS.  If http_addr is None
         // relay_server is serving HTTP, including the /generate_204 service.
         let relay_addr = relay_server.as_ref().map(|srv| srv.addr());
@@ -410,6 +702,25 @@ impl Server {
         self.supervisor.await?
     }
 
+    /// Drains the server, then shuts it down.
+    ///
+    /// New relay clients are refused and the connected clients are told to move to another
+    /// relay server.  After the `grace_period` the server shuts down like
+    /// [`Server::shutdown`], closing the connections of the clients which did not move yet.
+    ///
+    /// This allows replacing a relay server without interrupting the nodes using it.
+    pub async fn drain(mut self, grace_period: Duration) -> Result<()> {
+        if let Some(ref handle) = self.relay_handle {
+            info!(?grace_period, "draining relay server");
+            handle.drain(grace_period).await;
+            tokio::select! {
+                _ = tokio::time::sleep(grace_period) => (),
+                res = &mut self.supervisor => return res?,
+            }
+        }
+        self.shutdown().await
+    }
+
     /// Returns the handle for the task.
     ///
     /// This allows waiting for the server's supervisor task to finish.  Can be useful in
@@ -438,6 +749,17 @@ impl Server {
         self.stun_addr
     }
 
+    /// Returns the metrics of the relay server.
+    ///
+    /// Metrics are only collected once the [`iroh_metrics::core::Core`] is initialized with
+    /// the relay [`Metrics`], which [`Server::spawn`] does if a metrics address is
+    /// configured.  Returns `None` otherwise.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(iroh_docsrs, doc(cfg(feature = "metrics")))]
+    pub fn metrics(&self) -> Option<&'static Metrics> {
+        iroh_metrics::core::Core::get()?.get_collector::<Metrics>()
+    }
+
     /// The certificates chain if configured with manual TLS certificates.
     pub fn certificates(&self) -> Option<Vec<rustls::pki_types::CertificateDer<'static>>> {
         self.certificates.clone()
@@ -531,86 +853,6 @@ async fn relay_supervisor(
     ret
 }
 
-/// Runs a STUN server.
-///
-/// When the future is dropped, the server stops.
-async fn server_stun_listener(sock: UdpSocket) -> Result<()> {
-    info!(addr = ?sock.local_addr().ok(), "running STUN server");
-    let sock = Arc::new(sock);
-    let mut buffer = vec![0u8; 64 << 10];
-    let mut tasks = JoinSet::new();
-    loop {
-        tokio::select! {
-            biased;
-
-            Some(res) = tasks.join_next(), if !tasks.is_empty() => {
-                if let Err(err) = res {
-                    if err.is_panic() {
-                        panic!("task panicked: {:#?}", err);
-                    }
-                }
-            }
-            res = sock.recv_from(&mut buffer) => {
-                match res {
-                    Ok((n, src_addr)) => {
-                        inc!(StunMetrics, requests);
-                        let pkt = &buffer[..n];
-                        if !protos::stun::is(pkt) {
-                            debug!(%src_addr, "STUN: ignoring non stun packet");
-                            inc!(StunMetrics, bad_requests);
-                            continue;
-                        }
-                        let pkt = pkt.to_vec();
-                        tasks.spawn(handle_stun_request(src_addr, pkt, sock.clone()));
-                    }
-                    Err(err) => {
-                        inc!(StunMetrics, failures);
-                        warn!("failed to recv: {err:#}");
-                    }
-                }
-            }
-        }
-    }
-}
-
-/// Handles a single STUN request, doing all logging required.
-async fn handle_stun_request(src_addr: SocketAddr, pkt: Vec<u8>, sock: Arc<UdpSocket>) {
-    let (txid, response) = match protos::stun::parse_binding_request(&pkt) {
-        Ok(txid) => {
-            debug!(%src_addr, %txid, "STUN: received binding request");
-            (txid, protos::stun::response(txid, src_addr))
-        }
-        Err(err) => {
-            inc!(StunMetrics, bad_requests);
-            warn!(%src_addr, "STUN: invalid binding request: {:?}", err);
-            return;
-        }
-    };
-
-    match sock.send_to(&response, src_addr).await {
-        Ok(len) => {
-            if len != response.len() {
-                warn!(
-                    %src_addr,
-                    %txid,
-                    "failed to write response, {len}/{} bytes sent",
-                    response.len()
-                );
-            } else {
-                match src_addr {
-                    SocketAddr::V4(_) => inc!(StunMetrics, ipv4_success),
-                    SocketAddr::V6(_) => inc!(StunMetrics, ipv6_success),
-                }
-            }
-            trace!(%src_addr, %txid, "sent {len} bytes");
-        }
-        Err(err) => {
-            inc!(StunMetrics, failures);
-            warn!(%src_addr, %txid, "failed to write response: {err:#}");
-        }
-    }
-}
-
 fn root_handler(
     _r: Request<Incoming>,
     response: ResponseBuilder,
@@ -681,7 +923,12 @@ fn is_challenge_char(c: char) -> bool {
 }
 
 /// This is a future that never returns, drop it to cancel/abort.
-async fn run_captive_portal_service(http_listener: TcpListener) -> Result<()> {
+///
+/// If an `acme_resolver` is given this also answers ACME HTTP-01 challenges.
+async fn run_captive_portal_service(
+    http_listener: TcpListener,
+    acme_resolver: Option<Arc<ResolvesServerCertAcme>>,
+) -> Result<()> {
     info!("serving");
 
     // If this future is cancelled, this is dropped and all tasks are aborted.
@@ -703,7 +950,9 @@ async fn run_captive_portal_service(http_listener: TcpListener) -> Result<()> {
                 match res {
                     Ok((stream, peer_addr)) => {
                         debug!(%peer_addr, "Connection opened",);
-                        let handler = CaptivePortalService;
+                        let handler = CaptivePortalService {
+                            acme_resolver: acme_resolver.clone(),
+                        };
 
                         tasks.spawn(async move {
                             let stream = crate::server::streams::MaybeTlsStream::Plain(stream);
@@ -729,8 +978,16 @@ async fn run_captive_portal_service(http_listener: TcpListener) -> Result<()> {
     }
 }
 
+/// Returns the token of an ACME HTTP-01 challenge request path.
+fn acme_challenge_token(path: &str) -> Option<&str> {
+    path.strip_prefix(ACME_CHALLENGE_PATH)
+        .filter(|token| !token.is_empty() && !token.contains('/'))
+}
+
 #[derive(Clone)]
-struct CaptivePortalService;
+struct CaptivePortalService {
+    acme_resolver: Option<Arc<ResolvesServerCertAcme>>,
+}
 
 impl hyper::service::Service<Request<Incoming>> for CaptivePortalService {
     type Response = Response<BytesBody>;
@@ -743,6 +1000,28 @@ impl hyper::service::Service<Request<Incoming>> for CaptivePortalService {
             (&Method::GET, "/generate_204") => {
                 Box::pin(async move { serve_no_content_handler(req, Response::builder()) })
             }
+            // ACME HTTP-01 challenge
+            (&Method::GET, path) if path.starts_with(ACME_CHALLENGE_PATH) => {
+                let key_auth = self
+                    .acme_resolver
+                    .as_ref()
+                    .zip(acme_challenge_token(path))
+                    .and_then(|(resolver, token)| resolver.get_http_01_key_auth(token));
+                let r = match key_auth {
+                    Some(key_auth) => {
+                        debug!("answering ACME HTTP-01 challenge");
+                        Response::builder()
+                            .status(StatusCode::OK)
+                            .header(http::header::CONTENT_TYPE, "application/octet-stream")
+                            .body(key_auth.into())
+                    }
+                    None => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(NOTFOUND.into()),
+                };
+                let r = r.map_err(|err| Box::new(err) as HyperError);
+                Box::pin(async move { r })
+            }
             _ => {
                 // Return 404 not found response.
                 let r = Response::builder()
@@ -757,16 +1036,22 @@ impl hyper::service::Service<Request<Incoming>> for CaptivePortalService {
 
 #[cfg(test)]
 mod tests {
-    use std::{net::Ipv4Addr, time::Duration};
+    use std::net::Ipv4Addr;
 
     use bytes::Bytes;
     use http::header::UPGRADE;
-    use iroh_base::{key::SecretKey, node_addr::RelayUrl};
+    use iroh_base::{
+        key::{NodeId, SecretKey},
+        node_addr::RelayUrl,
+        relay_map::RelayAuthToken,
+    };
+    use tracing::warn;
 
     use super::*;
     use crate::{
-        client::{conn::ReceivedMessage, ClientBuilder},
+        client::{conn::ReceivedMessage, Client, ClientBuilder, ClientReceiver},
         http::{Protocol, HTTP_UPGRADE_PROTOCOL},
+        protos,
     };
 
     async fn spawn_local_relay() -> Result<Server> {
@@ -775,6 +1060,9 @@ mod tests {
                 http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
                 tls: None,
                 limits: Default::default(),
+                access: Default::default(),
+                mesh: None,
+                usage: None,
             }),
             quic: None,
             stun: None,
@@ -796,6 +1084,22 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[tokio::test]
+    async fn test_builder() {
+        let _guard = iroh_test::logging::setup();
+        let server = Server::builder()
+            .bind((Ipv4Addr::LOCALHOST, 0).into())
+            .stun((Ipv4Addr::LOCALHOST, 0).into())
+            .spawn()
+            .await
+            .unwrap();
+        assert!(server.http_addr().is_some());
+        assert!(server.stun_addr().is_some());
+        assert!(server.https_addr().is_none());
+        assert!(server.quic_addr().is_none());
+        server.shutdown().await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_conflicting_bind() {
         let _guard = iroh_test::logging::setup();
@@ -804,6 +1108,9 @@ mod tests {
                 http_bind_addr: (Ipv4Addr::LOCALHOST, 1234).into(),
                 tls: None,
                 limits: Default::default(),
+                access: Default::default(),
+                mesh: None,
+                usage: None,
             }),
             stun: None,
             quic: None,
@@ -851,6 +1158,458 @@ mod tests {
         assert!(body.is_empty());
     }
 
+    #[test]
+    fn test_acme_challenge_token() {
+        assert_eq!(
+            acme_challenge_token("/.well-known/acme-challenge/abc_DEF-123"),
+            Some("abc_DEF-123")
+        );
+        assert_eq!(acme_challenge_token("/.well-known/acme-challenge/"), None);
+        assert_eq!(
+            acme_challenge_token("/.well-known/acme-challenge/a/b"),
+            None
+        );
+        assert_eq!(acme_challenge_token("/generate_204"), None);
+    }
+
+    #[tokio::test]
+    async fn test_relay_access_denied() {
+        let _guard = iroh_test::logging::setup();
+        let allowed = SecretKey::generate().public();
+        let server = Server::builder()
+            .bind((Ipv4Addr::LOCALHOST, 0).into())
+            .access(AccessConfig::allowlist([allowed]))
+            .spawn()
+            .await
+            .unwrap();
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        let resolver = crate::dns::default_resolver().clone();
+        let (client, mut receiver) =
+            ClientBuilder::new(relay_url).build(SecretKey::generate(), resolver);
+        client.connect().await.unwrap();
+        let problem = tokio::time::timeout(Duration::from_secs(5), async move {
+            loop {
+                match receiver.recv().await {
+                    Some(Ok(ReceivedMessage::Health { problem })) => break problem,
+                    Some(_) => continue,
+                    None => break None,
+                }
+            }
+        })
+        .await
+        .expect("no health message received");
+        assert!(problem.unwrap().contains("not authorized"));
+    }
+
+    #[tokio::test]
+    async fn test_relay_auth_token() {
+        let _guard = iroh_test::logging::setup();
+        let token = RelayAuthToken::new("secret");
+        let server = Server::builder()
+            .bind((Ipv4Addr::LOCALHOST, 0).into())
+            .access(AccessConfig::tokens([token.clone()]))
+            .spawn()
+            .await
+            .unwrap();
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap())
+            .parse()
+            .unwrap();
+        let resolver = crate::dns::default_resolver().clone();
+
+        let (client, _receiver) = ClientBuilder::new(relay_url.clone())
+            .auth_token(token)
+            .build(SecretKey::generate(), resolver.clone());
+        client.connect().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), client.ping())
+            .await
+            .expect("ping timed out")
+            .expect("client with token denied");
+
+        let (client, mut receiver) =
+            ClientBuilder::new(relay_url).build(SecretKey::generate(), resolver);
+        client.connect().await.unwrap();
+        let problem = tokio::time::timeout(Duration::from_secs(5), async move {
+            loop {
+                match receiver.recv().await {
+                    Some(Ok(ReceivedMessage::Health { problem })) => break problem,
+                    Some(_) => continue,
+                    None => break None,
+                }
+            }
+        })
+        .await
+        .expect("no health message received");
+        assert!(problem.unwrap().contains("not authorized"));
+    }
+
+    #[tokio::test]
+    async fn test_relay_drain() {
+        let _guard = iroh_test::logging::setup();
+        let server = spawn_local_relay().await.unwrap();
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        async fn recv_go_away(receiver: &mut ClientReceiver) -> Option<Duration> {
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                loop {
+                    match receiver.recv().await {
+                        Some(Ok(ReceivedMessage::GoAway { close_in })) => break Some(close_in),
+                        Some(Ok(_)) => continue,
+                        _ => break None,
+                    }
+                }
+            })
+            .await
+            .expect("no go away message received")
+        }
+
+        let resolver = crate::dns::default_resolver().clone();
+        let (client_a, mut receiver_a) =
+            ClientBuilder::new(relay_url.clone()).build(SecretKey::generate(), resolver.clone());
+        client_a.connect().await.unwrap();
+
+        let grace_period = Duration::from_millis(500);
+        let drain = tokio::spawn(server.drain(grace_period));
+        assert_eq!(recv_go_away(&mut receiver_a).await, Some(grace_period));
+
+        // New clients are refused while draining.
+        let (client_b, mut receiver_b) =
+            ClientBuilder::new(relay_url).build(SecretKey::generate(), resolver);
+        client_b.connect().await.unwrap();
+        assert_eq!(recv_go_away(&mut receiver_b).await, Some(Duration::ZERO));
+
+        tokio::time::timeout(Duration::from_secs(5), drain)
+            .await
+            .expect("server not shut down after grace period")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relay_mesh_forward() {
+        let _guard = iroh_test::logging::setup();
+        let mesh_key_1 = SecretKey::generate();
+        let mesh_key_2 = SecretKey::generate();
+
+        // Relay 2 only receives forwarded packets, the URL of relay 1 is never used.
+        let server_2 = Server::builder()
+            .bind((Ipv4Addr::LOCALHOST, 0).into())
+            .mesh(MeshConfig {
+                secret_key: mesh_key_2.clone(),
+                peers: vec![MeshPeer {
+                    url: "http://127.0.0.1:1".parse().unwrap(),
+                    node_id: mesh_key_1.public(),
+                }],
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let relay_url_2: RelayUrl = format!("http://{}", server_2.http_addr().unwrap())
+            .parse()
+            .unwrap();
+        let server_1 = Server::builder()
+            .bind((Ipv4Addr::LOCALHOST, 0).into())
+            .mesh(MeshConfig {
+                secret_key: mesh_key_1,
+                peers: vec![MeshPeer {
+                    url: relay_url_2.clone(),
+                    node_id: mesh_key_2.public(),
+                }],
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let relay_url_1: RelayUrl = format!("http://{}", server_1.http_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        let a_secret_key = SecretKey::generate();
+        let a_key = a_secret_key.public();
+        let resolver = crate::dns::default_resolver().clone();
+        let (client_a, _client_a_receiver) =
+            ClientBuilder::new(relay_url_1).build(a_secret_key, resolver);
+        client_a.connect().await.unwrap();
+
+        let b_secret_key = SecretKey::generate();
+        let b_key = b_secret_key.public();
+        let resolver = crate::dns::default_resolver().clone();
+        let (client_b, mut client_b_receiver) =
+            ClientBuilder::new(relay_url_2).build(b_secret_key, resolver);
+        client_b.connect().await.unwrap();
+
+        // a and b are connected to different relays, relay 1 forwards to relay 2
+        let msg = Bytes::from("hello, b");
+        client_a.send(b_key, msg.clone()).await.unwrap();
+
+        let res = tokio::time::timeout(Duration::from_secs(5), client_b_receiver.recv())
+            .await
+            .expect("no packet forwarded")
+            .unwrap()
+            .unwrap();
+        if let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        {
+            assert_eq!(a_key, remote_node_id);
+            assert_eq!(msg, data);
+        } else {
+            panic!("client_b received unexpected message {res:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_mesh_node_moved() {
+        let _guard = iroh_test::logging::setup();
+        let mesh_key_1 = SecretKey::generate();
+        let mesh_key_2 = SecretKey::generate();
+        let mesh_key_3 = SecretKey::generate();
+
+        // Relays 2 and 3 forward to relay 1, which needs their URLs in turn.  So relay 1
+        // binds to an address picked before.
+        let addr_1 = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let relay_url_1: RelayUrl = format!("http://{addr_1}").parse().unwrap();
+        let spawn_relay = |secret_key: SecretKey| {
+            Server::builder()
+                .bind((Ipv4Addr::LOCALHOST, 0).into())
+                .mesh(MeshConfig {
+                    secret_key,
+                    peers: vec![MeshPeer {
+                        url: relay_url_1.clone(),
+                        node_id: mesh_key_1.public(),
+                    }],
+                })
+                .spawn()
+        };
+        let server_2 = spawn_relay(mesh_key_2.clone()).await.unwrap();
+        let server_3 = spawn_relay(mesh_key_3.clone()).await.unwrap();
+        let relay_url_2: RelayUrl = format!("http://{}", server_2.http_addr().unwrap())
+            .parse()
+            .unwrap();
+        let relay_url_3: RelayUrl = format!("http://{}", server_3.http_addr().unwrap())
+            .parse()
+            .unwrap();
+        let _server_1 = Server::builder()
+            .bind(addr_1)
+            .mesh(MeshConfig {
+                secret_key: mesh_key_1,
+                peers: vec![
+                    MeshPeer {
+                        url: relay_url_2.clone(),
+                        node_id: mesh_key_2.public(),
+                    },
+                    MeshPeer {
+                        url: relay_url_3.clone(),
+                        node_id: mesh_key_3.public(),
+                    },
+                ],
+            })
+            .spawn()
+            .await
+            .unwrap();
+
+        /// Sends `msg` to `dst` until it is received from `src`.
+        async fn send_until_received(
+            client: &Client,
+            receiver: &mut ClientReceiver,
+            src: NodeId,
+            dst: NodeId,
+            msg: Bytes,
+        ) {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    client.send(dst, msg.clone()).await.unwrap();
+                    let recv = tokio::time::timeout(Duration::from_millis(200), async {
+                        loop {
+                            match receiver.recv().await {
+                                Some(Ok(ReceivedMessage::ReceivedPacket {
+                                    remote_node_id,
+                                    data,
+                                })) if remote_node_id == src && data == msg => break,
+                                Some(_) => continue,
+                                None => panic!("receiver closed"),
+                            }
+                        }
+                    });
+                    if recv.await.is_ok() {
+                        break;
+                    }
+                }
+            })
+            .await
+            .expect("packet not forwarded");
+        }
+
+        let resolver = crate::dns::default_resolver().clone();
+        let a_secret_key = SecretKey::generate();
+        let a_key = a_secret_key.public();
+        let (client_a, mut client_a_receiver) =
+            ClientBuilder::new(relay_url_1).build(a_secret_key, resolver.clone());
+        client_a.connect().await.unwrap();
+        let b_secret_key = SecretKey::generate();
+        let b_key = b_secret_key.public();
+        let (client_b, mut client_b_receiver) =
+            ClientBuilder::new(relay_url_2).build(b_secret_key.clone(), resolver.clone());
+        client_b.connect().await.unwrap();
+
+        // Relay 1 learns that b is connected to relay 2.
+        let msg = Bytes::from("hello, a");
+        send_until_received(&client_b, &mut client_a_receiver, b_key, a_key, msg).await;
+        let msg = Bytes::from("hello, b");
+        send_until_received(&client_a, &mut client_b_receiver, a_key, b_key, msg).await;
+
+        // b moves to relay 3 and does not send anything, relay 2 reports it gone so relay 1
+        // looks for it on all peers again.
+        client_b.close().await.unwrap();
+        let (client_b, mut client_b_receiver) =
+            ClientBuilder::new(relay_url_3).build(b_secret_key, resolver);
+        client_b.connect().await.unwrap();
+        let msg = Bytes::from("hello again, b");
+        send_until_received(&client_a, &mut client_b_receiver, a_key, b_key, msg).await;
+    }
+
+    #[tokio::test]
+    async fn test_relay_clients_quic() {
+        let _guard = iroh_test::logging::setup();
+        let server = Server::spawn(testing::server_config()).await.unwrap();
+        let relay_url = server.https_url().unwrap();
+        let quic_port = server.quic_addr().unwrap().port();
+
+        let a_secret_key = SecretKey::generate();
+        let a_key = a_secret_key.public();
+        let resolver = crate::dns::default_resolver().clone();
+        let (client_a, mut client_a_receiver) = ClientBuilder::new(relay_url.clone())
+            .insecure_skip_cert_verify(true)
+            .build(a_secret_key, resolver);
+        client_a.connect().await.unwrap();
+
+        let b_secret_key = SecretKey::generate();
+        let b_key = b_secret_key.public();
+        let resolver = crate::dns::default_resolver().clone();
+        let (client_b, mut client_b_receiver) = ClientBuilder::new(relay_url)
+            .insecure_skip_cert_verify(true)
+            .quic(quic_port)
+            .build(b_secret_key, resolver);
+        client_b.connect().await.unwrap();
+        // Only relay connections over TCP have a local address.
+        assert!(client_a.local_addr().await.is_some());
+        assert!(client_b.local_addr().await.is_none());
+
+        // a small packet is sent as a datagram, a large one on the stream
+        for msg in [Bytes::from("hello, b"), Bytes::from(vec![7u8; 8 * 1024])] {
+            client_a.send(b_key, msg.clone()).await.unwrap();
+            let res = client_b_receiver.recv().await.unwrap().unwrap();
+            if let ReceivedMessage::ReceivedPacket {
+                remote_node_id,
+                data,
+            } = res
+            {
+                assert_eq!(a_key, remote_node_id);
+                assert_eq!(msg, data);
+            } else {
+                panic!("client_b received unexpected message {res:?}");
+            }
+
+            client_b.send(a_key, msg.clone()).await.unwrap();
+            let res = client_a_receiver.recv().await.unwrap().unwrap();
+            if let ReceivedMessage::ReceivedPacket {
+                remote_node_id,
+                data,
+            } = res
+            {
+                assert_eq!(b_key, remote_node_id);
+                assert_eq!(msg, data);
+            } else {
+                panic!("client_a received unexpected message {res:?}");
+            }
+        }
+    }
+
+    /// Forwards connections to `target`, but refuses relay protocol upgrades like a
+    /// middlebox which only passes plain HTTP and WebSocket traffic.
+    async fn spawn_upgrade_blocking_proxy(
+        target: SocketAddr,
+    ) -> (SocketAddr, AbortOnDropHandle<()>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 4096];
+                    let n = stream.read(&mut buf).await?;
+                    let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                    if request.contains(HTTP_UPGRADE_PROTOCOL) {
+                        stream
+                            .write_all(b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\n\r\n")
+                            .await?;
+                        return Ok(());
+                    }
+                    let mut upstream = tokio::net::TcpStream::connect(target).await?;
+                    upstream.write_all(&buf[..n]).await?;
+                    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+                    anyhow::Ok(())
+                });
+            }
+        });
+        (addr, AbortOnDropHandle::new(task))
+    }
+
+    #[tokio::test]
+    async fn test_relay_client_websocket_fallback() {
+        let _guard = iroh_test::logging::setup();
+        let server = spawn_local_relay().await.unwrap();
+        let (proxy_addr, _proxy) = spawn_upgrade_blocking_proxy(server.http_addr().unwrap()).await;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap())
+            .parse()
+            .unwrap();
+        let blocked_url: RelayUrl = format!("http://{proxy_addr}").parse().unwrap();
+
+        let a_secret_key = SecretKey::generate();
+        let a_key = a_secret_key.public();
+        let resolver = crate::dns::default_resolver().clone();
+        let (client_a, _client_a_receiver) =
+            ClientBuilder::new(relay_url).build(a_secret_key, resolver);
+        client_a.connect().await.unwrap();
+
+        // Without the fallback the upgrade is refused.
+        let resolver = crate::dns::default_resolver().clone();
+        let (client, _receiver) = ClientBuilder::new(blocked_url.clone())
+            .websocket_fallback(false)
+            .build(SecretKey::generate(), resolver);
+        assert!(client.connect().await.is_err());
+
+        let b_secret_key = SecretKey::generate();
+        let b_key = b_secret_key.public();
+        let resolver = crate::dns::default_resolver().clone();
+        let (client_b, mut client_b_receiver) =
+            ClientBuilder::new(blocked_url).build(b_secret_key, resolver);
+        client_b.connect().await.unwrap();
+
+        let msg = Bytes::from("hello, b");
+        client_a.send(b_key, msg.clone()).await.unwrap();
+
+        let res = client_b_receiver.recv().await.unwrap().unwrap();
+        if let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        {
+            assert_eq!(a_key, remote_node_id);
+            assert_eq!(msg, data);
+        } else {
+            panic!("client_b received unexpected message {res:?}");
+        }
+    }
+
     #[tokio::test]
     async fn test_relay_client_legacy_route() {
         let _guard = iroh_test::logging::setup();
@@ -1100,6 +1859,8 @@ mod tests {
             relay: None,
             stun: Some(StunConfig {
                 bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                rate_limit: None,
+                quic: false,
             }),
             quic: None,
             metrics_addr: None,
@@ -1124,4 +1885,36 @@ mod tests {
         assert_eq!(txid, txid_back);
         assert_eq!(response_addr, socket.local_addr().unwrap());
     }
+
+    #[tokio::test]
+    async fn test_stun_on_quic_port() {
+        let _guard = iroh_test::logging::setup();
+        let (_, server_config) = testing::self_signed_tls_certs_and_config();
+        let server = Server::builder()
+            .stun((Ipv4Addr::LOCALHOST, 0).into())
+            .stun_on_quic(true)
+            .quic(QuicConfig {
+                bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                server_config,
+            })
+            .spawn()
+            .await
+            .unwrap();
+        let quic_addr = server.quic_addr().unwrap();
+
+        let txid = protos::stun::TransactionId::default();
+        let req = protos::stun::request(txid);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(&req, quic_addr).await.unwrap();
+
+        let mut buf = vec![0u8; 64000];
+        let (len, addr) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .expect("no STUN response on the QUIC port")
+            .unwrap();
+        assert_eq!(addr, quic_addr);
+        let (txid_back, response_addr) = protos::stun::parse_response(&buf[..len]).unwrap();
+        assert_eq!(txid, txid_back);
+        assert_eq!(response_addr, socket.local_addr().unwrap());
+    }
 }
@@ -0,0 +1,155 @@
+//! Controlling which nodes may use the relay server.
+
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+use futures_lite::future::Boxed as BoxFuture;
+use iroh_base::{key::NodeId, relay_map::RelayAuthToken};
+use tracing::warn;
+use url::Url;
+
+/// How long to wait for an HTTP authorizer to answer before denying access.
+const HTTP_AUTHORIZER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether a node may use the relay server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// The node may use the relay server.
+    Allow,
+    /// The node's connection is closed.
+    Deny,
+}
+
+/// Controls which nodes may use the relay server.
+///
+/// The relay server authenticates each client by the [`NodeId`] it signs the connection
+/// handshake with.  Clients may additionally present a [`RelayAuthToken`] in the
+/// handshake.  Before a client is accepted the [`AccessConfig`] decides whether the node
+/// may use the relay.  Denied clients are sent a health message telling them so, and are
+/// disconnected.
+#[derive(derive_more::Debug, Clone, Default)]
+pub enum AccessConfig {
+    /// Every node may use the relay server.
+    #[default]
+    Everyone,
+    /// Only nodes for which the function returns [`Access::Allow`] may use the relay server.
+    Restricted(
+        #[debug("restricted")] Arc<dyn Fn(NodeId) -> BoxFuture<Access> + Send + Sync + 'static>,
+    ),
+    /// Only nodes for which the function returns [`Access::Allow`], given the auth token the
+    /// client presented, may use the relay server.
+    Token(
+        #[debug("token")]
+        Arc<dyn Fn(NodeId, Option<RelayAuthToken>) -> BoxFuture<Access> + Send + Sync + 'static>,
+    ),
+}
+
+impl AccessConfig {
+    /// Only allows the given nodes.
+    pub fn allowlist(nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        let nodes: Arc<BTreeSet<NodeId>> = Arc::new(nodes.into_iter().collect());
+        Self::Restricted(Arc::new(move |node_id| {
+            let allowed = nodes.contains(&node_id);
+            Box::pin(async move {
+                if allowed {
+                    Access::Allow
+                } else {
+                    Access::Deny
+                }
+            })
+        }))
+    }
+
+    /// Only allows clients which present one of the given tokens.
+    pub fn tokens(tokens: impl IntoIterator<Item = RelayAuthToken>) -> Self {
+        let tokens: Arc<BTreeSet<RelayAuthToken>> = Arc::new(tokens.into_iter().collect());
+        Self::Token(Arc::new(move |node_id, token| {
+            let allowed = token.is_some_and(|token| tokens.contains(&token));
+            if !allowed {
+                warn!(node_id = %node_id.fmt_short(), "access denied, missing or unknown auth token");
+            }
+            Box::pin(async move {
+                if allowed {
+                    Access::Allow
+                } else {
+                    Access::Deny
+                }
+            })
+        }))
+    }
+
+    /// Asks an external HTTP service whether a node is allowed.
+    ///
+    /// For every client a `POST` request with the client's [`NodeId`] as body is sent to
+    /// `url`.  If the client presented an auth token it is passed as bearer token in the
+    /// `Authorization` header.  The node is allowed if the service responds with a success
+    /// status code within a few seconds, and denied otherwise.
+    pub fn http_authorizer(url: Url) -> Self {
+        let client = reqwest::Client::new();
+        Self::Token(Arc::new(move |node_id, token| {
+            let mut request = client
+                .post(url.clone())
+                .timeout(HTTP_AUTHORIZER_TIMEOUT)
+                .body(node_id.to_string());
+            if let Some(token) = token {
+                request = request.bearer_auth(token.as_str());
+            }
+            Box::pin(async move {
+                match request.send().await {
+                    Ok(res) if res.status().is_success() => Access::Allow,
+                    Ok(res) => {
+                        warn!(node_id = %node_id.fmt_short(), status = %res.status(), "access denied by authorizer");
+                        Access::Deny
+                    }
+                    Err(err) => {
+                        warn!(node_id = %node_id.fmt_short(), "failed to reach authorizer: {err:#}");
+                        Access::Deny
+                    }
+                }
+            })
+        }))
+    }
+
+    /// Returns whether the node may use the relay server.
+    pub(super) async fn is_allowed(
+        &self,
+        node_id: NodeId,
+        auth_token: Option<RelayAuthToken>,
+    ) -> bool {
+        match self {
+            Self::Everyone => true,
+            Self::Restricted(check) => check(node_id).await == Access::Allow,
+            Self::Token(check) => check(node_id, auth_token).await == Access::Allow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::key::SecretKey;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_allowlist() {
+        let allowed = SecretKey::generate().public();
+        let stranger = SecretKey::generate().public();
+        let access = AccessConfig::allowlist([allowed]);
+        assert!(access.is_allowed(allowed, None).await);
+        assert!(!access.is_allowed(stranger, None).await);
+        assert!(AccessConfig::Everyone.is_allowed(stranger, None).await);
+    }
+
+    #[tokio::test]
+    async fn test_tokens() {
+        let node_id = SecretKey::generate().public();
+        let token = RelayAuthToken::new("secret");
+        let access = AccessConfig::tokens([token.clone()]);
+        assert!(access.is_allowed(node_id, Some(token)).await);
+        assert!(
+            !access
+                .is_allowed(node_id, Some(RelayAuthToken::new("guess")))
+                .await
+        );
+        assert!(!access.is_allowed(node_id, None).await);
+    }
+}
@@ -15,8 +15,13 @@ use tracing::{info, info_span, trace, warn, Instrument};
 
 use crate::{
     defaults::timeouts::SERVER_WRITE_TIMEOUT as WRITE_TIMEOUT,
-    protos::relay::SERVER_CHANNEL_SIZE,
-    server::{client_conn::ClientConnConfig, clients::Clients, metrics::Metrics},
+    protos::{disco, relay::SERVER_CHANNEL_SIZE},
+    server::{
+        client_conn::ClientConnConfig,
+        clients::Clients,
+        mesh::{Mesh, MeshConfig},
+        metrics::Metrics,
+    },
 };
 
 #[derive(Debug)]
@@ -31,11 +36,27 @@ pub(super) enum Message {
         data: Bytes,
         src: NodeId,
     },
+    /// A packet forwarded by the mesh peer `via`.
+    ForwardedPacket {
+        via: NodeId,
+        src: NodeId,
+        dst: NodeId,
+        data: Bytes,
+    },
+    /// The mesh peer `via` reported that `node_id` is not connected to it.
+    MeshNodeGone {
+        via: NodeId,
+        node_id: NodeId,
+    },
     CreateClient(ClientConnConfig),
     RemoveClient {
         node_id: NodeId,
         conn_num: usize,
     },
+    /// Tells all clients to move to another relay server, the server is draining.
+    GoAway {
+        close_in: Duration,
+    },
 }
 
 /// A request to write a dataframe to a Client
@@ -67,9 +88,12 @@ pub(super) struct ServerActorTask {
 
 impl ServerActorTask {
     /// Creates a new `ServerActorTask` and start the actor.
-    pub(super) fn spawn() -> Self {
+    ///
+    /// Packets for nodes not connected to this server are forwarded to the `mesh`, if any.
+    pub(super) fn spawn(mesh: Option<MeshConfig>) -> Self {
         let (server_channel_s, server_channel_r) = mpsc::channel(SERVER_CHANNEL_SIZE);
-        let server_actor = Actor::new(server_channel_r);
+        let mesh = mesh.map(|mesh| Mesh::spawn(mesh, server_channel_s.clone()));
+        let server_actor = Actor::new(server_channel_r, mesh);
         let cancel_token = CancellationToken::new();
         let done = cancel_token.clone();
         let server_task = AbortOnDropHandle::new(tokio::spawn(
@@ -102,14 +126,17 @@ struct Actor {
     clients: Clients,
     /// Statistics about the connected clients
     client_counter: ClientCounter,
+    /// The other relay servers of the mesh
+    mesh: Option<Mesh>,
 }
 
 impl Actor {
-    fn new(receiver: mpsc::Receiver<Message>) -> Self {
+    fn new(receiver: mpsc::Receiver<Message>, mesh: Option<Mesh>) -> Self {
         Self {
             receiver,
             clients: Clients::default(),
             client_counter: ClientCounter::default(),
+            mesh,
         }
     }
 
@@ -154,6 +181,9 @@ impl Actor {
                             inc!(Metrics, send_packets_dropped);
                         }
                     }
+                } else if let Some(ref mut mesh) = self.mesh {
+                    trace!(?dst, "client not connected, forwarding to mesh");
+                    mesh.forward(src, dst, data);
                 } else {
                     warn!(?dst, "no way to reach client, dropped packet");
                     inc!(Metrics, send_packets_dropped);
@@ -176,11 +206,55 @@ impl Actor {
                             inc!(Metrics, disco_packets_dropped);
                         }
                     }
+                } else if let Some(ref mut mesh) = self.mesh {
+                    trace!(?dst, "disco: client not connected, forwarding to mesh");
+                    mesh.forward(src, dst, data);
                 } else {
                     warn!(?dst, "disco: no way to reach client, dropped packet");
                     inc!(Metrics, disco_packets_dropped);
                 }
             }
+            Message::ForwardedPacket {
+                via,
+                src,
+                dst,
+                data,
+            } => {
+                trace!(?via, ?src, ?dst, len = data.len(), "forwarded packet");
+                let Some(mesh) = self.mesh.as_mut().filter(|mesh| mesh.is_peer(&via)) else {
+                    warn!(
+                        ?via,
+                        "packet forwarded by a node which is not a mesh peer, dropped"
+                    );
+                    inc!(Metrics, mesh_packets_dropped);
+                    return;
+                };
+                inc!(Metrics, mesh_packets_recv);
+                mesh.learn(src, via);
+                // Forwarded packets are only delivered locally, never forwarded again.
+                if !self.clients.contains_key(&dst) {
+                    // Tell the peer, so it stops forwarding packets for the node only to us.
+                    trace!(?dst, "forwarded packet for a node which is not connected");
+                    self.clients.send_peer_gone(&via, dst);
+                    inc!(Metrics, mesh_packets_dropped);
+                    return;
+                }
+                let packet = Packet { data, src };
+                let res = if disco::looks_like_disco_wrapper(&packet.data) {
+                    self.clients.send_disco_packet(&dst, packet).await
+                } else {
+                    self.clients.send_packet(&dst, packet).await
+                };
+                if let Err(err) = res {
+                    trace!(?dst, "failed to deliver forwarded packet: {err:#}");
+                    inc!(Metrics, mesh_packets_dropped);
+                }
+            }
+            Message::MeshNodeGone { via, node_id } => {
+                if let Some(ref mut mesh) = self.mesh {
+                    mesh.forget(node_id, via);
+                }
+            }
             Message::CreateClient(client_builder) => {
                 inc!(Metrics, accepts);
                 let node_id = client_builder.node_id;
@@ -202,6 +276,10 @@ impl Actor {
                     self.clients.unregister(&node_id).await;
                 }
             }
+            Message::GoAway { close_in } => {
+                info!(?close_in, "draining, telling clients to go away");
+                self.clients.go_away(close_in);
+            }
         }
     }
 }
@@ -269,7 +347,10 @@ mod tests {
                 write_timeout: Duration::from_secs(1),
                 channel_capacity: 10,
                 rate_limit: None,
+                tx_rate_limit: None,
                 server_channel,
+                large_packets: false,
+                usage: None,
             },
             Framed::new(test_io, DerpCodec),
         )
@@ -279,7 +360,7 @@ mod tests {
     async fn test_server_actor() -> Result<()> {
         // make server actor
         let (server_channel, server_channel_r) = mpsc::channel(20);
-        let server_actor: Actor = Actor::new(server_channel_r);
+        let server_actor: Actor = Actor::new(server_channel_r, None);
         let done = CancellationToken::new();
         let server_done = done.clone();
 
@@ -16,12 +16,13 @@ use tracing::{error, info, instrument, trace, warn, Instrument};
 use crate::{
     protos::{
         disco,
-        relay::{write_frame, Frame, KEEP_ALIVE},
+        relay::{split_large_packet, write_frame, Frame, KEEP_ALIVE, MAX_PACKET_SIZE},
     },
     server::{
         actor::{self, Packet},
         metrics::Metrics,
         streams::RelayedStream,
+        usage::{UsageCounter, UsageSink},
         ClientConnRateLimit,
     },
 };
@@ -34,7 +35,13 @@ pub(super) struct ClientConnConfig {
     pub(super) write_timeout: Duration,
     pub(super) channel_capacity: usize,
     pub(super) rate_limit: Option<ClientConnRateLimit>,
+    pub(super) tx_rate_limit: Option<ClientConnRateLimit>,
     pub(super) server_channel: mpsc::Sender<actor::Message>,
+    /// Whether the client supports packets up to
+    /// [`MAX_LARGE_PACKET_SIZE`](crate::protos::relay::MAX_LARGE_PACKET_SIZE).
+    pub(super) large_packets: bool,
+    /// Where to export the usage of the connection when it closes.
+    pub(super) usage: Option<Arc<dyn UsageSink>>,
 }
 
 /// The [`Server`] side representation of a [`Client`]'s connection.
@@ -57,6 +64,8 @@ pub(super) struct ClientConn {
     pub(super) disco_send_queue: mpsc::Sender<Packet>,
     /// Channel to notify the client that a previous sender has disconnected.
     pub(super) peer_gone: mpsc::Sender<NodeId>,
+    /// Channel to tell the client to move to another relay server.
+    pub(super) go_away: mpsc::Sender<Duration>,
 }
 
 impl ClientConn {
@@ -70,20 +79,20 @@ impl ClientConn {
             write_timeout,
             channel_capacity,
             rate_limit,
+            tx_rate_limit,
             server_channel,
+            large_packets,
+            usage: usage_sink,
         } = config;
 
         let stream = match rate_limit {
             Some(cfg) => {
-                let mut quota = governor::Quota::per_second(cfg.bytes_per_second);
-                if let Some(max_burst) = cfg.max_burst_bytes {
-                    quota = quota.allow_burst(max_burst);
-                }
-                let limiter = governor::RateLimiter::direct(quota);
+                let limiter = governor::RateLimiter::direct(quota(cfg));
                 RateLimitedRelayedStream::new(io, limiter)
             }
             None => RateLimitedRelayedStream::unlimited(io),
         };
+        let tx_limiter = tx_rate_limit.map(TxRateLimiter::new);
 
         let done = CancellationToken::new();
         let client_id = (key, conn_num);
@@ -91,16 +100,22 @@ impl ClientConn {
 
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(channel_capacity);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(channel_capacity);
+        let (go_away_s, go_away_r) = mpsc::channel(1);
+        let usage = Arc::new(UsageCounter::default());
 
         let actor = Actor {
             stream,
+            tx_limiter,
             timeout: write_timeout,
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             node_gone: peer_gone_r,
+            go_away: go_away_r,
             key,
             preferred: false,
             server_channel: server_channel.clone(),
+            large_packets,
+            usage: usage.clone(),
         };
 
         // start io loop
@@ -110,6 +125,9 @@ impl ClientConn {
             async move {
                 let (key, conn_num) = io_client_id;
                 let res = actor.run(io_done).await;
+                if let Some(sink) = usage_sink {
+                    sink.record(usage.record(key));
+                }
 
                 // remove the client when the actor terminates, no matter how it exits
                 let _ = server_channel
@@ -140,6 +158,7 @@ impl ClientConn {
             send_queue: send_queue_s,
             disco_send_queue: disco_send_queue_s,
             peer_gone: peer_gone_s,
+            go_away: go_away_s,
         }
     }
 
@@ -170,6 +189,7 @@ impl ClientConn {
 ///  - a PEER_GONE frame to inform the client that a peer they have previously sent messages to
 ///    is gone from the network
 ///  - packets from other peers
+///  - a GO_AWAY frame when the server is draining
 ///
 /// On the "read" side, it can:
 ///     - receive a ping and write a pong back
@@ -179,6 +199,8 @@ impl ClientConn {
 struct Actor {
     /// IO Stream to talk to the client
     stream: RateLimitedRelayedStream,
+    /// Rate limiter for the packets sent to the client
+    tx_limiter: Option<TxRateLimiter>,
     /// Maximum time we wait to complete a write to the client
     timeout: Duration,
     /// Packets queued to send to the client
@@ -187,6 +209,9 @@ struct Actor {
     disco_send_queue: mpsc::Receiver<Packet>,
     /// Notify the client that a previous sender has disconnected
     node_gone: mpsc::Receiver<NodeId>,
+    /// Tell the client to move to another relay server, the server closes the connection
+    /// after the given duration
+    go_away: mpsc::Receiver<Duration>,
     /// [`NodeId`] of this client
     key: NodeId,
     /// Channel used to communicate with the server about actions
@@ -195,6 +220,10 @@ struct Actor {
     /// Notes that the client considers this the preferred connection (important in cases
     /// where the client moves to a different network, but has the same NodeId)
     preferred: bool,
+    /// Whether the client supports packets larger than [`MAX_PACKET_SIZE`].
+    large_packets: bool,
+    /// Bytes relayed from and to the client
+    usage: Arc<UsageCounter>,
 }
 
 impl Actor {
@@ -234,10 +263,19 @@ impl Actor {
                     trace!("node_id gone: {:?}", node_id);
                     self.write_frame(Frame::NodeGone { node_id }).await?;
                 }
+                Some(close_in) = self.go_away.recv() => {
+                    trace!(?close_in, "go away");
+                    let close_in = close_in.as_millis().try_into().unwrap_or(u32::MAX);
+                    self.write_frame(Frame::GoAway { close_in }).await?;
+                }
                 packet = self.send_queue.recv() => {
                     let packet = packet.context("Server.send_queue dropped")?;
-                    trace!("send packet");
-                    self.send_packet(packet).await.context("send packet")?;
+                    if self.tx_limiter.as_mut().map_or(true, |limiter| limiter.check(&packet)) {
+                        trace!("send packet");
+                        self.send_packet(packet).await.context("send packet")?;
+                    } else {
+                        trace!("send packet rate-limited, dropping");
+                    }
                 }
                 packet = self.disco_send_queue.recv() => {
                     let packet = packet.context("Server.disco_send_queue dropped")?;
@@ -272,8 +310,21 @@ impl Actor {
         if let Ok(len) = content.len().try_into() {
             inc_by!(Metrics, bytes_sent, len);
         }
-        self.write_frame(Frame::RecvPacket { src_key, content })
-            .await
+        self.usage.add_to_client(content.len());
+        if self.large_packets || content.len() <= MAX_PACKET_SIZE {
+            return self
+                .write_frame(Frame::RecvPacket { src_key, content })
+                .await;
+        }
+        let Some(parts) = split_large_packet(&content) else {
+            warn!(len = content.len(), "dropping malformed large packet");
+            return Ok(());
+        };
+        for content in parts {
+            self.write_frame(Frame::RecvPacket { src_key, content })
+                .await?;
+        }
+        Ok(())
     }
 
     /// Handles frame read results.
@@ -291,6 +342,26 @@ impl Actor {
                 let packet_len = packet.len();
                 self.handle_frame_send_packet(dst_key, packet).await?;
                 inc_by!(Metrics, bytes_recv, packet_len as u64);
+                self.usage.add_from_client(packet_len);
+            }
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            } => {
+                let packet_len = packet.len();
+                let message = actor::Message::ForwardedPacket {
+                    via: self.key,
+                    src: src_key,
+                    dst: dst_key,
+                    data: packet,
+                };
+                self.server_channel
+                    .send(message)
+                    .await
+                    .map_err(|_| anyhow::anyhow!("server gone"))?;
+                inc_by!(Metrics, bytes_recv, packet_len as u64);
+                self.usage.add_from_client(packet_len);
             }
             Frame::Ping { data } => {
                 inc!(Metrics, got_ping);
@@ -333,6 +404,66 @@ impl Actor {
     }
 }
 
+/// Returns the token-bucket quota for a rate limit.
+fn quota(cfg: ClientConnRateLimit) -> governor::Quota {
+    let quota = governor::Quota::per_second(cfg.bytes_per_second);
+    match cfg.max_burst_bytes {
+        Some(max_burst) => quota.allow_burst(max_burst),
+        None => quota,
+    }
+}
+
+/// Rate limiter for the packets relayed to a client.
+///
+/// Unlike the [`RateLimitedRelayedStream`] this does not delay packets but drops them, like a
+/// congested link would.  Delaying them would stall the client's actor, and with it reading
+/// from the client.
+#[derive(Debug)]
+struct TxRateLimiter {
+    limiter: governor::DefaultDirectRateLimiter,
+    /// Keeps track if this client was ever rate-limited.
+    limited_once: bool,
+}
+
+impl TxRateLimiter {
+    fn new(cfg: ClientConnRateLimit) -> Self {
+        Self {
+            limiter: governor::RateLimiter::direct(quota(cfg)),
+            limited_once: false,
+        }
+    }
+
+    /// Returns whether the packet may be sent, records metrics if not.
+    fn check(&mut self, packet: &Packet) -> bool {
+        let frame = Frame::RecvPacket {
+            src_key: packet.src,
+            content: packet.data.clone(),
+        };
+        let frame_len = frame.len_with_header();
+        let Ok(n) = TryInto::<u32>::try_into(frame_len).and_then(TryInto::<NonZeroU32>::try_into)
+        else {
+            error!("frame len not NonZeroU32, is MAX_FRAME_SIZE too large?");
+            return true;
+        };
+        match self.limiter.check_n(n) {
+            Ok(Ok(_)) => true,
+            Ok(Err(_)) => {
+                inc!(Metrics, frames_tx_ratelimited_total);
+                inc_by!(Metrics, bytes_tx_ratelimited_total, frame_len as u64);
+                if !self.limited_once {
+                    inc!(Metrics, conns_tx_ratelimited_total);
+                    self.limited_once = true;
+                }
+                false
+            }
+            Err(_insufficient_capacity) => {
+                error!("frame larger than bucket capacity: max_burst_bytes < MAX_FRAME_SIZE?");
+                true
+            }
+        }
+    }
+}
+
 /// Rate limiter for reading from a [`RelayedStream`].
 ///
 /// The writes to the sink are not rate limited.
@@ -382,9 +513,10 @@ impl RateLimitedRelayedStream {
 
 impl RateLimitedRelayedStream {
     /// Records metrics about being rate-limited.
-    fn record_rate_limited(&mut self) {
+    fn record_rate_limited(&mut self, frame_len: u32) {
         // TODO: add a label for the frame type.
         inc!(Metrics, frames_rx_ratelimited_total);
+        inc_by!(Metrics, bytes_rx_ratelimited_total, frame_len.into());
         if !self.limited_once {
             inc!(Metrics, conns_rx_ratelimited_total);
             self.limited_once = true;
@@ -427,7 +559,7 @@ impl Stream for RateLimitedRelayedStream {
                                         Ok(Ok(_)) => return Poll::Ready(Some(item)),
                                         Ok(Err(_)) => {
                                             // Item is rate-limited.
-                                            self.record_rate_limited();
+                                            self.record_rate_limited(frame_len.get());
                                             let delay = Box::pin({
                                                 let limiter = limiter.clone();
                                                 async move {
@@ -522,28 +654,90 @@ mod tests {
         server::streams::MaybeTlsStream,
     };
 
+    #[tokio::test]
+    async fn test_client_actor_split_large_packet() -> TestResult {
+        let (send_queue_s, send_queue_r) = mpsc::channel(10);
+        let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
+        let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (_go_away_s, go_away_r) = mpsc::channel(1);
+
+        let key = SecretKey::generate().public();
+        let (io, io_rw) = tokio::io::duplex(64 * 1024);
+        let mut io_rw = Framed::new(io_rw, DerpCodec);
+        let (server_channel_s, _server_channel_r) = mpsc::channel(10);
+        let stream = RelayedStream::Derp(Framed::new(MaybeTlsStream::Test(io), DerpCodec));
+
+        let actor = Actor {
+            stream: RateLimitedRelayedStream::unlimited(stream),
+            tx_limiter: None,
+            timeout: Duration::from_secs(1),
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            node_gone: peer_gone_r,
+            go_away: go_away_r,
+            key,
+            server_channel: server_channel_s,
+            preferred: true,
+            large_packets: false,
+            usage: Default::default(),
+        };
+        let done = CancellationToken::new();
+        let _handle = AbortOnDropHandle::new(tokio::task::spawn(actor.run(done.clone())));
+
+        // Two datagrams which together do not fit into a packet.
+        let mut data = Vec::new();
+        for _ in 0..2 {
+            data.extend_from_slice(&40_000u16.to_le_bytes());
+            data.extend_from_slice(&[7u8; 40_000]);
+        }
+        let data = Bytes::from(data);
+        send_queue_s
+            .send(Packet {
+                src: key,
+                data: data.clone(),
+            })
+            .await?;
+        for part in [data.slice(..40_002), data.slice(40_002..)] {
+            let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
+            assert_eq!(
+                frame,
+                Frame::RecvPacket {
+                    src_key: key,
+                    content: part,
+                }
+            );
+        }
+        done.cancel();
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_client_actor_basic() -> Result<()> {
         let (send_queue_s, send_queue_r) = mpsc::channel(10);
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (go_away_s, go_away_r) = mpsc::channel(1);
 
         let key = SecretKey::generate().public();
         let (io, io_rw) = tokio::io::duplex(1024);
         let mut io_rw = Framed::new(io_rw, DerpCodec);
         let (server_channel_s, mut server_channel_r) = mpsc::channel(10);
         let stream = RelayedStream::Derp(Framed::new(MaybeTlsStream::Test(io), DerpCodec));
+        let usage = Arc::new(UsageCounter::default());
 
         let actor = Actor {
             stream: RateLimitedRelayedStream::unlimited(stream),
+            tx_limiter: None,
             timeout: Duration::from_secs(1),
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             node_gone: peer_gone_r,
-
+            go_away: go_away_r,
             key,
             server_channel: server_channel_s,
             preferred: true,
+            large_packets: false,
+            usage: usage.clone(),
         };
 
         let done = CancellationToken::new();
@@ -588,6 +782,12 @@ mod tests {
         let frame = recv_frame(FrameType::PeerGone, &mut io_rw).await?;
         assert_eq!(frame, Frame::NodeGone { node_id: key });
 
+        // send go away
+        println!("send go away");
+        go_away_s.send(Duration::from_secs(30)).await?;
+        let frame = recv_frame(FrameType::GoAway, &mut io_rw).await?;
+        assert_eq!(frame, Frame::GoAway { close_in: 30_000 });
+
         // Read tests
         println!("--read");
 
@@ -659,6 +859,14 @@ mod tests {
 
         done.cancel();
         handle.await??;
+
+        // usage of the relayed packets
+        let record = usage.record(key);
+        assert_eq!(record.bytes_to_client, 2 * data.len() as u64);
+        assert_eq!(
+            record.bytes_from_client,
+            (data.len() + disco_data.len()) as u64
+        );
         Ok(())
     }
 
@@ -667,6 +875,7 @@ mod tests {
         let (_send_queue_s, send_queue_r) = mpsc::channel(10);
         let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (_go_away_s, go_away_r) = mpsc::channel(1);
 
         let key = SecretKey::generate().public();
         let (io, io_rw) = tokio::io::duplex(1024);
@@ -677,14 +886,17 @@ mod tests {
         println!("-- create client conn");
         let actor = Actor {
             stream: RateLimitedRelayedStream::unlimited(stream),
+            tx_limiter: None,
             timeout: Duration::from_secs(1),
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             node_gone: peer_gone_r,
-
+            go_away: go_away_r,
             key,
             server_channel: server_channel_s,
             preferred: true,
+            large_packets: false,
+            usage: Default::default(),
         };
 
         let done = CancellationToken::new();
@@ -797,4 +1009,28 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_tx_rate_limit() -> TestResult {
+        let packet = Packet {
+            src: SecretKey::generate().public(),
+            data: Bytes::from_static(b"hello world!!"),
+        };
+        let frame_len = Frame::RecvPacket {
+            src_key: packet.src,
+            content: packet.data.clone(),
+        }
+        .len_with_header();
+
+        // Allows a single packet per second.
+        let mut limiter = TxRateLimiter::new(ClientConnRateLimit {
+            bytes_per_second: NonZeroU32::try_from(frame_len as u32)?,
+            max_burst_bytes: None,
+        });
+        assert!(limiter.check(&packet));
+        assert!(!limiter.check(&packet));
+        assert!(limiter.limited_once);
+
+        Ok(())
+    }
 }
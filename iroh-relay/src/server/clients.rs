@@ -1,7 +1,10 @@
 //! The "Server" side of the client. Uses the `ClientConnManager`.
 // Based on tailscale/derp/derp_server.go
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use anyhow::{bail, Result};
 use iroh_base::key::NodeId;
@@ -39,6 +42,17 @@ impl Clients {
         .await;
     }
 
+    /// Tells all clients to move to another relay server before the connection is closed
+    /// in `close_in`.
+    pub fn go_away(&self, close_in: Duration) {
+        trace!("sending go away to {} clients", self.inner.len());
+        for (key, client) in self.inner.iter() {
+            if client.conn.go_away.try_send(close_in).is_err() {
+                warn!("unable to send go away to {key:?}");
+            }
+        }
+    }
+
     /// Record that `src` sent or forwarded a packet to `dst`
     pub fn record_send(&mut self, src: &NodeId, dst: NodeId) {
         if let Some(client) = self.inner.get_mut(src) {
@@ -110,7 +124,8 @@ impl Clients {
         bail!("Could not find client for {key:?}, dropped packet");
     }
 
-    fn send_peer_gone(&mut self, key: &NodeId, peer: NodeId) {
+    /// Tells the client with [`NodeId`] `key` that `peer` is not connected.
+    pub fn send_peer_gone(&mut self, key: &NodeId, peer: NodeId) {
         if let Some(client) = self.inner.get(key) {
             let res = client.send_peer_gone(peer);
             let _ = self.process_result_no_fallback(key, res);
@@ -225,8 +240,6 @@ enum SendError {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
-
     use bytes::Bytes;
     use iroh_base::key::SecretKey;
     use tokio::io::DuplexStream;
@@ -248,7 +261,10 @@ mod tests {
                 write_timeout: Duration::from_secs(1),
                 channel_capacity: 10,
                 rate_limit: None,
+                tx_rate_limit: None,
                 server_channel,
+                large_packets: false,
+                usage: None,
             },
             FramedRead::new(test_io, DerpCodec),
         )
@@ -300,6 +316,11 @@ mod tests {
         let frame = recv_frame(FrameType::PeerGone, &mut a_rw).await?;
         assert_eq!(frame, Frame::NodeGone { node_id: b_key });
 
+        // send go away
+        clients.go_away(Duration::from_secs(1));
+        let frame = recv_frame(FrameType::GoAway, &mut a_rw).await?;
+        assert_eq!(frame, Frame::GoAway { close_in: 1000 });
+
         clients.unregister(&a_key.clone()).await;
 
         assert!(!clients.inner.contains_key(&a_key));
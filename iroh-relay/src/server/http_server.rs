@@ -1,5 +1,13 @@
 use std::{
-    collections::HashMap, future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration,
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use anyhow::{bail, ensure, Context as _, Result};
@@ -29,12 +37,19 @@ use tracing::{debug, debug_span, error, info, info_span, trace, warn, Instrument
 
 use crate::{
     http::{Protocol, LEGACY_RELAY_PATH, RELAY_PATH, SUPPORTED_WEBSOCKET_VERSION},
-    protos::relay::{recv_client_key, DerpCodec, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION},
+    protos::relay::{
+        recv_client_key, write_frame, DerpCodec, Frame, MAX_LARGE_PACKET_SIZE,
+        PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
+    },
+    quic::streams::{QuicFrameReader, QuicFrameWriter},
     server::{
+        access::AccessConfig,
         actor::{Message, ServerActorTask},
         client_conn::ClientConnConfig,
+        mesh::MeshConfig,
         metrics::Metrics,
         streams::{MaybeTlsStream, RelayedStream},
+        usage::UsageSink,
         ClientConnRateLimit,
     },
 };
@@ -80,6 +95,7 @@ pub(super) struct Server {
     addr: SocketAddr,
     http_server_task: AbortOnDropHandle<()>,
     cancel_server_loop: CancellationToken,
+    relay_acceptor: RelayAcceptor,
 }
 
 impl Server {
@@ -90,6 +106,7 @@ impl Server {
     pub(super) fn handle(&self) -> ServerHandle {
         ServerHandle {
             cancel_token: self.cancel_server_loop.clone(),
+            service: self.relay_acceptor.0.clone(),
         }
     }
 
@@ -111,6 +128,34 @@ impl Server {
     pub(super) fn addr(&self) -> SocketAddr {
         self.addr
     }
+
+    /// Returns a [`RelayAcceptor`] to add relay connections from other transports.
+    pub(super) fn relay_acceptor(&self) -> RelayAcceptor {
+        self.relay_acceptor.clone()
+    }
+}
+
+/// Adds relay connections which were not made over HTTP(S) to the relay server.
+#[derive(Debug, Clone)]
+pub(crate) struct RelayAcceptor(RelayService);
+
+impl RelayAcceptor {
+    /// Serves a relay client connected over QUIC.
+    ///
+    /// The `send` and `recv` streams are the bidirectional stream opened by the client.
+    pub(crate) async fn accept_quic(
+        &self,
+        conn: quinn::Connection,
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+    ) -> Result<()> {
+        inc!(Metrics, quic_accepts);
+        let io = RelayedStream::Quic {
+            reader: QuicFrameReader::new(conn.clone(), recv),
+            writer: QuicFrameWriter::new(conn, send),
+        };
+        self.0 .0.accept_stream(io).await
+    }
 }
 
 /// A handle for the [`Server`].
@@ -119,6 +164,7 @@ impl Server {
 #[derive(Debug, Clone)]
 pub(super) struct ServerHandle {
     cancel_token: CancellationToken,
+    service: RelayService,
 }
 
 impl ServerHandle {
@@ -126,6 +172,15 @@ impl ServerHandle {
     pub(super) fn shutdown(&self) {
         self.cancel_token.cancel()
     }
+
+    /// Starts draining the server.
+    ///
+    /// New relay clients are refused and connected clients are told to move to another
+    /// relay server before their connection is closed in `close_in`.  Shutting down the
+    /// server after `close_in` is up to the caller.
+    pub(super) async fn drain(&self, close_in: Duration) {
+        self.service.drain(close_in).await
+    }
 }
 
 /// Configuration to use for the TLS connection
@@ -161,6 +216,14 @@ pub(super) struct ServerBuilder {
     /// Rate-limiting is enforced on received traffic from individual clients.  This
     /// configuration applies to a single client connection.
     client_rx_ratelimit: Option<ClientConnRateLimit>,
+    /// Rate-limiting configuration for the traffic sent to an individual client.
+    client_tx_ratelimit: Option<ClientConnRateLimit>,
+    /// Which nodes may use the relay server.
+    access: AccessConfig,
+    /// The other relay servers to forward packets to.
+    mesh: Option<MeshConfig>,
+    /// Where to export the usage of each client connection.
+    usage: Option<Arc<dyn UsageSink>>,
 }
 
 impl ServerBuilder {
@@ -172,6 +235,10 @@ impl ServerBuilder {
             handlers: Default::default(),
             headers: HeaderMap::new(),
             client_rx_ratelimit: None,
+            client_tx_ratelimit: None,
+            access: AccessConfig::Everyone,
+            mesh: None,
+            usage: None,
         }
     }
 
@@ -190,6 +257,34 @@ impl ServerBuilder {
         self
     }
 
+    /// Sets the per-client rate-limit configuration for outgoing data.
+    ///
+    /// Packets relayed to a client exceeding the rate limit are dropped.  By default no
+    /// rate limit is enforced.
+    pub(super) fn client_tx_ratelimit(mut self, config: ClientConnRateLimit) -> Self {
+        self.client_tx_ratelimit = Some(config);
+        self
+    }
+
+    /// Sets which nodes may use the relay server.
+    pub(super) fn access(mut self, access: AccessConfig) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Forwards packets for nodes which are not connected to the other relay servers of
+    /// the mesh.
+    pub(super) fn mesh(mut self, mesh: MeshConfig) -> Self {
+        self.mesh = Some(mesh);
+        self
+    }
+
+    /// Exports the usage of each client connection to `sink`.
+    pub(super) fn usage(mut self, sink: Arc<dyn UsageSink>) -> Self {
+        self.usage = Some(sink);
+        self
+    }
+
     /// Adds a custom handler for a specific Method & URI.
     pub(super) fn request_handler(
         mut self,
@@ -211,17 +306,21 @@ impl ServerBuilder {
 
     /// Builds and spawns an HTTP(S) Relay Server.
     pub(super) async fn spawn(self) -> Result<Server> {
-        let server_task = ServerActorTask::spawn();
+        let server_task = ServerActorTask::spawn(self.mesh);
         let service = RelayService::new(
             self.handlers,
             self.headers,
             server_task.server_channel.clone(),
             server_task.write_timeout,
             self.client_rx_ratelimit,
+            self.client_tx_ratelimit,
+            self.access,
+            self.usage,
         );
 
         let addr = self.addr;
         let tls_config = self.tls_config;
+        let relay_acceptor = RelayAcceptor(service.clone());
 
         // Bind a TCP listener on `addr` and handles content using HTTPS.
 
@@ -285,6 +384,7 @@ impl ServerBuilder {
             addr,
             http_server_task: AbortOnDropHandle::new(task),
             cancel_server_loop,
+            relay_acceptor,
         })
     }
 }
@@ -300,6 +400,11 @@ struct Inner {
     server_channel: mpsc::Sender<Message>,
     write_timeout: Duration,
     rate_limit: Option<ClientConnRateLimit>,
+    tx_rate_limit: Option<ClientConnRateLimit>,
+    access: AccessConfig,
+    usage: Option<Arc<dyn UsageSink>>,
+    /// Whether the server is draining and refuses new clients.
+    draining: AtomicBool,
 }
 
 impl RelayService {
@@ -480,7 +585,8 @@ impl Inner {
     ///
     /// Will error if it takes too long (10 sec) to write or read to the connection, if there is
     /// some read or write error to the connection,  if the server is meant to verify clients,
-    /// and is unable to verify this one, or if there is some issue communicating with the server.
+    /// and is unable to verify this one, if the client is not allowed by the [`AccessConfig`],
+    /// or if there is some issue communicating with the server.
     ///
     /// The provided [`AsyncRead`] and [`AsyncWrite`] must be already connected to the connection.
     ///
@@ -488,7 +594,7 @@ impl Inner {
     /// [`AsyncWrite`]: tokio::io::AsyncWrite
     async fn accept(&self, protocol: Protocol, io: MaybeTlsStream) -> Result<()> {
         trace!(?protocol, "accept: start");
-        let mut io = match protocol {
+        let io = match protocol {
             Protocol::Relay => {
                 inc!(Metrics, derp_accepts);
                 RelayedStream::Derp(Framed::new(io, DerpCodec))
@@ -498,6 +604,11 @@ impl Inner {
                 RelayedStream::Ws(WebSocketStream::from_raw_socket(io, Role::Server, None).await)
             }
         };
+        self.accept_stream(io).await
+    }
+
+    /// Does the relay handshake on a connected stream and adds the client to the server.
+    async fn accept_stream(&self, mut io: RelayedStream) -> Result<()> {
         trace!("accept: recv client key");
         let (client_key, info) = recv_client_key(&mut io)
             .await
@@ -511,6 +622,37 @@ impl Inner {
             );
         }
 
+        if self.draining.load(Ordering::Relaxed) {
+            write_frame(
+                &mut io,
+                Frame::GoAway { close_in: 0 },
+                Some(self.write_timeout),
+            )
+            .await
+            .ok();
+            bail!(
+                "server is draining, refused client {}",
+                client_key.fmt_short()
+            );
+        }
+
+        trace!("accept: check access");
+        if !self.access.is_allowed(client_key, info.auth_token).await {
+            inc!(Metrics, accepts_denied);
+            let problem = Bytes::from_static(b"not authorized to use this relay server");
+            write_frame(&mut io, Frame::Health { problem }, Some(self.write_timeout))
+                .await
+                .ok();
+            bail!("client {} denied access", client_key.fmt_short());
+        }
+
+        if info.large_packets {
+            let frame = Frame::MaxPacketSize {
+                size: MAX_LARGE_PACKET_SIZE as u32,
+            };
+            write_frame(&mut io, frame, Some(self.write_timeout)).await?;
+        }
+
         trace!("accept: build client conn");
         let client_conn_builder = ClientConnConfig {
             node_id: client_key,
@@ -518,7 +660,10 @@ impl Inner {
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
             rate_limit: self.rate_limit,
+            tx_rate_limit: self.tx_rate_limit,
             server_channel: self.server_channel.clone(),
+            large_packets: info.large_packets,
+            usage: self.usage.clone(),
         };
         trace!("accept: create client");
         self.server_channel
@@ -548,6 +693,9 @@ impl RelayService {
         server_channel: mpsc::Sender<Message>,
         write_timeout: Duration,
         rate_limit: Option<ClientConnRateLimit>,
+        tx_rate_limit: Option<ClientConnRateLimit>,
+        access: AccessConfig,
+        usage: Option<Arc<dyn UsageSink>>,
     ) -> Self {
         Self(Arc::new(Inner {
             handlers,
@@ -555,9 +703,23 @@ impl RelayService {
             server_channel,
             write_timeout,
             rate_limit,
+            tx_rate_limit,
+            access,
+            usage,
+            draining: AtomicBool::new(false),
         }))
     }
 
+    /// Refuses new clients and tells the connected ones to go away.
+    async fn drain(&self, close_in: Duration) {
+        self.0.draining.store(true, Ordering::Relaxed);
+        self.0
+            .server_channel
+            .send(Message::GoAway { close_in })
+            .await
+            .ok();
+    }
+
     /// Handle the incoming connection.
     ///
     /// If a `tls_config` is given, will serve the connection using HTTPS.
@@ -914,13 +1076,16 @@ mod tests {
         let _guard = iroh_test::logging::setup();
 
         // create the server!
-        let server_task: ServerActorTask = ServerActorTask::spawn();
+        let server_task: ServerActorTask = ServerActorTask::spawn(None);
         let service = RelayService::new(
             Default::default(),
             Default::default(),
             server_task.server_channel.clone(),
             server_task.write_timeout,
             None,
+            None,
+            AccessConfig::Everyone,
+            None,
         );
 
         // create client a and connect it to the server
@@ -1000,13 +1165,16 @@ mod tests {
             .ok();
 
         // create the server!
-        let server_task: ServerActorTask = ServerActorTask::spawn();
+        let server_task: ServerActorTask = ServerActorTask::spawn(None);
         let service = RelayService::new(
             Default::default(),
             Default::default(),
             server_task.server_channel.clone(),
             server_task.write_timeout,
             None,
+            None,
+            AccessConfig::Everyone,
+            None,
         );
 
         // create client a and connect it to the server
@@ -0,0 +1,305 @@
+//! Forwarding packets between the relay servers of a mesh.
+//!
+//! Each relay server of a mesh connects to every other relay server of the mesh as an
+//! ordinary relay client, authenticated by the [`MeshConfig::secret_key`].  Packets for
+//! nodes which are not connected locally are forwarded over these connections, the
+//! receiving relay server only delivers them to its own clients and never forwards them
+//! again.  This way two nodes with different home relays can still reach each other.
+//!
+//! Packets for a node are only forwarded to the mesh peer it was last seen on.  If the node
+//! is no longer connected there, the peer replies with a `NodeGone` frame and packets are
+//! sent to all peers again until the node is seen on one of them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use iroh_base::key::{NodeId, SecretKey};
+use iroh_metrics::inc;
+use tokio::{sync::mpsc, time::Instant};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, info_span, trace, warn, Instrument};
+
+use crate::{
+    client::{conn::ReceivedMessage, ClientBuilder},
+    server::{actor::Message, metrics::Metrics},
+    RelayUrl,
+};
+
+/// The number of packets queued for each mesh peer before packets are dropped.
+const PEER_CHANNEL_SIZE: usize = 512;
+
+/// The maximum number of remote nodes for which the mesh peer they are connected to is
+/// remembered.
+const MAX_LOCATIONS: usize = 16 * 1024;
+
+/// How long the mesh peer a remote node was last seen on is remembered.
+///
+/// Packets for nodes which were not seen for longer are sent to all peers again, in case
+/// the node moved without the `NodeGone` reply of the old peer reaching us.
+const LOCATION_TTL: Duration = Duration::from_secs(60);
+
+/// Configuration for forwarding packets to other relay servers.
+///
+/// Every relay server of the mesh must list all the other relay servers as peers, and must
+/// allow the [`NodeId`]s of its peers to connect in its
+/// [`AccessConfig`](crate::server::AccessConfig).
+#[derive(Debug, Clone)]
+pub struct MeshConfig {
+    /// The key this relay server authenticates with to its peers.
+    pub secret_key: SecretKey,
+    /// The other relay servers of the mesh.
+    pub peers: Vec<MeshPeer>,
+}
+
+/// Another relay server of the mesh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeshPeer {
+    /// The URL to connect to the relay server.
+    pub url: RelayUrl,
+    /// The [`NodeId`] of the [`MeshConfig::secret_key`] of the relay server.
+    ///
+    /// Only packets forwarded by connections authenticated with this [`NodeId`] are
+    /// accepted.
+    pub node_id: NodeId,
+}
+
+/// A packet to forward to a mesh peer.
+#[derive(Debug)]
+struct Forward {
+    src: NodeId,
+    dst: NodeId,
+    data: Bytes,
+}
+
+/// The connections to the other relay servers of the mesh.
+#[derive(Debug)]
+pub(super) struct Mesh {
+    peers: BTreeMap<NodeId, PeerHandle>,
+    /// The mesh peer remote nodes were last seen on.
+    locations: HashMap<NodeId, Location>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    /// The mesh peer the node was seen on.
+    via: NodeId,
+    /// When the node was last seen.
+    seen: Instant,
+}
+
+#[derive(Debug)]
+struct PeerHandle {
+    sender: mpsc::Sender<Forward>,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl Mesh {
+    /// Starts connecting to the mesh peers.
+    ///
+    /// The `NodeGone` replies of the peers are sent to the `server` actor.
+    pub(super) fn spawn(config: MeshConfig, server: mpsc::Sender<Message>) -> Self {
+        let peers = config
+            .peers
+            .into_iter()
+            .map(|peer| {
+                let (sender, receiver) = mpsc::channel(PEER_CHANNEL_SIZE);
+                let span = info_span!("mesh-peer", url = %peer.url);
+                let task = tokio::spawn(
+                    run_peer(
+                        peer.clone(),
+                        config.secret_key.clone(),
+                        receiver,
+                        server.clone(),
+                    )
+                    .instrument(span),
+                );
+                let handle = PeerHandle {
+                    sender,
+                    _task: AbortOnDropHandle::new(task),
+                };
+                (peer.node_id, handle)
+            })
+            .collect();
+        Self {
+            peers,
+            locations: HashMap::new(),
+        }
+    }
+
+    /// Returns whether `node_id` is the key of a mesh peer.
+    pub(super) fn is_peer(&self, node_id: &NodeId) -> bool {
+        self.peers.contains_key(node_id)
+    }
+
+    /// Remembers that `src` is connected to the mesh peer `via`.
+    pub(super) fn learn(&mut self, src: NodeId, via: NodeId) {
+        if self.locations.len() >= MAX_LOCATIONS && !self.locations.contains_key(&src) {
+            self.locations.clear();
+        }
+        let seen = Instant::now();
+        self.locations.insert(src, Location { via, seen });
+    }
+
+    /// Forgets that `node_id` is connected to the mesh peer `via`, after `via` reported
+    /// that it is not.
+    pub(super) fn forget(&mut self, node_id: NodeId, via: NodeId) {
+        if self
+            .locations
+            .get(&node_id)
+            .is_some_and(|location| location.via == via)
+        {
+            debug!(node = %node_id.fmt_short(), "node gone from mesh peer");
+            self.locations.remove(&node_id);
+        }
+    }
+
+    /// Returns the mesh peer `node_id` was last seen on, unless that was too long ago.
+    fn location(&mut self, node_id: NodeId) -> Option<NodeId> {
+        let location = *self.locations.get(&node_id)?;
+        if location.seen.elapsed() >= LOCATION_TTL {
+            self.locations.remove(&node_id);
+            return None;
+        }
+        Some(location.via)
+    }
+
+    /// Forwards a packet for a node which is not connected to this relay server.
+    ///
+    /// The packet is sent to the mesh peer the node was last seen on, or to all peers if
+    /// it was not seen recently.
+    pub(super) fn forward(&mut self, src: NodeId, dst: NodeId, data: Bytes) {
+        match self.location(dst).and_then(|via| self.peers.get(&via)) {
+            Some(peer) => peer.send(Forward { src, dst, data }),
+            None => {
+                for peer in self.peers.values() {
+                    peer.send(Forward {
+                        src,
+                        dst,
+                        data: data.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl PeerHandle {
+    fn send(&self, packet: Forward) {
+        if self.sender.try_send(packet).is_err() {
+            inc!(Metrics, mesh_packets_dropped);
+        }
+    }
+}
+
+/// Forwards packets to a mesh peer, reconnecting as needed.
+///
+/// The `NodeGone` replies of the peer are passed on to the `server` actor.
+async fn run_peer(
+    peer: MeshPeer,
+    secret_key: SecretKey,
+    mut packets: mpsc::Receiver<Forward>,
+    server: mpsc::Sender<Message>,
+) {
+    let resolver = crate::dns::default_resolver().clone();
+    let (client, mut receiver) = ClientBuilder::new(peer.url).build(secret_key, resolver);
+    loop {
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some(Forward { src, dst, data }) = packet else {
+                    break;
+                };
+                match client.forward(src, dst, data).await {
+                    Ok(()) => inc!(Metrics, mesh_packets_sent),
+                    Err(err) => {
+                        warn!("failed to forward packet: {err:#}");
+                        inc!(Metrics, mesh_packets_dropped);
+                    }
+                }
+            }
+            msg = receiver.recv() => match msg {
+                Some(Ok(ReceivedMessage::NodeGone(node_id))) => {
+                    let msg = Message::MeshNodeGone {
+                        via: peer.node_id,
+                        node_id,
+                    };
+                    if server.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                // Nothing else is sent to mesh peers, but the connection must be read.
+                Some(Ok(msg)) => trace!(?msg, "ignoring message from mesh peer"),
+                Some(Err(err)) => debug!("mesh peer connection error: {err:#}"),
+                None => break,
+            }
+        }
+    }
+    client.close().await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_mesh(peers: &[NodeId]) -> Mesh {
+        let url: RelayUrl = "https://relay.example.invalid".parse().unwrap();
+        let (server, _) = mpsc::channel(1);
+        let peers = peers
+            .iter()
+            .map(|&node_id| MeshPeer {
+                url: url.clone(),
+                node_id,
+            })
+            .collect();
+        Mesh::spawn(
+            MeshConfig {
+                secret_key: SecretKey::generate(),
+                peers,
+            },
+            server,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_learn_locations() {
+        let peer_a = SecretKey::generate().public();
+        let peer_b = SecretKey::generate().public();
+        let mut mesh = spawn_mesh(&[peer_a, peer_b]);
+        assert!(mesh.is_peer(&peer_a));
+        assert!(!mesh.is_peer(&SecretKey::generate().public()));
+
+        let node = SecretKey::generate().public();
+        assert_eq!(mesh.location(node), None);
+        mesh.learn(node, peer_a);
+        assert_eq!(mesh.location(node), Some(peer_a));
+        mesh.learn(node, peer_b);
+        assert_eq!(mesh.location(node), Some(peer_b));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forget_locations() {
+        let peer_a = SecretKey::generate().public();
+        let peer_b = SecretKey::generate().public();
+        let mut mesh = spawn_mesh(&[peer_a, peer_b]);
+        let node = SecretKey::generate().public();
+
+        // Only the peer the node was seen on can report it gone.
+        mesh.learn(node, peer_a);
+        mesh.forget(node, peer_b);
+        assert_eq!(mesh.location(node), Some(peer_a));
+        mesh.forget(node, peer_a);
+        assert_eq!(mesh.location(node), None);
+
+        // Locations expire unless the node is seen again.
+        mesh.learn(node, peer_a);
+        tokio::time::advance(LOCATION_TTL / 2).await;
+        mesh.learn(node, peer_a);
+        tokio::time::advance(LOCATION_TTL / 2).await;
+        assert_eq!(mesh.location(node), Some(peer_a));
+        tokio::time::advance(LOCATION_TTL / 2).await;
+        assert_eq!(mesh.location(node), None);
+        assert!(mesh.locations.is_empty());
+    }
+}
@@ -44,14 +44,34 @@ pub struct Metrics {
 
     /// Number of frames received from client connection which have been rate-limited.
     pub frames_rx_ratelimited_total: Counter,
+    /// Number of bytes received from client connections which have been rate-limited.
+    pub bytes_rx_ratelimited_total: Counter,
     /// Number of client connections which have had any frames rate-limited.
     pub conns_rx_ratelimited_total: Counter,
+    /// Number of packets to client connections dropped by the rate-limit.
+    pub frames_tx_ratelimited_total: Counter,
+    /// Number of bytes to client connections dropped by the rate-limit.
+    pub bytes_tx_ratelimited_total: Counter,
+    /// Number of client connections which have had any packets to them rate-limited.
+    pub conns_tx_ratelimited_total: Counter,
+
+    /*
+     * Metrics about the mesh
+     */
+    /// Number of packets forwarded to other relay servers of the mesh.
+    pub mesh_packets_sent: Counter,
+    /// Number of packets received from other relay servers of the mesh.
+    pub mesh_packets_recv: Counter,
+    /// Number of packets which could not be forwarded to, or were rejected from, the mesh.
+    pub mesh_packets_dropped: Counter,
 
     /*
      * Metrics about peers
      */
     /// Number of connections we have accepted
     pub accepts: Counter,
+    /// Number of connections closed because the client was denied access
+    pub accepts_denied: Counter,
     /// Number of connections we have removed because of an error
     pub disconnects: Counter,
 
@@ -62,6 +82,8 @@ pub struct Metrics {
     pub websocket_accepts: Counter,
     /// Number of accepted 'iroh derp http' connection upgrades
     pub derp_accepts: Counter,
+    /// Number of accepted relay connections over QUIC
+    pub quic_accepts: Counter,
     // TODO: enable when we can have multiple connections for one node id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
@@ -99,20 +121,49 @@ impl Default for Metrics {
             frames_rx_ratelimited_total: Counter::new(
                 "Number of frames received from client connection which have been rate-limited.",
             ),
+            bytes_rx_ratelimited_total: Counter::new(
+                "Number of bytes received from client connections which have been rate-limited.",
+            ),
             conns_rx_ratelimited_total: Counter::new(
                 "Number of client connections which have had any frames rate-limited.",
             ),
+            frames_tx_ratelimited_total: Counter::new(
+                "Number of packets to client connections dropped by the rate-limit.",
+            ),
+            bytes_tx_ratelimited_total: Counter::new(
+                "Number of bytes to client connections dropped by the rate-limit.",
+            ),
+            conns_tx_ratelimited_total: Counter::new(
+                "Number of client connections which have had any packets to them rate-limited.",
+            ),
+
+            /*
+             * Metrics about the mesh
+             */
+            mesh_packets_sent: Counter::new(
+                "Number of packets forwarded to other relay servers of the mesh.",
+            ),
+            mesh_packets_recv: Counter::new(
+                "Number of packets received from other relay servers of the mesh.",
+            ),
+            mesh_packets_dropped: Counter::new(
+                "Number of packets which could not be forwarded to, or were rejected from, the mesh.",
+            ),
 
             /*
              * Metrics about peers
              */
             accepts: Counter::new("Number of times this server has accepted a connection."),
+            accepts_denied: Counter::new(
+                "Number of connections closed because the client was denied access.",
+            ),
             disconnects: Counter::new("Number of clients that have then disconnected."),
 
             unique_client_keys: Counter::new("Number of unique client keys per day."),
 
             websocket_accepts: Counter::new("Number of accepted websocket connections"),
             derp_accepts: Counter::new("Number of accepted 'iroh derp http' connection upgrades"),
+            quic_accepts: Counter::new("Number of accepted relay connections over QUIC"),
             // TODO: enable when we can have multiple connections for one node id
             // pub duplicate_client_keys: Counter::new("Number of duplicate client keys."),
             // pub duplicate_client_conns: Counter::new("Number of duplicate client connections."),
@@ -145,6 +196,8 @@ pub struct StunMetrics {
     pub bad_requests: Counter,
     /// Number of failures
     pub failures: Counter,
+    /// Number of requests dropped because of the rate limit
+    pub rate_limited: Counter,
 }
 
 impl Default for StunMetrics {
@@ -158,6 +211,7 @@ impl Default for StunMetrics {
             ipv6_success: Counter::new("Number of successful ipv6 STUN requests served."),
             bad_requests: Counter::new("Number of bad requests made to the STUN endpoint."),
             failures: Counter::new("Number of STUN requests that end in failure."),
+            rate_limited: Counter::new("Number of STUN requests dropped by the rate limiter."),
         }
     }
 }
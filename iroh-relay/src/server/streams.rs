@@ -12,7 +12,10 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::{tungstenite, WebSocketStream};
 use tokio_util::codec::Framed;
 
-use crate::protos::relay::{DerpCodec, Frame};
+use crate::{
+    protos::relay::{DerpCodec, Frame},
+    quic::streams::{QuicFrameReader, QuicFrameWriter},
+};
 
 /// A Stream and Sink for [`Frame`]s connected to a single relay client.
 ///
@@ -21,6 +24,10 @@ use crate::protos::relay::{DerpCodec, Frame};
 pub(crate) enum RelayedStream {
     Derp(Framed<MaybeTlsStream, DerpCodec>),
     Ws(WebSocketStream<MaybeTlsStream>),
+    Quic {
+        reader: QuicFrameReader,
+        writer: QuicFrameWriter,
+    },
 }
 
 fn tung_to_io_err(e: tungstenite::Error) -> std::io::Error {
@@ -37,6 +44,7 @@ impl Sink<Frame> for RelayedStream {
         match *self {
             Self::Derp(ref mut framed) => Pin::new(framed).poll_ready(cx),
             Self::Ws(ref mut ws) => Pin::new(ws).poll_ready(cx).map_err(tung_to_io_err),
+            Self::Quic { ref mut writer, .. } => Pin::new(writer).poll_ready(cx),
         }
     }
 
@@ -46,6 +54,7 @@ impl Sink<Frame> for RelayedStream {
             Self::Ws(ref mut ws) => Pin::new(ws)
                 .start_send(tungstenite::Message::Binary(item.encode_for_ws_msg()))
                 .map_err(tung_to_io_err),
+            Self::Quic { ref mut writer, .. } => Pin::new(writer).start_send(item),
         }
     }
 
@@ -53,6 +62,7 @@ impl Sink<Frame> for RelayedStream {
         match *self {
             Self::Derp(ref mut framed) => Pin::new(framed).poll_flush(cx),
             Self::Ws(ref mut ws) => Pin::new(ws).poll_flush(cx).map_err(tung_to_io_err),
+            Self::Quic { ref mut writer, .. } => Pin::new(writer).poll_flush(cx),
         }
     }
 
@@ -60,6 +70,7 @@ impl Sink<Frame> for RelayedStream {
         match *self {
             Self::Derp(ref mut framed) => Pin::new(framed).poll_close(cx),
             Self::Ws(ref mut ws) => Pin::new(ws).poll_close(cx).map_err(tung_to_io_err),
+            Self::Quic { ref mut writer, .. } => Pin::new(writer).poll_close(cx),
         }
     }
 }
@@ -82,6 +93,7 @@ impl Stream for RelayedStream {
                 Poll::Ready(None) => Poll::Ready(None),
                 Poll::Pending => Poll::Pending,
             },
+            Self::Quic { ref mut reader, .. } => Pin::new(reader).poll_next(cx),
         }
     }
 }
@@ -0,0 +1,294 @@
+//! The STUN server of the relay server.
+//!
+//! STUN binding requests are answered on a dedicated UDP socket, and optionally also on
+//! the UDP socket of the QUIC server, see [`StunConfig::quic`].
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Result;
+use iroh_metrics::inc;
+use quinn::AsyncUdpSocket;
+use tokio::{net::UdpSocket, task::JoinSet};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    protos,
+    server::{metrics::StunMetrics, StunConfig},
+};
+
+/// How often the rate limiter forgets the source addresses which stopped sending requests.
+const RATE_LIMITER_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Answers STUN binding requests.
+///
+/// Shared by the sockets STUN requests are received on, so that the rate limits apply
+/// across them.
+#[derive(derive_more::Debug)]
+pub(crate) struct StunResponder {
+    #[debug("{}", if limiter.is_some() { "rate limited" } else { "unlimited" })]
+    limiter: Option<governor::DefaultKeyedRateLimiter<IpAddr>>,
+}
+
+impl StunResponder {
+    pub(crate) fn new(config: &StunConfig) -> Self {
+        let limiter = config.rate_limit.map(|limit| {
+            let mut quota = governor::Quota::per_second(limit.requests_per_second);
+            if let Some(burst) = limit.max_burst {
+                quota = quota.allow_burst(burst);
+            }
+            governor::RateLimiter::keyed(quota)
+        });
+        Self { limiter }
+    }
+
+    /// Returns the response to a STUN binding request.
+    ///
+    /// Returns `None` if the packet is not a valid binding request, or if the source
+    /// address exceeded the rate limit.
+    fn respond(&self, src_addr: SocketAddr, pkt: &[u8]) -> Option<Vec<u8>> {
+        // Dual-stack sockets report IPv4 sources as IPv4-mapped IPv6 addresses.
+        let src_addr = SocketAddr::new(src_addr.ip().to_canonical(), src_addr.port());
+        if let Some(ref limiter) = self.limiter {
+            if limiter.check_key(&src_addr.ip()).is_err() {
+                inc!(StunMetrics, rate_limited);
+                trace!(%src_addr, "STUN: rate limited, dropping request");
+                return None;
+            }
+        }
+        match protos::stun::parse_binding_request(pkt) {
+            Ok(txid) => {
+                debug!(%src_addr, %txid, "STUN: received binding request");
+                Some(protos::stun::response(txid, src_addr))
+            }
+            Err(err) => {
+                inc!(StunMetrics, bad_requests);
+                warn!(%src_addr, "STUN: invalid binding request: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Forgets the source addresses which are no longer rate limited.
+    fn cleanup(&self) {
+        if let Some(ref limiter) = self.limiter {
+            limiter.retain_recent();
+            limiter.shrink_to_fit();
+        }
+    }
+}
+
+/// Counts a response sent to `dst`.
+fn inc_success(dst: SocketAddr) {
+    match dst.ip().to_canonical() {
+        IpAddr::V4(_) => inc!(StunMetrics, ipv4_success),
+        IpAddr::V6(_) => inc!(StunMetrics, ipv6_success),
+    }
+}
+
+/// Runs a STUN server.
+///
+/// When the future is dropped, the server stops.
+pub(super) async fn server_stun_listener(
+    sock: UdpSocket,
+    responder: Arc<StunResponder>,
+) -> Result<()> {
+    info!(addr = ?sock.local_addr().ok(), "running STUN server");
+    let sock = Arc::new(sock);
+    let mut buffer = vec![0u8; 64 << 10];
+    let mut tasks = JoinSet::new();
+    let mut cleanup = tokio::time::interval(RATE_LIMITER_CLEANUP_INTERVAL);
+    loop {
+        tokio::select! {
+            biased;
+
+            Some(res) = tasks.join_next(), if !tasks.is_empty() => {
+                if let Err(err) = res {
+                    if err.is_panic() {
+                        panic!("task panicked: {:#?}", err);
+                    }
+                }
+            }
+            _ = cleanup.tick() => responder.cleanup(),
+            res = sock.recv_from(&mut buffer) => {
+                match res {
+                    Ok((n, src_addr)) => {
+                        inc!(StunMetrics, requests);
+                        let pkt = &buffer[..n];
+                        if !protos::stun::is(pkt) {
+                            debug!(%src_addr, "STUN: ignoring non stun packet");
+                            inc!(StunMetrics, bad_requests);
+                            continue;
+                        }
+                        if let Some(response) = responder.respond(src_addr, pkt) {
+                            tasks.spawn(send_stun_response(src_addr, response, sock.clone()));
+                        }
+                    }
+                    Err(err) => {
+                        inc!(StunMetrics, failures);
+                        warn!("failed to recv: {err:#}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends a single STUN response, doing all logging required.
+async fn send_stun_response(src_addr: SocketAddr, response: Vec<u8>, sock: Arc<UdpSocket>) {
+    match sock.send_to(&response, src_addr).await {
+        Ok(len) => {
+            if len != response.len() {
+                warn!(
+                    %src_addr,
+                    "failed to write response, {len}/{} bytes sent",
+                    response.len()
+                );
+            } else {
+                inc_success(src_addr);
+            }
+            trace!(%src_addr, "sent {len} bytes");
+        }
+        Err(err) => {
+            inc!(StunMetrics, failures);
+            warn!(%src_addr, "failed to write response: {err:#}");
+        }
+    }
+}
+
+/// The UDP socket of the QUIC server when it also answers STUN requests.
+///
+/// STUN packets are told apart from QUIC packets by the two most significant bits of the
+/// first byte, which are zero for STUN and never for QUIC, see [RFC 7983].  They are
+/// answered right away and not passed on to the QUIC endpoint.
+///
+/// [RFC 7983]: https://www.rfc-editor.org/rfc/rfc7983
+#[derive(Debug)]
+pub(crate) struct StunQuicSocket {
+    inner: Arc<dyn AsyncUdpSocket>,
+    responder: Arc<StunResponder>,
+}
+
+impl StunQuicSocket {
+    pub(crate) fn new(inner: Arc<dyn AsyncUdpSocket>, responder: Arc<StunResponder>) -> Self {
+        Self { inner, responder }
+    }
+
+    /// Answers a STUN request received on the QUIC socket.
+    fn handle_stun(&self, meta: &quinn_udp::RecvMeta, pkt: &[u8]) {
+        inc!(StunMetrics, requests);
+        let Some(response) = self.responder.respond(meta.addr, pkt) else {
+            return;
+        };
+        let transmit = quinn_udp::Transmit {
+            destination: meta.addr,
+            ecn: None,
+            contents: &response,
+            segment_size: None,
+            src_ip: meta.dst_ip,
+        };
+        // STUN clients retry, so a response which can not be sent right away is dropped.
+        match self.inner.try_send(&transmit) {
+            Ok(()) => inc_success(meta.addr),
+            Err(err) => {
+                inc!(StunMetrics, failures);
+                debug!(src_addr = %meta.addr, "failed to write STUN response: {err:#}");
+            }
+        }
+    }
+}
+
+impl AsyncUdpSocket for StunQuicSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn quinn::UdpPoller>> {
+        self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &quinn_udp::Transmit) -> io::Result<()> {
+        self.inner.try_send(transmit)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let count = ready!(self.inner.poll_recv(cx, bufs, metas))?;
+            // Answer the STUN packets and move the remaining datagrams to the front.
+            let mut kept = 0;
+            for i in 0..count {
+                let meta = metas[i];
+                // STUN requests are never coalesced with other datagrams.
+                if meta.len == meta.stride && protos::stun::is(&bufs[i][..meta.len]) {
+                    self.handle_stun(&meta, &bufs[i][..meta.len]);
+                    continue;
+                }
+                if kept != i {
+                    let (head, tail) = bufs.split_at_mut(i);
+                    head[kept][..meta.len].copy_from_slice(&tail[0][..meta.len]);
+                    metas[kept] = meta;
+                }
+                kept += 1;
+            }
+            if kept > 0 {
+                return Poll::Ready(Ok(kept));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+    use crate::server::StunRateLimit;
+
+    #[test]
+    fn test_rate_limit() {
+        let responder = StunResponder::new(&StunConfig {
+            bind_addr: (IpAddr::from([127, 0, 0, 1]), 0).into(),
+            rate_limit: Some(StunRateLimit {
+                requests_per_second: NonZeroU32::new(1).unwrap(),
+                max_burst: NonZeroU32::new(2),
+            }),
+            quic: false,
+        });
+        let req = protos::stun::request(protos::stun::TransactionId::default());
+        let a: SocketAddr = "1.2.3.4:1234".parse().unwrap();
+        let b: SocketAddr = "[::ffff:5.6.7.8]:1234".parse().unwrap();
+
+        assert!(responder.respond(a, &req).is_some());
+        assert!(responder.respond(a, &req).is_some());
+        assert!(responder.respond(a, &req).is_none());
+        // Other source addresses have their own limit.
+        assert!(responder.respond(b, &req).is_some());
+
+        let response = responder.respond(b, &req).unwrap();
+        let (_, addr) = protos::stun::parse_response(&response).unwrap();
+        assert_eq!(addr, "5.6.7.8:1234".parse().unwrap());
+    }
+}
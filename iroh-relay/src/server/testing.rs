@@ -9,6 +9,8 @@ use super::{CertConfig, QuicConfig, RelayConfig, ServerConfig, StunConfig, TlsCo
 pub fn stun_config() -> StunConfig {
     StunConfig {
         bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+        rate_limit: None,
+        quic: false,
     }
 }
 
@@ -66,6 +68,9 @@ pub fn relay_config() -> RelayConfig<()> {
         http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
         tls: Some(tls_config()),
         limits: Default::default(),
+        access: Default::default(),
+        mesh: None,
+        usage: None,
     }
 }
 
@@ -0,0 +1,289 @@
+//! Accounting of how much each node uses the relay server.
+//!
+//! For every client connection the relay server counts the relayed bytes.  When the
+//! connection closes a [`UsageRecord`] is handed to the configured [`UsageSink`], which
+//! exports it for capacity planning or billing.
+
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use iroh_base::key::NodeId;
+use parking_lot::Mutex;
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{info, warn, Instrument};
+use url::Url;
+
+/// The header line of the CSV written by [`CsvUsageSink`] and [`HttpUsageSink`].
+const CSV_HEADER: &str = "node_id,connected_at,duration_ms,bytes_from_client,bytes_to_client";
+
+/// How many records an [`HttpUsageSink`] keeps while the endpoint is unreachable.
+const HTTP_MAX_PENDING_RECORDS: usize = 100_000;
+
+/// How long to wait for the endpoint of an [`HttpUsageSink`] to accept the records.
+const HTTP_PUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The usage of the relay server by a single client connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRecord {
+    /// The node which was connected.
+    pub node_id: NodeId,
+    /// When the client connected.
+    pub connected_at: SystemTime,
+    /// How long the client was connected.
+    pub duration: Duration,
+    /// Bytes the client sent to other nodes.
+    pub bytes_from_client: u64,
+    /// Bytes other nodes sent to the client.
+    pub bytes_to_client: u64,
+}
+
+impl UsageRecord {
+    /// Formats the record as a CSV line, without the line break.
+    ///
+    /// The columns are those of [`CSV_HEADER`], `connected_at` is in seconds since the unix
+    /// epoch.
+    fn to_csv(&self) -> String {
+        let connected_at = self
+            .connected_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        format!(
+            "{},{},{},{},{}",
+            self.node_id,
+            connected_at,
+            self.duration.as_millis(),
+            self.bytes_from_client,
+            self.bytes_to_client
+        )
+    }
+}
+
+/// Receives the [`UsageRecord`] of every client connection when it closes.
+///
+/// Configured with [`Builder::usage`](crate::server::Builder::usage).  Records are only
+/// produced for closed connections, so a node which stays connected shows up once it
+/// disconnects.
+pub trait UsageSink: std::fmt::Debug + Send + Sync + 'static {
+    /// Exports the usage of a closed client connection.
+    ///
+    /// This is called from the connection's task and must not block for long.
+    fn record(&self, record: UsageRecord);
+}
+
+/// Logs the usage records.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogUsageSink;
+
+impl UsageSink for LogUsageSink {
+    fn record(&self, record: UsageRecord) {
+        info!(
+            node_id = %record.node_id,
+            duration = ?record.duration,
+            bytes_from_client = record.bytes_from_client,
+            bytes_to_client = record.bytes_to_client,
+            "relay usage"
+        );
+    }
+}
+
+/// Appends the usage records to a CSV file.
+#[derive(Debug)]
+pub struct CsvUsageSink {
+    file: Mutex<LineWriter<File>>,
+}
+
+impl CsvUsageSink {
+    /// Opens the CSV file at `path` for appending, creating it if needed.
+    ///
+    /// A header line is written to new and empty files.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut file = LineWriter::new(file);
+        if is_empty {
+            writeln!(file, "{CSV_HEADER}")?;
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl UsageSink for CsvUsageSink {
+    fn record(&self, record: UsageRecord) {
+        if let Err(err) = writeln!(self.file.lock(), "{}", record.to_csv()) {
+            warn!("failed to write usage record: {err:#}");
+        }
+    }
+}
+
+/// Pushes the usage records to an HTTP endpoint.
+///
+/// Records are collected and periodically sent in a `POST` request, as CSV with a header
+/// line.  Records which could not be delivered are sent again with the next push.
+#[derive(Debug)]
+pub struct HttpUsageSink {
+    records: Arc<Mutex<Vec<UsageRecord>>>,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl HttpUsageSink {
+    /// Pushes the records to `url` every `interval`.
+    ///
+    /// Must be called from within a tokio runtime.  Records which were not pushed yet when
+    /// the sink is dropped are lost.
+    pub fn new(url: Url, interval: Duration) -> Self {
+        let records: Arc<Mutex<Vec<UsageRecord>>> = Default::default();
+        let task = tokio::spawn(
+            push_records(url, interval, records.clone()).instrument(tracing::info_span!("usage")),
+        );
+        Self {
+            records,
+            _task: AbortOnDropHandle::new(task),
+        }
+    }
+}
+
+impl UsageSink for HttpUsageSink {
+    fn record(&self, record: UsageRecord) {
+        let mut records = self.records.lock();
+        if records.len() >= HTTP_MAX_PENDING_RECORDS {
+            warn!("too many pending usage records, dropping the oldest");
+            records.remove(0);
+        }
+        records.push(record);
+    }
+}
+
+/// Runs the pushes of an [`HttpUsageSink`].
+async fn push_records(url: Url, interval: Duration, records: Arc<Mutex<Vec<UsageRecord>>>) {
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let batch = std::mem::take(&mut *records.lock());
+        if batch.is_empty() {
+            continue;
+        }
+        let mut body = String::from(CSV_HEADER);
+        for record in &batch {
+            body.push('\n');
+            body.push_str(&record.to_csv());
+        }
+        let res = client
+            .post(url.clone())
+            .timeout(HTTP_PUSH_TIMEOUT)
+            .header("Content-Type", "text/csv")
+            .body(body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+        if let Err(err) = res {
+            warn!(count = batch.len(), "failed to push usage records: {err:#}");
+            let mut records = records.lock();
+            let newer = std::mem::replace(&mut *records, batch);
+            records.extend(newer);
+            let excess = records.len().saturating_sub(HTTP_MAX_PENDING_RECORDS);
+            records.drain(..excess);
+        }
+    }
+}
+
+/// Counts the bytes relayed for a single client connection.
+#[derive(Debug)]
+pub(super) struct UsageCounter {
+    connected_at: SystemTime,
+    started: tokio::time::Instant,
+    bytes_from_client: AtomicU64,
+    bytes_to_client: AtomicU64,
+}
+
+impl Default for UsageCounter {
+    fn default() -> Self {
+        Self {
+            connected_at: SystemTime::now(),
+            started: tokio::time::Instant::now(),
+            bytes_from_client: AtomicU64::new(0),
+            bytes_to_client: AtomicU64::new(0),
+        }
+    }
+}
+
+impl UsageCounter {
+    pub(super) fn add_from_client(&self, len: usize) {
+        self.bytes_from_client
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_to_client(&self, len: usize) {
+        self.bytes_to_client
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the record of the connection of `node_id`, which closed now.
+    pub(super) fn record(&self, node_id: NodeId) -> UsageRecord {
+        UsageRecord {
+            node_id,
+            connected_at: self.connected_at,
+            duration: self.started.elapsed(),
+            bytes_from_client: self.bytes_from_client.load(Ordering::Relaxed),
+            bytes_to_client: self.bytes_to_client.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::key::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_usage_counter() {
+        let node_id = SecretKey::generate().public();
+        let counter = UsageCounter::default();
+        counter.add_from_client(100);
+        counter.add_from_client(20);
+        counter.add_to_client(3);
+        let record = counter.record(node_id);
+        assert_eq!(record.node_id, node_id);
+        assert_eq!(record.bytes_from_client, 120);
+        assert_eq!(record.bytes_to_client, 3);
+    }
+
+    #[test]
+    fn test_csv_sink() {
+        let path = std::env::temp_dir().join(format!("usage-{}.csv", rand::random::<u64>()));
+        let node_id = SecretKey::generate().public();
+        let record = UsageRecord {
+            node_id,
+            connected_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            duration: Duration::from_millis(1500),
+            bytes_from_client: 10,
+            bytes_to_client: 20,
+        };
+
+        let sink = CsvUsageSink::open(&path).unwrap();
+        sink.record(record.clone());
+        drop(sink);
+        // Reopening appends without another header.
+        let sink = CsvUsageSink::open(&path).unwrap();
+        sink.record(record);
+        drop(sink);
+
+        let line = format!("{node_id},1700000000,1500,10,20");
+        let expected = format!("{CSV_HEADER}\n{line}\n{line}\n");
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, expected);
+    }
+}
@@ -49,7 +49,7 @@ pub fn server_endpoint(
         }
 
         let addr = ep.bound_sockets();
-        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), addr.0.port());
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), addr.0.unwrap().port());
         let mut addr = NodeAddr::new(ep.node_id()).with_direct_addresses([addr]);
         if let Some(relay_url) = relay_url {
             addr = addr.with_relay_url(relay_url.clone());
@@ -58,6 +58,7 @@ pub mod prod {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(QuicConfig::default()),
+            auth_token: None,
         }
     }
 
@@ -72,6 +73,7 @@ pub mod prod {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(QuicConfig::default()),
+            auth_token: None,
         }
     }
 
@@ -86,6 +88,7 @@ pub mod prod {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(QuicConfig::default()),
+            auth_token: None,
         }
     }
 }
@@ -120,6 +123,7 @@ pub mod staging {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(QuicConfig::default()),
+            auth_token: None,
         }
     }
 
@@ -134,6 +138,7 @@ pub mod staging {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(QuicConfig::default()),
+            auth_token: None,
         }
     }
 }
@@ -380,6 +380,15 @@ impl Message {
             Message::CallMeMaybe(cm) => cm.as_bytes(),
         }
     }
+
+    /// Returns the type of this message.
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Message::Ping(_) => MessageType::Ping,
+            Message::Pong(_) => MessageType::Pong,
+            Message::CallMeMaybe(_) => MessageType::CallMeMaybe,
+        }
+    }
 }
 
 impl Display for Message {
@@ -19,7 +19,8 @@
 //! implementations to co-exist because there are many possible ways to implement this.
 //! Each [`Endpoint`] can use the discovery mechanisms most suitable to the application.
 //! The [`Builder::discovery`] method is used to add a discovery mechanism to an
-//! [`Endpoint`].
+//! [`Endpoint`].  Applications can implement the [`Discovery`] trait for their own
+//! mechanisms, see its documentation for how the [`Endpoint`] uses it.
 //!
 //! Some generally useful discovery implementations are provided:
 //!
@@ -39,8 +40,18 @@
 //! - The [`DhtDiscovery`] also uses the [`pkarr`] system but can also publish and lookup
 //!   records to/from the Mainline DHT.
 //!
+#![cfg_attr(
+    feature = "discovery-file",
+    doc = "- The [`FileDiscovery`](file::FileDiscovery) reads the addresses of nodes from a
+             TOML or JSON file, for fleets with a known topology."
+)]
+#![cfg_attr(feature = "discovery-file", doc = "")]
+//! - The [`PeerExchange`](pex::PeerExchange) learns the addresses of nodes from connected
+//!   nodes, so a mesh can bootstrap from a single seed node.
+//!
 //! To use multiple discovery systems simultaneously use [`ConcurrentDiscovery`] which will
-//! perform lookups to all discovery systems at the same time.
+//! perform lookups to all discovery systems at the same time.  To avoid repeating lookups of
+//! the same node, wrap the discovery in a [`CachingDiscovery`].
 //!
 //! # Examples
 //!
@@ -100,6 +111,7 @@
 //! ```
 //!
 //! [`RelayUrl`]: crate::relay::RelayUrl
+//! [`CachingDiscovery`]: cache::CachingDiscovery
 //! [`Builder::discovery`]: crate::endpoint::Builder::discovery
 //! [`DnsDiscovery`]: dns::DnsDiscovery
 //! [Number 0]: https://n0.computer
@@ -112,21 +124,36 @@
     doc = "[`LocalSwarmDiscovery`]: local_swarm_discovery::LocalSwarmDiscovery"
 )]
 
-use std::time::Duration;
+use std::{
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, ensure, Result};
-use futures_lite::stream::{Boxed as BoxStream, StreamExt};
+use futures_lite::stream::{Boxed as BoxStream, Stream, StreamExt};
 use iroh_base::node_addr::NodeAddr;
-use tokio::{sync::oneshot, task::JoinHandle};
+use iroh_metrics::inc;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tokio_util::task::AbortOnDropHandle;
 use tracing::{debug, error_span, warn, Instrument};
 
-use crate::{AddrInfo, Endpoint, NodeId};
+use crate::{magicsock::Metrics as MagicsockMetrics, AddrInfo, Endpoint, NodeId};
 
+pub mod cache;
 pub mod dns;
 
+#[cfg(feature = "discovery-file")]
+#[cfg_attr(iroh_docsrs, doc(cfg(feature = "discovery-file")))]
+pub mod file;
 #[cfg(feature = "discovery-local-network")]
 #[cfg_attr(iroh_docsrs, doc(cfg(feature = "discovery-local-network")))]
 pub mod local_swarm_discovery;
+pub mod pex;
 pub mod pkarr;
 pub mod static_provider;
 
@@ -145,7 +172,93 @@ pub mod static_provider;
 /// discovery information changes. If a discovery mechanism requires a periodic
 /// refresh, it should start its own task.
 ///
+/// # Resolving
+///
+/// The [`Endpoint`] calls [`Discovery::resolve`] whenever it connects to a node by
+/// [`NodeId`] without a recently working path to it, e.g. when [`Endpoint::connect`] is
+/// called with only a [`NodeId`].  The connection attempt waits for the first
+/// [`DiscoveryItem`] with a non-empty [`AddrInfo`], the stream is dropped once the
+/// connection is established.  Later items add more addresses to the endpoint, so a
+/// service which knows several sources should yield each of them as soon as it arrives.
+/// Errors are logged, the other discovery services keep resolving.
+/// [`Endpoint::watch_node_addr`] resolves periodically as well.
+///
+/// The [`DiscoveryItem::provenance`] identifies the service in the
+/// [statistics](Endpoint::discovery_stats) and the logs.  Services which know when the
+/// node published its addresses should set [`DiscoveryItem::last_updated`], so stale
+/// results can be ignored.
+///
+/// # Implementing a discovery service
+///
+/// Discovery services only need to implement the methods they support, all methods have
+/// default implementations doing nothing.  A service is added to an endpoint with
+/// [`Builder::add_discovery`], which passes the secret key of the endpoint to publishing
+/// services.  This service shares the addresses of nodes in the same process:
+///
+/// ```no_run
+/// use std::{
+///     collections::BTreeMap,
+///     sync::{Arc, RwLock},
+///     time::SystemTime,
+/// };
+///
+/// use anyhow::Result;
+/// use futures_lite::stream::{self, Boxed as BoxStream, StreamExt};
+/// use iroh::{
+///     discovery::{Discovery, DiscoveryItem},
+///     AddrInfo, Endpoint, NodeId,
+/// };
+///
+/// #[derive(Debug)]
+/// struct Directory {
+///     node_id: NodeId,
+///     nodes: Arc<RwLock<BTreeMap<NodeId, (AddrInfo, u64)>>>,
+/// }
+///
+/// impl Discovery for Directory {
+///     fn publish(&self, info: &AddrInfo) {
+///         let now = SystemTime::now()
+///             .duration_since(SystemTime::UNIX_EPOCH)
+///             .unwrap()
+///             .as_micros() as u64;
+///         let mut nodes = self.nodes.write().unwrap();
+///         nodes.insert(self.node_id, (info.clone(), now));
+///     }
+///
+///     fn resolve(
+///         &self,
+///         _endpoint: Endpoint,
+///         node_id: NodeId,
+///     ) -> Option<BoxStream<Result<DiscoveryItem>>> {
+///         let (addr_info, last_updated) = self.nodes.read().unwrap().get(&node_id)?.clone();
+///         let item = DiscoveryItem {
+///             node_id,
+///             provenance: "directory",
+///             last_updated: Some(last_updated),
+///             addr_info,
+///             user_data: None,
+///         };
+///         Some(stream::once(Ok(item)).boxed())
+///     }
+/// }
+///
+/// # async fn wrapper() -> Result<()> {
+/// let nodes = Arc::new(RwLock::new(BTreeMap::new()));
+/// let ep = Endpoint::builder()
+///     .add_discovery(move |secret_key| {
+///         Some(Directory {
+///             node_id: secret_key.public(),
+///             nodes,
+///         })
+///     })
+///     .bind()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
 /// [`RelayUrl`]: crate::relay::RelayUrl
+/// [`Builder::add_discovery`]: crate::endpoint::Builder::add_discovery
 pub trait Discovery: std::fmt::Debug + Send + Sync {
     /// Publishes the given [`AddrInfo`] to the discovery mechanism.
     ///
@@ -156,6 +269,15 @@ pub trait Discovery: std::fmt::Debug + Send + Sync {
     /// These tasks will be run on the runtime of the [`super::Endpoint`].
     fn publish(&self, _info: &AddrInfo) {}
 
+    /// Publishes the given [`AddrInfo`] together with the node's [`UserData`].
+    ///
+    /// The [`Endpoint`] calls this instead of [`Discovery::publish`].  Discovery services
+    /// which can publish user data should implement this method, the default
+    /// implementation ignores the user data and calls [`Discovery::publish`].
+    fn publish_with_user_data(&self, info: &AddrInfo, _user_data: Option<&UserData>) {
+        self.publish(info)
+    }
+
     /// Resolves the [`AddrInfo`] for the given [`NodeId`].
     ///
     /// Once the returned [`BoxStream`] is dropped, the service should stop any pending
@@ -193,6 +315,16 @@ pub trait Discovery: std::fmt::Debug + Send + Sync {
     fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
         None
     }
+
+    /// Pauses or resumes the background activity of the discovery service.
+    ///
+    /// Called by [`Endpoint::pause`] and [`Endpoint::resume`].  While paused a service
+    /// should not publish on its own, e.g. to republish records before they expire.  The
+    /// [`Endpoint`] does not call [`Discovery::publish`] while paused and publishes its
+    /// current addresses again when resuming.
+    ///
+    /// The default implementation does nothing.
+    fn set_paused(&self, _paused: bool) {}
 }
 
 /// The results returned from [`Discovery::resolve`].
@@ -206,16 +338,116 @@ pub struct DiscoveryItem {
     pub provenance: &'static str,
     /// Optional timestamp when this node address info was last updated.
     ///
-    /// Must be microseconds since the unix epoch.
-    // TODO(ramfox): this is currently unused. As we develop more `DiscoveryService`s, we may discover that we do not need this. It is only truly relevant when comparing `relay_urls`, since we can attempt to dial any number of socket addresses, but expect each node to have one "home relay" that we will attempt to contact them on. This means we would need some way to determine which relay url to choose between, if more than one relay url is reported.
+    /// Must be microseconds since the unix epoch.  Services resolving signed records, like
+    /// the [`PkarrResolver`](pkarr::PkarrResolver), set it to the time the node signed the
+    /// record.  Results older than [`Builder::discovery_max_age`] are ignored.
+    ///
+    /// [`Builder::discovery_max_age`]: crate::endpoint::Builder::discovery_max_age
     pub last_updated: Option<u64>,
     /// The address info for the node being resolved.
     pub addr_info: AddrInfo,
+    /// The [`UserData`] published by the node, if any.
+    pub user_data: Option<UserData>,
+}
+
+impl DiscoveryItem {
+    /// Returns how long ago this node address info was last updated.
+    ///
+    /// Returns `None` if the discovery service did not set [`Self::last_updated`].  A
+    /// timestamp in the future is treated as an age of zero.
+    pub fn age(&self) -> Option<Duration> {
+        let last_updated = Duration::from_micros(self.last_updated?);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Some(now.saturating_sub(last_updated))
+    }
+}
+
+/// Application-defined data published together with the addressing information of a node.
+///
+/// This can be used to advertise e.g. the supported protocols or a display name of the
+/// node.  It is set with [`Builder::user_data_for_discovery`] or
+/// [`Endpoint::set_user_data_for_discovery`], and resolvers return it in
+/// [`DiscoveryItem::user_data`].
+///
+/// Discovery services which publish signed records, like the [`PkarrPublisher`] and the
+/// [`DhtDiscovery`], include it in the signed record.  The data is a UTF-8 string of at
+/// most [`UserData::MAX_LENGTH`] bytes, so that it fits into a single DNS TXT string.
+///
+/// [`Builder::user_data_for_discovery`]: crate::endpoint::Builder::user_data_for_discovery
+/// [`PkarrPublisher`]: pkarr::PkarrPublisher
+/// [`DhtDiscovery`]: pkarr::dht::DhtDiscovery
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, derive_more::Display)]
+pub struct UserData(String);
+
+impl UserData {
+    /// The maximum length of the user data in bytes.
+    ///
+    /// A DNS TXT string holds up to 255 bytes, which includes the `user-data=` prefix.
+    pub const MAX_LENGTH: usize = 245;
+}
+
+/// Error returned when the user data is longer than [`UserData::MAX_LENGTH`].
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("user data must be at most {} bytes long", UserData::MAX_LENGTH)]
+pub struct MaxLengthExceededError {
+    _private: (),
+}
+
+impl TryFrom<String> for UserData {
+    type Error = MaxLengthExceededError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.len() > Self::MAX_LENGTH {
+            Err(MaxLengthExceededError { _private: () })
+        } else {
+            Ok(Self(value))
+        }
+    }
+}
+
+impl FromStr for UserData {
+    type Err = MaxLengthExceededError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s.to_string())
+    }
+}
+
+impl AsRef<str> for UserData {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<UserData> for String {
+    fn from(value: UserData) -> Self {
+        value.0
+    }
+}
+
+/// Statistics about the results of a discovery service.
+///
+/// See [`Endpoint::discovery_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiscoveryStats {
+    /// Number of results the service produced while resolving nodes.
+    pub results: u64,
+    /// Number of times the service was the first to produce a result for a node which was
+    /// being resolved.
+    pub first_results: u64,
 }
 
 /// A discovery service that combines multiple discovery sources.
 ///
-/// The discovery services will resolve concurrently.
+/// The discovery services resolve concurrently and their results are merged as they
+/// arrive.  Every result is added to the endpoint's addresses of the node right away, so
+/// a connection which is still being established uses the addresses of a slower service
+/// as soon as they are found.  A service which fails does not stop the others.
+///
+/// [`Endpoint::discovery_stats`] shows which services produced the results, identified by
+/// their [`DiscoveryItem::provenance`].
 #[derive(Debug, Default)]
 pub struct ConcurrentDiscovery {
     services: Vec<Box<dyn Discovery>>,
@@ -255,6 +487,12 @@ impl Discovery for ConcurrentDiscovery {
         }
     }
 
+    fn publish_with_user_data(&self, info: &AddrInfo, user_data: Option<&UserData>) {
+        for service in &self.services {
+            service.publish_with_user_data(info, user_data);
+        }
+    }
+
     fn resolve(
         &self,
         endpoint: Endpoint,
@@ -280,12 +518,126 @@ impl Discovery for ConcurrentDiscovery {
         let streams = futures_buffered::MergeBounded::from_iter(streams);
         Some(Box::pin(streams))
     }
+
+    fn set_paused(&self, paused: bool) {
+        for service in &self.services {
+            service.set_paused(paused);
+        }
+    }
 }
 
 /// Maximum duration since the last control or data message received from an endpoint to make us
 /// start a discovery task.
 const MAX_AGE: Duration = Duration::from_secs(10);
 
+/// How often [`NodeAddrStream`] resolves the watched node again.
+const WATCH_RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A stream of the addresses discovered for a node, see [`Endpoint::watch_node_addr`].
+///
+/// A new [`NodeAddr`] is yielded whenever a discovery service reports addressing
+/// information for the node which differs from the last yielded one.  The node is resolved
+/// again every 30 seconds, and passively discovered addresses are yielded as they arrive.
+/// Discovered addresses are also added to the endpoint, so dialing the node again uses
+/// them.
+///
+/// Watching stops when the stream is dropped or the endpoint is closed.
+#[derive(Debug)]
+pub struct NodeAddrStream {
+    receiver: mpsc::Receiver<NodeAddr>,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl NodeAddrStream {
+    /// Starts watching the addresses of `node_id`.
+    pub(super) fn start(ep: Endpoint, node_id: NodeId) -> Result<Self> {
+        ensure!(ep.discovery().is_some(), "No discovery services configured");
+        let (sender, receiver) = mpsc::channel(16);
+        let me = ep.node_id();
+        let task = tokio::task::spawn(Self::run(ep, node_id, sender).instrument(error_span!(
+            "watch_node_addr",
+            me = %me.fmt_short(),
+            node = %node_id.fmt_short()
+        )));
+        Ok(Self {
+            receiver,
+            _task: AbortOnDropHandle::new(task),
+        })
+    }
+
+    async fn run(ep: Endpoint, node_id: NodeId, sender: mpsc::Sender<NodeAddr>) {
+        let Some(discovery) = ep.discovery() else {
+            return;
+        };
+        let mut passive = discovery.subscribe();
+        let mut resolve: Option<BoxStream<Result<DiscoveryItem>>> = None;
+        let mut resolve_timer = tokio::time::interval(WATCH_RESOLVE_INTERVAL);
+        resolve_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut last_info = None;
+        loop {
+            let item = tokio::select! {
+                _ = ep.cancel_token().cancelled() => break,
+                _ = sender.closed() => break,
+                _ = resolve_timer.tick() => {
+                    // A slow resolve which did not finish yet is restarted.
+                    resolve = ep.discovery().and_then(|d| d.resolve(ep.clone(), node_id));
+                    continue;
+                }
+                item = async { passive.as_mut().expect("checked").next().await }, if passive.is_some() => {
+                    match item {
+                        Some(item) if item.node_id == node_id => item,
+                        Some(_) => continue,
+                        None => {
+                            passive = None;
+                            continue;
+                        }
+                    }
+                }
+                item = async { resolve.as_mut().expect("checked").next().await }, if resolve.is_some() => {
+                    match item {
+                        Some(Ok(item)) => item,
+                        Some(Err(err)) => {
+                            debug!("discovery service produced error: {err:#}");
+                            continue;
+                        }
+                        None => {
+                            resolve = None;
+                            continue;
+                        }
+                    }
+                }
+            };
+            if item.addr_info.is_empty() || last_info.as_ref() == Some(&item.addr_info) {
+                continue;
+            }
+            if ep.is_discovery_item_stale(&item) {
+                debug!(provenance = %item.provenance, age = ?item.age(), "discovery: ignoring stale address");
+                continue;
+            }
+            debug!(provenance = %item.provenance, addr = ?item.addr_info, "discovery: address changed");
+            last_info = Some(item.addr_info.clone());
+            let age = item.age().unwrap_or_default();
+            let addr = NodeAddr {
+                node_id,
+                info: item.addr_info,
+            };
+            ep.add_node_addr_with_age(addr.clone(), item.provenance, age)
+                .ok();
+            if sender.send(addr).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Stream for NodeAddrStream {
+    type Item = NodeAddr;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
 /// A wrapper around a tokio task which runs a node discovery.
 pub(super) struct DiscoveryTask {
     on_first_rx: oneshot::Receiver<Result<()>>,
@@ -402,7 +754,9 @@ impl DiscoveryTask {
             }
         };
         let mut on_first_tx = Some(on_first_tx);
+        let mut last_err = None;
         debug!("discovery: start");
+        inc!(MagicsockMetrics, discovery_resolve);
         loop {
             let next = tokio::select! {
                 _ = ep.cancel_token().cancelled() => break,
@@ -414,25 +768,40 @@ impl DiscoveryTask {
                         debug!(provenance = %r.provenance, addr = ?r.addr_info, "discovery: empty address found");
                         continue;
                     }
+                    if ep.is_discovery_item_stale(&r) {
+                        debug!(provenance = %r.provenance, age = ?r.age(), "discovery: stale address found");
+                        continue;
+                    }
                     debug!(provenance = %r.provenance, addr = ?r.addr_info, "discovery: new address found");
+                    inc!(MagicsockMetrics, discovery_results);
+                    ep.note_discovery_result(r.provenance, on_first_tx.is_some());
+                    let age = r.age().unwrap_or_default();
                     let addr = NodeAddr {
                         info: r.addr_info,
                         node_id,
                     };
-                    ep.add_node_addr_with_source(addr, r.provenance).ok();
+                    ep.add_node_addr_with_age(addr, r.provenance, age).ok();
                     if let Some(tx) = on_first_tx.take() {
                         tx.send(Ok(())).ok();
                     }
                 }
                 Some(Err(err)) => {
+                    // Other discovery services might still produce results.
                     warn!(?err, "discovery service produced error");
-                    break;
+                    inc!(MagicsockMetrics, discovery_errors);
+                    last_err = Some(err);
                 }
                 None => break,
             }
         }
         if let Some(tx) = on_first_tx.take() {
-            let err = anyhow!("Discovery produced no results for {}", node_id.fmt_short());
+            let err = match last_err {
+                Some(err) => err.context(format!(
+                    "Discovery produced no results for {}",
+                    node_id.fmt_short()
+                )),
+                None => anyhow!("Discovery produced no results for {}", node_id.fmt_short()),
+            };
             tx.send(Err(err)).ok();
         }
     }
@@ -532,6 +901,7 @@ mod tests {
                         provenance: "test-disco",
                         last_updated: Some(ts),
                         addr_info,
+                        user_data: None,
                     };
                     let delay = self.delay;
                     let fut = async move {
@@ -565,6 +935,18 @@ mod tests {
         }
     }
 
+    #[derive(Debug)]
+    struct FailingDiscovery;
+    impl Discovery for FailingDiscovery {
+        fn resolve(
+            &self,
+            _endpoint: Endpoint,
+            _node_id: NodeId,
+        ) -> Option<BoxStream<Result<DiscoveryItem>>> {
+            Some(futures_lite::stream::once(Err(anyhow!("resolve failed"))).boxed())
+        }
+    }
+
     const TEST_ALPN: &[u8] = b"n0/iroh/test";
 
     /// This is a smoke test for our discovery mechanism.
@@ -645,6 +1027,82 @@ mod tests {
         Ok(())
     }
 
+    /// This test adds a discovery which fails right away, the other discovery still answers.
+    #[tokio::test]
+    async fn endpoint_discovery_combined_with_failing() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let disco_shared = TestDiscoveryShared::default();
+        let (ep1, _guard1) = {
+            let secret = SecretKey::generate();
+            let disco = disco_shared.create_discovery(secret.public());
+            new_endpoint(secret, disco).await
+        };
+        let (ep2, _guard2) = {
+            let secret = SecretKey::generate();
+            let disco1 = FailingDiscovery;
+            let disco2 = disco_shared.create_discovery(secret.public());
+            let mut disco = ConcurrentDiscovery::empty();
+            disco.add(disco1);
+            disco.add(disco2);
+            new_endpoint(secret, disco).await
+        };
+        let ep1_addr = NodeAddr::new(ep1.node_id());
+        // wait for out address to be updated and thus published at least once
+        ep1.node_addr().await?;
+        let _conn = ep2.connect(ep1_addr, TEST_ALPN).await?;
+
+        let stats = ep2.discovery_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(
+            stats.get("test-disco"),
+            Some(&DiscoveryStats {
+                results: 1,
+                first_results: 1,
+            })
+        );
+        Ok(())
+    }
+
+    /// Watching a node yields its addresses again when they change.
+    #[tokio::test]
+    async fn endpoint_discovery_watch_node_addr() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let disco_shared = TestDiscoveryShared::default();
+        let (ep1, _guard1) = {
+            let secret = SecretKey::generate();
+            let disco = disco_shared.create_discovery(secret.public());
+            new_endpoint(secret, disco).await
+        };
+        let (ep2, _guard2) = {
+            let secret = SecretKey::generate();
+            let disco = disco_shared.create_discovery(secret.public());
+            new_endpoint(secret, disco).await
+        };
+        // wait for out address to be updated and thus published at least once
+        ep1.node_addr().await?;
+
+        let mut stream = ep2.watch_node_addr(ep1.node_id())?;
+        let addr = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await?
+            .expect("stream ended");
+        assert_eq!(addr.node_id, ep1.node_id());
+
+        // The node publishes new addresses, they are found by the next resolve.
+        let new_info = AddrInfo {
+            relay_url: None,
+            direct_addresses: BTreeSet::from(["240.0.0.1:1000".parse().unwrap()]),
+        };
+        disco_shared
+            .nodes
+            .lock()
+            .insert(ep1.node_id(), (new_info.clone(), system_time_now()));
+        let addr = tokio::time::timeout(WATCH_RESOLVE_INTERVAL * 2, stream.next())
+            .await?
+            .expect("stream ended");
+        assert_eq!(addr.info, new_info);
+        Ok(())
+    }
+
     /// This test only has the "lying" discovery. It is here to make sure that this actually fails.
     #[tokio::test]
     async fn endpoint_discovery_combined_wrong_only() -> anyhow::Result<()> {
@@ -698,6 +1156,67 @@ mod tests {
         Ok(())
     }
 
+    /// This test checks that discovery results older than the max age are ignored.
+    #[tokio::test]
+    async fn endpoint_discovery_max_age() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let (ep1, _guard1) = new_endpoint(SecretKey::generate(), EmptyDiscovery).await;
+        let disco_shared = TestDiscoveryShared::default();
+        let ep2 = {
+            let secret = SecretKey::generate();
+            let disco = disco_shared.create_discovery(secret.public());
+            Endpoint::builder()
+                .secret_key(secret)
+                .discovery(Box::new(disco))
+                .relay_mode(RelayMode::Disabled)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .discovery_max_age(Duration::from_secs(60))
+                .bind()
+                .await?
+        };
+        let ep1_info = ep1.node_addr().await?.info;
+
+        // The address of ep1 was published an hour ago.
+        let an_hour_ago = system_time_now() - 3600 * 1_000_000;
+        disco_shared
+            .nodes
+            .lock()
+            .insert(ep1.node_id(), (ep1_info.clone(), an_hour_ago));
+        let res = ep2.connect(ep1.node_id(), TEST_ALPN).await;
+        assert!(res.is_err());
+
+        disco_shared
+            .nodes
+            .lock()
+            .insert(ep1.node_id(), (ep1_info, system_time_now()));
+        let _conn = ep2.connect(ep1.node_id(), TEST_ALPN).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn discovery_item_age() {
+        let item = DiscoveryItem {
+            node_id: SecretKey::generate().public(),
+            provenance: "test",
+            last_updated: Some(system_time_now() - 10 * 1_000_000),
+            addr_info: Default::default(),
+            user_data: None,
+        };
+        let age = item.age().unwrap();
+        assert!(age >= Duration::from_secs(10) && age < Duration::from_secs(20));
+
+        let future = DiscoveryItem {
+            last_updated: Some(system_time_now() + 10 * 1_000_000),
+            ..item.clone()
+        };
+        assert_eq!(future.age(), Some(Duration::ZERO));
+        let unknown = DiscoveryItem {
+            last_updated: None,
+            ..item
+        };
+        assert_eq!(unknown.age(), None);
+    }
+
     async fn new_endpoint(
         secret: SecretKey,
         disco: impl Discovery + 'static,
@@ -748,8 +1267,8 @@ mod test_dns_pkarr {
     use tokio_util::task::AbortOnDropHandle;
 
     use crate::{
-        discovery::pkarr::PkarrPublisher,
-        dns::{node_info::NodeInfo, ResolverExt},
+        discovery::{pkarr::PkarrPublisher, Discovery, UserData},
+        dns::{lookup_node_info_by_id_staggered, node_info::NodeInfo, ResolverExt},
         test_utils::{
             dns_server::{create_dns_resolver, run_dns_server},
             pkarr_dns_state::State,
@@ -818,6 +1337,65 @@ mod test_dns_pkarr {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn pkarr_publish_multiple_relays() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let origin = "testdns.example".to_string();
+        let dns_pkarr_server = DnsPkarrServer::run_with_origin(origin.clone()).await?;
+
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let resolver = create_dns_resolver(dns_pkarr_server.nameserver)?;
+        // Nothing listens on this port, publishing still succeeds with the other relay.
+        let publisher = PkarrPublisher::builder("http://127.0.0.1:1/pkarr".parse()?)
+            .add_pkarr_relay(dns_pkarr_server.pkarr_url.clone())
+            .ttl(10)
+            .republish_interval(Duration::from_secs(1))
+            .build(secret_key);
+        publisher.update_addr_info(&addr_info);
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+        let resolved = resolver.lookup_by_id(&node_id, &origin).await?;
+
+        let expected = NodeAddr {
+            info: addr_info,
+            node_id,
+        };
+        assert_eq!(resolved, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pkarr_publish_dns_resolve_user_data() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let origin = "testdns.example".to_string();
+        let dns_pkarr_server = DnsPkarrServer::run_with_origin(origin.clone()).await?;
+
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+        let user_data: UserData = "proto=foo/1".parse()?;
+
+        let resolver = create_dns_resolver(dns_pkarr_server.nameserver)?;
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone());
+        publisher.publish_with_user_data(&addr_info, Some(&user_data));
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+        let resolved = lookup_node_info_by_id_staggered(&resolver, &node_id, &origin, &[]).await?;
+
+        assert_eq!(resolved.user_data, Some(user_data));
+        assert_eq!(AddrInfo::from(resolved), addr_info);
+        Ok(())
+    }
+
     const TEST_ALPN: &[u8] = b"TEST";
 
     #[tokio::test]
@@ -0,0 +1,293 @@
+//! Caching of discovery results.
+//!
+//! [`CachingDiscovery`] wraps another [`Discovery`] service and remembers the results of
+//! resolving a node for a while, so repeated connection attempts to the same node do not
+//! query DNS or pkarr relays every time.  Lookups which produced no result are remembered
+//! as well, for a shorter time, so a node which can not be found is not looked up again
+//! right away.
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use futures_lite::stream::{self, Boxed as BoxStream, StreamExt};
+use tracing::trace;
+
+use super::{Discovery, DiscoveryItem, UserData};
+use crate::{AddrInfo, Endpoint, NodeId};
+
+/// The default time the results of a successful lookup are cached.
+pub const DEFAULT_POSITIVE_TTL: Duration = Duration::from_secs(60);
+
+/// The default time a lookup which produced no results is cached.
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+/// A [`Discovery`] service which caches the results of another one.
+///
+/// The results of resolving a node are cached for the [positive
+/// TTL](Self::positive_ttl), during which resolving the node returns the cached results
+/// without asking the wrapped service.  A lookup which finished without any result is cached
+/// for the [negative TTL](Self::negative_ttl), resolving the node fails right away during
+/// that time.
+///
+/// The cache is shared between clones, keep a clone to [invalidate](Self::invalidate) the
+/// cached results of a node, e.g. after failing to connect to it.  Publishing and passive
+/// discovery are passed on to the wrapped service unchanged.
+#[derive(Debug, Clone)]
+pub struct CachingDiscovery {
+    inner: Arc<dyn Discovery>,
+    cache: Arc<parking_lot::Mutex<HashMap<NodeId, CacheEntry>>>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+}
+
+#[derive(Debug)]
+enum CacheEntry {
+    /// The items resolved for the node.
+    Found {
+        items: Vec<DiscoveryItem>,
+        expires: Instant,
+    },
+    /// The lookup of the node produced no items.
+    NotFound { expires: Instant },
+}
+
+impl CacheEntry {
+    fn expires(&self) -> Instant {
+        match self {
+            Self::Found { expires, .. } => *expires,
+            Self::NotFound { expires } => *expires,
+        }
+    }
+}
+
+impl CachingDiscovery {
+    /// Creates a cache in front of the `inner` discovery service.
+    pub fn new(inner: impl Discovery + 'static) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            cache: Default::default(),
+            positive_ttl: DEFAULT_POSITIVE_TTL,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+        }
+    }
+
+    /// Sets how long the results of a successful lookup are cached, by default
+    /// [`DEFAULT_POSITIVE_TTL`].
+    pub fn positive_ttl(mut self, ttl: Duration) -> Self {
+        self.positive_ttl = ttl;
+        self
+    }
+
+    /// Sets how long a lookup which produced no results is cached, by default
+    /// [`DEFAULT_NEGATIVE_TTL`].
+    ///
+    /// Set to zero to disable negative caching.
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Removes the cached results of `node_id`, the next lookup asks the wrapped service.
+    pub fn invalidate(&self, node_id: NodeId) {
+        self.cache.lock().remove(&node_id);
+    }
+
+    /// Removes all cached results.
+    pub fn clear(&self) {
+        self.cache.lock().clear();
+    }
+
+    fn insert_item(&self, item: &DiscoveryItem) {
+        let now = Instant::now();
+        let mut cache = self.cache.lock();
+        match cache.get_mut(&item.node_id) {
+            Some(CacheEntry::Found { items, expires }) => {
+                // Only the latest item of each service is kept.
+                items.retain(|cached| cached.provenance != item.provenance);
+                items.push(item.clone());
+                *expires = now + self.positive_ttl;
+            }
+            _ => {
+                Self::remove_expired(&mut cache, now);
+                cache.insert(
+                    item.node_id,
+                    CacheEntry::Found {
+                        items: vec![item.clone()],
+                        expires: now + self.positive_ttl,
+                    },
+                );
+            }
+        }
+    }
+
+    fn insert_not_found(&self, node_id: NodeId) {
+        if self.negative_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock();
+        Self::remove_expired(&mut cache, now);
+        cache.insert(
+            node_id,
+            CacheEntry::NotFound {
+                expires: now + self.negative_ttl,
+            },
+        );
+    }
+
+    /// Drops the expired entries when a new node is added, to bound the size of the cache.
+    fn remove_expired(cache: &mut HashMap<NodeId, CacheEntry>, now: Instant) {
+        cache.retain(|_, entry| entry.expires() > now);
+    }
+}
+
+impl Discovery for CachingDiscovery {
+    fn publish(&self, info: &AddrInfo) {
+        self.inner.publish(info)
+    }
+
+    fn publish_with_user_data(&self, info: &AddrInfo, user_data: Option<&UserData>) {
+        self.inner.publish_with_user_data(info, user_data)
+    }
+
+    fn resolve(
+        &self,
+        endpoint: Endpoint,
+        node_id: NodeId,
+    ) -> Option<BoxStream<Result<DiscoveryItem>>> {
+        {
+            let mut cache = self.cache.lock();
+            match cache.get(&node_id) {
+                Some(entry) if entry.expires() <= Instant::now() => {
+                    cache.remove(&node_id);
+                }
+                Some(CacheEntry::Found { items, .. }) => {
+                    trace!(node = %node_id.fmt_short(), "discovery: cached results");
+                    let items: Vec<_> = items.iter().cloned().map(Ok).collect();
+                    return Some(stream::iter(items).boxed());
+                }
+                Some(CacheEntry::NotFound { .. }) => {
+                    trace!(node = %node_id.fmt_short(), "discovery: cached lookup failure");
+                    let err = anyhow!("node was not found recently, lookup is cached");
+                    return Some(stream::once(Err(err)).boxed());
+                }
+                None => {}
+            }
+        }
+        let inner = self.inner.resolve(endpoint, node_id)?;
+        let this = self.clone();
+        // Caches the items as they arrive.  A lookup without any item is only cached once it
+        // finished, not when the stream is dropped early.
+        let stream = stream::unfold((inner, false), move |(mut inner, found)| {
+            let this = this.clone();
+            async move {
+                match inner.next().await {
+                    Some(Ok(item)) => {
+                        this.insert_item(&item);
+                        Some((Ok(item), (inner, true)))
+                    }
+                    Some(Err(err)) => Some((Err(err), (inner, found))),
+                    None => {
+                        if !found {
+                            this.insert_not_found(node_id);
+                        }
+                        None
+                    }
+                }
+            }
+        });
+        Some(stream.boxed())
+    }
+
+    fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
+        self.inner.subscribe()
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.inner.set_paused(paused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use iroh_base::key::SecretKey;
+    use testresult::TestResult;
+
+    use super::*;
+
+    /// Resolves every node to the same address and counts the lookups.
+    #[derive(Debug, Clone, Default)]
+    struct CountingDiscovery {
+        lookups: Arc<AtomicUsize>,
+        empty: bool,
+    }
+
+    impl Discovery for CountingDiscovery {
+        fn resolve(
+            &self,
+            _endpoint: Endpoint,
+            node_id: NodeId,
+        ) -> Option<BoxStream<Result<DiscoveryItem>>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            if self.empty {
+                return Some(stream::empty().boxed());
+            }
+            let item = DiscoveryItem {
+                node_id,
+                provenance: "counting",
+                last_updated: None,
+                addr_info: AddrInfo {
+                    relay_url: None,
+                    direct_addresses: ["127.0.0.1:1234".parse().unwrap()].into(),
+                },
+                user_data: None,
+            };
+            Some(stream::once(Ok(item)).boxed())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_caching_discovery() -> TestResult {
+        let _logging_guard = iroh_test::logging::setup();
+        let ep = Endpoint::builder().bind().await?;
+        let node_id = SecretKey::generate().public();
+
+        let counting = CountingDiscovery::default();
+        let cache = CachingDiscovery::new(counting.clone()).positive_ttl(Duration::from_secs(60));
+        for _ in 0..3 {
+            let items: Vec<_> = cache.resolve(ep.clone(), node_id).unwrap().collect().await;
+            assert_eq!(items.len(), 1);
+            assert!(items[0].is_ok());
+        }
+        assert_eq!(counting.lookups.load(Ordering::Relaxed), 1);
+
+        cache.invalidate(node_id);
+        let items: Vec<_> = cache.resolve(ep.clone(), node_id).unwrap().collect().await;
+        assert_eq!(items.len(), 1);
+        assert_eq!(counting.lookups.load(Ordering::Relaxed), 2);
+
+        // Failed lookups are cached until the negative TTL expired.
+        let empty = CountingDiscovery {
+            empty: true,
+            ..Default::default()
+        };
+        let cache = CachingDiscovery::new(empty.clone()).negative_ttl(Duration::from_millis(200));
+        let items: Vec<_> = cache.resolve(ep.clone(), node_id).unwrap().collect().await;
+        assert!(items.is_empty());
+        let items: Vec<_> = cache.resolve(ep.clone(), node_id).unwrap().collect().await;
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_err());
+        assert_eq!(empty.lookups.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let items: Vec<_> = cache.resolve(ep.clone(), node_id).unwrap().collect().await;
+        assert!(items.is_empty());
+        assert_eq!(empty.lookups.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+}
@@ -5,7 +5,7 @@ use futures_lite::stream::Boxed as BoxStream;
 
 use crate::{
     discovery::{Discovery, DiscoveryItem},
-    dns::ResolverExt,
+    dns::lookup_node_info_by_id_staggered,
     endpoint::force_staging_infra,
     Endpoint, NodeId,
 };
@@ -32,11 +32,15 @@ const DNS_STAGGERING_MS: &[u64] = &[200, 300];
 /// If a TXT record contains multiple character strings, they are concatenated first.
 /// The supported attributes are:
 /// * `relay=<url>`: The URL of the home relay server of the node
+/// * `user-data=<data>`: The [`UserData`] of the node
 ///
 /// The DNS resolver defaults to using the nameservers configured on the host system, but can be changed
-/// with [`crate::endpoint::Builder::dns_resolver`].
+/// with [`crate::endpoint::Builder::dns_resolver`].  With the `encrypted-dns` feature, a resolver
+/// querying a nameserver using DNS-over-HTTPS or DNS-over-TLS can be created with
+/// `DnsResolver::with_https_nameserver` or `DnsResolver::with_tls_nameserver`.
 ///
 /// [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
+/// [`UserData`]: crate::discovery::UserData
 #[derive(Debug)]
 pub struct DnsDiscovery {
     origin_domain: String,
@@ -71,14 +75,20 @@ impl Discovery for DnsDiscovery {
         let resolver = ep.dns_resolver().clone();
         let origin_domain = self.origin_domain.clone();
         let fut = async move {
-            let node_addr = resolver
-                .lookup_by_id_staggered(&node_id, &origin_domain, DNS_STAGGERING_MS)
-                .await?;
+            let mut node_info = lookup_node_info_by_id_staggered(
+                &resolver,
+                &node_id,
+                &origin_domain,
+                DNS_STAGGERING_MS,
+            )
+            .await?;
+            let user_data = node_info.user_data.take();
             Ok(DiscoveryItem {
                 node_id,
                 provenance: "dns",
                 last_updated: None,
-                addr_info: node_addr.info,
+                addr_info: node_info.into(),
+                user_data,
             })
         };
         let stream = futures_lite::stream::once_future(fut);
@@ -0,0 +1,317 @@
+//! A discovery service which reads the addresses of nodes from a file.
+//!
+//! This is useful for fleets with a known topology which do not want to depend on any
+//! discovery infrastructure.  The file maps [`NodeId`]s to their relay URL and direct
+//! addresses.  It is written in TOML if the file name ends in `.toml`, or in JSON if it
+//! ends in `.json`:
+//!
+//! ```toml
+//! [nodes.ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6]
+//! relay_url = "https://relay.example.com./"
+//! direct_addresses = ["192.168.1.10:11204"]
+//! ```
+//!
+//! ```json
+//! {
+//!   "nodes": {
+//!     "ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6": {
+//!       "relay_url": "https://relay.example.com./",
+//!       "direct_addresses": ["192.168.1.10:11204"]
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! Both fields of a node are optional.  With [`FileDiscovery::reload_interval`] the file is
+//! checked for changes periodically and reloaded when it was modified.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use futures_lite::stream::{self, StreamExt};
+use iroh_base::{
+    key::NodeId,
+    node_addr::{AddrInfo, NodeAddr, RelayUrl},
+};
+use serde::{Deserialize, Serialize};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, warn};
+
+use super::{Discovery, DiscoveryItem};
+
+/// A discovery service which reads the addresses of nodes from a file.
+///
+/// See the [module docs](self) for the file format.
+#[derive(Debug, Clone)]
+pub struct FileDiscovery {
+    inner: Arc<Inner>,
+    _reload_task: Option<Arc<AbortOnDropHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    format: FileFormat,
+    state: RwLock<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    nodes: BTreeMap<NodeId, AddrInfo>,
+    modified: Option<SystemTime>,
+}
+
+/// The format of a [`FileDiscovery`] file, chosen by the file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileFormat {
+    Toml,
+    Json,
+}
+
+impl FileFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(Self::Toml),
+            Some("json") => Ok(Self::Json),
+            _ => bail!(
+                "unknown format of {}, expected a .toml or .json file",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// The contents of a [`FileDiscovery`] file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct NodesFile {
+    #[serde(default)]
+    nodes: BTreeMap<NodeId, NodeEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NodeEntry {
+    #[serde(default)]
+    relay_url: Option<RelayUrl>,
+    #[serde(default)]
+    direct_addresses: BTreeSet<SocketAddr>,
+}
+
+impl NodesFile {
+    fn parse(format: FileFormat, data: &str) -> Result<Self> {
+        let file = match format {
+            FileFormat::Toml => toml::from_str(data)?,
+            FileFormat::Json => serde_json::from_str(data)?,
+        };
+        Ok(file)
+    }
+}
+
+impl FileDiscovery {
+    /// The provenance string for this discovery implementation.
+    pub const PROVENANCE: &'static str = "file_discovery";
+
+    /// Loads the node addresses from the file at `path`.
+    ///
+    /// The file is read once, use [`FileDiscovery::reload`] or
+    /// [`FileDiscovery::reload_interval`] to pick up changes.
+    pub async fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let format = FileFormat::from_path(&path)?;
+        let inner = Arc::new(Inner {
+            path,
+            format,
+            state: Default::default(),
+        });
+        inner.reload().await?;
+        Ok(Self {
+            inner,
+            _reload_task: None,
+        })
+    }
+
+    /// Checks the file for changes every `interval` and reloads it when it was modified.
+    ///
+    /// A file which can not be read or parsed is logged, the previously loaded node
+    /// addresses are kept until the file is fixed.
+    ///
+    /// This spawns a task, so it must be called within a tokio runtime.
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        let inner = self.inner.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(err) = inner.reload_if_modified().await {
+                    warn!(path = %inner.path.display(), "failed to reload node addresses: {err:#}");
+                }
+            }
+        });
+        self._reload_task = Some(Arc::new(AbortOnDropHandle::new(task)));
+        self
+    }
+
+    /// Reads the file again, replacing all node addresses.
+    pub async fn reload(&self) -> Result<()> {
+        self.inner.reload().await
+    }
+
+    /// Returns the addresses of all nodes in the file.
+    pub fn node_addrs(&self) -> Vec<NodeAddr> {
+        let state = self.inner.state.read().expect("poisoned");
+        state
+            .nodes
+            .iter()
+            .map(|(node_id, info)| NodeAddr {
+                node_id: *node_id,
+                info: info.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Inner {
+    async fn reload(&self) -> Result<()> {
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|meta| meta.modified())
+            .ok();
+        let data = tokio::fs::read_to_string(&self.path)
+            .await
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        let file = NodesFile::parse(self.format, &data)
+            .with_context(|| format!("invalid node addresses file {}", self.path.display()))?;
+        let nodes: BTreeMap<_, _> = file
+            .nodes
+            .into_iter()
+            .map(|(node_id, entry)| {
+                let info = AddrInfo {
+                    relay_url: entry.relay_url,
+                    direct_addresses: entry.direct_addresses,
+                };
+                (node_id, info)
+            })
+            .collect();
+        debug!(path = %self.path.display(), count = nodes.len(), "loaded node addresses");
+        let mut state = self.state.write().expect("poisoned");
+        state.nodes = nodes;
+        state.modified = modified;
+        Ok(())
+    }
+
+    async fn reload_if_modified(&self) -> Result<()> {
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|meta| meta.modified())
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        let loaded = self.state.read().expect("poisoned").modified;
+        if loaded == Some(modified) {
+            return Ok(());
+        }
+        self.reload().await
+    }
+}
+
+impl Discovery for FileDiscovery {
+    fn resolve(
+        &self,
+        _endpoint: crate::Endpoint,
+        node_id: NodeId,
+    ) -> Option<futures_lite::stream::Boxed<Result<DiscoveryItem>>> {
+        let state = self.inner.state.read().expect("poisoned");
+        let info = state.nodes.get(&node_id)?;
+        let last_updated = state.modified.and_then(|modified| {
+            modified
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_micros() as u64)
+        });
+        let item = DiscoveryItem {
+            node_id,
+            provenance: Self::PROVENANCE,
+            last_updated,
+            addr_info: info.clone(),
+            user_data: None,
+        };
+        Some(stream::iter(Some(Ok(item))).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::key::SecretKey;
+    use testresult::TestResult;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_discovery() -> TestResult {
+        let _logging_guard = iroh_test::logging::setup();
+        let dir =
+            std::env::temp_dir().join(format!("iroh-file-discovery-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let node_a = SecretKey::generate().public();
+        let node_b = SecretKey::generate().public();
+        let toml_path = dir.join("nodes.toml");
+        let toml = format!(
+            "[nodes.{node_a}]\n\
+             relay_url = \"https://relay.example.com./\"\n\
+             direct_addresses = [\"127.0.0.1:1234\"]\n\
+             \n\
+             [nodes.{node_b}]\n\
+             direct_addresses = [\"127.0.0.1:5678\"]\n"
+        );
+        tokio::fs::write(&toml_path, toml).await?;
+        let discovery = FileDiscovery::load(&toml_path).await?;
+        let addrs = discovery.node_addrs();
+        assert_eq!(addrs.len(), 2);
+        let addr_a = addrs.iter().find(|addr| addr.node_id == node_a).unwrap();
+        assert_eq!(
+            addr_a.relay_url(),
+            Some(&"https://relay.example.com./".parse()?)
+        );
+        assert_eq!(
+            addr_a.direct_addresses().collect::<Vec<_>>(),
+            vec![&"127.0.0.1:1234".parse::<SocketAddr>()?]
+        );
+
+        let ep = crate::Endpoint::builder().bind().await?;
+        let mut stream = discovery.resolve(ep.clone(), node_b).unwrap();
+        let item = stream.next().await.unwrap()?;
+        assert_eq!(item.provenance, FileDiscovery::PROVENANCE);
+        assert_eq!(item.addr_info.relay_url, None);
+        let unknown = SecretKey::generate().public();
+        assert!(discovery.resolve(ep.clone(), unknown).is_none());
+
+        // The same nodes in JSON, reloaded after a change.
+        let json_path = dir.join("nodes.json");
+        let json = format!(r#"{{"nodes": {{"{node_a}": {{}}}}}}"#);
+        tokio::fs::write(&json_path, json).await?;
+        let discovery = FileDiscovery::load(&json_path)
+            .await?
+            .reload_interval(Duration::from_millis(50));
+        assert_eq!(discovery.node_addrs().len(), 1);
+        assert_eq!(discovery.node_addrs()[0].info, AddrInfo::default());
+
+        let json = format!(r#"{{"nodes": {{"{node_a}": {{}}, "{node_b}": {{}}}}}}"#);
+        // Make sure the modification time changes on file systems with coarse timestamps.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        tokio::fs::write(&json_path, json).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while discovery.node_addrs().len() != 2 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await?;
+
+        assert!(FileDiscovery::load(dir.join("nodes.yaml")).await.is_err());
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,433 @@
+//! Peer exchange: learning the addresses of nodes from connected nodes.
+//!
+//! With [`PeerExchange`] nodes share the addresses of the other nodes they know, so a mesh
+//! can bootstrap from a single seed node without any central discovery service.  Every node
+//! signs its own addresses, as a [pkarr] signed packet like the one published by the
+//! [`PkarrPublisher`].  Nodes pass on these signed records unchanged, so a node can not
+//! forge the addresses of another node.
+//!
+//! [`PeerExchange`] is both a [`Discovery`] service and a [`ProtocolHandler`] for the
+//! [`ALPN`] protocol.  When exchanging records with a node, either by calling
+//! [`PeerExchange::exchange`] or when a node connects to us, both nodes send all records
+//! they know which the [`PexPolicy`] allows to share.  The received records are verified,
+//! filtered by the [`PexPolicy`] and added to the address book of the endpoint.
+//!
+//! ```no_run
+//! use iroh::{
+//!     discovery::pex::{self, PeerExchange},
+//!     key::SecretKey,
+//!     protocol::Router,
+//!     Endpoint, NodeAddr,
+//! };
+//!
+//! # async fn wrapper(seed: NodeAddr) -> anyhow::Result<()> {
+//! let secret_key = SecretKey::generate();
+//! let pex = PeerExchange::new(secret_key.clone());
+//! let endpoint = Endpoint::builder()
+//!     .secret_key(secret_key)
+//!     .add_discovery({
+//!         let pex = pex.clone();
+//!         move |_| Some(pex)
+//!     })
+//!     .bind()
+//!     .await?;
+//! let router = Router::builder(endpoint.clone())
+//!     .accept(pex::ALPN, pex.clone())
+//!     .spawn()
+//!     .await?;
+//!
+//! // Learn the nodes known to the seed node.
+//! pex.exchange(&endpoint, seed).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [pkarr]: https://pkarr.org
+//! [`PkarrPublisher`]: super::pkarr::PkarrPublisher
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{ensure, Context, Result};
+use bytes::Bytes;
+use futures_lite::future::Boxed as BoxedFuture;
+use pkarr::SignedPacket;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::{pkarr::DEFAULT_PKARR_TTL, Discovery, DiscoveryItem, UserData};
+use crate::{
+    dns::node_info::NodeInfo,
+    endpoint::{get_remote_node_id, Connecting},
+    key::SecretKey,
+    protocol::ProtocolHandler,
+    AddrInfo, Endpoint, NodeAddr, NodeId,
+};
+
+/// The ALPN of the peer exchange protocol.
+pub const ALPN: &[u8] = b"/iroh/pex/0";
+
+/// The default maximum number of records a [`PeerExchange`] keeps.
+pub const DEFAULT_MAX_RECORDS: usize = 256;
+
+/// The maximum size of a single signed record, the length prefix is 2 bytes.
+const MAX_RECORD_SIZE: usize = 32 + 2 + u16::MAX as usize;
+
+/// Decides which records are shared with and accepted from other nodes.
+///
+/// The policy is set with [`PeerExchange::policy`], by default all records are shared and
+/// accepted.
+pub trait PexPolicy: std::fmt::Debug + Send + Sync + 'static {
+    /// Returns whether to send the record of `node` to `peer`.
+    fn share(&self, _peer: NodeId, _node: &NodeAddr) -> bool {
+        true
+    }
+
+    /// Returns whether to accept the record of `node` received from `peer`.
+    fn accept(&self, _peer: NodeId, _node: &NodeAddr) -> bool {
+        true
+    }
+}
+
+/// The default [`PexPolicy`], sharing and accepting all records.
+#[derive(Debug)]
+struct ShareAll;
+
+impl PexPolicy for ShareAll {}
+
+/// Shares signed node records with other nodes.
+///
+/// See the [module docs](self) for details.
+#[derive(derive_more::Debug, Clone)]
+pub struct PeerExchange {
+    #[debug("SecretKey")]
+    secret_key: SecretKey,
+    policy: Arc<dyn PexPolicy>,
+    max_records: usize,
+    state: Arc<parking_lot::Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Our own signed record, once the endpoint published its addresses.
+    own: Option<SignedPacket>,
+    /// The verified records of other nodes.
+    records: BTreeMap<NodeId, SignedPacket>,
+    subscribers: Vec<mpsc::Sender<DiscoveryItem>>,
+}
+
+impl PeerExchange {
+    /// The provenance string for this discovery implementation.
+    pub const PROVENANCE: &'static str = "pex";
+
+    /// Creates a peer exchange for the endpoint with the [`SecretKey`].
+    ///
+    /// The secret key signs the record of our own addresses.
+    pub fn new(secret_key: SecretKey) -> Self {
+        Self {
+            secret_key,
+            policy: Arc::new(ShareAll),
+            max_records: DEFAULT_MAX_RECORDS,
+            state: Default::default(),
+        }
+    }
+
+    /// Sets the [`PexPolicy`] deciding which records are shared and accepted.
+    pub fn policy(mut self, policy: impl PexPolicy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Sets how many records of other nodes are kept, by default [`DEFAULT_MAX_RECORDS`].
+    ///
+    /// When the limit is reached the oldest records are dropped first.  This also limits
+    /// the number of records accepted from a single node.
+    pub fn max_records(mut self, max_records: usize) -> Self {
+        self.max_records = max_records;
+        self
+    }
+
+    /// Returns the addresses of all nodes learned from other nodes.
+    pub fn node_addrs(&self) -> Vec<NodeAddr> {
+        let state = self.state.lock();
+        state
+            .records
+            .values()
+            .filter_map(|packet| NodeInfo::from_pkarr_signed_packet(packet).ok())
+            .map(NodeAddr::from)
+            .collect()
+    }
+
+    /// Exchanges records with the node at `node_addr`.
+    ///
+    /// Returns the number of new or updated records received.
+    pub async fn exchange(
+        &self,
+        endpoint: &Endpoint,
+        node_addr: impl Into<NodeAddr>,
+    ) -> Result<usize> {
+        let node_addr = node_addr.into();
+        let peer = node_addr.node_id;
+        let connection = endpoint.connect(node_addr, ALPN).await?;
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(&self.encode_records(peer)).await?;
+        send.finish()?;
+        let data = recv
+            .read_to_end(self.max_message_size())
+            .await
+            .context("failed to read records")?;
+        let updated = self.receive_records(peer, data.into())?;
+        connection.close(0u32.into(), b"done");
+        Ok(updated)
+    }
+
+    fn max_message_size(&self) -> usize {
+        // Our own record is sent in addition to the records of other nodes.
+        (self.max_records + 1) * MAX_RECORD_SIZE
+    }
+
+    /// Encodes the records to send to `peer`.
+    ///
+    /// Each record is prefixed by the public key of the node and the length of the signed
+    /// packet as a big-endian `u16`.
+    fn encode_records(&self, peer: NodeId) -> Vec<u8> {
+        let state = self.state.lock();
+        let mut buf = Vec::new();
+        for packet in state.own.iter().chain(state.records.values()) {
+            let Ok(info) = NodeInfo::from_pkarr_signed_packet(packet) else {
+                continue;
+            };
+            if info.node_id == peer || !self.policy.share(peer, &info.into()) {
+                continue;
+            }
+            let payload = packet.to_relay_payload();
+            buf.extend_from_slice(&packet.public_key().to_bytes());
+            buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&payload);
+        }
+        buf
+    }
+
+    /// Verifies and stores the records received from `peer`.
+    ///
+    /// Returns the number of new or updated records.
+    fn receive_records(&self, peer: NodeId, data: Bytes) -> Result<usize> {
+        let me = self.secret_key.public();
+        let mut updated = 0;
+        let mut pos = 0;
+        while pos < data.len() {
+            ensure!(data.len() - pos >= 34, "truncated record");
+            let key: [u8; 32] = data[pos..pos + 32].try_into().expect("checked length");
+            let len = u16::from_be_bytes([data[pos + 32], data[pos + 33]]) as usize;
+            pos += 34;
+            ensure!(data.len() - pos >= len, "truncated record");
+            let payload = data.slice(pos..pos + len);
+            pos += len;
+            let packet = match pkarr::PublicKey::try_from(&key)
+                .map_err(anyhow::Error::from)
+                .and_then(|key| Ok(SignedPacket::from_relay_payload(&key, &payload)?))
+            {
+                Ok(packet) => packet,
+                Err(err) => {
+                    warn!(peer = %peer.fmt_short(), "invalid record: {err:#}");
+                    continue;
+                }
+            };
+            let Ok(info) = NodeInfo::from_pkarr_signed_packet(&packet) else {
+                continue;
+            };
+            let node_id = info.node_id;
+            if node_id == me || !self.policy.accept(peer, &info.clone().into()) {
+                continue;
+            }
+            if self.insert(node_id, packet, info) {
+                updated += 1;
+            }
+        }
+        debug!(peer = %peer.fmt_short(), %updated, "received records");
+        Ok(updated)
+    }
+
+    /// Stores a record unless a more recent one is known, notifying the subscribers.
+    fn insert(&self, node_id: NodeId, packet: SignedPacket, mut info: NodeInfo) -> bool {
+        let mut state = self.state.lock();
+        if let Some(existing) = state.records.get(&node_id) {
+            if !packet.more_recent_than(existing) {
+                return false;
+            }
+        } else if state.records.len() >= self.max_records {
+            let oldest = state
+                .records
+                .iter()
+                .min_by_key(|(_, packet)| packet.timestamp())
+                .map(|(node_id, packet)| (*node_id, packet.timestamp()));
+            match oldest {
+                Some((oldest, timestamp)) if timestamp < packet.timestamp() => {
+                    state.records.remove(&oldest);
+                }
+                _ => return false,
+            }
+        }
+        let item = DiscoveryItem {
+            node_id,
+            provenance: Self::PROVENANCE,
+            last_updated: Some(packet.timestamp()),
+            user_data: info.user_data.take(),
+            addr_info: info.into(),
+        };
+        state.records.insert(node_id, packet);
+        state
+            .subscribers
+            .retain(|sender| match sender.try_send(item.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => true,
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+        true
+    }
+}
+
+impl Discovery for PeerExchange {
+    fn publish(&self, info: &AddrInfo) {
+        self.publish_with_user_data(info, None)
+    }
+
+    fn publish_with_user_data(&self, info: &AddrInfo, user_data: Option<&UserData>) {
+        let info = NodeInfo::new(
+            self.secret_key.public(),
+            info.relay_url.clone().map(Into::into),
+            info.direct_addresses.clone(),
+        )
+        .with_user_data(user_data.cloned());
+        match info.to_pkarr_signed_packet(&self.secret_key, DEFAULT_PKARR_TTL) {
+            Ok(packet) => self.state.lock().own = Some(packet),
+            Err(err) => warn!("failed to sign own record: {err:#}"),
+        }
+    }
+
+    fn resolve(
+        &self,
+        _endpoint: Endpoint,
+        node_id: NodeId,
+    ) -> Option<futures_lite::stream::Boxed<Result<DiscoveryItem>>> {
+        let state = self.state.lock();
+        let packet = state.records.get(&node_id)?;
+        let mut info = NodeInfo::from_pkarr_signed_packet(packet).ok()?;
+        let item = DiscoveryItem {
+            node_id,
+            provenance: Self::PROVENANCE,
+            last_updated: Some(packet.timestamp()),
+            user_data: info.user_data.take(),
+            addr_info: info.into(),
+        };
+        Some(Box::pin(futures_lite::stream::once(Ok(item))))
+    }
+
+    fn subscribe(&self) -> Option<futures_lite::stream::Boxed<DiscoveryItem>> {
+        let (sender, receiver) = mpsc::channel(32);
+        self.state.lock().subscribers.push(sender);
+        Some(Box::pin(tokio_stream::wrappers::ReceiverStream::new(
+            receiver,
+        )))
+    }
+}
+
+impl ProtocolHandler for PeerExchange {
+    fn accept(&self, conn: Connecting) -> BoxedFuture<Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let connection = conn.await?;
+            let peer = get_remote_node_id(&connection)?;
+            let (mut send, mut recv) = connection.accept_bi().await?;
+            let data = recv
+                .read_to_end(this.max_message_size())
+                .await
+                .context("failed to read records")?;
+            this.receive_records(peer, data.into())?;
+            send.write_all(&this.encode_records(peer)).await?;
+            send.finish()?;
+            connection.closed().await;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_lite::StreamExt;
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::{protocol::Router, RelayMode};
+
+    async fn pex_node(
+        pex: impl FnOnce(SecretKey) -> PeerExchange,
+    ) -> Result<(Endpoint, PeerExchange, Router)> {
+        let secret_key = SecretKey::generate();
+        let pex = pex(secret_key.clone());
+        let endpoint = Endpoint::builder()
+            .secret_key(secret_key)
+            .relay_mode(RelayMode::Disabled)
+            .add_discovery({
+                let pex = pex.clone();
+                move |_| Some(pex)
+            })
+            .bind()
+            .await?;
+        let router = Router::builder(endpoint.clone())
+            .accept(ALPN, pex.clone())
+            .spawn()
+            .await?;
+        // Make sure the own record is published.
+        endpoint.node_addr().await?;
+        Ok((endpoint, pex, router))
+    }
+
+    #[derive(Debug)]
+    struct Deny(NodeId);
+
+    impl PexPolicy for Deny {
+        fn share(&self, _peer: NodeId, node: &NodeAddr) -> bool {
+            node.node_id != self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_exchange() -> TestResult {
+        let _logging_guard = iroh_test::logging::setup();
+        let (seed, seed_pex, _seed_router) = pex_node(PeerExchange::new).await?;
+        let (ep_a, pex_a, _router_a) = pex_node(PeerExchange::new).await?;
+        let (ep_b, pex_b, _router_b) = pex_node(PeerExchange::new).await?;
+        let seed_addr = seed.node_addr().await?;
+
+        // a learns the record of the seed, the seed learns the records of a and b.
+        assert_eq!(pex_a.exchange(&ep_a, seed_addr.clone()).await?, 1);
+        let mut subscription = pex_b.subscribe().unwrap();
+        assert_eq!(pex_b.exchange(&ep_b, seed_addr.clone()).await?, 2);
+        assert_eq!(seed_pex.node_addrs().len(), 2);
+
+        // b learned the record of a from the seed and can connect to it by node id.
+        let items: Vec<_> = tokio::time::timeout(
+            Duration::from_secs(5),
+            subscription.as_mut().take(2).collect(),
+        )
+        .await?;
+        assert!(items.iter().any(|item| item.node_id == ep_a.node_id()));
+        assert!(items
+            .iter()
+            .all(|item| item.provenance == PeerExchange::PROVENANCE));
+        pex_b.exchange(&ep_b, ep_a.node_id()).await?;
+
+        // The policy of the other seed does not share the record of a.
+        let a = ep_a.node_id();
+        let (seed2, _seed2_pex, _seed2_router) =
+            pex_node(|secret_key| PeerExchange::new(secret_key).policy(Deny(a))).await?;
+        let seed2_addr = seed2.node_addr().await?;
+        pex_a.exchange(&ep_a, seed2_addr.clone()).await?;
+        let (ep_c, pex_c, _router_c) = pex_node(PeerExchange::new).await?;
+        assert_eq!(pex_c.exchange(&ep_c, seed2_addr).await?, 1);
+        assert!(pex_c.node_addrs().iter().all(|addr| addr.node_id != a));
+        Ok(())
+    }
+}
@@ -35,6 +35,9 @@
 //! - [`DhtDiscovery`], which resolves and publishes from both pkarr relay servers and well
 //!   as the Mainline DHT.
 //!
+//! Nodes can also announce [topics](topic) with the [`PkarrPublisher`], which allows
+//! finding all nodes announcing a topic with a [`TopicResolver`].
+//!
 //! [pkarr]: https://pkarr.org
 //! [DNS Resource Records]: https://en.wikipedia.org/wiki/Domain_Name_System#Resource_records
 //! [Mainline DHT]: https://en.wikipedia.org/wiki/Mainline_DHT
@@ -43,8 +46,9 @@
 //! [`NodeId`]: crate::key::NodeId
 //! [`DnsDiscovery`]: crate::discovery::dns::DnsDiscovery
 //! [`DhtDiscovery`]: dht::DhtDiscovery
+//! [`TopicResolver`]: topic::TopicResolver
 
-use std::sync::Arc;
+use std::{collections::BTreeSet, sync::Arc};
 
 use anyhow::{anyhow, bail, Result};
 use futures_util::stream::BoxStream;
@@ -58,7 +62,7 @@ use url::Url;
 use watchable::{Watchable, Watcher};
 
 use crate::{
-    discovery::{Discovery, DiscoveryItem},
+    discovery::{Discovery, DiscoveryItem, UserData},
     dns::node_info::NodeInfo,
     endpoint::force_staging_infra,
     key::SecretKey,
@@ -68,6 +72,9 @@ use crate::{
 #[cfg(feature = "discovery-pkarr-dht")]
 #[cfg_attr(iroh_docsrs, doc(cfg(feature = "discovery-pkarr-dht")))]
 pub mod dht;
+pub mod topic;
+
+use self::topic::Topic;
 
 /// The production pkarr relay run by [number 0].
 ///
@@ -118,7 +125,9 @@ pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 5);
 #[derive(derive_more::Debug, Clone)]
 pub struct PkarrPublisher {
     node_id: NodeId,
+    topics: BTreeSet<Topic>,
     watchable: Watchable<Option<NodeInfo>>,
+    paused: Watchable<bool>,
     join_handle: Arc<JoinHandle<()>>,
 }
 
@@ -132,12 +141,15 @@ impl PkarrPublisher {
     ///
     /// [pkarr]: https://pkarr.org
     pub fn new(secret_key: SecretKey, pkarr_relay: Url) -> Self {
-        Self::with_options(
-            secret_key,
-            pkarr_relay,
-            DEFAULT_PKARR_TTL,
-            DEFAULT_REPUBLISH_INTERVAL,
-        )
+        Self::builder(pkarr_relay).build(secret_key)
+    }
+
+    /// Creates a [`PkarrPublisherBuilder`] publishing to the pkarr relay server at the URL.
+    ///
+    /// The builder allows to publish to more pkarr relay servers and to configure how often
+    /// the node info is published.
+    pub fn builder(pkarr_relay: Url) -> PkarrPublisherBuilder {
+        PkarrPublisherBuilder::new(pkarr_relay)
     }
 
     /// Creates a new [`PkarrPublisher`] with a custom TTL and republish intervals.
@@ -150,16 +162,33 @@ impl PkarrPublisher {
         ttl: u32,
         republish_interval: std::time::Duration,
     ) -> Self {
-        debug!("creating pkarr publisher that publishes to {pkarr_relay}");
+        Self::builder(pkarr_relay)
+            .ttl(ttl)
+            .republish_interval(republish_interval)
+            .build(secret_key)
+    }
+
+    fn spawn(builder: PkarrPublisherBuilder, secret_key: SecretKey) -> Self {
+        debug!(
+            "creating pkarr publisher that publishes to {:?}",
+            builder.pkarr_relays
+        );
         let node_id = secret_key.public();
-        let pkarr_client = PkarrRelayClient::new(pkarr_relay);
+        let pkarr_clients = builder
+            .pkarr_relays
+            .into_iter()
+            .map(PkarrRelayClient::new)
+            .collect();
         let watchable = Watchable::default();
+        let paused = Watchable::new(false);
         let service = PublisherService {
-            ttl,
+            ttl: builder.ttl,
             watcher: watchable.watch(),
+            paused: paused.watch(),
             secret_key,
-            pkarr_client,
-            republish_interval,
+            pkarr_clients,
+            republish_interval: builder.republish_interval,
+            republish_on_change: builder.republish_on_change,
         };
         let join_handle = tokio::task::spawn(
             service
@@ -168,7 +197,9 @@ impl PkarrPublisher {
         );
         Self {
             watchable,
+            paused,
             node_id,
+            topics: builder.topics,
             join_handle: Arc::new(join_handle),
         }
     }
@@ -197,12 +228,18 @@ impl PkarrPublisher {
     ///
     /// This is a nonblocking function, the actual update is performed in the background.
     pub fn update_addr_info(&self, info: &AddrInfo) {
+        self.update_node_info(info, None)
+    }
+
+    fn update_node_info(&self, info: &AddrInfo, user_data: Option<&UserData>) {
         let (relay_url, direct_addresses) = if let Some(relay_url) = info.relay_url.as_ref() {
             (Some(relay_url.clone().into()), Default::default())
         } else {
             (None, info.direct_addresses.clone())
         };
-        let info = NodeInfo::new(self.node_id, relay_url, direct_addresses);
+        let info = NodeInfo::new(self.node_id, relay_url, direct_addresses)
+            .with_user_data(user_data.cloned())
+            .with_topics(self.topics.clone());
         self.watchable.update(Some(info)).ok();
     }
 }
@@ -211,6 +248,14 @@ impl Discovery for PkarrPublisher {
     fn publish(&self, info: &AddrInfo) {
         self.update_addr_info(info);
     }
+
+    fn publish_with_user_data(&self, info: &AddrInfo, user_data: Option<&UserData>) {
+        self.update_node_info(info, user_data);
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.paused.update(paused).ok();
+    }
 }
 
 impl Drop for PkarrPublisher {
@@ -222,25 +267,115 @@ impl Drop for PkarrPublisher {
     }
 }
 
-/// Publish node info to a pkarr relay.
+/// Builder for [`PkarrPublisher`].
+///
+/// Created with [`PkarrPublisher::builder`].
+#[derive(Debug, Clone)]
+pub struct PkarrPublisherBuilder {
+    pkarr_relays: Vec<Url>,
+    ttl: u32,
+    republish_interval: Duration,
+    republish_on_change: bool,
+    topics: BTreeSet<Topic>,
+}
+
+impl PkarrPublisherBuilder {
+    fn new(pkarr_relay: Url) -> Self {
+        Self {
+            pkarr_relays: vec![pkarr_relay],
+            ttl: DEFAULT_PKARR_TTL,
+            republish_interval: DEFAULT_REPUBLISH_INTERVAL,
+            republish_on_change: true,
+            topics: Default::default(),
+        }
+    }
+
+    /// Adds another pkarr relay server to publish to.
+    ///
+    /// The node info is published to all pkarr relay servers concurrently.  Publishing is
+    /// retried only if it failed on all of them.
+    pub fn add_pkarr_relay(mut self, pkarr_relay: Url) -> Self {
+        if !self.pkarr_relays.contains(&pkarr_relay) {
+            self.pkarr_relays.push(pkarr_relay);
+        }
+        self
+    }
+
+    /// Sets the time-to-live value of the published records, by default
+    /// [`DEFAULT_PKARR_TTL`].
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Sets the interval in which the node info is republished even if unchanged, by
+    /// default [`DEFAULT_REPUBLISH_INTERVAL`].
+    pub fn republish_interval(mut self, republish_interval: Duration) -> Self {
+        self.republish_interval = republish_interval;
+        self
+    }
+
+    /// Sets whether to publish right away when the node info changes.
+    ///
+    /// When disabled, changes are only published with the next periodic republish, which
+    /// reduces the load on the pkarr relay servers for nodes whose addresses change often.
+    /// The first node info is always published right away.
+    ///
+    /// Enabled by default.
+    pub fn republish_on_change(mut self, republish_on_change: bool) -> Self {
+        self.republish_on_change = republish_on_change;
+        self
+    }
+
+    /// Adds a [`Topic`] to announce with the node info.
+    ///
+    /// Pkarr relay servers which index topics, such as `iroh-dns-server`, return this node
+    /// when resolving the topic with a [`TopicResolver`](topic::TopicResolver).  The
+    /// topics count towards the 1000 bytes limit of the signed packet, so only a few
+    /// topics can be announced.
+    pub fn add_topic(mut self, topic: Topic) -> Self {
+        self.topics.insert(topic);
+        self
+    }
+
+    /// Builds the [`PkarrPublisher`] publishing the records of the [`SecretKey`].
+    ///
+    /// This spawns the publishing task, so it must be called within a tokio runtime.
+    pub fn build(self, secret_key: SecretKey) -> PkarrPublisher {
+        PkarrPublisher::spawn(self, secret_key)
+    }
+}
+
+/// Publish node info to pkarr relays.
 #[derive(derive_more::Debug, Clone)]
 struct PublisherService {
     #[debug("SecretKey")]
     secret_key: SecretKey,
-    #[debug("PkarrClient")]
-    pkarr_client: PkarrRelayClient,
+    #[debug("Vec<PkarrClient>")]
+    pkarr_clients: Vec<PkarrRelayClient>,
     watcher: Watcher<Option<NodeInfo>>,
+    /// Whether publishing is paused, see [`Discovery::set_paused`].
+    paused: Watcher<bool>,
     ttl: u32,
     republish_interval: Duration,
+    republish_on_change: bool,
 }
 
 impl PublisherService {
     async fn run(self) {
         let mut failed_attempts = 0;
+        let mut published = false;
         let republish = tokio::time::sleep(Duration::MAX);
         tokio::pin!(republish);
         loop {
+            // Neither retries nor the periodic republish run while paused.
+            while self.paused.get() {
+                if self.paused.watch_async().await.is_err() {
+                    return;
+                }
+            }
             if let Some(info) = self.watcher.get() {
+                published = true;
                 if let Err(err) = self.publish_current(info).await {
                     failed_attempts += 1;
                     // Retry after increasing timeout
@@ -248,7 +383,6 @@ impl PublisherService {
                     republish.as_mut().reset(Instant::now() + retry_after);
                     warn!(
                         err = %format!("{err:#}"),
-                        url = %self.pkarr_client.pkarr_relay_url ,
                         ?retry_after,
                         %failed_attempts,
                         "Failed to publish to pkarr",
@@ -262,28 +396,60 @@ impl PublisherService {
                 }
             }
             // Wait until either the retry/republish timeout is reached, or the node info changed.
-            tokio::select! {
-                res = self.watcher.watch_async() => match res {
-                    Ok(()) => debug!("Publish node info to pkarr (info changed)"),
-                    Err(_disconnected) => break,
-                },
-                _ = &mut republish => debug!("Publish node info to pkarr (interval elapsed)"),
+            loop {
+                tokio::select! {
+                    res = self.watcher.watch_async() => match res {
+                        Ok(()) if self.republish_on_change || !published => {
+                            debug!("Publish node info to pkarr (info changed)");
+                            break;
+                        }
+                        Ok(()) => debug!("Node info changed, publishing with the next republish"),
+                        Err(_disconnected) => return,
+                    },
+                    _ = &mut republish => {
+                        debug!("Publish node info to pkarr (interval elapsed)");
+                        break;
+                    }
+                }
             }
         }
     }
 
+    /// Publishes to all pkarr relays, succeeds if publishing to any of them succeeded.
     async fn publish_current(&self, info: NodeInfo) -> Result<()> {
         info!(
             relay_url = ?info
                 .relay_url
                 .as_ref()
                 .map(|s| s.as_str()),
-            pkarr_relay = %self.pkarr_client.pkarr_relay_url,
             "Publish node info to pkarr"
         );
         let signed_packet = info.to_pkarr_signed_packet(&self.secret_key, self.ttl)?;
-        self.pkarr_client.publish(&signed_packet).await?;
-        Ok(())
+        let results = futures_buffered::join_all(
+            self.pkarr_clients
+                .iter()
+                .map(|client| client.publish(&signed_packet)),
+        )
+        .await;
+        let mut last_err = None;
+        let mut published = false;
+        for (client, res) in self.pkarr_clients.iter().zip(results) {
+            let url = &client.pkarr_relay_url;
+            match res {
+                Ok(()) => {
+                    debug!(pkarr_relay = %url, "Published node info");
+                    published = true;
+                }
+                Err(err) => {
+                    warn!(pkarr_relay = %url, "Failed to publish node info: {err:#}");
+                    last_err = Some(err.context(format!("pkarr relay {url}")));
+                }
+            }
+        }
+        match last_err {
+            Some(err) if !published => Err(err),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -342,12 +508,14 @@ impl Discovery for PkarrResolver {
         let pkarr_client = self.pkarr_client.clone();
         let fut = async move {
             let signed_packet = pkarr_client.resolve(node_id).await?;
-            let info = NodeInfo::from_pkarr_signed_packet(&signed_packet)?;
+            let mut info = NodeInfo::from_pkarr_signed_packet(&signed_packet)?;
+            let user_data = info.user_data.take();
             let item = DiscoveryItem {
                 node_id,
                 provenance: "pkarr",
-                last_updated: None,
+                last_updated: Some(signed_packet.timestamp()),
                 addr_info: info.into(),
+                user_data,
             };
             Ok(item)
         };
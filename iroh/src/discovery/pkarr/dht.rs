@@ -22,7 +22,7 @@ use url::Url;
 use crate::{
     discovery::{
         pkarr::{DEFAULT_PKARR_TTL, N0_DNS_PKARR_RELAY_PROD},
-        Discovery, DiscoveryItem,
+        Discovery, DiscoveryItem, UserData,
     },
     dns::node_info::NodeInfo,
     key::SecretKey,
@@ -289,15 +289,17 @@ impl DhtDiscovery {
         let response = relay.resolve(&pkarr_public_key).await;
         match response {
             Ok(Some(signed_packet)) => {
-                if let Ok(node_info) = NodeInfo::from_pkarr_signed_packet(&signed_packet) {
+                if let Ok(mut node_info) = NodeInfo::from_pkarr_signed_packet(&signed_packet) {
                     let node_id = node_info.node_id;
+                    let user_data = node_info.user_data.take();
                     let addr_info = node_info.into();
                     tracing::info!("discovered node info from relay {:?}", addr_info);
                     co.yield_(Ok(DiscoveryItem {
                         node_id,
                         provenance: "relay",
-                        last_updated: None,
+                        last_updated: Some(signed_packet.timestamp()),
                         addr_info,
+                        user_data,
                     }))
                     .await;
                 } else {
@@ -335,15 +337,17 @@ impl DhtDiscovery {
             tracing::debug!("no signed packet found in DHT");
             return;
         };
-        if let Ok(node_info) = NodeInfo::from_pkarr_signed_packet(&signed_packet) {
+        if let Ok(mut node_info) = NodeInfo::from_pkarr_signed_packet(&signed_packet) {
             let node_id = node_info.node_id;
+            let user_data = node_info.user_data.take();
             let addr_info = node_info.into();
             tracing::info!("discovered node info from DHT {:?}", addr_info);
             co.yield_(Ok(DiscoveryItem {
                 node_id,
                 provenance: "mainline",
-                last_updated: None,
+                last_updated: Some(signed_packet.timestamp()),
                 addr_info,
+                user_data,
             }))
             .await;
         } else {
@@ -363,6 +367,10 @@ impl DhtDiscovery {
 
 impl Discovery for DhtDiscovery {
     fn publish(&self, info: &AddrInfo) {
+        self.publish_with_user_data(info, None)
+    }
+
+    fn publish_with_user_data(&self, info: &AddrInfo, user_data: Option<&UserData>) {
         let Some(keypair) = &self.0.secret_key else {
             tracing::debug!("no keypair set, not publishing");
             return;
@@ -376,6 +384,8 @@ impl Discovery for DhtDiscovery {
             } else {
                 Default::default()
             },
+            user_data: user_data.cloned(),
+            topics: Default::default(),
         };
         let Ok(signed_packet) = info.to_pkarr_signed_packet(keypair, self.0.ttl) else {
             tracing::warn!("failed to create signed packet");
@@ -398,6 +408,13 @@ impl Discovery for DhtDiscovery {
         tracing::info!("resolving {} as {}", node_id, pkarr_public_key.to_z32());
         Some(Gen::new(|co| async move { this.gen_resolve(node_id, co).await }).boxed())
     }
+
+    fn set_paused(&self, paused: bool) {
+        // The endpoint publishes again when resuming, which restarts the loop.
+        if paused {
+            self.0.task.lock().unwrap().take();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,279 @@
+//! Finding nodes by topic instead of by [`NodeId`].
+//!
+//! A [`Topic`] is an application-chosen 32 byte key, e.g. the hash of the name of a swarm
+//! or service.  Nodes announce topics by adding them to their published node info with
+//! [`PkarrPublisherBuilder::add_topic`].  A pkarr relay server which indexes topics, such
+//! as `iroh-dns-server`, can then list all nodes announcing a topic, which is done with a
+//! [`TopicResolver`].  This allows a swarm to find its members without a separate tracker
+//! service.
+//!
+//! The nodes are listed in pages, ordered by [`NodeId`].  The pkarr relay server only
+//! returns the signed packets published by the nodes, so it can not forge node info: the
+//! [`TopicResolver`] verifies the signature of each packet and checks the node still
+//! announces the topic.  It can however leave out nodes.
+//!
+//! # Protocol
+//!
+//! The nodes are listed with a HTTP GET request to `<pkarr-relay>/topic/<topic>`, with the
+//! topic in [z-base-32] encoding.  The optional `limit` query parameter sets the maximum
+//! number of nodes in the response, the optional `cursor` query parameter continues a
+//! previous listing.
+//!
+//! The response body is a sequence of entries, each consisting of the 32 byte public key
+//! of the node, the length of the signed packet as a big-endian `u16`, and the signed
+//! packet in the encoding of the pkarr relay protocol.  The cursor to request the next page
+//! is returned in the [`TOPIC_CURSOR_HEADER`] header, it is missing on the last page.
+//!
+//! [`NodeId`]: crate::NodeId
+//! [`PkarrPublisherBuilder::add_topic`]: super::PkarrPublisherBuilder::add_topic
+//! [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use futures_lite::StreamExt;
+use futures_util::stream::BoxStream;
+use pkarr::SignedPacket;
+use tracing::{debug, warn};
+use url::Url;
+
+use super::{N0_DNS_PKARR_RELAY_PROD, N0_DNS_PKARR_RELAY_STAGING};
+use crate::{discovery::DiscoveryItem, dns::node_info::NodeInfo, endpoint::force_staging_infra};
+
+/// The HTTP response header containing the cursor to request the next page of nodes.
+pub const TOPIC_CURSOR_HEADER: &str = "iroh-topic-cursor";
+
+/// The number of nodes requested per page by [`TopicResolver::resolve`].
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+
+/// An application-chosen key under which nodes can be found.
+///
+/// See the [module docs](self) for details.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Topic([u8; 32]);
+
+impl Topic {
+    /// Creates a topic from the SHA-256 hash of a name.
+    pub fn new(name: impl AsRef<[u8]>) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, name.as_ref());
+        let bytes = digest
+            .as_ref()
+            .try_into()
+            .expect("SHA-256 is 32 bytes long");
+        Self(bytes)
+    }
+
+    /// Creates a topic from its bytes.
+    pub const fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the bytes of the topic.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", z32::encode(&self.0))
+    }
+}
+
+impl fmt::Debug for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Topic({self})")
+    }
+}
+
+impl FromStr for Topic {
+    type Err = anyhow::Error;
+
+    /// Parses a topic from its [z-base-32] encoding.
+    ///
+    /// [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = z32::decode(s.as_bytes()).map_err(|_| anyhow!("invalid z32"))?;
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| anyhow!("not 32 bytes long"))?;
+        Ok(Self(bytes))
+    }
+}
+
+/// The position in a listing of the nodes of a topic, to request the next page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicCursor(String);
+
+/// A page of the nodes announcing a topic.
+#[derive(Debug, Clone)]
+pub struct TopicPage {
+    /// The nodes in this page.
+    pub items: Vec<DiscoveryItem>,
+    /// The cursor to request the next page, `None` if this is the last page.
+    pub next: Option<TopicCursor>,
+}
+
+/// Lists the nodes announcing a [`Topic`] on a pkarr relay server.
+///
+/// See the [module docs](self) for details.
+#[derive(Debug, Clone)]
+pub struct TopicResolver {
+    http_client: reqwest::Client,
+    pkarr_relay_url: Url,
+}
+
+impl TopicResolver {
+    /// The provenance string of the items returned by this resolver.
+    pub const PROVENANCE: &'static str = "pkarr_topic";
+
+    /// Creates a resolver using the pkarr relay server at the URL.
+    pub fn new(pkarr_relay_url: Url) -> Self {
+        Self {
+            http_client: reqwest::Client::new(),
+            pkarr_relay_url,
+        }
+    }
+
+    /// Creates a resolver which uses the [number 0] pkarr relay server.
+    ///
+    /// When running with the environment variable `IROH_FORCE_STAGING_RELAYS` set to any
+    /// non empty value [`N0_DNS_PKARR_RELAY_STAGING`] server is used instead of
+    /// [`N0_DNS_PKARR_RELAY_PROD`].
+    ///
+    /// [number 0]: https://n0.computer
+    pub fn n0_dns() -> Self {
+        let pkarr_relay = match force_staging_infra() {
+            true => N0_DNS_PKARR_RELAY_STAGING,
+            false => N0_DNS_PKARR_RELAY_PROD,
+        };
+        Self::new(pkarr_relay.parse().expect("url is valid"))
+    }
+
+    /// Requests a single page of up to `limit` nodes announcing the topic.
+    ///
+    /// Pass the [`TopicPage::next`] cursor of the previous page to continue the listing.
+    /// Entries with an invalid signature or which do not announce the topic are left out
+    /// of the page.
+    pub async fn resolve_page(
+        &self,
+        topic: &Topic,
+        cursor: Option<&TopicCursor>,
+        limit: usize,
+    ) -> Result<TopicPage> {
+        let mut url = self.pkarr_relay_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Failed to resolve topic: Invalid relay URL"))?
+            .push("topic")
+            .push(&topic.to_string());
+        url.query_pairs_mut()
+            .append_pair("limit", &limit.to_string());
+        if let Some(cursor) = cursor {
+            url.query_pairs_mut().append_pair("cursor", &cursor.0);
+        }
+
+        let response = self.http_client.get(url).send().await?;
+        if !response.status().is_success() {
+            bail!(
+                "Topic resolve request failed with status {}",
+                response.status()
+            );
+        }
+        let next = response
+            .headers()
+            .get(TOPIC_CURSOR_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(|cursor| TopicCursor(cursor.to_string()));
+        let body = response.bytes().await?;
+
+        let mut items = Vec::new();
+        let mut pos = 0;
+        while pos < body.len() {
+            ensure!(
+                body.len() - pos >= 34,
+                "invalid topic response: truncated entry"
+            );
+            let key: [u8; 32] = body[pos..pos + 32].try_into().expect("checked length");
+            let len = u16::from_be_bytes([body[pos + 32], body[pos + 33]]) as usize;
+            pos += 34;
+            ensure!(
+                body.len() - pos >= len,
+                "invalid topic response: truncated packet"
+            );
+            let payload = body.slice(pos..pos + len);
+            pos += len;
+            match verify_entry(topic, &key, &payload) {
+                Ok(Some(item)) => items.push(item),
+                Ok(None) => {
+                    debug!(topic = %topic, "skipping node which does not announce the topic")
+                }
+                Err(err) => warn!(topic = %topic, "skipping invalid topic entry: {err:#}"),
+            }
+        }
+        Ok(TopicPage { items, next })
+    }
+
+    /// Lists all nodes announcing the topic.
+    ///
+    /// The pages are requested one after the other while the stream is polled.  The
+    /// stream ends after the first error.
+    pub fn resolve(&self, topic: Topic) -> BoxStream<'static, Result<DiscoveryItem>> {
+        let this = self.clone();
+        // `None` once the last page was returned.
+        let start: Option<Option<TopicCursor>> = Some(None);
+        let pages = futures_lite::stream::unfold(start, move |cursor| {
+            let this = this.clone();
+            async move {
+                let cursor = cursor?;
+                match this
+                    .resolve_page(&topic, cursor.as_ref(), DEFAULT_PAGE_SIZE)
+                    .await
+                {
+                    Ok(page) => {
+                        let items: Vec<_> = page.items.into_iter().map(Ok).collect();
+                        Some((items, page.next.map(Some)))
+                    }
+                    Err(err) => Some((vec![Err(err)], None)),
+                }
+            }
+        });
+        Box::pin(pages.flat_map(futures_lite::stream::iter))
+    }
+}
+
+/// Verifies the signed packet of a topic entry.
+///
+/// Returns `None` if the node does not announce the topic.
+fn verify_entry(
+    topic: &Topic,
+    key: &[u8; 32],
+    payload: &bytes::Bytes,
+) -> Result<Option<DiscoveryItem>> {
+    let public_key = pkarr::PublicKey::try_from(key)?;
+    let signed_packet = SignedPacket::from_relay_payload(&public_key, payload)?;
+    let mut info = NodeInfo::from_pkarr_signed_packet(&signed_packet)?;
+    if !info.topics.contains(topic) {
+        return Ok(None);
+    }
+    let user_data = info.user_data.take();
+    Ok(Some(DiscoveryItem {
+        node_id: info.node_id,
+        provenance: TopicResolver::PROVENANCE,
+        last_updated: Some(signed_packet.timestamp()),
+        addr_info: info.into(),
+        user_data,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_roundtrip() {
+        let topic = Topic::new("my-swarm");
+        assert_eq!(topic, Topic::new(b"my-swarm"));
+        assert_ne!(topic, Topic::new("other-swarm"));
+        let parsed: Topic = topic.to_string().parse().unwrap();
+        assert_eq!(topic, parsed);
+        assert!("not-a-topic".parse::<Topic>().is_err());
+    }
+}
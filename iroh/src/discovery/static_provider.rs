@@ -153,6 +153,7 @@ impl Discovery for StaticProvider {
                             .as_micros() as u64,
                     ),
                     addr_info: addr_info.info.clone(),
+                    user_data: None,
                 };
                 Some(stream::iter(Some(Ok(item))).boxed())
             }
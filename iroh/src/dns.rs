@@ -1,86 +1,26 @@
 //! This module exports a DNS resolver, which is also the default resolver used in the
 //! [`crate::Endpoint`] if no custom resolver is configured.
 //!
+//! A custom way of resolving names can be used by implementing [`Resolver`] and passing it
+//! to [`crate::endpoint::Builder::dns_resolver`] using [`DnsResolver::new`].
+//!
 //! It also exports [`ResolverExt`]: A extension trait over [`DnsResolver`] to perform DNS queries
 //! by ipv4, ipv6, name and node_id. See the [`node_info`] module documentation for details on how
 //! iroh node records are structured.
 
-use std::{
-    fmt::Write,
-    net::{IpAddr, Ipv6Addr},
-    time::Duration,
-};
+use std::{fmt::Write, net::IpAddr, time::Duration};
 
 use anyhow::Result;
 use futures_lite::{Future, StreamExt};
-use hickory_resolver::{AsyncResolver, IntoName, TokioAsyncResolver};
+use hickory_resolver::IntoName;
 use iroh_base::{key::NodeId, node_addr::NodeAddr};
-use once_cell::sync::Lazy;
+pub use iroh_relay::dns::{default_resolver, DnsResolver, Resolver, TxtRecord};
 
 pub mod node_info;
 
-/// The DNS resolver type used throughout `iroh`.
-pub type DnsResolver = TokioAsyncResolver;
-
-static DNS_RESOLVER: Lazy<TokioAsyncResolver> =
-    Lazy::new(|| create_default_resolver().expect("unable to create DNS resolver"));
-
-/// Get a reference to the default DNS resolver.
-///
-/// The default resolver can be cheaply cloned and is shared throughout the running process.
-/// It is configured to use the system's DNS configuration.
-pub fn default_resolver() -> &'static DnsResolver {
-    &DNS_RESOLVER
-}
-
 /// Get the DNS resolver used within iroh.
-pub fn resolver() -> &'static TokioAsyncResolver {
-    Lazy::force(&DNS_RESOLVER)
-}
-
-/// Deprecated IPv6 site-local anycast addresses still configured by windows.
-///
-/// Windows still configures these site-local addresses as soon even as an IPv6 loopback
-/// interface is configured.  We do not want to use these DNS servers, the chances of them
-/// being usable are almost always close to zero, while the chance of DNS configuration
-/// **only** relying on these servers and not also being configured normally are also almost
-/// zero.  The chance of the DNS resolver accidentally trying one of these and taking a
-/// bunch of timeouts to figure out they're no good are on the other hand very high.
-const WINDOWS_BAD_SITE_LOCAL_DNS_SERVERS: [IpAddr; 3] = [
-    IpAddr::V6(Ipv6Addr::new(0xfec0, 0, 0, 0xffff, 0, 0, 0, 1)),
-    IpAddr::V6(Ipv6Addr::new(0xfec0, 0, 0, 0xffff, 0, 0, 0, 2)),
-    IpAddr::V6(Ipv6Addr::new(0xfec0, 0, 0, 0xffff, 0, 0, 0, 3)),
-];
-
-/// Get resolver to query MX records.
-///
-/// We first try to read the system's resolver from `/etc/resolv.conf`.
-/// This does not work at least on some Androids, therefore we fallback
-/// to the default `ResolverConfig` which uses eg. to google's `8.8.8.8` or `8.8.4.4`.
-fn create_default_resolver() -> Result<TokioAsyncResolver> {
-    let (system_config, mut options) =
-        hickory_resolver::system_conf::read_system_conf().unwrap_or_default();
-
-    // Copy all of the system config, but strip the bad windows nameservers.  Unfortunately
-    // there is no easy way to do this.
-    let mut config = hickory_resolver::config::ResolverConfig::new();
-    if let Some(name) = system_config.domain() {
-        config.set_domain(name.clone());
-    }
-    for name in system_config.search() {
-        config.add_search(name.clone());
-    }
-    for nameserver_cfg in system_config.name_servers() {
-        if !WINDOWS_BAD_SITE_LOCAL_DNS_SERVERS.contains(&nameserver_cfg.socket_addr.ip()) {
-            config.add_name_server(nameserver_cfg.clone());
-        }
-    }
-
-    // see [`ResolverExt::lookup_ipv4_ipv6`] for info on why we avoid `LookupIpStrategy::Ipv4AndIpv6`
-    options.ip_strategy = hickory_resolver::config::LookupIpStrategy::Ipv4thenIpv6;
-
-    let resolver = AsyncResolver::tokio(config, options);
-    Ok(resolver)
+pub fn resolver() -> &'static DnsResolver {
+    default_resolver()
 }
 
 /// Extension trait to [`DnsResolver`].
@@ -189,7 +129,7 @@ impl ResolverExt for DnsResolver {
         timeout: Duration,
     ) -> Result<impl Iterator<Item = IpAddr>> {
         let addrs = tokio::time::timeout(timeout, self.ipv4_lookup(host)).await??;
-        Ok(addrs.into_iter().map(|ip| IpAddr::V4(ip.0)))
+        Ok(addrs.map(IpAddr::V4))
     }
 
     async fn lookup_ipv6<N: IntoName>(
@@ -198,7 +138,7 @@ impl ResolverExt for DnsResolver {
         timeout: Duration,
     ) -> Result<impl Iterator<Item = IpAddr>> {
         let addrs = tokio::time::timeout(timeout, self.ipv6_lookup(host)).await??;
-        Ok(addrs.into_iter().map(|ip| IpAddr::V6(ip.0)))
+        Ok(addrs.map(IpAddr::V6))
     }
 
     /// Resolve IPv4 and IPv6 in parallel.
@@ -321,6 +261,26 @@ impl ResolverExt for DnsResolver {
     }
 }
 
+/// Looks up the full [`node_info::NodeInfo`] by [`NodeId`] and origin domain name.
+///
+/// Unlike [`ResolverExt::lookup_by_id_staggered`] this keeps the [`UserData`] of the node.
+///
+/// [`UserData`]: crate::discovery::UserData
+pub(crate) async fn lookup_node_info_by_id_staggered(
+    resolver: &DnsResolver,
+    node_id: &NodeId,
+    origin: &str,
+    delays_ms: &[u64],
+) -> Result<node_info::NodeInfo> {
+    let f = || async {
+        let attrs =
+            node_info::TxtAttrs::<node_info::IrohAttr>::lookup_by_id(resolver, node_id, origin)
+                .await?;
+        Ok(attrs.into())
+    };
+    stagger_call(f, delays_ms).await
+}
+
 /// Helper enum to give a unified type to the iterators of [`ResolverExt::lookup_ipv4_ipv6`].
 enum LookupIter<A, B> {
     Ipv4(A),
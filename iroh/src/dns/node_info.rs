@@ -25,12 +25,18 @@
 //! - `addr=<addr> <addr>`: A space-separated list of sockets addresses for this iroh node.
 //!   Each address is an IPv4 or IPv6 address with a port.
 //!
+//! - `user-data=<data>`: Application-defined [`UserData`] of this node.
+//!
+//! - `topic=<topic>`: A [`Topic`] this node announces, in [z-base-32] encoding.  There is one
+//!   attribute for each topic.
+//!
 //! [Pkarr]: https://app.pkarr.org
 //! [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
 //! [RFC1464]: https://www.rfc-editor.org/rfc/rfc1464
 //! [`RelayUrl`]: iroh_base::node_addr::RelayUrl
 //! [`N0_DNS_NODE_ORIGIN_PROD`]: crate::discovery::dns::N0_DNS_NODE_ORIGIN_PROD
 //! [`N0_DNS_NODE_ORIGIN_STAGING`]: crate::discovery::dns::N0_DNS_NODE_ORIGIN_STAGING
+//! [`Topic`]: crate::discovery::pkarr::topic::Topic
 
 use std::{
     collections::{BTreeMap, BTreeSet},
@@ -42,10 +48,15 @@ use std::{
 
 use anyhow::{anyhow, ensure, Result};
 use hickory_proto::error::ProtoError;
-use hickory_resolver::{Name, TokioAsyncResolver};
+use hickory_resolver::Name;
 use url::Url;
 
-use crate::{key::SecretKey, AddrInfo, NodeAddr, NodeId};
+use crate::{
+    discovery::{pkarr::topic::Topic, UserData},
+    dns::DnsResolver,
+    key::SecretKey,
+    AddrInfo, NodeAddr, NodeId,
+};
 
 /// The DNS name for the iroh TXT record.
 pub const IROH_TXT_NAME: &str = "_iroh";
@@ -62,6 +73,10 @@ pub enum IrohAttr {
     Relay,
     /// Direct address.
     Addr,
+    /// Application-defined user data.
+    UserData,
+    /// Announced topic.
+    Topic,
 }
 
 /// Encodes a [`NodeId`] in [`z-base-32`] encoding.
@@ -91,6 +106,10 @@ pub struct NodeInfo {
     pub relay_url: Option<Url>,
     /// Any direct addresses.
     pub direct_addresses: BTreeSet<SocketAddr>,
+    /// Optional application-defined user data.
+    pub user_data: Option<UserData>,
+    /// The topics this node announces.
+    pub topics: BTreeSet<Topic>,
 }
 
 impl From<TxtAttrs<IrohAttr>> for NodeInfo {
@@ -115,10 +134,24 @@ impl From<&TxtAttrs<IrohAttr>> for NodeInfo {
             .flatten()
             .filter_map(|s| SocketAddr::from_str(s).ok())
             .collect();
+        let user_data = attrs
+            .get(&IrohAttr::UserData)
+            .into_iter()
+            .flatten()
+            .next()
+            .and_then(|s| UserData::from_str(s).ok());
+        let topics = attrs
+            .get(&IrohAttr::Topic)
+            .into_iter()
+            .flatten()
+            .filter_map(|s| Topic::from_str(s).ok())
+            .collect();
         Self {
             node_id,
             relay_url,
             direct_addresses,
+            user_data,
+            topics,
         }
     }
 }
@@ -132,6 +165,12 @@ impl From<&NodeInfo> for TxtAttrs<IrohAttr> {
         for addr in &info.direct_addresses {
             attrs.push((IrohAttr::Addr, addr.to_string()));
         }
+        if let Some(user_data) = &info.user_data {
+            attrs.push((IrohAttr::UserData, user_data.to_string()));
+        }
+        for topic in &info.topics {
+            attrs.push((IrohAttr::Topic, topic.to_string()));
+        }
         Self::from_parts(info.node_id, attrs.into_iter())
     }
 }
@@ -165,9 +204,23 @@ impl NodeInfo {
             node_id,
             relay_url,
             direct_addresses,
+            user_data: None,
+            topics: Default::default(),
         }
     }
 
+    /// Sets the [`UserData`] of this node.
+    pub fn with_user_data(mut self, user_data: Option<UserData>) -> Self {
+        self.user_data = user_data;
+        self
+    }
+
+    /// Sets the [`Topic`]s this node announces.
+    pub fn with_topics(mut self, topics: BTreeSet<Topic>) -> Self {
+        self.topics = topics;
+        self
+    }
+
     fn to_attrs(&self) -> TxtAttrs<IrohAttr> {
         self.into()
     }
@@ -251,8 +304,8 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
     pub fn from_strings(node_id: NodeId, strings: impl Iterator<Item = String>) -> Result<Self> {
         let mut attrs: BTreeMap<T, Vec<String>> = BTreeMap::new();
         for s in strings {
-            let mut parts = s.split('=');
-            let (Some(key), Some(value)) = (parts.next(), parts.next()) else {
+            // Only the first `=` separates the key, values like user data may contain more.
+            let Some((key, value)) = s.split_once('=') else {
                 continue;
             };
             let Ok(attr) = T::from_str(key) else {
@@ -263,16 +316,20 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
         Ok(Self { attrs, node_id })
     }
 
-    async fn lookup(resolver: &TokioAsyncResolver, name: Name) -> Result<Self> {
+    async fn lookup(resolver: &DnsResolver, name: Name) -> Result<Self> {
         let name = ensure_iroh_txt_label(name)?;
-        let lookup = resolver.txt_lookup(name).await?;
-        let attrs = Self::from_hickory_records(lookup.as_lookup().records())?;
-        Ok(attrs)
+        let records = resolver.txt_lookup(name).await?;
+        let records = records.filter_map(|record| {
+            let name = Name::from_utf8(record.name()).ok()?;
+            let node_id = node_id_from_hickory_name(&name)?;
+            Some((node_id, record.value().to_string()))
+        });
+        Self::from_node_txt_strings(records)
     }
 
     /// Looks up attributes by [`NodeId`] and origin domain.
     pub async fn lookup_by_id(
-        resolver: &TokioAsyncResolver,
+        resolver: &DnsResolver,
         node_id: &NodeId,
         origin: &str,
     ) -> Result<Self> {
@@ -281,7 +338,7 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
     }
 
     /// Looks up attributes by DNS name.
-    pub async fn lookup_by_name(resolver: &TokioAsyncResolver, name: &str) -> Result<Self> {
+    pub async fn lookup_by_name(resolver: &DnsResolver, name: &str) -> Result<Self> {
         let name = Name::from_str(name)?;
         TxtAttrs::lookup(resolver, name).await
     }
@@ -322,22 +379,29 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
     /// Parses a set of DNS resource records.
     pub fn from_hickory_records(records: &[hickory_proto::rr::Record]) -> Result<Self> {
         use hickory_proto::rr;
-        let mut records = records.iter().filter_map(|rr| match rr.data() {
+        let records = records.iter().filter_map(|rr| match rr.data() {
             rr::RData::TXT(txt) => {
-                node_id_from_hickory_name(rr.name()).map(|node_id| (node_id, txt))
+                node_id_from_hickory_name(rr.name()).map(|node_id| (node_id, txt.to_string()))
             }
             _ => None,
         });
+        Self::from_node_txt_strings(records)
+    }
+
+    /// Parses the strings of TXT records, each with the [`NodeId`] parsed from its name.
+    fn from_node_txt_strings(mut records: impl Iterator<Item = (NodeId, String)>) -> Result<Self> {
         let (node_id, first) = records.next().ok_or_else(|| {
             anyhow!("invalid DNS answer: no TXT record with name _iroh.z32encodedpubkey found")
         })?;
-        ensure!(
-            &records.all(|(n, _)| n == node_id),
-            "invalid DNS answer: all _iroh txt records must belong to the same node domain"
-        );
-        let records = records.map(|(_, txt)| txt).chain(Some(first));
-        let strings = records.map(ToString::to_string);
-        Self::from_strings(node_id, strings)
+        let mut strings = vec![first];
+        for (other, string) in records {
+            ensure!(
+                other == node_id,
+                "invalid DNS answer: all _iroh txt records must belong to the same node domain"
+            );
+            strings.push(string);
+        }
+        Self::from_strings(node_id, strings.into_iter())
     }
 
     fn to_txt_strings(&self) -> impl Iterator<Item = String> + '_ {
@@ -418,6 +482,7 @@ mod tests {
     use iroh_base::key::SecretKey;
 
     use super::NodeInfo;
+    use crate::discovery::{pkarr::topic::Topic, UserData};
 
     #[test]
     fn txt_attr_roundtrip() {
@@ -427,6 +492,8 @@ mod tests {
                 .unwrap(),
             relay_url: Some("https://example.com".parse().unwrap()),
             direct_addresses: ["127.0.0.1:1234".parse().unwrap()].into_iter().collect(),
+            user_data: Some("foo=bar".parse().unwrap()),
+            topics: [Topic::new("foo")].into(),
         };
         let attrs = expected.to_attrs();
         let actual = NodeInfo::from(&attrs);
@@ -441,9 +508,26 @@ mod tests {
             node_id: secret_key.public(),
             relay_url: Some("https://example.com".parse().unwrap()),
             direct_addresses: ["127.0.0.1:1234".parse().unwrap()].into_iter().collect(),
+            user_data: Some("foobar".parse().unwrap()),
+            topics: [Topic::new("foo"), Topic::new("bar")].into(),
         };
         let packet = expected.to_pkarr_signed_packet(&secret_key, 30).unwrap();
         let actual = NodeInfo::from_pkarr_signed_packet(&packet).unwrap();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn user_data_max_length() {
+        let max = "a".repeat(UserData::MAX_LENGTH);
+        let user_data: UserData = max.parse().unwrap();
+        let secret_key = SecretKey::generate();
+        let expected = NodeInfo::new(secret_key.public(), None, Default::default())
+            .with_user_data(Some(user_data));
+        let packet = expected.to_pkarr_signed_packet(&secret_key, 30).unwrap();
+        let actual = NodeInfo::from_pkarr_signed_packet(&packet).unwrap();
+        assert_eq!(expected, actual);
+
+        let too_long = "a".repeat(UserData::MAX_LENGTH + 1);
+        assert!(too_long.parse::<UserData>().is_err());
+    }
 }
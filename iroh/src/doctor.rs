@@ -0,0 +1,964 @@
+//! Diagnostics of the connectivity of a node.
+//!
+//! When a node can not reach other nodes, or only slowly, the [`Doctor`] helps to find out
+//! why.  It runs a set of self-contained routines, each of which can also be run on its own:
+//!
+//! - [`Doctor::probe_relay`] connects to a relay server and measures its latency.
+//! - [`Doctor::loopback_transfer`] transfers data between two endpoints in this process,
+//!   which tests the local network stack without depending on any remote node.
+//! - [`Doctor::hole_punch`] connects to a remote node running the [`Echo`] protocol and
+//!   checks whether a direct connection can be established, e.g. to a node run by the
+//!   support team for this purpose.
+//! - [`Doctor::reachability`] asks a remote node running the [`Reachability`] protocol to
+//!   connect back to each direct address of an endpoint, which shows whether a port
+//!   forwarding or port mapping actually works before handing out tickets.
+//!
+//! [`Doctor::run`] runs all of them together with a [net report] and collects the results
+//! in a [`DoctorReport`], which can be printed to send it along with a bug report:
+//!
+//! ```no_run
+//! # async fn wrapper() -> anyhow::Result<()> {
+//! use iroh::doctor::Doctor;
+//!
+//! let report = Doctor::new().run().await;
+//! println!("{report}");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [net report]: crate::endpoint::NetReport
+
+use std::{
+    collections::HashSet,
+    fmt,
+    net::SocketAddr,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use futures_lite::{future::Boxed as BoxedFuture, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OnceCell, Semaphore};
+use tracing::{debug, warn};
+
+use crate::{
+    endpoint::{
+        get_remote_node_id, Connecting, Connection, ConnectionType, DirectAddrType, NetReport,
+        PortMappingConfig, RelayMode,
+    },
+    protocol::{ProtocolHandler, Router},
+    relay_map::RelayMapProber,
+    Endpoint, NodeAddr, NodeId, RelayUrl,
+};
+
+/// The ALPN of the [`Echo`] protocol.
+pub const ECHO_ALPN: &[u8] = b"/iroh/doctor/echo/0";
+
+/// The ALPN of the [`Reachability`] protocol.
+pub const REACHABILITY_ALPN: &[u8] = b"/iroh/doctor/reachability/0";
+
+/// The default time each routine of the [`Doctor`] may take.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default number of bytes sent by [`Doctor::loopback_transfer`].
+const DEFAULT_TRANSFER_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The size of the buffers written by [`Doctor::loopback_transfer`].
+const TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The message sent by [`Doctor::hole_punch`].
+const ECHO_MESSAGE: &[u8] = b"iroh doctor hole punch test";
+
+/// The most addresses a [`Reachability`] node probes for a single request.
+const MAX_PROBED_ADDRS: usize = 16;
+
+/// The maximum size of a reachability request or response.
+const MAX_REACHABILITY_MESSAGE_SIZE: usize = 4096;
+
+/// How long a [`Reachability`] node tries to connect to each address.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most requests a [`Reachability`] node handles at the same time.
+const MAX_REACHABILITY_REQUESTS: usize = 4;
+
+/// The error code a [`Reachability`] node closes connections with when it is busy.
+const REACHABILITY_BUSY_CODE: u32 = 1;
+
+/// A protocol which sends back everything received on a bidirectional stream.
+///
+/// Accept it on a [`Router`] with [`ECHO_ALPN`] to allow other nodes to test hole punching
+/// to this node with [`Doctor::hole_punch`].
+#[derive(Debug, Clone, Default)]
+pub struct Echo;
+
+impl ProtocolHandler for Echo {
+    fn accept(&self, connecting: Connecting) -> BoxedFuture<Result<()>> {
+        Box::pin(async move {
+            let connection = connecting.await?;
+            while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                tokio::spawn(async move {
+                    match tokio::io::copy(&mut recv, &mut send).await {
+                        Ok(len) => {
+                            debug!("echoed {len} bytes");
+                            send.finish().ok();
+                        }
+                        Err(err) => debug!("echo failed: {err:#}"),
+                    }
+                });
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A protocol which probes whether the direct addresses of other nodes are reachable.
+///
+/// A node asking for probes with [`Doctor::reachability`] sends its direct addresses.  For
+/// each of them this node tries to connect to the requesting node using only this address,
+/// from a separate endpoint without relay servers.  The probe endpoint forgets the
+/// requesting node after each probe, so this only succeeds if the address accepts inbound
+/// connections from the internet.
+///
+/// The probe connections use [`REACHABILITY_ALPN`] as well, so the node being tested must
+/// accept this protocol too.  Only addresses on the IP address the requesting node is
+/// connected from are probed, one after the other, so the protocol can not be used to make
+/// this node connect to other hosts.  At most 16 addresses are accepted per request, and
+/// at most 4 requests, each from a different node, are handled at the same time.
+#[derive(Debug, Clone)]
+pub struct Reachability {
+    inner: Arc<ReachabilityInner>,
+}
+
+#[derive(Debug)]
+struct ReachabilityInner {
+    /// The endpoint accepting the requests.
+    endpoint: Endpoint,
+    /// The endpoint connecting to the probed addresses, bound on the first request.
+    probe_endpoint: OnceCell<Endpoint>,
+    requests: Semaphore,
+    /// The nodes whose requests are currently handled.
+    nodes: parking_lot::Mutex<HashSet<NodeId>>,
+}
+
+impl Reachability {
+    /// Creates the protocol for the node of `endpoint`, which must be the endpoint of the
+    /// [`Router`] accepting it.
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            inner: Arc::new(ReachabilityInner {
+                endpoint,
+                probe_endpoint: OnceCell::new(),
+                requests: Semaphore::new(MAX_REACHABILITY_REQUESTS),
+                nodes: Default::default(),
+            }),
+        }
+    }
+}
+
+impl ProtocolHandler for Reachability {
+    fn accept(&self, connecting: Connecting) -> BoxedFuture<Result<()>> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let connection = connecting.await?;
+            let node_id = get_remote_node_id(&connection)?;
+            // Probe connections are closed without opening a stream.
+            let Ok((mut send, mut recv)) = connection.accept_bi().await else {
+                return Ok(());
+            };
+            let Ok(_permit) = inner.requests.try_acquire() else {
+                debug!(node = %node_id.fmt_short(), "too many reachability requests");
+                connection.close(REACHABILITY_BUSY_CODE.into(), b"busy");
+                return Ok(());
+            };
+            let Some(_guard) = NodeGuard::new(&inner.nodes, node_id) else {
+                debug!(node = %node_id.fmt_short(), "reachability request already running");
+                connection.close(REACHABILITY_BUSY_CODE.into(), b"busy");
+                return Ok(());
+            };
+            let request = recv.read_to_end(MAX_REACHABILITY_MESSAGE_SIZE).await?;
+            let ReachabilityRequest { addrs } = postcard::from_bytes(&request)?;
+            ensure!(addrs.len() <= MAX_PROBED_ADDRS, "too many addresses");
+            debug!(node = %node_id.fmt_short(), "probing {} addresses", addrs.len());
+            let mut results = Vec::with_capacity(addrs.len());
+            match remote_direct_addr(&inner.endpoint, node_id).await {
+                Some(remote_addr) => {
+                    for addr in addrs {
+                        let res = match check_probe_target(addr, remote_addr) {
+                            Ok(()) => inner.probe(node_id, addr).await,
+                            Err(err) => Err(err),
+                        };
+                        results.push(res);
+                    }
+                }
+                None => {
+                    let err = "no direct path to the requesting node".to_string();
+                    results.resize(addrs.len(), Err(err));
+                }
+            }
+            let response = postcard::to_stdvec(&ReachabilityResponse { results })?;
+            send.write_all(&response).await?;
+            send.finish()?;
+            connection.closed().await;
+            Ok(())
+        })
+    }
+
+    fn shutdown(&self) -> BoxedFuture<()> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            if let Some(endpoint) = inner.probe_endpoint.get() {
+                endpoint.close().await.ok();
+            }
+        })
+    }
+}
+
+impl ReachabilityInner {
+    /// Connects to `node_id` using only `addr`, from the probe endpoint.
+    ///
+    /// Returns how long it took to establish the connection.
+    async fn probe(&self, node_id: NodeId, addr: SocketAddr) -> Result<Duration, String> {
+        let res = async {
+            let endpoint = self
+                .probe_endpoint
+                .get_or_try_init(|| {
+                    Endpoint::builder()
+                        .relay_mode(RelayMode::Disabled)
+                        .port_mapping(PortMappingConfig::disabled())
+                        .bind()
+                })
+                .await?;
+            let start = Instant::now();
+            let node_addr = NodeAddr::from_parts(node_id, None, [addr]);
+            let res = tokio::time::timeout(
+                PROBE_TIMEOUT,
+                endpoint.connect(node_addr, REACHABILITY_ALPN),
+            )
+            .await
+            .context("timeout")
+            .and_then(|res| Ok(res?))
+            .map(|conn| {
+                conn.close(0u32.into(), b"reachable");
+                start.elapsed()
+            });
+            // Without the path of this probe the next probe can only use its own address.
+            endpoint.forget_node(node_id);
+            res
+        };
+        res.await.map_err(|err: anyhow::Error| format!("{err:#}"))
+    }
+}
+
+/// Marks a node as having a [`Reachability`] request running, until dropped.
+struct NodeGuard<'a> {
+    nodes: &'a parking_lot::Mutex<HashSet<NodeId>>,
+    node_id: NodeId,
+}
+
+impl<'a> NodeGuard<'a> {
+    /// Returns `None` if a request of the node is already running.
+    fn new(nodes: &'a parking_lot::Mutex<HashSet<NodeId>>, node_id: NodeId) -> Option<Self> {
+        nodes
+            .lock()
+            .insert(node_id)
+            .then_some(Self { nodes, node_id })
+    }
+}
+
+impl Drop for NodeGuard<'_> {
+    fn drop(&mut self) {
+        self.nodes.lock().remove(&self.node_id);
+    }
+}
+
+/// Waits for a direct path to `node_id` and returns its address.
+async fn remote_direct_addr(endpoint: &Endpoint, node_id: NodeId) -> Option<SocketAddr> {
+    let conn_types = endpoint.conn_type_stream(node_id).ok()?;
+    let mut direct_addrs = pin!(conn_types.filter_map(|conn_type| match conn_type {
+        ConnectionType::Direct(addr) | ConnectionType::Mixed(addr, _) => Some(addr),
+        ConnectionType::Relay(_) | ConnectionType::None => None,
+    }));
+    tokio::time::timeout(PROBE_TIMEOUT, direct_addrs.next())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Checks whether a node seen at `remote_addr` may ask for `target` to be probed.
+///
+/// Only addresses on the IP address of the requesting node are probed.  This also means
+/// loopback, private and link-local addresses are only probed if the requesting node is
+/// connected from them.
+fn check_probe_target(target: SocketAddr, remote_addr: SocketAddr) -> Result<(), String> {
+    if target.ip().to_canonical() != remote_addr.ip().to_canonical() {
+        return Err(format!(
+            "not probed, the requesting node is connected from {}",
+            remote_addr.ip()
+        ));
+    }
+    Ok(())
+}
+
+/// The request of the [`Reachability`] protocol.
+#[derive(Debug, Serialize, Deserialize)]
+struct ReachabilityRequest {
+    addrs: Vec<SocketAddr>,
+}
+
+/// The response of the [`Reachability`] protocol, with a result for each address.
+#[derive(Debug, Serialize, Deserialize)]
+struct ReachabilityResponse {
+    results: Vec<Result<Duration, String>>,
+}
+
+/// Runs diagnostics of the connectivity of a node.
+///
+/// See the [module docs](self) for details.
+#[derive(Debug, Clone)]
+pub struct Doctor {
+    relay_mode: RelayMode,
+    timeout: Duration,
+    transfer_size: u64,
+    echo_node: Option<NodeAddr>,
+    #[cfg(any(test, feature = "test-utils"))]
+    insecure_skip_relay_cert_verify: bool,
+}
+
+impl Default for Doctor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Doctor {
+    /// Creates a doctor which uses the default relay servers.
+    pub fn new() -> Self {
+        Self {
+            relay_mode: RelayMode::Default,
+            timeout: DEFAULT_TIMEOUT,
+            transfer_size: DEFAULT_TRANSFER_SIZE,
+            echo_node: None,
+            #[cfg(any(test, feature = "test-utils"))]
+            insecure_skip_relay_cert_verify: false,
+        }
+    }
+
+    /// Sets the relay servers used by the endpoints of the doctor.
+    ///
+    /// This should be the same as the one of the node being diagnosed.
+    pub fn relay_mode(mut self, relay_mode: RelayMode) -> Self {
+        self.relay_mode = relay_mode;
+        self
+    }
+
+    /// Sets how long each routine may take, by default 10 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of bytes sent by [`Doctor::loopback_transfer`], by default 16 MiB.
+    pub fn transfer_size(mut self, size: u64) -> Self {
+        self.transfer_size = size;
+        self
+    }
+
+    /// Sets the node [`Doctor::run`] tests hole punching against.
+    ///
+    /// The node must accept the [`Echo`] protocol.  Without it [`Doctor::run`] skips the
+    /// hole punching test.
+    pub fn echo_node(mut self, node_addr: NodeAddr) -> Self {
+        self.echo_node = Some(node_addr);
+        self
+    }
+
+    /// Skips verification of SSL certificates from relay servers.
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+    pub fn insecure_skip_relay_cert_verify(mut self, skip_verify: bool) -> Self {
+        self.insecure_skip_relay_cert_verify = skip_verify;
+        self
+    }
+
+    /// Runs all diagnostics.
+    ///
+    /// Failures of the individual routines are recorded in the report.
+    pub async fn run(&self) -> DoctorReport {
+        let endpoint = match self.bind().await {
+            Ok(endpoint) => endpoint,
+            Err(err) => return DoctorReport::failed(err),
+        };
+
+        let net_report = tokio::time::timeout(self.timeout, endpoint.net_report_stream().next())
+            .await
+            .ok()
+            .flatten();
+        let relays = futures_buffered::join_all(
+            self.relay_mode
+                .relay_map()
+                .nodes()
+                .map(|node| self.probe_relay(&node.url)),
+        )
+        .await;
+        let loopback = self
+            .loopback_transfer()
+            .await
+            .map_err(|err| format!("{err:#}"));
+        let hole_punch = match &self.echo_node {
+            Some(echo_node) => Some(
+                self.hole_punch_from(&endpoint, echo_node.clone())
+                    .await
+                    .map_err(|err| format!("{err:#}")),
+            ),
+            None => None,
+        };
+        let report = DoctorReport {
+            node_id: Some(endpoint.node_id()),
+            error: None,
+            net_report,
+            relays,
+            loopback: Some(loopback),
+            hole_punch,
+        };
+        endpoint.close().await.ok();
+        report
+    }
+
+    /// Connects to the relay server at `url` and measures the round trip time of a ping.
+    pub async fn probe_relay(&self, url: &RelayUrl) -> RelayReport {
+        let prober = RelayMapProber::new([url.clone()]).timeout(self.timeout);
+        #[cfg(any(test, feature = "test-utils"))]
+        let prober = prober.insecure_skip_cert_verify(self.insecure_skip_relay_cert_verify);
+        match prober.probe_one(url, &prober.resolver()).await {
+            Ok(latency) => RelayReport {
+                url: url.clone(),
+                latency: Some(latency),
+                error: None,
+            },
+            Err(err) => RelayReport {
+                url: url.clone(),
+                latency: None,
+                error: Some(format!("{err:#}")),
+            },
+        }
+    }
+
+    /// Transfers data between two endpoints in this process.
+    ///
+    /// The data is sent to an endpoint running the [`Echo`] protocol and read back, so the
+    /// report measures the time it took in both directions at the same time.
+    pub async fn loopback_transfer(&self) -> Result<TransferReport> {
+        let router = Router::builder(self.bind().await?)
+            .accept(ECHO_ALPN, Echo)
+            .spawn()
+            .await?;
+        let endpoint = self.bind().await?;
+        let res = tokio::time::timeout(self.timeout, async {
+            let node_addr = router.endpoint().node_addr().await?;
+            let node_id = node_addr.node_id;
+            let conn = endpoint.connect(node_addr, ECHO_ALPN).await?;
+            let start = Instant::now();
+            let len = self.transfer(&conn).await?;
+            let duration = start.elapsed();
+            let conn_type = endpoint
+                .remote_info(node_id)
+                .map(|info| info.conn_type)
+                .unwrap_or(ConnectionType::None);
+            conn.close(0u32.into(), b"done");
+            anyhow::Ok(TransferReport {
+                len,
+                duration,
+                conn_type,
+            })
+        })
+        .await
+        .context("timeout")
+        .and_then(|res| res);
+        endpoint.close().await.ok();
+        router.shutdown().await.ok();
+        res
+    }
+
+    /// Tests hole punching to a node running the [`Echo`] protocol.
+    ///
+    /// Connects to the node, sends a message which is echoed back and then waits for a
+    /// direct connection until the [timeout](Self::timeout) expires.  If no direct
+    /// connection is established the report shows the connection stayed relayed, which is
+    /// not an error.
+    pub async fn hole_punch(&self, echo_node: NodeAddr) -> Result<HolePunchReport> {
+        let endpoint = self.bind().await?;
+        let res = self.hole_punch_from(&endpoint, echo_node).await;
+        endpoint.close().await.ok();
+        res
+    }
+
+    async fn hole_punch_from(
+        &self,
+        endpoint: &Endpoint,
+        echo_node: NodeAddr,
+    ) -> Result<HolePunchReport> {
+        let node_id = echo_node.node_id;
+        let start = Instant::now();
+        let conn = tokio::time::timeout(self.timeout, endpoint.connect(echo_node, ECHO_ALPN))
+            .await
+            .context("timeout")??;
+        let connect_time = start.elapsed();
+
+        let echo_start = Instant::now();
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(ECHO_MESSAGE).await?;
+        send.finish()?;
+        let echo = recv.read_to_end(ECHO_MESSAGE.len()).await?;
+        ensure!(echo == ECHO_MESSAGE, "invalid echo response");
+        let echo_rtt = echo_start.elapsed();
+
+        let mut conn_types = endpoint.conn_type_stream(node_id)?;
+        let initial_conn_type = conn_types.next().await.unwrap_or(ConnectionType::None);
+        let mut conn_type = initial_conn_type.clone();
+        let mut time_to_direct = None;
+        let deadline = tokio::time::Instant::from_std(start + self.timeout);
+        loop {
+            if let ConnectionType::Direct(_) = conn_type {
+                time_to_direct = Some(start.elapsed());
+                break;
+            }
+            match tokio::time::timeout_at(deadline, conn_types.next()).await {
+                Ok(Some(next)) => conn_type = next,
+                Ok(None) | Err(_) => break,
+            }
+        }
+        conn.close(0u32.into(), b"done");
+        Ok(HolePunchReport {
+            node_id,
+            connect_time,
+            echo_rtt,
+            initial_conn_type,
+            conn_type,
+            time_to_direct,
+        })
+    }
+
+    /// Asks a node running the [`Reachability`] protocol which direct addresses of `endpoint`
+    /// are reachable from it.
+    ///
+    /// The `endpoint` must accept [`REACHABILITY_ALPN`] with the [`Reachability`] protocol,
+    /// otherwise none of its addresses are reported as reachable.  The remote node should be
+    /// outside of the local network, e.g. a node run by the support team for this purpose,
+    /// as addresses which are only reachable in the local network are reported as reachable
+    /// otherwise.
+    pub async fn reachability(
+        &self,
+        endpoint: &Endpoint,
+        helper: NodeAddr,
+    ) -> Result<ReachabilityReport> {
+        let helper_id = helper.node_id;
+        let addrs: Vec<_> = endpoint
+            .direct_addresses()
+            .next()
+            .await
+            .context("no direct addresses")?
+            .into_iter()
+            .take(MAX_PROBED_ADDRS)
+            .collect();
+        let conn = tokio::time::timeout(self.timeout, endpoint.connect(helper, REACHABILITY_ALPN))
+            .await
+            .context("timeout")??;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        let request = ReachabilityRequest {
+            addrs: addrs.iter().map(|addr| addr.addr).collect(),
+        };
+        send.write_all(&postcard::to_stdvec(&request)?).await?;
+        send.finish()?;
+        // The helper first waits for a direct path, then probes the addresses one after the
+        // other, each of which may take up to the probe timeout.
+        let probe_time = PROBE_TIMEOUT * (addrs.len() as u32 + 1);
+        let response = tokio::time::timeout(
+            self.timeout + probe_time,
+            recv.read_to_end(MAX_REACHABILITY_MESSAGE_SIZE),
+        )
+        .await
+        .context("timeout")??;
+        conn.close(0u32.into(), b"done");
+        let ReachabilityResponse { results } = postcard::from_bytes(&response)?;
+        ensure!(
+            results.len() == addrs.len(),
+            "invalid reachability response"
+        );
+        let addrs = addrs
+            .into_iter()
+            .zip(results)
+            .map(|(addr, res)| {
+                let (connect_time, error) = match res {
+                    Ok(connect_time) => (Some(connect_time), None),
+                    Err(err) => (None, Some(err)),
+                };
+                AddrReachability {
+                    addr: addr.addr,
+                    typ: addr.typ,
+                    connect_time,
+                    error,
+                }
+            })
+            .collect();
+        Ok(ReachabilityReport {
+            node_id: helper_id,
+            addrs,
+        })
+    }
+
+    /// Writes [`Doctor::transfer_size`] bytes to an [`Echo`] connection and reads them back.
+    async fn transfer(&self, conn: &Connection) -> Result<u64> {
+        let (mut send, mut recv) = conn.open_bi().await?;
+        let write = async {
+            let chunk = vec![0u8; TRANSFER_CHUNK_SIZE];
+            let mut remaining = self.transfer_size;
+            while remaining > 0 {
+                let len = remaining.min(chunk.len() as u64) as usize;
+                send.write_all(&chunk[..len]).await?;
+                remaining -= len as u64;
+            }
+            send.finish()?;
+            anyhow::Ok(())
+        };
+        let read = async {
+            let mut len = 0u64;
+            while let Some(chunk) = recv.read_chunk(TRANSFER_CHUNK_SIZE, true).await? {
+                len += chunk.bytes.len() as u64;
+            }
+            anyhow::Ok(len)
+        };
+        let ((), len) = tokio::try_join!(write, read)?;
+        ensure!(
+            len == self.transfer_size,
+            "received {len} of {} bytes",
+            self.transfer_size
+        );
+        Ok(len)
+    }
+
+    /// Binds an endpoint with the configured relay servers.
+    async fn bind(&self) -> Result<Endpoint> {
+        let builder = Endpoint::builder().relay_mode(self.relay_mode.clone());
+        #[cfg(any(test, feature = "test-utils"))]
+        let builder = builder.insecure_skip_relay_cert_verify(self.insecure_skip_relay_cert_verify);
+        builder.bind().await
+    }
+}
+
+/// The result of [`Doctor::probe_relay`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayReport {
+    /// The URL of the relay server.
+    pub url: RelayUrl,
+    /// The round trip time of a ping, `None` if the relay server was not reachable.
+    pub latency: Option<Duration>,
+    /// Why the relay server was not reachable.
+    pub error: Option<String>,
+}
+
+/// The result of [`Doctor::loopback_transfer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferReport {
+    /// The number of bytes sent, the same number was received.
+    pub len: u64,
+    /// How long sending and receiving took.
+    pub duration: Duration,
+    /// The path used by the connection at the end of the transfer.
+    pub conn_type: ConnectionType,
+}
+
+impl TransferReport {
+    /// Returns the throughput in bytes per second, in each direction.
+    pub fn throughput(&self) -> f64 {
+        self.len as f64 / self.duration.as_secs_f64()
+    }
+}
+
+/// The result of [`Doctor::hole_punch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HolePunchReport {
+    /// The node hole punching was tested against.
+    pub node_id: NodeId,
+    /// How long it took to establish the connection.
+    pub connect_time: Duration,
+    /// The round trip time of the echoed message.
+    pub echo_rtt: Duration,
+    /// The path used right after the echoed message was received.
+    pub initial_conn_type: ConnectionType,
+    /// The path used at the end of the test.
+    pub conn_type: ConnectionType,
+    /// How long it took from connecting until a direct path was used.
+    ///
+    /// `None` if the connection stayed relayed.
+    pub time_to_direct: Option<Duration>,
+}
+
+impl HolePunchReport {
+    /// Whether a direct path was used at the end of the test.
+    pub fn is_direct(&self) -> bool {
+        matches!(self.conn_type, ConnectionType::Direct(_))
+    }
+}
+
+/// The result of [`Doctor::reachability`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReachabilityReport {
+    /// The node which probed the addresses.
+    pub node_id: NodeId,
+    /// The result for each direct address of the endpoint.
+    pub addrs: Vec<AddrReachability>,
+}
+
+impl ReachabilityReport {
+    /// Returns the direct addresses which are reachable.
+    pub fn reachable(&self) -> impl Iterator<Item = &AddrReachability> {
+        self.addrs.iter().filter(|addr| addr.is_reachable())
+    }
+}
+
+/// Whether a single direct address is reachable, see [`ReachabilityReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrReachability {
+    /// The direct address.
+    pub addr: SocketAddr,
+    /// How the endpoint learned about this address.
+    pub typ: DirectAddrType,
+    /// How long it took to connect to this address, `None` if it was not reachable.
+    pub connect_time: Option<Duration>,
+    /// Why the address was not reachable.
+    pub error: Option<String>,
+}
+
+impl AddrReachability {
+    /// Whether a connection to this address could be established.
+    pub fn is_reachable(&self) -> bool {
+        self.connect_time.is_some()
+    }
+}
+
+impl fmt::Display for ReachabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for addr in &self.addrs {
+            match (addr.connect_time, &addr.error) {
+                (Some(time), _) => {
+                    writeln!(f, "{} ({}): reachable in {time:?}", addr.addr, addr.typ)?
+                }
+                (None, Some(err)) => {
+                    writeln!(f, "{} ({}): unreachable: {err}", addr.addr, addr.typ)?
+                }
+                (None, None) => writeln!(f, "{} ({}): unreachable", addr.addr, addr.typ)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The result of [`Doctor::run`].
+///
+/// The [`Display`](fmt::Display) implementation prints a summary suitable for bug reports.
+#[derive(Debug, Clone)]
+pub struct DoctorReport {
+    /// The node id of the endpoint running the diagnostics, `None` if it could not be bound.
+    pub node_id: Option<NodeId>,
+    /// Why the diagnostics could not be run at all.
+    pub error: Option<String>,
+    /// The first net report of the endpoint, `None` if it did not complete in time.
+    pub net_report: Option<Arc<NetReport>>,
+    /// The results of probing each configured relay server.
+    pub relays: Vec<RelayReport>,
+    /// The result of the loopback transfer.
+    pub loopback: Option<Result<TransferReport, String>>,
+    /// The result of the hole punching test, `None` if no echo node was configured.
+    pub hole_punch: Option<Result<HolePunchReport, String>>,
+}
+
+impl DoctorReport {
+    fn failed(err: anyhow::Error) -> Self {
+        warn!("failed to run diagnostics: {err:#}");
+        Self {
+            node_id: None,
+            error: Some(format!("{err:#}")),
+            net_report: None,
+            relays: Vec::new(),
+            loopback: None,
+            hole_punch: None,
+        }
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(err) = &self.error {
+            return writeln!(f, "diagnostics failed: {err}");
+        }
+        if let Some(node_id) = &self.node_id {
+            writeln!(f, "node id: {node_id}")?;
+        }
+        match &self.net_report {
+            Some(report) => {
+                writeln!(f, "udp: {}", report.udp)?;
+                writeln!(f, "ipv4: {}, ipv6: {}", report.ipv4, report.ipv6)?;
+                match report.global_v4 {
+                    Some(addr) => writeln!(f, "public ipv4 address: {addr}")?,
+                    None => writeln!(f, "public ipv4 address: unknown")?,
+                }
+                match report.global_v6 {
+                    Some(addr) => writeln!(f, "public ipv6 address: {addr}")?,
+                    None => writeln!(f, "public ipv6 address: unknown")?,
+                }
+                match report.nat_mapping() {
+                    Some(mapping) => writeln!(f, "nat mapping: {mapping}")?,
+                    None => writeln!(f, "nat mapping: unknown")?,
+                }
+                match report.portal() {
+                    Some(portal) => writeln!(f, "captive portal: {portal}")?,
+                    None => writeln!(f, "captive portal: unknown")?,
+                }
+            }
+            None => writeln!(f, "net report: not completed")?,
+        }
+        for relay in &self.relays {
+            match (relay.latency, &relay.error) {
+                (Some(latency), _) => writeln!(f, "relay {}: {latency:?}", relay.url)?,
+                (None, Some(err)) => writeln!(f, "relay {}: unreachable: {err}", relay.url)?,
+                (None, None) => writeln!(f, "relay {}: unreachable", relay.url)?,
+            }
+        }
+        match &self.loopback {
+            Some(Ok(transfer)) => writeln!(
+                f,
+                "loopback transfer: {} bytes in {:?} ({:.2} MiB/s) via {}",
+                transfer.len,
+                transfer.duration,
+                transfer.throughput() / (1024.0 * 1024.0),
+                transfer.conn_type
+            )?,
+            Some(Err(err)) => writeln!(f, "loopback transfer: failed: {err}")?,
+            None => {}
+        }
+        match &self.hole_punch {
+            Some(Ok(report)) => match report.time_to_direct {
+                Some(time) => writeln!(
+                    f,
+                    "hole punching to {}: direct after {time:?} via {}",
+                    report.node_id.fmt_short(),
+                    report.conn_type
+                )?,
+                None => writeln!(
+                    f,
+                    "hole punching to {}: not direct, via {}",
+                    report.node_id.fmt_short(),
+                    report.conn_type
+                )?,
+            },
+            Some(Err(err)) => writeln!(f, "hole punching: failed: {err}")?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::run_relay_server;
+
+    #[tokio::test]
+    async fn test_doctor() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+        let (relay_map, relay_url, _relay) = run_relay_server().await?;
+        let doctor = Doctor::new()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .transfer_size(1024 * 1024);
+
+        let echo_ep = doctor.bind().await?;
+        let echo = Router::builder(echo_ep)
+            .accept(ECHO_ALPN, Echo)
+            .spawn()
+            .await?;
+        let echo_addr = echo.endpoint().node_addr().await?;
+
+        let report = doctor.clone().echo_node(echo_addr).run().await;
+        println!("{report}");
+        assert!(report.error.is_none());
+        assert_eq!(report.relays.len(), 1);
+        assert_eq!(report.relays[0].url, relay_url);
+        assert!(report.relays[0].latency.is_some());
+        let loopback = report.loopback.unwrap().unwrap();
+        assert_eq!(loopback.len, 1024 * 1024);
+        let hole_punch = report.hole_punch.unwrap().unwrap();
+        assert!(hole_punch.is_direct());
+
+        // An unreachable relay server is reported with the error.
+        let unreachable: RelayUrl = "https://127.0.0.1:1".parse()?;
+        let relay = doctor
+            .timeout(Duration::from_secs(1))
+            .probe_relay(&unreachable)
+            .await;
+        assert!(relay.latency.is_none());
+        assert!(relay.error.is_some());
+
+        echo.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reachability() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+        let doctor = Doctor::new().relay_mode(RelayMode::Disabled);
+
+        let helper_endpoint = doctor.bind().await?;
+        let helper = Router::builder(helper_endpoint.clone())
+            .accept(REACHABILITY_ALPN, Reachability::new(helper_endpoint))
+            .spawn()
+            .await?;
+        let helper_addr = helper.endpoint().node_addr().await?;
+
+        // The local addresses are reachable from the helper in the same process.
+        let node_endpoint = doctor.bind().await?;
+        let node = Router::builder(node_endpoint.clone())
+            .accept(REACHABILITY_ALPN, Reachability::new(node_endpoint))
+            .spawn()
+            .await?;
+        let report = doctor
+            .reachability(node.endpoint(), helper_addr.clone())
+            .await?;
+        println!("{report}");
+        assert_eq!(report.node_id, helper_addr.node_id);
+        assert!(!report.addrs.is_empty());
+        assert!(report.reachable().next().is_some());
+
+        // Without accepting the protocol no address is reachable.
+        let endpoint = doctor.bind().await?;
+        let report = doctor.reachability(&endpoint, helper_addr).await?;
+        assert!(!report.addrs.is_empty());
+        assert!(report.reachable().next().is_none());
+        assert!(report.addrs.iter().all(|addr| addr.error.is_some()));
+
+        endpoint.close().await?;
+        node.shutdown().await?;
+        helper.shutdown().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_probe_target() {
+        let remote: SocketAddr = "203.0.113.7:4433".parse().unwrap();
+        assert!(check_probe_target("203.0.113.7:1234".parse().unwrap(), remote).is_ok());
+        assert!(check_probe_target("[::ffff:203.0.113.7]:1234".parse().unwrap(), remote).is_ok());
+        // Other hosts, including private and loopback ones, are not probed.
+        assert!(check_probe_target("203.0.113.8:4433".parse().unwrap(), remote).is_err());
+        assert!(check_probe_target("192.168.1.2:4433".parse().unwrap(), remote).is_err());
+        assert!(check_probe_target("127.0.0.1:4433".parse().unwrap(), remote).is_err());
+        assert!(check_probe_target("[fe80::1]:4433".parse().unwrap(), remote).is_err());
+
+        // A node in the local network may have its private addresses probed.
+        let remote: SocketAddr = "192.168.1.2:4433".parse().unwrap();
+        assert!(check_probe_target("192.168.1.2:1234".parse().unwrap(), remote).is_ok());
+    }
+}
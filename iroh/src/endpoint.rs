@@ -13,38 +13,61 @@
 
 use std::{
     any::Any,
+    collections::BTreeMap,
     future::{Future, IntoFuture},
     net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
     task::Poll,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use derive_more::Debug;
 use futures_lite::{Stream, StreamExt};
 use iroh_base::relay_map::RelayMap;
 use pin_project::pin_project;
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, instrument, trace, warn};
+use tracing::{debug, instrument, trace, warn, Instrument, Span};
 use url::Url;
 
+#[cfg(any(test, feature = "test-utils"))]
+use crate::test_utils::MemoryNetwork;
 use crate::{
     discovery::{
-        dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery, Discovery, DiscoveryTask,
+        dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery, Discovery, DiscoveryItem,
+        DiscoveryStats, DiscoveryTask, NodeAddrStream, UserData,
     },
     dns::{default_resolver, DnsResolver},
     key::{PublicKey, SecretKey},
-    magicsock::{self, Handle, QuicMappedAddr},
+    magicsock::{self, Counters, Handle, QuicMappedAddr},
+    runtime::{self, Runtime, TokioRuntime},
     tls, NodeId, RelayUrl,
 };
 
+mod access;
+mod close;
+mod connect_error;
+mod connect_options;
+mod events;
+mod interop;
+mod limits;
+mod metadata;
+mod peers;
+mod pool;
 mod rtt_actor;
+mod send_rate;
+mod spans;
 
 pub use bytes::Bytes;
 pub use iroh_base::node_addr::{AddrInfo, AddrInfoOptions, NodeAddr};
+pub use net_report::{Config as NetReportConfig, NatMapping, Portal, Report as NetReport};
 // Missing still: SendDatagram and ConnectionClose::frame_type's Type.
+/// An incoming connection from a plain QUIC client, see [`Endpoint::accept_interop`].
+pub use quinn::Incoming as InteropIncoming;
 pub use quinn::{
     AcceptBi, AcceptUni, AckFrequencyConfig, ApplicationClose, Chunk, ClosedStream, Connection,
     ConnectionClose, ConnectionError, ConnectionStats, MtuDiscoveryConfig, OpenBi, OpenUni,
@@ -61,10 +84,28 @@ pub use quinn_proto::{
     FrameStats, PathStats, TransportError, TransportErrorCode, UdpStats, Written,
 };
 
+pub use self::access::AccessPolicy;
+pub(crate) use self::access::SharedAccessPolicy;
+pub use self::close::{CloseSide, ClosedReason, ConnectionExt, ErrorCode};
+pub use self::connect_error::ConnectError;
+pub use self::connect_options::ConnectOptions;
+use self::connect_options::NodePolicies;
+pub use self::events::EndpointEvent;
+pub use self::interop::InteropConfig;
+pub use self::metadata::{NodeMetadata, MAX_APP_METADATA_LEN};
 use self::rtt_actor::RttMessage;
+#[cfg(any(test, feature = "test-utils"))]
+#[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+pub use super::magicsock::Faults;
 pub use super::magicsock::{
-    ConnectionType, ConnectionTypeStream, ControlMsg, DirectAddr, DirectAddrInfo, DirectAddrType,
-    DirectAddrsStream, RemoteInfo, Source,
+    AddrFamilyPolicy, ConnectionType, ConnectionTypeStream, ConnectionTypeWatcher,
+    ConnectivityFailure, ConnectivityPhase, ConnectivityReason, ConnectivityState,
+    ConnectivityTransition, ControlMsg, DirectAddr, DirectAddrFilter, DirectAddrInfo,
+    DirectAddrType, DirectAddrsStream, DiscoCounts, DiscoStats, EndpointMetrics, HolePunchConfig,
+    KeepaliveConfig, LatencyPathSelector, MultipathPolicy, NodePathStats, PacketKind, PacketTap,
+    PathAddr, PathCandidate, PathPolicy, PathSelector, PcapngWriter, PingResult, PortMappingConfig,
+    PortMappingStatus, RelayHealth, RelayKeepaliveConfig, RelayQueueConfig, RelayStatus,
+    RemoteInfo, Source, TapDirection, TapPath, TappedPacket, TrafficStats,
 };
 
 /// The delay to fall back to discovery when direct addresses fail.
@@ -74,6 +115,40 @@ pub use super::magicsock::{
 /// is still no connection the configured [`Discovery`] will be used however.
 const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
 
+/// The error code used to close connections which are still open when the [`Endpoint`] is
+/// closed.
+///
+/// Peers will see the connection closed with [`ConnectionError::ApplicationClosed`] using
+/// this code, or [`ClosedReason::RemoteApplication`] with [`ConnectionExt`].
+pub const ENDPOINT_CLOSED_CODE: VarInt = VarInt::from_u32(0);
+
+/// The error code used to close incoming connections which turn out to exceed the
+/// [`Builder::max_connections_per_node_id`] limit after their handshake.
+///
+/// Peers will see the connection closed with [`ConnectionError::ApplicationClosed`] using
+/// this code, or [`ClosedReason::RemoteApplication`] with [`ConnectionExt`].
+pub const CONNECTION_LIMIT_CODE: VarInt = VarInt::from_u32(1);
+
+/// The default interval of QUIC keep-alives, see [`Builder::keep_alive_interval`].
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The interval of QUIC keep-alives of connections established while the endpoint is paused.
+///
+/// Just short enough to not run into the default idle timeout of 30 seconds.
+const PAUSED_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(25);
+
+/// The delay before starting the next connection attempt in [`Endpoint::connect_any`].
+const CONNECT_ANY_STAGGER: Duration = Duration::from_millis(250);
+
+/// How often to check for remaining connections during [`Endpoint::close_with_grace_period`].
+const GRACE_PERIOD_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The maximum number of keys accepted by [`Builder::previous_secret_keys`].
+///
+/// Messages of nodes which might address us by a previous key are tried against all of
+/// them, so their number is bounded.
+pub const MAX_PREVIOUS_SECRET_KEYS: usize = 8;
+
 type DiscoveryBuilder = Box<dyn FnOnce(&SecretKey) -> Option<Box<dyn Discovery>> + Send + Sync>;
 
 /// Builder for [`Endpoint`].
@@ -85,39 +160,127 @@ type DiscoveryBuilder = Box<dyn FnOnce(&SecretKey) -> Option<Box<dyn Discovery>>
 #[derive(Debug)]
 pub struct Builder {
     secret_key: Option<SecretKey>,
+    previous_secret_keys: Vec<SecretKey>,
     relay_mode: RelayMode,
+    discovery_mode: DiscoveryMode,
     alpn_protocols: Vec<Vec<u8>>,
     transport_config: Option<quinn::TransportConfig>,
+    mtu_discovery: Option<MtuDiscoveryConfig>,
+    keep_alive_interval: Option<Duration>,
     keylog: bool,
     #[debug(skip)]
     discovery: Vec<DiscoveryBuilder>,
+    discovery_user_data: Option<UserData>,
+    discovery_max_age: Option<Duration>,
     proxy_url: Option<Url>,
     /// List of known nodes. See [`Builder::known_nodes`].
     node_map: Option<Vec<NodeAddr>>,
+    /// File to load known nodes from. See [`Builder::restore_peers`].
+    peers_path: Option<PathBuf>,
     dns_resolver: Option<DnsResolver>,
     #[cfg(any(test, feature = "test-utils"))]
     #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
     insecure_skip_relay_cert_verify: bool,
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+    memory_network: Option<MemoryNetwork>,
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+    rng_seed: Option<u64>,
     addr_v4: Option<SocketAddrV4>,
     addr_v6: Option<SocketAddrV6>,
+    ipv4: bool,
+    ipv6: bool,
+    bind_interface: Option<String>,
+    per_interface_sockets: bool,
+    connection_pool_idle_timeout: Option<Duration>,
+    access_policy: AccessPolicy,
+    max_send_rate: Option<u64>,
+    hole_punching: HolePunchConfig,
+    keepalive: KeepaliveConfig,
+    path_selector: Option<Arc<dyn PathSelector>>,
+    addr_family: AddrFamilyPolicy,
+    direct_addr_filter: Option<Arc<dyn DirectAddrFilter>>,
+    external_addrs: Vec<SocketAddr>,
+    port_mapping: PortMappingConfig,
+    ecn: bool,
+    dscp: Option<u8>,
+    segmentation_offload: bool,
+    relay_queue: RelayQueueConfig,
+    relay_keepalive: RelayKeepaliveConfig,
+    standby_relay: bool,
+    net_report: NetReportConfig,
+    net_report_interval: Option<Duration>,
+    packet_tap: Option<Arc<dyn PacketTap>>,
+    runtime: Arc<dyn Runtime>,
+    interop: Option<InteropConfig>,
+    #[cfg(feature = "event-log")]
+    #[debug("Option<Box<dyn Write>>")]
+    event_log: Option<Box<dyn std::io::Write + Send>>,
+    max_incoming_connections: Option<usize>,
+    max_connections_per_node_id: Option<usize>,
+    user_agent: Option<String>,
+    app_metadata: Bytes,
 }
 
 impl Default for Builder {
     fn default() -> Self {
         Self {
             secret_key: Default::default(),
+            previous_secret_keys: Vec::new(),
             relay_mode: default_relay_mode(),
+            discovery_mode: DiscoveryMode::Default,
             alpn_protocols: Default::default(),
             transport_config: Default::default(),
+            mtu_discovery: Some(MtuDiscoveryConfig::default()),
+            keep_alive_interval: Some(DEFAULT_KEEP_ALIVE_INTERVAL),
             keylog: Default::default(),
             discovery: Default::default(),
+            discovery_user_data: None,
+            discovery_max_age: None,
             proxy_url: None,
             node_map: None,
+            peers_path: None,
             dns_resolver: None,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
+            #[cfg(any(test, feature = "test-utils"))]
+            memory_network: None,
+            #[cfg(any(test, feature = "test-utils"))]
+            rng_seed: None,
             addr_v4: None,
             addr_v6: None,
+            ipv4: true,
+            ipv6: true,
+            bind_interface: None,
+            per_interface_sockets: false,
+            connection_pool_idle_timeout: None,
+            access_policy: AccessPolicy::default(),
+            max_send_rate: None,
+            hole_punching: HolePunchConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+            path_selector: None,
+            addr_family: AddrFamilyPolicy::Any,
+            direct_addr_filter: None,
+            external_addrs: Vec::new(),
+            port_mapping: PortMappingConfig::default(),
+            ecn: true,
+            dscp: None,
+            segmentation_offload: true,
+            relay_queue: RelayQueueConfig::default(),
+            relay_keepalive: RelayKeepaliveConfig::default(),
+            standby_relay: false,
+            net_report: NetReportConfig::default(),
+            net_report_interval: None,
+            packet_tap: None,
+            runtime: Arc::new(TokioRuntime),
+            interop: None,
+            #[cfg(feature = "event-log")]
+            event_log: None,
+            max_incoming_connections: None,
+            max_connections_per_node_id: None,
+            user_agent: None,
+            app_metadata: Bytes::new(),
         }
     }
 }
@@ -130,21 +293,71 @@ impl Builder {
 
     /// Binds the magic endpoint.
     pub async fn bind(self) -> Result<Endpoint> {
-        let relay_map = self.relay_mode.relay_map();
-        let secret_key = self.secret_key.unwrap_or_else(SecretKey::generate);
+        ensure!(
+            self.app_metadata.len() <= MAX_APP_METADATA_LEN,
+            "app metadata is longer than {MAX_APP_METADATA_LEN} bytes"
+        );
+        ensure!(
+            self.previous_secret_keys.len() <= MAX_PREVIOUS_SECRET_KEYS,
+            "more than {MAX_PREVIOUS_SECRET_KEYS} previous secret keys"
+        );
+        if let Some(dscp) = self.dscp {
+            ensure!(dscp < 64, "DSCP codepoint {dscp} is larger than 63");
+        }
+        let local_only = self.discovery_mode == DiscoveryMode::LocalOnly;
+        let relay_map = if local_only {
+            RelayMap::empty()
+        } else {
+            self.relay_mode.relay_map()
+        };
+        #[cfg(any(test, feature = "test-utils"))]
+        let mut rng = self.rng_seed.map(StdRng::seed_from_u64);
+        #[cfg(not(any(test, feature = "test-utils")))]
+        let mut rng: Option<StdRng> = None;
+        let secret_key = self.secret_key.unwrap_or_else(|| match rng {
+            Some(ref mut rng) => SecretKey::generate_with_rng(rng),
+            None => SecretKey::generate(),
+        });
+        let mut transport_config = self.transport_config.unwrap_or_default();
+        transport_config.mtu_discovery_config(self.mtu_discovery.clone());
         let static_config = StaticConfig {
-            transport_config: Arc::new(self.transport_config.unwrap_or_default()),
+            transport_config: Arc::new(transport_config),
+            mtu_discovery: self.mtu_discovery,
+            keep_alive_interval: self.keep_alive_interval,
             keylog: self.keylog,
             secret_key: secret_key.clone(),
+            previous_secret_keys: self.previous_secret_keys.clone(),
+            connection_pool_idle_timeout: self.connection_pool_idle_timeout,
+            access_policy: Arc::new(parking_lot::RwLock::new(self.access_policy)),
+            connection_limits: Arc::new(limits::ConnectionLimits::new(
+                self.max_incoming_connections,
+                self.max_connections_per_node_id,
+            )),
+            metadata: NodeMetadata::new(self.user_agent, self.app_metadata),
+            discovery_max_age: self.discovery_max_age,
+            quic_rng_seed: rng.as_mut().map(|rng| rng.gen()),
         };
         let dns_resolver = self
             .dns_resolver
             .unwrap_or_else(|| default_resolver().clone());
-        let discovery = self
-            .discovery
+        let mut discovery_builders = self.discovery;
+        if local_only {
+            if !discovery_builders.is_empty() {
+                debug!("local only mode, ignoring configured discovery services");
+            }
+            discovery_builders = local_discovery();
+        }
+        let discovery = discovery_builders
             .into_iter()
             .filter_map(|f| f(&secret_key))
             .collect::<Vec<_>>();
+        let mut node_map = self.node_map;
+        if let Some(path) = self.peers_path {
+            match peers::load(&path).await {
+                Ok(peers) => node_map.get_or_insert_with(Vec::new).extend(peers),
+                Err(err) => warn!("not restoring peers: {err:#}"),
+            }
+        }
         let discovery: Option<Box<dyn Discovery>> = match discovery.len() {
             0 => None,
             1 => Some(discovery.into_iter().next().unwrap()),
@@ -153,16 +366,52 @@ impl Builder {
         let msock_opts = magicsock::Options {
             addr_v4: self.addr_v4,
             addr_v6: self.addr_v6,
+            ipv4: self.ipv4,
+            ipv6: self.ipv6,
+            bind_interface: self.bind_interface,
+            per_interface_sockets: self.per_interface_sockets,
             secret_key,
+            previous_secret_keys: self.previous_secret_keys,
             relay_map,
-            node_map: self.node_map,
+            node_map,
             discovery,
+            discovery_user_data: self.discovery_user_data,
             proxy_url: self.proxy_url,
             dns_resolver,
+            max_send_rate: self.max_send_rate,
+            hole_punching: self.hole_punching,
+            keepalive: self.keepalive,
+            path_selector: self.path_selector,
+            addr_family: self.addr_family,
+            direct_addr_filter: self.direct_addr_filter,
+            external_addrs: self.external_addrs,
+            port_mapping: match local_only {
+                true => PortMappingConfig::disabled(),
+                false => self.port_mapping,
+            },
+            ecn: self.ecn,
+            dscp: self.dscp,
+            segmentation_offload: self.segmentation_offload,
+            relay_queue: self.relay_queue,
+            relay_keepalive: self.relay_keepalive,
+            standby_relay: self.standby_relay,
+            net_report: self.net_report,
+            net_report_interval: self.net_report_interval,
+            packet_tap: self.packet_tap,
+            rng_seed: rng.as_mut().map(|rng| rng.gen()),
+            runtime: self.runtime,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
+            #[cfg(any(test, feature = "test-utils"))]
+            memory_network: self.memory_network,
         };
-        Endpoint::bind(static_config, msock_opts, self.alpn_protocols).await
+        let ep =
+            Endpoint::bind(static_config, msock_opts, self.alpn_protocols, self.interop).await?;
+        #[cfg(feature = "event-log")]
+        if let Some(writer) = self.event_log {
+            events::spawn_event_log(&ep.events.sender, writer)?;
+        }
+        Ok(ep)
     }
 
     // # The very common methods everyone basically needs.
@@ -189,6 +438,62 @@ impl Builder {
         self
     }
 
+    /// Sets whether to use IPv4, enabled by default.
+    ///
+    /// If disabled no IPv4 socket is bound and nodes can only be reached directly over IPv6,
+    /// binding then fails if no IPv6 socket can be bound.  Relay servers are still
+    /// contacted over whichever family their addresses resolve to.
+    pub fn ipv4(mut self, enabled: bool) -> Self {
+        self.ipv4 = enabled;
+        self
+    }
+
+    /// Sets whether to use IPv6, enabled by default.
+    ///
+    /// If enabled, failing to bind an IPv6 socket is not an error as long as IPv4 is
+    /// enabled, the endpoint then only uses IPv4.  Use [`Endpoint::bound_sockets`] to check
+    /// which families are in use.
+    pub fn ipv6(mut self, enabled: bool) -> Self {
+        self.ipv6 = enabled;
+        self
+    }
+
+    /// Binds to the addresses of the network interface with the given name, e.g. `"wg0"`.
+    ///
+    /// The sockets are bound to the first IPv4 and IPv6 address of the interface instead
+    /// of the unspecified addresses, using the ports of [`Builder::bind_addr_v4`] and
+    /// [`Builder::bind_addr_v6`].  Only these addresses are advertised as local direct
+    /// addresses.  If the interface has no IPv6 address, IPv6 is not used at all.
+    ///
+    /// The sockets, including the connections to relay servers, are also bound to the
+    /// interface itself, so packets leave through it regardless of the routing table.  This
+    /// uses `SO_BINDTODEVICE` on Linux and Android and `IP_BOUND_IF` on macOS and iOS, on
+    /// other platforms binding fails.  The addresses are looked up when binding, if they
+    /// change the endpoint needs to be recreated.
+    ///
+    /// Binding fails if there is no interface with this name or it has no IPv4 address.
+    pub fn bind_interface(mut self, name: impl Into<String>) -> Self {
+        self.bind_interface = Some(name.into());
+        self
+    }
+
+    /// Sets whether to bind an additional socket to each network interface.
+    ///
+    /// On hosts with several network interfaces, e.g. WiFi and a cellular modem, the OS
+    /// sends all traffic of the main sockets through the interface of its default route.
+    /// With this enabled a socket is bound to every address of every interface as well, and
+    /// advertised as a direct address of its own.  Remote nodes then hole punch to each
+    /// interface separately, and keep using the other interfaces when one goes down.  The
+    /// sockets follow changes of the network interfaces.
+    ///
+    /// [`DirectAddr::interface`] tells which interface a direct address belongs to.
+    ///
+    /// Disabled by default.
+    pub fn bind_per_interface(mut self, enabled: bool) -> Self {
+        self.per_interface_sockets = enabled;
+        self
+    }
+
     /// Sets a secret key to authenticate with other peers.
     ///
     /// This secret key's public key will be the [`PublicKey`] of this endpoint and thus
@@ -200,6 +505,31 @@ impl Builder {
         self
     }
 
+    /// Sets previous secret keys which remote nodes may still use to connect to this endpoint.
+    ///
+    /// This allows rotating the identity of a node: the [`Builder::secret_key`] is the new
+    /// key, which is used for all outgoing connections and published by discovery.  Nodes
+    /// connecting to the [`NodeId`] of one of the previous keys, e.g. because they use an
+    /// old ticket, can still do so while the old addressing information drains.
+    ///
+    /// The certificate of this endpoint proves possession of all these keys.  It is only
+    /// sent encrypted, so observers can not link the identities.  Connections made to a
+    /// previous [`NodeId`] report the current [`NodeId`] as the remote node id.
+    ///
+    /// Relay servers only know this endpoint by its current [`NodeId`].  Therefore nodes
+    /// using a previous [`NodeId`] can only connect if they have a working direct address
+    /// of this endpoint.  Nodes running an iroh version without support for this can only
+    /// connect using the current [`NodeId`].  A single remote node should not connect using
+    /// both the current and a previous [`NodeId`], as the direct addresses can only be
+    /// associated with one of them.
+    ///
+    /// At most [`MAX_PREVIOUS_SECRET_KEYS`] keys are supported, otherwise [`Builder::bind`]
+    /// fails.
+    pub fn previous_secret_keys(mut self, keys: impl IntoIterator<Item = SecretKey>) -> Self {
+        self.previous_secret_keys = keys.into_iter().collect();
+        self
+    }
+
     /// Sets the [ALPN] protocols that this endpoint will accept on incoming connections.
     ///
     /// Not setting this will still allow creating connections, but to accept incoming
@@ -233,6 +563,17 @@ impl Builder {
         self
     }
 
+    /// Sets whether nodes are found and reached via the internet or only on the local
+    /// network.
+    ///
+    /// By default [`DiscoveryMode::Default`] is used.  With [`DiscoveryMode::LocalOnly`] the
+    /// endpoint never contacts any server on the internet, regardless of the
+    /// [relay mode](Self::relay_mode) and the configured discovery services.
+    pub fn discovery_mode(mut self, mode: DiscoveryMode) -> Self {
+        self.discovery_mode = mode;
+        self
+    }
+
     /// Removes all discovery services from the builder.
     pub fn clear_discovery(mut self) -> Self {
         self.discovery.clear();
@@ -334,12 +675,40 @@ impl Builder {
     /// configuration options. If you need any of those, you should manually
     /// create a LocalSwarmDiscovery and add it with [`Builder::add_discovery`].
     pub fn discovery_local_network(mut self) -> Self {
-        use crate::discovery::local_swarm_discovery::LocalSwarmDiscovery;
-        self.discovery.push(Box::new(|secret_key| {
-            LocalSwarmDiscovery::new(secret_key.public())
-                .map(|x| Box::new(x) as _)
-                .ok()
-        }));
+        self.discovery.extend(local_discovery());
+        self
+    }
+
+    /// Sets the [`UserData`] published by the discovery services.
+    ///
+    /// The user data is published together with the addresses of this endpoint, resolving
+    /// nodes receive it in [`DiscoveryItem::user_data`].  Only some discovery services
+    /// publish user data, see [`Discovery::publish_with_user_data`].
+    ///
+    /// It can be changed later using [`Endpoint::set_user_data_for_discovery`].
+    ///
+    /// [`DiscoveryItem::user_data`]: crate::discovery::DiscoveryItem::user_data
+    pub fn user_data_for_discovery(mut self, user_data: UserData) -> Self {
+        self.discovery_user_data = Some(user_data);
+        self
+    }
+
+    /// Ignores discovery results which were published longer than `max_age` ago.
+    ///
+    /// Nodes which went offline keep their last published records for a while, dialing
+    /// their stale addresses only delays the connection.  The age is taken from
+    /// [`DiscoveryItem::last_updated`], results without a timestamp are always used.  The
+    /// pkarr and DHT based discovery services report the time at which the node signed
+    /// its record.
+    ///
+    /// By default results of any age are used.  Note that nodes republish their records
+    /// only every few minutes, see [`DEFAULT_REPUBLISH_INTERVAL`], so `max_age` should be
+    /// well above the republish interval.
+    ///
+    /// [`DiscoveryItem::last_updated`]: crate::discovery::DiscoveryItem::last_updated
+    /// [`DEFAULT_REPUBLISH_INTERVAL`]: crate::discovery::pkarr::DEFAULT_REPUBLISH_INTERVAL
+    pub fn discovery_max_age(mut self, max_age: Duration) -> Self {
+        self.discovery_max_age = Some(max_age);
         self
     }
 
@@ -349,6 +718,29 @@ impl Builder {
         self
     }
 
+    /// Restores the known nodes saved by [`Endpoint::save_peers`] from a file.
+    ///
+    /// The nodes are loaded when binding and added to any nodes set using
+    /// [`Builder::known_nodes`], so that they can be dialed right away without waiting for
+    /// discovery.  A missing file is not an error; an unreadable file is logged and
+    /// otherwise ignored.
+    pub fn restore_peers(mut self, path: impl Into<PathBuf>) -> Self {
+        self.peers_path = Some(path.into());
+        self
+    }
+
+    /// Sets the [`AccessPolicy`] deciding which nodes may connect to this endpoint.
+    ///
+    /// Incoming connections from nodes which are not allowed are refused, they are never
+    /// returned from [`Endpoint::accept`].  The policy can be changed later using
+    /// [`Endpoint::set_access_policy`].
+    ///
+    /// By default all nodes are allowed to connect.
+    pub fn access_policy(mut self, access_policy: AccessPolicy) -> Self {
+        self.access_policy = access_policy;
+        self
+    }
+
     // # Methods for more specialist customisation.
 
     /// Sets a custom [`quinn::TransportConfig`] for this endpoint.
@@ -364,6 +756,42 @@ impl Builder {
         self
     }
 
+    /// Configures path MTU discovery for the connections of this endpoint.
+    ///
+    /// QUIC connections start with 1200 byte datagrams and probe for larger datagrams,
+    /// bounded by [`MtuDiscoveryConfig::upper_bound`] and repeated every
+    /// [`MtuDiscoveryConfig::interval`].  When larger datagrams start getting lost, e.g.
+    /// because traffic moved to a relay server, the connection falls back to 1200 bytes and
+    /// only probes again after [`MtuDiscoveryConfig::black_hole_cooldown`].
+    ///
+    /// Setting this to `None` disables path MTU discovery.  This takes precedence over the
+    /// MTU discovery config of [`Builder::transport_config`].  Defaults to
+    /// [`MtuDiscoveryConfig::default`].
+    pub fn mtu_discovery(mut self, config: Option<MtuDiscoveryConfig>) -> Self {
+        self.mtu_discovery = config;
+        self
+    }
+
+    /// Sets the interval of QUIC keep-alives on outgoing connections.
+    ///
+    /// An idle connection sends a keep-alive after `interval` without sending anything else,
+    /// so it does not run into the idle timeout and the remote node notices quickly when it
+    /// goes away.  Setting this to `None` disables keep-alives, idle connections are then
+    /// closed after the idle timeout of the [`Builder::transport_config`].  The interval
+    /// should be shorter than the idle timeout.
+    ///
+    /// Connections established while the endpoint is paused, see [`Endpoint::pause`], only
+    /// send a keep-alive every 25 seconds, unless `interval` is longer, and keep doing so
+    /// after the endpoint is resumed.  Incoming
+    /// connections use the keep-alives of the [`Builder::transport_config`], which are
+    /// disabled by default.
+    ///
+    /// Defaults to 1 second.
+    pub fn keep_alive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive_interval = interval;
+        self
+    }
+
     /// Optionally sets a custom DNS resolver to use for this endpoint.
     ///
     /// The DNS resolver is used to resolve relay hostnames, and node addresses if
@@ -372,6 +800,12 @@ impl Builder {
     /// By default, all endpoints share a DNS resolver, which is configured to use the
     /// host system's DNS configuration. You can pass a custom instance of [`DnsResolver`]
     /// here to use a differently configured DNS resolver for this endpoint.
+    ///
+    /// To replace the built-in resolver entirely, e.g. to only use DNS-over-HTTPS or to
+    /// resolve names deterministically in tests, implement [`Resolver`] and wrap it using
+    /// [`DnsResolver::new`].
+    ///
+    /// [`Resolver`]: crate::dns::Resolver
     pub fn dns_resolver(mut self, dns_resolver: DnsResolver) -> Self {
         self.dns_resolver = Some(dns_resolver);
         self
@@ -394,6 +828,275 @@ impl Builder {
         self
     }
 
+    /// Enables pooling of outgoing connections.
+    ///
+    /// With pooling enabled [`Endpoint::connect`] returns a handle to an existing, still open
+    /// connection to the same [`NodeId`] and ALPN instead of establishing a new connection.
+    /// Concurrent calls for the same node and ALPN wait for a single connection attempt and
+    /// share its connection.  Connections which have not been handed out by
+    /// [`Endpoint::connect`] for longer than `idle_timeout` are evicted from the pool.  An
+    /// evicted connection is closed once all the other handles to it are dropped.
+    ///
+    /// Since pooled connections are shared, closing one with [`Connection::close`] closes
+    /// it for all users.
+    ///
+    /// By default connections are not pooled.
+    pub fn connection_pool(mut self, idle_timeout: Duration) -> Self {
+        self.connection_pool_idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Limits the rate at which this endpoint sends data, in bytes per second.
+    ///
+    /// The limit applies to all data sent by the endpoint combined, over direct paths as well
+    /// as via relay servers.  When the limit is reached connections are held back until
+    /// sending is allowed again, as if the network was congested.  Data sent on multiple
+    /// paths at once, e.g. while a direct connection is being established, counts once for
+    /// each path.
+    ///
+    /// A single connection can be further limited using
+    /// [`Endpoint::connect_with_max_send_rate`].
+    ///
+    /// By default the send rate is not limited.
+    pub fn max_send_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.max_send_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Configures how direct paths to other nodes are established by hole punching.
+    ///
+    /// While no direct path to a node is known, data is sent via the relay server and hole
+    /// punching is attempted.  The [`HolePunchConfig`] controls how often this is retried
+    /// and when to give up and settle on the relay server.
+    pub fn hole_punching(mut self, config: HolePunchConfig) -> Self {
+        self.hole_punching = config;
+        self
+    }
+
+    /// Configures how direct paths to other nodes are kept alive.
+    ///
+    /// Battery-constrained devices can use [`KeepaliveConfig::low_power`] to send fewer
+    /// keepalive packets, at the cost of noticing broken direct paths more slowly.
+    pub fn keepalive(mut self, config: KeepaliveConfig) -> Self {
+        self.keepalive = config;
+        self
+    }
+
+    /// Sets how the direct path used to send to a node is chosen.
+    ///
+    /// When several direct paths to a node work, the [`PathSelector`] chooses which one is
+    /// used based on their round-trip time, jitter and loss.  By default the
+    /// [`LatencyPathSelector`] chooses the path with the lowest round-trip time.
+    pub fn path_selector(mut self, selector: impl PathSelector) -> Self {
+        self.path_selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Sets which address families may be used for direct paths to other nodes.
+    ///
+    /// This allows preferring IPv6 paths over IPv4 paths when both work, or to only use
+    /// one of the families.  Individual connections can override this using
+    /// [`ConnectOptions::addr_family_policy`].  By default [`AddrFamilyPolicy::Any`] is
+    /// used, which uses the path with the lowest latency.
+    pub fn addr_family_policy(mut self, policy: AddrFamilyPolicy) -> Self {
+        self.addr_family = policy;
+        self
+    }
+
+    /// Sets a filter for the direct addresses advertised to other nodes.
+    ///
+    /// The [`DirectAddrFilter`] may remove addresses, e.g. private addresses on a public
+    /// server, or add addresses the endpoint can not discover itself, e.g. a static port
+    /// forwarding on the router.  The filtered addresses are sent to remote nodes, published
+    /// to discovery and returned by [`Endpoint::direct_addresses`].  By default all
+    /// discovered addresses are advertised.
+    pub fn direct_addr_filter(mut self, filter: impl DirectAddrFilter) -> Self {
+        self.direct_addr_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Adds a public address on which this endpoint can be reached.
+    ///
+    /// For hosts behind a port forwarding manually configured on the router.  The address
+    /// is always advertised as a [`DirectAddrType::External`] direct address, also before
+    /// or without STUN discovering the public address.  If an IPv4 address is added, the
+    /// endpoint no longer guesses that the router forwards its local port.
+    ///
+    /// Can be called multiple times to add several addresses.
+    pub fn add_external_address(mut self, addr: SocketAddr) -> Self {
+        if !self.external_addrs.contains(&addr) {
+            self.external_addrs.push(addr);
+        }
+        self
+    }
+
+    /// Configures which protocols are used to request a port mapping from the router.
+    ///
+    /// By default UPnP, NAT-PMP and PCP are all tried.  Use
+    /// [`PortMappingConfig::disabled`] to not request port mappings at all, e.g. on
+    /// networks where the router logs such requests as suspicious.  Port mapping is always
+    /// disabled with [`DiscoveryMode::LocalOnly`].  The state of the mapping can be
+    /// watched with [`Endpoint::watch_port_mapping`].
+    pub fn port_mapping(mut self, config: PortMappingConfig) -> Self {
+        self.port_mapping = config;
+        self
+    }
+
+    /// Sets whether to use Explicit Congestion Notification (ECN) on direct paths.
+    ///
+    /// With ECN enabled, QUIC packets sent directly over UDP are marked as ECN-capable so
+    /// that routers can signal congestion by marking packets instead of dropping them.  QUIC
+    /// verifies the markings survive the path and stops marking packets otherwise.  Packets
+    /// sent via relay servers are never marked.
+    ///
+    /// Enabled by default.
+    pub fn ecn(mut self, enabled: bool) -> Self {
+        self.ecn = enabled;
+        self
+    }
+
+    /// Sets the DSCP codepoint to mark packets sent on direct paths with.
+    ///
+    /// The Differentiated Services Code Point in the IP header lets networks prioritise
+    /// traffic, e.g. `46` for Expedited Forwarding (EF) as used for real-time media.  The
+    /// codepoint must fit in six bits, otherwise [`Builder::bind`] fails.  ECN markings are
+    /// kept alongside it.  Packets sent via relay servers are never marked.
+    ///
+    /// Marked packets are sent one by one, without segmentation offload, and the sockets
+    /// are not rebound after major network changes.  Windows ignores the codepoint unless
+    /// a QoS policy allows it.
+    ///
+    /// Not set by default.
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// Sets whether to use UDP segmentation offload on direct paths.
+    ///
+    /// Where the OS supports it, generic segmentation offload (GSO) hands a batch of QUIC
+    /// datagrams to the kernel in a single system call and generic receive offload (GRO)
+    /// receives a batch of datagrams at once.  This considerably reduces the CPU cost of
+    /// sending and receiving at high throughput.  The `send_gso_*` and `recv_gro_*`
+    /// magicsock metrics show how large the batches are.
+    ///
+    /// Disabling this makes QUIC send and receive single datagrams, which can help to rule
+    /// out driver problems with offloading.
+    ///
+    /// Enabled by default.
+    pub fn segmentation_offload(mut self, enabled: bool) -> Self {
+        self.segmentation_offload = enabled;
+        self
+    }
+
+    /// Configures the queue of datagrams waiting to be sent to relay servers.
+    ///
+    /// By default datagrams are dropped when the queue is full, the `send_relay_dropped_*`
+    /// magicsock metrics count them.  See [`RelayQueueConfig`] for sending with
+    /// backpressure instead.
+    pub fn relay_queue(mut self, config: RelayQueueConfig) -> Self {
+        self.relay_queue = config;
+        self
+    }
+
+    /// Configures how relay connections are kept alive and reconnected.
+    ///
+    /// By default dead relay connections are only noticed when the relay server was silent
+    /// for two minutes.  See [`RelayKeepaliveConfig`] to ping the relay servers and to tune
+    /// the reconnect backoff.  The `relay_reconnect` and `relay_pong_timeout` magicsock
+    /// metrics and [`Endpoint::relay_health`] show how the connections fare.
+    pub fn relay_keepalive(mut self, config: RelayKeepaliveConfig) -> Self {
+        self.relay_keepalive = config;
+        self
+    }
+
+    /// Sets whether to keep a connection to a standby relay server.
+    ///
+    /// The standby relay is the fastest of the configured relay servers apart from the home
+    /// relay.  When the connection to the home relay is lost, the standby relay becomes the
+    /// home relay right away instead of waiting for the home relay to reconnect, and data
+    /// to nodes on the lost relay is sent via the home relay meanwhile.  This costs one more
+    /// relay connection.  See [`Endpoint::watch_relay_status`] for the current relays.
+    ///
+    /// Disabled by default.
+    pub fn standby_relay(mut self, enabled: bool) -> Self {
+        self.standby_relay = enabled;
+        self
+    }
+
+    /// Configures which probes are used to learn about the network conditions.
+    ///
+    /// The results are available from [`Endpoint::net_report`].  Use
+    /// [`NetReportConfig::icmp`] to disable the ICMP probes in environments where opening
+    /// ICMP sockets trips security tooling.
+    pub fn net_report(mut self, config: NetReportConfig) -> Self {
+        self.net_report = config;
+        self
+    }
+
+    /// Sets how often the network conditions are probed.
+    ///
+    /// A new report is also made whenever the network changes.  By default a report is made
+    /// every 20 to 26 seconds, just below the common UDP NAT timeout of 30 seconds, which
+    /// keeps the mappings of the NAT alive.  Longer intervals save traffic, but NAT mappings
+    /// may then expire unnoticed.  How many of these reports probe all relay servers is set
+    /// with [`NetReportConfig::full_report_interval`].
+    pub fn net_report_interval(mut self, interval: Duration) -> Self {
+        self.net_report_interval = Some(interval);
+        self
+    }
+
+    /// Limits the number of incoming connections.
+    ///
+    /// When the limit is reached further incoming connections are refused before any
+    /// state is allocated for them, they are never returned from [`Endpoint::accept`].
+    ///
+    /// A connection counts towards the limit as soon as it is returned from
+    /// [`Endpoint::accept`], while it is still performing its handshake, and stops counting
+    /// if the handshake fails, or once it is closed and all handles to it are dropped.
+    /// Outgoing connections do not count.
+    ///
+    /// By default the number of incoming connections is not limited.
+    pub fn max_incoming_connections(mut self, max: usize) -> Self {
+        self.max_incoming_connections = Some(max);
+        self
+    }
+
+    /// Limits the number of established incoming connections from a single node.
+    ///
+    /// This works like [`Builder::max_incoming_connections`], counting the connections of
+    /// each remote node separately.  Like the [`AccessPolicy`] this can only be enforced
+    /// before the handshake if the endpoint already knows the remote node, which is the
+    /// case for connections arriving via the magic socket.  Otherwise it is checked once
+    /// the handshake authenticated the node, and connections over the limit are closed
+    /// with [`CONNECTION_LIMIT_CODE`].
+    ///
+    /// By default the number of incoming connections per node is not limited.
+    pub fn max_connections_per_node_id(mut self, max: usize) -> Self {
+        self.max_connections_per_node_id = Some(max);
+        self
+    }
+
+    /// Sets the user agent sent to remote nodes when establishing connections.
+    ///
+    /// Remote nodes can read it together with the iroh version of this endpoint using
+    /// [`get_remote_metadata`].  By default no user agent is sent.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Sets application-defined data sent to remote nodes when establishing connections.
+    ///
+    /// Remote nodes can read it using [`get_remote_metadata`].  The data must not be longer
+    /// than [`MAX_APP_METADATA_LEN`], otherwise [`Builder::bind`] fails.  It is sent
+    /// encrypted but before the remote node is authorized, so it should not contain
+    /// secrets.
+    pub fn app_metadata(mut self, data: impl Into<Bytes>) -> Self {
+        self.app_metadata = data.into();
+        self
+    }
+
     /// Enables saving the TLS pre-master key for connections.
     ///
     /// This key should normally remain secret but can be useful to debug networking issues
@@ -406,6 +1109,66 @@ impl Builder {
         self
     }
 
+    /// Sets a hook receiving copies of all packets sent and received.
+    ///
+    /// The [`PacketTap`] sees every UDP datagram and relay frame together with its
+    /// direction, the path and the remote node, which helps to debug hole punching without
+    /// capturing on the network interface.  Use [`PcapngWriter`] to write the packets to a
+    /// file which can be opened in Wireshark, and enable [`Builder::keylog`] to decrypt the
+    /// QUIC packets.
+    pub fn packet_tap(mut self, tap: impl PacketTap) -> Self {
+        self.packet_tap = Some(Arc::new(tap));
+        self
+    }
+
+    /// Sets the async runtime the endpoint spawns its tasks and runs its timers on.
+    ///
+    /// Defaults to [`TokioRuntime`].  Only used in tests until the whole endpoint runs on
+    /// the [`Runtime`], see the [`runtime`] module.
+    #[cfg(test)]
+    pub(crate) fn runtime(mut self, runtime: impl Runtime) -> Self {
+        self.runtime = Arc::new(runtime);
+        self
+    }
+
+    /// Enables connections with plain QUIC peers which are not iroh nodes.
+    ///
+    /// This allows migrating to iroh incrementally: [`Endpoint::connect_interop`] dials
+    /// standard QUIC servers by their socket address, verifying their certificate using
+    /// WebPKI like any QUIC client.  With [`InteropConfig::accept`] the endpoint also
+    /// accepts standard QUIC clients on dedicated ALPNs, see [`Endpoint::accept_interop`].
+    /// These connections do not use a [`NodeId`], relay servers, discovery or hole punching:
+    /// they only work where plain UDP works.
+    ///
+    /// Plain QUIC peers use a separate UDP socket, so they never interfere with iroh nodes.
+    /// Its address is returned by [`Endpoint::interop_local_addr`] and can be fixed with
+    /// [`InteropConfig::bind_addr`].
+    ///
+    /// Disabled by default.
+    pub fn interop(mut self, config: InteropConfig) -> Self {
+        self.interop = Some(config);
+        self
+    }
+
+    /// Writes the [`EndpointEvent`]s as JSON lines to `writer`.
+    ///
+    /// Each line is a JSON object with the type of the event in the `event` field, e.g.
+    /// `path_changed`, `relay_connected`, `discovery_published` or `connection_failed`, and
+    /// the milliseconds since the Unix epoch in `timestamp_ms`.  This does not depend on the
+    /// tracing subscriber, so the events can be ingested into log pipelines on their own.
+    ///
+    /// The lines are written and flushed from a thread of its own, which stops at the first
+    /// error writing.  Wrap files in a [`std::io::BufWriter`] to reduce the number of writes.
+    ///
+    /// Like any subscriber of [`Endpoint::events`] this keeps connections open until they
+    /// are closed explicitly, see [`EndpointEvent::ConnectionClosed`].
+    #[cfg(feature = "event-log")]
+    #[cfg_attr(iroh_docsrs, doc(cfg(feature = "event-log")))]
+    pub fn event_log(mut self, writer: impl std::io::Write + Send + 'static) -> Self {
+        self.event_log = Some(Box::new(writer));
+        self
+    }
+
     /// Skip verification of SSL certificates from relay servers
     ///
     /// May only be used in tests.
@@ -415,29 +1178,117 @@ impl Builder {
         self.insecure_skip_relay_cert_verify = skip_verify;
         self
     }
+
+    /// Exchanges packets over an in-memory network instead of binding UDP sockets.
+    ///
+    /// All endpoints built with the same [`MemoryNetwork`] can reach each other using their
+    /// direct addresses, which makes for fast and deterministic tests of protocols against
+    /// the real [`Endpoint`] API.  The relay servers can not be reached, so this is best
+    /// combined with [`RelayMode::Disabled`].  The bind addresses are ignored.
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+    pub fn memory_network(mut self, network: &MemoryNetwork) -> Self {
+        self.memory_network = Some(network.clone());
+        self
+    }
+
+    /// Derives the randomness of the endpoint from `seed`, to make tests reproducible.
+    ///
+    /// This seeds the QUIC connection IDs and the randomness of QUIC itself, the jitter of
+    /// the interval between net_report probes and the jitter of the backoff between
+    /// attempts to reconnect to relay servers.  Unless set with [`Builder::secret_key`], the
+    /// secret key is derived from the seed as well.  The randomness of the TLS handshakes
+    /// is not affected.
+    ///
+    /// Combined with a [`MemoryNetwork`] seeded with [`MemoryNetwork::with_seed`], a failing
+    /// test can be run again with the same seed to reproduce it.
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
 }
 
 /// Configuration for a [`quinn::Endpoint`] that cannot be changed at runtime.
 #[derive(Debug)]
 struct StaticConfig {
     secret_key: SecretKey,
+    /// See [`Builder::previous_secret_keys`].
+    previous_secret_keys: Vec<SecretKey>,
     transport_config: Arc<quinn::TransportConfig>,
+    /// See [`Builder::mtu_discovery`].
+    mtu_discovery: Option<MtuDiscoveryConfig>,
+    /// See [`Builder::keep_alive_interval`].
+    keep_alive_interval: Option<Duration>,
     keylog: bool,
+    connection_pool_idle_timeout: Option<Duration>,
+    /// Shared with the TLS config, so it can be updated without recreating the latter.
+    access_policy: SharedAccessPolicy,
+    connection_limits: Arc<limits::ConnectionLimits>,
+    /// Sent to remote nodes in the handshake of every connection.
+    metadata: NodeMetadata,
+    /// See [`Builder::discovery_max_age`].
+    discovery_max_age: Option<Duration>,
+    /// Seeds the randomness of QUIC, see [`Builder::rng_seed`].
+    quic_rng_seed: Option<u64>,
 }
 
 impl StaticConfig {
     /// Create a [`quinn::ServerConfig`] with the specified ALPN protocols.
     fn create_server_config(&self, alpn_protocols: Vec<Vec<u8>>) -> Result<ServerConfig> {
-        let server_config = make_server_config(
+        let quic_server_config = tls::make_server_config_with_access_policy(
             &self.secret_key,
+            &self.previous_secret_keys,
             alpn_protocols,
-            self.transport_config.clone(),
             self.keylog,
+            Some(self.access_policy.clone()),
+            Some(&self.metadata),
         )?;
+        let mut server_config = ServerConfig::with_crypto(Arc::new(quic_server_config));
+        server_config.transport_config(self.transport_config.clone());
         Ok(server_config)
     }
 }
 
+/// The length of the connection IDs generated by [`SeededCidGenerator`], the same as
+/// quinn's default.
+const SEEDED_CID_LEN: usize = 8;
+
+/// Generates QUIC connection IDs from a seeded RNG, see [`Builder::rng_seed`].
+#[derive(Debug)]
+struct SeededCidGenerator {
+    rng: StdRng,
+}
+
+impl SeededCidGenerator {
+    fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl quinn_proto::ConnectionIdGenerator for SeededCidGenerator {
+    fn generate_cid(&mut self) -> quinn_proto::ConnectionId {
+        let mut bytes = [0u8; SEEDED_CID_LEN];
+        self.rng.fill_bytes(&mut bytes);
+        quinn_proto::ConnectionId::new(&bytes)
+    }
+
+    fn cid_len(&self) -> usize {
+        SEEDED_CID_LEN
+    }
+
+    fn cid_lifetime(&self) -> Option<Duration> {
+        None
+    }
+}
+
 /// Creates a [`ServerConfig`] with the given secret key and limits.
 // This return type can not longer be used anywhere in our public API.  It is however still
 // used by iroh::node::Node (or rather iroh::node::Builder) to create a plain Quinn
@@ -481,9 +1332,17 @@ pub fn make_server_config(
 pub struct Endpoint {
     msock: Handle,
     endpoint: quinn::Endpoint,
+    /// The QUIC endpoint for plain QUIC peers, see [`Builder::interop`].
+    interop: Option<Arc<interop::InteropEndpoint>>,
     rtt_actor: Arc<rtt_actor::RttHandle>,
+    events: Arc<events::EventsHandle>,
     cancel_token: CancellationToken,
     static_config: Arc<StaticConfig>,
+    pool: Option<Arc<pool::ConnectionPool>>,
+    /// The path policies of the open outgoing connections.
+    policies: Arc<connect_options::ConnectionPolicies>,
+    /// Results of the discovery services, by provenance.
+    discovery_stats: Arc<parking_lot::Mutex<BTreeMap<&'static str, DiscoveryStats>>>,
 }
 
 impl Endpoint {
@@ -507,6 +1366,7 @@ impl Endpoint {
         static_config: StaticConfig,
         msock_opts: magicsock::Options,
         initial_alpns: Vec<Vec<u8>>,
+        interop: Option<InteropConfig>,
     ) -> Result<Self> {
         let msock = magicsock::MagicSock::spawn(msock_opts).await?;
         trace!("created magicsock");
@@ -520,21 +1380,44 @@ impl Endpoint {
         // through to quinn. We set the first byte of the packet to zero, which makes quinn ignore
         // the packet if grease_quic_bit is set to false.
         endpoint_config.grease_quic_bit(false);
+        if let Some(seed) = static_config.quic_rng_seed {
+            let mut rng = StdRng::seed_from_u64(seed);
+            endpoint_config.rng_seed(Some(rng.gen()));
+            let cid_seed: u64 = rng.gen();
+            endpoint_config.cid_generator(move || Box::new(SeededCidGenerator::new(cid_seed)));
+        }
 
         let endpoint = quinn::Endpoint::new_with_abstract_socket(
             endpoint_config,
             Some(server_config),
             Arc::new(msock.clone()),
-            Arc::new(quinn::TokioRuntime),
+            msock.runtime().quinn_runtime(),
         )?;
         trace!("created quinn endpoint");
+        let interop = match interop {
+            Some(config) => Some(Arc::new(interop::InteropEndpoint::new(
+                config,
+                msock.runtime().quinn_runtime(),
+                static_config.transport_config.clone(),
+            )?)),
+            None => None,
+        };
+        let pool = static_config
+            .connection_pool_idle_timeout
+            .map(|idle_timeout| Arc::new(pool::ConnectionPool::new(idle_timeout)));
+        let events = events::EventsHandle::new(&msock);
         debug!(version = env!("CARGO_PKG_VERSION"), "iroh Endpoint created");
         Ok(Self {
             msock,
             endpoint,
-            rtt_actor: Arc::new(rtt_actor::RttHandle::new()),
+            interop,
+            rtt_actor: Arc::new(rtt_actor::RttHandle::new(events.sender.clone())),
+            events: Arc::new(events),
             cancel_token: CancellationToken::new(),
             static_config: Arc::new(static_config),
+            pool,
+            policies: Default::default(),
+            discovery_stats: Default::default(),
         })
     }
 
@@ -548,6 +1431,19 @@ impl Endpoint {
         Ok(())
     }
 
+    /// Replaces the [`AccessPolicy`] deciding which nodes may connect to this endpoint.
+    ///
+    /// This only affects new incoming connections, already established connections are
+    /// not closed.
+    pub fn set_access_policy(&self, access_policy: AccessPolicy) {
+        *self.static_config.access_policy.write() = access_policy;
+    }
+
+    /// Returns the current [`AccessPolicy`] of this endpoint.
+    pub fn access_policy(&self) -> AccessPolicy {
+        self.static_config.access_policy.read().clone()
+    }
+
     // # Methods for establishing connectivity.
 
     /// Connects to a remote [`Endpoint`].
@@ -572,22 +1468,189 @@ impl Endpoint {
     /// The `alpn`, or application-level protocol identifier, is also required. The remote
     /// endpoint must support this `alpn`, otherwise the connection attempt will fail with
     /// an error.
+    ///
+    /// If connection pooling is enabled using [`Builder::connection_pool`], an existing open
+    /// connection to the same node using the same `alpn` is returned instead of creating a
+    /// new connection.
+    ///
+    /// With the `connection-spans` cargo feature, connection attempts and established
+    /// connections are traced with spans which can be exported to OpenTelemetry using
+    /// `tracing-opentelemetry`.  They record the remote node, the ALPN and the path in use,
+    /// and cover discovery, the handshake, hole punching and falling back to the relay.
+    ///
+    /// See [`ConnectError`] for the ways in which connecting can fail.
+    pub async fn connect(
+        &self,
+        node_addr: impl Into<NodeAddr>,
+        alpn: &[u8],
+    ) -> Result<Connection, ConnectError> {
+        self.connect_inner(node_addr.into(), alpn, ConnectOptions::default())
+            .await
+    }
+
+    /// Connects to a remote [`Endpoint`] using the given [`ConnectOptions`].
+    ///
+    /// This works like [`Endpoint::connect`], the options allow e.g. to only ever use the
+    /// relay server to talk to the node, so that it never learns our IP address, or to never
+    /// use the relay server to send data.  See [`ConnectOptions`] for all options.
+    pub async fn connect_with_opts(
+        &self,
+        node_addr: impl Into<NodeAddr>,
+        alpn: &[u8],
+        options: ConnectOptions,
+    ) -> Result<Connection, ConnectError> {
+        self.connect_inner(node_addr.into(), alpn, options).await
+    }
+
+    /// Connects to whichever of several remote nodes can be reached first.
+    ///
+    /// Connection attempts are started in the order of `node_addrs`, in the style of "happy
+    /// eyeballs": the next attempt is started when the previous one failed, or after a short
+    /// delay if it did not complete yet.  The first connection established is returned and
+    /// all other attempts are cancelled.
+    ///
+    /// This is useful when the same content or service is provided by several nodes and any
+    /// one of them will do.  Such a set of nodes can be shared as a single
+    /// [`iroh_base::ticket::NodesTicket`].  See [`Endpoint::connect`] for how each individual
+    /// node is dialed.
+    ///
+    /// If all attempts fail the error of the last failed attempt is returned.
+    pub async fn connect_any(
+        &self,
+        node_addrs: &[NodeAddr],
+        alpn: &[u8],
+    ) -> Result<Connection, ConnectError> {
+        let mut candidates = node_addrs.iter().cloned().peekable();
+        // Dropping the JoinSet aborts any attempts still in progress.
+        let mut attempts = JoinSet::new();
+        let mut last_err = None;
+        loop {
+            if let Some(node_addr) = candidates.next() {
+                let ep = self.clone();
+                let alpn = alpn.to_vec();
+                attempts.spawn(async move { ep.connect(node_addr, &alpn).await });
+            }
+            let res = if candidates.peek().is_some() {
+                match tokio::time::timeout(CONNECT_ANY_STAGGER, attempts.join_next()).await {
+                    Ok(res) => res,
+                    // Give the next candidate a chance as well.
+                    Err(_elapsed) => continue,
+                }
+            } else {
+                attempts.join_next().await
+            };
+            match res {
+                Some(Ok(Ok(conn))) => return Ok(conn),
+                Some(Ok(Err(err))) => {
+                    debug!("connection attempt failed: {err:#}");
+                    last_err = Some(err);
+                }
+                Some(Err(err)) => {
+                    if err.is_panic() {
+                        std::panic::resume_unwind(err.into_panic());
+                    }
+                }
+                None => return Err(last_err.unwrap_or(ConnectError::NoNodes)),
+            }
+        }
+    }
+
+    /// Connects to a remote [`Endpoint`], limiting the rate at which the new connection
+    /// sends data.
+    ///
+    /// This works like [`Endpoint::connect`], except that the connection sends at most
+    /// `bytes_per_sec` bytes per second.  The limit is enforced by capping the congestion
+    /// window of the connection, so it is approximate: it is adjusted to the measured
+    /// round trip time but never drops below two packets per round trip.
+    ///
+    /// A limit configured using [`Builder::max_send_rate`] still applies to the connection
+    /// as well.  The connection is never taken from or added to the connection pool.
+    pub async fn connect_with_max_send_rate(
+        &self,
+        node_addr: impl Into<NodeAddr>,
+        alpn: &[u8],
+        bytes_per_sec: u64,
+    ) -> Result<Connection, ConnectError> {
+        let options = ConnectOptions::new().max_send_rate(bytes_per_sec);
+        self.connect_inner(node_addr.into(), alpn, options).await
+    }
+
     #[instrument(skip_all, fields(me = %self.node_id().fmt_short(), alpn = ?String::from_utf8_lossy(alpn)))]
-    pub async fn connect(&self, node_addr: impl Into<NodeAddr>, alpn: &[u8]) -> Result<Connection> {
-        let node_addr = node_addr.into();
+    async fn connect_inner(
+        &self,
+        node_addr: NodeAddr,
+        alpn: &[u8],
+        options: ConnectOptions,
+    ) -> Result<Connection, ConnectError> {
         tracing::Span::current().record("remote", node_addr.node_id.fmt_short());
+        let node_id = node_addr.node_id;
+        let span = spans::connect(node_id, alpn);
+        let conn = self
+            .connect_with_span(node_addr, alpn, options, &span)
+            .instrument(span.clone())
+            .await;
+        match &conn {
+            Ok(_) => spans::established(&span, self, node_id, alpn),
+            Err(err) => {
+                spans::failed(&span, err);
+                self.emit(EndpointEvent::ConnectionFailed {
+                    node_id: Some(node_id),
+                    incoming: false,
+                    error: err.to_string(),
+                });
+            }
+        }
+        conn
+    }
+
+    /// Connects to the node, with `span` being the span of this connection attempt.
+    async fn connect_with_span(
+        &self,
+        node_addr: NodeAddr,
+        alpn: &[u8],
+        options: ConnectOptions,
+        span: &Span,
+    ) -> Result<Connection, ConnectError> {
         // Connecting to ourselves is not supported.
         if node_addr.node_id == self.node_id() {
-            bail!(
-                "Connecting to ourself is not supported ({} is the node id of this node)",
-                node_addr.node_id.fmt_short()
-            );
+            return Err(ConnectError::SelfConnect(node_addr.node_id));
         }
 
         if !node_addr.info.is_empty() {
-            self.add_node_addr(node_addr.clone())?;
+            self.add_node_addr(node_addr.clone()).map_err(|source| {
+                ConnectError::InvalidNodeAddr {
+                    node_id: node_addr.node_id,
+                    source,
+                }
+            })?;
         }
 
+        // Kept for as long as the connection is open, see `ConnectOptions::path_policy`.
+        let policy = self
+            .policies
+            .reserve(node_addr.node_id, options.policies())
+            .map_err(|_| ConnectError::PolicyConflict(node_addr.node_id))?;
+        let policies = policy.policies();
+        // The policies are set again once discovery found the node, if it is not yet known.
+        self.set_policies(node_addr.node_id, &policies);
+
+        // Connections with their own send rate limit are not shared.
+        let max_send_rate = options.max_send_rate;
+        let pool = self.pool.as_ref().filter(|_| max_send_rate.is_none());
+        // Held until the connection is pooled, so concurrent calls reuse it.
+        let _dial_guard = match pool {
+            Some(pool) => {
+                let guard = pool.lock_dial(node_addr.node_id, alpn).await;
+                if let Some(conn) = pool.get(node_addr.node_id, alpn) {
+                    debug!("reusing pooled connection");
+                    policy.commit(conn.weak_handle());
+                    return Ok(conn);
+                }
+                Some(guard)
+            }
+            None => None,
+        };
+
         let NodeAddr { node_id, info } = node_addr.clone();
 
         // Get the mapped IPv6 address from the magic socket. Quinn will connect to this address.
@@ -595,13 +1658,9 @@ impl Endpoint {
         // address information for this node.
         let (addr, discovery) = self
             .get_mapping_addr_and_maybe_start_discovery(node_addr)
-            .await
-            .with_context(|| {
-                format!(
-                    "No addressing information for NodeId({}), unable to connect",
-                    node_id.fmt_short()
-                )
-            })?;
+            .instrument(spans::discovery(span))
+            .await?;
+        self.set_policies(node_id, &policies);
 
         debug!(
             "connecting to {}: (via {} - {:?})",
@@ -610,16 +1669,47 @@ impl Endpoint {
 
         // Start connecting via quinn. This will time out after 10 seconds if no reachable address
         // is available.
-        let conn = self.connect_quinn(node_id, alpn, addr).await;
+        let handshake = spans::handshake(span);
+        let conn = self
+            .connect_quinn(node_id, alpn, addr, max_send_rate, &handshake)
+            .instrument(handshake.clone())
+            .await;
 
         // Cancel the node discovery task (if still running).
         if let Some(discovery) = discovery {
             discovery.cancel();
         }
 
+        let conn = conn.map_err(|err| match err {
+            ConnectError::Timeout if policies.path == PathPolicy::DirectOnly => {
+                ConnectError::NoDirectPath(node_id)
+            }
+            ConnectError::Timeout => self.relay_unreachable(node_id).unwrap_or(err),
+            err => err,
+        });
+
+        if let Ok(conn) = &conn {
+            policy.commit(conn.weak_handle());
+            if let Some(pool) = pool {
+                pool.insert(node_id, alpn, conn.clone());
+            }
+        }
+
         conn
     }
 
+    /// Applies the `policies` to the node.
+    ///
+    /// The `policies` combine the options of the connection with those of the open
+    /// connections to the node.
+    fn set_policies(&self, node_id: NodeId, policies: &NodePolicies) {
+        self.msock.set_path_policy(node_id, policies.path);
+        self.msock
+            .set_multipath_policy(node_id, policies.multipath.unwrap_or_default());
+        self.msock
+            .set_addr_family_policy(node_id, policies.addr_family);
+    }
+
     /// Connects to a remote endpoint, using just the nodes's [`NodeId`].
     ///
     /// This is a convenience function for [`Endpoint::connect`].  It relies on addressing
@@ -632,7 +1722,32 @@ impl Endpoint {
     )]
     pub async fn connect_by_node_id(&self, node_id: NodeId, alpn: &[u8]) -> Result<Connection> {
         let addr = NodeAddr::new(node_id);
-        self.connect(addr, alpn).await
+        Ok(self.connect(addr, alpn).await?)
+    }
+
+    /// Returns [`ConnectError::RelayUnreachable`] if the node could only be dialed via its
+    /// relay server and nothing was ever received from it this way.
+    fn relay_unreachable(&self, node_id: NodeId) -> Option<ConnectError> {
+        let info = self.remote_info(node_id)?;
+        let relay = info.relay_url?;
+        let nothing_direct = info.last_received().is_none();
+        (nothing_direct && relay.last_alive.is_none()).then_some(ConnectError::RelayUnreachable {
+            node_id,
+            relay_url: relay.relay_url,
+        })
+    }
+
+    /// The interval of QUIC keep-alives for a new outgoing connection.
+    ///
+    /// While paused the interval is stretched to [`PAUSED_KEEP_ALIVE_INTERVAL`], QUIC can not
+    /// change it once the connection is established.
+    fn keep_alive_interval(&self) -> Option<Duration> {
+        let interval = self.static_config.keep_alive_interval?;
+        if self.is_paused() {
+            Some(interval.max(PAUSED_KEEP_ALIVE_INTERVAL))
+        } else {
+            Some(interval)
+        }
     }
 
     #[instrument(
@@ -644,19 +1759,28 @@ impl Endpoint {
         node_id: NodeId,
         alpn: &[u8],
         addr: QuicMappedAddr,
-    ) -> Result<Connection> {
+        max_send_rate: Option<u64>,
+        span: &Span,
+    ) -> Result<Connection, ConnectError> {
         debug!("Attempting connection...");
         let client_config = {
             let alpn_protocols = vec![alpn.to_vec()];
-            let quic_client_config = tls::make_client_config(
+            let quic_client_config = tls::make_client_config_with_metadata(
                 &self.static_config.secret_key,
                 Some(node_id),
                 alpn_protocols,
                 self.static_config.keylog,
+                Some(&self.static_config.metadata),
             )?;
             let mut client_config = quinn::ClientConfig::new(Arc::new(quic_client_config));
             let mut transport_config = quinn::TransportConfig::default();
-            transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
+            transport_config.keep_alive_interval(self.keep_alive_interval());
+            transport_config.mtu_discovery_config(self.static_config.mtu_discovery.clone());
+            if let Some(bytes_per_sec) = max_send_rate {
+                transport_config.congestion_controller_factory(Arc::new(
+                    send_rate::SendRateControllerFactory::new(bytes_per_sec),
+                ));
+            }
             client_config.transport_config(Arc::new(transport_config));
             client_config
         };
@@ -666,18 +1790,24 @@ impl Endpoint {
             .endpoint
             .connect_with(client_config, addr.0, "localhost")?;
 
-        let connection = connect
-            .await
-            .context("failed connecting to remote endpoint")?;
-
-        let rtt_msg = RttMessage::NewConnection {
-            connection: connection.weak_handle(),
-            conn_type_changes: self.conn_type_stream(node_id)?,
-            node_id,
-        };
-        if let Err(err) = self.rtt_actor.msg_tx.send(rtt_msg).await {
-            // If this actor is dead, that's not great but we can still function.
-            warn!("rtt-actor not reachable: {err:#}");
+        let connection = connect.await?;
+        Counters::add(&self.msock.counters().connections_dialed, 1);
+
+        match self.conn_type_stream(node_id) {
+            Ok(conn_type_changes) => {
+                let rtt_msg = RttMessage::NewConnection {
+                    connection: connection.clone(),
+                    conn_type_changes,
+                    node_id,
+                    alpn: alpn.to_vec(),
+                    span: span.clone(),
+                };
+                if let Err(err) = self.rtt_actor.msg_tx.send(rtt_msg).await {
+                    // If this actor is dead, that's not great but we can still function.
+                    warn!("rtt-actor not reachable: {err:#}");
+                }
+            }
+            Err(err) => warn!("failed to create conn_type_stream: {err:#}"),
         }
         debug!("Connection established");
         Ok(connection)
@@ -689,15 +1819,62 @@ impl Endpoint {
     /// If multiple ALPNs have been configured the ALPN can be inspected before accepting
     /// the connection using [`Connecting::alpn`].
     ///
+    /// Incoming connections from nodes denied by the [`AccessPolicy`] are refused and not
+    /// returned.
+    ///
     /// The returned future will yield `None` if the endpoint is closed by calling
     /// [`Endpoint::close`].
     pub fn accept(&self) -> Accept<'_> {
         Accept {
             inner: self.endpoint.accept(),
+            endpoint: &self.endpoint,
             ep: self.clone(),
         }
     }
 
+    /// Connects to a plain QUIC server which is not an iroh node.
+    ///
+    /// The server at `addr` must present a certificate valid for `server_name` and accept
+    /// the `alpn`.  The connection is made directly over UDP, without relay servers or
+    /// discovery.  Requires [`Builder::interop`].
+    pub async fn connect_interop(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+        alpn: &[u8],
+    ) -> Result<Connection> {
+        let Some(ref interop) = self.interop else {
+            bail!("interop is not enabled, see Builder::interop");
+        };
+        interop.connect(addr, server_name, alpn).await
+    }
+
+    /// Accepts an incoming connection from a plain QUIC client.
+    ///
+    /// Only clients using one of the ALPNs of [`InteropConfig::accept`] can connect, on the
+    /// address returned by [`Endpoint::interop_local_addr`].
+    ///
+    /// Returns `None` if the endpoint is closed, or immediately if it does not accept plain
+    /// QUIC clients.
+    pub async fn accept_interop(&self) -> Option<InteropIncoming> {
+        let interop = self.interop.as_ref()?;
+        if !interop.accepts_incoming() {
+            return None;
+        }
+        interop.accept().await
+    }
+
+    /// Returns the address of the UDP socket used for plain QUIC peers.
+    ///
+    /// Returns `None` if interop is not enabled, see [`Builder::interop`].
+    pub fn interop_local_addr(&self) -> Option<SocketAddr> {
+        let interop = self.interop.as_ref()?;
+        interop
+            .local_addr()
+            .inspect_err(|err| warn!("failed to get interop address: {err:#}"))
+            .ok()
+    }
+
     // # Methods for manipulating the internal state about other nodes.
 
     /// Informs this [`Endpoint`] about addresses of the iroh node.
@@ -718,6 +1895,18 @@ impl Endpoint {
         self.add_node_addr_inner(node_addr, magicsock::Source::App)
     }
 
+    /// Removes all information about a remote node from this [`Endpoint`].
+    ///
+    /// This evicts the node from the nodes listed by [`Endpoint::remote_info_iter`], together
+    /// with all its addresses and path state.  The node is added again when connecting to it,
+    /// or when it contacts this endpoint.  Connections to the node which are still open lose
+    /// their paths and will likely fail, so close them first.
+    ///
+    /// Returns `false` if the node was not known.
+    pub fn forget_node(&self, node_id: NodeId) -> bool {
+        self.msock.forget_node(node_id)
+    }
+
     /// Informs this [`Endpoint`] about addresses of the iroh node, noting the source.
     ///
     /// This updates the local state for the remote node.  If the provided [`NodeAddr`] contains a
@@ -743,7 +1932,46 @@ impl Endpoint {
         )
     }
 
+    /// Informs this [`Endpoint`] about addresses of the iroh node, which the source last saw
+    /// `age` ago.
+    ///
+    /// This works like [`Endpoint::add_node_addr_with_source`], but records the addresses as
+    /// seen `age` ago instead of now, e.g. the [age] of a discovery result or of a ticket.
+    ///
+    /// The addresses of a node learned from all sources are merged.  When connecting, the
+    /// endpoint first tries the addresses which were recently in use, then those whose pings
+    /// were answered more often, then the most recently reported ones.  Older information
+    /// also does not replace a relay URL which was reported more recently.
+    ///
+    /// [age]: crate::discovery::DiscoveryItem::age
+    pub fn add_node_addr_with_age(
+        &self,
+        node_addr: NodeAddr,
+        source: &'static str,
+        age: Duration,
+    ) -> Result<()> {
+        let now = Instant::now();
+        // Only fails if the age reaches back before the monotonic clock started.
+        let seen_at = now.checked_sub(age).unwrap_or(now);
+        self.add_node_addr_inner_seen_at(
+            node_addr,
+            magicsock::Source::NamedApp {
+                name: source.into(),
+            },
+            seen_at,
+        )
+    }
+
     fn add_node_addr_inner(&self, node_addr: NodeAddr, source: magicsock::Source) -> Result<()> {
+        self.add_node_addr_inner_seen_at(node_addr, source, Instant::now())
+    }
+
+    fn add_node_addr_inner_seen_at(
+        &self,
+        node_addr: NodeAddr,
+        source: magicsock::Source,
+        seen_at: Instant,
+    ) -> Result<()> {
         // Connecting to ourselves is not supported.
         if node_addr.node_id == self.node_id() {
             bail!(
@@ -751,7 +1979,7 @@ impl Endpoint {
                 node_addr.node_id.fmt_short()
             );
         }
-        self.msock.add_node_addr(node_addr, source)
+        self.msock.add_node_addr_seen_at(node_addr, source, seen_at)
     }
 
     // # Getter methods for properties of this Endpoint itself.
@@ -804,6 +2032,91 @@ impl Endpoint {
         self.msock.my_relay()
     }
 
+    /// Returns the state of the port mapping requested from the router.
+    ///
+    /// See [`Builder::port_mapping`] to choose the protocols used.
+    pub fn port_mapping_status(&self) -> PortMappingStatus {
+        self.msock.port_mapping_status()
+    }
+
+    /// Watches for changes to the port mapping requested from the router.
+    ///
+    /// The current [`PortMappingStatus`] is yielded immediately as the first item in the
+    /// stream.  A [`PortMappingStatus::Lost`] mapping indicates that renewing it failed, e.g.
+    /// because the router restarted.
+    pub fn watch_port_mapping(&self) -> impl Stream<Item = PortMappingStatus> {
+        self.msock.watch_port_mapping()
+    }
+
+    /// Returns the home relay and the standby relay of this endpoint.
+    ///
+    /// The standby relay is only used if enabled with [`Builder::standby_relay`].
+    pub fn relay_status(&self) -> RelayStatus {
+        self.msock.relay_status()
+    }
+
+    /// Watches for changes to the home relay and the standby relay.
+    ///
+    /// The current [`RelayStatus`] is yielded immediately as the first item in the stream.
+    /// When the connection to the home relay is lost the standby relay takes over, which
+    /// shows as the previous standby relay becoming the [`RelayStatus::home`] relay.
+    pub fn watch_relay_status(&self) -> impl Stream<Item = RelayStatus> {
+        self.msock.watch_relay_status()
+    }
+
+    /// Returns the health of the connections to relay servers.
+    ///
+    /// Connections which were closed, e.g. because they were idle, are not included.
+    pub fn relay_health(&self) -> BTreeMap<RelayUrl, RelayHealth> {
+        self.msock.relay_health()
+    }
+
+    /// Switches the home relay to the relay server at `url`.
+    ///
+    /// The home relay is normally chosen automatically: it is re-evaluated on every
+    /// periodic probe of the configured relay servers, and moves to another server when the
+    /// current one becomes unreachable or when another one is consistently much faster.
+    /// This forces a switch right away, after which the same rules apply to the new home
+    /// relay.
+    ///
+    /// On every change the new home relay is published to the configured discovery
+    /// services.  Connections to the previous home relay stay open while they are in use,
+    /// so nodes which currently reach this endpoint through it are not disrupted.
+    ///
+    /// Returns an error if `url` is not one of the relay servers configured with
+    /// [`Builder::relay_mode`].
+    pub async fn set_home_relay(&self, url: RelayUrl) -> Result<()> {
+        self.msock.set_home_relay(url).await
+    }
+
+    /// Returns the last report about the network conditions of this endpoint.
+    ///
+    /// The report is updated periodically and whenever the network changes, by probing the
+    /// configured relay servers.  Among other things it tells whether UDP works, whether
+    /// IPv6 is available, the latency to each relay server, whether a captive portal was
+    /// detected, the public addresses of this endpoint and how its NAT behaves, see
+    /// [`NetReport::nat_mapping`] and [`NetReport::hair_pinning`].  Applications can use
+    /// this to predict whether hole punching with another node is likely to succeed, see
+    /// [`NatMapping::hole_punching_likely`].
+    ///
+    /// Returns `None` until the first report completed, and if relays are disabled.  Use
+    /// [`Endpoint::net_report_stream`] to be notified of new reports.
+    pub fn net_report(&self) -> Option<Arc<NetReport>> {
+        self.msock.net_report()
+    }
+
+    /// Watches for changes to the network conditions of this endpoint.
+    ///
+    /// If a report completed already it is yielded immediately as the first item in the
+    /// stream, after that each new report is yielded as it completes, unless it is
+    /// identical to the previous one.  See [`Endpoint::net_report`] for what the report
+    /// contains.
+    ///
+    /// If relays are disabled no reports are made and this stream never yields an item.
+    pub fn net_report_stream(&self) -> impl Stream<Item = Arc<NetReport>> {
+        self.msock.watch_net_report()
+    }
+
     /// Watches for changes to the home relay.
     ///
     /// If there is currently a home relay it will be yielded immediately as the first item
@@ -817,6 +2130,28 @@ impl Endpoint {
         self.msock.watch_home_relay()
     }
 
+    /// Returns a stream of [`EndpointEvent`]s.
+    ///
+    /// The stream reports connections being established, failing and closed, changes of the
+    /// path used to reach remote nodes with open connections, changes of the home relay and
+    /// direct addresses of this endpoint, connections to relay servers, publishing the
+    /// addresses to discovery, and captive portals on the network.
+    ///
+    /// Only events emitted after calling this are yielded.  If the stream is not consumed
+    /// quickly enough, the oldest events are dropped.  The stream ends once the [`Endpoint`]
+    /// and all its clones are dropped.
+    ///
+    /// While subscribed, connections are kept open until they are closed explicitly, see
+    /// [`EndpointEvent::ConnectionClosed`].
+    pub fn events(&self) -> impl Stream<Item = EndpointEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends an event to the subscribers of [`Endpoint::events`], if any.
+    fn emit(&self, event: EndpointEvent) {
+        self.events.sender.send(event).ok();
+    }
+
     /// Returns the direct addresses of this [`Endpoint`].
     ///
     /// The direct addresses of the [`Endpoint`] are those that could be used by other
@@ -855,11 +2190,13 @@ impl Endpoint {
         self.msock.direct_addresses()
     }
 
-    /// Returns the local socket addresses on which the underlying sockets are bound.
+    /// Returns the local IPv4 and IPv6 socket addresses on which the underlying sockets are
+    /// bound.
     ///
-    /// The [`Endpoint`] always binds on an IPv4 address and also tries to bind on an IPv6
-    /// address if available.
-    pub fn bound_sockets(&self) -> (SocketAddr, Option<SocketAddr>) {
+    /// By default the [`Endpoint`] binds on an IPv4 address and also tries to bind on an
+    /// IPv6 address if available.  A family is `None` if it is disabled, see
+    /// [`Builder::ipv4`] and [`Builder::ipv6`], or could not be bound.
+    pub fn bound_sockets(&self) -> (Option<SocketAddr>, Option<SocketAddr>) {
         self.msock.local_addr()
     }
 
@@ -894,6 +2231,112 @@ impl Endpoint {
         self.msock.list_remote_infos().into_iter()
     }
 
+    /// Returns statistics about each network path to a remote node.
+    ///
+    /// This includes all known direct paths as well as the path via the node's relay
+    /// server, and shows which of them is currently used to send data.  All connections
+    /// with the node share these paths, [`get_remote_node_id`] returns the node of a
+    /// connection.
+    ///
+    /// Returns `None` if nothing is known about the node.
+    pub fn path_stats(&self, node_id: NodeId) -> Option<Vec<NodePathStats>> {
+        self.msock.path_stats(node_id)
+    }
+
+    /// Returns statistics about the DISCO messages exchanged with a remote node.
+    ///
+    /// DISCO messages are used to find and validate the network paths to a node, these
+    /// statistics help to understand why hole punching to a node fails.  See
+    /// [`DiscoStats`] for details.
+    ///
+    /// Returns `None` if nothing is known about the node.
+    pub fn disco_stats(&self, node_id: NodeId) -> Option<DiscoStats> {
+        self.msock.disco_stats(node_id)
+    }
+
+    /// Returns the payload bytes exchanged with each remote node.
+    ///
+    /// The bytes are split by whether they were sent directly over UDP or via a relay
+    /// server, see [`TrafficStats`].  Nodes which nothing was exchanged with are left out.
+    ///
+    /// The counters start when the endpoint first learns about a node and are kept as
+    /// long as the endpoint knows the node.  Use [`Endpoint::reset_traffic_stats`] to
+    /// account for the traffic in periods.
+    pub fn traffic_stats(&self) -> BTreeMap<NodeId, TrafficStats> {
+        self.msock.traffic_stats(false)
+    }
+
+    /// Returns the payload bytes exchanged with each remote node and resets the counters.
+    ///
+    /// The counters of all nodes start from zero again.  Reading and resetting happens at
+    /// once, so no bytes are lost between two calls.  See [`Endpoint::traffic_stats`].
+    pub fn reset_traffic_stats(&self) -> BTreeMap<NodeId, TrafficStats> {
+        self.msock.traffic_stats(true)
+    }
+
+    /// Returns a snapshot of the key counters of this endpoint.
+    ///
+    /// These are the bytes sent and received over UDP and via relay servers, the number of
+    /// connections accepted and dialed, and the number of hole punching attempts and how
+    /// many of them succeeded.  See [`EndpointMetrics`].
+    ///
+    /// The counters are recorded for each endpoint, whether or not the `metrics` feature is
+    /// enabled and the global metrics are registered, see [`crate::metrics`].
+    pub fn metrics(&self) -> EndpointMetrics {
+        self.msock.metrics()
+    }
+
+    /// Returns a handle to inject faults into this endpoint while it is running.
+    ///
+    /// This allows tests to kill the relay connections, drop DISCO pongs, blackhole the UDP
+    /// path to a remote node or delay rebinding, to verify how an application recovers from
+    /// these failures.  See [`Faults`].
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+    pub fn faults(&self) -> Faults {
+        self.msock.faults()
+    }
+
+    /// Returns the state of establishing a direct connection to a remote node.
+    ///
+    /// This records the phases the connectivity to the node went through, from learning its
+    /// direct addresses over probing them to using a direct path or falling back to the
+    /// relay server, together with the event which caused each transition.  It shows why
+    /// the connection to a node ended up on its current path, see [`ConnectivityState`].
+    ///
+    /// Returns `None` if nothing is known about the node.
+    pub fn connectivity_state(&self, node_id: NodeId) -> Option<ConnectivityState> {
+        self.msock.connectivity_state(node_id)
+    }
+
+    /// Saves the addressing information of all known remote nodes to a file.
+    ///
+    /// The nodes can be restored after a restart using [`Builder::restore_peers`].  Only
+    /// nodes with a relay URL or direct address are saved.  Any existing file is replaced.
+    pub async fn save_peers(&self, path: impl AsRef<Path>) -> Result<()> {
+        let peers: Vec<NodeAddr> = self
+            .remote_info_iter()
+            .filter(|info| info.has_send_address())
+            .map(Into::into)
+            .collect();
+        peers::save(path.as_ref(), &peers).await
+    }
+
+    /// Measures the round trip time to a remote node.
+    ///
+    /// A DISCO ping is sent on the path currently used to send data to the node: the direct
+    /// path if one is established, otherwise via the relay server.  This does not need a
+    /// connection or an application ALPN, but the node must be known, e.g. from a previous
+    /// connection or [`Endpoint::add_node_addr`].
+    ///
+    /// Returns an error if there is no path to the node or no reply is received within 5
+    /// seconds.
+    pub async fn ping(&self, node_id: NodeId) -> Result<PingResult> {
+        self.msock.ping(node_id).await
+    }
+
     // # Methods for less common getters.
     //
     // Partially they return things passed into the builder.
@@ -920,6 +2363,21 @@ impl Endpoint {
         self.msock.conn_type_stream(node_id)
     }
 
+    /// Returns a watcher for the kind of connection used for the remote node.
+    ///
+    /// The [`ConnectionTypeWatcher`] gives access to the current [`ConnectionType`] and
+    /// reports each transition between a direct, relayed or mixed connection.  This is useful
+    /// to show whether data is currently sent directly or via a relay server.  Unlike
+    /// [`Endpoint::conn_type_stream`] it does not report changes of the address or relay
+    /// server used.
+    ///
+    /// # Errors
+    ///
+    /// Will error if we do not have any address information for the given `node_id`.
+    pub fn conn_type_changes(&self, node_id: NodeId) -> Result<ConnectionTypeWatcher> {
+        self.msock.conn_type_watcher(node_id)
+    }
+
     /// Returns the DNS resolver used in this [`Endpoint`].
     ///
     /// See [`Builder::discovery`].
@@ -934,6 +2392,40 @@ impl Endpoint {
         self.msock.discovery()
     }
 
+    /// Sets the [`UserData`] published by the discovery services.
+    ///
+    /// The addresses of this endpoint are published again right away with the new user
+    /// data.  Passing `None` stops publishing user data.
+    ///
+    /// See [`Builder::user_data_for_discovery`].
+    pub fn set_user_data_for_discovery(&self, user_data: Option<UserData>) {
+        self.msock.set_discovery_user_data(user_data);
+    }
+
+    /// Returns statistics about the results of each discovery service.
+    ///
+    /// The services are identified by the [`provenance`] of their results.  Only services
+    /// which produced results while resolving nodes are included.
+    ///
+    /// [`provenance`]: crate::discovery::DiscoveryItem::provenance
+    pub fn discovery_stats(&self) -> BTreeMap<&'static str, DiscoveryStats> {
+        self.discovery_stats.lock().clone()
+    }
+
+    /// Watches the addresses the discovery services find for a node.
+    ///
+    /// The returned stream yields a new [`NodeAddr`] whenever the discovered addresses of
+    /// the node change, e.g. because the node moved to another network.  This allows
+    /// long-running applications to re-dial nodes whose addresses rotate without polling.
+    /// See [`NodeAddrStream`] for details.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no discovery services are configured.
+    pub fn watch_node_addr(&self, node_id: NodeId) -> Result<NodeAddrStream> {
+        NodeAddrStream::start(self.clone(), node_id)
+    }
+
     // # Methods for less common state updates.
 
     /// Notifies the system of potential network changes.
@@ -950,13 +2442,75 @@ impl Endpoint {
         self.msock.network_change().await;
     }
 
+    /// Re-binds the sockets and re-validates all paths immediately.
+    ///
+    /// Unlike [`Endpoint::network_change`], which lets iroh decide whether anything
+    /// relevant changed, this always treats the change as a major one.  The UDP sockets are
+    /// re-bound, the DNS cache is cleared, relay connections which no longer work are
+    /// re-established, the direct addresses are refreshed and the paths to all remote nodes
+    /// are re-validated.
+    ///
+    /// Call this when the OS reports the active network interface changed, e.g. on a
+    /// handover between WiFi and cellular, to avoid waiting for timeouts to notice the old
+    /// paths are gone.
+    pub async fn rebind(&self) {
+        self.msock.rebind().await;
+    }
+
+    /// Pauses the background activity of the endpoint.
+    ///
+    /// This is intended for mobile applications moving to the background, where the OS
+    /// penalises apps which keep waking up the radio.  While paused the endpoint does not:
+    /// - send heartbeat pings to keep direct paths alive,
+    /// - run net_report probes,
+    /// - renew port mappings,
+    /// - ping relay servers, see [`RelayKeepaliveConfig::ping_interval`],
+    /// - publish to discovery, which is told to pause with [`Discovery::set_paused`].  The
+    ///   pkarr and DHT publishers stop republishing their records until resumed.
+    ///
+    /// Connections and their state are kept, the endpoint can still be used while paused.
+    /// Sending data still wakes up the radio of course.  Outgoing connections established
+    /// while paused send QUIC keep-alives only every 25 seconds instead of every
+    /// [`Builder::keep_alive_interval`].  Note that some activity is not paused:
+    /// - QUIC can not change the keep-alive interval of an established connection, so
+    ///   connections established before pausing keep sending keep-alives at their interval.
+    ///   Use a longer [`Builder::keep_alive_interval`] if this matters, or close idle
+    ///   connections before pausing;
+    /// - incoming connections send keep-alives configured with
+    ///   [`TransportConfig::keep_alive_interval`];
+    /// - the relay server may still send its own pings, which are answered;
+    /// - discovery services which do not implement [`Discovery::set_paused`], like the local
+    ///   network discovery, keep running.
+    ///
+    /// Call [`Endpoint::resume`] to resume.  Pausing an already paused endpoint does nothing.
+    pub async fn pause(&self) {
+        self.msock.pause().await;
+    }
+
+    /// Resumes the background activity after [`Endpoint::pause`].
+    ///
+    /// The network may have changed while paused, so the paths to all remote nodes are
+    /// re-validated and the direct addresses of this endpoint are refreshed immediately
+    /// rather than waiting for the next heartbeat.
+    ///
+    /// Resuming an endpoint which is not paused does nothing.
+    pub async fn resume(&self) {
+        self.msock.resume().await;
+    }
+
+    /// Returns whether the endpoint is paused, see [`Endpoint::pause`].
+    pub fn is_paused(&self) -> bool {
+        self.msock.is_paused()
+    }
+
     // # Methods for terminating the endpoint.
 
     /// Closes the QUIC endpoint and the magic socket.
     ///
-    /// This will close any remaining open [`Connection`]s with an error code
-    /// of `0` and an empty reason.  Though it is best practice to close those
-    /// explicitly before with a custom error code and reason.
+    /// This will close any remaining open [`Connection`]s with [`ENDPOINT_CLOSED_CODE`] and
+    /// an empty reason.  Though it is best practice to close those explicitly before with a
+    /// custom error code and reason.  See [`Endpoint::close_with_grace_period`] to give
+    /// connections some time to finish first.
     ///
     /// It will then make a best effort to wait for all close notifications to be
     /// acknowledged by the peers, re-transmitting them if needed. This ensures the
@@ -975,15 +2529,64 @@ impl Endpoint {
         }
 
         self.cancel_token.cancel();
+        if let Some(ref pool) = self.pool {
+            pool.clear();
+        }
         tracing::debug!("Closing connections");
-        self.endpoint.close(0u16.into(), b"");
-        self.endpoint.wait_idle().await;
+        self.endpoint.close(ENDPOINT_CLOSED_CODE, b"");
+        match self.interop {
+            Some(ref interop) => {
+                futures_lite::future::zip(
+                    self.endpoint.wait_idle(),
+                    interop.close(ENDPOINT_CLOSED_CODE),
+                )
+                .await;
+            }
+            None => self.endpoint.wait_idle().await,
+        }
 
         tracing::debug!("Connections closed");
         self.msock.close().await?;
         Ok(())
     }
 
+    /// Closes the endpoint after giving open connections time to finish.
+    ///
+    /// New incoming connections are refused immediately.  Then this waits until all
+    /// connections have been closed, or until `grace_period` has elapsed, before closing the
+    /// endpoint like [`Endpoint::close`] does.  Connections still open at that point are
+    /// closed with [`ENDPOINT_CLOSED_CODE`].
+    ///
+    /// Pooled connections, see [`Builder::connection_pool`], are released right away so they
+    /// do not hold up closing unless they are still in use.
+    pub async fn close_with_grace_period(&self, grace_period: Duration) -> Result<()> {
+        if self.is_closed() {
+            return Ok(());
+        }
+
+        self.endpoint.set_server_config(None);
+        if let Some(ref pool) = self.pool {
+            pool.clear();
+        }
+        tracing::debug!(?grace_period, "Waiting for connections to finish");
+        let rt = self.msock.runtime();
+        let wait_closed = async {
+            while self.endpoint.open_connections() > 0 {
+                rt.sleep(GRACE_PERIOD_POLL_INTERVAL).await;
+            }
+        };
+        if runtime::timeout(&**rt, grace_period, wait_closed)
+            .await
+            .is_err()
+        {
+            tracing::debug!(
+                remaining = self.endpoint.open_connections(),
+                "Grace period elapsed, closing remaining connections"
+            );
+        }
+        self.close().await
+    }
+
     /// Check if this endpoint is still alive, or already closed.
     pub fn is_closed(&self) -> bool {
         self.cancel_token.is_cancelled() && self.msock.is_closed()
@@ -991,6 +2594,26 @@ impl Endpoint {
 
     // # Remaining private methods
 
+    /// Returns whether a discovery result is older than [`Builder::discovery_max_age`].
+    pub(crate) fn is_discovery_item_stale(&self, item: &DiscoveryItem) -> bool {
+        match (self.static_config.discovery_max_age, item.age()) {
+            (Some(max_age), Some(age)) => age > max_age,
+            _ => false,
+        }
+    }
+
+    /// Counts a result of the discovery service with the given `provenance`.
+    ///
+    /// `first` is whether it was the first result while resolving a node.
+    pub(crate) fn note_discovery_result(&self, provenance: &'static str, first: bool) {
+        let mut stats = self.discovery_stats.lock();
+        let stats = stats.entry(provenance).or_default();
+        stats.results += 1;
+        if first {
+            stats.first_results += 1;
+        }
+    }
+
     /// Expose the internal [`CancellationToken`] to link shutdowns.
     pub(crate) fn cancel_token(&self) -> &CancellationToken {
         &self.cancel_token
@@ -1012,7 +2635,7 @@ impl Endpoint {
     async fn get_mapping_addr_and_maybe_start_discovery(
         &self,
         node_addr: NodeAddr,
-    ) -> Result<(QuicMappedAddr, Option<DiscoveryTask>)> {
+    ) -> Result<(QuicMappedAddr, Option<DiscoveryTask>), ConnectError> {
         let node_id = node_addr.node_id;
 
         // Only return a mapped addr if we have some way of dialing this node, in other
@@ -1044,15 +2667,18 @@ impl Endpoint {
                 // only then continue, because otherwise we wouldn't have any
                 // path to the remote endpoint.
                 let mut discovery = DiscoveryTask::start(self.clone(), node_id)
-                    .context("Discovery service required due to missing addressing information")?;
+                    .map_err(|_| ConnectError::NoAddress(node_id))?;
                 discovery
                     .first_arrived()
                     .await
-                    .context("Discovery service failed")?;
+                    .map_err(|source| ConnectError::Discovery { node_id, source })?;
                 if let Some(addr) = self.msock.get_mapping_addr(node_id) {
                     Ok((addr, Some(discovery)))
                 } else {
-                    bail!("Discovery did not find addressing information");
+                    Err(ConnectError::Discovery {
+                        node_id,
+                        source: anyhow!("Discovery did not find addressing information"),
+                    })
                 }
             }
         }
@@ -1075,21 +2701,55 @@ pub struct Accept<'a> {
     #[pin]
     #[debug("quinn::Accept")]
     inner: quinn::Accept<'a>,
+    #[debug(skip)]
+    endpoint: &'a quinn::Endpoint,
     ep: Endpoint,
 }
 
-impl Future for Accept<'_> {
+impl<'a> Future for Accept<'a> {
     type Output = Option<Incoming>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        match this.inner.poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(inner)) => Poll::Ready(Some(Incoming {
-                inner,
-                ep: this.ep.clone(),
-            })),
+        let mut this = self.project();
+        let endpoint: &'a quinn::Endpoint = this.endpoint;
+        loop {
+            match this.inner.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(Some(inner)) => {
+                    let mut incoming = Incoming {
+                        inner,
+                        ep: this.ep.clone(),
+                        limit_slot: None,
+                    };
+                    // Nodes we do not know yet are checked during the TLS handshake.
+                    let node_id = incoming.remote_node_id();
+                    if let Some(node_id) = node_id {
+                        if !this
+                            .ep
+                            .static_config
+                            .access_policy
+                            .read()
+                            .is_allowed(&node_id)
+                        {
+                            debug!(remote = %node_id.fmt_short(), "refusing incoming connection denied by access policy");
+                            incoming.refuse();
+                            this.inner.set(endpoint.accept());
+                            continue;
+                        }
+                    }
+                    match this.ep.static_config.connection_limits.reserve(node_id) {
+                        Ok(slot) => incoming.limit_slot = slot,
+                        Err(limit) => {
+                            debug!(remote = ?node_id.map(|id| id.fmt_short()), ?limit, "refusing incoming connection over limit");
+                            incoming.refuse();
+                            this.inner.set(endpoint.accept());
+                            continue;
+                        }
+                    }
+                    return Poll::Ready(Some(incoming));
+                }
+            }
         }
     }
 }
@@ -1100,6 +2760,8 @@ impl Future for Accept<'_> {
 pub struct Incoming {
     inner: quinn::Incoming,
     ep: Endpoint,
+    /// The slot of the connection within the connection limits.
+    limit_slot: Option<limits::LimitSlot>,
 }
 
 impl Incoming {
@@ -1116,6 +2778,8 @@ impl Incoming {
         self.inner.accept().map(|conn| Connecting {
             inner: conn,
             ep: self.ep,
+            limit_slot: self.limit_slot,
+            span: spans::accept(),
         })
     }
 
@@ -1133,6 +2797,8 @@ impl Incoming {
             .map(|conn| Connecting {
                 inner: conn,
                 ep: self.ep,
+                limit_slot: self.limit_slot,
+                span: spans::accept(),
             })
     }
 
@@ -1166,6 +2832,18 @@ impl Incoming {
         self.inner.remote_address()
     }
 
+    /// Returns the [`NodeId`] of the peer, if it is already known.
+    ///
+    /// The node is only known if this endpoint has received packets from it before the
+    /// connection attempt, e.g. because of an earlier connection.  The identity is not
+    /// authenticated until the handshake completes, use [`get_remote_node_id`] on the
+    /// established connection for that.
+    pub fn remote_node_id(&self) -> Option<NodeId> {
+        self.ep
+            .msock
+            .get_node_id_for_mapping_addr(QuicMappedAddr(self.remote_address()))
+    }
+
     /// Whether the socket address that is initiating this connection has been validated.
     ///
     /// This means that the sender of the initial packet has proved that they can receive
@@ -1183,6 +2861,8 @@ impl IntoFuture for Incoming {
         IncomingFuture {
             inner: self.inner.into_future(),
             ep: self.ep,
+            limit_slot: self.limit_slot,
+            span: spans::accept(),
         }
     }
 }
@@ -1194,6 +2874,8 @@ pub struct IncomingFuture {
     #[pin]
     inner: quinn::IncomingFuture,
     ep: Endpoint,
+    limit_slot: Option<limits::LimitSlot>,
+    span: Span,
 }
 
 impl Future for IncomingFuture {
@@ -1201,12 +2883,23 @@ impl Future for IncomingFuture {
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
+        let _guard = this.span.enter();
         match this.inner.poll(cx) {
             Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Ready(Err(err)) => {
+                spans::failed(this.span, &err);
+                this.ep.emit(EndpointEvent::ConnectionFailed {
+                    node_id: None,
+                    incoming: true,
+                    error: err.to_string(),
+                });
+                Poll::Ready(Err(err))
+            }
             Poll::Ready(Ok(conn)) => {
-                try_send_rtt_msg(&conn, this.ep);
-                Poll::Ready(Ok(conn))
+                match register_incoming(&conn, this.ep, this.limit_slot.take(), this.span) {
+                    Ok(()) => Poll::Ready(Ok(conn)),
+                    Err(err) => Poll::Ready(Err(err)),
+                }
             }
         }
     }
@@ -1219,17 +2912,28 @@ pub struct Connecting {
     #[pin]
     inner: quinn::Connecting,
     ep: Endpoint,
+    limit_slot: Option<limits::LimitSlot>,
+    span: Span,
 }
 
 impl Connecting {
     /// Convert into a 0-RTT or 0.5-RTT connection at the cost of weakened security.
+    ///
+    /// The connection is closed with [`CONNECTION_LIMIT_CODE`] if it exceeds the
+    /// [`Builder::max_connections_per_node_id`] limit.
     pub fn into_0rtt(self) -> Result<(Connection, ZeroRttAccepted), Self> {
         match self.inner.into_0rtt() {
             Ok((conn, zrtt_accepted)) => {
-                try_send_rtt_msg(&conn, &self.ep);
+                // The error is reported by the closed connection itself.
+                register_incoming(&conn, &self.ep, self.limit_slot, &self.span).ok();
                 Ok((conn, zrtt_accepted))
             }
-            Err(inner) => Err(Self { inner, ep: self.ep }),
+            Err(inner) => Err(Self {
+                inner,
+                ep: self.ep,
+                limit_slot: self.limit_slot,
+                span: self.span,
+            }),
         }
     }
 
@@ -1268,12 +2972,23 @@ impl Future for Connecting {
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
+        let _guard = this.span.enter();
         match this.inner.poll(cx) {
             Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Ready(Err(err)) => {
+                spans::failed(this.span, &err);
+                this.ep.emit(EndpointEvent::ConnectionFailed {
+                    node_id: None,
+                    incoming: true,
+                    error: err.to_string(),
+                });
+                Poll::Ready(Err(err))
+            }
             Poll::Ready(Ok(conn)) => {
-                try_send_rtt_msg(&conn, this.ep);
-                Poll::Ready(Ok(conn))
+                match register_incoming(&conn, this.ep, this.limit_slot.take(), this.span) {
+                    Ok(()) => Poll::Ready(Ok(conn)),
+                    Err(err) => Poll::Ready(Err(err)),
+                }
             }
         }
     }
@@ -1282,6 +2997,22 @@ impl Future for Connecting {
 /// Extract the [`PublicKey`] from the peer's TLS certificate.
 // TODO: make this a method now
 pub fn get_remote_node_id(connection: &Connection) -> Result<PublicKey> {
+    with_peer_certificate(connection, |cert| cert.peer_id())
+}
+
+/// Extract the [`NodeMetadata`] the peer sent in its TLS certificate.
+///
+/// Returns `None` if the peer did not send any metadata, e.g. because it runs an older
+/// version of iroh.
+pub fn get_remote_metadata(connection: &Connection) -> Result<Option<NodeMetadata>> {
+    with_peer_certificate(connection, |cert| cert.metadata().cloned())
+}
+
+/// Parses the peer's TLS certificate and applies `f` to it.
+fn with_peer_certificate<T>(
+    connection: &Connection,
+    f: impl FnOnce(&tls::certificate::P2pCertificate<'_>) -> T,
+) -> Result<T> {
     let data = connection.peer_identity();
     match data {
         None => bail!("no peer certificate found"),
@@ -1294,31 +3025,102 @@ pub fn get_remote_node_id(connection: &Connection) -> Result<PublicKey> {
                     );
                 }
                 let cert = tls::certificate::parse(&certs[0])?;
-                Ok(cert.peer_id())
+                Ok(f(&cert))
             }
             Err(_) => bail!("invalid peer certificate"),
         },
     }
 }
 
+/// Derives `len` bytes of keying material bound to the TLS session of the connection.
+///
+/// This is the TLS exporter of RFC 5705 and RFC 8446.  Both sides of a connection derive the
+/// same secret when using the same `label` and `context`, no other connection does.  This
+/// allows applications to derive keys for their own encryption, or to bind tokens to the
+/// connection, without running a handshake of their own.
+///
+/// The `label` should be unique to the application and purpose, see [RFC 5705] for
+/// guidance.  This is a convenience wrapper around [`Connection::export_keying_material`].
+///
+/// [RFC 5705]: https://www.rfc-editor.org/rfc/rfc5705#section-4
+pub fn export_keying_material(
+    connection: &Connection,
+    label: &[u8],
+    context: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, ExportKeyingMaterialError> {
+    let mut output = vec![0u8; len];
+    connection.export_keying_material(&mut output, label, context)?;
+    Ok(output)
+}
+
+/// Extract the ALPN protocol negotiated for the connection.
+fn get_alpn(connection: &Connection) -> Option<Vec<u8>> {
+    let data = connection.handshake_data()?;
+    data.downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?
+        .protocol
+}
+
+/// Registers an established incoming connection.
+///
+/// The connection keeps its slot within the [`Builder::max_incoming_connections`] and
+/// [`Builder::max_connections_per_node_id`] limits, and the rtt-actor is notified.  If the
+/// remote node turns out to exceed its limit, the connection is closed with
+/// [`CONNECTION_LIMIT_CODE`] instead.
+fn register_incoming(
+    conn: &Connection,
+    magic_ep: &Endpoint,
+    limit_slot: Option<limits::LimitSlot>,
+    span: &Span,
+) -> Result<(), ConnectionError> {
+    let Ok(peer_id) = get_remote_node_id(conn) else {
+        warn!(?conn, "failed to get remote node id");
+        return Ok(());
+    };
+    if let Some(slot) = limit_slot {
+        if slot.establish(peer_id, conn.weak_handle()).is_err() {
+            debug!(remote = %peer_id.fmt_short(), "closing incoming connection over per-node limit");
+            conn.close(CONNECTION_LIMIT_CODE, b"connection limit");
+            let err = ConnectionError::LocallyClosed;
+            spans::failed(span, &err);
+            magic_ep.emit(EndpointEvent::ConnectionFailed {
+                node_id: Some(peer_id),
+                incoming: true,
+                error: "per-node connection limit exceeded".to_string(),
+            });
+            return Err(err);
+        }
+    }
+    Counters::add(&magic_ep.msock.counters().connections_accepted, 1);
+    let alpn = get_alpn(conn).unwrap_or_default();
+    spans::established(span, magic_ep, peer_id, &alpn);
+    try_send_rtt_msg(conn, peer_id, alpn, magic_ep, span);
+    Ok(())
+}
+
 /// Try send a message to the rtt-actor.
 ///
 /// If we can't notify the actor that will impact performance a little, but we can still
 /// function.
-fn try_send_rtt_msg(conn: &Connection, magic_ep: &Endpoint) {
+fn try_send_rtt_msg(
+    conn: &Connection,
+    peer_id: NodeId,
+    alpn: Vec<u8>,
+    magic_ep: &Endpoint,
+    span: &Span,
+) {
     // If we can't notify the rtt-actor that's not great but not critical.
-    let Ok(peer_id) = get_remote_node_id(conn) else {
-        warn!(?conn, "failed to get remote node id");
-        return;
-    };
     let Ok(conn_type_changes) = magic_ep.conn_type_stream(peer_id) else {
         warn!(?conn, "failed to create conn_type_stream");
         return;
     };
     let rtt_msg = RttMessage::NewConnection {
-        connection: conn.weak_handle(),
+        connection: conn.clone(),
         conn_type_changes,
         node_id: peer_id,
+        alpn,
+        span: span.clone(),
     };
     if let Err(err) = magic_ep.rtt_actor.msg_tx.try_send(rtt_msg) {
         warn!(?conn, "rtt-actor not reachable: {err:#}");
@@ -1391,6 +3193,44 @@ impl RelayMode {
     }
 }
 
+/// Whether an [`Endpoint`] finds and reaches other nodes via the internet.
+///
+/// See [`Builder::discovery_mode`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoveryMode {
+    /// Use the relay servers and discovery services configured on the [`Builder`].
+    #[default]
+    Default,
+    /// Only find and connect to nodes on the local network, for offline or air-gapped
+    /// deployments.
+    ///
+    /// No relay servers are used, as with [`RelayMode::Disabled`], so no STUN probes are
+    /// sent either.  No port mappings are requested from the router.  The discovery
+    /// services configured on the [`Builder`] are replaced by local network discovery,
+    /// if the `discovery-local-network` feature is enabled.  Other nodes are connected to
+    /// directly, using the addresses from local network discovery or those given in their
+    /// [`NodeAddr`].
+    LocalOnly,
+}
+
+/// Returns the discovery services used with [`DiscoveryMode::LocalOnly`].
+fn local_discovery() -> Vec<DiscoveryBuilder> {
+    #[cfg(feature = "discovery-local-network")]
+    {
+        use crate::discovery::local_swarm_discovery::LocalSwarmDiscovery;
+        let discovery: DiscoveryBuilder = Box::new(|secret_key| {
+            LocalSwarmDiscovery::new(secret_key.public())
+                .map(|x| Box::new(x) as _)
+                .ok()
+        });
+        vec![discovery]
+    }
+    #[cfg(not(feature = "discovery-local-network"))]
+    {
+        Vec::new()
+    }
+}
+
 /// Environment variable to force the use of staging relays.
 #[cfg_attr(iroh_docsrs, doc(cfg(not(test))))]
 pub const ENV_FORCE_STAGING_RELAYS: &str = "IROH_FORCE_STAGING_RELAYS";
@@ -1425,14 +3265,15 @@ fn is_cgi() -> bool {
 #[cfg(test)]
 mod tests {
 
-    use std::time::Instant;
-
+    use iroh_base::ticket::NodesTicket;
     use iroh_test::CallOnDrop;
     use rand::SeedableRng;
     use tracing::{error_span, info, info_span, Instrument};
 
     use super::*;
-    use crate::test_utils::{run_relay_server, run_relay_server_with};
+    use crate::test_utils::{
+        run_relay_server, run_relay_server_with, LinkConditions, NatType, SimNetwork,
+    };
 
     const TEST_ALPN: &[u8] = b"n0/iroh/test";
 
@@ -1462,6 +3303,7 @@ mod tests {
         let res = ep.connect(my_addr.clone(), TEST_ALPN).await;
         assert!(res.is_err());
         let err = res.err().unwrap();
+        assert!(matches!(err, ConnectError::SelfConnect(_)));
         assert!(err.to_string().starts_with("Connecting to ourself"));
 
         let res = ep.add_node_addr(my_addr);
@@ -1644,7 +3486,7 @@ mod tests {
                         .await
                         .unwrap();
                     let eps = ep.bound_sockets();
-                    info!(me = %ep.node_id().fmt_short(), ipv4=%eps.0, ipv6=?eps.1, "server listening on");
+                    info!(me = %ep.node_id().fmt_short(), ipv4=?eps.0, ipv6=?eps.1, "server listening on");
                     for i in 0..n_clients {
                         let now = Instant::now();
                         println!("[server] round {}", i + 1);
@@ -1690,7 +3532,7 @@ mod tests {
                     .await
                     .unwrap();
                 let eps = ep.bound_sockets();
-                info!(me = %ep.node_id().fmt_short(), ipv4=%eps.0, ipv6=?eps.1, "client bound");
+                info!(me = %ep.node_id().fmt_short(), ipv4=?eps.0, ipv6=?eps.1, "client bound");
                 let node_addr = NodeAddr::new(server_node_id).with_relay_url(relay_url);
                 info!(to = ?node_addr, "client connecting");
                 let conn = ep.connect(node_addr, TEST_ALPN).await.unwrap();
@@ -1815,6 +3657,356 @@ mod tests {
         p2_connect.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn endpoint_connection_pool() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .connection_pool(Duration::from_secs(10))
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                conn.closed().await;
+            }
+        });
+
+        let conn1 = client
+            .connect(server_addr.clone(), TEST_ALPN)
+            .await
+            .unwrap();
+        let conn2 = client
+            .connect(server_addr.clone(), TEST_ALPN)
+            .await
+            .unwrap();
+        assert_eq!(conn1.stable_id(), conn2.stable_id());
+
+        // A closed connection is not handed out again.
+        conn1.close(0u8.into(), b"done");
+        accept_task.await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let conn3 = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        assert_ne!(conn1.stable_id(), conn3.stable_id());
+        accept_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_connection_pool_concurrent() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .connection_pool(Duration::from_secs(10))
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                conn.closed().await;
+            }
+        });
+
+        // Both calls start before either connection is established, only one dials.
+        let (conn1, conn2) = tokio::join!(
+            client.connect(server_addr.clone(), TEST_ALPN),
+            client.connect(server_addr.clone(), TEST_ALPN),
+        );
+        let (conn1, conn2) = (conn1.unwrap(), conn2.unwrap());
+        assert_eq!(conn1.stable_id(), conn2.stable_id());
+
+        conn1.close(0u8.into(), b"done");
+        accept_task.await.unwrap();
+    }
+
+    // Runs on a memory network with paused time, so the measured duration only depends on
+    // the rate limit and not on how busy the machine running the test is.
+    #[tokio::test(start_paused = true)]
+    async fn endpoint_max_send_rate() {
+        let _logging_guard = iroh_test::logging::setup();
+        const RATE: u64 = 200 * 1024;
+        const SIZE: usize = 400 * 1024;
+        let network = MemoryNetwork::with_seed(0);
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .memory_network(&network)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .memory_network(&network)
+            .max_send_rate(RATE)
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                let mut recv = conn.accept_uni().await.unwrap();
+                let data = recv.read_to_end(SIZE).await.unwrap();
+                assert_eq!(data.len(), SIZE);
+            }
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let start = tokio::time::Instant::now();
+        let mut send = conn.open_uni().await.unwrap();
+        send.write_all(&vec![0u8; SIZE]).await.unwrap();
+        send.finish().unwrap();
+        accept_task.await.unwrap();
+        let elapsed = start.elapsed();
+        info!(?elapsed, "sent {SIZE} bytes");
+        // Sending takes at least about SIZE / RATE, minus the initial burst and the last
+        // transmits which may overdraw the limit.
+        assert!(elapsed > Duration::from_millis(1500), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn endpoint_connect_any() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+        // A node which will never answer.
+        let unreachable = NodeAddr::new(SecretKey::generate().public())
+            .with_direct_addresses(["127.0.0.1:1".parse().unwrap()]);
+
+        let res = client.connect_any(&[], TEST_ALPN).await;
+        assert!(matches!(res, Err(ConnectError::NoNodes)));
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        // The nodes are shared as a ticket.
+        let ticket = NodesTicket::new([unreachable, server_addr]).unwrap();
+        let ticket: NodesTicket = ticket.to_string().parse().unwrap();
+        let conn = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.connect_any(ticket.node_addrs(), TEST_ALPN),
+        )
+        .await
+        .expect("timeout")
+        .unwrap();
+        assert_eq!(get_remote_node_id(&conn).unwrap(), server.node_id());
+        accept_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_access_policy() {
+        let _logging_guard = iroh_test::logging::setup();
+        let denied_key = SecretKey::generate();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .access_policy(AccessPolicy::deny([denied_key.public()]))
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                loop {
+                    let incoming = server.accept().await.unwrap();
+                    match incoming.await {
+                        Ok(conn) => return conn,
+                        Err(err) => info!("failed to accept: {err:#}"),
+                    }
+                }
+            }
+        });
+
+        let denied = Endpoint::builder()
+            .secret_key(denied_key)
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let res = denied.connect(server_addr.clone(), TEST_ALPN).await;
+        assert!(res.is_err());
+
+        let allowed = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let _conn = allowed.connect(server_addr, TEST_ALPN).await.unwrap();
+        let conn = accept_task.await.unwrap();
+        assert_eq!(get_remote_node_id(&conn).unwrap(), allowed.node_id());
+
+        server.set_access_policy(AccessPolicy::allow([allowed.node_id()]));
+        assert_eq!(
+            server.access_policy(),
+            AccessPolicy::allow([allowed.node_id()])
+        );
+    }
+
+    #[tokio::test]
+    async fn endpoint_standby_relay_failover() {
+        const TIMEOUT: Duration = Duration::from_secs(20);
+        let _logging_guard = iroh_test::logging::setup();
+        let (relay_map_a, relay_url_a, relay_a) = run_relay_server().await.unwrap();
+        let (relay_map_b, relay_url_b, relay_b) = run_relay_server().await.unwrap();
+        let relay_map =
+            RelayMap::from_nodes(relay_map_a.nodes().chain(relay_map_b.nodes()).cloned()).unwrap();
+        let ep = Endpoint::builder()
+            .insecure_skip_relay_cert_verify(true)
+            .relay_mode(RelayMode::Custom(relay_map))
+            .standby_relay(true)
+            .bind()
+            .await
+            .unwrap();
+
+        let mut status_stream = ep.watch_relay_status();
+        let status = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                let status = status_stream.next().await.unwrap();
+                if status.home.is_some() && status.standby.is_some() {
+                    break status;
+                }
+            }
+        })
+        .await
+        .expect("no standby relay");
+        let home = status.home.unwrap();
+        let standby = status.standby.unwrap();
+        assert_ne!(home, standby);
+
+        // Stop the home relay, the standby relay takes over.
+        let (relay_home, relay_standby) = if home == relay_url_a {
+            (relay_a, relay_b)
+        } else {
+            assert_eq!(home, relay_url_b);
+            (relay_b, relay_a)
+        };
+        relay_home.shutdown().await.unwrap();
+        tokio::time::timeout(TIMEOUT, async {
+            while status_stream.next().await.unwrap().home.as_ref() != Some(&standby) {}
+        })
+        .await
+        .expect("no failover to standby relay");
+        assert_eq!(ep.home_relay(), Some(standby));
+        drop(relay_standby);
+    }
+
+    #[tokio::test]
+    async fn endpoint_relay_go_away() {
+        const TIMEOUT: Duration = Duration::from_secs(20);
+        let _logging_guard = iroh_test::logging::setup();
+        let (relay_map_a, relay_url_a, relay_a) = run_relay_server().await.unwrap();
+        let (relay_map_b, relay_url_b, relay_b) = run_relay_server().await.unwrap();
+        let relay_map =
+            RelayMap::from_nodes(relay_map_a.nodes().chain(relay_map_b.nodes()).cloned()).unwrap();
+        let ep = Endpoint::builder()
+            .insecure_skip_relay_cert_verify(true)
+            .relay_mode(RelayMode::Custom(relay_map))
+            .bind()
+            .await
+            .unwrap();
+
+        let mut status_stream = ep.watch_relay_status();
+        let home = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let Some(home) = status_stream.next().await.unwrap().home {
+                    break home;
+                }
+            }
+        })
+        .await
+        .expect("no home relay");
+
+        // Drain the home relay, the endpoint moves to the other relay before the grace
+        // period is over.
+        let (relay_home, relay_other, other) = if home == relay_url_a {
+            (relay_a, relay_b, relay_url_b)
+        } else {
+            assert_eq!(home, relay_url_b);
+            (relay_b, relay_a, relay_url_a)
+        };
+        let grace_period = Duration::from_secs(60);
+        let drain = tokio::spawn(relay_home.drain(grace_period));
+        tokio::time::timeout(TIMEOUT, async {
+            while status_stream.next().await.unwrap().home.as_ref() != Some(&other) {}
+        })
+        .await
+        .expect("home relay did not move away from draining relay");
+        assert_eq!(ep.home_relay(), Some(other));
+        drain.abort();
+        drop(relay_other);
+    }
+
+    #[tokio::test]
+    async fn endpoint_relay_keepalive_health() {
+        const TIMEOUT: Duration = Duration::from_secs(20);
+        let _logging_guard = iroh_test::logging::setup();
+        let (relay_map, relay_url, _relay_guard) = run_relay_server().await.unwrap();
+        let ep = Endpoint::builder()
+            .insecure_skip_relay_cert_verify(true)
+            .relay_mode(RelayMode::Custom(relay_map))
+            .relay_keepalive(
+                RelayKeepaliveConfig::default()
+                    .ping_interval(Duration::from_millis(100))
+                    .pong_timeout(Duration::from_secs(2)),
+            )
+            .bind()
+            .await
+            .unwrap();
+
+        // The keepalive pings are answered by the relay server.
+        let health = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let Some(health) = ep.relay_health().remove(&relay_url) {
+                    if health.last_pong.is_some() {
+                        break health;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("no keepalive pong");
+        assert!(health.connected);
+        assert_eq!(health.reconnects, 0);
+    }
+
     #[tokio::test]
     async fn endpoint_conn_type_stream() {
         const TIMEOUT: Duration = std::time::Duration::from_secs(15);
@@ -1900,21 +4092,1384 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_direct_addresses_no_stun_relay() {
-        let _guard = iroh_test::logging::setup();
-        let (relay_map, _, _guard) = run_relay_server_with(None, false).await.unwrap();
-
-        let ep = Endpoint::builder()
+    async fn endpoint_conn_type_changes() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
             .alpns(vec![TEST_ALPN.to_vec()])
-            .relay_mode(RelayMode::Custom(relay_map))
-            .insecure_skip_relay_cert_verify(true)
+            .relay_mode(RelayMode::Disabled)
             .bind()
             .await
             .unwrap();
-
-        tokio::time::timeout(Duration::from_secs(10), ep.direct_addresses().next())
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
             .await
-            .unwrap()
             .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let _conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let _server_conn = accept_task.await.unwrap();
+
+        let mut watcher = client.conn_type_changes(server.node_id()).unwrap();
+        let conn_type = match watcher.get() {
+            conn_type @ ConnectionType::Direct(_) => conn_type,
+            _ => tokio::time::timeout(Duration::from_secs(10), watcher.updated())
+                .await
+                .expect("timeout")
+                .unwrap(),
+        };
+        assert!(matches!(conn_type, ConnectionType::Direct(_)));
+    }
+
+    #[tokio::test]
+    async fn endpoint_pause_resume() {
+        /// Records the calls to [`Discovery::set_paused`].
+        #[derive(Debug, Clone, Default)]
+        struct PauseRecorder(Arc<std::sync::Mutex<Vec<bool>>>);
+
+        impl Discovery for PauseRecorder {
+            fn set_paused(&self, paused: bool) {
+                self.0.lock().unwrap().push(paused);
+            }
+        }
+
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let discovery = PauseRecorder::default();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .discovery(Box::new(discovery.clone()))
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                for _ in 0..2 {
+                    let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                    let msg = recv.read_to_end(1000).await.unwrap();
+                    send.write_all(&msg).await.unwrap();
+                    send.finish().unwrap();
+                }
+                conn.closed().await;
+            }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let roundtrip = |conn: Connection| async move {
+            let (mut send, mut recv) = conn.open_bi().await.unwrap();
+            send.write_all(b"hello").await.unwrap();
+            send.finish().unwrap();
+            recv.read_to_end(1000).await.unwrap()
+        };
+
+        server.pause().await;
+        client.pause().await;
+        // Pausing twice does nothing.
+        client.pause().await;
+        assert!(server.is_paused());
+        assert_eq!(
+            client.keep_alive_interval(),
+            Some(PAUSED_KEEP_ALIVE_INTERVAL)
+        );
+        assert_eq!(roundtrip(conn.clone()).await, b"hello");
+
+        server.resume().await;
+        client.resume().await;
+        assert!(!server.is_paused());
+        assert_eq!(
+            client.keep_alive_interval(),
+            Some(DEFAULT_KEEP_ALIVE_INTERVAL)
+        );
+        assert_eq!(roundtrip(conn.clone()).await, b"hello");
+        assert_eq!(*discovery.0.lock().unwrap(), vec![true, false]);
+
+        conn.close(0u32.into(), b"done");
+        accept_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_rebind() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                let msg = recv.read_to_end(1000).await.unwrap();
+                send.write_all(&msg).await.unwrap();
+                send.finish().unwrap();
+                conn.closed().await;
+            }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+
+        client.rebind().await;
+
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(10), recv.read_to_end(1000))
+            .await
+            .expect("timeout")
+            .unwrap();
+        assert_eq!(msg, b"hello");
+
+        conn.close(0u32.into(), b"done");
+        accept_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_ip_families() {
+        let _logging_guard = iroh_test::logging::setup();
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .ipv6(false)
+            .bind()
+            .await
+            .unwrap();
+        let (v4, v6) = ep.bound_sockets();
+        assert!(v4.is_some());
+        assert!(v6.is_none());
+
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .ipv4(false)
+            .ipv6(false)
+            .bind()
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn endpoint_bind_interface_unknown() {
+        let _logging_guard = iroh_test::logging::setup();
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind_interface("iroh-test-none0")
+            .bind()
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn endpoint_bind_interface_loopback() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind_interface("lo")
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind_interface("lo")
+            .bind()
+            .await
+            .unwrap();
+        let (v4, _v6) = server.bound_sockets();
+        assert!(v4.unwrap().ip().is_loopback());
+
+        let addr = server.node_addr().await.unwrap();
+        let accept = tokio::spawn(async move {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let mut recv = conn.accept_uni().await.unwrap();
+            recv.read_to_end(16).await.unwrap()
+        });
+        let conn = client.connect(addr, TEST_ALPN).await.unwrap();
+        let mut send = conn.open_uni().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().unwrap();
+        assert_eq!(accept.await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn endpoint_forget_node() {
+        let _logging_guard = iroh_test::logging::setup();
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let node_id = SecretKey::generate().public();
+        let node_addr =
+            NodeAddr::new(node_id).with_direct_addresses(["127.0.0.1:1234".parse().unwrap()]);
+        ep.add_node_addr(node_addr).unwrap();
+        assert!(ep.remote_info_iter().any(|info| info.node_id == node_id));
+
+        assert!(ep.forget_node(node_id));
+        assert!(ep.remote_info(node_id).is_none());
+        assert!(!ep.remote_info_iter().any(|info| info.node_id == node_id));
+        assert!(!ep.forget_node(node_id));
+    }
+
+    #[tokio::test]
+    async fn endpoint_save_restore_peers() {
+        let _logging_guard = iroh_test::logging::setup();
+        let path = std::env::temp_dir().join(format!("iroh-peers-{}", rand::random::<u64>()));
+        let node_addr = NodeAddr::new(SecretKey::generate().public())
+            .with_relay_url("https://relay.example.com".parse().unwrap())
+            .with_direct_addresses(["127.0.0.1:1234".parse().unwrap()]);
+
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        ep.add_node_addr(node_addr.clone()).unwrap();
+        ep.save_peers(&path).await.unwrap();
+        ep.close().await.unwrap();
+
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .restore_peers(&path)
+            .bind()
+            .await
+            .unwrap();
+        let restored: NodeAddr = ep.remote_info(node_addr.node_id).unwrap().into();
+        assert_eq!(restored, node_addr);
+        std::fs::remove_file(&path).unwrap();
+
+        // A missing file means no peers.
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .restore_peers(&path)
+            .bind()
+            .await
+            .unwrap();
+        assert_eq!(ep.remote_info_iter().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn endpoint_ping() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+
+        // Nothing is known about the node yet.
+        assert!(client.ping(server.node_id()).await.is_err());
+
+        client
+            .add_node_addr(server.node_addr().await.unwrap())
+            .unwrap();
+        let res = client.ping(server.node_id()).await.unwrap();
+        assert!(matches!(res.conn_type, ConnectionType::Direct(_)));
+        assert!(res.latency < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn endpoint_path_stats() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        assert!(client.path_stats(server.node_id()).is_none());
+
+        let server_addr = server.node_addr().await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                let mut recv = conn.accept_uni().await.unwrap();
+                recv.read_to_end(1024).await.unwrap();
+                conn
+            }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let mut send = conn.open_uni().await.unwrap();
+        send.write_all(&[0u8; 512]).await.unwrap();
+        send.finish().unwrap();
+        let _server_conn = accept_task.await.unwrap();
+
+        let stats = client.path_stats(server.node_id()).unwrap();
+        let active: Vec<_> = stats.iter().filter(|stats| stats.active).collect();
+        assert_eq!(active.len(), 1);
+        assert!(matches!(active[0].path, PathAddr::Direct(_)));
+        assert!(active[0].bytes_sent >= 512);
+        assert!(active[0].bytes_recv > 0);
+    }
+
+    #[tokio::test]
+    async fn endpoint_runtime() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Debug, Clone, Default)]
+        struct CountingRuntime {
+            spawned: Arc<AtomicUsize>,
+            quinn: Arc<AtomicUsize>,
+        }
+
+        impl Runtime for CountingRuntime {
+            fn spawn(&self, future: runtime::BoxFuture) {
+                self.spawned.fetch_add(1, Ordering::Relaxed);
+                TokioRuntime.spawn(future)
+            }
+
+            fn sleep(&self, duration: Duration) -> runtime::BoxFuture {
+                TokioRuntime.sleep(duration)
+            }
+
+            fn quinn_runtime(&self) -> Arc<dyn quinn::Runtime> {
+                self.quinn.fetch_add(1, Ordering::Relaxed);
+                TokioRuntime.quinn_runtime()
+            }
+        }
+
+        let _logging_guard = iroh_test::logging::setup();
+        let runtime = CountingRuntime::default();
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .runtime(runtime.clone())
+            .bind()
+            .await
+            .unwrap();
+        // The direct addresses are found by a task spawned on the runtime.
+        tokio::time::timeout(Duration::from_secs(10), ep.direct_addresses().next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(runtime.spawned.load(Ordering::Relaxed) > 0);
+        assert!(runtime.quinn.load(Ordering::Relaxed) > 0);
+        ep.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_interop() {
+        use std::net::Ipv4Addr;
+
+        const INTEROP_ALPN: &[u8] = b"n0/iroh/test/interop";
+
+        let _logging_guard = iroh_test::logging::setup();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = cert.cert.der().clone();
+        let key_der = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert_der.clone()).unwrap();
+
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .interop(
+                InteropConfig::new()
+                    .accept(vec![INTEROP_ALPN.to_vec()], vec![cert_der], key_der.into())
+                    .unwrap(),
+            )
+            .bind()
+            .await
+            .unwrap();
+        let port = server.interop_local_addr().unwrap().port();
+        assert_ne!(port, server.bound_sockets().0.unwrap().port());
+        let server_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                for _ in 0..2 {
+                    let conn = server.accept_interop().await.unwrap().await.unwrap();
+                    let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                    let msg = recv.read_to_end(100).await.unwrap();
+                    send.write_all(&msg).await.unwrap();
+                    send.finish().unwrap();
+                    conn.closed().await;
+                }
+            }
+        });
+
+        // A plain quinn client.
+        let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots.clone())
+        .with_no_client_auth();
+        crypto.alpn_protocols = vec![INTEROP_ALPN.to_vec()];
+        let client_config = quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+        ));
+        let quinn_client =
+            quinn::Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let conn = quinn_client
+            .connect_with(client_config, server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"hello quinn").await.unwrap();
+        send.finish().unwrap();
+        assert_eq!(recv.read_to_end(100).await.unwrap(), b"hello quinn");
+        conn.close(0u32.into(), b"done");
+
+        // An iroh endpoint dialing in interop mode.
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .interop(InteropConfig::new().root_certificates(roots))
+            .bind()
+            .await
+            .unwrap();
+        let conn = client
+            .connect_interop(server_addr, "localhost", INTEROP_ALPN)
+            .await
+            .unwrap();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"hello iroh").await.unwrap();
+        send.finish().unwrap();
+        assert_eq!(recv.read_to_end(100).await.unwrap(), b"hello iroh");
+        conn.close(0u32.into(), b"done");
+
+        accept_task.await.unwrap();
+        // Without interop enabled there is nothing to dial or accept.
+        let plain = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        assert!(plain
+            .connect_interop(server_addr, "localhost", INTEROP_ALPN)
+            .await
+            .is_err());
+        assert!(plain.accept_interop().await.is_none());
+        assert!(plain.interop_local_addr().is_none());
+
+        client.close().await.unwrap();
+        server.close().await.unwrap();
+        plain.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_packet_tap() {
+        #[derive(Debug, Clone, Default)]
+        struct Recorder(Arc<std::sync::Mutex<Vec<(TapDirection, PacketKind, Option<NodeId>)>>>);
+
+        impl PacketTap for Recorder {
+            fn packet(&self, packet: &TappedPacket<'_>) {
+                assert!(matches!(packet.path, TapPath::Udp { .. }));
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((packet.direction, packet.kind, packet.node_id));
+            }
+        }
+
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let recorder = Recorder::default();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .packet_tap(recorder.clone())
+            .bind()
+            .await
+            .unwrap();
+
+        let server_addr = server.node_addr().await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let _server_conn = accept_task.await.unwrap();
+        conn.close(0u32.into(), b"done");
+
+        let packets = recorder.0.lock().unwrap().clone();
+        let server_id = Some(server.node_id());
+        for direction in [TapDirection::Send, TapDirection::Recv] {
+            assert!(packets
+                .iter()
+                .any(|p| *p == (direction, PacketKind::Quic, server_id)));
+        }
+    }
+
+    #[tokio::test]
+    async fn endpoint_memory_network() {
+        let _logging_guard = iroh_test::logging::setup();
+        let network = MemoryNetwork::with_seed(1);
+        network.set_conditions(
+            LinkConditions::default()
+                .latency(Duration::from_millis(5))
+                .loss(0.05)
+                .reorder(0.05),
+        );
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .memory_network(&network)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .memory_network(&network)
+            .bind()
+            .await
+            .unwrap();
+        assert!(matches!(server.bound_sockets(), (Some(_), None)));
+
+        let server_addr = server.node_addr().await.unwrap();
+        let server_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                let msg = recv.read_to_end(1024).await.unwrap();
+                send.write_all(&msg).await.unwrap();
+                send.finish().unwrap();
+                conn.closed().await;
+            }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().unwrap();
+        assert_eq!(recv.read_to_end(1024).await.unwrap(), b"hello");
+        conn.close(0u32.into(), b"done");
+        server_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_faults_udp_blackhole() {
+        let _logging_guard = iroh_test::logging::setup();
+        let network = MemoryNetwork::with_seed(2);
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .memory_network(&network)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .memory_network(&network)
+            .bind()
+            .await
+            .unwrap();
+
+        let server_addr = server.node_addr().await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let server_conn = accept_task.await.unwrap();
+
+        let faults = client.faults();
+        faults.set_udp_blackhole(server.node_id(), true);
+        let mut recv_task = tokio::spawn(async move {
+            let mut recv = server_conn.accept_uni().await.unwrap();
+            recv.read_to_end(1024).await.unwrap()
+        });
+        let mut send = conn.open_uni().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().unwrap();
+
+        // Nothing reaches the server while the path is blackholed.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), &mut recv_task)
+                .await
+                .is_err()
+        );
+
+        // QUIC retransmits the data once the path works again.
+        faults.clear();
+        let msg = tokio::time::timeout(Duration::from_secs(10), recv_task)
+            .await
+            .expect("timeout")
+            .unwrap();
+        assert_eq!(msg, b"hello");
+    }
+
+    #[tokio::test]
+    async fn endpoint_rng_seed() {
+        let _logging_guard = iroh_test::logging::setup();
+        let network = MemoryNetwork::with_seed(3);
+        let bind = |seed| {
+            Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .memory_network(&network)
+                .rng_seed(seed)
+                .bind()
+        };
+        let ep = bind(1).await.unwrap();
+        let same_seed = bind(1).await.unwrap();
+        let other_seed = bind(2).await.unwrap();
+        assert_eq!(ep.node_id(), same_seed.node_id());
+        assert_ne!(ep.node_id(), other_seed.node_id());
+    }
+
+    #[test]
+    fn test_seeded_cid_generator() {
+        use quinn_proto::ConnectionIdGenerator;
+
+        let mut cids = SeededCidGenerator::new(1);
+        let mut same_seed = SeededCidGenerator::new(1);
+        let mut other_seed = SeededCidGenerator::new(2);
+        let cid = cids.generate_cid();
+        assert_eq!(cid.len(), cids.cid_len());
+        assert_eq!(cid, same_seed.generate_cid());
+        assert_ne!(cid, other_seed.generate_cid());
+        assert_ne!(cid, cids.generate_cid());
+    }
+
+    #[tokio::test]
+    async fn endpoint_sim_network_nat() {
+        let _logging_guard = iroh_test::logging::setup();
+        let sim = SimNetwork::with_seed(7);
+        sim.set_conditions(
+            LinkConditions::default()
+                .latency(Duration::from_millis(10))
+                .jitter(Duration::from_millis(5)),
+        );
+        let full_cone = sim.add_nat(NatType::FullCone);
+        let symmetric = sim.add_nat(NatType::Symmetric);
+        let server = sim
+            .builder(Some(&full_cone))
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await
+            .unwrap();
+        let client = sim.builder(Some(&symmetric)).bind().await.unwrap();
+
+        let server_addr = server.node_addr().await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let _conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let _server_conn = accept_task.await.unwrap();
+
+        // The client reaches the server on the external address of the full cone NAT.
+        let mut watcher = client.conn_type_changes(server.node_id()).unwrap();
+        let conn_type = match watcher.get() {
+            conn_type @ ConnectionType::Direct(_) => conn_type,
+            _ => tokio::time::timeout(Duration::from_secs(10), watcher.updated())
+                .await
+                .expect("timeout")
+                .unwrap(),
+        };
+        match conn_type {
+            ConnectionType::Direct(addr) => assert_eq!(addr.ip(), full_cone.public_ip()),
+            other => panic!("unexpected connection type {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn endpoint_metrics() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        assert_eq!(client.metrics(), EndpointMetrics::default());
+
+        let server_addr = server.node_addr().await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let _server_conn = accept_task.await.unwrap();
+        conn.close(0u32.into(), b"done");
+
+        let client_metrics = client.metrics();
+        assert_eq!(client_metrics.connections_dialed, 1);
+        assert_eq!(client_metrics.connections_accepted, 0);
+        assert!(client_metrics.udp_bytes_sent > 0);
+        assert!(client_metrics.udp_bytes_recv > 0);
+        assert_eq!(client_metrics.relay_bytes_sent, 0);
+        assert_eq!(server.metrics().connections_accepted, 1);
+    }
+
+    #[tokio::test]
+    async fn endpoint_close_reason() {
+        #[derive(Debug, PartialEq)]
+        struct Done;
+
+        impl ErrorCode for Done {
+            fn code(&self) -> VarInt {
+                VarInt::from_u32(42)
+            }
+
+            fn from_code(code: VarInt) -> Option<Self> {
+                (code == VarInt::from_u32(42)).then_some(Done)
+            }
+        }
+
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+
+        let server_addr = server.node_addr().await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let server_conn = accept_task.await.unwrap();
+        assert_eq!(conn.closed_reason(), None);
+
+        server_conn.close_with(Done, "all done");
+        assert_eq!(
+            server_conn.closed_reason(),
+            Some(ClosedReason::LocalApplication)
+        );
+        let reason = conn.wait_closed().await;
+        assert_eq!(reason.side(), CloseSide::Remote);
+        assert!(reason.is_application());
+        assert_eq!(reason.application_code(), Some(Done));
+        assert_eq!(reason.reason(), Some(&b"all done"[..]));
+    }
+
+    #[tokio::test]
+    async fn endpoint_metadata() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .user_agent("test-server/1.0")
+            .app_metadata(&b"server data"[..])
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .user_agent("test-client/1.0")
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let server_conn = accept_task.await.unwrap();
+
+        let server_meta = get_remote_metadata(&conn).unwrap().unwrap();
+        assert_eq!(server_meta.iroh_version(), env!("CARGO_PKG_VERSION"));
+        assert_eq!(server_meta.user_agent(), Some("test-server/1.0"));
+        assert_eq!(&server_meta.app_data()[..], b"server data");
+
+        let client_meta = get_remote_metadata(&server_conn).unwrap().unwrap();
+        assert_eq!(client_meta.user_agent(), Some("test-client/1.0"));
+        assert!(client_meta.app_data().is_empty());
+
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .app_metadata(vec![0u8; MAX_APP_METADATA_LEN + 1])
+            .bind()
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn endpoint_dscp() {
+        let _logging_guard = iroh_test::logging::setup();
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .dscp(64)
+            .bind()
+            .await;
+        assert!(res.is_err());
+
+        let ep = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .dscp(46)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .dscp(46)
+            .bind()
+            .await
+            .unwrap();
+        let addr = ep.node_addr().await.unwrap();
+        let accept = tokio::spawn(async move {
+            let conn = ep.accept().await.unwrap().await.unwrap();
+            let mut recv = conn.accept_uni().await.unwrap();
+            recv.read_to_end(16).await.unwrap()
+        });
+        let conn = client.connect(addr, TEST_ALPN).await.unwrap();
+        let mut send = conn.open_uni().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().unwrap();
+        assert_eq!(accept.await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn endpoint_export_keying_material() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let server_conn = accept_task.await.unwrap();
+
+        let client_key = export_keying_material(&conn, b"iroh test", b"ctx", 32).unwrap();
+        let server_key = export_keying_material(&server_conn, b"iroh test", b"ctx", 32).unwrap();
+        assert_eq!(client_key.len(), 32);
+        assert_eq!(client_key, server_key);
+        let other_key = export_keying_material(&conn, b"iroh test", b"other", 32).unwrap();
+        assert_ne!(client_key, other_key);
+    }
+
+    #[tokio::test]
+    async fn endpoint_previous_secret_keys() {
+        let _logging_guard = iroh_test::logging::setup();
+        let old_key = SecretKey::generate();
+        let new_key = SecretKey::generate();
+        let server = Endpoint::builder()
+            .secret_key(new_key.clone())
+            .previous_secret_keys([old_key.clone()])
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let addrs = server.node_addr().await.unwrap().info.direct_addresses;
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                for _ in 0..2 {
+                    let conn = server.accept().await.unwrap().await.unwrap();
+                    let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                    let msg = recv.read_to_end(1000).await.unwrap();
+                    send.write_all(&msg).await.unwrap();
+                    send.finish().unwrap();
+                    conn.closed().await;
+                }
+            }
+        });
+
+        for key in [old_key, new_key.clone()] {
+            let client = Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .bind()
+                .await
+                .unwrap();
+            let node_addr = NodeAddr::from_parts(key.public(), None, addrs.clone());
+            let conn = tokio::time::timeout(
+                Duration::from_secs(10),
+                client.connect(node_addr, TEST_ALPN),
+            )
+            .await
+            .expect("timeout")
+            .unwrap();
+            // The server always presents its current identity.
+            assert_eq!(get_remote_node_id(&conn).unwrap(), new_key.public());
+            let (mut send, mut recv) = conn.open_bi().await.unwrap();
+            send.write_all(b"hello").await.unwrap();
+            send.finish().unwrap();
+            let msg = recv.read_to_end(1000).await.unwrap();
+            assert_eq!(msg, b"hello");
+            conn.close(0u32.into(), b"done");
+        }
+        accept_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_connect_relay_only() {
+        let _logging_guard = iroh_test::logging::setup();
+        let (relay_map, _relay_url, _relay_guard) = run_relay_server().await.unwrap();
+        let server = Endpoint::builder()
+            .insecure_skip_relay_cert_verify(true)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Custom(relay_map.clone()))
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .insecure_skip_relay_cert_verify(true)
+            .relay_mode(RelayMode::Custom(relay_map))
+            .bind()
+            .await
+            .unwrap();
+        // The server address includes its direct addresses, which must not be used.
+        let server_addr = server.node_addr().await.unwrap();
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                let msg = recv.read_to_end(1000).await.unwrap();
+                send.write_all(&msg).await.unwrap();
+                send.finish().unwrap();
+                let plain = server.accept().await.unwrap().await.unwrap();
+                conn.closed().await;
+                plain.closed().await;
+            }
+        });
+        let options = ConnectOptions::new().path_policy(PathPolicy::RelayOnly);
+        let conn = tokio::time::timeout(
+            Duration::from_secs(15),
+            client.connect_with_opts(server_addr, TEST_ALPN, options),
+        )
+        .await
+        .expect("timeout")
+        .unwrap();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().unwrap();
+        let msg = recv.read_to_end(1000).await.unwrap();
+        assert_eq!(msg, b"hello");
+
+        let conn_type = client.remote_info(server.node_id()).unwrap().conn_type;
+        assert!(matches!(conn_type, ConnectionType::Relay(_)), "{conn_type}");
+        // The server never learned any direct address of the client.
+        let info = server.remote_info(client.node_id()).unwrap();
+        assert!(info.addrs.is_empty());
+
+        // The policy of the open connection can not be contradicted.
+        let options = ConnectOptions::new().path_policy(PathPolicy::DirectOnly);
+        let err = client
+            .connect_with_opts(server.node_id(), TEST_ALPN, options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ConnectError::PolicyConflict(_)), "{err}");
+        // A plain connection keeps relying on the relay only.
+        let plain = tokio::time::timeout(
+            Duration::from_secs(15),
+            client.connect(server.node_id(), TEST_ALPN),
+        )
+        .await
+        .expect("timeout")
+        .unwrap();
+        let conn_type = client.remote_info(server.node_id()).unwrap().conn_type;
+        assert!(matches!(conn_type, ConnectionType::Relay(_)), "{conn_type}");
+        let info = server.remote_info(client.node_id()).unwrap();
+        assert!(info.addrs.is_empty());
+
+        conn.close(0u32.into(), b"done");
+        plain.close(0u32.into(), b"done");
+        accept_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn endpoint_connection_limits() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .max_incoming_connections(2)
+            .max_connections_per_node_id(1)
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+        let (accepted_tx, mut accepted_rx) = tokio::sync::mpsc::channel(8);
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move {
+                while let Some(incoming) = server.accept().await {
+                    if let Ok(conn) = incoming.await {
+                        accepted_tx.send(conn).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let client = Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .bind()
+                .await
+                .unwrap();
+            clients.push(client);
+        }
+        let connect = |client: &Endpoint| {
+            let client = client.clone();
+            let server_addr = server_addr.clone();
+            async move {
+                tokio::time::timeout(
+                    Duration::from_secs(10),
+                    client.connect(server_addr, TEST_ALPN),
+                )
+                .await
+                .expect("timeout")
+            }
+        };
+
+        let _conn0 = connect(&clients[0]).await.unwrap();
+        let _server_conn0 = accepted_rx.recv().await.unwrap();
+        // The per-node limit is reached.
+        assert!(connect(&clients[0]).await.is_err());
+        let _conn1 = connect(&clients[1]).await.unwrap();
+        let _server_conn1 = accepted_rx.recv().await.unwrap();
+        // The total limit is reached.
+        assert!(connect(&clients[2]).await.is_err());
+
+        accept_task.abort();
+    }
+
+    #[tokio::test]
+    async fn endpoint_events() {
+        let _logging_guard = iroh_test::logging::setup();
+        let server = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let server_addr = server.node_addr().await.unwrap();
+        let mut events = std::pin::pin!(server.events());
+
+        let accept_task = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await.unwrap().await.unwrap() }
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await.unwrap();
+        let server_conn = accept_task.await.unwrap();
+
+        let event = tokio::time::timeout(
+            Duration::from_secs(10),
+            events.find(|event| matches!(event, EndpointEvent::ConnectionEstablished { .. })),
+        )
+        .await
+        .expect("timeout")
+        .unwrap();
+        assert_eq!(
+            event,
+            EndpointEvent::ConnectionEstablished {
+                node_id: client.node_id(),
+                alpn: TEST_ALPN.to_vec(),
+            }
+        );
+
+        conn.close(0u32.into(), b"done");
+        drop(conn);
+        drop(server_conn);
+        let event = tokio::time::timeout(
+            Duration::from_secs(15),
+            events.find(|event| matches!(event, EndpointEvent::ConnectionClosed { .. })),
+        )
+        .await
+        .expect("timeout")
+        .unwrap();
+        assert_eq!(
+            event,
+            EndpointEvent::ConnectionClosed {
+                node_id: client.node_id(),
+                alpn: TEST_ALPN.to_vec(),
+                reason: ClosedReason::RemoteApplication {
+                    code: 0u32.into(),
+                    reason: bytes::Bytes::from_static(b"done"),
+                },
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_direct_addresses_no_stun_relay() {
+        let _guard = iroh_test::logging::setup();
+        let (relay_map, _, _guard) = run_relay_server_with(None, false).await.unwrap();
+
+        let ep = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), ep.direct_addresses().next())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    #[tokio::test]
+    async fn test_net_report() {
+        let _guard = iroh_test::logging::setup();
+        let (relay_map, _, _guard) = run_relay_server().await.unwrap();
+
+        let ep = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await
+            .unwrap();
+
+        let report = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(report) = ep.net_report() {
+                    break report;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("no net_report report");
+        assert!(report.udp);
+        assert!(report.global_v4.is_some());
+        // Nothing on localhost maps addresses depending on the destination.
+        assert_ne!(
+            report.nat_mapping(),
+            Some(NatMapping::AddressAndPortDependent)
+        );
+
+        // The stream starts with the current report.
+        let mut reports = ep.net_report_stream();
+        let first = tokio::time::timeout(Duration::from_secs(1), reports.next())
+            .await
+            .expect("no report in stream")
+            .unwrap();
+        assert!(first.udp);
+    }
+
+    #[tokio::test]
+    async fn test_direct_addresses_quic_addr_disc() {
+        let _guard = iroh_test::logging::setup();
+        // Without STUN only QUIC address discovery can learn the reflexive address.
+        let (relay_map, _, _guard) = run_relay_server_with(None, true).await.unwrap();
+
+        let ep = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await
+            .unwrap();
+
+        let mut direct_addrs = ep.direct_addresses();
+        tokio::time::timeout(Duration::from_secs(10), async move {
+            while let Some(addrs) = direct_addrs.next().await {
+                if addrs.iter().any(|addr| addr.typ == DirectAddrType::Stun) {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("no reflexive address discovered");
+    }
+
+    #[tokio::test]
+    async fn test_discovery_mode_local_only() {
+        let _logging_guard = iroh_test::logging::setup();
+        let ep1 = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Default)
+            .discovery_n0()
+            .discovery_mode(DiscoveryMode::LocalOnly)
+            .bind()
+            .await
+            .unwrap();
+        let ep2 = Endpoint::builder()
+            .discovery_mode(DiscoveryMode::LocalOnly)
+            .bind()
+            .await
+            .unwrap();
+        assert_eq!(ep1.home_relay(), None);
+        assert_eq!(ep1.port_mapping_status(), PortMappingStatus::Disabled);
+        #[cfg(not(feature = "discovery-local-network"))]
+        assert!(ep1.discovery().is_none());
+
+        let ep1_addr = ep1.node_addr().await.unwrap();
+        assert!(ep1_addr.info.relay_url.is_none());
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move {
+                let conn = ep1.accept().await.unwrap().await.unwrap();
+                conn.closed().await;
+            }
+        });
+        // Without any relay server the connection can only be direct.
+        let conn = ep2.connect(ep1_addr, TEST_ALPN).await.unwrap();
+        conn.close(0u32.into(), b"done");
+        accept.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mtu_discovery_disabled() {
+        let _logging_guard = iroh_test::logging::setup();
+        let ep1 = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .mtu_discovery(None)
+            .bind()
+            .await
+            .unwrap();
+        let ep2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .mtu_discovery(None)
+            .bind()
+            .await
+            .unwrap();
+        let ep1_addr = ep1.node_addr().await.unwrap();
+        let ep2_id = ep2.node_id();
+
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move {
+                let conn = ep1.accept().await.unwrap().await.unwrap();
+                let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                let data = recv.read_to_end(100_000).await.unwrap();
+                send.write_all(&data).await.unwrap();
+                send.finish().unwrap();
+                conn.closed().await;
+                conn
+            }
+        });
+
+        let conn = ep2.connect(ep1_addr, TEST_ALPN).await.unwrap();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(&[0u8; 50_000]).await.unwrap();
+        send.finish().unwrap();
+        let data = recv.read_to_end(100_000).await.unwrap();
+        assert_eq!(data.len(), 50_000);
+        // Without MTU discovery the connection never grows past the initial MTU.
+        assert_eq!(conn.stats().path.current_mtu, 1200);
+        conn.close(0u32.into(), b"done");
+
+        let conn = accept.await.unwrap();
+        assert_eq!(conn.stats().path.current_mtu, 1200);
+        let paths = ep1.path_stats(ep2_id).unwrap();
+        let mtu = paths
+            .iter()
+            .filter_map(|stats| stats.mtu)
+            .max()
+            .expect("no datagrams received on a direct path");
+        assert!(mtu <= 1200);
+    }
+
+    #[tokio::test]
+    async fn test_add_external_address() {
+        let _logging_guard = iroh_test::logging::setup();
+        let external: SocketAddr = "203.0.113.1:4433".parse().unwrap();
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .add_external_address(external)
+            .bind()
+            .await
+            .unwrap();
+
+        let addrs = ep.direct_addresses().next().await.unwrap();
+        assert!(addrs
+            .iter()
+            .any(|addr| addr.addr == external && addr.typ == DirectAddrType::External));
+        let node_addr = ep.node_addr().await.unwrap();
+        assert!(node_addr.info.direct_addresses.contains(&external));
+    }
+
+    #[tokio::test]
+    async fn test_bind_per_interface() {
+        let _logging_guard = iroh_test::logging::setup();
+        let ep1 = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind_per_interface(true)
+            .bind()
+            .await
+            .unwrap();
+        let ep2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+
+        let addrs = ep1.direct_addresses().next().await.unwrap();
+        let local_addrs: Vec<_> = addrs
+            .iter()
+            .filter(|addr| addr.typ == DirectAddrType::Local)
+            .collect();
+        assert!(!local_addrs.is_empty());
+        for addr in local_addrs {
+            assert!(addr.interface.is_some(), "no interface for {addr:?}");
+        }
+
+        let ep1_addr = ep1.node_addr().await.unwrap();
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move {
+                let conn = ep1.accept().await.unwrap().await.unwrap();
+                conn.closed().await;
+            }
+        });
+        let conn = ep2.connect(ep1_addr, TEST_ALPN).await.unwrap();
+        conn.close(0u32.into(), b"done");
+        accept.await.unwrap();
     }
 }
@@ -0,0 +1,78 @@
+//! Access control for incoming connections.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use parking_lot::RwLock;
+
+use crate::NodeId;
+
+/// Decides which remote nodes are allowed to establish incoming connections.
+///
+/// The policy is enforced in two places:
+///
+/// - When an incoming connection is received and the [`Endpoint`] already knows which node
+///   sent it, a denied connection is refused before any part of the handshake is started.
+///   Such connections are never returned from [`Endpoint::accept`].
+/// - During the TLS handshake the [`NodeId`] presented by the remote node is checked, and
+///   the handshake is aborted for denied nodes.
+///
+/// The policy only applies to incoming connections, outgoing connections are not affected.
+///
+/// [`Endpoint`]: crate::Endpoint
+/// [`Endpoint::accept`]: crate::Endpoint::accept
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AccessPolicy {
+    /// Allow connections from any node.
+    #[default]
+    AllowAll,
+    /// Only allow connections from the listed nodes.
+    AllowList(BTreeSet<NodeId>),
+    /// Allow connections from all nodes except the listed ones.
+    DenyList(BTreeSet<NodeId>),
+}
+
+impl AccessPolicy {
+    /// Creates an [`AccessPolicy::AllowList`] from the given nodes.
+    pub fn allow(nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        Self::AllowList(nodes.into_iter().collect())
+    }
+
+    /// Creates an [`AccessPolicy::DenyList`] from the given nodes.
+    pub fn deny(nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        Self::DenyList(nodes.into_iter().collect())
+    }
+
+    /// Returns whether the node is allowed to connect.
+    pub fn is_allowed(&self, node_id: &NodeId) -> bool {
+        match self {
+            Self::AllowAll => true,
+            Self::AllowList(nodes) => nodes.contains(node_id),
+            Self::DenyList(nodes) => !nodes.contains(node_id),
+        }
+    }
+}
+
+/// An [`AccessPolicy`] shared between the endpoint and its TLS configuration.
+pub(crate) type SharedAccessPolicy = Arc<RwLock<AccessPolicy>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_access_policy() {
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+
+        assert!(AccessPolicy::AllowAll.is_allowed(&a));
+
+        let allow = AccessPolicy::allow([a]);
+        assert!(allow.is_allowed(&a));
+        assert!(!allow.is_allowed(&b));
+
+        let deny = AccessPolicy::deny([a]);
+        assert!(!deny.is_allowed(&a));
+        assert!(deny.is_allowed(&b));
+    }
+}
@@ -0,0 +1,285 @@
+//! Typed reasons for closing connections.
+//!
+//! QUIC closes a connection with a numeric error code and a reason.  Protocols usually
+//! define their own set of error codes, which can be described by implementing
+//! [`ErrorCode`].  Connections are closed with such a code using
+//! [`ConnectionExt::close_with`], and [`ConnectionExt::closed_reason`] reports why a
+//! connection was closed as a [`ClosedReason`].
+
+use std::future::Future;
+
+use bytes::Bytes;
+use quinn::{Connection, ConnectionError, VarInt};
+use quinn_proto::TransportErrorCode;
+
+/// An application error code used to close connections.
+///
+/// This is usually implemented by an enum of the error codes of a protocol:
+///
+/// ```
+/// use iroh::endpoint::{ErrorCode, VarInt};
+///
+/// #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// enum ChatError {
+///     Done,
+///     Banned,
+/// }
+///
+/// impl ErrorCode for ChatError {
+///     fn code(&self) -> VarInt {
+///         match self {
+///             ChatError::Done => VarInt::from_u32(0),
+///             ChatError::Banned => VarInt::from_u32(1),
+///         }
+///     }
+///
+///     fn from_code(code: VarInt) -> Option<Self> {
+///         match code.into_inner() {
+///             0 => Some(ChatError::Done),
+///             1 => Some(ChatError::Banned),
+///             _ => None,
+///         }
+///     }
+/// }
+/// ```
+pub trait ErrorCode: Sized {
+    /// Returns the code sent to the remote node.
+    fn code(&self) -> VarInt;
+
+    /// Parses a code received from the remote node, `None` if it is unknown.
+    fn from_code(code: VarInt) -> Option<Self>;
+}
+
+impl ErrorCode for VarInt {
+    fn code(&self) -> VarInt {
+        *self
+    }
+
+    fn from_code(code: VarInt) -> Option<Self> {
+        Some(code)
+    }
+}
+
+/// Which side of a connection closed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, derive_more::Display)]
+pub enum CloseSide {
+    /// This node closed the connection.
+    #[display("local")]
+    Local,
+    /// The remote node closed the connection.
+    #[display("remote")]
+    Remote,
+}
+
+/// Why a connection was closed.
+///
+/// Unlike [`ConnectionError`] this tells apart whether this node or the remote node closed
+/// the connection with [`ClosedReason::side`], and whether the application or the QUIC
+/// transport closed it with [`ClosedReason::is_application`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ClosedReason {
+    /// This node closed the connection, or the [`Endpoint`] it belongs to.
+    ///
+    /// The code and reason used to close the connection are not retained, only the remote
+    /// node receives them.
+    ///
+    /// [`Endpoint`]: crate::Endpoint
+    #[error("closed locally")]
+    LocalApplication,
+    /// The remote node closed the connection.
+    #[error("closed by remote application with code {code}: {}", String::from_utf8_lossy(.reason))]
+    RemoteApplication {
+        /// The application error code, see [`ClosedReason::application_code`].
+        code: VarInt,
+        /// The reason given by the remote node.
+        reason: Bytes,
+    },
+    /// The QUIC transport of this node aborted the connection, e.g. because the remote node
+    /// violated the protocol.
+    #[error("aborted locally with {code}: {reason}")]
+    LocalTransport {
+        /// The transport error code.
+        code: TransportErrorCode,
+        /// A description of the error.
+        reason: String,
+    },
+    /// The QUIC transport of the remote node aborted the connection.
+    #[error("aborted by remote with {code}: {}", String::from_utf8_lossy(.reason))]
+    RemoteTransport {
+        /// The transport error code.
+        code: TransportErrorCode,
+        /// The reason given by the remote node.
+        reason: Bytes,
+    },
+    /// Nothing was received from the remote node for longer than the idle timeout.
+    #[error("timed out")]
+    TimedOut,
+    /// The remote node reset the connection, usually because it lost the connection state.
+    #[error("reset by remote")]
+    Reset,
+    /// The remote node does not support any of the QUIC versions of this node.
+    #[error("QUIC version mismatch")]
+    VersionMismatch,
+}
+
+impl ClosedReason {
+    /// Returns which side closed the connection.
+    ///
+    /// Idle timeouts are detected by this node and thus [`CloseSide::Local`].
+    pub fn side(&self) -> CloseSide {
+        match self {
+            Self::LocalApplication | Self::LocalTransport { .. } | Self::TimedOut => {
+                CloseSide::Local
+            }
+            Self::RemoteApplication { .. }
+            | Self::RemoteTransport { .. }
+            | Self::Reset
+            | Self::VersionMismatch => CloseSide::Remote,
+        }
+    }
+
+    /// Returns whether an application closed the connection, rather than the transport.
+    pub fn is_application(&self) -> bool {
+        matches!(
+            self,
+            Self::LocalApplication | Self::RemoteApplication { .. }
+        )
+    }
+
+    /// Returns the error code the remote application closed the connection with.
+    ///
+    /// Returns `None` if the connection was not closed by the remote application, or if
+    /// `C` does not know the code.
+    pub fn application_code<C: ErrorCode>(&self) -> Option<C> {
+        match self {
+            Self::RemoteApplication { code, .. } => C::from_code(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns the reason given by the remote node when closing the connection.
+    pub fn reason(&self) -> Option<&[u8]> {
+        match self {
+            Self::RemoteApplication { reason, .. } | Self::RemoteTransport { reason, .. } => {
+                Some(reason)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<ConnectionError> for ClosedReason {
+    fn from(err: ConnectionError) -> Self {
+        match err {
+            ConnectionError::LocallyClosed => Self::LocalApplication,
+            ConnectionError::ApplicationClosed(close) => Self::RemoteApplication {
+                code: close.error_code,
+                reason: close.reason,
+            },
+            ConnectionError::TransportError(err) => Self::LocalTransport {
+                code: err.code,
+                reason: err.reason,
+            },
+            ConnectionError::CidsExhausted => Self::LocalTransport {
+                code: TransportErrorCode::CONNECTION_ID_LIMIT_ERROR,
+                reason: "connection IDs exhausted".to_string(),
+            },
+            ConnectionError::ConnectionClosed(close) => Self::RemoteTransport {
+                code: close.error_code,
+                reason: close.reason,
+            },
+            ConnectionError::TimedOut => Self::TimedOut,
+            ConnectionError::Reset => Self::Reset,
+            ConnectionError::VersionMismatch => Self::VersionMismatch,
+        }
+    }
+}
+
+/// Extension trait to [`Connection`] for closing connections with typed error codes.
+pub trait ConnectionExt {
+    /// Closes the connection with an application error code and a reason.
+    ///
+    /// The remote node receives them as [`ClosedReason::RemoteApplication`].
+    fn close_with(&self, code: impl ErrorCode, reason: &str);
+
+    /// Returns why the connection was closed, or `None` while it is open.
+    fn closed_reason(&self) -> Option<ClosedReason>;
+
+    /// Waits for the connection to be closed and returns why.
+    fn wait_closed(&self) -> impl Future<Output = ClosedReason> + Send;
+}
+
+impl ConnectionExt for Connection {
+    fn close_with(&self, code: impl ErrorCode, reason: &str) {
+        self.close(code.code(), reason.as_bytes());
+    }
+
+    fn closed_reason(&self) -> Option<ClosedReason> {
+        self.close_reason().map(Into::into)
+    }
+
+    async fn wait_closed(&self) -> ClosedReason {
+        self.closed().await.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Banned;
+
+    impl ErrorCode for Banned {
+        fn code(&self) -> VarInt {
+            VarInt::from_u32(3)
+        }
+
+        fn from_code(code: VarInt) -> Option<Self> {
+            (code == VarInt::from_u32(3)).then_some(Banned)
+        }
+    }
+
+    #[test]
+    fn test_from_connection_error() {
+        let reason = ClosedReason::from(ConnectionError::ApplicationClosed(
+            quinn::ApplicationClose {
+                error_code: Banned.code(),
+                reason: Bytes::from_static(b"go away"),
+            },
+        ));
+        assert_eq!(reason.side(), CloseSide::Remote);
+        assert!(reason.is_application());
+        assert_eq!(reason.application_code(), Some(Banned));
+        assert_eq!(reason.application_code(), Some(VarInt::from_u32(3)));
+        assert_eq!(reason.reason(), Some(&b"go away"[..]));
+
+        let other = ClosedReason::from(ConnectionError::ApplicationClosed(
+            quinn::ApplicationClose {
+                error_code: VarInt::from_u32(4),
+                reason: Bytes::new(),
+            },
+        ));
+        assert_eq!(other.application_code::<Banned>(), None);
+
+        let local = ClosedReason::from(ConnectionError::LocallyClosed);
+        assert_eq!(local.side(), CloseSide::Local);
+        assert!(local.is_application());
+        assert_eq!(local.application_code::<VarInt>(), None);
+
+        let transport = ClosedReason::from(ConnectionError::ConnectionClosed(
+            quinn_proto::ConnectionClose {
+                error_code: TransportErrorCode::PROTOCOL_VIOLATION,
+                frame_type: None,
+                reason: Bytes::new(),
+            },
+        ));
+        assert_eq!(transport.side(), CloseSide::Remote);
+        assert!(!transport.is_application());
+
+        let timeout = ClosedReason::from(ConnectionError::TimedOut);
+        assert_eq!(timeout.side(), CloseSide::Local);
+        assert!(!timeout.is_application());
+    }
+}
@@ -0,0 +1,160 @@
+//! The error returned when connecting to a remote node fails.
+
+use iroh_base::key::NodeId;
+use iroh_relay::RelayUrl;
+use quinn_proto::TransportErrorCode;
+
+use crate::tls;
+
+/// TLS alert sent when none of the offered ALPNs is supported, see RFC 8446.
+const ALERT_NO_APPLICATION_PROTOCOL: u8 = 120;
+
+/// TLS alerts sent when a certificate, and thus the identity of a node, is rejected.
+///
+/// These are `bad_certificate`, `unsupported_certificate`, `certificate_expired`,
+/// `certificate_unknown`, `illegal_parameter` and `unknown_ca` from RFC 8446.
+const ALERTS_IDENTITY: [u8; 6] = [42, 43, 45, 46, 47, 48];
+
+/// TLS alert sent when the remote node refuses us access, see RFC 8446.
+const ALERT_ACCESS_DENIED: u8 = 49;
+
+/// Error returned by [`Endpoint::connect`].
+///
+/// [`Endpoint::connect`]: crate::Endpoint::connect
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ConnectError {
+    /// Connecting to ourselves is not supported.
+    #[error("Connecting to ourself is not supported ({} is the node id of this node)", .0.fmt_short())]
+    SelfConnect(NodeId),
+    /// The addressing information provided for the node could not be used.
+    #[error("Invalid addressing information for NodeId({})", .node_id.fmt_short())]
+    InvalidNodeAddr {
+        /// The node we tried to connect to.
+        node_id: NodeId,
+        /// Why the addressing information was rejected.
+        #[source]
+        source: anyhow::Error,
+    },
+    /// No addressing information was known, and discovery could not provide any.
+    #[error("Discovery failed to find addressing information for NodeId({})", .node_id.fmt_short())]
+    Discovery {
+        /// The node we tried to connect to.
+        node_id: NodeId,
+        /// The error from the discovery service.
+        #[source]
+        source: anyhow::Error,
+    },
+    /// No nodes were given to connect to.
+    #[error("No nodes to connect to")]
+    NoNodes,
+    /// No addressing information is known and no discovery service is configured.
+    #[error("No addressing information for NodeId({}), unable to connect", .0.fmt_short())]
+    NoAddress(NodeId),
+    /// The node could only be reached via its relay server, but nothing was received from it.
+    ///
+    /// Either the relay server is unreachable, or the node is not connected to it.
+    #[error("NodeId({}) is not reachable via relay {relay_url}", .node_id.fmt_short())]
+    RelayUnreachable {
+        /// The node we tried to connect to.
+        node_id: NodeId,
+        /// The relay server the node was dialed through.
+        relay_url: RelayUrl,
+    },
+    /// No direct path to the node could be established, and the relay server was not allowed
+    /// to be used.
+    ///
+    /// See [`PathPolicy::DirectOnly`].
+    ///
+    /// [`PathPolicy::DirectOnly`]: crate::endpoint::PathPolicy::DirectOnly
+    #[error("No direct path to NodeId({}) could be established", .0.fmt_short())]
+    NoDirectPath(NodeId),
+    /// The path, multipath or address family policy contradicts the policy of an open
+    /// connection to the node.
+    ///
+    /// See [`ConnectOptions::path_policy`].
+    ///
+    /// [`ConnectOptions::path_policy`]: crate::endpoint::ConnectOptions::path_policy
+    #[error("Policy conflicts with an open connection to NodeId({})", .0.fmt_short())]
+    PolicyConflict(NodeId),
+    /// The identity of the remote node did not match the [`NodeId`] we dialed.
+    #[error("Remote node failed identity verification")]
+    IdentityMismatch,
+    /// The remote node does not accept connections from our node.
+    #[error("Remote node denied access")]
+    AccessDenied,
+    /// The remote node does not support the requested ALPN.
+    #[error("Remote node does not support the ALPN")]
+    AlpnRejected,
+    /// The connection could not be established in time.
+    #[error("Timed out establishing the connection")]
+    Timeout,
+    /// Creating the TLS configuration failed.
+    #[error("Failed to create the TLS configuration")]
+    TlsConfig(#[from] tls::CreateConfigError),
+    /// The connection attempt could not be started.
+    #[error("Failed to start connecting")]
+    Connect(#[from] quinn::ConnectError),
+    /// The connection failed for another reason.
+    #[error("Connection failed")]
+    Connection(#[source] quinn::ConnectionError),
+}
+
+impl From<quinn::ConnectionError> for ConnectError {
+    fn from(err: quinn::ConnectionError) -> Self {
+        let code = match err {
+            quinn::ConnectionError::TimedOut => return Self::Timeout,
+            quinn::ConnectionError::TransportError(ref err) => err.code,
+            quinn::ConnectionError::ConnectionClosed(ref close) => close.error_code,
+            _ => return Self::Connection(err),
+        };
+        match tls_alert(code) {
+            Some(ALERT_NO_APPLICATION_PROTOCOL) => Self::AlpnRejected,
+            Some(ALERT_ACCESS_DENIED) => Self::AccessDenied,
+            Some(alert) if ALERTS_IDENTITY.contains(&alert) => Self::IdentityMismatch,
+            _ => Self::Connection(err),
+        }
+    }
+}
+
+/// Returns the TLS alert carried by a QUIC crypto error code, see RFC 9001.
+fn tls_alert(code: TransportErrorCode) -> Option<u8> {
+    let code = u64::from(code);
+    (0x100..0x200).contains(&code).then(|| (code - 0x100) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_connection_error() {
+        let closed = |alert| {
+            quinn::ConnectionError::ConnectionClosed(quinn_proto::ConnectionClose {
+                error_code: TransportErrorCode::crypto(alert),
+                frame_type: None,
+                reason: Default::default(),
+            })
+        };
+        assert!(matches!(
+            ConnectError::from(closed(ALERT_NO_APPLICATION_PROTOCOL)),
+            ConnectError::AlpnRejected
+        ));
+        assert!(matches!(
+            ConnectError::from(closed(ALERT_ACCESS_DENIED)),
+            ConnectError::AccessDenied
+        ));
+        assert!(matches!(
+            ConnectError::from(closed(42)),
+            ConnectError::IdentityMismatch
+        ));
+        assert!(matches!(
+            ConnectError::from(closed(40)),
+            ConnectError::Connection(_)
+        ));
+        assert!(matches!(
+            ConnectError::from(quinn::ConnectionError::TimedOut),
+            ConnectError::Timeout
+        ));
+    }
+}
@@ -0,0 +1,339 @@
+//! Options for a single outgoing connection.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+use quinn::WeakConnectionHandle;
+
+use crate::{
+    magicsock::{AddrFamilyPolicy, MultipathPolicy, PathPolicy},
+    NodeId,
+};
+
+/// Options for [`Endpoint::connect_with_opts`].
+///
+/// The default options are used by [`Endpoint::connect`].
+///
+/// [`Endpoint::connect`]: crate::Endpoint::connect
+/// [`Endpoint::connect_with_opts`]: crate::Endpoint::connect_with_opts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectOptions {
+    pub(super) path_policy: PathPolicy,
+    pub(super) multipath: Option<MultipathPolicy>,
+    pub(super) addr_family: Option<AddrFamilyPolicy>,
+    pub(super) max_send_rate: Option<u64>,
+}
+
+impl ConnectOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets which paths may be used to send data to the remote node.
+    ///
+    /// All connections to a node share the paths to it, so the policy applies to all
+    /// connections with the node, including incoming ones.  It stays in effect as long as
+    /// the connection is open: connecting again with [`PathPolicy::Any`] keeps using the
+    /// policy of the open connection, while connecting with a policy which contradicts it
+    /// fails with [`ConnectError::PolicyConflict`].  By default [`PathPolicy::Any`] is used.
+    ///
+    /// With [`PathPolicy::DirectOnly`] a connection attempt fails with
+    /// [`ConnectError::NoDirectPath`] if no direct path can be established.
+    ///
+    /// [`ConnectError::NoDirectPath`]: super::ConnectError::NoDirectPath
+    /// [`ConnectError::PolicyConflict`]: super::ConnectError::PolicyConflict
+    pub fn path_policy(mut self, policy: PathPolicy) -> Self {
+        self.path_policy = policy;
+        self
+    }
+
+    /// Sets how data is spread when several direct paths to the remote node work.
+    ///
+    /// Like the [path policy](Self::path_policy) this applies to all connections with the
+    /// node for as long as the connection is open.  Connections which do not set a
+    /// multipath policy keep using the policy of the open connections, connecting with a
+    /// different policy fails with [`ConnectError::PolicyConflict`].  Without any policy
+    /// [`MultipathPolicy::BestOnly`] is used.
+    ///
+    /// [`ConnectError::PolicyConflict`]: super::ConnectError::PolicyConflict
+    pub fn multipath(mut self, policy: MultipathPolicy) -> Self {
+        self.multipath = Some(policy);
+        self
+    }
+
+    /// Sets which address families may be used for direct paths to the remote node.
+    ///
+    /// Like the [path policy](Self::path_policy) this applies to all connections with the
+    /// node for as long as the connection is open.  Connections which do not set an address
+    /// family policy keep using the policy of the open connections, connecting with a
+    /// different policy fails with [`ConnectError::PolicyConflict`].  Without any policy
+    /// the policy set with [`Builder::addr_family_policy`] is used.
+    ///
+    /// [`Builder::addr_family_policy`]: crate::endpoint::Builder::addr_family_policy
+    /// [`ConnectError::PolicyConflict`]: super::ConnectError::PolicyConflict
+    pub fn addr_family_policy(mut self, policy: AddrFamilyPolicy) -> Self {
+        self.addr_family = Some(policy);
+        self
+    }
+
+    /// Limits the rate at which the connection sends data to `bytes_per_sec` bytes per
+    /// second.
+    ///
+    /// See [`Endpoint::connect_with_max_send_rate`] for details.
+    ///
+    /// [`Endpoint::connect_with_max_send_rate`]: crate::Endpoint::connect_with_max_send_rate
+    pub fn max_send_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.max_send_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Returns the policies for the paths to the remote node.
+    pub(super) fn policies(&self) -> NodePolicies {
+        NodePolicies {
+            path: self.path_policy,
+            multipath: self.multipath,
+            addr_family: self.addr_family,
+        }
+    }
+}
+
+/// The policies for the paths to a node, shared by all connections with it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct NodePolicies {
+    pub(super) path: PathPolicy,
+    /// The multipath policy, `None` if the connection does not care.
+    pub(super) multipath: Option<MultipathPolicy>,
+    /// The address family policy, `None` to use the endpoint's policy.
+    pub(super) addr_family: Option<AddrFamilyPolicy>,
+}
+
+impl NodePolicies {
+    /// Combines the policies of two connections, if they do not contradict.
+    fn combine(self, other: Self) -> Option<Self> {
+        Some(Self {
+            path: combine_path_policies(self.path, other.path)?,
+            multipath: combine_preferences(self.multipath, other.multipath)?,
+            addr_family: combine_preferences(self.addr_family, other.addr_family)?,
+        })
+    }
+}
+
+/// The policies of the open outgoing connections.
+///
+/// All connections to a node share the paths to it, so the policies in effect for a node
+/// combine the policies of its connections, see [`ConnectOptions::path_policy`].  Only weak
+/// handles are kept, a connection stops constraining the policies once it is closed and all
+/// handles to it are dropped.
+#[derive(Debug, Default)]
+pub(super) struct ConnectionPolicies {
+    entries: Mutex<Vec<PolicyEntry>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct PolicyEntry {
+    id: u64,
+    node_id: NodeId,
+    policies: NodePolicies,
+    /// The connection using the policies, `None` while still connecting.
+    connection: Option<WeakConnectionHandle>,
+}
+
+/// The policies of a new connection contradict the policies of an open connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct PolicyConflict;
+
+impl ConnectionPolicies {
+    /// Reserves `policies` for a new connection to `node_id`.
+    ///
+    /// Returns the policies in effect for the node together with the open connections, or
+    /// an error if the policies contradict each other.  The reservation is released when
+    /// the returned [`PolicyReservation`] is dropped without
+    /// [`PolicyReservation::commit`]ting it to an established connection.
+    pub(super) fn reserve(
+        &self,
+        node_id: NodeId,
+        policies: NodePolicies,
+    ) -> Result<PolicyReservation<'_>, PolicyConflict> {
+        let mut entries = self.entries.lock();
+        entries.retain(|entry| entry.connection.as_ref().map_or(true, |c| c.is_alive()));
+        let combined = entries
+            .iter()
+            .filter(|entry| entry.node_id == node_id)
+            .try_fold(policies, |policies, entry| policies.combine(entry.policies))
+            .ok_or(PolicyConflict)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        entries.push(PolicyEntry {
+            id,
+            node_id,
+            policies,
+            connection: None,
+        });
+        Ok(PolicyReservation {
+            registry: self,
+            id,
+            policies: combined,
+        })
+    }
+}
+
+/// Combines two path policies into the most restrictive one, if they do not contradict.
+fn combine_path_policies(a: PathPolicy, b: PathPolicy) -> Option<PathPolicy> {
+    match (a, b) {
+        (PathPolicy::Any, policy) | (policy, PathPolicy::Any) => Some(policy),
+        (a, b) if a == b => Some(a),
+        _ => None,
+    }
+}
+
+/// Combines two optional policies, if they do not contradict.
+///
+/// Returns `None` if both are set to different policies.
+fn combine_preferences<T: PartialEq>(a: Option<T>, b: Option<T>) -> Option<Option<T>> {
+    match (a, b) {
+        (None, policy) | (policy, None) => Some(policy),
+        (a, b) if a == b => Some(a),
+        _ => None,
+    }
+}
+
+/// The policies reserved for a connection attempt, see [`ConnectionPolicies::reserve`].
+#[derive(Debug)]
+pub(super) struct PolicyReservation<'a> {
+    registry: &'a ConnectionPolicies,
+    id: u64,
+    policies: NodePolicies,
+}
+
+impl PolicyReservation<'_> {
+    /// The policies in effect for the node.
+    pub(super) fn policies(&self) -> NodePolicies {
+        self.policies
+    }
+
+    /// Keeps the policies in effect for as long as the `connection` is alive.
+    pub(super) fn commit(self, connection: WeakConnectionHandle) {
+        let mut entries = self.registry.entries.lock();
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.id == self.id) {
+            entry.connection = Some(connection);
+        }
+    }
+}
+
+impl Drop for PolicyReservation<'_> {
+    fn drop(&mut self) {
+        // Only removes the reservation if it was not committed.
+        self.registry
+            .entries
+            .lock()
+            .retain(|entry| entry.id != self.id || entry.connection.is_some());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    fn path(path: PathPolicy) -> NodePolicies {
+        NodePolicies {
+            path,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_combine_path_policies() {
+        use PathPolicy::*;
+        assert_eq!(combine_path_policies(Any, Any), Some(Any));
+        assert_eq!(combine_path_policies(Any, RelayOnly), Some(RelayOnly));
+        assert_eq!(combine_path_policies(DirectOnly, Any), Some(DirectOnly));
+        assert_eq!(combine_path_policies(RelayOnly, RelayOnly), Some(RelayOnly));
+        assert_eq!(combine_path_policies(RelayOnly, DirectOnly), None);
+        assert_eq!(combine_path_policies(DirectOnly, RelayOnly), None);
+    }
+
+    #[test]
+    fn test_combine_preferences() {
+        use MultipathPolicy::*;
+        assert_eq!(
+            combine_preferences::<MultipathPolicy>(None, None),
+            Some(None)
+        );
+        assert_eq!(
+            combine_preferences(None, Some(Aggregate)),
+            Some(Some(Aggregate))
+        );
+        assert_eq!(
+            combine_preferences(Some(BestOnly), None),
+            Some(Some(BestOnly))
+        );
+        assert_eq!(
+            combine_preferences(Some(ActiveBackup), Some(ActiveBackup)),
+            Some(Some(ActiveBackup))
+        );
+        assert_eq!(combine_preferences(Some(BestOnly), Some(Aggregate)), None);
+    }
+
+    #[test]
+    fn test_policy_reservations() {
+        let policies = ConnectionPolicies::default();
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+
+        let relay_only = policies.reserve(a, path(PathPolicy::RelayOnly)).unwrap();
+        // A plain connection keeps the stricter policy of the pending one.
+        let any = policies.reserve(a, path(PathPolicy::Any)).unwrap();
+        assert_eq!(any.policies().path, PathPolicy::RelayOnly);
+        assert_eq!(
+            policies
+                .reserve(a, path(PathPolicy::DirectOnly))
+                .unwrap_err(),
+            PolicyConflict
+        );
+        // Other nodes are not affected.
+        let other = policies.reserve(b, path(PathPolicy::DirectOnly)).unwrap();
+        assert_eq!(other.policies().path, PathPolicy::DirectOnly);
+
+        // Failed connection attempts release their policy.
+        drop(relay_only);
+        drop(any);
+        let direct = policies.reserve(a, path(PathPolicy::DirectOnly)).unwrap();
+        assert_eq!(direct.policies().path, PathPolicy::DirectOnly);
+    }
+
+    #[test]
+    fn test_multipath_and_addr_family_reservations() {
+        let policies = ConnectionPolicies::default();
+        let a = SecretKey::generate().public();
+
+        let aggregate = NodePolicies {
+            multipath: Some(MultipathPolicy::Aggregate),
+            addr_family: Some(AddrFamilyPolicy::Ipv4Only),
+            ..Default::default()
+        };
+        let first = policies.reserve(a, aggregate).unwrap();
+        assert_eq!(first.policies(), aggregate);
+        // Connections without a preference keep the policies of the open connections.
+        let plain = policies.reserve(a, NodePolicies::default()).unwrap();
+        assert_eq!(plain.policies(), aggregate);
+
+        let best_only = NodePolicies {
+            multipath: Some(MultipathPolicy::BestOnly),
+            ..Default::default()
+        };
+        assert!(policies.reserve(a, best_only).is_err());
+        let ipv6_only = NodePolicies {
+            addr_family: Some(AddrFamilyPolicy::Ipv6Only),
+            ..Default::default()
+        };
+        assert!(policies.reserve(a, ipv6_only).is_err());
+
+        // Once the connections are gone, other policies can be used.
+        drop(first);
+        drop(plain);
+        let reservation = policies.reserve(a, best_only).unwrap();
+        assert_eq!(reservation.policies(), best_only);
+    }
+}
@@ -0,0 +1,327 @@
+//! Events describing changes to the state of the [`Endpoint`].
+//!
+//! See [`Endpoint::events`] for details.  With the `event-log` feature the events can also
+//! be written as JSON lines, see [`Builder::event_log`].
+//!
+//! [`Endpoint`]: super::Endpoint
+//! [`Endpoint::events`]: super::Endpoint::events
+//! [`Builder::event_log`]: super::Builder::event_log
+
+use std::collections::BTreeSet;
+#[cfg(feature = "event-log")]
+use std::{
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_lite::{Stream, StreamExt};
+use iroh_base::{key::NodeId, node_addr::AddrInfo};
+use tokio::sync::broadcast;
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, info_span, warn, Instrument};
+
+use super::{ClosedReason, Portal};
+use crate::{
+    magicsock::{ConnectionType, DirectAddr, Handle},
+    RelayUrl,
+};
+
+/// How many events are buffered for each subscriber before older events are dropped.
+const EVENTS_CAPACITY: usize = 128;
+
+/// An event emitted by the [`Endpoint`].
+///
+/// See [`Endpoint::events`].
+///
+/// [`Endpoint`]: super::Endpoint
+/// [`Endpoint::events`]: super::Endpoint::events
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EndpointEvent {
+    /// A connection to a remote node was established, either by connecting or accepting.
+    ConnectionEstablished {
+        /// The remote node.
+        node_id: NodeId,
+        /// The ALPN negotiated for the connection.
+        alpn: Vec<u8>,
+    },
+    /// A connection to a remote node was closed.
+    ///
+    /// This is emitted as soon as the connection is closed, for the connections established
+    /// while the events are subscribed to.  To be able to report the reason the endpoint
+    /// holds a handle to each of these connections until they are closed: dropping all
+    /// other handles does not close them, they must be closed explicitly or by the remote
+    /// node, or time out.
+    ConnectionClosed {
+        /// The remote node.
+        node_id: NodeId,
+        /// The ALPN negotiated for the connection.
+        alpn: Vec<u8>,
+        /// Why the connection was closed.
+        reason: ClosedReason,
+    },
+    /// The path used to send data to a remote node with open connections changed.
+    ///
+    /// This is also emitted with the initial path when the first connection to a node is
+    /// established.
+    PathChanged {
+        /// The remote node.
+        node_id: NodeId,
+        /// The new kind of path.
+        conn_type: ConnectionType,
+    },
+    /// The home relay of this endpoint changed.
+    HomeRelayChanged(RelayUrl),
+    /// The direct addresses of this endpoint changed.
+    DirectAddrsChanged(BTreeSet<DirectAddr>),
+    /// A captive portal was detected on the network, or is no longer detected.
+    ///
+    /// While a captive portal is detected relay servers and other nodes are usually not
+    /// reachable, applications should ask the user to sign in to the network, e.g. by
+    /// opening a web browser.  Once the network is usable again this is emitted with
+    /// [`Portal::NotDetected`].  See [`NetReport::portal`] for how portals are detected.
+    ///
+    /// [`NetReport::portal`]: super::NetReport::portal
+    PortalChanged(Portal),
+    /// The connection to a relay server was established, or re-established after it was
+    /// lost.
+    RelayConnected(RelayUrl),
+    /// The connection to a relay server was lost.
+    ///
+    /// The connection is re-established as long as the relay server is used.
+    RelayDisconnected(RelayUrl),
+    /// The addresses of this endpoint were published to the discovery service.
+    DiscoveryPublished(AddrInfo),
+    /// A connection attempt failed before the connection was established.
+    ///
+    /// This includes failing to find the addresses of the remote node as well as failed
+    /// handshakes.
+    ConnectionFailed {
+        /// The remote node, unknown for incoming connections failing during the handshake.
+        node_id: Option<NodeId>,
+        /// Whether the remote node was connecting to us.
+        incoming: bool,
+        /// What went wrong.
+        error: String,
+    },
+}
+
+impl EndpointEvent {
+    /// Creates the sender to broadcast events with.
+    pub(crate) fn sender() -> broadcast::Sender<Self> {
+        broadcast::channel(EVENTS_CAPACITY).0
+    }
+
+    /// Returns the event as a JSON object, with its type in the `event` field.
+    #[cfg(feature = "event-log")]
+    fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+
+        let alpn = |alpn: &[u8]| String::from_utf8_lossy(alpn).into_owned();
+        match self {
+            Self::ConnectionEstablished { node_id, alpn: a } => json!({
+                "event": "connection_established",
+                "node_id": node_id.to_string(),
+                "alpn": alpn(a),
+            }),
+            Self::ConnectionClosed {
+                node_id,
+                alpn: a,
+                reason,
+            } => json!({
+                "event": "connection_closed",
+                "node_id": node_id.to_string(),
+                "alpn": alpn(a),
+                "reason": reason.to_string(),
+            }),
+            Self::PathChanged { node_id, conn_type } => json!({
+                "event": "path_changed",
+                "node_id": node_id.to_string(),
+                "conn_type": conn_type.to_string(),
+            }),
+            Self::HomeRelayChanged(url) => json!({
+                "event": "home_relay_changed",
+                "relay_url": url.to_string(),
+            }),
+            Self::DirectAddrsChanged(addrs) => json!({
+                "event": "direct_addrs_changed",
+                "addrs": addrs
+                    .iter()
+                    .map(|addr| json!({"addr": addr.addr.to_string(), "type": addr.typ.to_string()}))
+                    .collect::<Vec<_>>(),
+            }),
+            Self::PortalChanged(portal) => json!({
+                "event": "portal_changed",
+                "portal": portal.to_string(),
+            }),
+            Self::RelayConnected(url) => json!({
+                "event": "relay_connected",
+                "relay_url": url.to_string(),
+            }),
+            Self::RelayDisconnected(url) => json!({
+                "event": "relay_disconnected",
+                "relay_url": url.to_string(),
+            }),
+            Self::DiscoveryPublished(info) => json!({
+                "event": "discovery_published",
+                "relay_url": info.relay_url.as_ref().map(|url| url.to_string()),
+                "direct_addrs": info
+                    .direct_addresses
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<_>>(),
+            }),
+            Self::ConnectionFailed {
+                node_id,
+                incoming,
+                error,
+            } => json!({
+                "event": "connection_failed",
+                "node_id": node_id.map(|node_id| node_id.to_string()),
+                "incoming": incoming,
+                "error": error,
+            }),
+        }
+    }
+}
+
+/// Broadcasts [`EndpointEvent`]s to all subscribers.
+#[derive(Debug)]
+pub(super) struct EventsHandle {
+    pub(super) sender: broadcast::Sender<EndpointEvent>,
+    _network_task: AbortOnDropHandle<()>,
+}
+
+impl EventsHandle {
+    /// Creates the handle and starts forwarding the network changes of the magic socket.
+    ///
+    /// The events are sent on the sender of the magic socket, which emits some itself.
+    pub(super) fn new(msock: &Handle) -> Self {
+        let sender = msock.events().clone();
+        let home_relay = msock
+            .watch_home_relay()
+            .map(EndpointEvent::HomeRelayChanged);
+        let direct_addrs = msock
+            .direct_addresses()
+            .map(EndpointEvent::DirectAddrsChanged);
+        // Only changes are reported, so nothing is emitted as long as no portal is detected.
+        let mut last_portal = Portal::NotDetected;
+        let portal = msock.watch_net_report().filter_map(move |report| {
+            let portal = report.portal()?;
+            (std::mem::replace(&mut last_portal, portal) != portal).then_some(portal)
+        });
+        let portal = portal.map(EndpointEvent::PortalChanged);
+        let mut network_events = home_relay.race(direct_addrs).race(portal);
+        let tx = sender.clone();
+        let task = tokio::spawn(
+            async move {
+                while let Some(event) = network_events.next().await {
+                    // Sending only fails when there are no subscribers.
+                    tx.send(event).ok();
+                }
+                debug!("network events finished");
+            }
+            .instrument(info_span!("endpoint-events")),
+        );
+        Self {
+            sender,
+            _network_task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Returns a stream of all events emitted from now on.
+    pub(super) fn subscribe(&self) -> impl Stream<Item = EndpointEvent> {
+        futures_lite::stream::unfold(self.sender.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("endpoint events subscriber lagged, {n} events dropped");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+/// Writes all events from now on as JSON lines to `writer`.
+///
+/// Each line is a JSON object with the milliseconds since the Unix epoch in `timestamp_ms`
+/// and the type of the event in `event`.  The events are written from a thread of its own,
+/// which ends once the endpoint is gone or writing fails.
+#[cfg(feature = "event-log")]
+pub(super) fn spawn_event_log(
+    sender: &broadcast::Sender<EndpointEvent>,
+    mut writer: Box<dyn Write + Send>,
+) -> std::io::Result<()> {
+    let mut rx = sender.subscribe();
+    std::thread::Builder::new()
+        .name("iroh-event-log".into())
+        .spawn(move || loop {
+            let event = match rx.blocking_recv() {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("event log lagged, {n} events dropped");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if let Err(err) = write_event(&mut writer, &event) {
+                warn!("failed to write event log, stopping: {err:#}");
+                break;
+            }
+        })?;
+    Ok(())
+}
+
+/// Writes a single event as a JSON line.
+#[cfg(feature = "event-log")]
+fn write_event(writer: &mut impl Write, event: &EndpointEvent) -> std::io::Result<()> {
+    let mut line = event.to_json();
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    line["timestamp_ms"] = timestamp_ms.into();
+    serde_json::to_writer(&mut *writer, &line)?;
+    writer.write_all(b"\n")?;
+    writer.flush()
+}
+
+#[cfg(all(test, feature = "event-log"))]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_write_event() {
+        let node_id = SecretKey::generate().public();
+        let mut buf = Vec::new();
+        write_event(
+            &mut buf,
+            &EndpointEvent::ConnectionFailed {
+                node_id: Some(node_id),
+                incoming: false,
+                error: "timed out".into(),
+            },
+        )
+        .unwrap();
+        let url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        write_event(&mut buf, &EndpointEvent::RelayConnected(url.clone())).unwrap();
+
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&buf)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "connection_failed");
+        assert_eq!(lines[0]["node_id"], node_id.to_string());
+        assert_eq!(lines[0]["incoming"], false);
+        assert_eq!(lines[0]["error"], "timed out");
+        assert!(lines[0]["timestamp_ms"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["event"], "relay_connected");
+        assert_eq!(lines[1]["relay_url"], url.to_string());
+    }
+}
@@ -0,0 +1,215 @@
+//! Connections with plain QUIC peers which are not iroh nodes.
+//!
+//! See [`Builder::interop`] for details.
+//!
+//! [`Builder::interop`]: super::Builder::interop
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use anyhow::{ensure, Context, Result};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    Connection, Incoming, TransportConfig,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tracing::debug;
+
+/// Configuration for connections with plain QUIC peers, see [`Builder::interop`].
+///
+/// By default only outgoing connections are made, verifying the certificates of servers
+/// against the Mozilla root certificates like a browser would.  Use
+/// [`InteropConfig::accept`] to accept connections from plain QUIC clients as well.
+///
+/// [`Builder::interop`]: super::Builder::interop
+#[derive(Debug, Clone)]
+pub struct InteropConfig {
+    root_certificates: rustls::RootCertStore,
+    server_config: Option<Arc<rustls::ServerConfig>>,
+    bind_addr: Option<SocketAddr>,
+}
+
+impl Default for InteropConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InteropConfig {
+    /// Creates a configuration for outgoing connections only.
+    pub fn new() -> Self {
+        Self {
+            root_certificates: rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+            server_config: None,
+            bind_addr: None,
+        }
+    }
+
+    /// Sets the address the UDP socket for plain QUIC peers is bound to.
+    ///
+    /// By default a random port is used, on all IPv6 and IPv4 addresses if the system
+    /// supports dual-stack sockets, otherwise on all IPv4 addresses.  Plain QUIC clients need
+    /// a fixed port to connect to, see [`Endpoint::interop_local_addr`].
+    ///
+    /// [`Endpoint::interop_local_addr`]: super::Endpoint::interop_local_addr
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    /// Replaces the root certificates the certificates of servers are verified against.
+    ///
+    /// This is useful for servers with certificates of a private certificate authority.
+    pub fn root_certificates(mut self, roots: rustls::RootCertStore) -> Self {
+        self.root_certificates = roots;
+        self
+    }
+
+    /// Accepts connections from plain QUIC clients using one of the `alpns`.
+    ///
+    /// The endpoint presents the certificate chain `cert_chain` with its private `key`,
+    /// clients are not authenticated.  The ALPNs should differ from those of
+    /// [`Builder::alpns`], so clients can not confuse the two kinds of endpoints.
+    ///
+    /// Fails if no ALPN is given or the key does not match the certificate.
+    ///
+    /// [`Builder::alpns`]: super::Builder::alpns
+    pub fn accept(
+        mut self,
+        alpns: Vec<Vec<u8>>,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self> {
+        ensure!(!alpns.is_empty(), "no ALPN for plain QUIC clients");
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .expect("protocols supported by ring")
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .context("invalid certificate")?;
+        config.alpn_protocols = alpns;
+        self.server_config = Some(Arc::new(config));
+        Ok(self)
+    }
+
+    /// Whether connections from plain QUIC clients are accepted.
+    pub(super) fn accepts_incoming(&self) -> bool {
+        self.server_config.is_some()
+    }
+}
+
+/// The QUIC endpoint for plain QUIC peers.
+///
+/// This uses its own UDP socket, so its packets are never confused with those of iroh nodes.
+#[derive(Debug)]
+pub(super) struct InteropEndpoint {
+    endpoint: quinn::Endpoint,
+    config: InteropConfig,
+    transport_config: Arc<TransportConfig>,
+}
+
+impl InteropEndpoint {
+    pub(super) fn new(
+        config: InteropConfig,
+        runtime: Arc<dyn quinn::Runtime>,
+        transport_config: Arc<TransportConfig>,
+    ) -> Result<Self> {
+        let socket = match config.bind_addr {
+            Some(addr) => std::net::UdpSocket::bind(addr)
+                .with_context(|| format!("failed to bind interop socket to {addr}"))?,
+            None => bind_default().context("failed to bind interop socket")?,
+        };
+        debug!(addr = ?socket.local_addr(), "bound interop socket");
+        let server_config = match config.server_config {
+            Some(ref server_config) => {
+                let crypto = QuicServerConfig::try_from(server_config.clone())?;
+                let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+                server_config.transport_config(transport_config.clone());
+                Some(server_config)
+            }
+            None => None,
+        };
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            server_config,
+            socket,
+            runtime,
+        )?;
+        Ok(Self {
+            endpoint,
+            config,
+            transport_config,
+        })
+    }
+
+    /// Connects to the server at `addr`, verifying its certificate is valid for
+    /// `server_name`.
+    pub(super) async fn connect(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+        alpn: &[u8],
+    ) -> Result<Connection> {
+        let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .expect("protocols supported by ring")
+        .with_root_certificates(self.config.root_certificates.clone())
+        .with_no_client_auth();
+        crypto.alpn_protocols = vec![alpn.to_vec()];
+        let mut client_config =
+            quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+        client_config.transport_config(self.transport_config.clone());
+        let connection = self
+            .endpoint
+            .connect_with(client_config, addr, server_name)?
+            .await?;
+        Ok(connection)
+    }
+
+    /// Whether connections from plain QUIC clients are accepted.
+    pub(super) fn accepts_incoming(&self) -> bool {
+        self.config.accepts_incoming()
+    }
+
+    /// The address the UDP socket is bound to.
+    pub(super) fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Waits for the next incoming connection.
+    pub(super) async fn accept(&self) -> Option<Incoming> {
+        self.endpoint.accept().await
+    }
+
+    /// Closes all connections and waits for the peers to be notified.
+    pub(super) async fn close(&self, error_code: quinn::VarInt) {
+        self.endpoint.close(error_code, b"");
+        self.endpoint.wait_idle().await;
+    }
+}
+
+/// Binds a dual-stack socket on a random port, falling back to IPv4 only.
+fn bind_default() -> std::io::Result<std::net::UdpSocket> {
+    let bind_v6 = || -> std::io::Result<std::net::UdpSocket> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV6,
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+        Ok(socket.into())
+    };
+    bind_v6().or_else(|err| {
+        debug!("failed to bind dual-stack interop socket, using IPv4: {err:#}");
+        std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+    })
+}
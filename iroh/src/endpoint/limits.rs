@@ -0,0 +1,205 @@
+//! Limits on the number of incoming connections.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::Mutex;
+use quinn::WeakConnectionHandle;
+
+use crate::NodeId;
+
+/// Tracks the incoming connections to enforce the configured limits.
+///
+/// A connection takes up a slot as soon as it is accepted, before its handshake, so
+/// concurrent handshakes can not exceed the limits.  The slot is released if the handshake
+/// fails.  Established connections are only tracked with weak handles, a connection stops
+/// counting towards the limits once it is closed and all handles to it are dropped.
+#[derive(Debug)]
+pub(super) struct ConnectionLimits {
+    max_incoming: Option<usize>,
+    max_per_node: Option<usize>,
+    state: Mutex<LimitsState>,
+}
+
+#[derive(Debug, Default)]
+struct LimitsState {
+    /// The number of connections still performing their handshake.
+    pending: usize,
+    /// The number of connections still performing their handshake, of the nodes known
+    /// before the handshake.
+    pending_per_node: HashMap<NodeId, usize>,
+    /// The established connections.
+    connections: Vec<(NodeId, WeakConnectionHandle)>,
+}
+
+/// Which limit prevents accepting another connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LimitExceeded {
+    /// The limit on incoming connections from all nodes.
+    Total,
+    /// The limit on incoming connections from a single node.
+    PerNode,
+}
+
+/// The slot of an incoming connection which is still performing its handshake.
+///
+/// Dropping it releases the slot, see [`LimitSlot::establish`] to keep it.
+#[derive(Debug)]
+pub(super) struct LimitSlot {
+    limits: Arc<ConnectionLimits>,
+    node_id: Option<NodeId>,
+    /// Whether the slot was released already.
+    released: bool,
+}
+
+impl ConnectionLimits {
+    pub(super) fn new(max_incoming: Option<usize>, max_per_node: Option<usize>) -> Self {
+        Self {
+            max_incoming,
+            max_per_node,
+            state: Default::default(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_incoming.is_some() || self.max_per_node.is_some()
+    }
+
+    /// Reserves a slot for an incoming connection, before its handshake.
+    ///
+    /// The per-node limit can only be checked if the remote node is already known,
+    /// otherwise it is checked again by [`LimitSlot::establish`].  Returns `None` if no
+    /// limits are configured.
+    pub(super) fn reserve(
+        self: &Arc<Self>,
+        node_id: Option<NodeId>,
+    ) -> Result<Option<LimitSlot>, LimitExceeded> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let mut state = self.state.lock();
+        state.connections.retain(|(_, handle)| handle.is_alive());
+        if self
+            .max_incoming
+            .is_some_and(|max| state.connections.len() + state.pending >= max)
+        {
+            return Err(LimitExceeded::Total);
+        }
+        if let (Some(max), Some(node_id)) = (self.max_per_node, node_id) {
+            if state.count(node_id) >= max {
+                return Err(LimitExceeded::PerNode);
+            }
+        }
+        state.pending += 1;
+        if let Some(node_id) = node_id {
+            *state.pending_per_node.entry(node_id).or_default() += 1;
+        }
+        Ok(Some(LimitSlot {
+            limits: self.clone(),
+            node_id,
+            released: false,
+        }))
+    }
+}
+
+impl LimitsState {
+    /// The number of connections of a node, established or still performing the handshake.
+    fn count(&self, node_id: NodeId) -> usize {
+        let established = self
+            .connections
+            .iter()
+            .filter(|(id, _)| *id == node_id)
+            .count();
+        established
+            + self
+                .pending_per_node
+                .get(&node_id)
+                .copied()
+                .unwrap_or_default()
+    }
+
+    fn release(&mut self, node_id: Option<NodeId>) {
+        self.pending -= 1;
+        if let Some(node_id) = node_id {
+            if let Some(count) = self.pending_per_node.get_mut(&node_id) {
+                *count -= 1;
+                if *count == 0 {
+                    self.pending_per_node.remove(&node_id);
+                }
+            }
+        }
+    }
+}
+
+impl LimitSlot {
+    /// Turns the slot into the one of the established connection of `node_id`.
+    ///
+    /// Fails if the node turns out to have reached the per-node limit, in which case the
+    /// slot is released and the connection must be closed.
+    pub(super) fn establish(
+        mut self,
+        node_id: NodeId,
+        handle: WeakConnectionHandle,
+    ) -> Result<(), LimitExceeded> {
+        let mut state = self.limits.state.lock();
+        state.release(self.node_id);
+        self.released = true;
+        state.connections.retain(|(_, handle)| handle.is_alive());
+        if self
+            .limits
+            .max_per_node
+            .is_some_and(|max| state.count(node_id) >= max)
+        {
+            return Err(LimitExceeded::PerNode);
+        }
+        state.connections.push((node_id, handle));
+        Ok(())
+    }
+}
+
+impl Drop for LimitSlot {
+    fn drop(&mut self) {
+        if !self.released {
+            self.limits.state.lock().release(self.node_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_reserve_counts_handshakes() {
+        let limits = Arc::new(ConnectionLimits::new(Some(2), Some(1)));
+        let node_a = SecretKey::generate().public();
+        let node_b = SecretKey::generate().public();
+
+        let slot_a = limits.reserve(Some(node_a)).unwrap().unwrap();
+        // The handshake of node a counts towards its limit.
+        assert_eq!(
+            limits.reserve(Some(node_a)).unwrap_err(),
+            LimitExceeded::PerNode
+        );
+        let slot_unknown = limits.reserve(None).unwrap().unwrap();
+        // Both handshakes count towards the total limit.
+        assert_eq!(
+            limits.reserve(Some(node_b)).unwrap_err(),
+            LimitExceeded::Total
+        );
+
+        // Failed handshakes release their slots.
+        drop(slot_unknown);
+        let slot_b = limits.reserve(Some(node_b)).unwrap().unwrap();
+        drop(slot_a);
+        drop(slot_b);
+        assert_eq!(limits.state.lock().pending, 0);
+        assert!(limits.state.lock().pending_per_node.is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limits = Arc::new(ConnectionLimits::new(None, None));
+        assert!(limits.reserve(None).unwrap().is_none());
+    }
+}
@@ -0,0 +1,52 @@
+//! Metadata about a node exchanged when establishing connections.
+
+use bytes::Bytes;
+
+/// The maximum length of the application data in the [`NodeMetadata`].
+pub const MAX_APP_METADATA_LEN: usize = 1024;
+
+/// Metadata about a node, exchanged during the handshake of every connection.
+///
+/// The metadata is carried in the TLS certificate of the node and thus authenticated by its
+/// [`NodeId`].  Configure what this endpoint sends using [`Builder::user_agent`] and
+/// [`Builder::app_metadata`], and read what the remote node sent using
+/// [`get_remote_metadata`].
+///
+/// [`NodeId`]: crate::NodeId
+/// [`Builder::user_agent`]: super::Builder::user_agent
+/// [`Builder::app_metadata`]: super::Builder::app_metadata
+/// [`get_remote_metadata`]: super::get_remote_metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeMetadata {
+    pub(crate) iroh_version: String,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) app_data: Bytes,
+}
+
+impl NodeMetadata {
+    /// Creates the metadata sent by this node.
+    pub(super) fn new(user_agent: Option<String>, app_data: Bytes) -> Self {
+        Self {
+            iroh_version: env!("CARGO_PKG_VERSION").to_string(),
+            user_agent,
+            app_data,
+        }
+    }
+
+    /// Returns the version of iroh the node is running.
+    pub fn iroh_version(&self) -> &str {
+        &self.iroh_version
+    }
+
+    /// Returns the user agent of the node, if it set one.
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    /// Returns the application-defined data of the node.
+    ///
+    /// This is empty if the node did not set any.
+    pub fn app_data(&self) -> &Bytes {
+        &self.app_data
+    }
+}
@@ -0,0 +1,94 @@
+//! Persisting the known peers of an endpoint across restarts.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+
+use super::NodeAddr;
+
+/// Loads the peers saved by [`save`].
+///
+/// A missing file is treated as having no saved peers.
+pub(super) async fn load(path: &Path) -> Result<Vec<NodeAddr>> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+    postcard::from_bytes(&data).with_context(|| format!("invalid peers file {}", path.display()))
+}
+
+/// Saves the peers to `path`.
+///
+/// The file is replaced atomically, a concurrent or crashed save never leaves a truncated
+/// file behind.  Each save writes its own temporary file, which is synced to disk together
+/// with the directory before this returns.
+pub(super) async fn save(path: &Path, peers: &[NodeAddr]) -> Result<()> {
+    let data = postcard::to_stdvec(peers)?;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+    let tmp_path = PathBuf::from(tmp_path);
+    let res = write_and_rename(&tmp_path, path, &data).await;
+    if res.is_err() {
+        tokio::fs::remove_file(&tmp_path).await.ok();
+    }
+    res.with_context(|| format!("failed to write {}", path.display()))
+}
+
+async fn write_and_rename(tmp_path: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(tmp_path)
+        .await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(tmp_path, path).await?;
+    // The rename is only durable once the directory is synced.  Directories can not be
+    // opened as files on Windows, where the rename is durable already.
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[tokio::test]
+    async fn save_concurrently() {
+        let dir = std::env::temp_dir().join(format!("iroh-peers-{}", rand::random::<u64>()));
+        tokio::fs::create_dir(&dir).await.unwrap();
+        let path = dir.join("peers");
+        let peers: Vec<_> = (0..8)
+            .map(|_| vec![NodeAddr::new(SecretKey::generate().public())])
+            .collect();
+
+        let saves = peers.iter().map(|peers| save(&path, peers));
+        for res in futures_buffered::join_all(saves).await {
+            res.unwrap();
+        }
+        // One of the saves won, and no temporary files are left behind.
+        let loaded = load(&path).await.unwrap();
+        assert!(peers.contains(&loaded));
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, vec!["peers"]);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! Opt-in pool of outgoing connections, keyed by remote node and ALPN.
+//!
+//! See [`Builder::connection_pool`] for details.
+//!
+//! [`Builder::connection_pool`]: super::Builder::connection_pool
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use iroh_base::key::NodeId;
+use parking_lot::Mutex;
+use tokio::{sync::OwnedMutexGuard, time::Instant};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, info_span, trace, Instrument};
+
+use super::Connection;
+
+type PoolKey = (NodeId, Vec<u8>);
+
+/// A pool of established outgoing connections.
+///
+/// Connections are only handed out again while they are still open.  Connections which
+/// have not been handed out for longer than the idle timeout are dropped from the pool,
+/// which closes them once no other handles to them remain.
+///
+/// Only one connection attempt per node and ALPN is made at a time, see
+/// [`ConnectionPool::lock_dial`].
+#[derive(Debug)]
+pub(super) struct ConnectionPool {
+    idle_timeout: Duration,
+    conns: Arc<Mutex<HashMap<PoolKey, PooledConnection>>>,
+    /// The locks of ongoing connection attempts.
+    ///
+    /// Entries are removed once no attempt holds or waits for the lock anymore.
+    dials: Mutex<HashMap<PoolKey, Weak<tokio::sync::Mutex<()>>>>,
+    _evict_task: AbortOnDropHandle<()>,
+}
+
+#[derive(Debug)]
+struct PooledConnection {
+    conn: Connection,
+    last_used: Instant,
+}
+
+impl ConnectionPool {
+    /// Creates a new pool and spawns the task evicting idle connections.
+    pub(super) fn new(idle_timeout: Duration) -> Self {
+        let conns: Arc<Mutex<HashMap<PoolKey, PooledConnection>>> = Default::default();
+        let task = tokio::spawn({
+            let conns = conns.clone();
+            async move {
+                // Never tick faster than once a second, even for very short timeouts.
+                let period = (idle_timeout / 2).max(Duration::from_secs(1));
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    evict(&mut conns.lock(), idle_timeout, Instant::now());
+                }
+            }
+            .instrument(info_span!("connection-pool"))
+        });
+        Self {
+            idle_timeout,
+            conns,
+            dials: Default::default(),
+            _evict_task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Returns a handle to a still open pooled connection, if there is one.
+    pub(super) fn get(&self, node_id: NodeId, alpn: &[u8]) -> Option<Connection> {
+        let mut conns = self.conns.lock();
+        let now = Instant::now();
+        evict(&mut conns, self.idle_timeout, now);
+        let entry = conns.get_mut(&(node_id, alpn.to_vec()))?;
+        entry.last_used = now;
+        Some(entry.conn.clone())
+    }
+
+    /// Waits until no other connection attempt to the node with this ALPN is in progress.
+    ///
+    /// The returned guard must be held while connecting.  Concurrent callers wait for the
+    /// first attempt to finish and can then pick up its connection using
+    /// [`ConnectionPool::get`], instead of dialing the node again.
+    pub(super) async fn lock_dial(&self, node_id: NodeId, alpn: &[u8]) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut dials = self.dials.lock();
+            dials.retain(|_, lock| lock.strong_count() > 0);
+            let key = (node_id, alpn.to_vec());
+            match dials.get(&key).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    dials.insert(key, Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+
+    /// Adds a newly established connection to the pool.
+    ///
+    /// Replaces any connection previously pooled for the same node and ALPN.
+    pub(super) fn insert(&self, node_id: NodeId, alpn: &[u8], conn: Connection) {
+        let entry = PooledConnection {
+            conn,
+            last_used: Instant::now(),
+        };
+        self.conns.lock().insert((node_id, alpn.to_vec()), entry);
+    }
+
+    /// Removes all connections from the pool.
+    pub(super) fn clear(&self) {
+        self.conns.lock().clear();
+    }
+}
+
+/// Removes closed connections and connections idle for longer than `idle_timeout`.
+fn evict(conns: &mut HashMap<PoolKey, PooledConnection>, idle_timeout: Duration, now: Instant) {
+    conns.retain(|(node_id, _alpn), entry| {
+        if entry.conn.close_reason().is_some() {
+            trace!(remote = %node_id.fmt_short(), "evicting closed connection");
+            return false;
+        }
+        if now.duration_since(entry.last_used) > idle_timeout {
+            debug!(remote = %node_id.fmt_short(), "evicting idle connection");
+            return false;
+        }
+        true
+    });
+}
@@ -7,12 +7,14 @@ use futures_lite::StreamExt;
 use iroh_base::key::NodeId;
 use iroh_metrics::inc;
 use tokio::{
-    sync::{mpsc, Notify},
+    sync::{broadcast, mpsc, Notify},
+    task::{JoinError, JoinSet},
     time::Duration,
 };
 use tokio_util::task::AbortOnDropHandle;
-use tracing::{debug, error, info_span, trace, Instrument};
+use tracing::{debug, error, info_span, trace, Instrument, Span};
 
+use super::{events::EndpointEvent, spans::ConnectionSpans, ClosedReason};
 use crate::{
     magicsock::{ConnectionType, ConnectionTypeStream},
     metrics::MagicsockMetrics,
@@ -26,12 +28,8 @@ pub(super) struct RttHandle {
 }
 
 impl RttHandle {
-    pub(super) fn new() -> Self {
-        let mut actor = RttActor {
-            connection_events: stream_group::StreamGroup::new().keyed(),
-            connections: HashMap::new(),
-            tick: Notify::new(),
-        };
+    pub(super) fn new(events: broadcast::Sender<EndpointEvent>) -> Self {
+        let mut actor = RttActor::new(events);
         let (msg_tx, msg_rx) = mpsc::channel(16);
         let handle = tokio::spawn(
             async move {
@@ -52,11 +50,17 @@ pub(super) enum RttMessage {
     /// Informs the [`RttActor`] of a new connection is should monitor.
     NewConnection {
         /// The connection.
-        connection: quinn::WeakConnectionHandle,
+        ///
+        /// Only a weak handle is kept, unless the closing of the connection is reported.
+        connection: quinn::Connection,
         /// Path changes for this connection from the magic socket.
         conn_type_changes: ConnectionTypeStream,
         /// For reporting-only, the Node ID of this connection.
         node_id: NodeId,
+        /// For reporting-only, the ALPN of this connection.
+        alpn: Vec<u8>,
+        /// For reporting-only, the span in which this connection was established.
+        span: Span,
     },
 }
 
@@ -64,6 +68,9 @@ pub(super) enum RttMessage {
 ///
 /// The magic socket can change the underlying network path, between two nodes.  If we can
 /// inform the QUIC congestion controller of this event it will work much more efficiently.
+///
+/// Since it tracks all connections, it also reports the connection related
+/// [`EndpointEvent`]s.
 #[derive(Debug)]
 struct RttActor {
     /// Stream of connection type changes.
@@ -72,16 +79,54 @@ struct RttActor {
     ///
     /// These are weak references so not to keep the connections alive.  The key allows
     /// removing the corresponding stream from `conn_type_changes`.
-    /// The boolean is an indiciator of whether this connection was direct before.
-    /// This helps establish metrics on number of connections that became direct.
-    connections: HashMap<stream_group::Key, (quinn::WeakConnectionHandle, NodeId, bool)>,
+    connections: HashMap<stream_group::Key, ConnectionState>,
+    /// The last path reported for each node with open connections.
+    ///
+    /// This avoids reporting a path change once for each connection to the same node.
+    node_paths: HashMap<NodeId, ConnectionType>,
     /// A way to notify the main actor loop to run over.
     ///
     /// E.g. when a new stream was added.
     tick: Notify,
+    /// Where to report [`EndpointEvent`]s.
+    events: broadcast::Sender<EndpointEvent>,
+    /// Tasks waiting for connections to close, to report why.
+    closed_tasks: JoinSet<ClosedConnection>,
+}
+
+/// A connection which was closed, see [`EndpointEvent::ConnectionClosed`].
+#[derive(Debug)]
+struct ClosedConnection {
+    node_id: NodeId,
+    alpn: Vec<u8>,
+    reason: ClosedReason,
+}
+
+#[derive(Debug)]
+struct ConnectionState {
+    handle: quinn::WeakConnectionHandle,
+    node_id: NodeId,
+    alpn: Vec<u8>,
+    /// Whether this connection was direct before.
+    ///
+    /// This helps establish metrics on number of connections that became direct.
+    was_direct_before: bool,
+    /// The spans tracing the lifetime and paths of this connection.
+    spans: ConnectionSpans,
 }
 
 impl RttActor {
+    fn new(events: broadcast::Sender<EndpointEvent>) -> Self {
+        Self {
+            connection_events: stream_group::StreamGroup::new().keyed(),
+            connections: HashMap::new(),
+            node_paths: HashMap::new(),
+            tick: Notify::new(),
+            events,
+            closed_tasks: JoinSet::new(),
+        }
+    }
+
     /// Runs the actor main loop.
     ///
     /// The main loop will finish when the sender is dropped.
@@ -100,6 +145,9 @@ impl RttActor {
                 item = self.connection_events.next(), if !self.connection_events.is_empty() => {
                     self.do_reset_rtt(item);
                 }
+                Some(res) = self.closed_tasks.join_next(), if !self.closed_tasks.is_empty() => {
+                    self.handle_closed(res);
+                }
                 _ = cleanup_interval.tick() => self.do_connections_cleanup(),
                 () = self.tick.notified() => continue,
             }
@@ -114,8 +162,10 @@ impl RttActor {
                 connection,
                 conn_type_changes,
                 node_id,
+                alpn,
+                span,
             } => {
-                self.handle_new_connection(connection, conn_type_changes, node_id);
+                self.handle_new_connection(connection, conn_type_changes, node_id, alpn, &span);
             }
         }
     }
@@ -123,14 +173,40 @@ impl RttActor {
     /// Handles the new connection message.
     fn handle_new_connection(
         &mut self,
-        connection: quinn::WeakConnectionHandle,
+        connection: quinn::Connection,
         conn_type_changes: ConnectionTypeStream,
         node_id: NodeId,
+        alpn: Vec<u8>,
+        span: &Span,
     ) {
+        // Without subscribers nobody would learn why the connection closed, so there is no
+        // need to keep it open until then.
+        let handle = connection.weak_handle();
+        if self.events.receiver_count() > 0 {
+            let alpn = alpn.clone();
+            self.closed_tasks.spawn(async move {
+                let reason = connection.closed().await.into();
+                ClosedConnection {
+                    node_id,
+                    alpn,
+                    reason,
+                }
+            });
+        }
         let key = self.connection_events.insert(conn_type_changes);
-        self.connections.insert(key, (connection, node_id, false));
+        self.connections.insert(
+            key,
+            ConnectionState {
+                handle,
+                node_id,
+                alpn: alpn.clone(),
+                was_direct_before: false,
+                spans: ConnectionSpans::new(span, node_id, &alpn),
+            },
+        );
         self.tick.notify_one();
         inc!(MagicsockMetrics, connection_handshake_success);
+        self.emit(EndpointEvent::ConnectionEstablished { node_id, alpn });
     }
 
     /// Performs the congestion controller reset for a magic socket path change.
@@ -142,24 +218,28 @@ impl RttActor {
     fn do_reset_rtt(&mut self, item: Option<(stream_group::Key, ConnectionType)>) {
         match item {
             Some((key, new_conn_type)) => match self.connections.get_mut(&key) {
-                Some((handle, node_id, was_direct_before)) => {
-                    if handle.network_path_changed() {
+                Some(conn) => {
+                    if conn.handle.network_path_changed() {
                         debug!(
-                            node_id = %node_id.fmt_short(),
+                            node_id = %conn.node_id.fmt_short(),
                             new_type = ?new_conn_type,
                             "Congestion controller state reset",
                         );
-                        if !*was_direct_before && matches!(new_conn_type, ConnectionType::Direct(_))
+                        if !conn.was_direct_before
+                            && matches!(new_conn_type, ConnectionType::Direct(_))
                         {
-                            *was_direct_before = true;
+                            conn.was_direct_before = true;
                             inc!(MagicsockMetrics, connection_became_direct);
                         }
+                        conn.spans.path_changed(&new_conn_type);
+                        let node_id = conn.node_id;
+                        self.report_path(node_id, new_conn_type);
                     } else {
                         debug!(
-                            node_id = %node_id.fmt_short(),
+                            node_id = %conn.node_id.fmt_short(),
                             "removing dropped connection",
                         );
-                        self.connection_events.remove(key);
+                        self.remove_connection(key);
                     }
                 }
                 None => error!("No connection found for stream item"),
@@ -172,12 +252,65 @@ impl RttActor {
 
     /// Performs cleanup for closed connection.
     fn do_connections_cleanup(&mut self) {
-        for (key, (handle, node_id, _)) in self.connections.iter() {
-            if !handle.is_alive() {
-                trace!(node_id = %node_id.fmt_short(), "removing stale connection");
-                self.connection_events.remove(*key);
+        let stale: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| !conn.handle.is_alive())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in stale {
+            self.remove_connection(key);
+        }
+    }
+
+    /// Stops tracking a connection which is no longer alive.
+    fn remove_connection(&mut self, key: stream_group::Key) {
+        self.connection_events.remove(key);
+        let Some(conn) = self.connections.remove(&key) else {
+            return;
+        };
+        trace!(node_id = %conn.node_id.fmt_short(), "removing stale connection");
+        inc!(MagicsockMetrics, connection_closed);
+        if !self
+            .connections
+            .values()
+            .any(|other| other.node_id == conn.node_id)
+        {
+            self.node_paths.remove(&conn.node_id);
+        }
+    }
+
+    /// Reports a connection which was closed.
+    fn handle_closed(&mut self, res: Result<ClosedConnection, JoinError>) {
+        match res {
+            Ok(ClosedConnection {
+                node_id,
+                alpn,
+                reason,
+            }) => {
+                debug!(node_id = %node_id.fmt_short(), %reason, "connection closed");
+                self.emit(EndpointEvent::ConnectionClosed {
+                    node_id,
+                    alpn,
+                    reason,
+                });
             }
+            Err(err) => error!("connection close task failed: {err:#}"),
+        }
+    }
+
+    /// Reports a path change, unless it was already reported for another connection.
+    fn report_path(&mut self, node_id: NodeId, conn_type: ConnectionType) {
+        if self.node_paths.get(&node_id) == Some(&conn_type) {
+            return;
         }
+        self.node_paths.insert(node_id, conn_type.clone());
+        self.emit(EndpointEvent::PathChanged { node_id, conn_type });
+    }
+
+    fn emit(&self, event: EndpointEvent) {
+        // Sending only fails when there are no subscribers.
+        self.events.send(event).ok();
     }
 }
 
@@ -187,11 +320,8 @@ mod tests {
 
     #[tokio::test]
     async fn test_actor_mspc_close() {
-        let mut actor = RttActor {
-            connection_events: stream_group::StreamGroup::new().keyed(),
-            connections: HashMap::new(),
-            tick: Notify::new(),
-        };
+        let (events, _) = broadcast::channel(1);
+        let mut actor = RttActor::new(events);
         let (msg_tx, msg_rx) = mpsc::channel(16);
         let handle = tokio::spawn(async move {
             actor.run(msg_rx).await;
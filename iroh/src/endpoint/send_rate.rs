@@ -0,0 +1,125 @@
+//! Per-connection send rate limit, implemented as a congestion controller.
+//!
+//! See [`Endpoint::connect_with_max_send_rate`] for details.
+//!
+//! [`Endpoint::connect_with_max_send_rate`]: super::Endpoint::connect_with_max_send_rate
+
+use std::{
+    any::Any,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use quinn::congestion::{Controller, ControllerFactory, CubicConfig};
+use quinn_proto::RttEstimator;
+
+/// The RTT assumed before the first acknowledgement, matching the QUIC recommendation.
+const INITIAL_RTT: Duration = Duration::from_millis(333);
+
+/// Creates [`SendRateController`]s wrapping the default congestion controller.
+#[derive(Debug)]
+pub(super) struct SendRateControllerFactory {
+    bytes_per_sec: u64,
+    inner: Arc<CubicConfig>,
+}
+
+impl SendRateControllerFactory {
+    pub(super) fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            inner: Arc::new(CubicConfig::default()),
+        }
+    }
+}
+
+impl ControllerFactory for SendRateControllerFactory {
+    fn build(self: Arc<Self>, now: Instant, current_mtu: u16) -> Box<dyn Controller> {
+        Box::new(SendRateController {
+            inner: self.inner.clone().build(now, current_mtu),
+            bytes_per_sec: self.bytes_per_sec,
+            rtt: INITIAL_RTT,
+            mtu: current_mtu,
+        })
+    }
+}
+
+/// Caps the congestion window of the wrapped controller to one RTT worth of data at the
+/// configured rate.
+///
+/// Since QUIC paces sends across the RTT this limits the connection to roughly the
+/// configured rate.  The window never drops below two packets so the connection is always
+/// able to make progress.
+struct SendRateController {
+    inner: Box<dyn Controller>,
+    bytes_per_sec: u64,
+    rtt: Duration,
+    mtu: u16,
+}
+
+impl Controller for SendRateController {
+    fn on_sent(&mut self, now: Instant, bytes: u64, last_packet_number: u64) {
+        self.inner.on_sent(now, bytes, last_packet_number)
+    }
+
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        rtt: &RttEstimator,
+    ) {
+        self.rtt = rtt.get();
+        self.inner.on_ack(now, sent, bytes, app_limited, rtt)
+    }
+
+    fn on_end_acks(
+        &mut self,
+        now: Instant,
+        in_flight: u64,
+        app_limited: bool,
+        largest_packet_num_acked: Option<u64>,
+    ) {
+        self.inner
+            .on_end_acks(now, in_flight, app_limited, largest_packet_num_acked)
+    }
+
+    fn on_congestion_event(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        is_persistent_congestion: bool,
+        lost_bytes: u64,
+    ) {
+        self.inner
+            .on_congestion_event(now, sent, is_persistent_congestion, lost_bytes)
+    }
+
+    fn on_mtu_update(&mut self, new_mtu: u16) {
+        self.mtu = new_mtu;
+        self.inner.on_mtu_update(new_mtu)
+    }
+
+    fn window(&self) -> u64 {
+        let rate_window = (self.bytes_per_sec as f64 * self.rtt.as_secs_f64()) as u64;
+        let min_window = 2 * self.mtu as u64;
+        self.inner.window().min(rate_window.max(min_window))
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(Self {
+            inner: self.inner.clone_box(),
+            bytes_per_sec: self.bytes_per_sec,
+            rtt: self.rtt,
+            mtu: self.mtu,
+        })
+    }
+
+    fn initial_window(&self) -> u64 {
+        self.inner.initial_window()
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
@@ -0,0 +1,214 @@
+//! Tracing spans for the lifecycle of connections.
+//!
+//! With the `connection-spans` feature the endpoint records:
+//!
+//! - An `iroh.connect` span for each outgoing connection attempt, with the child spans
+//!   `iroh.discovery` for finding the addresses of the remote node and `iroh.handshake` for
+//!   the QUIC handshake.
+//! - An `iroh.accept` span for each incoming connection, covering its handshake.
+//! - An `iroh.connection` span for the lifetime of each established connection, which
+//!   follows from its connect or accept span.  While no direct path was found its child
+//!   span `iroh.hole_punching` is open, and each time the direct path is lost an
+//!   `iroh.relay_fallback` child span is open until a direct path is found again.
+//!
+//! All spans carry the remote node id in `iroh.node_id` and the ALPN in `iroh.alpn`, the
+//! connection spans record the path in use in `iroh.path`.  The fields follow the
+//! conventions of `tracing-opentelemetry`, so `otel.kind`, `otel.status_code` and
+//! `error.message` are exported as the kind and status of the OpenTelemetry span.
+//!
+//! Without the feature all these spans are disabled.
+
+use std::fmt::Display;
+
+use iroh_base::key::NodeId;
+use tracing::{
+    field::{display, Empty},
+    info_span, Span,
+};
+
+use super::Endpoint;
+use crate::magicsock::ConnectionType;
+
+/// Whether the connection spans are enabled.
+const ENABLED: bool = cfg!(feature = "connection-spans");
+
+/// Creates the span of an outgoing connection attempt.
+pub(super) fn connect(node_id: NodeId, alpn: &[u8]) -> Span {
+    if !ENABLED {
+        return Span::none();
+    }
+    info_span!(
+        target: "iroh::connection",
+        "iroh.connect",
+        otel.kind = "client",
+        otel.status_code = Empty,
+        error.message = Empty,
+        iroh.node_id = %node_id,
+        iroh.alpn = %String::from_utf8_lossy(alpn),
+        iroh.path = Empty,
+    )
+}
+
+/// Creates the child span of a connect span for finding the addresses of the remote node.
+pub(super) fn discovery(connect: &Span) -> Span {
+    if !ENABLED {
+        return Span::none();
+    }
+    info_span!(target: "iroh::connection", parent: connect, "iroh.discovery")
+}
+
+/// Creates the child span of a connect span for the QUIC handshake.
+pub(super) fn handshake(connect: &Span) -> Span {
+    if !ENABLED {
+        return Span::none();
+    }
+    info_span!(target: "iroh::connection", parent: connect, "iroh.handshake")
+}
+
+/// Creates the span of an incoming connection.
+///
+/// The remote node and ALPN are only known after the handshake, they are recorded by
+/// [`established`].
+pub(super) fn accept() -> Span {
+    if !ENABLED {
+        return Span::none();
+    }
+    info_span!(
+        target: "iroh::connection",
+        "iroh.accept",
+        otel.kind = "server",
+        otel.status_code = Empty,
+        error.message = Empty,
+        iroh.node_id = Empty,
+        iroh.alpn = Empty,
+        iroh.path = Empty,
+    )
+}
+
+/// Records a successful handshake on a connect or accept span.
+pub(super) fn established(span: &Span, ep: &Endpoint, node_id: NodeId, alpn: &[u8]) {
+    if span.is_disabled() {
+        return;
+    }
+    span.record("otel.status_code", "OK");
+    span.record("iroh.node_id", display(node_id));
+    span.record("iroh.alpn", display(String::from_utf8_lossy(alpn)));
+    if let Ok(conn_type) = ep.conn_type_changes(node_id) {
+        span.record("iroh.path", display(conn_type.get()));
+    }
+}
+
+/// Records a failed connection attempt on a connect or accept span.
+pub(super) fn failed(span: &Span, err: &dyn Display) {
+    span.record("otel.status_code", "ERROR");
+    span.record("error.message", display(err));
+}
+
+/// The spans of an established connection.
+///
+/// The spans end when this is dropped, which happens once the connection is closed.
+#[derive(Debug)]
+pub(super) struct ConnectionSpans {
+    connection: Span,
+    phase: Phase,
+}
+
+#[derive(Debug)]
+enum Phase {
+    /// No direct path was found yet.
+    HolePunching(Span),
+    /// A direct path is used.
+    Direct,
+    /// The direct path was lost and the relay is used.
+    RelayFallback(Span),
+}
+
+impl ConnectionSpans {
+    /// Starts the spans of a connection established in the `handshake` span.
+    pub(super) fn new(handshake: &Span, node_id: NodeId, alpn: &[u8]) -> Self {
+        if !ENABLED {
+            return Self {
+                connection: Span::none(),
+                phase: Phase::HolePunching(Span::none()),
+            };
+        }
+        let connection = info_span!(
+            target: "iroh::connection",
+            parent: None,
+            "iroh.connection",
+            iroh.node_id = %node_id,
+            iroh.alpn = %String::from_utf8_lossy(alpn),
+            iroh.path = Empty,
+        );
+        connection.follows_from(handshake);
+        let hole_punching = info_span!(
+            target: "iroh::connection",
+            parent: &connection,
+            "iroh.hole_punching",
+            iroh.outcome = Empty,
+        );
+        Self {
+            connection,
+            phase: Phase::HolePunching(hole_punching),
+        }
+    }
+
+    /// Records a change of the path used by the connection.
+    pub(super) fn path_changed(&mut self, conn_type: &ConnectionType) {
+        self.connection.record("iroh.path", display(conn_type));
+        let direct = matches!(conn_type, ConnectionType::Direct(_));
+        match (&self.phase, direct) {
+            (Phase::HolePunching(span), true) => {
+                span.record("iroh.outcome", "direct");
+                self.phase = Phase::Direct;
+            }
+            (Phase::RelayFallback(_), true) => self.phase = Phase::Direct,
+            (Phase::Direct, false) => {
+                let span = if ENABLED {
+                    info_span!(
+                        target: "iroh::connection",
+                        parent: &self.connection,
+                        "iroh.relay_fallback",
+                    )
+                } else {
+                    Span::none()
+                };
+                self.phase = Phase::RelayFallback(span);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for ConnectionSpans {
+    fn drop(&mut self) {
+        if let Phase::HolePunching(span) = &self.phase {
+            span.record("iroh.outcome", "closed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_connection_spans_phases() {
+        let node_id = SecretKey::generate().public();
+        let relay = ConnectionType::Relay("https://relay.example.com".parse().unwrap());
+        let direct = ConnectionType::Direct("192.0.2.1:1234".parse().unwrap());
+        let mut spans = ConnectionSpans::new(&Span::none(), node_id, b"test");
+        assert!(matches!(spans.phase, Phase::HolePunching(_)));
+
+        // Using the relay while hole punching does not end hole punching.
+        spans.path_changed(&relay);
+        assert!(matches!(spans.phase, Phase::HolePunching(_)));
+        spans.path_changed(&direct);
+        assert!(matches!(spans.phase, Phase::Direct));
+        spans.path_changed(&relay);
+        assert!(matches!(spans.phase, Phase::RelayFallback(_)));
+        spans.path_changed(&direct);
+        assert!(matches!(spans.phase, Phase::Direct));
+    }
+}
@@ -238,19 +238,25 @@ pub mod dialer;
 mod disco;
 pub mod discovery;
 pub mod dns;
+pub mod doctor;
 pub mod endpoint;
 mod magicsock;
 pub mod metrics;
 pub mod protocol;
+pub mod relay_map;
+mod runtime;
 pub mod tls;
+#[cfg(feature = "webtransport")]
+#[cfg_attr(iroh_docsrs, doc(cfg(feature = "webtransport")))]
+pub mod webtransport;
 
 pub(crate) mod util;
 
-pub use endpoint::{AddrInfo, AddrInfoOptions, Endpoint, NodeAddr, RelayMode};
+pub use endpoint::{AddrInfo, AddrInfoOptions, DiscoveryMode, Endpoint, NodeAddr, RelayMode};
 pub use iroh_base::{
     hash, key,
     key::NodeId,
-    relay_map::{RelayMap, RelayNode, RelayUrl},
+    relay_map::{RelayAuthToken, RelayMap, RelayNode, RelayUrl},
     ticket,
 };
 pub use iroh_relay as relay;
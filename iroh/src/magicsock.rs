@@ -29,17 +29,21 @@ use std::{
     time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
 use bytes::Bytes;
 use concurrent_queue::ConcurrentQueue;
 use futures_lite::{FutureExt, Stream, StreamExt};
 use futures_util::{stream::BoxStream, task::AtomicWaker};
 use iroh_base::key::NodeId;
 use iroh_metrics::{inc, inc_by};
-use iroh_relay::protos::stun;
-use netwatch::{interfaces, ip::LocalAddresses, netmon, UdpSocket};
+use iroh_relay::{protos::stun, quic::QuicClient};
+use netwatch::{
+    interfaces,
+    ip::{is_unicast_link_local, LocalAddresses},
+    netmon,
+};
 use quinn::AsyncUdpSocket;
-use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use smallvec::{smallvec, SmallVec};
 use tokio::{
     sync::{self, mpsc, Mutex},
@@ -55,41 +59,82 @@ use url::Url;
 use watchable::Watchable;
 
 use self::{
+    interface_sockets::InterfaceSockets,
     metrics::Metrics as MagicsockMetrics,
     node_map::{NodeMap, PingAction, PingRole, SendPing},
-    relay_actor::{RelayActor, RelayActorMessage, RelayRecvDatagram},
-    udp_conn::UdpConn,
+    qad::QadSocket,
+    rate_limit::RateLimiter,
+    relay_actor::{RelayActor, RelayActorMessage, RelayHealthMap, RelayRecvDatagram},
+    udp_conn::{OsSocket, SocketOptions, UdpConn},
 };
+#[cfg(any(test, feature = "test-utils"))]
+use crate::test_utils::MemoryNetwork;
 use crate::{
     defaults::timeouts::NET_REPORT_TIMEOUT,
     disco::{self, CallMeMaybe, SendAddr},
-    discovery::{Discovery, DiscoveryItem},
+    discovery::{Discovery, DiscoveryItem, UserData},
     dns::DnsResolver,
-    endpoint::NodeAddr,
+    endpoint::{EndpointEvent, NodeAddr},
     key::{PublicKey, SecretKey, SharedSecret},
+    runtime::{self, Runtime, TokioRuntime},
     AddrInfo, RelayMap, RelayUrl,
 };
 
+mod counters;
+#[cfg(any(test, feature = "test-utils"))]
+mod faults;
+mod interface_sockets;
 mod metrics;
 mod node_map;
+mod pinhole;
+mod port_mapping;
+mod qad;
+mod rate_limit;
 mod relay_actor;
+mod tap;
 mod timer;
 mod udp_conn;
 
 pub use node_map::Source;
 
+pub(crate) use self::counters::Counters;
+#[cfg(any(test, feature = "test-utils"))]
+pub use self::faults::Faults;
 pub(super) use self::timer::Timer;
 pub use self::{
+    counters::EndpointMetrics,
     metrics::Metrics,
-    node_map::{ConnectionType, ConnectionTypeStream, ControlMsg, DirectAddrInfo, RemoteInfo},
+    node_map::{
+        AddrFamilyPolicy, ConnectionType, ConnectionTypeStream, ConnectionTypeWatcher,
+        ConnectivityFailure, ConnectivityPhase, ConnectivityReason, ConnectivityState,
+        ConnectivityTransition, ControlMsg, DirectAddrInfo, DiscoCounts, DiscoStats,
+        HolePunchConfig, KeepaliveConfig, LatencyPathSelector, MultipathPolicy, NodePathStats,
+        PathAddr, PathCandidate, PathPolicy, PathSelector, PingResult, RemoteInfo, TrafficStats,
+    },
+    port_mapping::{PortMappingConfig, PortMappingStatus},
+    relay_actor::{RelayHealth, RelayKeepaliveConfig, RelayQueueConfig},
+    tap::{PacketKind, PacketTap, PcapngWriter, TapDirection, TapPath, TappedPacket},
 };
 
 /// How long we consider a STUN-derived endpoint valid for. UDP NAT mappings typically
 /// expire at 30 seconds, so this is a few seconds shy of that.
 const ENDPOINTS_FRESH_ENOUGH_DURATION: Duration = Duration::from_secs(27);
 
+/// The default interval at which direct paths are kept alive, see [`KeepaliveConfig`].
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
+/// How many consecutive net_report reports another relay must be much faster than the home
+/// relay before the home relay moves to it.
+///
+/// Reports run every 20 to 26 seconds, so this takes about a minute.
+const HOME_RELAY_SWITCH_REPORTS: usize = 3;
+
+/// How long to avoid a draining relay server after it closed our connection.
+///
+/// A relay server which sent a go away message shuts down, after the upgrade it is
+/// fine to use it again.
+const DRAINING_RELAY_AVOID_TIME: Duration = Duration::from_secs(60);
+
 /// Contains options for `MagicSock::listen`.
 #[derive(derive_more::Debug)]
 pub(crate) struct Options {
@@ -102,9 +147,30 @@ pub(crate) struct Options {
     /// If set to `None` it will choose a random port and listen on `[::]:0`.
     pub(crate) addr_v6: Option<SocketAddrV6>,
 
+    /// Whether to bind an IPv4 socket.
+    pub(crate) ipv4: bool,
+
+    /// Whether to try binding an IPv6 socket.
+    ///
+    /// Failing to bind IPv6 is only an error if IPv4 is disabled.
+    pub(crate) ipv6: bool,
+
+    /// The name of the network interface to bind the sockets to.
+    ///
+    /// If set, the IP addresses of `addr_v4` and `addr_v6` are replaced by the interface's
+    /// addresses, and the sockets, including those to relay servers, are bound to the
+    /// interface itself.
+    pub(crate) bind_interface: Option<String>,
+
+    /// Whether to bind an additional socket to each network interface address.
+    pub(crate) per_interface_sockets: bool,
+
     /// Secret key for this node.
     pub(crate) secret_key: SecretKey,
 
+    /// Previous secret keys of this node, still accepted from nodes addressing us by them.
+    pub(crate) previous_secret_keys: Vec<SecretKey>,
+
     /// The [`RelayMap`] to use, leave empty to not use a relay server.
     pub(crate) relay_map: RelayMap,
 
@@ -114,6 +180,9 @@ pub(crate) struct Options {
     /// Optional node discovery mechanism.
     pub(crate) discovery: Option<Box<dyn Discovery>>,
 
+    /// Application-defined data published together with our addresses.
+    pub(crate) discovery_user_data: Option<UserData>,
+
     /// A DNS resolver to use for resolving relay URLs.
     ///
     /// You can use [`crate::dns::default_resolver`] for a resolver that uses the system's DNS
@@ -123,12 +192,76 @@ pub(crate) struct Options {
     /// Proxy configuration.
     pub(crate) proxy_url: Option<Url>,
 
+    /// Maximum rate in bytes per second at which data is sent, across all paths.
+    pub(crate) max_send_rate: Option<u64>,
+
+    /// How to establish direct paths to other nodes.
+    pub(crate) hole_punching: HolePunchConfig,
+
+    /// How direct paths to other nodes are kept alive.
+    pub(crate) keepalive: KeepaliveConfig,
+
+    /// Chooses the direct path to send on, if not the [`LatencyPathSelector`].
+    pub(crate) path_selector: Option<Arc<dyn PathSelector>>,
+
+    /// Which address families may be used for direct paths, unless set per node.
+    pub(crate) addr_family: AddrFamilyPolicy,
+
+    /// Filters or rewrites the direct addresses advertised to other nodes.
+    pub(crate) direct_addr_filter: Option<Arc<dyn DirectAddrFilter>>,
+
+    /// Statically configured public addresses, e.g. of a port forwarding on the router.
+    pub(crate) external_addrs: Vec<SocketAddr>,
+
+    /// Which protocols to use to request port mappings from the router.
+    pub(crate) port_mapping: PortMappingConfig,
+
+    /// Whether to mark packets sent on direct paths as ECN-capable.
+    pub(crate) ecn: bool,
+
+    /// The DSCP codepoint to mark packets sent on direct paths with.
+    pub(crate) dscp: Option<u8>,
+
+    /// Whether to batch datagrams using GSO and GRO where the OS supports it.
+    pub(crate) segmentation_offload: bool,
+
+    /// The queue of datagrams waiting to be sent to relay servers.
+    pub(crate) relay_queue: RelayQueueConfig,
+
+    /// How relay connections are kept alive and reconnected.
+    pub(crate) relay_keepalive: RelayKeepaliveConfig,
+
+    /// Whether to keep a connection to a standby relay to fail over to.
+    pub(crate) standby_relay: bool,
+
+    /// Which net_report probes to run and how often to make full reports.
+    pub(crate) net_report: net_report::Config,
+
+    /// How often to make a net_report report, `None` for a random interval below 30s.
+    pub(crate) net_report_interval: Option<Duration>,
+
+    /// Receives copies of the packets sent and received, for debugging.
+    pub(crate) packet_tap: Option<Arc<dyn PacketTap>>,
+
+    /// Seeds the randomness of the timers, `None` for a seed from the OS.
+    pub(crate) rng_seed: Option<u64>,
+
+    /// The runtime to spawn tasks on.
+    pub(crate) runtime: Arc<dyn Runtime>,
+
     /// Skip verification of SSL certificates from relay servers
     ///
     /// May only be used in tests.
     #[cfg(any(test, feature = "test-utils"))]
     #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
     pub(crate) insecure_skip_relay_cert_verify: bool,
+
+    /// Exchange packets over this in-memory network instead of binding UDP sockets.
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+    pub(crate) memory_network: Option<MemoryNetwork>,
 }
 
 impl Default for Options {
@@ -136,14 +269,41 @@ impl Default for Options {
         Options {
             addr_v4: None,
             addr_v6: None,
+            ipv4: true,
+            ipv6: true,
+            bind_interface: None,
+            per_interface_sockets: false,
             secret_key: SecretKey::generate(),
+            previous_secret_keys: Vec::new(),
             relay_map: RelayMap::empty(),
             node_map: None,
             discovery: None,
+            discovery_user_data: None,
             proxy_url: None,
             dns_resolver: crate::dns::default_resolver().clone(),
+            max_send_rate: None,
+            hole_punching: HolePunchConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+            path_selector: None,
+            addr_family: AddrFamilyPolicy::Any,
+            direct_addr_filter: None,
+            external_addrs: Vec::new(),
+            port_mapping: PortMappingConfig::default(),
+            ecn: true,
+            dscp: None,
+            segmentation_offload: true,
+            relay_queue: RelayQueueConfig::default(),
+            relay_keepalive: RelayKeepaliveConfig::default(),
+            standby_relay: false,
+            net_report: net_report::Config::default(),
+            net_report_interval: None,
+            packet_tap: None,
+            rng_seed: None,
+            runtime: Arc::new(TokioRuntime),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
+            #[cfg(any(test, feature = "test-utils"))]
+            memory_network: None,
         }
     }
 }
@@ -152,6 +312,24 @@ impl Default for Options {
 /// common case of a single packet.
 type RelayContents = SmallVec<[Bytes; 1]>;
 
+/// The relay servers an endpoint keeps connections to, to be reachable by other nodes.
+///
+/// See [`Endpoint::watch_relay_status`].
+///
+/// [`Endpoint::watch_relay_status`]: crate::endpoint::Endpoint::watch_relay_status
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayStatus {
+    /// The active home relay, which other nodes use to reach this endpoint.
+    pub home: Option<RelayUrl>,
+    /// The standby relay, which becomes the home relay when the connection to the home
+    /// relay is lost.
+    ///
+    /// Only set if enabled with [`Builder::standby_relay`].
+    ///
+    /// [`Builder::standby_relay`]: crate::endpoint::Builder::standby_relay
+    pub standby: Option<RelayUrl>,
+}
+
 /// Handle for [`MagicSock`].
 ///
 /// Dereferences to [`MagicSock`], and handles closing.
@@ -181,17 +359,37 @@ pub(crate) struct MagicSock {
     me: String,
     /// Proxy
     proxy_url: Option<Url>,
+    /// The network interface the sockets, including those to relay servers, are bound to.
+    bind_interface: Option<String>,
     /// Queue to receive datagrams from relays for [`AsyncUdpSocket::poll_recv`].
     ///
     /// Relay datagrams received by relays are put into this queue and consumed by
     /// [`AsyncUdpSocket`].  This queue takes care of the wakers needed by
     /// [`AsyncUdpSocket::poll_recv`].
     relay_datagrams_queue: Arc<RelayDatagramsQueue>,
-    /// Waker to wake the [`AsyncUdpSocket`] when more data can be sent to the relay server.
+    /// Wakers to wake the [`AsyncUdpSocket`] when more data can be sent to the relay server.
+    ///
+    /// These wakers are used by [`IoPoller`] and the [`RelayActor`] to signal when more
+    /// datagrams can be sent to the relays.  Each connection has its own [`IoPoller`].
+    relay_send_wakers: Arc<parking_lot::Mutex<Vec<Waker>>>,
+    /// Whether to wait for space in the relay queue instead of dropping datagrams.
+    relay_backpressure: bool,
+    /// Limits the rate at which data is sent, if configured.
     ///
-    /// This waker is used by [`IoPoller`] and the [`RelayActor`] to signal when more
-    /// datagrams can be sent to the relays.
-    relay_send_waker: Arc<parking_lot::Mutex<Option<Waker>>>,
+    /// Shared with the [`IoPoller`] so it can wait until sending is allowed again.
+    send_rate_limiter: Option<Arc<RateLimiter>>,
+    /// Whether to keep the ECN codepoints quinn sets on packets sent over UDP.
+    ecn: bool,
+    /// Whether quinn may batch datagrams using GSO and GRO.
+    segmentation_offload: bool,
+    /// Receives copies of the packets sent and received, if set.
+    packet_tap: Option<Arc<dyn PacketTap>>,
+    /// The counters of this endpoint, see [`MagicSock::metrics`].
+    counters: Arc<Counters>,
+    /// Seeds the RNGs of the parts of the endpoint, see [`MagicSock::fork_rng`].
+    rng: parking_lot::Mutex<StdRng>,
+    /// The runtime to spawn tasks on.
+    runtime: Arc<dyn Runtime>,
     /// Counter for ordering of [`MagicSock::poll_recv`] polling order.
     poll_recv_counter: AtomicUsize,
 
@@ -200,9 +398,11 @@ pub(crate) struct MagicSock {
 
     /// Key for this node.
     secret_key: SecretKey,
+    /// Previous keys of this node, see [`Options::previous_secret_keys`].
+    previous_secret_keys: Vec<SecretKey>,
 
     /// Cached version of the Ipv4 and Ipv6 addrs of the current connection.
-    local_addrs: std::sync::RwLock<(SocketAddr, Option<SocketAddr>)>,
+    local_addrs: std::sync::RwLock<(Option<SocketAddr>, Option<SocketAddr>)>,
 
     /// Preferred port from `Options::port`; 0 means auto.
     port: AtomicU16,
@@ -211,19 +411,41 @@ pub(crate) struct MagicSock {
     closing: AtomicBool,
     /// Close was called.
     closed: AtomicBool,
+    /// Background activity is paused, see [`MagicSock::pause`].
+    ///
+    /// Shared with the relay connections, which do not ping the relay servers while paused.
+    paused: Arc<AtomicBool>,
     /// If the last net_report report, reports IPv6 to be available.
     ipv6_reported: Arc<AtomicBool>,
+    /// The last successful net_report report.
+    net_report: Watchable<Option<Arc<net_report::Report>>>,
 
     /// None (or zero nodes) means relay is disabled.
     relay_map: RelayMap,
     /// Nearest relay node ID; 0 means none/unknown.
     my_relay: Watchable<Option<RelayUrl>>,
+    /// The home relay together with the standby relay.
+    relay_status: Watchable<RelayStatus>,
+    /// The state of the port mapping, updated by the actor.
+    port_mapping_status: Watchable<PortMappingStatus>,
+    /// The health of the relay connections, updated by the relay actor.
+    relay_health: RelayHealthMap,
+    /// Broadcasts the [`EndpointEvent`]s of the endpoint.
+    events: sync::broadcast::Sender<EndpointEvent>,
     /// Tracks the networkmap node entity for each node discovery key.
     node_map: NodeMap,
     /// UDP IPv4 socket
-    pconn4: UdpConn,
+    pconn4: Option<UdpConn>,
     /// UDP IPv6 socket
     pconn6: Option<UdpConn>,
+    /// Sockets bound to each network interface, if enabled.
+    interface_sockets: Option<InterfaceSockets>,
+    /// Filters the direct addresses before they are advertised.
+    direct_addr_filter: Option<Arc<dyn DirectAddrFilter>>,
+    /// Statically configured public addresses, always advertised.
+    external_addrs: Vec<SocketAddr>,
+    /// Socket of the QUIC endpoint used for QUIC address discovery.
+    qad_socket: Arc<QadSocket>,
     /// NetReport client
     net_reporter: net_report::Addr,
     /// The state for an active DiscoKey.
@@ -235,6 +457,9 @@ pub(crate) struct MagicSock {
     /// Optional discovery service
     discovery: Option<Box<dyn Discovery>>,
 
+    /// Application-defined data published together with our addresses.
+    discovery_user_data: parking_lot::RwLock<Option<UserData>>,
+
     /// Our discovered direct addresses.
     direct_addrs: DiscoveredDirectAddrs,
 
@@ -251,6 +476,10 @@ pub(crate) struct MagicSock {
     #[cfg(any(test, feature = "test-utils"))]
     #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
     insecure_skip_relay_cert_verify: bool,
+
+    /// Faults injected by tests, see [`MagicSock::faults`].
+    #[cfg(any(test, feature = "test-utils"))]
+    faults: Arc<faults::FaultState>,
 }
 
 impl MagicSock {
@@ -266,16 +495,79 @@ impl MagicSock {
         self.my_relay.get()
     }
 
+    /// Returns the last net_report report, if one completed.
+    pub(crate) fn net_report(&self) -> Option<Arc<net_report::Report>> {
+        self.net_report.get()
+    }
+
     /// Get the current proxy configuration.
     pub(crate) fn proxy_url(&self) -> Option<&Url> {
         self.proxy_url.as_ref()
     }
 
+    /// Returns the name of the network interface the sockets are bound to, if any.
+    pub(crate) fn bind_interface(&self) -> Option<&str> {
+        self.bind_interface.as_deref()
+    }
+
+    /// Returns the standby relay, which takes over when the home relay fails.
+    pub(crate) fn standby_relay(&self) -> Option<RelayUrl> {
+        self.relay_status.get().standby
+    }
+
+    /// Returns the home relay and the standby relay.
+    pub(crate) fn relay_status(&self) -> RelayStatus {
+        self.relay_status.get()
+    }
+
+    /// Returns the state of the port mapping.
+    pub(crate) fn port_mapping_status(&self) -> PortMappingStatus {
+        self.port_mapping_status.get()
+    }
+
+    /// Watch for changes to the state of the port mapping.
+    ///
+    /// The current status is the first item in the stream.
+    pub(crate) fn watch_port_mapping(&self) -> impl Stream<Item = PortMappingStatus> {
+        let current = futures_lite::stream::once(self.port_mapping_status());
+        current.chain(self.port_mapping_status.watch().into_stream())
+    }
+
+    /// Returns the health of the current relay connections.
+    pub(crate) fn relay_health(&self) -> BTreeMap<RelayUrl, RelayHealth> {
+        self.relay_health.lock().clone()
+    }
+
+    /// Returns the sender of the [`EndpointEvent`]s.
+    pub(crate) fn events(&self) -> &sync::broadcast::Sender<EndpointEvent> {
+        &self.events
+    }
+
     /// Sets the relay node with the best latency.
     ///
     /// If we are not connected to any relay nodes, set this to `None`.
     fn set_my_relay(&self, my_relay: Option<RelayUrl>) -> Option<RelayUrl> {
-        self.my_relay.replace(my_relay)
+        let standby = self
+            .standby_relay()
+            .filter(|url| Some(url) != my_relay.as_ref());
+        let old = self.my_relay.replace(my_relay.clone());
+        self.relay_status
+            .update(RelayStatus {
+                home: my_relay,
+                standby,
+            })
+            .ok();
+        old
+    }
+
+    /// Sets the standby relay.
+    fn set_standby_relay(&self, standby: Option<RelayUrl>) {
+        self.relay_status
+            .update(RelayStatus {
+                home: self.my_relay(),
+                standby,
+            })
+            .ok();
     }
 
     fn is_closing(&self) -> bool {
@@ -286,12 +578,16 @@ impl MagicSock {
         self.closed.load(Ordering::SeqCst)
     }
 
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
     fn public_key(&self) -> PublicKey {
         self.secret_key.public()
     }
 
     /// Get the cached version of the Ipv4 and Ipv6 addrs of the current connection.
-    pub(crate) fn local_addr(&self) -> (SocketAddr, Option<SocketAddr>) {
+    pub(crate) fn local_addr(&self) -> (Option<SocketAddr>, Option<SocketAddr>) {
         *self.local_addrs.read().expect("not poisoned")
     }
 
@@ -312,6 +608,94 @@ impl MagicSock {
         self.node_map.remote_info(node_id)
     }
 
+    /// Returns the statistics of all paths to a node, if the node is known.
+    pub(crate) fn path_stats(&self, node_id: NodeId) -> Option<Vec<NodePathStats>> {
+        self.node_map.path_stats(node_id)
+    }
+
+    /// Returns the statistics of the DISCO messages exchanged with a node, if it is known.
+    pub(crate) fn disco_stats(&self, node_id: NodeId) -> Option<DiscoStats> {
+        self.node_map.disco_stats(node_id)
+    }
+
+    /// Returns the payload bytes exchanged with each node, optionally resetting the counters.
+    pub(crate) fn traffic_stats(&self, reset: bool) -> BTreeMap<NodeId, TrafficStats> {
+        self.node_map.traffic_stats(reset)
+    }
+
+    /// Returns a snapshot of the counters of this endpoint.
+    pub(crate) fn metrics(&self) -> EndpointMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Returns the counters of this endpoint.
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Returns a new RNG for a part of the endpoint, seeded from the RNG of the endpoint.
+    ///
+    /// With [`Options::rng_seed`] set, the randomness of the endpoint is reproducible.
+    pub(super) fn fork_rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.rng.lock().gen())
+    }
+
+    /// Returns the runtime this endpoint spawns its tasks on.
+    pub(crate) fn runtime(&self) -> &Arc<dyn Runtime> {
+        &self.runtime
+    }
+
+    /// Returns a handle to inject faults into this endpoint.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(crate) fn faults(&self) -> Faults {
+        Faults::new(self.faults.clone(), self.relay_actor_sender.clone())
+    }
+
+    /// Returns whether DISCO pongs received are dropped by an injected fault.
+    fn drops_pongs(&self) -> bool {
+        #[cfg(any(test, feature = "test-utils"))]
+        {
+            self.faults.drops_pongs()
+        }
+        #[cfg(not(any(test, feature = "test-utils")))]
+        {
+            false
+        }
+    }
+
+    /// Returns whether UDP datagrams exchanged with `node_id` are dropped by an injected
+    /// fault.
+    fn is_udp_blackholed(&self, node_id: NodeId) -> bool {
+        #[cfg(any(test, feature = "test-utils"))]
+        {
+            self.faults.is_udp_blackholed(node_id)
+        }
+        #[cfg(not(any(test, feature = "test-utils")))]
+        {
+            let _ = node_id;
+            false
+        }
+    }
+
+    /// Returns whether UDP datagrams received from `addr` are dropped by an injected fault.
+    fn is_udp_blackholed_addr(&self, addr: SocketAddr) -> bool {
+        #[cfg(any(test, feature = "test-utils"))]
+        {
+            self.faults
+                .is_udp_blackholed_addr(addr, |addr| self.node_map.get_node_id_for_ip_port(addr))
+        }
+        #[cfg(not(any(test, feature = "test-utils")))]
+        {
+            let _ = addr;
+            false
+        }
+    }
+
+    /// Returns the state of establishing a direct connection to a node, if it is known.
+    pub(crate) fn connectivity_state(&self, node_id: NodeId) -> Option<ConnectivityState> {
+        self.node_map.connectivity_state(node_id)
+    }
+
     /// Returns the direct addresses as a stream.
     ///
     /// The [`MagicSock`] continuously monitors the direct addresses, the network addresses
@@ -344,6 +728,28 @@ impl MagicSock {
         current.chain(changes)
     }
 
+    /// Watch for new net_report reports.
+    ///
+    /// If a report completed already, it is the first item in the stream.  Reports which
+    /// are identical to the previous one are not yielded.
+    pub(crate) fn watch_net_report(&self) -> impl Stream<Item = Arc<net_report::Report>> {
+        let current = futures_lite::stream::iter(self.net_report());
+        let changes = self
+            .net_report
+            .watch()
+            .into_stream()
+            .filter_map(|maybe_report| maybe_report);
+        current.chain(changes)
+    }
+
+    /// Watch for changes to the home relay and the standby relay.
+    ///
+    /// The current status is the first item in the stream.
+    pub(crate) fn watch_relay_status(&self) -> impl Stream<Item = RelayStatus> {
+        let current = futures_lite::stream::once(self.relay_status());
+        current.chain(self.relay_status.watch().into_stream())
+    }
+
     /// Returns a stream that reports the [`ConnectionType`] we have to the
     /// given `node_id`.
     ///
@@ -361,14 +767,70 @@ impl MagicSock {
         self.node_map.conn_type_stream(node_id)
     }
 
+    /// Returns a [`ConnectionTypeWatcher`] for the given `node_id`.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is no address information known about the
+    /// given `node_id`.
+    pub(crate) fn conn_type_watcher(&self, node_id: NodeId) -> Result<ConnectionTypeWatcher> {
+        self.node_map.conn_type_watcher(node_id)
+    }
+
     /// Returns the socket address which can be used by the QUIC layer to dial this node.
     pub(crate) fn get_mapping_addr(&self, node_id: NodeId) -> Option<QuicMappedAddr> {
         self.node_map.get_quic_mapped_addr_for_node_key(node_id)
     }
 
+    /// Returns the node which the QUIC layer identifies by this mapped address, if known.
+    pub(crate) fn get_node_id_for_mapping_addr(&self, addr: QuicMappedAddr) -> Option<NodeId> {
+        self.node_map.get_node_id_for_quic_mapped_addr(addr)
+    }
+
+    /// Removes all information about a node.
+    ///
+    /// Returns `false` if the node was not known.
+    pub(crate) fn forget_node(&self, node_id: NodeId) -> bool {
+        self.disco_secrets.remove(&node_id);
+        self.node_map.forget_node(node_id)
+    }
+
+    /// Sets which paths may be used to send to the given node.
+    ///
+    /// Does nothing if nothing is known about the node yet.
+    pub(crate) fn set_path_policy(&self, node_id: NodeId, policy: PathPolicy) {
+        self.node_map.set_path_policy(node_id, policy)
+    }
+
+    /// Sets which address families may be used for direct paths to the given node.
+    ///
+    /// Without a `policy` the default set in the [`Options`] is used.  Does nothing if
+    /// nothing is known about the node yet.
+    pub(crate) fn set_addr_family_policy(&self, node_id: NodeId, policy: Option<AddrFamilyPolicy>) {
+        self.node_map.set_addr_family_policy(node_id, policy)
+    }
+
+    /// Sets how data is spread over multiple direct paths to the given node.
+    ///
+    /// Does nothing if nothing is known about the node yet.
+    pub(crate) fn set_multipath_policy(&self, node_id: NodeId, policy: MultipathPolicy) {
+        self.node_map.set_multipath_policy(node_id, policy)
+    }
+
     /// Add addresses for a node to the magic socket's addresbook.
+    pub fn add_node_addr(&self, addr: NodeAddr, source: node_map::Source) -> Result<()> {
+        self.add_node_addr_seen_at(addr, source, Instant::now())
+    }
+
+    /// Add addresses for a node to the magic socket's addresbook, which the source last saw
+    /// at `seen_at`.
     #[instrument(skip_all, fields(me = %self.me))]
-    pub fn add_node_addr(&self, mut addr: NodeAddr, source: node_map::Source) -> Result<()> {
+    pub fn add_node_addr_seen_at(
+        &self,
+        mut addr: NodeAddr,
+        source: node_map::Source,
+        seen_at: Instant,
+    ) -> Result<()> {
         let mut pruned = 0;
         for my_addr in self.direct_addrs.sockaddrs() {
             if addr.info.direct_addresses.remove(&my_addr) {
@@ -377,7 +839,7 @@ impl MagicSock {
             }
         }
         if !addr.info.is_empty() {
-            self.node_map.add_node_addr(addr, source);
+            self.node_map.add_node_addr_seen_at(addr, source, seen_at);
             Ok(())
         } else if pruned != 0 {
             Err(anyhow::anyhow!(
@@ -390,13 +852,34 @@ impl MagicSock {
 
     /// Stores a new set of direct addresses.
     ///
-    /// If the direct addresses have changed from the previous set, they are published to
-    /// discovery.
+    /// The addresses are passed through the [`DirectAddrFilter`], if any.  If the filtered
+    /// addresses have changed from the previous set, they are published to discovery.
     pub(super) fn store_direct_addresses(&self, addrs: BTreeSet<DirectAddr>) {
+        let reflexive_ips = addrs
+            .iter()
+            .filter(|addr| {
+                matches!(
+                    addr.typ,
+                    DirectAddrType::Stun
+                        | DirectAddrType::Stun4LocalPort
+                        | DirectAddrType::Portmapped
+                        | DirectAddrType::External
+                )
+            })
+            .map(|addr| addr.addr.ip())
+            .filter(|ip| ip.is_ipv4())
+            .collect();
+        // Our own addresses must not be used as paths to other nodes, even the ones which
+        // are not advertised.
+        let sockaddrs = addrs.iter().map(|addr| addr.addr).collect();
+        let addrs = match self.direct_addr_filter {
+            Some(ref filter) => filter.filter(addrs),
+            None => addrs,
+        };
         let updated = self.direct_addrs.update(addrs);
         if updated {
-            self.node_map
-                .on_direct_addr_discovered(self.direct_addrs.sockaddrs());
+            self.node_map.set_reflexive_ips(reflexive_ips);
+            self.node_map.on_direct_addr_discovered(sockaddrs);
             self.publish_my_addr();
         }
     }
@@ -411,6 +894,12 @@ impl MagicSock {
         self.discovery.as_ref().map(Box::as_ref)
     }
 
+    /// Sets the user data published by discovery and republishes our addresses.
+    pub(crate) fn set_discovery_user_data(&self, user_data: Option<UserData>) {
+        *self.discovery_user_data.write() = user_data;
+        self.publish_my_addr();
+    }
+
     /// Call to notify the system of potential network changes.
     pub(crate) async fn network_change(&self) {
         self.actor_sender
@@ -419,6 +908,83 @@ impl MagicSock {
             .ok();
     }
 
+    /// Measures the latency to a node using a DISCO ping on the path currently used for it.
+    pub(crate) async fn ping(&self, node_id: NodeId) -> Result<PingResult> {
+        let (ping, result) = self
+            .node_map
+            .start_probe(node_id, self.ipv6_reported.load(Ordering::Relaxed))
+            .context("no path to node")?;
+        let tx_id = ping.tx_id;
+        self.send_ping_queued(ping);
+        match time::timeout(node_map::PING_TIMEOUT_DURATION, result).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) | Err(_) => {
+                self.node_map.cancel_probe(node_id, tx_id);
+                Err(anyhow!("no pong received"))
+            }
+        }
+    }
+
+    /// Handles a network change as major, regardless of what the network monitor detects.
+    ///
+    /// Returns once the sockets are re-bound and the re-validation of the paths and relay
+    /// connections has been started.
+    pub(crate) async fn rebind(&self) {
+        let (tx, rx) = sync::oneshot::channel();
+        if self
+            .actor_sender
+            .send(ActorMessage::Rebind(tx))
+            .await
+            .is_ok()
+        {
+            rx.await.ok();
+        }
+    }
+
+    /// Pauses all background activity.
+    ///
+    /// While paused no heartbeat pings are sent to other nodes, no net_report probes are
+    /// run, port mappings are not renewed, relay servers are not pinged and nothing is
+    /// published to discovery.  Existing connections and relay connections are kept.
+    pub(crate) async fn pause(&self) {
+        if self.paused.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        if let Some(discovery) = self.discovery() {
+            discovery.set_paused(true);
+        }
+        self.actor_sender.send(ActorMessage::Pause).await.ok();
+    }
+
+    /// Resumes background activity after [`MagicSock::pause`].
+    ///
+    /// Since the network may have changed while paused, the paths to all nodes are
+    /// re-validated and our own addresses are refreshed immediately.
+    pub(crate) async fn resume(&self) {
+        if !self.paused.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        if let Some(discovery) = self.discovery() {
+            discovery.set_paused(false);
+        }
+        self.actor_sender.send(ActorMessage::Resume).await.ok();
+    }
+
+    /// Switches the home relay to the relay at `url`.
+    ///
+    /// The relay must be part of the [`RelayMap`].  The home relay only moves away from it
+    /// again once it becomes unreachable or another relay is consistently much faster.
+    pub(crate) async fn set_home_relay(&self, url: RelayUrl) -> Result<()> {
+        ensure!(
+            self.relay_map.contains_node(&url),
+            "relay {url} is not in the relay map"
+        );
+        self.actor_sender
+            .send(ActorMessage::SetHomeRelay(url))
+            .await
+            .map_err(|_| anyhow!("magicsock actor closed"))
+    }
+
     #[cfg(test)]
     async fn force_network_change(&self, is_major: bool) {
         self.actor_sender
@@ -430,8 +996,8 @@ impl MagicSock {
     #[cfg_attr(windows, allow(dead_code))]
     fn normalized_local_addr(&self) -> io::Result<SocketAddr> {
         let (v4, v6) = self.local_addr();
-        let addr = if let Some(v6) = v6 { v6 } else { v4 };
-        Ok(addr)
+        v6.or(v4)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no socket bound"))
     }
 
     fn create_io_poller(&self) -> Pin<Box<dyn quinn::UdpPoller>> {
@@ -449,14 +1015,17 @@ impl MagicSock {
         // Right now however we have one single poller behaving the same for each
         // connection.  It checks all paths and returns Poll::Ready as soon as any path is
         // ready.
-        let ipv4_poller = self.pconn4.create_io_poller();
+        let ipv4_poller = self.pconn4.as_ref().map(|sock| sock.create_io_poller());
         let ipv6_poller = self.pconn6.as_ref().map(|sock| sock.create_io_poller());
         let relay_sender = self.relay_actor_sender.clone();
         Box::pin(IoPoller {
             ipv4_poller,
             ipv6_poller,
             relay_sender,
-            relay_send_waker: self.relay_send_waker.clone(),
+            relay_send_wakers: self.relay_send_wakers.clone(),
+            relay_backpressure: self.relay_backpressure,
+            rate_limiter: self.send_rate_limiter.clone(),
+            rate_limit_sleep: None,
         })
     }
 
@@ -485,11 +1054,14 @@ impl MagicSock {
             "sending",
         );
         let mut transmit = transmit.clone();
+        if !self.ecn {
+            transmit.ecn = None;
+        }
         match self
             .node_map
             .get_send_addrs(dest, self.ipv6_reported.load(Ordering::Relaxed))
         {
-            Some((node_id, udp_addr, relay_url, msgs)) => {
+            Some((node_id, mut udp_addr, mut relay_url, msgs)) => {
                 let mut pings_sent = false;
                 // If we have pings to send, we *have* to send them out first.
                 if !msgs.is_empty() {
@@ -502,6 +1074,18 @@ impl MagicSock {
                     pings_sent = true;
                 }
 
+                if let Some(ref limiter) = self.send_rate_limiter {
+                    // Mixed connections send the data on both paths.
+                    let paths = udp_addr.is_some() as usize + relay_url.is_some() as usize;
+                    if !limiter.try_acquire(transmit.contents.len() * paths) {
+                        inc!(MagicsockMetrics, send_data_rate_limited);
+                        return Err(io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            "send rate limit reached",
+                        ));
+                    }
+                }
+
                 let mut udp_sent = false;
                 let mut udp_error = None;
                 let mut relay_sent = false;
@@ -511,7 +1095,14 @@ impl MagicSock {
                 if let Some(addr) = udp_addr {
                     // rewrite target address
                     transmit.destination = addr;
-                    match self.try_send_udp(addr, &transmit) {
+                    let res = if self.is_udp_blackholed(node_id) {
+                        trace!(node = %node_id.fmt_short(), dst = %addr,
+                               "dropping transmit, UDP path is blackholed");
+                        Ok(())
+                    } else {
+                        self.try_send_udp(addr, &transmit)
+                    };
+                    match res {
                         Ok(()) => {
                             trace!(node = %node_id.fmt_short(), dst = %addr,
                                    "sent transmit over UDP");
@@ -520,11 +1111,41 @@ impl MagicSock {
                         Err(err) => {
                             error!(node = %node_id.fmt_short(), dst = %addr,
                                    "failed to send udp: {err:#}");
-                            udp_error = Some(err);
+                            if is_path_unreachable(&err) {
+                                // Migrate to another path and send the transmit there, so
+                                // QUIC does not need to detect the loss.
+                                match self.node_map.direct_path_failed(node_id, addr) {
+                                    Some(SendAddr::Udp(new_addr)) => {
+                                        transmit.destination = new_addr;
+                                        if self.try_send_udp(new_addr, &transmit).is_ok() {
+                                            udp_addr = Some(new_addr);
+                                            udp_sent = true;
+                                        }
+                                    }
+                                    Some(SendAddr::Relay(url)) => {
+                                        relay_url.get_or_insert(url);
+                                    }
+                                    None => {}
+                                }
+                            }
+                            if !udp_sent {
+                                udp_error = Some(err);
+                            }
                         }
                     }
                 }
 
+                if let Some(addr) = udp_addr.filter(|_| udp_sent) {
+                    let stride = transmit.segment_size.unwrap_or(transmit.contents.len());
+                    self.tap_udp(
+                        TapDirection::Send,
+                        addr,
+                        Some(node_id),
+                        transmit.contents,
+                        stride,
+                    );
+                }
+
                 // send relay
                 if let Some(ref relay_url) = relay_url {
                     match self.try_send_relay(relay_url, node_id, split_packets(&transmit)) {
@@ -537,6 +1158,15 @@ impl MagicSock {
                     }
                 }
 
+                if udp_sent || relay_sent {
+                    self.node_map.add_bytes_sent(
+                        node_id,
+                        udp_addr.filter(|_| udp_sent),
+                        relay_url.as_ref().filter(|_| relay_sent),
+                        transmit.contents.len(),
+                    );
+                }
+
                 let udp_pending = udp_error
                     .as_ref()
                     .map(|err| err.kind() == io::ErrorKind::WouldBlock)
@@ -548,7 +1178,21 @@ impl MagicSock {
                 if udp_pending && relay_pending {
                     // Handle backpressure.
                     Err(io::Error::new(io::ErrorKind::WouldBlock, "pending"))
+                } else if relay_pending && !udp_sent && self.relay_backpressure {
+                    // Wait for space in the relay queue, the IoPoller wakes us up.
+                    inc!(MagicsockMetrics, send_relay_backpressure);
+                    Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "relay queue full",
+                    ))
                 } else {
+                    if relay_pending {
+                        warn!(
+                            node = %node_id.fmt_short(),
+                            "send relay: message dropped, relay queue is full",
+                        );
+                        inc!(MagicsockMetrics, send_relay_dropped_queue_full);
+                    }
                     if relay_sent || udp_sent {
                         trace!(
                             node = %node_id.fmt_short(),
@@ -597,6 +1241,7 @@ impl MagicSock {
             len = contents.iter().map(|c| c.len()).sum::<usize>(),
             "send relay",
         );
+        let tapped = self.packet_tap.is_some().then(|| contents.clone());
         let msg = RelayActorMessage::Send {
             url: url.clone(),
             contents,
@@ -606,19 +1251,23 @@ impl MagicSock {
             Ok(_) => {
                 trace!(node = %node.fmt_short(), relay_url = %url,
                        "send relay: message queued");
+                for packet in tapped.iter().flatten() {
+                    self.tap_relay(TapDirection::Send, url, node, packet);
+                }
                 Ok(())
             }
             Err(mpsc::error::TrySendError::Closed(_)) => {
                 warn!(node = %node.fmt_short(), relay_url = %url,
                       "send relay: message dropped, channel to actor is closed");
+                inc!(MagicsockMetrics, send_relay_dropped_closed);
                 Err(io::Error::new(
                     io::ErrorKind::ConnectionReset,
                     "channel to actor is closed",
                 ))
             }
             Err(mpsc::error::TrySendError::Full(_)) => {
-                warn!(node = %node.fmt_short(), relay_url = %url,
-                      "send relay: message dropped, channel to actor is full");
+                trace!(node = %node.fmt_short(), relay_url = %url,
+                       "send relay: channel to actor is full");
                 Err(io::Error::new(
                     io::ErrorKind::WouldBlock,
                     "channel to actor is full",
@@ -627,10 +1276,86 @@ impl MagicSock {
         }
     }
 
+    /// Passes copies of UDP datagrams exchanged with `remote` to the packet tap, if set.
+    ///
+    /// The `contents` hold datagrams of `stride` bytes, like a GSO transmit.
+    fn tap_udp(
+        &self,
+        direction: TapDirection,
+        remote: SocketAddr,
+        node_id: Option<NodeId>,
+        contents: &[u8],
+        stride: usize,
+    ) {
+        if self.packet_tap.is_none() {
+            return;
+        }
+        let (local_v4, local_v6) = self.local_addr();
+        let local = match remote {
+            SocketAddr::V4(_) => local_v4,
+            SocketAddr::V6(_) => local_v6,
+        };
+        let path = TapPath::Udp { local, remote };
+        self.tap_packets(direction, path, node_id, contents, stride);
+    }
+
+    /// Passes a copy of a relay frame exchanged with `node_id` to the packet tap, if set.
+    fn tap_relay(&self, direction: TapDirection, url: &RelayUrl, node_id: NodeId, frame: &[u8]) {
+        let path = TapPath::Relay(url);
+        self.tap_packets(direction, path, Some(node_id), frame, frame.len());
+    }
+
+    fn tap_packets(
+        &self,
+        direction: TapDirection,
+        path: TapPath<'_>,
+        node_id: Option<NodeId>,
+        contents: &[u8],
+        stride: usize,
+    ) {
+        let Some(ref tap) = self.packet_tap else {
+            return;
+        };
+        for data in contents.chunks(stride.max(1)) {
+            tap.packet(&TappedPacket {
+                direction,
+                path,
+                node_id,
+                kind: PacketKind::of(data),
+                data,
+            });
+        }
+    }
+
     fn try_send_udp(&self, addr: SocketAddr, transmit: &quinn_udp::Transmit) -> io::Result<()> {
-        let conn = self.conn_for_addr(addr)?;
-        conn.try_send(transmit)?;
+        match self
+            .interface_sockets
+            .as_ref()
+            .and_then(|sockets| sockets.conn_for(addr))
+        {
+            Some(conn) => {
+                // Reply from the interface socket the remote sent to.  Its source address
+                // is fixed by the socket.
+                let transmit = quinn_udp::Transmit {
+                    src_ip: None,
+                    ..transmit.clone()
+                };
+                conn.try_send(&transmit)?;
+            }
+            None => self.conn_for_addr(addr)?.try_send(transmit)?,
+        }
         let total_bytes: u64 = transmit.contents.len() as u64;
+        if let Some(segment_size) = transmit.segment_size {
+            if transmit.contents.len() > segment_size {
+                inc!(MagicsockMetrics, send_gso_datagrams);
+                inc_by!(
+                    MagicsockMetrics,
+                    send_gso_segments,
+                    transmit.contents.len().div_ceil(segment_size) as u64
+                );
+            }
+        }
+        Counters::add(&self.counters.udp_bytes_sent, total_bytes);
         if addr.is_ipv6() {
             inc_by!(MagicsockMetrics, send_ipv6, total_bytes);
         } else {
@@ -639,9 +1364,17 @@ impl MagicSock {
         Ok(())
     }
 
+    /// Returns the bound UDP sockets.
+    fn udp_conns(&self) -> impl Iterator<Item = &UdpConn> {
+        self.pconn4.iter().chain(self.pconn6.iter())
+    }
+
     fn conn_for_addr(&self, addr: SocketAddr) -> io::Result<&UdpConn> {
         let sock = match addr {
-            SocketAddr::V4(_) => &self.pconn4,
+            SocketAddr::V4(_) => self
+                .pconn4
+                .as_ref()
+                .ok_or(io::Error::new(io::ErrorKind::Other, "no IPv4 connection"))?,
             SocketAddr::V6(_) => self
                 .pconn6
                 .as_ref()
@@ -668,11 +1401,13 @@ impl MagicSock {
         // Pending).
         macro_rules! poll_ipv4 {
             () => {
-                match self.pconn4.poll_recv(cx, bufs, metas)? {
-                    Poll::Pending | Poll::Ready(0) => {}
-                    Poll::Ready(n) => {
-                        self.process_udp_datagrams(true, &mut bufs[..n], &mut metas[..n]);
-                        return Poll::Ready(Ok(n));
+                if let Some(ref pconn) = self.pconn4 {
+                    match pconn.poll_recv(cx, bufs, metas)? {
+                        Poll::Pending | Poll::Ready(0) => {}
+                        Poll::Ready(n) => {
+                            self.process_udp_datagrams(true, &mut bufs[..n], &mut metas[..n]);
+                            return Poll::Ready(Ok(n));
+                        }
                     }
                 }
             };
@@ -690,6 +1425,20 @@ impl MagicSock {
                 }
             };
         }
+        macro_rules! poll_interfaces {
+            () => {
+                if let Some(ref sockets) = self.interface_sockets {
+                    if let Poll::Ready((n, local_addr)) = sockets.poll_recv(cx, bufs, metas)? {
+                        self.process_udp_datagrams(
+                            local_addr.is_ipv4(),
+                            &mut bufs[..n],
+                            &mut metas[..n],
+                        );
+                        return Poll::Ready(Ok(n));
+                    }
+                }
+            };
+        }
         macro_rules! poll_relay {
             () => {
                 match self.poll_recv_relay(cx, bufs, metas) {
@@ -706,6 +1455,7 @@ impl MagicSock {
                 poll_ipv4!();
                 poll_ipv6!();
                 poll_relay!();
+                poll_interfaces!();
                 Poll::Pending
             }
             1 => {
@@ -713,6 +1463,7 @@ impl MagicSock {
                 poll_ipv6!();
                 poll_relay!();
                 poll_ipv4!();
+                poll_interfaces!();
                 Poll::Pending
             }
             _ => {
@@ -720,6 +1471,7 @@ impl MagicSock {
                 poll_relay!();
                 poll_ipv4!();
                 poll_ipv6!();
+                poll_interfaces!();
                 Poll::Pending
             }
         }
@@ -762,9 +1514,16 @@ impl MagicSock {
         for (meta, buf) in metas.iter_mut().zip(bufs.iter_mut()) {
             let mut buf_contains_quic_datagrams = false;
             let mut quic_datagram_count = 0;
+            let mut quic_datagram_bytes = 0;
+            let mut quic_datagram_max = 0;
             if meta.len > meta.stride {
                 trace!(%meta.len, %meta.stride, "GRO datagram received");
                 inc!(MagicsockMetrics, recv_gro_datagrams);
+                inc_by!(
+                    MagicsockMetrics,
+                    recv_gro_segments,
+                    meta.len.div_ceil(meta.stride) as u64
+                );
             }
 
             // Chunk through the datagrams in this GRO payload to find disco and stun
@@ -784,32 +1543,70 @@ impl MagicSock {
                 // which we do in Endpoint::bind.
                 if stun::is(datagram) {
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: stun packet");
+                    self.tap_udp(TapDirection::Recv, meta.addr, None, datagram, meta.stride);
                     let packet2 = Bytes::copy_from_slice(datagram);
                     self.net_reporter.receive_stun_packet(packet2, meta.addr);
                     datagram[0] = 0u8;
                 } else if let Some((sender, sealed_box)) = disco::source_and_box(datagram) {
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: disco packet");
-                    self.handle_disco_message(
-                        sender,
-                        sealed_box,
-                        DiscoMessageSource::Udp(meta.addr),
+                    self.tap_udp(
+                        TapDirection::Recv,
+                        meta.addr,
+                        Some(sender),
+                        datagram,
+                        meta.stride,
                     );
+                    if self.is_udp_blackholed(sender) {
+                        trace!(
+                            src = %meta.addr,
+                            "UDP recv: dropping disco packet, path is blackholed",
+                        );
+                    } else {
+                        self.handle_disco_message(
+                            sender,
+                            sealed_box,
+                            DiscoMessageSource::Udp(meta.addr),
+                        );
+                    }
                     datagram[0] = 0u8;
                 } else {
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: quic packet");
+                    Counters::add(&self.counters.udp_bytes_recv, datagram.len() as _);
                     if from_ipv4 {
                         inc_by!(MagicsockMetrics, recv_data_ipv4, datagram.len() as _);
                     } else {
                         inc_by!(MagicsockMetrics, recv_data_ipv6, datagram.len() as _);
                     }
                     quic_datagram_count += 1;
+                    quic_datagram_bytes += datagram.len();
+                    quic_datagram_max = quic_datagram_max.max(datagram.len());
                     buf_contains_quic_datagrams = true;
                 };
             }
 
-            if buf_contains_quic_datagrams {
+            if buf_contains_quic_datagrams && self.is_udp_blackholed_addr(meta.addr) {
+                trace!(src = %meta.addr, "UDP recv: dropping quic packets, path is blackholed");
+                meta.len = 0;
+            } else if buf_contains_quic_datagrams {
+                let (src, len) = (meta.addr, meta.len);
                 // Update the NodeMap and remap RecvMeta to the QuicMappedAddr.
-                match self.node_map.receive_udp(meta.addr) {
+                let node_id = match self.node_map.receive_udp(
+                    meta.addr,
+                    quic_datagram_bytes,
+                    quic_datagram_max,
+                ) {
+                    None if self.qad_socket.is_remote(meta.addr) => {
+                        // QUIC address discovery responses from a relay server, DISCO and
+                        // STUN datagrams already have their first byte zeroed.
+                        for datagram in buf[..meta.len].chunks(meta.stride) {
+                            if datagram[0] != 0 {
+                                self.qad_socket
+                                    .try_recv(meta.addr, Bytes::copy_from_slice(datagram));
+                            }
+                        }
+                        meta.len = 0;
+                        None
+                    }
                     None => {
                         warn!(
                             src = ?meta.addr,
@@ -820,6 +1617,7 @@ impl MagicSock {
                         // If we have no node state for the from addr, set len to 0 to make
                         // quinn skip the buf completely.
                         meta.len = 0;
+                        None
                     }
                     Some((node_id, quic_mapped_addr)) => {
                         trace!(
@@ -831,6 +1629,22 @@ impl MagicSock {
                         );
                         quic_packets_total += quic_datagram_count;
                         meta.addr = quic_mapped_addr.0;
+                        Some(node_id)
+                    }
+                };
+                if self.packet_tap.is_some() {
+                    // DISCO and STUN datagrams were already tapped and have their first
+                    // byte zeroed.
+                    for datagram in buf[..len].chunks(meta.stride) {
+                        if datagram[0] != 0 {
+                            self.tap_udp(
+                                TapDirection::Recv,
+                                src,
+                                node_id,
+                                datagram,
+                                datagram.len(),
+                            );
+                        }
                     }
                 }
             } else {
@@ -884,6 +1698,7 @@ impl MagicSock {
                     }
                     Some((node_id, meta, buf)) => {
                         inc_by!(MagicsockMetrics, recv_data_relay, buf.len() as _);
+                        Counters::add(&self.counters.relay_bytes_recv, buf.len() as _);
                         trace!(
                             src = %meta.addr,
                             node = %node_id.fmt_short(),
@@ -925,13 +1740,14 @@ impl MagicSock {
             warn!("received empty relay packet");
             return None;
         }
+        self.tap_relay(TapDirection::Recv, &dm.url, dm.src, &dm.buf);
 
         if self.handle_relay_disco_message(&dm.buf, &dm.url, dm.src) {
             // DISCO messages are handled internally in the MagicSock, do not pass to Quinn.
             return None;
         }
 
-        let quic_mapped_addr = self.node_map.receive_relay(&dm.url, dm.src);
+        let quic_mapped_addr = self.node_map.receive_relay(&dm.url, dm.src, dm.buf.len());
 
         // Normalize local_ip
         #[cfg(not(windows))]
@@ -989,8 +1805,9 @@ impl MagicSock {
         // this node, do the heavy crypto lifting to see what they want.
         let dm = match self.disco_secrets.unseal_and_decode(
             &self.secret_key,
+            &self.previous_secret_keys,
             sender,
-            sealed_box.to_vec(),
+            sealed_box,
         ) {
             Ok(dm) => dm,
             Err(DiscoBoxError::Open(err)) => {
@@ -1020,11 +1837,16 @@ impl MagicSock {
         let span = trace_span!("handle_disco", ?dm);
         let _guard = span.enter();
         trace!("receive disco message");
+        let typ = dm.message_type();
         match dm {
             disco::Message::Ping(ping) => {
                 inc!(MagicsockMetrics, recv_disco_ping);
                 self.handle_ping(ping, sender, src);
             }
+            disco::Message::Pong(_) if self.drops_pongs() => {
+                debug!("dropping pong, injected fault");
+                return;
+            }
             disco::Message::Pong(pong) => {
                 inc!(MagicsockMetrics, recv_disco_pong);
                 self.node_map.handle_pong(sender, &src, pong);
@@ -1059,11 +1881,18 @@ impl MagicSock {
                 }
             }
         }
+        // Recorded after handling, a ping from an unknown node adds it to the NodeMap.
+        self.node_map.disco_received(sender, typ, src.is_relay());
         trace!("disco message handled");
     }
 
     /// Handle a ping message.
     fn handle_ping(&self, dm: disco::Ping, sender: NodeId, src: DiscoMessageSource) {
+        if !src.is_relay() && self.node_map.path_policy(sender) == PathPolicy::RelayOnly {
+            // Answering would reveal our direct address to the node.
+            debug!(%src, tx = %hex::encode(dm.tx_id), "received direct ping: relay only path policy, ignore");
+            return;
+        }
         // Insert the ping into the node map, and return whether a ping with this tx_id was already
         // received.
         let addr: SendAddr = src.clone().into();
@@ -1225,6 +2054,7 @@ impl MagicSock {
                 }
                 inc!(MagicsockMetrics, sent_disco_relay);
                 disco_message_sent(&msg);
+                self.node_map.disco_sent(dst, &msg, true);
                 true
             }
             Err(_) => false,
@@ -1244,7 +2074,7 @@ impl MagicSock {
                     Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
                         // This is the socket .try_send_disco_message_udp used.
                         let sock = self.conn_for_addr(dst)?;
-                        match sock.as_socket_ref().poll_writable(cx) {
+                        match sock.poll_writable(cx) {
                             Poll::Ready(Ok(())) => continue,
                             Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
                             Poll::Pending => return Poll::Pending,
@@ -1281,12 +2111,23 @@ impl MagicSock {
             segment_size: None,
             src_ip: None, // TODO
         };
-        let sent = self.try_send_udp(dst, &transmit);
+        let sent = if self.is_udp_blackholed(dst_node) {
+            trace!(
+                %dst,
+                node = %dst_node.fmt_short(),
+                "dropping disco message, UDP path is blackholed",
+            );
+            Ok(())
+        } else {
+            self.try_send_udp(dst, &transmit)
+        };
         match sent {
             Ok(()) => {
                 trace!(%dst, node = %dst_node.fmt_short(), %msg, "sent disco message");
+                self.tap_udp(TapDirection::Send, dst, Some(dst_node), &pkt, pkt.len());
                 inc!(MagicsockMetrics, sent_disco_udp);
                 disco_message_sent(msg);
+                self.node_map.disco_sent(dst_node, msg, false);
                 Ok(())
             }
             Err(err) => {
@@ -1371,6 +2212,10 @@ impl MagicSock {
     /// Triggers an address discovery. The provided why string is for debug logging only.
     #[instrument(skip_all)]
     fn re_stun(&self, why: &'static str) {
+        if self.is_paused() {
+            debug!("re_stun: {} (skipped, paused)", why);
+            return;
+        }
         debug!("re_stun: {}", why);
         inc!(MagicsockMetrics, re_stun_calls);
         self.direct_addr_update_state.schedule_run(why);
@@ -1380,12 +2225,20 @@ impl MagicSock {
     ///
     /// Called whenever our addresses or home relay node changes.
     fn publish_my_addr(&self) {
+        if self.is_paused() {
+            return;
+        }
         if let Some(ref discovery) = self.discovery {
             let info = AddrInfo {
                 relay_url: self.my_relay(),
                 direct_addresses: self.direct_addrs.sockaddrs(),
             };
-            discovery.publish(&info);
+            let user_data = self.discovery_user_data.read();
+            discovery.publish_with_user_data(&info, user_data.as_ref());
+            // Sending only fails when there are no subscribers.
+            self.events
+                .send(EndpointEvent::DiscoveryPublished(info))
+                .ok();
         }
     }
 }
@@ -1499,25 +2352,82 @@ impl Handle {
     }
 
     async fn with_name(me: String, opts: Options) -> Result<Self> {
-        let port_mapper = portmapper::Client::default();
-
         let Options {
             addr_v4,
             addr_v6,
+            ipv4,
+            ipv6,
+            bind_interface,
+            per_interface_sockets,
             secret_key,
+            previous_secret_keys,
             relay_map,
             node_map,
             discovery,
+            discovery_user_data,
             dns_resolver,
             proxy_url,
+            max_send_rate,
+            hole_punching,
+            keepalive,
+            path_selector,
+            addr_family,
+            direct_addr_filter,
+            external_addrs,
+            port_mapping,
+            ecn,
+            dscp,
+            segmentation_offload,
+            relay_queue,
+            relay_keepalive,
+            standby_relay,
+            net_report: net_report_config,
+            net_report_interval,
+            packet_tap,
+            rng_seed,
+            runtime,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
+            #[cfg(any(test, feature = "test-utils"))]
+            memory_network,
         } = opts;
 
+        let port_mapper = portmapper::Client::new(port_mapping.into());
+        let pinholer = pinhole::Client::new(port_mapping.pcp_enabled());
+        let port_mapping_status = match port_mapping.is_enabled() {
+            true => PortMappingStatus::Pending,
+            false => PortMappingStatus::Disabled,
+        };
+
         let relay_datagrams_queue = Arc::new(RelayDatagramsQueue::new());
 
-        let (pconn4, pconn6) = bind(addr_v4, addr_v6)?;
-        let port = pconn4.port();
+        let interface = match bind_interface {
+            Some(ref name) => Some(interface_addrs(name).await?),
+            None => None,
+        };
+        let socket_options = SocketOptions {
+            dscp,
+            interface: bind_interface.clone(),
+        };
+        #[cfg(any(test, feature = "test-utils"))]
+        let (pconn4, pconn6) = match memory_network {
+            Some(network) => (Some(UdpConn::memory(network.bind())), None),
+            None => bind(addr_v4, addr_v6, ipv4, ipv6, interface, &socket_options)?,
+        };
+        #[cfg(not(any(test, feature = "test-utils")))]
+        let (pconn4, pconn6) = bind(addr_v4, addr_v6, ipv4, ipv6, interface, &socket_options)?;
+        let port = pconn4.as_ref().map(|c| c.port()).unwrap_or_default();
+        let interface_sockets = if per_interface_sockets {
+            // The interface sockets are bound to the addresses of every interface.
+            let sockets = InterfaceSockets::new(SocketOptions {
+                interface: None,
+                ..socket_options.clone()
+            });
+            sockets.rebind(pconn4.is_some(), pconn6.is_some()).await;
+            Some(sockets)
+        } else {
+            None
+        };
 
         // NOTE: we can end up with a zero port if `std::net::UdpSocket::socket_addr` fails
         match port.try_into() {
@@ -1526,57 +2436,109 @@ impl Handle {
             }
             Err(_zero_port) => debug!("Skipping port mapping with zero local port"),
         }
-        let ipv4_addr = pconn4.local_addr()?;
+        let ipv4_addr = pconn4.as_ref().map(|c| c.local_addr()).transpose()?;
         let ipv6_addr = pconn6.as_ref().and_then(|c| c.local_addr().ok());
 
-        let net_reporter =
-            net_report::Client::new(Some(port_mapper.clone()), dns_resolver.clone())?;
+        let net_reporter = net_report::Client::with_config(
+            Some(port_mapper.clone()),
+            dns_resolver.clone(),
+            net_report_config,
+        )?;
+
+        let pconn4_sock = pconn4.as_ref().and_then(|p| p.as_socket());
+        let pconn6_sock = pconn6.as_ref().and_then(|p| p.as_socket());
 
-        let pconn4_sock = pconn4.as_socket();
-        let pconn6_sock = pconn6.as_ref().map(|p| p.as_socket());
+        let qad_socket = Arc::new(QadSocket::new(pconn4.clone(), pconn6.clone()));
+        let qad_tls_config = qad::tls_config();
+        #[cfg(any(test, feature = "test-utils"))]
+        let qad_tls_config = match insecure_skip_relay_cert_verify {
+            true => iroh_relay::client::make_dangerous_client_config(),
+            false => qad_tls_config,
+        };
+        let quic_client = match qad::quic_client(qad_socket.clone(), qad_tls_config, &*runtime) {
+            Ok(client) => Some(client),
+            Err(err) => {
+                warn!("failed to create QUIC address discovery client: {err:#}");
+                None
+            }
+        };
 
         let (actor_sender, actor_receiver) = mpsc::channel(256);
-        let (relay_actor_sender, relay_actor_receiver) = mpsc::channel(256);
+        let (relay_actor_sender, relay_actor_receiver) = mpsc::channel(relay_queue.depth);
         let (udp_disco_sender, mut udp_disco_receiver) = mpsc::channel(256);
 
         // load the node data
         let node_map = node_map.unwrap_or_default();
-        let node_map = NodeMap::load_from_vec(node_map);
+        let counters = Arc::new(Counters::default());
+        let node_map = NodeMap::load_from_vec(
+            node_map,
+            hole_punching,
+            keepalive,
+            path_selector,
+            addr_family,
+            counters.clone(),
+        );
 
         let inner = Arc::new(MagicSock {
             me,
             port: AtomicU16::new(port),
             secret_key,
+            previous_secret_keys,
             proxy_url,
+            bind_interface,
             local_addrs: std::sync::RwLock::new((ipv4_addr, ipv6_addr)),
             closing: AtomicBool::new(false),
             closed: AtomicBool::new(false),
+            paused: Arc::new(AtomicBool::new(false)),
             relay_datagrams_queue: relay_datagrams_queue.clone(),
-            relay_send_waker: Arc::new(parking_lot::Mutex::new(None)),
+            relay_send_wakers: Default::default(),
+            relay_backpressure: relay_queue.backpressure,
+            send_rate_limiter: max_send_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+            ecn,
+            segmentation_offload,
+            packet_tap,
+            counters,
+            rng: parking_lot::Mutex::new(match rng_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            }),
+            runtime: runtime.clone(),
             poll_recv_counter: AtomicUsize::new(0),
             actor_sender: actor_sender.clone(),
             ipv6_reported: Arc::new(AtomicBool::new(false)),
+            net_report: Default::default(),
             relay_map,
             my_relay: Default::default(),
+            relay_status: Default::default(),
+            port_mapping_status: Watchable::new(port_mapping_status),
+            relay_health: Default::default(),
+            events: EndpointEvent::sender(),
             net_reporter: net_reporter.addr(),
             pconn4,
             pconn6,
+            interface_sockets,
+            direct_addr_filter,
+            external_addrs,
+            qad_socket,
             disco_secrets: DiscoSecrets::default(),
             node_map,
             relay_actor_sender: relay_actor_sender.clone(),
             udp_disco_sender,
             discovery,
+            discovery_user_data: parking_lot::RwLock::new(discovery_user_data),
             direct_addrs: Default::default(),
             pending_call_me_maybes: Default::default(),
             direct_addr_update_state: DirectAddrUpdateState::new(),
             dns_resolver,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
+            #[cfg(any(test, feature = "test-utils"))]
+            faults: Default::default(),
         });
 
         let mut actor_tasks = JoinSet::default();
 
-        let relay_actor = RelayActor::new(inner.clone(), relay_datagrams_queue);
+        let relay_actor = RelayActor::new(inner.clone(), relay_datagrams_queue, relay_keepalive);
         let relay_actor_cancel_token = relay_actor.cancel_token();
         actor_tasks.spawn(
             async move {
@@ -1596,6 +2558,8 @@ impl Handle {
 
         let inner2 = inner.clone();
         let network_monitor = netmon::Monitor::new().await?;
+        let mut actor_rng = inner.fork_rng();
+        let periodic_re_stun_timer = new_re_stun_timer(false, net_report_interval, &mut actor_rng);
         actor_tasks.spawn(
             async move {
                 let actor = Actor {
@@ -1604,14 +2568,22 @@ impl Handle {
                     relay_actor_sender,
                     relay_actor_cancel_token,
                     msock: inner2,
-                    periodic_re_stun_timer: new_re_stun_timer(false),
+                    periodic_re_stun_timer,
+                    net_report_interval,
+                    rng: actor_rng,
                     net_info_last: None,
+                    home_relay_candidate: None,
+                    standby_relay,
+                    draining_relays: HashMap::new(),
                     port_mapper,
+                    pinholer,
                     pconn4: pconn4_sock,
                     pconn6: pconn6_sock,
                     no_v4_send: false,
                     net_reporter,
+                    quic_client,
                     network_monitor,
+                    heartbeat_interval: keepalive.heartbeat_interval,
                 };
 
                 if let Err(err) = actor.run().await {
@@ -1668,19 +2640,47 @@ impl Handle {
     }
 }
 
+/// The shared secrets used to seal DISCO messages, by remote node.
+///
+/// Nodes addressing us by one of our previous secret keys are answered using that key.
 #[derive(Debug, Default)]
-struct DiscoSecrets(parking_lot::Mutex<HashMap<PublicKey, SharedSecret>>);
+struct DiscoSecrets(parking_lot::Mutex<HashMap<PublicKey, NodeSecrets>>);
+
+/// The shared secrets with a single remote node.
+#[derive(Debug)]
+struct NodeSecrets {
+    /// The secrets derived so far, with the public key of our secret key they belong to.
+    ///
+    /// Only the secret of our current key is derived up front.  Those of the previous keys
+    /// are derived once, when a message fails to open with the active secret.
+    secrets: Vec<(PublicKey, SharedSecret)>,
+    /// The index of the secret of the key the node addresses us by.
+    active: usize,
+}
+
+impl NodeSecrets {
+    fn new(secret: &SecretKey, node_id: PublicKey) -> Self {
+        Self {
+            secrets: vec![(secret.public(), secret.shared(&node_id))],
+            active: 0,
+        }
+    }
+}
 
 impl DiscoSecrets {
+    fn remove(&self, node_id: &PublicKey) {
+        self.0.lock().remove(node_id);
+    }
+
     fn get(
         &self,
         secret: &SecretKey,
         node_id: PublicKey,
-    ) -> parking_lot::MappedMutexGuard<SharedSecret> {
+    ) -> parking_lot::MappedMutexGuard<NodeSecrets> {
         parking_lot::MutexGuard::map(self.0.lock(), |inner| {
             inner
                 .entry(node_id)
-                .or_insert_with(|| secret.shared(&node_id))
+                .or_insert_with(|| NodeSecrets::new(secret, node_id))
         })
     }
 
@@ -1691,20 +2691,54 @@ impl DiscoSecrets {
         msg: &disco::Message,
     ) -> Bytes {
         let mut seal = msg.as_bytes();
-        self.get(secret_key, node_id).seal(&mut seal);
-        disco::encode_message(&secret_key.public(), seal).into()
+        let node = self.get(secret_key, node_id);
+        let (public, secret) = &node.secrets[node.active];
+        secret.seal(&mut seal);
+        disco::encode_message(public, seal).into()
     }
 
+    /// Opens a DISCO message from `node_id`.
+    ///
+    /// Messages are tried against the secrets of at most [`MAX_PREVIOUS_SECRET_KEYS`]
+    /// `previous_keys`, which are only derived once per node.
+    ///
+    /// [`MAX_PREVIOUS_SECRET_KEYS`]: crate::endpoint::MAX_PREVIOUS_SECRET_KEYS
     pub fn unseal_and_decode(
         &self,
         secret: &SecretKey,
+        previous_keys: &[SecretKey],
         node_id: PublicKey,
-        mut sealed_box: Vec<u8>,
+        sealed_box: &[u8],
     ) -> Result<disco::Message, DiscoBoxError> {
-        self.get(secret, node_id)
-            .open(&mut sealed_box)
-            .map_err(DiscoBoxError::Open)?;
-        disco::Message::from_bytes(&sealed_box).map_err(DiscoBoxError::Parse)
+        let mut msg = sealed_box.to_vec();
+        let mut node = self.get(secret, node_id);
+        if let Err(err) = node.secrets[node.active].1.open(&mut msg) {
+            // The node might have switched to addressing us by another one of our keys.
+            if node.secrets.len() == 1 {
+                node.secrets.extend(
+                    previous_keys
+                        .iter()
+                        .take(crate::endpoint::MAX_PREVIOUS_SECRET_KEYS)
+                        .map(|key| (key.public(), key.shared(&node_id))),
+                );
+            }
+            let active = node.active;
+            let (index, opened) = node
+                .secrets
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| *index != active)
+                .find_map(|(index, (_, shared))| {
+                    let mut msg = sealed_box.to_vec();
+                    shared.open(&mut msg).ok()?;
+                    Some((index, msg))
+                })
+                .ok_or(DiscoBoxError::Open(err))?;
+            debug!(node = %node_id.fmt_short(), me = %node.secrets[index].0.fmt_short(), "node addresses us by another key");
+            node.active = index;
+            msg = opened;
+        }
+        disco::Message::from_bytes(&msg).map_err(DiscoBoxError::Parse)
     }
 }
 
@@ -1809,7 +2843,7 @@ impl AsyncUdpSocket for Handle {
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
         match &*self.msock.local_addrs.read().expect("not poisoned") {
-            (ipv4, None) => {
+            (Some(ipv4), None) => {
                 // Pretend to be IPv6, because our QuinnMappedAddrs
                 // need to be IPv6.
                 let ip: IpAddr = match ipv4.ip() {
@@ -1819,61 +2853,76 @@ impl AsyncUdpSocket for Handle {
                 Ok(SocketAddr::new(ip, ipv4.port()))
             }
             (_, Some(ipv6)) => Ok(*ipv6),
+            (None, None) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no socket bound",
+            )),
         }
     }
 
     fn max_transmit_segments(&self) -> usize {
-        if let Some(pconn6) = self.pconn6.as_ref() {
-            std::cmp::min(
-                pconn6.max_transmit_segments(),
-                self.pconn4.max_transmit_segments(),
-            )
-        } else {
-            self.pconn4.max_transmit_segments()
+        if !self.segmentation_offload {
+            return 1;
         }
+        self.udp_conns()
+            .map(|conn| conn.max_transmit_segments())
+            .min()
+            .unwrap_or(1)
     }
 
     fn max_receive_segments(&self) -> usize {
-        if let Some(pconn6) = self.pconn6.as_ref() {
-            // `max_receive_segments` controls the size of the `RecvMeta` buffer
-            // that quinn creates. Having buffers slightly bigger than necessary
-            // isn't terrible, and makes sure a single socket can read the maximum
-            // amount with a single poll. We considered adding these numbers instead,
-            // but we never get data from both sockets at the same time in `poll_recv`
-            // and it's impossible and unnecessary to be refactored that way.
-            std::cmp::max(
-                pconn6.max_receive_segments(),
-                self.pconn4.max_receive_segments(),
-            )
-        } else {
-            self.pconn4.max_receive_segments()
+        // `max_receive_segments` controls the size of the `RecvMeta` buffer
+        // that quinn creates. Having buffers slightly bigger than necessary
+        // isn't terrible, and makes sure a single socket can read the maximum
+        // amount with a single poll. We considered adding these numbers instead,
+        // but we never get data from both sockets at the same time in `poll_recv`
+        // and it's impossible and unnecessary to be refactored that way.
+        if !self.segmentation_offload {
+            return 1;
         }
+        self.udp_conns()
+            .map(|conn| conn.max_receive_segments())
+            .max()
+            .unwrap_or(1)
     }
 
     fn may_fragment(&self) -> bool {
-        if let Some(pconn6) = self.pconn6.as_ref() {
-            pconn6.may_fragment() || self.pconn4.may_fragment()
-        } else {
-            self.pconn4.may_fragment()
-        }
+        self.udp_conns().any(|conn| conn.may_fragment())
     }
 }
 
 #[derive(Debug)]
 struct IoPoller {
-    ipv4_poller: Pin<Box<dyn quinn::UdpPoller>>,
+    ipv4_poller: Option<Pin<Box<dyn quinn::UdpPoller>>>,
     ipv6_poller: Option<Pin<Box<dyn quinn::UdpPoller>>>,
     relay_sender: mpsc::Sender<RelayActorMessage>,
-    relay_send_waker: Arc<parking_lot::Mutex<Option<Waker>>>,
+    relay_send_wakers: Arc<parking_lot::Mutex<Vec<Waker>>>,
+    /// Whether writing must wait for space in the relay queue.
+    relay_backpressure: bool,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    rate_limit_sleep: Option<Pin<Box<tokio::time::Sleep>>>,
 }
 
 impl quinn::UdpPoller for IoPoller {
     fn poll_writable(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
         // This version returns Ready as soon as any of them are ready.
         let this = &mut *self;
-        match this.ipv4_poller.as_mut().poll_writable(cx) {
-            Poll::Ready(_) => return Poll::Ready(Ok(())),
-            Poll::Pending => (),
+        if let Some(ref limiter) = this.rate_limiter {
+            if limiter
+                .poll_ready(cx, &mut this.rate_limit_sleep)
+                .is_pending()
+            {
+                return Poll::Pending;
+            }
+        }
+        if this.relay_backpressure && !this.poll_relay_capacity(cx) {
+            return Poll::Pending;
+        }
+        if let Some(ref mut ipv4_poller) = this.ipv4_poller {
+            match ipv4_poller.as_mut().poll_writable(cx) {
+                Poll::Ready(_) => return Poll::Ready(Ok(())),
+                Poll::Pending => (),
+            }
         }
         if let Some(ref mut ipv6_poller) = this.ipv6_poller {
             match ipv6_poller.as_mut().poll_writable(cx) {
@@ -1881,13 +2930,28 @@ impl quinn::UdpPoller for IoPoller {
                 Poll::Pending => (),
             }
         }
-        match this.relay_sender.capacity() {
-            0 => {
-                self.relay_send_waker.lock().replace(cx.waker().clone());
-                Poll::Pending
+        if this.poll_relay_capacity(cx) {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl IoPoller {
+    /// Returns whether the relay queue has space, registering to be woken up if not.
+    fn poll_relay_capacity(&self, cx: &Context) -> bool {
+        if self.relay_sender.capacity() > 0 {
+            return true;
+        }
+        {
+            let mut wakers = self.relay_send_wakers.lock();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
             }
-            _ => Poll::Ready(Ok(())),
         }
+        // The relay actor might have made space before the waker was registered.
+        self.relay_sender.capacity() > 0
     }
 }
 
@@ -1897,6 +2961,21 @@ enum ActorMessage {
     EndpointPingExpired(usize, stun_rs::TransactionId),
     NetReport(Result<Option<Arc<net_report::Report>>>, &'static str),
     NetworkChange,
+    Rebind(sync::oneshot::Sender<()>),
+    Pause,
+    Resume,
+    SetHomeRelay(RelayUrl),
+    /// The connection to the home relay `from` was lost, the standby relay `to` is still
+    /// connected.
+    RelayFailover {
+        from: RelayUrl,
+        to: RelayUrl,
+    },
+    /// The relay server `url` is draining and closes our connection in `close_in`.
+    RelayGoAway {
+        url: RelayUrl,
+        close_in: Duration,
+    },
     #[cfg(test)]
     ForceNetworkChange(bool),
 }
@@ -1909,15 +2988,28 @@ struct Actor {
     relay_actor_cancel_token: CancellationToken,
     /// When set, is an AfterFunc timer that will call MagicSock::do_periodic_stun.
     periodic_re_stun_timer: time::Interval,
+    /// The interval of `periodic_re_stun_timer`, see [`Options::net_report_interval`].
+    net_report_interval: Option<Duration>,
+    /// Randomizes the interval of `periodic_re_stun_timer`.
+    rng: StdRng,
     /// The `NetInfo` provided in the last call to `net_info_func`. It's used to deduplicate calls to netInfoFunc.
     net_info_last: Option<NetInfo>,
+    /// The relay which is much faster than the home relay, and in how many consecutive
+    /// reports it was.
+    home_relay_candidate: Option<(RelayUrl, usize)>,
+    /// Whether to keep a connection to a standby relay, see [`Options::standby_relay`].
+    standby_relay: bool,
+    /// Relay servers which are draining, and until when to avoid them.
+    draining_relays: HashMap<RelayUrl, Instant>,
 
     // The underlying UDP sockets used to send/rcv packets.
-    pconn4: Arc<UdpSocket>,
-    pconn6: Option<Arc<UdpSocket>>,
+    pconn4: Option<OsSocket>,
+    pconn6: Option<OsSocket>,
 
     /// The NAT-PMP/PCP/UPnP prober/client, for requesting port mappings from NAT devices.
     port_mapper: portmapper::Client,
+    /// The PCP client requesting an IPv6 pinhole from the router's firewall.
+    pinholer: pinhole::Client,
 
     /// Whether IPv4 UDP is known to be unable to transmit
     /// at all. This could happen if the socket is in an invalid state
@@ -1926,8 +3018,13 @@ struct Actor {
 
     /// The prober that discovers local network conditions, including the closest relay relay and NAT mappings.
     net_reporter: net_report::Client,
+    /// Client for the QUIC address discovery probes of the net_reporter.
+    quic_client: Option<QuicClient>,
 
     network_monitor: netmon::Monitor,
+
+    /// The interval at which the paths to all nodes are kept alive.
+    heartbeat_interval: Duration,
 }
 
 impl Actor {
@@ -1947,12 +3044,13 @@ impl Actor {
 
         // Let the the heartbeat only start a couple seconds later
         let mut direct_addr_heartbeat_timer = time::interval_at(
-            time::Instant::now() + HEARTBEAT_INTERVAL,
-            HEARTBEAT_INTERVAL,
+            time::Instant::now() + self.heartbeat_interval,
+            self.heartbeat_interval,
         );
         let mut direct_addr_update_receiver =
             self.msock.direct_addr_update_state.running.subscribe();
         let mut portmap_watcher = self.port_mapper.watch_external_address();
+        let mut pinhole_watcher = self.pinholer.watch_external_address();
 
         let mut discovery_events: BoxStream<DiscoveryItem> =
             Box::pin(futures_lite::stream::empty());
@@ -1964,6 +3062,7 @@ impl Actor {
 
         let mut receiver_closed = false;
         let mut portmap_watcher_closed = false;
+        let mut pinhole_watcher_closed = false;
         let mut link_change_closed = false;
         loop {
             inc!(Metrics, actor_tick_main);
@@ -2001,9 +3100,30 @@ impl Actor {
                     inc!(Metrics, actor_tick_portmap_changed);
                     let new_external_address = *portmap_watcher.borrow();
                     debug!("external address updated: {new_external_address:?}");
+                    let status = self.msock.port_mapping_status().update(new_external_address);
+                    self.msock.port_mapping_status.update(status).ok();
                     self.msock.re_stun("portmap_updated");
                 },
+                change = pinhole_watcher.changed(), if !pinhole_watcher_closed => {
+                    if change.is_err() {
+                        trace!("tick: pinhole watcher closed");
+                        inc!(Metrics, actor_tick_other);
+
+                        pinhole_watcher_closed = true;
+                        continue;
+                    }
+
+                    trace!("tick: pinhole changed");
+                    inc!(Metrics, actor_tick_portmap_changed);
+                    let new_external_address = *pinhole_watcher.borrow();
+                    debug!("external IPv6 address updated: {new_external_address:?}");
+                    self.msock.re_stun("pinhole_updated");
+                },
                 _ = direct_addr_heartbeat_timer.tick() => {
+                    if self.msock.is_paused() {
+                        trace!("tick: direct addr heartbeat skipped, paused");
+                        continue;
+                    }
                     trace!(
                         "tick: direct addr heartbeat {} direct addrs",
                         self.msock.node_map.node_count(),
@@ -2012,7 +3132,8 @@ impl Actor {
                     // TODO: this might trigger too many packets at once, pace this
 
                     self.msock.node_map.prune_inactive();
-                    let msgs = self.msock.node_map.nodes_stayin_alive();
+                    let have_ipv6 = self.msock.ipv6_reported.load(Ordering::Relaxed);
+                let msgs = self.msock.node_map.nodes_stayin_alive(have_ipv6);
                     self.handle_ping_actions(msgs).await;
                 }
                 _ = direct_addr_update_receiver.changed() => {
@@ -2041,8 +3162,10 @@ impl Actor {
                 // forever like we do with the other branches that yield `Option`s
                 Some(discovery_item) = discovery_events.next() => {
                     trace!("tick: discovery event, address discovered: {discovery_item:?}");
+                    let now = Instant::now();
+                    let seen_at = discovery_item.age().and_then(|age| now.checked_sub(age)).unwrap_or(now);
                     let node_addr = NodeAddr {node_id: discovery_item.node_id, info: discovery_item.addr_info};
-                    if let Err(e) = self.msock.add_node_addr(node_addr.clone(), Source::Discovery { name: discovery_item.provenance.into() }) {
+                    if let Err(e) = self.msock.add_node_addr_seen_at(node_addr.clone(), Source::Discovery { name: discovery_item.provenance.into() }, seen_at) {
                         warn!(?node_addr, "unable to add discovered node address to the node map: {e:?}");
                     }
                 }
@@ -2053,10 +3176,23 @@ impl Actor {
     async fn handle_network_change(&mut self, is_major: bool) {
         debug!("link change detected: major? {}", is_major);
 
+        #[cfg(any(test, feature = "test-utils"))]
+        if let Some(delay) = self.msock.faults.rebind_delay() {
+            debug!(?delay, "delaying rebind, injected fault");
+            time::sleep(delay).await;
+        }
+
+        if let Some(ref sockets) = self.msock.interface_sockets {
+            sockets
+                .rebind(self.msock.pconn4.is_some(), self.msock.pconn6.is_some())
+                .await;
+        }
         if is_major {
-            if let Err(err) = self.pconn4.rebind() {
-                warn!("failed to rebind Udp IPv4 socket: {:?}", err);
-            };
+            if let Some(ref pconn4) = self.pconn4 {
+                if let Err(err) = pconn4.rebind() {
+                    warn!("failed to rebind Udp IPv4 socket: {:?}", err);
+                };
+            }
             if let Some(ref pconn6) = self.pconn6 {
                 if let Err(err) = pconn6.rebind() {
                     warn!("failed to rebind Udp IPv6 socket: {:?}", err);
@@ -2092,6 +3228,7 @@ impl Actor {
 
                 self.msock.node_map.notify_shutdown();
                 self.port_mapper.deactivate();
+                self.pinholer.deactivate();
                 self.relay_actor_cancel_token.cancel();
 
                 debug!("shutdown complete");
@@ -2117,6 +3254,44 @@ impl Actor {
             ActorMessage::NetworkChange => {
                 self.network_monitor.network_change().await.ok();
             }
+            ActorMessage::Rebind(done) => {
+                self.handle_network_change(true).await;
+                done.send(()).ok();
+            }
+            ActorMessage::Pause => {
+                debug!("pausing");
+                self.port_mapper.deactivate();
+                self.pinholer.deactivate();
+            }
+            ActorMessage::Resume => {
+                debug!("resuming");
+                self.reset_endpoint_states();
+                let have_ipv6 = self.msock.ipv6_reported.load(Ordering::Relaxed);
+                let msgs = self.msock.node_map.nodes_stayin_alive(have_ipv6);
+                self.handle_ping_actions(msgs).await;
+                self.msock.re_stun("resume");
+                self.msock.publish_my_addr();
+            }
+            ActorMessage::SetHomeRelay(url) => {
+                self.home_relay_candidate = None;
+                self.set_nearest_relay(Some(url));
+            }
+            ActorMessage::RelayFailover { from, to } => {
+                if self.msock.my_relay().as_ref() == Some(&from)
+                    && self.msock.standby_relay().as_ref() == Some(&to)
+                {
+                    warn!(%from, %to, "home relay connection lost, failing over to standby relay");
+                    inc!(MagicsockMetrics, relay_home_failover);
+                    self.home_relay_candidate = None;
+                    self.set_nearest_relay(Some(to));
+                    // The old home relay keeps reconnecting, until the next report picks
+                    // a better standby relay.
+                    self.msock.set_standby_relay(Some(from));
+                }
+            }
+            ActorMessage::RelayGoAway { url, close_in } => {
+                self.handle_relay_go_away(url, close_in);
+            }
             #[cfg(test)]
             ActorMessage::ForceNetworkChange(is_major) => {
                 self.handle_network_change(is_major).await;
@@ -2147,6 +3322,7 @@ impl Actor {
     /// Updates the [`DiscoveredDirectAddrs`] of this [`MagicSock`] with the current set of
     /// direct addresses from:
     ///
+    /// - The statically configured external addresses.
     /// - The portmapper.
     /// - A net_report report.
     /// - The local interfaces IP addresses.
@@ -2158,7 +3334,13 @@ impl Actor {
         // DirectAddr from each entry.
         let mut addrs: BTreeMap<SocketAddr, DirectAddrType> = BTreeMap::new();
 
-        // First add PortMapper provided addresses.
+        // First add the configured external addresses, these are known to be right.
+        for addr in &self.msock.external_addrs {
+            addrs.insert(*addr, DirectAddrType::External);
+        }
+        let has_external_v4 = self.msock.external_addrs.iter().any(|addr| addr.is_ipv4());
+
+        // Next add PortMapper provided addresses.
         let maybe_port_mapped = *portmap_watcher.borrow();
         if let Some(portmap_ext) = maybe_port_mapped.map(SocketAddr::V4) {
             addrs
@@ -2166,10 +3348,20 @@ impl Actor {
                 .or_insert(DirectAddrType::Portmapped);
             self.set_net_info_have_port_map();
         }
+        let maybe_pinholed = *self.pinholer.watch_external_address().borrow();
+        if let Some(pinhole_ext) = maybe_pinholed.map(SocketAddr::V6) {
+            addrs
+                .entry(pinhole_ext)
+                .or_insert(DirectAddrType::Portmapped);
+        }
 
-        // Next add STUN addresses from the net_report report.
+        // Next add STUN addresses from the net_report report.  Without an IPv4 socket
+        // net_report uses its own, its mapped address is of no use to us.
         if let Some(net_report_report) = net_report_report {
-            if let Some(global_v4) = net_report_report.global_v4 {
+            let global_v4 = net_report_report
+                .global_v4
+                .filter(|_| self.pconn4.is_some());
+            if let Some(global_v4) = global_v4 {
                 addrs
                     .entry(global_v4.into())
                     .or_insert(DirectAddrType::Stun);
@@ -2178,11 +3370,13 @@ impl Actor {
                 // port locally, assume they might've added a static
                 // port mapping on their router to the same explicit
                 // port that we are running with. Worst case it's an invalid candidate mapping.
+                // No need to guess if the port mapping was configured.
                 let port = self.msock.port.load(Ordering::Relaxed);
                 if net_report_report
                     .mapping_varies_by_dest_ip
                     .unwrap_or_default()
                     && port != 0
+                    && !has_external_v4
                 {
                     let mut addr = global_v4;
                     addr.set_port(port);
@@ -2198,7 +3392,7 @@ impl Actor {
             }
         }
 
-        let local_addr_v4 = self.pconn4.local_addr().ok();
+        let local_addr_v4 = self.pconn4.as_ref().and_then(|c| c.local_addr().ok());
         let local_addr_v6 = self.pconn6.as_ref().and_then(|c| c.local_addr().ok());
 
         let is_unspecified_v4 = local_addr_v4
@@ -2212,7 +3406,7 @@ impl Actor {
 
         // The following code can be slow, we do not want to block the caller since it would
         // block the actor loop.
-        tokio::spawn(
+        self.msock.runtime.spawn(Box::pin(
             async move {
                 // If a socket is bound to the unspecified address, create SocketAddrs for
                 // each local IP address by pairing it with the port the socket is bound on.
@@ -2260,6 +3454,28 @@ impl Actor {
                     }
                 }
 
+                // Add the sockets bound to each network interface.
+                if let Some(ref sockets) = msock.interface_sockets {
+                    for socket in sockets.sockets() {
+                        addrs
+                            .entry(socket.local_addr)
+                            .or_insert(DirectAddrType::Local);
+                    }
+                }
+
+                // Local addresses are annotated with the interface they belong to.
+                let ifs = interfaces::State::new().await;
+                let interface_names: BTreeMap<IpAddr, String> = ifs
+                    .interfaces
+                    .iter()
+                    .flat_map(|(name, netif)| {
+                        netif
+                            .addrs()
+                            .map(|ipnet| (ipnet.addr(), name.clone()))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+
                 // Finally create and store store all these direct addresses and send any
                 // queued call-me-maybe messages.
                 msock.store_direct_addresses(
@@ -2268,13 +3484,17 @@ impl Actor {
                         .map(|(addr, typ)| DirectAddr {
                             addr: *addr,
                             typ: *typ,
+                            interface: match typ {
+                                DirectAddrType::Local => interface_names.get(&addr.ip()).cloned(),
+                                _ => None,
+                            },
                         })
                         .collect(),
                 );
                 msock.send_queued_call_me_maybes();
             }
             .instrument(Span::current()),
-        );
+        ));
     }
 
     /// Called when a direct addr update is done, no matter if it was successful or not.
@@ -2285,7 +3505,8 @@ impl Actor {
                 self.msock.direct_addr_update_state.run(new_why);
                 return;
             }
-            self.periodic_re_stun_timer = new_re_stun_timer(true);
+            self.periodic_re_stun_timer =
+                new_re_stun_timer(true, self.net_report_interval, &mut self.rng);
         }
 
         self.msock.direct_addr_update_state.finish_run();
@@ -2333,19 +3554,21 @@ impl Actor {
         }
 
         let relay_map = self.msock.relay_map.clone();
-        let pconn4 = Some(self.pconn4.clone());
-        let pconn6 = self.pconn6.clone();
+        let pconn4 = self.pconn4.as_ref().map(|sock| sock.stun_socket());
+        let pconn6 = self.pconn6.as_ref().map(|sock| sock.stun_socket());
+        let quic_client = self.quic_client.clone();
 
         debug!("requesting net_report report");
         match self
             .net_reporter
-            .get_report_channel(relay_map, pconn4, pconn6)
+            .get_report_channel(relay_map, pconn4, pconn6, quic_client)
             .await
         {
             Ok(rx) => {
                 let msg_sender = self.msg_sender.clone();
-                tokio::task::spawn(async move {
-                    let report = time::timeout(NET_REPORT_TIMEOUT, rx).await;
+                let msock = self.msock.clone();
+                self.msock.runtime.spawn(Box::pin(async move {
+                    let report = runtime::timeout(&*msock.runtime, NET_REPORT_TIMEOUT, rx).await;
                     let report: anyhow::Result<_> = match report {
                         Ok(Ok(Ok(report))) => Ok(Some(report)),
                         Ok(Ok(Err(err))) => Err(err),
@@ -2358,7 +3581,7 @@ impl Actor {
                         .ok();
                     // The receiver of the NetReport message will call
                     // .finalize_direct_addrs_update().
-                });
+                }));
             }
             Err(err) => {
                 warn!("unable to start net_report generation: {:?}", err);
@@ -2369,6 +3592,9 @@ impl Actor {
 
     async fn handle_net_report_report(&mut self, report: Option<Arc<net_report::Report>>) {
         if let Some(ref report) = report {
+            let now = Instant::now();
+            self.draining_relays.retain(|_, until| *until > now);
+            self.msock.net_report.update(Some(report.clone())).ok();
             self.msock
                 .ipv6_reported
                 .store(report.ipv6, Ordering::Relaxed);
@@ -2397,20 +3623,47 @@ impl Actor {
             for (rid, d) in r.relay_v4_latency.iter() {
                 ni.relay_latency
                     .insert(format!("{rid}-v4"), d.as_secs_f64());
+                #[cfg(feature = "metrics")]
+                crate::metrics::record_relay_latency(rid, "ipv4", d);
             }
             for (rid, d) in r.relay_v6_latency.iter() {
                 ni.relay_latency
                     .insert(format!("{rid}-v6"), d.as_secs_f64());
+                #[cfg(feature = "metrics")]
+                crate::metrics::record_relay_latency(rid, "ipv6", d);
             }
 
             if ni.preferred_relay.is_none() {
                 // Perhaps UDP is blocked. Pick a deterministic but arbitrary one.
                 ni.preferred_relay = self.pick_relay_fallback();
             }
+            if ni
+                .preferred_relay
+                .as_ref()
+                .is_some_and(|url| self.draining_relays.contains_key(url))
+            {
+                ni.preferred_relay = self.fastest_relay(Some(report)).or(ni.preferred_relay);
+            }
+            ni.preferred_relay = self.select_home_relay(ni.preferred_relay, r);
 
             if !self.set_nearest_relay(ni.preferred_relay.clone()) {
                 ni.preferred_relay = None;
             }
+            if self.standby_relay {
+                self.update_standby_relay(r);
+            }
+
+            // A global IPv6 address may still sit behind a stateful firewall, ask the router
+            // to open a pinhole for our socket.
+            let local_port_v6 = self
+                .pconn6
+                .as_ref()
+                .and_then(|c| c.local_addr().ok())
+                .map(|addr| addr.port());
+            if let (Some(global_v6), Some(port)) = (r.global_v6, local_port_v6) {
+                self.pinholer
+                    .procure(SocketAddrV6::new(*global_v6.ip(), port, 0, 0));
+            }
 
             // TODO: set link type
             self.call_net_info_callback(ni).await;
@@ -2442,6 +3695,151 @@ impl Actor {
         true
     }
 
+    /// Decides whether the home relay moves to the `preferred` relay of the report.
+    ///
+    /// Moving the home relay makes other nodes reach us through a different relay, so it
+    /// only moves when the current home relay is unreachable, or when `preferred` was much
+    /// faster in [`HOME_RELAY_SWITCH_REPORTS`] consecutive reports.  This also keeps a home
+    /// relay chosen with [`MagicSock::set_home_relay`] unless it is clearly worse.
+    fn select_home_relay(
+        &mut self,
+        preferred: Option<RelayUrl>,
+        report: &net_report::Report,
+    ) -> Option<RelayUrl> {
+        let (Some(home), Some(preferred)) = (self.msock.my_relay(), preferred.clone()) else {
+            self.home_relay_candidate = None;
+            return preferred;
+        };
+        if home == preferred || report.relay_latency.is_empty() {
+            self.home_relay_candidate = None;
+            return Some(home);
+        }
+        if self.draining_relays.contains_key(&home) {
+            debug!(%home, "home relay draining");
+            self.home_relay_candidate = None;
+            return Some(preferred);
+        }
+        let (Some(home_latency), Some(preferred_latency)) = (
+            report.relay_latency.get(&home),
+            report.relay_latency.get(&preferred),
+        ) else {
+            debug!(%home, "home relay unreachable");
+            self.home_relay_candidate = None;
+            return Some(preferred);
+        };
+        if preferred_latency > home_latency / 3 * 2 {
+            self.home_relay_candidate = None;
+            return Some(home);
+        }
+        let count = match self.home_relay_candidate.take() {
+            Some((url, count)) if url == preferred => count + 1,
+            _ => 1,
+        };
+        if count >= HOME_RELAY_SWITCH_REPORTS {
+            debug!(
+                %home,
+                %preferred,
+                ?home_latency,
+                ?preferred_latency,
+                "home relay consistently slower"
+            );
+            return Some(preferred);
+        }
+        trace!(%preferred, count, "relay faster than home relay");
+        self.home_relay_candidate = Some((preferred, count));
+        Some(home)
+    }
+
+    /// Picks the standby relay from the report.
+    ///
+    /// This is the fastest relay apart from the home relay.  To not needlessly replace the
+    /// standby connection, the standby relay is kept for as long as it is reachable.
+    fn update_standby_relay(&mut self, report: &net_report::Report) {
+        let home = self.msock.my_relay();
+        let current = self.msock.standby_relay();
+        let reachable = |url: &RelayUrl| {
+            Some(url) != home.as_ref()
+                && !self.draining_relays.contains_key(url)
+                && report.relay_latency.get(url).is_some()
+        };
+        if current.as_ref().is_some_and(reachable) {
+            return;
+        }
+        let standby = report
+            .relay_latency
+            .iter()
+            .filter(|(url, _)| Some(*url) != home.as_ref())
+            .filter(|(url, _)| !self.draining_relays.contains_key(*url))
+            .min_by_key(|(_, latency)| *latency)
+            .map(|(url, _)| url.clone());
+        if standby == current {
+            return;
+        }
+        debug!(?standby, "standby relay changed");
+        self.msock.set_standby_relay(standby.clone());
+        if let Some(url) = standby {
+            self.send_relay_actor(RelayActorMessage::SetStandby { url });
+        }
+    }
+
+    /// Moves the home or standby relay away from a draining relay server.
+    ///
+    /// The standby relay is already connected so it is preferred as the new home relay,
+    /// otherwise the fastest relay of the last report is used.
+    fn handle_relay_go_away(&mut self, url: RelayUrl, close_in: Duration) {
+        inc!(MagicsockMetrics, relay_go_away);
+        self.draining_relays.insert(
+            url.clone(),
+            Instant::now() + close_in + DRAINING_RELAY_AVOID_TIME,
+        );
+        let report = self.msock.net_report.read().expect("not poisoned").clone();
+        if self.msock.my_relay().as_ref() == Some(&url) {
+            let home = self
+                .msock
+                .standby_relay()
+                .filter(|standby| !self.draining_relays.contains_key(standby))
+                .or_else(|| self.fastest_relay(report.as_ref()));
+            match home {
+                Some(home) => {
+                    info!(%url, %home, ?close_in, "home relay draining, moving home relay");
+                    self.home_relay_candidate = None;
+                    self.set_nearest_relay(Some(home));
+                }
+                None => warn!(%url, "home relay draining, no other relay available"),
+            }
+        } else if self.msock.standby_relay().as_ref() == Some(&url) {
+            debug!(%url, "standby relay draining");
+            self.msock.set_standby_relay(None);
+            if let Some(report) = report {
+                self.update_standby_relay(&report);
+            }
+        }
+    }
+
+    /// Returns the fastest relay which is not draining.
+    ///
+    /// Without latencies in the `report` any relay of the relay map which is not draining
+    /// is returned.
+    fn fastest_relay(&self, report: Option<&Arc<net_report::Report>>) -> Option<RelayUrl> {
+        let usable = |url: &RelayUrl| !self.draining_relays.contains_key(url);
+        report
+            .and_then(|report| {
+                report
+                    .relay_latency
+                    .iter()
+                    .filter(|(url, _)| usable(*url))
+                    .min_by_key(|(_, latency)| *latency)
+                    .map(|(url, _)| url.clone())
+            })
+            .or_else(|| {
+                self.msock
+                    .relay_map
+                    .urls()
+                    .find(|url| usable(*url))
+                    .cloned()
+            })
+    }
+
     /// Returns a deterministic relay node to connect to. This is only used if net_report
     /// couldn't find the nearest one, for instance, if UDP is blocked and thus STUN
     /// latency checks aren't working.
@@ -2464,7 +3862,7 @@ impl Actor {
         }
 
         let ids = self.msock.relay_map.urls().collect::<Vec<_>>();
-        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut rng = StdRng::seed_from_u64(0);
         ids.choose(&mut rng).map(|c| (*c).clone())
     }
 
@@ -2504,11 +3902,15 @@ impl Actor {
     }
 }
 
-fn new_re_stun_timer(initial_delay: bool) -> time::Interval {
-    // Pick a random duration between 20 and 26 seconds (just under 30s,
+fn new_re_stun_timer(
+    initial_delay: bool,
+    interval: Option<Duration>,
+    rng: &mut impl Rng,
+) -> time::Interval {
+    // Unless configured, pick a random duration between 20 and 26 seconds (just under 30s,
     // a common UDP NAT timeout on Linux,etc)
-    let mut rng = rand::thread_rng();
-    let d: Duration = rng.gen_range(Duration::from_secs(20)..=Duration::from_secs(26));
+    let d = interval
+        .unwrap_or_else(|| rng.gen_range(Duration::from_secs(20)..=Duration::from_secs(26)));
     if initial_delay {
         debug!("scheduling periodic_stun to run in {}s", d.as_secs());
         time::interval_at(time::Instant::now() + d, d)
@@ -2521,24 +3923,87 @@ fn new_re_stun_timer(initial_delay: bool) -> time::Interval {
     }
 }
 
-/// Initial connection setup.
+/// The addresses of a network interface to bind to.
+#[derive(Debug, Clone, Copy)]
+struct InterfaceAddrs {
+    v4: Option<Ipv4Addr>,
+    /// The interface's first IPv6 address which is not link-local, if any.
+    v6: Option<Ipv6Addr>,
+}
+
+/// Looks up the addresses of the network interface named `name`.
+async fn interface_addrs(name: &str) -> Result<InterfaceAddrs> {
+    let state = interfaces::State::new().await;
+    let netif = state
+        .interfaces
+        .get(name)
+        .with_context(|| format!("no network interface named {name}"))?;
+    let v4 = netif.addrs().find_map(|ipnet| match ipnet.addr() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    });
+    let v6 = netif.addrs().find_map(|ipnet| match ipnet.addr() {
+        IpAddr::V6(ip) if !is_unicast_link_local(ip) => Some(ip),
+        _ => None,
+    });
+    Ok(InterfaceAddrs { v4, v6 })
+}
+
+/// Creates the enabled IPv4 and IPv6 sockets.
+///
+/// Failing to bind IPv6 is ignored as long as there is an IPv4 socket.  If an `interface`
+/// is given, the sockets are bound to its addresses and no IPv6 socket is created if it has
+/// no IPv6 address.
 fn bind(
     addr_v4: Option<SocketAddrV4>,
     addr_v6: Option<SocketAddrV6>,
-) -> Result<(UdpConn, Option<UdpConn>)> {
-    let addr_v4 = addr_v4.unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
-    let pconn4 = UdpConn::bind(SocketAddr::V4(addr_v4)).context("bind IPv4 failed")?;
+    ipv4: bool,
+    ipv6: bool,
+    interface: Option<InterfaceAddrs>,
+    options: &SocketOptions,
+) -> Result<(Option<UdpConn>, Option<UdpConn>)> {
+    ensure!(ipv4 || ipv6, "both IPv4 and IPv6 are disabled");
+    let pconn4 = if ipv4 {
+        let mut addr_v4 = addr_v4.unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+        match interface {
+            Some(InterfaceAddrs { v4: Some(ip), .. }) => addr_v4.set_ip(ip),
+            Some(InterfaceAddrs { v4: None, .. }) => bail!("bind interface has no IPv4 address"),
+            None => (),
+        }
+        let conn = UdpConn::bind_with_options(SocketAddr::V4(addr_v4), options);
+        Some(conn.context("bind IPv4 failed")?)
+    } else {
+        None
+    };
+    if !ipv6 {
+        return Ok((pconn4, None));
+    }
 
-    let ip4_port = pconn4.local_addr()?.port();
-    let ip6_port = ip4_port.checked_add(1).unwrap_or(ip4_port - 1);
-    let addr_v6 =
+    let ip6_port = match pconn4 {
+        Some(ref pconn4) => {
+            let ip4_port = pconn4.local_addr()?.port();
+            ip4_port.checked_add(1).unwrap_or(ip4_port - 1)
+        }
+        None => 0,
+    };
+    let mut addr_v6 =
         addr_v6.unwrap_or_else(|| SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, ip6_port, 0, 0));
-    let pconn6 = match UdpConn::bind(SocketAddr::V6(addr_v6)) {
+    match interface {
+        Some(InterfaceAddrs { v6: Some(ip), .. }) => addr_v6.set_ip(ip),
+        Some(InterfaceAddrs { v6: None, .. }) => {
+            ensure!(pconn4.is_some(), "bind interface has no IPv6 address");
+            info!("bind interface has no IPv6 address, not binding IPv6");
+            return Ok((pconn4, None));
+        }
+        None => (),
+    }
+    let pconn6 = match UdpConn::bind_with_options(SocketAddr::V6(addr_v6), options) {
         Ok(conn) => Some(conn),
-        Err(err) => {
+        Err(err) if pconn4.is_some() => {
             info!("bind ignoring IPv6 bind failure: {:?}", err);
             None
         }
+        Err(err) => return Err(err.context("bind IPv6 failed")),
     };
 
     Ok((pconn4, pconn6))
@@ -2654,6 +4119,32 @@ impl Stream for DirectAddrsStream {
     }
 }
 
+/// Whether a send error means the network path is gone.
+///
+/// This is the case when the network interface the path used went down or lost its
+/// address, e.g. when WiFi is switched off.
+fn is_path_unreachable(err: &io::Error) -> bool {
+    if err.kind() == io::ErrorKind::AddrNotAvailable {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::ENETDOWN | libc::ENETUNREACH | libc::EHOSTUNREACH)
+        )
+    }
+    #[cfg(windows)]
+    {
+        // WSAENETDOWN, WSAENETUNREACH and WSAEHOSTUNREACH.
+        matches!(err.raw_os_error(), Some(10050 | 10051 | 10065))
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        false
+    }
+}
+
 /// Split a transmit containing a GSO payload into individual packets.
 ///
 /// This allocates the data.
@@ -2749,6 +4240,10 @@ pub struct DirectAddr {
     pub addr: SocketAddr,
     /// The origin of this direct address.
     pub typ: DirectAddrType,
+    /// The name of the network interface this address belongs to.
+    ///
+    /// Only known for [`DirectAddrType::Local`] addresses.
+    pub interface: Option<String>,
 }
 
 /// The type of direct address.
@@ -2761,10 +4256,11 @@ pub enum DirectAddrType {
     Unknown,
     /// A locally bound socket address.
     Local,
-    /// Public internet address discovered via STUN.
+    /// Public internet address discovered via STUN or QUIC address discovery.
     ///
-    /// When possible an iroh node will perform STUN to discover which is the address
-    /// from which it sends data on the public internet.  This can be different from locally
+    /// When possible an iroh node will perform STUN or QUIC address discovery against the
+    /// relay servers to discover which is the address from which it sends data on the
+    /// public internet.  This can be different from locally
     /// bound addresses when the node is on a local network which performs NAT or similar.
     Stun,
     /// An address assigned by the router using port mapping.
@@ -2778,6 +4274,12 @@ pub enum DirectAddrType {
     /// configure the router to forward this port to the iroh node.  This indicates a
     /// situation like this, which still uses STUN to discover the public address.
     Stun4LocalPort,
+    /// A public address configured using [`Builder::add_external_address`].
+    ///
+    /// Usually the address of a port forwarding manually configured on the router.
+    ///
+    /// [`Builder::add_external_address`]: crate::endpoint::Builder::add_external_address
+    External,
 }
 
 impl Display for DirectAddrType {
@@ -2788,10 +4290,30 @@ impl Display for DirectAddrType {
             DirectAddrType::Stun => write!(f, "stun"),
             DirectAddrType::Portmapped => write!(f, "portmap"),
             DirectAddrType::Stun4LocalPort => write!(f, "stun4localport"),
+            DirectAddrType::External => write!(f, "external"),
         }
     }
 }
 
+/// Filters or rewrites the direct addresses advertised to other nodes.
+///
+/// Whenever the magic socket discovers a new set of [`DirectAddr`]s it is passed through the
+/// filter.  Only the returned addresses are sent to remote nodes in call-me-maybe messages,
+/// published to discovery and returned by [`Endpoint::direct_addresses`] and
+/// [`Endpoint::node_addr`].  This can be used to e.g. not advertise private addresses on a
+/// public server, hide the public address for privacy or add the address of a static port
+/// forwarding.
+///
+/// The filter is set using [`Builder::direct_addr_filter`].
+///
+/// [`Endpoint::direct_addresses`]: crate::Endpoint::direct_addresses
+/// [`Endpoint::node_addr`]: crate::Endpoint::node_addr
+/// [`Builder::direct_addr_filter`]: crate::endpoint::Builder::direct_addr_filter
+pub trait DirectAddrFilter: std::fmt::Debug + Send + Sync + 'static {
+    /// Returns the direct addresses to advertise.
+    fn filter(&self, addrs: BTreeSet<DirectAddr>) -> BTreeSet<DirectAddr>;
+}
+
 /// Contains information about the host's network state.
 #[derive(Debug, Clone, PartialEq)]
 struct NetInfo {
@@ -3670,6 +5192,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_disco_secrets_previous_keys() {
+        let current = SecretKey::generate();
+        let previous = SecretKey::generate();
+        let remote = SecretKey::generate();
+        let secrets = DiscoSecrets::default();
+        let msg = disco::Message::Ping(disco::Ping {
+            tx_id: stun_rs::TransactionId::default(),
+            node_key: remote.public(),
+        });
+
+        // The remote node addresses us by our previous key.
+        let mut sealed = msg.as_bytes();
+        remote.shared(&previous.public()).seal(&mut sealed);
+        let opened = secrets
+            .unseal_and_decode(&current, &[previous.clone()], remote.public(), &sealed)
+            .unwrap();
+        assert_eq!(opened, msg);
+        // Replies use the previous key as well.
+        let reply = secrets.encode_and_seal(&current, remote.public(), &msg);
+        let (sender, _) = disco::source_and_box(&reply).unwrap();
+        assert_eq!(sender, previous.public());
+
+        // Garbage does not derive the secrets again.
+        assert!(secrets
+            .unseal_and_decode(&current, &[previous.clone()], remote.public(), &[0u8; 64])
+            .is_err());
+        assert_eq!(secrets.get(&current, remote.public()).secrets.len(), 2);
+    }
+
     #[test]
     fn test_split_packets() {
         fn mk_transmit(contents: &[u8], segment_size: Option<usize>) -> quinn_udp::Transmit<'_> {
@@ -3710,6 +5262,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_is_path_unreachable() {
+        assert!(is_path_unreachable(&io::Error::from(
+            io::ErrorKind::AddrNotAvailable
+        )));
+        assert!(!is_path_unreachable(&io::Error::from(
+            io::ErrorKind::WouldBlock
+        )));
+        #[cfg(unix)]
+        assert!(is_path_unreachable(&io::Error::from_raw_os_error(
+            libc::ENETUNREACH
+        )));
+    }
+
     #[tokio::test]
     async fn test_local_endpoints() {
         let _guard = iroh_test::logging::setup();
@@ -3726,6 +5292,71 @@ mod tests {
         assert_eq!(eps0, eps1);
     }
 
+    #[tokio::test]
+    async fn test_segmentation_offload_disabled() {
+        let _guard = iroh_test::logging::setup();
+        let ms = Handle::new(Options {
+            segmentation_offload: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(ms.max_transmit_segments(), 1);
+        assert_eq!(ms.max_receive_segments(), 1);
+        ms.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_direct_addr_filter() {
+        #[derive(Debug)]
+        struct PublicOnly;
+
+        impl DirectAddrFilter for PublicOnly {
+            fn filter(&self, addrs: BTreeSet<DirectAddr>) -> BTreeSet<DirectAddr> {
+                let mut addrs: BTreeSet<_> = addrs
+                    .into_iter()
+                    .filter(|addr| match addr.addr.ip() {
+                        IpAddr::V4(ip) => !ip.is_private() && !ip.is_loopback(),
+                        IpAddr::V6(ip) => !ip.is_loopback(),
+                    })
+                    .collect();
+                addrs.insert(DirectAddr {
+                    addr: "203.0.113.1:4433".parse().unwrap(),
+                    typ: DirectAddrType::Portmapped,
+                    interface: None,
+                });
+                addrs
+            }
+        }
+
+        let msock = MagicSock::spawn(Options {
+            direct_addr_filter: Some(Arc::new(PublicOnly)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let local = DirectAddr {
+            addr: "192.168.1.2:1234".parse().unwrap(),
+            typ: DirectAddrType::Local,
+            interface: Some("eth0".to_string()),
+        };
+        let stun = DirectAddr {
+            addr: "198.51.100.7:1234".parse().unwrap(),
+            typ: DirectAddrType::Stun,
+            interface: None,
+        };
+        msock.store_direct_addresses(BTreeSet::from([local, stun]));
+
+        assert_eq!(
+            msock.direct_addrs.sockaddrs(),
+            BTreeSet::from([
+                "198.51.100.7:1234".parse().unwrap(),
+                "203.0.113.1:4433".parse().unwrap(),
+            ])
+        );
+        msock.close().await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_watch_home_relay() {
         // use an empty relay map to get full control of the changes during the test
@@ -3756,6 +5387,39 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_set_home_relay() {
+        let _guard = iroh_test::logging::setup();
+        let url_a: RelayUrl = "https://relay-a.example.invalid".parse().unwrap();
+        let url_b: RelayUrl = "https://relay-b.example.invalid".parse().unwrap();
+        let relay_map = RelayMap::from_nodes(
+            RelayMap::from_url(url_a.clone())
+                .nodes()
+                .chain(RelayMap::from_url(url_b.clone()).nodes())
+                .cloned(),
+        )
+        .unwrap();
+        let ops = Options {
+            relay_map,
+            ..Default::default()
+        };
+        let msock = MagicSock::spawn(ops).await.unwrap();
+
+        let unknown: RelayUrl = "https://relay-c.example.invalid".parse().unwrap();
+        assert!(msock.set_home_relay(unknown).await.is_err());
+
+        for url in [url_a, url_b] {
+            msock.set_home_relay(url.clone()).await.unwrap();
+            let mut relay_stream = msock.watch_home_relay();
+            time::timeout(Duration::from_secs(5), async {
+                while relay_stream.next().await != Some(url.clone()) {}
+            })
+            .await
+            .expect("home relay not switched");
+        }
+        msock.close().await.unwrap();
+    }
+
     /// Creates a new [`quinn::Endpoint`] hooked up to a [`MagicSock`].
     ///
     /// This is without involving [`crate::endpoint::Endpoint`].  The socket will accept
@@ -3767,13 +5431,39 @@ mod tests {
         let opts = Options {
             addr_v4: None,
             addr_v6: None,
+            ipv4: true,
+            ipv6: true,
+            bind_interface: None,
+            per_interface_sockets: false,
             secret_key: secret_key.clone(),
+            previous_secret_keys: Vec::new(),
             relay_map: RelayMap::empty(),
             node_map: None,
             discovery: None,
+            discovery_user_data: None,
             dns_resolver: crate::dns::default_resolver().clone(),
             proxy_url: None,
+            max_send_rate: None,
+            hole_punching: HolePunchConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+            path_selector: None,
+            addr_family: AddrFamilyPolicy::Any,
+            direct_addr_filter: None,
+            external_addrs: Vec::new(),
+            port_mapping: PortMappingConfig::default(),
+            ecn: true,
+            dscp: None,
+            segmentation_offload: true,
+            relay_queue: RelayQueueConfig::default(),
+            relay_keepalive: RelayKeepaliveConfig::default(),
+            standby_relay: false,
+            net_report: net_report::Config::default(),
+            net_report_interval: None,
+            packet_tap: None,
+            rng_seed: None,
+            runtime: Arc::new(TokioRuntime),
             insecure_skip_relay_cert_verify: true,
+            memory_network: None,
         };
         let msock = MagicSock::spawn(opts).await?;
         let server_config = crate::endpoint::make_server_config(
@@ -4090,4 +5780,39 @@ mod tests {
 
         tasks.join_all().await;
     }
+
+    #[tokio::test]
+    async fn test_io_poller_relay_backpressure() {
+        async fn poll_writable(poller: &mut IoPoller) -> bool {
+            futures_lite::future::poll_fn(|cx| {
+                Poll::Ready(Pin::new(&mut *poller).poll_writable(cx).is_ready())
+            })
+            .await
+        }
+
+        let conn = UdpConn::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let (relay_sender, mut relay_receiver) = mpsc::channel(1);
+        let url = staging::default_na_relay_node().url;
+        relay_sender
+            .try_send(RelayActorMessage::SetHome { url })
+            .unwrap();
+        let mut poller = IoPoller {
+            ipv4_poller: Some(conn.create_io_poller()),
+            ipv6_poller: None,
+            relay_sender,
+            relay_send_wakers: Default::default(),
+            relay_backpressure: false,
+            rate_limiter: None,
+            rate_limit_sleep: None,
+        };
+        // Without backpressure the full relay queue does not block sending over UDP.
+        assert!(poll_writable(&mut poller).await);
+
+        // With backpressure it does, until the relay actor made space.
+        poller.relay_backpressure = true;
+        assert!(!poll_writable(&mut poller).await);
+        assert_eq!(poller.relay_send_wakers.lock().len(), 1);
+        relay_receiver.recv().await.unwrap();
+        assert!(poll_writable(&mut poller).await);
+    }
 }
@@ -0,0 +1,68 @@
+//! Counters of a single endpoint.
+//!
+//! Unlike the [`Metrics`](super::Metrics), which are global and only recorded once
+//! registered with [`iroh_metrics::core::Core`], these are always recorded and are
+//! per-endpoint.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// A snapshot of the key counters of an endpoint, see [`Endpoint::metrics`].
+///
+/// All counters start at zero when the endpoint is bound.
+///
+/// [`Endpoint::metrics`]: crate::Endpoint::metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndpointMetrics {
+    /// The bytes sent over UDP, including DISCO messages.
+    pub udp_bytes_sent: u64,
+    /// The bytes of QUIC packets received over UDP.
+    pub udp_bytes_recv: u64,
+    /// The bytes sent via relay servers.
+    pub relay_bytes_sent: u64,
+    /// The bytes of QUIC packets received via relay servers.
+    pub relay_bytes_recv: u64,
+    /// The number of incoming connections accepted.
+    pub connections_accepted: u64,
+    /// The number of outgoing connections established.
+    pub connections_dialed: u64,
+    /// The number of times hole punching to a node was started.
+    pub hole_punch_attempts: u64,
+    /// The number of times hole punching to a node found a direct path.
+    pub hole_punch_successes: u64,
+}
+
+/// The counters of an endpoint, shared by its parts.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) udp_bytes_sent: AtomicU64,
+    pub(crate) udp_bytes_recv: AtomicU64,
+    pub(crate) relay_bytes_sent: AtomicU64,
+    pub(crate) relay_bytes_recv: AtomicU64,
+    pub(crate) connections_accepted: AtomicU64,
+    pub(crate) connections_dialed: AtomicU64,
+    pub(crate) hole_punch_attempts: AtomicU64,
+    pub(crate) hole_punch_successes: AtomicU64,
+}
+
+impl Counters {
+    /// Adds `n` to a counter.
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> EndpointMetrics {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        EndpointMetrics {
+            udp_bytes_sent: get(&self.udp_bytes_sent),
+            udp_bytes_recv: get(&self.udp_bytes_recv),
+            relay_bytes_sent: get(&self.relay_bytes_sent),
+            relay_bytes_recv: get(&self.relay_bytes_recv),
+            connections_accepted: get(&self.connections_accepted),
+            connections_dialed: get(&self.connections_dialed),
+            hole_punch_attempts: get(&self.hole_punch_attempts),
+            hole_punch_successes: get(&self.hole_punch_successes),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Faults injected into an endpoint, to test how applications handle failures.
+//!
+//! See [`Endpoint::faults`].
+//!
+//! [`Endpoint::faults`]: crate::Endpoint::faults
+
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use iroh_base::key::NodeId;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use super::relay_actor::RelayActorMessage;
+
+/// Injects faults into an endpoint while it is running.
+///
+/// This reproduces the failures iroh has to deal with in real networks, so that tests can
+/// verify how an application handles reconnecting and failing over between paths:
+///
+/// - [`Faults::kill_relay_connections`] breaks the relay connections, as if the relay
+///   server went away.
+/// - [`Faults::set_drop_pongs`] drops all DISCO pongs received, so no direct path can be
+///   validated by hole punching.
+/// - [`Faults::set_udp_blackhole`] drops all UDP datagrams exchanged with a remote node, as
+///   if a firewall started dropping them.
+/// - [`Faults::set_rebind_delay`] delays rebinding the sockets after a network change.
+///
+/// Obtained from [`Endpoint::faults`], all handles of an endpoint control the same faults.
+///
+/// May only be used in tests.
+///
+/// [`Endpoint::faults`]: crate::Endpoint::faults
+#[derive(Debug, Clone)]
+pub struct Faults {
+    state: Arc<FaultState>,
+    relay_actor_sender: mpsc::Sender<RelayActorMessage>,
+}
+
+impl Faults {
+    pub(super) fn new(
+        state: Arc<FaultState>,
+        relay_actor_sender: mpsc::Sender<RelayActorMessage>,
+    ) -> Self {
+        Self {
+            state,
+            relay_actor_sender,
+        }
+    }
+
+    /// Breaks all relay connections.
+    ///
+    /// The connections are handled as lost: the endpoint fails over to the standby relay if
+    /// one is connected, and reconnects to the relay servers.
+    pub async fn kill_relay_connections(&self) {
+        if self
+            .relay_actor_sender
+            .send(RelayActorMessage::KillConnections)
+            .await
+            .is_err()
+        {
+            warn!("unable to kill relay connections, relay actor is gone");
+        }
+    }
+
+    /// Sets whether all DISCO pongs received are dropped.
+    ///
+    /// While set, pings sent to the direct addresses of remote nodes are never confirmed,
+    /// so no new direct paths are found and known direct paths expire.
+    pub fn set_drop_pongs(&self, drop: bool) {
+        self.state.drop_pongs.store(drop, Ordering::Relaxed);
+    }
+
+    /// Sets whether all UDP datagrams sent to and received from `node_id` are dropped.
+    ///
+    /// Datagrams are dropped silently, like in a network which stopped forwarding them.
+    /// Datagrams sent via relay servers are not affected.
+    pub fn set_udp_blackhole(&self, node_id: NodeId, blackhole: bool) {
+        let mut blackholed = self.state.udp_blackholed.write();
+        if blackhole {
+            blackholed.insert(node_id);
+        } else {
+            blackholed.remove(&node_id);
+        }
+    }
+
+    /// Sets how long rebinding the sockets is delayed after a network change.
+    ///
+    /// `None` rebinds immediately, which is the default.
+    pub fn set_rebind_delay(&self, delay: Option<Duration>) {
+        *self.state.rebind_delay.lock() = delay;
+    }
+
+    /// Removes all injected faults.
+    pub fn clear(&self) {
+        self.set_drop_pongs(false);
+        self.state.udp_blackholed.write().clear();
+        self.set_rebind_delay(None);
+    }
+}
+
+/// The faults of an endpoint, shared with the [`MagicSock`](super::MagicSock).
+#[derive(Debug, Default)]
+pub(super) struct FaultState {
+    drop_pongs: AtomicBool,
+    udp_blackholed: parking_lot::RwLock<BTreeSet<NodeId>>,
+    rebind_delay: parking_lot::Mutex<Option<Duration>>,
+}
+
+impl FaultState {
+    /// Returns whether DISCO pongs received are dropped.
+    pub(super) fn drops_pongs(&self) -> bool {
+        self.drop_pongs.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether UDP datagrams exchanged with `node_id` are dropped.
+    pub(super) fn is_udp_blackholed(&self, node_id: NodeId) -> bool {
+        self.udp_blackholed.read().contains(&node_id)
+    }
+
+    /// Returns whether UDP datagrams received from `addr` are dropped.
+    ///
+    /// `node_for_addr` looks up the node using `addr`, it is only called when some node is
+    /// blackholed.
+    pub(super) fn is_udp_blackholed_addr(
+        &self,
+        addr: SocketAddr,
+        node_for_addr: impl FnOnce(SocketAddr) -> Option<NodeId>,
+    ) -> bool {
+        let blackholed = self.udp_blackholed.read();
+        if blackholed.is_empty() {
+            return false;
+        }
+        node_for_addr(addr).is_some_and(|node_id| blackholed.contains(&node_id))
+    }
+
+    /// Returns how long rebinding the sockets is delayed.
+    pub(super) fn rebind_delay(&self) -> Option<Duration> {
+        *self.rebind_delay.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_faults_udp_blackhole() {
+        let state = Arc::new(FaultState::default());
+        let (sender, _receiver) = mpsc::channel(1);
+        let faults = Faults::new(state.clone(), sender);
+        let node_id = SecretKey::generate().public();
+        let addr: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+
+        // The node is not looked up while nothing is blackholed.
+        assert!(!state.is_udp_blackholed_addr(addr, |_| panic!("looked up")));
+
+        faults.set_udp_blackhole(node_id, true);
+        assert!(state.is_udp_blackholed(node_id));
+        assert!(state.is_udp_blackholed_addr(addr, |_| Some(node_id)));
+        assert!(!state.is_udp_blackholed_addr(addr, |_| None));
+
+        faults.set_drop_pongs(true);
+        faults.set_rebind_delay(Some(Duration::from_secs(1)));
+        assert!(state.drops_pongs());
+        assert_eq!(state.rebind_delay(), Some(Duration::from_secs(1)));
+
+        faults.clear();
+        assert!(!state.is_udp_blackholed(node_id));
+        assert!(!state.drops_pongs());
+        assert_eq!(state.rebind_delay(), None);
+    }
+}
@@ -0,0 +1,195 @@
+//! UDP sockets bound to the addresses of the individual network interfaces.
+//!
+//! The main sockets of the [`MagicSock`] are usually bound to the unspecified address, the
+//! OS then chooses the interface to send each datagram on.  On hosts with several network
+//! interfaces the [`InterfaceSockets`] additionally bind one socket to each interface
+//! address.  These are advertised as direct addresses of their own, so remote nodes can
+//! hole punch to each interface separately and keep using the others when one goes down.
+//!
+//! [`MagicSock`]: super::MagicSock
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    task::{Context, Poll},
+};
+
+use netwatch::{interfaces, ip::is_unicast_link_local};
+use quinn::AsyncUdpSocket;
+use tracing::{debug, warn};
+
+use super::udp_conn::{SocketOptions, UdpConn};
+
+/// The maximum number of remote addresses for which the receiving socket is remembered.
+const MAX_REPLY_ADDRS: usize = 4096;
+
+/// A socket bound to the address of one network interface.
+#[derive(Debug, Clone)]
+pub(super) struct InterfaceSocket {
+    /// The name of the network interface.
+    pub(super) interface: String,
+    /// The address the socket is bound to.
+    pub(super) local_addr: SocketAddr,
+    conn: UdpConn,
+}
+
+/// The sockets bound to each network interface.
+#[derive(Debug, Default)]
+pub(super) struct InterfaceSockets {
+    sockets: parking_lot::RwLock<Vec<InterfaceSocket>>,
+    /// The local address of the socket which last received from a remote address.
+    ///
+    /// Datagrams to the remote address are sent from the same socket, otherwise they would
+    /// arrive from an address the remote did not send to.
+    reply_addrs: parking_lot::Mutex<HashMap<SocketAddr, SocketAddr>>,
+    /// The options applied to newly bound sockets.
+    options: SocketOptions,
+}
+
+impl InterfaceSockets {
+    pub(super) fn new(options: SocketOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Binds sockets to the addresses of the current network interfaces.
+    ///
+    /// Sockets for addresses which are still present are kept, so their ports do not
+    /// change.  Loopback and IPv6 link-local addresses are skipped.
+    pub(super) async fn rebind(&self, ipv4: bool, ipv6: bool) {
+        let state = interfaces::State::new().await;
+        let addrs = state.interfaces.iter().flat_map(|(name, netif)| {
+            netif
+                .addrs()
+                .map(|ipnet| (name.clone(), ipnet.addr()))
+                .collect::<Vec<_>>()
+        });
+        let addrs = addrs.filter(|(_, ip)| match ip {
+            IpAddr::V4(ip) => ipv4 && !ip.is_loopback(),
+            IpAddr::V6(ip) => ipv6 && !ip.is_loopback() && !is_unicast_link_local(*ip),
+        });
+        self.update(addrs);
+    }
+
+    /// Makes the sockets match the given interface addresses.
+    fn update(&self, addrs: impl IntoIterator<Item = (String, IpAddr)>) {
+        let old = std::mem::take(&mut *self.sockets.write());
+        let mut sockets = Vec::new();
+        for (interface, ip) in addrs {
+            if let Some(socket) = old
+                .iter()
+                .find(|socket| socket.interface == interface && socket.local_addr.ip() == ip)
+            {
+                sockets.push(socket.clone());
+                continue;
+            }
+            let bound = UdpConn::bind_with_options(SocketAddr::new(ip, 0), &self.options)
+                .and_then(|conn| Ok((conn.local_addr()?, conn)));
+            match bound {
+                Ok((local_addr, conn)) => {
+                    debug!(%interface, %local_addr, "bound interface socket");
+                    sockets.push(InterfaceSocket {
+                        interface,
+                        local_addr,
+                        conn,
+                    });
+                }
+                Err(err) => warn!(%interface, %ip, "failed to bind interface socket: {err:#}"),
+            }
+        }
+        self.reply_addrs
+            .lock()
+            .retain(|_, local_addr| sockets.iter().any(|s| s.local_addr == *local_addr));
+        *self.sockets.write() = sockets;
+    }
+
+    /// Returns the bound sockets.
+    pub(super) fn sockets(&self) -> Vec<InterfaceSocket> {
+        self.sockets.read().clone()
+    }
+
+    /// Returns the socket to send to `dst` from, if `dst` was last heard from on one.
+    pub(super) fn conn_for(&self, dst: SocketAddr) -> Option<UdpConn> {
+        let local_addr = *self.reply_addrs.lock().get(&dst)?;
+        self.sockets
+            .read()
+            .iter()
+            .find(|socket| socket.local_addr == local_addr)
+            .map(|socket| socket.conn.clone())
+    }
+
+    /// Polls all sockets for received datagrams.
+    ///
+    /// Returns the number of received datagrams and the local address of the socket they
+    /// were received on.
+    pub(super) fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        for socket in self.sockets.read().iter() {
+            match socket.conn.poll_recv(cx, bufs, metas)? {
+                Poll::Pending | Poll::Ready(0) => {}
+                Poll::Ready(n) => {
+                    let mut reply_addrs = self.reply_addrs.lock();
+                    if reply_addrs.len() >= MAX_REPLY_ADDRS {
+                        reply_addrs.clear();
+                    }
+                    for meta in &metas[..n] {
+                        reply_addrs.insert(meta.addr, socket.local_addr);
+                    }
+                    return Poll::Ready(Ok((n, socket.local_addr)));
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_update_keeps_sockets() {
+        let sockets = InterfaceSockets::default();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        sockets.update([("lo".to_string(), ip)]);
+        let bound = sockets.sockets();
+        assert_eq!(bound.len(), 1);
+        assert_eq!(bound[0].interface, "lo");
+        assert_eq!(bound[0].local_addr.ip(), ip);
+        let local_addr = bound[0].local_addr;
+
+        // Receive a datagram so replies to its sender use the interface socket.
+        let remote = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        remote.send_to(b"hello", local_addr).unwrap();
+        let mut buf = [0u8; 1500];
+        let mut bufs = [io::IoSliceMut::new(&mut buf)];
+        let mut metas = [quinn_udp::RecvMeta::default()];
+        let (n, recv_addr) =
+            futures_lite::future::poll_fn(|cx| sockets.poll_recv(cx, &mut bufs, &mut metas))
+                .await
+                .unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(recv_addr, local_addr);
+        assert!(sockets.conn_for(remote_addr).is_some());
+
+        // The address is still there, the socket is kept.
+        sockets.update([("lo".to_string(), ip)]);
+        assert_eq!(sockets.sockets()[0].local_addr, local_addr);
+        assert!(sockets.conn_for(remote_addr).is_some());
+
+        // The interface went away.
+        sockets.update([]);
+        assert!(sockets.sockets().is_empty());
+        assert!(sockets.conn_for(remote_addr).is_none());
+    }
+}
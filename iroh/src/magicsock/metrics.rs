@@ -16,10 +16,20 @@ pub struct Metrics {
     pub send_ipv6: Counter,
     pub send_relay: Counter,
     pub send_relay_error: Counter,
+    /// Number of transmits for relay servers dropped because the relay queue was full.
+    pub send_relay_dropped_queue_full: Counter,
+    /// Number of transmits for relay servers dropped because the relay actor was closed.
+    pub send_relay_dropped_closed: Counter,
+    /// Number of datagrams dropped because they were too large for a relay frame.
+    pub send_relay_dropped_too_large: Counter,
+    /// Number of times sending waited for space in the full relay queue.
+    pub send_relay_backpressure: Counter,
 
     // Data packets (non-disco)
     pub send_data: Counter,
     pub send_data_network_down: Counter,
+    /// Number of transmits held back because the send rate limit was reached.
+    pub send_data_rate_limited: Counter,
     pub recv_data_relay: Counter,
     pub recv_data_ipv4: Counter,
     pub recv_data_ipv6: Counter,
@@ -27,6 +37,16 @@ pub struct Metrics {
     pub recv_datagrams: Counter,
     /// Number of datagrams received using GRO
     pub recv_gro_datagrams: Counter,
+    /// Number of QUIC datagrams received in GRO batches.
+    ///
+    /// Divided by `recv_gro_datagrams` this is the average GRO batch size.
+    pub recv_gro_segments: Counter,
+    /// Number of transmits sent over UDP using GSO.
+    pub send_gso_datagrams: Counter,
+    /// Number of QUIC datagrams sent in GSO transmits.
+    ///
+    /// Divided by `send_gso_datagrams` this is the average GSO batch size.
+    pub send_gso_segments: Counter,
 
     // Disco packets
     pub send_disco_udp: Counter,
@@ -48,6 +68,21 @@ pub struct Metrics {
 
     // How many times our relay home node DI has changed from non-zero to a different non-zero.
     pub relay_home_change: Counter,
+    /// Number of times the home relay failed over to the standby relay.
+    pub relay_home_failover: Counter,
+    /// Number of go away messages received from draining relay servers.
+    pub relay_go_away: Counter,
+    /// Number of times a lost relay connection was re-established.
+    pub relay_reconnect: Counter,
+    /// Number of relay connections considered dead because a keepalive ping was not answered.
+    pub relay_pong_timeout: Counter,
+
+    /// Number of times discovery services were asked to resolve a node.
+    pub discovery_resolve: Counter,
+    /// Number of results discovery services produced while resolving nodes.
+    pub discovery_results: Counter,
+    /// Number of errors discovery services produced while resolving nodes.
+    pub discovery_errors: Counter,
 
     /*
      * Connection Metrics
@@ -77,8 +112,14 @@ pub struct Metrics {
 
     /// Number of connections with a successful handshake.
     pub connection_handshake_success: Counter,
+    /// Number of connections which were closed.
+    ///
+    /// Subtracted from `connection_handshake_success` this is the number of open connections.
+    pub connection_closed: Counter,
     /// Number of connections with a successful handshake that became direct.
     pub connection_became_direct: Counter,
+    /// Number of times sending moved away from a direct path which could not be sent on.
+    pub path_migrations: Counter,
 }
 
 impl Default for Metrics {
@@ -95,15 +136,23 @@ impl Default for Metrics {
             send_ipv6: Counter::new("send_ipv6"),
             send_relay: Counter::new("send_relay"),
             send_relay_error: Counter::new("send_relay_error"),
+            send_relay_dropped_queue_full: Counter::new("send_relay_dropped_queue_full"),
+            send_relay_dropped_closed: Counter::new("send_relay_dropped_closed"),
+            send_relay_dropped_too_large: Counter::new("send_relay_dropped_too_large"),
+            send_relay_backpressure: Counter::new("send_relay_backpressure"),
 
             // Data packets (non-disco)
             send_data: Counter::new("send_data"),
             send_data_network_down: Counter::new("send_data_network_down"),
+            send_data_rate_limited: Counter::new("send_data_rate_limited"),
             recv_data_relay: Counter::new("recv_data_relay"),
             recv_data_ipv4: Counter::new("recv_data_ipv4"),
             recv_data_ipv6: Counter::new("recv_data_ipv6"),
             recv_datagrams: Counter::new("recv_datagrams"),
             recv_gro_datagrams: Counter::new("recv_gro_packets"),
+            recv_gro_segments: Counter::new("recv_gro_segments"),
+            send_gso_datagrams: Counter::new("send_gso_packets"),
+            send_gso_segments: Counter::new("send_gso_segments"),
 
             // Disco packets
             send_disco_udp: Counter::new("disco_send_udp"),
@@ -125,6 +174,28 @@ impl Default for Metrics {
 
             // How many times our relay home node DI has changed from non-zero to a different non-zero.
             relay_home_change: Counter::new("relay_home_change"),
+            relay_home_failover: Counter::new(
+                "Number of times the home relay failed over to the standby relay.",
+            ),
+            relay_go_away: Counter::new(
+                "Number of go away messages received from draining relay servers.",
+            ),
+            relay_reconnect: Counter::new(
+                "Number of times a lost relay connection was re-established.",
+            ),
+            relay_pong_timeout: Counter::new(
+                "Number of relay connections considered dead because a keepalive ping was not answered.",
+            ),
+
+            discovery_resolve: Counter::new(
+                "Number of times discovery services were asked to resolve a node.",
+            ),
+            discovery_results: Counter::new(
+                "Number of results discovery services produced while resolving nodes.",
+            ),
+            discovery_errors: Counter::new(
+                "Number of errors discovery services produced while resolving nodes.",
+            ),
 
             num_direct_conns_added: Counter::new(
                 "number of direct connections to a peer we have added",
@@ -148,7 +219,9 @@ impl Default for Metrics {
             nodes_contacted_directly: Counter::new("nodes_contacted_directly"),
 
             connection_handshake_success: Counter::new("connection_handshake_success"),
+            connection_closed: Counter::new("Number of connections which were closed."),
             connection_became_direct: Counter::new("connection_became_direct"),
+            path_migrations: Counter::new("path_migrations"),
         }
     }
 }
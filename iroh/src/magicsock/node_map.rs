@@ -1,8 +1,9 @@
 use std::{
-    collections::{hash_map::Entry, BTreeSet, HashMap},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
     hash::Hash,
     net::{IpAddr, SocketAddr},
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
     time::Instant,
 };
@@ -21,21 +22,35 @@ use self::{
     node_state::{NodeState, Options, PingHandled},
 };
 use super::{
-    metrics::Metrics as MagicsockMetrics, ActorMessage, DiscoMessageSource, QuicMappedAddr,
+    metrics::Metrics as MagicsockMetrics, ActorMessage, Counters, DiscoMessageSource,
+    QuicMappedAddr,
 };
 use crate::{
-    disco::{CallMeMaybe, Pong, SendAddr},
+    disco::{self, CallMeMaybe, Pong, SendAddr},
     key::PublicKey,
     NodeAddr,
 };
 
 mod best_addr;
+mod connectivity;
 mod node_state;
+mod path_selector;
 mod path_state;
 mod udp_paths;
 
-pub use node_state::{ConnectionType, ControlMsg, DirectAddrInfo, RemoteInfo};
-pub(super) use node_state::{DiscoPingPurpose, PingAction, PingRole, SendPing};
+pub use connectivity::{
+    ConnectivityFailure, ConnectivityPhase, ConnectivityReason, ConnectivityState,
+    ConnectivityTransition,
+};
+pub use node_state::{
+    AddrFamilyPolicy, ConnectionType, ControlMsg, DirectAddrInfo, DiscoCounts, DiscoStats,
+    HolePunchConfig, KeepaliveConfig, MultipathPolicy, NodePathStats, PathAddr, PathPolicy,
+    PingResult, RemoteInfo, TrafficStats,
+};
+pub(super) use node_state::{
+    DiscoPingPurpose, PingAction, PingRole, SendPing, PING_TIMEOUT_DURATION,
+};
+pub use path_selector::{LatencyPathSelector, PathCandidate, PathSelector};
 
 /// Number of nodes that are inactive for which we keep info about. This limit is enforced
 /// periodically via [`NodeMap::prune_inactive`].
@@ -70,6 +85,16 @@ pub(super) struct NodeMapInner {
     by_quic_mapped_addr: HashMap<QuicMappedAddr, usize>,
     by_id: HashMap<usize, NodeState>,
     next_id: usize,
+    hole_punching: HolePunchConfig,
+    keepalive: KeepaliveConfig,
+    /// The path selector for all nodes, if not the default one.
+    path_selector: Option<Arc<dyn PathSelector>>,
+    /// The address family policy for nodes which do not have their own.
+    addr_family: AddrFamilyPolicy,
+    /// Our own public IP addresses, see [`NodeMap::set_reflexive_ips`].
+    reflexive_ips: Arc<BTreeSet<IpAddr>>,
+    /// The counters of the endpoint.
+    counters: Arc<Counters>,
 }
 
 /// Identifier to look up a [`NodeState`] in the [`NodeMap`].
@@ -129,8 +154,22 @@ pub enum Source {
 
 impl NodeMap {
     /// Create a new [`NodeMap`] from a list of [`NodeAddr`]s.
-    pub(super) fn load_from_vec(nodes: Vec<NodeAddr>) -> Self {
-        Self::from_inner(NodeMapInner::load_from_vec(nodes))
+    pub(super) fn load_from_vec(
+        nodes: Vec<NodeAddr>,
+        hole_punching: HolePunchConfig,
+        keepalive: KeepaliveConfig,
+        path_selector: Option<Arc<dyn PathSelector>>,
+        addr_family: AddrFamilyPolicy,
+        counters: Arc<Counters>,
+    ) -> Self {
+        Self::from_inner(NodeMapInner::load_from_vec(
+            nodes,
+            hole_punching,
+            keepalive,
+            path_selector,
+            addr_family,
+            counters,
+        ))
     }
 
     fn from_inner(inner: NodeMapInner) -> Self {
@@ -141,7 +180,17 @@ impl NodeMap {
 
     /// Add the contact information for a node.
     pub(super) fn add_node_addr(&self, node_addr: NodeAddr, source: Source) {
-        self.inner.lock().add_node_addr(node_addr, source)
+        self.add_node_addr_seen_at(node_addr, source, Instant::now())
+    }
+
+    /// Add the contact information for a node, which the source last saw at `seen_at`.
+    pub(super) fn add_node_addr_seen_at(
+        &self,
+        node_addr: NodeAddr,
+        source: Source,
+        seen_at: Instant,
+    ) {
+        self.inner.lock().add_node_addr(node_addr, source, seen_at)
     }
 
     /// Number of nodes currently listed.
@@ -149,12 +198,59 @@ impl NodeMap {
         self.inner.lock().node_count()
     }
 
-    pub(super) fn receive_udp(&self, udp_addr: SocketAddr) -> Option<(PublicKey, QuicMappedAddr)> {
-        self.inner.lock().receive_udp(udp_addr)
+    pub(super) fn receive_udp(
+        &self,
+        udp_addr: SocketAddr,
+        len: usize,
+        max_datagram: usize,
+    ) -> Option<(PublicKey, QuicMappedAddr)> {
+        self.inner.lock().receive_udp(udp_addr, len, max_datagram)
+    }
+
+    pub(super) fn receive_relay(
+        &self,
+        relay_url: &RelayUrl,
+        src: NodeId,
+        len: usize,
+    ) -> QuicMappedAddr {
+        self.inner.lock().receive_relay(relay_url, src, len)
+    }
+
+    /// Records `len` bytes of payload sent to a node on the given paths.
+    pub(super) fn add_bytes_sent(
+        &self,
+        node_id: NodeId,
+        udp_addr: Option<SocketAddr>,
+        relay_url: Option<&RelayUrl>,
+        len: usize,
+    ) {
+        if let Some(ep) = self.inner.lock().get_mut(NodeStateKey::NodeId(node_id)) {
+            ep.add_bytes_sent(udp_addr, relay_url, len);
+        }
+    }
+
+    /// Handles a failure to send to a node on the direct path to `addr`.
+    ///
+    /// Returns the path to send on instead, see [`NodeState::direct_path_failed`].
+    pub(super) fn direct_path_failed(&self, node_id: NodeId, addr: SocketAddr) -> Option<SendAddr> {
+        self.inner
+            .lock()
+            .get_mut(NodeStateKey::NodeId(node_id))?
+            .direct_path_failed(addr, Instant::now())
+    }
+
+    /// Records a DISCO message sent to a node.
+    pub(super) fn disco_sent(&self, node_id: NodeId, msg: &disco::Message, via_relay: bool) {
+        if let Some(ep) = self.inner.lock().get_mut(NodeStateKey::NodeId(node_id)) {
+            ep.disco_sent(msg.message_type(), via_relay);
+        }
     }
 
-    pub(super) fn receive_relay(&self, relay_url: &RelayUrl, src: NodeId) -> QuicMappedAddr {
-        self.inner.lock().receive_relay(relay_url, src)
+    /// Records a DISCO message received from a node.
+    pub(super) fn disco_received(&self, node_id: NodeId, typ: disco::MessageType, via_relay: bool) {
+        if let Some(ep) = self.inner.lock().get_mut(NodeStateKey::NodeId(node_id)) {
+            ep.disco_received(typ, via_relay);
+        }
     }
 
     pub(super) fn notify_ping_sent(
@@ -186,6 +282,75 @@ impl NodeMap {
             .map(|ep| *ep.quic_mapped_addr())
     }
 
+    /// Sets how data is spread over multiple direct paths to a node, if the node is known.
+    pub(super) fn set_multipath_policy(&self, node_id: NodeId, policy: MultipathPolicy) {
+        if let Some(ep) = self.inner.lock().get_mut(NodeStateKey::NodeId(node_id)) {
+            ep.set_multipath_policy(policy);
+        }
+    }
+
+    /// Sets which paths may be used to send to a node, if the node is known.
+    pub(super) fn set_path_policy(&self, node_id: NodeId, policy: PathPolicy) {
+        if let Some(ep) = self.inner.lock().get_mut(NodeStateKey::NodeId(node_id)) {
+            ep.set_path_policy(policy);
+        }
+    }
+
+    /// Sets which address families may be used for direct paths to a node, if the node is
+    /// known.
+    ///
+    /// Without a `policy` the default address family policy of the [`NodeMap`] is used.
+    pub(super) fn set_addr_family_policy(&self, node_id: NodeId, policy: Option<AddrFamilyPolicy>) {
+        let mut inner = self.inner.lock();
+        let policy = policy.unwrap_or(inner.addr_family);
+        if let Some(ep) = inner.get_mut(NodeStateKey::NodeId(node_id)) {
+            ep.set_addr_family_policy(policy);
+        }
+    }
+
+    /// Starts a ping measuring the latency to a node, see [`NodeState::start_probe`].
+    pub(super) fn start_probe(
+        &self,
+        node_id: NodeId,
+        have_ipv6: bool,
+    ) -> Option<(SendPing, tokio::sync::oneshot::Receiver<PingResult>)> {
+        self.inner
+            .lock()
+            .get_mut(NodeStateKey::NodeId(node_id))?
+            .start_probe(have_ipv6)
+    }
+
+    pub(super) fn cancel_probe(&self, node_id: NodeId, tx_id: TransactionId) {
+        if let Some(ep) = self.inner.lock().get_mut(NodeStateKey::NodeId(node_id)) {
+            ep.cancel_probe(tx_id);
+        }
+    }
+
+    /// Returns which paths may be used to send to a node.
+    pub(super) fn path_policy(&self, node_id: NodeId) -> PathPolicy {
+        self.inner
+            .lock()
+            .get(NodeStateKey::NodeId(node_id))
+            .map(|ep| ep.path_policy())
+            .unwrap_or_default()
+    }
+
+    pub(super) fn get_node_id_for_quic_mapped_addr(&self, addr: QuicMappedAddr) -> Option<NodeId> {
+        self.inner
+            .lock()
+            .get(NodeStateKey::QuicMappedAddr(addr))
+            .map(|ep| *ep.public_key())
+    }
+
+    /// Returns the node we believe to be at `addr`.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(super) fn get_node_id_for_ip_port(&self, addr: SocketAddr) -> Option<NodeId> {
+        self.inner
+            .lock()
+            .get(NodeStateKey::IpPort(addr.into()))
+            .map(|ep| *ep.public_key())
+    }
+
     /// Insert a received ping into the node map, and return whether a ping with this tx_id was already
     /// received.
     pub(super) fn handle_ping(
@@ -243,11 +408,11 @@ impl NodeMap {
         }
     }
 
-    pub(super) fn nodes_stayin_alive(&self) -> Vec<PingAction> {
+    pub(super) fn nodes_stayin_alive(&self, have_ipv6: bool) -> Vec<PingAction> {
         let mut inner = self.inner.lock();
         inner
             .node_states_mut()
-            .flat_map(|(_idx, node_state)| node_state.stayin_alive())
+            .flat_map(|(_idx, node_state)| node_state.stayin_alive(have_ipv6))
             .collect()
     }
 
@@ -273,11 +438,71 @@ impl NodeMap {
         self.inner.lock().conn_type_stream(node_id)
     }
 
+    /// Returns a [`ConnectionTypeWatcher`] for the node.
+    ///
+    /// Will return an error if there is not an entry in the [`NodeMap`] for
+    /// the `node_id`
+    pub(super) fn conn_type_watcher(
+        &self,
+        node_id: NodeId,
+    ) -> anyhow::Result<ConnectionTypeWatcher> {
+        self.inner.lock().conn_type_watcher(node_id)
+    }
+
     /// Get the [`RemoteInfo`]s for the node identified by [`NodeId`].
     pub(super) fn remote_info(&self, node_id: NodeId) -> Option<RemoteInfo> {
         self.inner.lock().remote_info(node_id)
     }
 
+    /// Returns the statistics of all paths to a node, if the node is known.
+    pub(super) fn path_stats(&self, node_id: NodeId) -> Option<Vec<NodePathStats>> {
+        self.inner
+            .lock()
+            .get(NodeStateKey::NodeId(node_id))
+            .map(|ep| ep.path_stats(Instant::now()))
+    }
+
+    /// Returns the statistics of the DISCO messages exchanged with a node, if it is known.
+    pub(super) fn disco_stats(&self, node_id: NodeId) -> Option<DiscoStats> {
+        self.inner
+            .lock()
+            .get(NodeStateKey::NodeId(node_id))
+            .map(|ep| ep.disco_stats(Instant::now()))
+    }
+
+    /// Returns the payload bytes exchanged with each node, leaving out nodes without any.
+    ///
+    /// With `reset` the counters start from zero again.
+    pub(super) fn traffic_stats(&self, reset: bool) -> BTreeMap<NodeId, TrafficStats> {
+        self.inner
+            .lock()
+            .node_states_mut()
+            .filter_map(|(_, ep)| {
+                let stats = if reset {
+                    ep.reset_traffic_stats()
+                } else {
+                    ep.traffic_stats()
+                };
+                (!stats.is_empty()).then(|| (*ep.public_key(), stats))
+            })
+            .collect()
+    }
+
+    /// Returns the state of establishing a direct connection to a node, if it is known.
+    pub(super) fn connectivity_state(&self, node_id: NodeId) -> Option<ConnectivityState> {
+        self.inner
+            .lock()
+            .get(NodeStateKey::NodeId(node_id))
+            .map(|ep| ep.connectivity_state(Instant::now()))
+    }
+
+    /// Removes all information about a node.
+    ///
+    /// Returns `false` if the node was not known.
+    pub(super) fn forget_node(&self, node_id: NodeId) -> bool {
+        self.inner.lock().remove_node(node_id).is_some()
+    }
+
     /// Prunes nodes without recent activity so that at most [`MAX_INACTIVE_NODES`] are kept.
     pub(super) fn prune_inactive(&self) {
         self.inner.lock().prune_inactive();
@@ -286,21 +511,47 @@ impl NodeMap {
     pub(crate) fn on_direct_addr_discovered(&self, discovered: BTreeSet<SocketAddr>) {
         self.inner.lock().on_direct_addr_discovered(discovered);
     }
+
+    /// Sets our own public IP addresses, as discovered by STUN or port mapping.
+    ///
+    /// Nodes advertising one of these addresses are behind the same NAT as we are.
+    pub(crate) fn set_reflexive_ips(&self, ips: BTreeSet<IpAddr>) {
+        let mut inner = self.inner.lock();
+        let ips = Arc::new(ips);
+        for (_, node_state) in inner.node_states_mut() {
+            node_state.set_reflexive_ips(ips.clone());
+        }
+        inner.reflexive_ips = ips;
+    }
 }
 
 impl NodeMapInner {
     /// Create a new [`NodeMap`] from a list of [`NodeAddr`]s.
-    fn load_from_vec(nodes: Vec<NodeAddr>) -> Self {
-        let mut me = Self::default();
+    fn load_from_vec(
+        nodes: Vec<NodeAddr>,
+        hole_punching: HolePunchConfig,
+        keepalive: KeepaliveConfig,
+        path_selector: Option<Arc<dyn PathSelector>>,
+        addr_family: AddrFamilyPolicy,
+        counters: Arc<Counters>,
+    ) -> Self {
+        let mut me = Self {
+            hole_punching,
+            keepalive,
+            path_selector,
+            addr_family,
+            counters,
+            ..Default::default()
+        };
         for node_addr in nodes {
-            me.add_node_addr(node_addr, Source::Saved);
+            me.add_node_addr(node_addr, Source::Saved, Instant::now());
         }
         me
     }
 
     /// Add the contact information for a node.
     #[instrument(skip_all, fields(node = %node_addr.node_id.fmt_short()))]
-    fn add_node_addr(&mut self, node_addr: NodeAddr, source: Source) {
+    fn add_node_addr(&mut self, node_addr: NodeAddr, source: Source, seen_at: Instant) {
         let NodeAddr { node_id, info } = node_addr;
 
         let source0 = source.clone();
@@ -310,7 +561,7 @@ impl NodeMapInner {
             active: false,
             source,
         });
-        node_state.update_from_node_addr(&info, source0);
+        node_state.update_from_node_addr(&info, source0, seen_at);
         let id = node_state.id();
         for addr in &info.direct_addresses {
             self.set_node_state_for_ip_port(*addr, id);
@@ -377,18 +628,23 @@ impl NodeMapInner {
     }
 
     /// Marks the node we believe to be at `ipp` as recently used.
-    fn receive_udp(&mut self, udp_addr: SocketAddr) -> Option<(NodeId, QuicMappedAddr)> {
+    fn receive_udp(
+        &mut self,
+        udp_addr: SocketAddr,
+        len: usize,
+        max_datagram: usize,
+    ) -> Option<(NodeId, QuicMappedAddr)> {
         let ip_port: IpPort = udp_addr.into();
         let Some(node_state) = self.get_mut(NodeStateKey::IpPort(ip_port)) else {
             info!(src=%udp_addr, "receive_udp: no node_state found for addr, ignore");
             return None;
         };
-        node_state.receive_udp(ip_port, Instant::now());
+        node_state.receive_udp(ip_port, len, max_datagram, Instant::now());
         Some((*node_state.public_key(), *node_state.quic_mapped_addr()))
     }
 
     #[instrument(skip_all, fields(src = %src.fmt_short()))]
-    fn receive_relay(&mut self, relay_url: &RelayUrl, src: NodeId) -> QuicMappedAddr {
+    fn receive_relay(&mut self, relay_url: &RelayUrl, src: NodeId, len: usize) -> QuicMappedAddr {
         let node_state = self.get_or_insert_with(NodeStateKey::NodeId(src), || {
             trace!("packets from unknown node, insert into node map");
             Options {
@@ -398,7 +654,7 @@ impl NodeMapInner {
                 source: Source::Relay,
             }
         });
-        node_state.receive_relay(relay_url, src, Instant::now());
+        node_state.receive_relay(relay_url, src, len, Instant::now());
         *node_state.quic_mapped_addr()
     }
 
@@ -440,6 +696,16 @@ impl NodeMapInner {
         }
     }
 
+    fn conn_type_watcher(&self, node_id: NodeId) -> anyhow::Result<ConnectionTypeWatcher> {
+        match self.get(NodeStateKey::NodeId(node_id)) {
+            Some(ep) => Ok(ConnectionTypeWatcher {
+                reported: ep.conn_type(),
+                inner: ep.conn_type_watcher(),
+            }),
+            None => anyhow::bail!("No endpoint for {node_id:?} found"),
+        }
+    }
+
     fn handle_pong(&mut self, sender: NodeId, src: &DiscoMessageSource, pong: Pong) {
         if let Some(ns) = self.get_mut(NodeStateKey::NodeId(sender)).as_mut() {
             let insert = ns.handle_pong(&pong, src.into());
@@ -510,7 +776,15 @@ impl NodeMapInner {
         );
         let id = self.next_id;
         self.next_id = self.next_id.wrapping_add(1);
-        let node_state = NodeState::new(id, options);
+        let mut node_state = NodeState::new(id, options);
+        node_state.set_hole_punching(self.hole_punching);
+        node_state.set_keepalive(self.keepalive);
+        if let Some(ref selector) = self.path_selector {
+            node_state.set_path_selector(selector.clone());
+        }
+        node_state.set_addr_family_policy(self.addr_family);
+        node_state.set_reflexive_ips(self.reflexive_ips.clone());
+        node_state.set_counters(self.counters.clone());
 
         // update indices
         self.by_quic_mapped_addr
@@ -571,22 +845,29 @@ impl NodeMapInner {
                 None => trace!(%node, last_used=%"never", "pruning inactive"),
             }
 
-            let Some(id) = self.by_node_key.remove(&public_key) else {
-                debug_assert!(false, "missing by_node_key entry for pk in by_id");
-                continue;
-            };
+            let removed = self.remove_node(public_key);
+            debug_assert!(
+                removed.is_some(),
+                "missing by_node_key entry for pk in by_id"
+            );
+        }
+    }
 
-            let Some(ep) = self.by_id.remove(&id) else {
-                debug_assert!(false, "missing by_id entry for id in by_node_key");
-                continue;
-            };
+    /// Removes a node and all the lookup entries pointing to it.
+    fn remove_node(&mut self, node_id: NodeId) -> Option<NodeState> {
+        let id = self.by_node_key.remove(&node_id)?;
 
-            for ip_port in ep.direct_addresses() {
-                self.by_ip_port.remove(&ip_port);
-            }
+        let Some(ep) = self.by_id.remove(&id) else {
+            debug_assert!(false, "missing by_id entry for id in by_node_key");
+            return None;
+        };
 
-            self.by_quic_mapped_addr.remove(ep.quic_mapped_addr());
+        for ip_port in ep.direct_addresses() {
+            self.by_ip_port.remove(&ip_port);
         }
+
+        self.by_quic_mapped_addr.remove(ep.quic_mapped_addr());
+        Some(ep)
     }
 }
 
@@ -609,6 +890,51 @@ impl Stream for ConnectionTypeStream {
     }
 }
 
+/// Watches the kind of [`ConnectionType`] used for a remote node.
+///
+/// Unlike [`ConnectionTypeStream`] this only reports transitions between
+/// [`ConnectionType::Direct`], [`ConnectionType::Relay`], [`ConnectionType::Mixed`] and
+/// [`ConnectionType::None`].  Changes of the address or relay server used, without changing
+/// the kind of connection, are not reported.
+#[derive(Debug)]
+pub struct ConnectionTypeWatcher {
+    /// The last value returned from [`ConnectionTypeWatcher::updated`], or the initial value.
+    reported: ConnectionType,
+    inner: watchable::Watcher<ConnectionType>,
+}
+
+impl ConnectionTypeWatcher {
+    /// Returns the current [`ConnectionType`].
+    pub fn get(&self) -> ConnectionType {
+        self.inner.get()
+    }
+
+    /// Waits until the kind of connection changes, and returns the new [`ConnectionType`].
+    ///
+    /// Returns `None` once the remote node is no longer tracked by the endpoint.
+    pub async fn updated(&mut self) -> Option<ConnectionType> {
+        loop {
+            let current = self.inner.get();
+            if std::mem::discriminant(&current) != std::mem::discriminant(&self.reported) {
+                self.reported = current.clone();
+                return Some(current);
+            }
+            self.inner.watch_async().await.ok()?;
+        }
+    }
+
+    /// Converts this watcher into a stream of the [`ConnectionType`] transitions.
+    ///
+    /// The stream does not yield the current [`ConnectionType`], use
+    /// [`ConnectionTypeWatcher::get`] for this.
+    pub fn into_stream(self) -> impl Stream<Item = ConnectionType> + Send + Unpin + 'static {
+        Box::pin(futures_lite::stream::unfold(self, |mut this| async move {
+            let conn_type = this.updated().await?;
+            Some((conn_type, this))
+        }))
+    }
+}
+
 /// An (Ip, Port) pair.
 ///
 /// NOTE: storing an [`IpPort`] is safer than storing a [`SocketAddr`] because for IPv6 socket
@@ -706,7 +1032,14 @@ mod tests {
                 Some(addr)
             })
             .collect();
-        let loaded_node_map = NodeMap::load_from_vec(addrs.clone());
+        let loaded_node_map = NodeMap::load_from_vec(
+            addrs.clone(),
+            Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+        );
 
         let mut loaded: Vec<NodeAddr> = loaded_node_map
             .list_remote_infos(Instant::now())
@@ -762,7 +1095,7 @@ mod tests {
             // add address
             node_map.add_test_addr(node_addr);
             // make it active
-            node_map.inner.lock().receive_udp(addr);
+            node_map.inner.lock().receive_udp(addr, 0, 0);
         }
 
         info!("Adding offline/inactive addresses");
@@ -811,7 +1144,11 @@ mod tests {
         let active_node = SecretKey::generate().public();
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 167);
         node_map.add_test_addr(NodeAddr::new(active_node).with_direct_addresses([addr]));
-        node_map.inner.lock().receive_udp(addr).expect("registered");
+        node_map
+            .inner
+            .lock()
+            .receive_udp(addr, 0, 0)
+            .expect("registered");
 
         for _ in 0..MAX_INACTIVE_NODES + 1 {
             let node = SecretKey::generate().public();
@@ -8,7 +8,11 @@ use std::{
 use tracing::{debug, info};
 
 /// How long we trust a UDP address as the exclusive path (without using relay) without having heard a Pong reply.
-const TRUST_UDP_ADDR_DURATION: Duration = Duration::from_millis(6500);
+///
+/// This is the default of [`KeepaliveConfig::path_timeout`].
+///
+/// [`KeepaliveConfig::path_timeout`]: super::KeepaliveConfig::path_timeout
+pub(super) const TRUST_UDP_ADDR_DURATION: Duration = Duration::from_millis(6500);
 
 #[derive(Debug, Default)]
 pub(super) struct BestAddr(Option<BestAddrInner>);
@@ -40,12 +44,12 @@ pub(super) enum Source {
 }
 
 impl Source {
-    fn trust_until(&self, from: Instant) -> Instant {
+    fn trust_until(&self, from: Instant, path_timeout: Duration) -> Instant {
         match self {
-            Source::ReceivedPong => from + TRUST_UDP_ADDR_DURATION,
+            Source::ReceivedPong => from + path_timeout,
             // TODO: Fix time
             Source::BestCandidate => from + Duration::from_secs(60 * 60),
-            Source::Udp => from + TRUST_UDP_ADDR_DURATION,
+            Source::Udp => from + path_timeout,
         }
     }
 }
@@ -63,6 +67,7 @@ pub enum ClearReason {
     Inactive,
     PongTimeout,
     MatchesOurLocalAddr,
+    SendFailed,
 }
 
 impl BestAddr {
@@ -117,41 +122,50 @@ impl BestAddr {
         latency: Duration,
         source: Source,
         confirmed_at: Instant,
+        path_timeout: Duration,
     ) {
         match self.0.as_mut() {
             None => {
-                self.insert(addr, latency, source, confirmed_at);
+                self.insert(addr, latency, source, confirmed_at, path_timeout);
             }
             Some(state) => {
                 let candidate = AddrLatency { addr, latency };
                 if !state.is_trusted(confirmed_at) || candidate.is_better_than(&state.addr) {
-                    self.insert(addr, latency, source, confirmed_at);
+                    self.insert(addr, latency, source, confirmed_at, path_timeout);
                 } else if state.addr.addr == addr {
                     state.confirmed_at = confirmed_at;
-                    state.trust_until = Some(source.trust_until(confirmed_at));
+                    state.trust_until = Some(source.trust_until(confirmed_at, path_timeout));
                 }
             }
         }
     }
 
     /// Reset the expiry, if the passed in addr matches the currently used one.
-    pub fn reconfirm_if_used(&mut self, addr: SocketAddr, source: Source, confirmed_at: Instant) {
+    pub fn reconfirm_if_used(
+        &mut self,
+        addr: SocketAddr,
+        source: Source,
+        confirmed_at: Instant,
+        path_timeout: Duration,
+    ) {
         if let Some(state) = self.0.as_mut() {
             if state.addr.addr == addr {
                 state.confirmed_at = confirmed_at;
-                state.trust_until = Some(source.trust_until(confirmed_at));
+                state.trust_until = Some(source.trust_until(confirmed_at, path_timeout));
             }
         }
     }
 
-    fn insert(
+    /// Unconditionally selects `addr` as the best address.
+    pub fn insert(
         &mut self,
         addr: SocketAddr,
         latency: Duration,
         source: Source,
         confirmed_at: Instant,
+        path_timeout: Duration,
     ) {
-        let trust_until = source.trust_until(confirmed_at);
+        let trust_until = source.trust_until(confirmed_at, path_timeout);
 
         if self
             .0
@@ -205,7 +219,7 @@ pub struct AddrLatency {
 
 impl AddrLatency {
     /// Reports whether `self` is a better addr to use than `other`.
-    fn is_better_than(&self, other: &Self) -> bool {
+    pub(super) fn is_better_than(&self, other: &Self) -> bool {
         if self.addr == other.addr {
             return false;
         }
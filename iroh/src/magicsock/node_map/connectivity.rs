@@ -0,0 +1,271 @@
+//! The state of establishing connectivity to a remote node.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::ConnectionType;
+
+/// The number of transitions kept per node.
+const MAX_TRANSITIONS: usize = 32;
+
+/// The phase of establishing a direct connection to a remote node.
+///
+/// A node usually starts with [`NoCandidates`] or [`CandidatesGathered`], depending on
+/// whether its direct addresses are known.  Sending DISCO pings to the direct addresses
+/// starts [`Probing`], which ends in [`Direct`] once a pong is received on a direct path,
+/// or in [`RelayFallback`] when no pong arrives in time.  Losing the direct path falls back
+/// to the relay as well, until probing starts again.
+///
+/// [`NoCandidates`]: ConnectivityPhase::NoCandidates
+/// [`CandidatesGathered`]: ConnectivityPhase::CandidatesGathered
+/// [`Probing`]: ConnectivityPhase::Probing
+/// [`Direct`]: ConnectivityPhase::Direct
+/// [`RelayFallback`]: ConnectivityPhase::RelayFallback
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, derive_more::Display)]
+pub enum ConnectivityPhase {
+    /// No direct address of the node is known, only the relay path can be used.
+    #[display("no candidates")]
+    NoCandidates,
+    /// Direct addresses of the node are known, but they were not probed yet.
+    #[display("candidates gathered")]
+    CandidatesGathered,
+    /// DISCO pings were sent to the direct addresses and are waiting for their pongs.
+    #[display("probing")]
+    Probing,
+    /// A direct path was validated by a pong and is used to send data.
+    #[display("direct")]
+    Direct,
+    /// Probing the direct addresses failed or the direct path was lost, data is sent via
+    /// the relay server.
+    #[display("relay fallback")]
+    RelayFallback,
+}
+
+/// Why the [`ConnectivityPhase`] of a node changed.
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+pub enum ConnectivityReason {
+    /// New direct addresses of the node were learned, e.g. from discovery or a ticket.
+    #[display("candidates learned")]
+    CandidatesLearned,
+    /// A DISCO ping was sent to this direct address.
+    #[display("ping sent to {_0}")]
+    PingSent(SocketAddr),
+    /// A pong was received on this direct address.
+    #[display("pong received from {_0}")]
+    PongReceived(SocketAddr),
+    /// No pong was received on this direct address in time.
+    #[display("pong timeout for {_0}")]
+    PongTimeout(SocketAddr),
+    /// Sending on this direct address failed, e.g. because its network is gone.
+    #[display("send failed on {_0}")]
+    SendFailed(SocketAddr),
+    /// The path used to send data changed to this connection type, e.g. because the direct
+    /// path was not confirmed for too long.
+    #[display("connection type changed to {_0}")]
+    ConnTypeChanged(ConnectionType),
+    /// The network of this endpoint changed, all paths have to be validated again.
+    #[display("network changed")]
+    NetworkChanged,
+    /// The direct paths were reset, e.g. after the endpoint was resumed.
+    #[display("reset")]
+    Reset,
+}
+
+/// A change of the [`ConnectivityPhase`] of a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectivityTransition {
+    /// The phase before the transition.
+    pub from: ConnectivityPhase,
+    /// The phase after the transition.
+    pub to: ConnectivityPhase,
+    /// Why the phase changed.
+    pub reason: ConnectivityReason,
+    /// How long ago the transition happened.
+    pub ago: Duration,
+}
+
+/// A direct path which failed, see [`ConnectivityState::last_failure`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectivityFailure {
+    /// What failed, either [`ConnectivityReason::PongTimeout`] or
+    /// [`ConnectivityReason::SendFailed`].
+    pub reason: ConnectivityReason,
+    /// How long ago the failure happened.
+    pub ago: Duration,
+}
+
+/// The state of establishing connectivity to a remote node.
+///
+/// This shows why the connection to a node ended up on its current path: each
+/// [`ConnectivityTransition`] records the event which caused it.  See
+/// [`Endpoint::connectivity_state`].
+///
+/// [`Endpoint::connectivity_state`]: crate::Endpoint::connectivity_state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectivityState {
+    /// The current phase.
+    pub phase: ConnectivityPhase,
+    /// How long the node has been in the current phase.
+    pub since: Duration,
+    /// The most recent transitions, oldest first.
+    ///
+    /// Only the last 32 transitions are kept.
+    pub transitions: Vec<ConnectivityTransition>,
+    /// The most recent failure of a direct path, even if it did not change the phase.
+    pub last_failure: Option<ConnectivityFailure>,
+    /// The number of hole punching rounds since the last pong on a direct path.
+    pub hole_punch_rounds: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Transition {
+    from: ConnectivityPhase,
+    to: ConnectivityPhase,
+    reason: ConnectivityReason,
+    at: Instant,
+}
+
+/// Records the [`ConnectivityPhase`] transitions of a node.
+#[derive(Debug, Clone)]
+pub(super) struct ConnectivityLog {
+    phase: ConnectivityPhase,
+    since: Instant,
+    transitions: VecDeque<Transition>,
+    last_failure: Option<(ConnectivityReason, Instant)>,
+}
+
+impl ConnectivityLog {
+    pub(super) fn new(now: Instant) -> Self {
+        Self {
+            phase: ConnectivityPhase::NoCandidates,
+            since: now,
+            transitions: VecDeque::new(),
+            last_failure: None,
+        }
+    }
+
+    pub(super) fn phase(&self) -> ConnectivityPhase {
+        self.phase
+    }
+
+    /// Moves to `phase`, recording the transition if the phase changed.
+    pub(super) fn transition(
+        &mut self,
+        phase: ConnectivityPhase,
+        reason: ConnectivityReason,
+        now: Instant,
+    ) {
+        if phase == self.phase {
+            return;
+        }
+        if self.transitions.len() == MAX_TRANSITIONS {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(Transition {
+            from: self.phase,
+            to: phase,
+            reason,
+            at: now,
+        });
+        self.phase = phase;
+        self.since = now;
+    }
+
+    /// Records a failure of a direct path.
+    pub(super) fn failure(&mut self, reason: ConnectivityReason, now: Instant) {
+        self.last_failure = Some((reason, now));
+    }
+
+    pub(super) fn state(&self, now: Instant, hole_punch_rounds: u32) -> ConnectivityState {
+        ConnectivityState {
+            phase: self.phase,
+            since: now.saturating_duration_since(self.since),
+            transitions: self
+                .transitions
+                .iter()
+                .map(|t| ConnectivityTransition {
+                    from: t.from,
+                    to: t.to,
+                    reason: t.reason.clone(),
+                    ago: now.saturating_duration_since(t.at),
+                })
+                .collect(),
+            last_failure: self
+                .last_failure
+                .as_ref()
+                .map(|(reason, at)| ConnectivityFailure {
+                    reason: reason.clone(),
+                    ago: now.saturating_duration_since(*at),
+                }),
+            hole_punch_rounds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connectivity_log() {
+        let start = Instant::now();
+        let mut log = ConnectivityLog::new(start);
+        let addr: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+
+        // Staying in the same phase is not recorded.
+        log.transition(
+            ConnectivityPhase::NoCandidates,
+            ConnectivityReason::Reset,
+            start,
+        );
+        assert!(log.state(start, 0).transitions.is_empty());
+
+        let later = start + Duration::from_secs(1);
+        log.transition(
+            ConnectivityPhase::Probing,
+            ConnectivityReason::PingSent(addr),
+            later,
+        );
+        log.failure(ConnectivityReason::PongTimeout(addr), later);
+        let state = log.state(later + Duration::from_secs(2), 1);
+        assert_eq!(state.phase, ConnectivityPhase::Probing);
+        assert_eq!(state.since, Duration::from_secs(2));
+        assert_eq!(
+            state.transitions,
+            vec![ConnectivityTransition {
+                from: ConnectivityPhase::NoCandidates,
+                to: ConnectivityPhase::Probing,
+                reason: ConnectivityReason::PingSent(addr),
+                ago: Duration::from_secs(2),
+            }]
+        );
+        assert_eq!(
+            state.last_failure,
+            Some(ConnectivityFailure {
+                reason: ConnectivityReason::PongTimeout(addr),
+                ago: Duration::from_secs(2),
+            })
+        );
+
+        // Only the most recent transitions are kept.
+        for i in 0..MAX_TRANSITIONS {
+            let (phase, reason) = match i % 2 {
+                0 => (
+                    ConnectivityPhase::Direct,
+                    ConnectivityReason::PongReceived(addr),
+                ),
+                _ => (
+                    ConnectivityPhase::Probing,
+                    ConnectivityReason::PingSent(addr),
+                ),
+            };
+            log.transition(phase, reason, later);
+        }
+        let state = log.state(later, 0);
+        assert_eq!(state.transitions.len(), MAX_TRANSITIONS);
+        assert_eq!(state.transitions[0].to, ConnectivityPhase::Direct);
+    }
+}
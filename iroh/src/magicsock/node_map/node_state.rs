@@ -2,6 +2,7 @@ use std::{
     collections::{btree_map::Entry, BTreeSet, HashMap},
     hash::Hash,
     net::{IpAddr, SocketAddr},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
@@ -9,13 +10,15 @@ use iroh_metrics::inc;
 use iroh_relay::{protos::stun, RelayUrl};
 use netwatch::ip::is_unicast_link_local;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, event, info, instrument, trace, warn, Level};
-use watchable::{Watchable, WatcherStream};
+use watchable::{Watchable, Watcher, WatcherStream};
 
 use super::{
-    best_addr::{self, ClearReason, Source as BestAddrSource},
-    path_state::{summarize_node_paths, PathState},
+    best_addr::{self, ClearReason, Source as BestAddrSource, TRUST_UDP_ADDR_DURATION},
+    connectivity::{ConnectivityLog, ConnectivityPhase, ConnectivityReason, ConnectivityState},
+    path_selector::{LatencyPathSelector, PathSelector},
+    path_state::{summarize_node_paths, PathState, DISCO_PING_INTERVAL},
     udp_paths::{NodeUdpPaths, UdpSendAddr},
     IpPort, Source,
 };
@@ -23,7 +26,9 @@ use crate::{
     disco::{self, SendAddr},
     endpoint::AddrInfo,
     key::PublicKey,
-    magicsock::{ActorMessage, MagicsockMetrics, QuicMappedAddr, Timer, HEARTBEAT_INTERVAL},
+    magicsock::{
+        ActorMessage, Counters, MagicsockMetrics, QuicMappedAddr, Timer, HEARTBEAT_INTERVAL,
+    },
     util::relay_only_mode,
     NodeAddr, NodeId,
 };
@@ -37,7 +42,7 @@ pub(super) const MAX_INACTIVE_DIRECT_ADDRESSES: usize = 20;
 const LAST_ALIVE_PRUNE_DURATION: Duration = Duration::from_secs(120);
 
 /// How long we wait for a pong reply before assuming it's never coming.
-const PING_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
+pub(super) const PING_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
 
 /// The latency at or under which we don't try to upgrade to a better path.
 const GOOD_ENOUGH_LATENCY: Duration = Duration::from_millis(5);
@@ -46,7 +51,7 @@ const GOOD_ENOUGH_LATENCY: Duration = Duration::from_millis(5);
 /// It's also the idle time at which we stop doing STUN queries to keep NAT mappings alive.
 pub(super) const SESSION_ACTIVE_TIMEOUT: Duration = Duration::from_secs(45);
 
-/// How often we try to upgrade to a better patheven if we have some non-relay route that works.
+/// How often we try to upgrade to a better path even if we have some non-relay route that works.
 const UPGRADE_INTERVAL: Duration = Duration::from_secs(60);
 
 /// How long until we send a stayin alive ping
@@ -137,6 +142,30 @@ pub(super) struct NodeState {
     ///
     /// Used for metric reporting.
     has_been_direct: bool,
+    /// Which paths may be used to send to this node.
+    path_policy: PathPolicy,
+    /// How data is spread over multiple direct paths to this node.
+    multipath_policy: MultipathPolicy,
+    /// Pings sent on behalf of [`NodeState::start_probe`], waiting for their pong.
+    probes: HashMap<stun::TransactionId, oneshot::Sender<PingResult>>,
+    /// How to attempt establishing direct paths to this node.
+    hole_punching: HolePunchConfig,
+    /// Chooses the direct path to send on.
+    path_selector: Arc<dyn PathSelector>,
+    /// The number of hole punching rounds since we last received a pong on a direct path.
+    hole_punch_rounds: u32,
+    /// Our own public IPv4 addresses, as seen from the internet.
+    ///
+    /// If the node advertises one of these it is behind the same NAT as us.
+    reflexive_ips: Arc<BTreeSet<IpAddr>>,
+    /// The counters of the endpoint, shared by all nodes.
+    counters: Arc<Counters>,
+    /// The DISCO messages exchanged with this node.
+    disco_counters: DiscoCounters,
+    /// The payload bytes exchanged with this node.
+    traffic: TrafficStats,
+    /// The phases of establishing a direct connection to this node.
+    connectivity: ConnectivityLog,
 }
 
 /// Options for creating a new [`NodeState`].
@@ -177,6 +206,17 @@ impl NodeState {
             last_call_me_maybe: None,
             conn_type: Watchable::new(ConnectionType::None),
             has_been_direct: false,
+            path_policy: PathPolicy::Any,
+            multipath_policy: MultipathPolicy::BestOnly,
+            probes: HashMap::new(),
+            hole_punching: HolePunchConfig::default(),
+            path_selector: Arc::new(LatencyPathSelector),
+            hole_punch_rounds: 0,
+            reflexive_ips: Default::default(),
+            counters: Default::default(),
+            disco_counters: Default::default(),
+            traffic: Default::default(),
+            connectivity: ConnectivityLog::new(now),
         }
     }
 
@@ -200,6 +240,10 @@ impl NodeState {
         self.conn_type.watch().into_stream()
     }
 
+    pub(super) fn conn_type_watcher(&self) -> Watcher<ConnectionType> {
+        self.conn_type.watch()
+    }
+
     /// Returns info about this node.
     pub(super) fn info(&self, now: Instant) -> RemoteInfo {
         let conn_type = self.conn_type.get();
@@ -230,10 +274,10 @@ impl NodeState {
             ConnectionType::None => None,
         };
 
-        let addrs = self
-            .udp_paths
-            .paths
-            .iter()
+        let mut paths: Vec<_> = self.udp_paths.paths.iter().collect();
+        paths.sort_by(|(_, a), (_, b)| a.dial_cmp(b));
+        let addrs = paths
+            .into_iter()
             .map(|(addr, path_state)| DirectAddrInfo {
                 addr: SocketAddr::from(*addr),
                 latency: path_state.recent_pong.as_ref().map(|pong| pong.latency),
@@ -268,6 +312,58 @@ impl NodeState {
         self.relay_url.as_ref().map(|(url, _state)| url.clone())
     }
 
+    pub(super) fn path_policy(&self) -> PathPolicy {
+        self.path_policy
+    }
+
+    /// Sets which paths may be used to send to this node.
+    ///
+    /// When switching to [`PathPolicy::RelayOnly`] the current direct path is dropped.
+    pub(super) fn set_path_policy(&mut self, policy: PathPolicy) {
+        if policy == self.path_policy {
+            return;
+        }
+        debug!(node = %self.node_id.fmt_short(), ?policy, "path policy changed");
+        self.path_policy = policy;
+        if policy == PathPolicy::RelayOnly {
+            self.udp_paths
+                .best_addr
+                .clear(ClearReason::Reset, self.relay_url.is_some());
+        }
+    }
+
+    /// Sets which address families may be used for direct paths to this node.
+    ///
+    /// The current direct path is dropped if its family is no longer allowed.
+    pub(super) fn set_addr_family_policy(&mut self, policy: AddrFamilyPolicy) {
+        if policy == self.udp_paths.addr_family {
+            return;
+        }
+        debug!(node = %self.node_id.fmt_short(), ?policy, "address family policy changed");
+        self.udp_paths.addr_family = policy;
+        if let Some(addr) = self.udp_paths.best_addr.addr() {
+            if !policy.allows(addr) {
+                self.udp_paths.best_addr.clear_if_equals(
+                    addr,
+                    ClearReason::Reset,
+                    self.relay_url.is_some(),
+                );
+            }
+        }
+    }
+
+    pub(super) fn set_multipath_policy(&mut self, policy: MultipathPolicy) {
+        if policy != self.multipath_policy {
+            debug!(node = %self.node_id.fmt_short(), ?policy, "multipath policy changed");
+            self.multipath_policy = policy;
+        }
+    }
+
+    /// Whether no direct paths may be used for this node.
+    fn relay_only(&self) -> bool {
+        relay_only_mode() || self.path_policy == PathPolicy::RelayOnly
+    }
+
     /// Returns the address(es) that should be used for sending the next packet.
     ///
     /// This may return to send on one, both or no paths.
@@ -280,7 +376,10 @@ impl NodeState {
             debug!("in `DEV_relay_ONLY` mode, giving the relay address as the only viable address for this endpoint");
             return (None, self.relay_url());
         }
-        let (best_addr, relay_url) = match self.udp_paths.send_addr(*now, have_ipv6) {
+        let send_addr = self
+            .udp_paths
+            .send_addr(*now, have_ipv6, self.multipath_policy);
+        let (best_addr, relay_url) = match send_addr {
             UdpSendAddr::Valid(addr) => {
                 // If we have a valid address we use it.
                 trace!(%addr, "UdpSendAddr is valid, use it");
@@ -302,6 +401,11 @@ impl NodeState {
                 (None, self.relay_url())
             }
         };
+        let (best_addr, relay_url) = match self.path_policy {
+            PathPolicy::Any => (best_addr, relay_url),
+            PathPolicy::RelayOnly => (None, self.relay_url()),
+            PathPolicy::DirectOnly => (best_addr, None),
+        };
         let typ = match (best_addr, relay_url.clone()) {
             (Some(best_addr), Some(relay_url)) => ConnectionType::Mixed(best_addr, relay_url),
             (Some(best_addr), None) => ConnectionType::Direct(best_addr),
@@ -321,6 +425,7 @@ impl NodeState {
                 conn_type = ?typ,
             );
             info!(%typ, "new connection type");
+            self.update_connectivity(ConnectivityReason::ConnTypeChanged(typ.clone()), *now);
 
             // Update some metrics
             match (prev_typ, typ) {
@@ -351,6 +456,15 @@ impl NodeState {
                 _ => (),
             }
         }
+
+        // The connection type reports the best path, even if the data is spread over
+        // all validated paths.
+        let best_addr = match best_addr {
+            Some(addr) if self.multipath_policy == MultipathPolicy::Aggregate => {
+                self.udp_paths.stripe_addr(*now, have_ipv6).or(Some(addr))
+            }
+            addr => addr,
+        };
         (best_addr, relay_url)
     }
 
@@ -374,10 +488,56 @@ impl NodeState {
         );
     }
 
+    pub(super) fn set_hole_punching(&mut self, config: HolePunchConfig) {
+        self.hole_punching = config;
+    }
+
+    pub(super) fn set_keepalive(&mut self, config: KeepaliveConfig) {
+        self.udp_paths.keepalive = config;
+    }
+
+    pub(super) fn set_path_selector(&mut self, selector: Arc<dyn PathSelector>) {
+        self.path_selector = selector;
+    }
+
+    /// Sets our own public IP addresses, see [`NodeState::behind_same_nat`].
+    pub(super) fn set_reflexive_ips(&mut self, ips: Arc<BTreeSet<IpAddr>>) {
+        self.reflexive_ips = ips;
+    }
+
+    /// Sets the counters of the endpoint, see [`Endpoint::metrics`].
+    ///
+    /// [`Endpoint::metrics`]: crate::Endpoint::metrics
+    pub(super) fn set_counters(&mut self, counters: Arc<Counters>) {
+        self.counters = counters;
+    }
+
+    /// Whether the node is behind the same NAT as we are.
+    ///
+    /// This is the case when one of its direct addresses has our public IP address.  Many
+    /// routers can not hairpin, i.e. forward packets sent to their own public address back
+    /// into the local network.  So the node is best reached via its local addresses.
+    fn behind_same_nat(&self) -> bool {
+        self.udp_paths.paths.keys().any(|ipp| self.is_hairpin(ipp))
+    }
+
+    /// Whether sending to `ipp` would need to hairpin through our own NAT.
+    fn is_hairpin(&self, ipp: &IpPort) -> bool {
+        self.reflexive_ips.contains(ipp.ip())
+    }
+
+    /// Whether we gave up on hole punching after [`HolePunchConfig::max_rounds`] rounds.
+    fn settled_on_relay(&self) -> bool {
+        self.hole_punching
+            .max_rounds
+            .is_some_and(|max| self.hole_punch_rounds >= max)
+    }
+
     /// Whether we need to send another call-me-maybe to the endpoint.
     ///
     /// Basically we need to send a call-me-maybe if we need to find a better path.  Maybe
-    /// we only have a relay path, or our path is expired.
+    /// we only have a relay path, or our path is expired.  Once we settled on the relay
+    /// path, we only try again every [`HolePunchConfig::upgrade_interval`], if at all.
     ///
     /// When a call-me-maybe message is sent we also need to send pings to all known paths
     /// of the endpoint.  The [`NodeState::send_call_me_maybe`] function takes care of this.
@@ -388,7 +548,20 @@ impl NodeState {
             debug!("no previous full ping: need full ping");
             return true;
         };
+        let upgrade_interval = self.hole_punching.upgrade_interval;
         match self.udp_paths.best_addr.state(*now) {
+            best_addr::State::Empty | best_addr::State::Outdated(_) if self.settled_on_relay() => {
+                if !self.hole_punching.upgrade_on_relay {
+                    trace!("settled on relay: not needed");
+                    false
+                } else if *now - last_full_ping >= upgrade_interval {
+                    debug!("settled on relay and full ping interval expired: need full ping");
+                    true
+                } else {
+                    trace!("settled on relay: not needed yet");
+                    false
+                }
+            }
             best_addr::State::Empty => {
                 debug!("best addr not set: need full ping");
                 true
@@ -398,7 +571,7 @@ impl NodeState {
                 true
             }
             best_addr::State::Valid(addr) => {
-                if addr.latency > GOOD_ENOUGH_LATENCY && *now - last_full_ping >= UPGRADE_INTERVAL {
+                if addr.latency > GOOD_ENOUGH_LATENCY && *now - last_full_ping >= upgrade_interval {
                     debug!(
                         "full ping interval expired and latency is only {}ms: need full ping",
                         addr.latency.as_millis()
@@ -415,15 +588,22 @@ impl NodeState {
     /// Cleanup the expired ping for the passed in txid.
     #[instrument("disco", skip_all, fields(node = %self.node_id.fmt_short()))]
     pub(super) fn ping_timeout(&mut self, txid: stun::TransactionId) {
+        self.probes.remove(&txid);
         if let Some(sp) = self.sent_pings.remove(&txid) {
             debug!(tx = %hex::encode(txid), addr = %sp.to, "pong not received in timeout");
             match sp.to {
                 SendAddr::Udp(addr) => {
+                    let now = Instant::now();
+                    self.connectivity
+                        .failure(ConnectivityReason::PongTimeout(addr), now);
                     if let Some(path_state) = self.udp_paths.paths.get_mut(&addr.into()) {
                         path_state.last_ping = None;
+                        path_state.add_ping_timeout();
                         let consider_alive = path_state
                             .last_alive()
-                            .map(|last_alive| last_alive.elapsed() <= PING_TIMEOUT_DURATION)
+                            .map(|last_alive| {
+                                last_alive.elapsed() <= self.hole_punching.ping_timeout
+                            })
                             .unwrap_or(false);
                         if !consider_alive {
                             // If there was no sign of life from this path during the time
@@ -446,12 +626,14 @@ impl NodeState {
                             self.relay_url.is_some(),
                         );
                     }
+                    self.update_connectivity(ConnectivityReason::PongTimeout(addr), now);
                 }
                 SendAddr::Relay(ref url) => {
                     if let Some((home_relay, relay_state)) = self.relay_url.as_mut() {
                         if home_relay == url {
                             // lost connectivity via relay
                             relay_state.last_ping = None;
+                            relay_state.add_ping_timeout();
                         }
                     }
                 }
@@ -461,11 +643,21 @@ impl NodeState {
 
     #[must_use = "pings must be handled"]
     fn start_ping(&self, dst: SendAddr, purpose: DiscoPingPurpose) -> Option<SendPing> {
-        if relay_only_mode() && !dst.is_relay() {
+        if self.relay_only() && !dst.is_relay() {
             // don't attempt any hole punching in relay only mode
-            warn!("in `DEV_relay_ONLY` mode, ignoring request to start a hole punching attempt.");
+            if relay_only_mode() {
+                warn!(
+                    "in `DEV_relay_ONLY` mode, ignoring request to start a hole punching attempt."
+                );
+            }
             return None;
         }
+        if let SendAddr::Udp(addr) = dst {
+            if !self.udp_paths.addr_family.allows(addr) {
+                trace!(%addr, "address family not allowed, not pinging");
+                return None;
+            }
+        }
         let tx_id = stun::TransactionId::default();
         trace!(tx = %hex::encode(tx_id), %dst, ?purpose,
                dst = %self.node_id.fmt_short(), "start ping");
@@ -486,6 +678,39 @@ impl NodeState {
         })
     }
 
+    /// Starts a ping to measure the latency on the path currently used to send to the node.
+    ///
+    /// This is the direct path if one is confirmed, otherwise the relay path.  The result is
+    /// sent once the pong is received, the sender is dropped if the ping times out.
+    #[must_use = "pings must be handled"]
+    pub(super) fn start_probe(
+        &mut self,
+        have_ipv6: bool,
+    ) -> Option<(SendPing, oneshot::Receiver<PingResult>)> {
+        let now = Instant::now();
+        let send_addr = self
+            .udp_paths
+            .send_addr(now, have_ipv6, self.multipath_policy);
+        let dst = match (send_addr, self.relay_url()) {
+            (_, relay_url) if self.relay_only() => SendAddr::Relay(relay_url?),
+            (UdpSendAddr::Valid(addr), _) => SendAddr::Udp(addr),
+            (_, Some(relay_url)) => SendAddr::Relay(relay_url),
+            (UdpSendAddr::Outdated(addr) | UdpSendAddr::Unconfirmed(addr), None) => {
+                SendAddr::Udp(addr)
+            }
+            (UdpSendAddr::None, None) => return None,
+        };
+        let ping = self.start_ping(dst, DiscoPingPurpose::Probe)?;
+        let (tx, rx) = oneshot::channel();
+        self.probes.insert(ping.tx_id, tx);
+        Some((ping, rx))
+    }
+
+    /// Stops waiting for the pong of a ping started by [`NodeState::start_probe`].
+    pub(super) fn cancel_probe(&mut self, tx_id: stun::TransactionId) {
+        self.probes.remove(&tx_id);
+    }
+
     /// Record the fact that a ping has been sent out.
     pub(super) fn ping_sent(
         &mut self,
@@ -521,12 +746,16 @@ impl NodeState {
         }
 
         let id = self.id;
-        let timer = Timer::after(PING_TIMEOUT_DURATION, async move {
+        let timer = Timer::after(self.hole_punching.ping_timeout, async move {
             sender
                 .send(ActorMessage::EndpointPingExpired(id, tx_id))
                 .await
                 .ok();
         });
+        let udp_addr = match to {
+            SendAddr::Udp(addr) => Some(addr),
+            SendAddr::Relay(_) => None,
+        };
         self.sent_pings.insert(
             tx_id,
             SentPing {
@@ -536,6 +765,9 @@ impl NodeState {
                 timer,
             },
         );
+        if let Some(addr) = udp_addr {
+            self.update_connectivity(ConnectivityReason::PingSent(addr), now);
+        }
     }
 
     /// Send a DISCO call-me-maybe message to the peer.
@@ -556,7 +788,7 @@ impl NodeState {
             SendCallMeMaybe::IfNoRecent => {
                 let had_recent_call_me_maybe = self
                     .last_call_me_maybe
-                    .map(|when| when.elapsed() < HEARTBEAT_INTERVAL)
+                    .map(|when| when.elapsed() < self.udp_paths.keepalive.heartbeat_interval)
                     .unwrap_or(false);
                 if had_recent_call_me_maybe {
                     trace!("skipping call-me-maybe, still recent");
@@ -570,8 +802,17 @@ impl NodeState {
         // ping to the direct address paths so that the other node will learn about us and
         // accepts the connection.
         let mut msgs = self.send_pings(now);
+        if !self.relay_only() {
+            if self.hole_punch_rounds == 0 {
+                Counters::add(&self.counters.hole_punch_attempts, 1);
+            }
+            self.hole_punch_rounds = self.hole_punch_rounds.saturating_add(1);
+        }
 
-        if let Some(url) = self.relay_url() {
+        if self.path_policy == PathPolicy::RelayOnly {
+            // A call-me-maybe would tell the node our direct addresses.
+            trace!("not sending call-me-maybe, relay only path policy");
+        } else if let Some(url) = self.relay_url() {
             debug!(%url, "queue call-me-maybe");
             msgs.push(PingAction::SendCallMeMaybe {
                 relay_url: url,
@@ -595,9 +836,10 @@ impl NodeState {
     fn send_pings(&mut self, now: Instant) -> Vec<PingAction> {
         // We allocate +1 in case the caller wants to add a call-me-maybe message.
         let mut ping_msgs = Vec::with_capacity(self.udp_paths.paths.len() + 1);
+        let ping_interval = self.hole_punching.ping_interval;
 
         if let Some((url, state)) = self.relay_url.as_ref() {
-            if state.needs_ping(&now) {
+            if state.needs_ping(&now, ping_interval) {
                 debug!(%url, "relay path needs ping");
                 if let Some(msg) =
                     self.start_ping(SendAddr::Relay(url.clone()), DiscoPingPurpose::Discovery)
@@ -606,18 +848,30 @@ impl NodeState {
                 }
             }
         }
-        if relay_only_mode() {
-            warn!(
-                "in `DEV_relay_ONLY` mode, ignoring request to respond to a hole punching attempt."
-            );
+        if self.relay_only() {
+            if relay_only_mode() {
+                warn!(
+                    "in `DEV_relay_ONLY` mode, ignoring request to respond to a hole punching attempt."
+                );
+            }
             return ping_msgs;
         }
         self.prune_direct_addresses();
+        // Behind the same NAT the local addresses are tried first.  Only when they did not
+        // work in the first round we fall back to the addresses which need hairpinning.
+        let skip_hairpin = self.hole_punch_rounds == 0 && self.behind_same_nat();
+        if skip_hairpin {
+            debug!(
+                node = %self.node_id.fmt_short(),
+                "behind the same NAT, pinging local addresses first",
+            );
+        }
         let mut ping_dsts = String::from("[");
         self.udp_paths
             .paths
             .iter()
-            .filter_map(|(ipp, state)| state.needs_ping(&now).then_some(*ipp))
+            .filter(|(ipp, _state)| !(skip_hairpin && self.is_hairpin(ipp)))
+            .filter_map(|(ipp, state)| state.needs_ping(&now, ping_interval).then_some(*ipp))
             .filter_map(|ipp| {
                 self.start_ping(SendAddr::Udp(ipp.into()), DiscoPingPurpose::Discovery)
             })
@@ -637,7 +891,18 @@ impl NodeState {
         ping_msgs
     }
 
-    pub(super) fn update_from_node_addr(&mut self, n: &AddrInfo, source: super::Source) {
+    /// Merges the addressing information reported by `source` into the paths of the node.
+    ///
+    /// The `seen_at` instant is when the source last saw the information, e.g. the time a
+    /// discovery record was published.  It ranks the addresses by freshness, and the relay
+    /// URL is only replaced if the information is not older than what we know about the
+    /// current relay URL.
+    pub(super) fn update_from_node_addr(
+        &mut self,
+        n: &AddrInfo,
+        source: super::Source,
+        seen_at: Instant,
+    ) {
         if self.udp_paths.best_addr.is_empty() {
             // we do not have a direct connection, so changing the relay information may
             // have an effect on our connection status
@@ -650,19 +915,37 @@ impl NodeState {
             }
         }
 
-        let now = Instant::now();
-
-        if n.relay_url.is_some() && n.relay_url != self.relay_url() {
-            debug!(
-                "Changing relay node from {:?} to {:?}",
-                self.relay_url, n.relay_url
-            );
-            self.relay_url = n.relay_url.as_ref().map(|url| {
-                (
-                    url.clone(),
-                    PathState::new(self.node_id, url.clone().into(), source.clone(), now),
-                )
-            });
+        if let Some(new_url) = &n.relay_url {
+            match &mut self.relay_url {
+                Some((url, state)) if url == new_url => {
+                    state.add_source(source.clone(), seen_at);
+                }
+                Some((url, state))
+                    if state
+                        .last_reported()
+                        .is_some_and(|reported| reported > seen_at) =>
+                {
+                    debug!(
+                        "Not changing relay node from {:?} to {:?}, the information is older",
+                        url, new_url
+                    );
+                }
+                _ => {
+                    debug!(
+                        "Changing relay node from {:?} to {:?}",
+                        self.relay_url, n.relay_url
+                    );
+                    self.relay_url = Some((
+                        new_url.clone(),
+                        PathState::new(
+                            self.node_id,
+                            new_url.clone().into(),
+                            source.clone(),
+                            seen_at,
+                        ),
+                    ));
+                }
+            }
         }
 
         for &addr in n.direct_addresses.iter() {
@@ -670,14 +953,15 @@ impl NodeState {
                 .paths
                 .entry(addr.into())
                 .and_modify(|path_state| {
-                    path_state.add_source(source.clone(), now);
+                    path_state.add_source(source.clone(), seen_at);
                 })
                 .or_insert_with(|| {
-                    PathState::new(self.node_id, SendAddr::from(addr), source.clone(), now)
+                    PathState::new(self.node_id, SendAddr::from(addr), source.clone(), seen_at)
                 });
         }
         let paths = summarize_node_paths(&self.udp_paths.paths);
         debug!(new = ?n.direct_addresses , %paths, "added new direct paths for endpoint");
+        self.update_connectivity(ConnectivityReason::CandidatesLearned, Instant::now());
     }
 
     /// Clears all the endpoint's p2p state, reverting it to a relay-only endpoint.
@@ -691,6 +975,7 @@ impl NodeState {
         for es in self.udp_paths.paths.values_mut() {
             es.last_ping = None;
         }
+        self.update_connectivity(ConnectivityReason::Reset, Instant::now());
     }
 
     /// Handle a received Disco Ping.
@@ -708,10 +993,15 @@ impl NodeState {
         tx_id: stun::TransactionId,
     ) -> PingHandled {
         let now = Instant::now();
+        let heartbeat_interval = self.udp_paths.keepalive.heartbeat_interval;
 
         let role = match path {
             SendAddr::Udp(addr) => match self.udp_paths.paths.entry(addr.into()) {
-                Entry::Occupied(mut occupied) => occupied.get_mut().handle_ping(tx_id, now),
+                Entry::Occupied(mut occupied) => {
+                    occupied
+                        .get_mut()
+                        .handle_ping(tx_id, now, heartbeat_interval)
+                }
                 Entry::Vacant(vacant) => {
                     info!(%addr, "new direct addr for node");
                     vacant.insert(PathState::with_ping(
@@ -742,7 +1032,7 @@ impl NodeState {
                         ));
                         PingRole::NewPath
                     }
-                    Some((_home_url, state)) => state.handle_ping(tx_id, now),
+                    Some((_home_url, state)) => state.handle_ping(tx_id, now, heartbeat_interval),
                     None => {
                         info!(%url, "new relay addr for node");
                         self.relay_url = Some((
@@ -851,6 +1141,8 @@ impl NodeState {
         for es in self.udp_paths.paths.values_mut() {
             es.clear();
         }
+        self.hole_punch_rounds = 0;
+        self.update_connectivity(ConnectivityReason::NetworkChanged, Instant::now());
     }
 
     /// Handles a Pong message (a reply to an earlier ping).
@@ -894,6 +1186,14 @@ impl NodeState {
                     "received pong",
                 );
 
+                if let Some(probe) = self.probes.remove(&m.tx_id) {
+                    let conn_type = match src {
+                        SendAddr::Udp(addr) => ConnectionType::Direct(addr),
+                        SendAddr::Relay(ref url) => ConnectionType::Relay(url.clone()),
+                    };
+                    probe.send(PingResult { latency, conn_type }).ok();
+                }
+
                 match src {
                     SendAddr::Udp(addr) => {
                         match self.udp_paths.paths.get_mut(&addr.into()) {
@@ -904,6 +1204,11 @@ impl NodeState {
                             }
                             Some(st) => {
                                 node_map_insert = Some((addr, self.node_id));
+                                if self.hole_punch_rounds > 0 {
+                                    Counters::add(&self.counters.hole_punch_successes, 1);
+                                }
+                                self.hole_punch_rounds = 0;
+                                self.disco_counters.last_direct_handshake = Some(now);
                                 st.add_pong_reply(PongReply {
                                     latency,
                                     pong_at: now,
@@ -919,6 +1224,7 @@ impl NodeState {
                     }
                     SendAddr::Relay(ref url) => match self.relay_url.as_mut() {
                         Some((home_url, state)) if home_url == url => {
+                            self.disco_counters.last_relay_handshake = Some(now);
                             state.add_pong_reply(PongReply {
                                 latency,
                                 pong_at: now,
@@ -940,16 +1246,12 @@ impl NodeState {
                     },
                 }
 
-                // Promote this pong response to our current best address if it's lower latency.
-                // TODO(bradfitz): decide how latency vs. preference order affects decision
+                // Let the path selector decide whether to promote this path to our current
+                // best address.
                 if let SendAddr::Udp(to) = sp.to {
                     debug_assert!(!is_relay, "mismatching relay & udp");
-                    self.udp_paths.best_addr.insert_if_better_or_reconfirm(
-                        to,
-                        latency,
-                        best_addr::Source::ReceivedPong,
-                        now,
-                    );
+                    self.select_path(to, now);
+                    self.update_connectivity(ConnectivityReason::PongReceived(to), now);
                 }
 
                 node_map_insert
@@ -957,6 +1259,77 @@ impl NodeState {
         }
     }
 
+    /// Handles a failure to send on the direct path to `addr`.
+    ///
+    /// Errors like an unreachable network mean the path is gone, e.g. because its network
+    /// interface went down.  The path is no longer trusted and sending migrates to another
+    /// validated direct path, or otherwise to the relay path, without waiting for pings to
+    /// time out.  Returns the path to send on instead.
+    pub(super) fn direct_path_failed(
+        &mut self,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> Option<SendAddr> {
+        let state = self.udp_paths.paths.get_mut(&addr.into())?;
+        state.clear();
+        let was_best = self.udp_paths.best_addr.addr() == Some(addr);
+        self.udp_paths.best_addr.clear_if_equals(
+            addr,
+            ClearReason::SendFailed,
+            self.relay_url.is_some(),
+        );
+        self.select_path(addr, now);
+        self.connectivity
+            .failure(ConnectivityReason::SendFailed(addr), now);
+        self.update_connectivity(ConnectivityReason::SendFailed(addr), now);
+        let to = match self.udp_paths.best_addr.addr() {
+            Some(addr) => SendAddr::Udp(addr),
+            None => SendAddr::Relay(self.relay_url()?),
+        };
+        if was_best {
+            event!(
+                target: "iroh::_events::path::migrated",
+                Level::DEBUG,
+                remote_node = %self.node_id.fmt_short(),
+                from = %addr,
+                to = %to,
+            );
+            inc!(MagicsockMetrics, path_migrations);
+        }
+        Some(to)
+    }
+
+    /// Asks the [`PathSelector`] for the best address after a pong was received on `to`.
+    fn select_path(&mut self, to: SocketAddr, now: Instant) {
+        let candidates = self.udp_paths.path_candidates(now);
+        if candidates.is_empty() {
+            return;
+        }
+        let Some(addr) = self.path_selector.select(&candidates) else {
+            return;
+        };
+        let Some(candidate) = candidates.iter().find(|candidate| candidate.addr == addr) else {
+            warn!(%addr, "path selector chose an unknown path");
+            return;
+        };
+        if !candidate.selected {
+            self.udp_paths.best_addr.insert(
+                addr,
+                candidate.latency,
+                best_addr::Source::ReceivedPong,
+                now - candidate.since_validated,
+                self.udp_paths.keepalive.path_timeout,
+            );
+        } else if addr == to {
+            self.udp_paths.best_addr.reconfirm_if_used(
+                addr,
+                best_addr::Source::ReceivedPong,
+                now,
+                self.udp_paths.keepalive.path_timeout,
+            );
+        }
+    }
+
     /// Handles a DISCO CallMeMaybe discovery message.
     ///
     /// The contract for use of this message is that the node has already pinged to us via
@@ -1025,46 +1398,195 @@ impl NodeState {
             paths = %summarize_node_paths(&self.udp_paths.paths),
             "updated endpoint paths from call-me-maybe",
         );
+        self.update_connectivity(ConnectivityReason::CandidatesLearned, now);
         self.send_pings(now)
     }
 
     /// Marks this node as having received a UDP payload message.
-    pub(super) fn receive_udp(&mut self, addr: IpPort, now: Instant) {
+    ///
+    /// The `len` bytes were received in datagrams of at most `max_datagram` bytes.
+    pub(super) fn receive_udp(
+        &mut self,
+        addr: IpPort,
+        len: usize,
+        max_datagram: usize,
+        now: Instant,
+    ) {
+        self.traffic.direct_recv += len as u64;
         let Some(state) = self.udp_paths.paths.get_mut(&addr) else {
             debug_assert!(false, "node map inconsistency by_ip_port <-> direct addr");
             return;
         };
         state.last_payload_msg = Some(now);
+        state.bytes_recv += len as u64;
+        let max_datagram = u16::try_from(max_datagram).unwrap_or(u16::MAX);
+        state.max_recv_datagram = state.max_recv_datagram.max(Some(max_datagram));
         self.last_used = Some(now);
-        self.udp_paths
-            .best_addr
-            .reconfirm_if_used(addr.into(), BestAddrSource::Udp, now);
+        self.udp_paths.best_addr.reconfirm_if_used(
+            addr.into(),
+            BestAddrSource::Udp,
+            now,
+            self.udp_paths.keepalive.path_timeout,
+        );
     }
 
-    pub(super) fn receive_relay(&mut self, url: &RelayUrl, src: NodeId, now: Instant) {
+    pub(super) fn receive_relay(&mut self, url: &RelayUrl, src: NodeId, len: usize, now: Instant) {
+        self.traffic.relay_recv += len as u64;
         match self.relay_url.as_mut() {
             Some((current_home, state)) if current_home == url => {
                 // We received on the expected url. update state.
                 state.last_payload_msg = Some(now);
+                state.bytes_recv += len as u64;
             }
             Some((_current_home, _state)) => {
                 // we have a different url. we only update on ping, not on receive_relay.
             }
             None => {
-                self.relay_url = Some((
-                    url.clone(),
-                    PathState::with_last_payload(
-                        src,
-                        SendAddr::from(url.clone()),
-                        Source::Relay,
-                        now,
-                    ),
-                ));
+                let mut state = PathState::with_last_payload(
+                    src,
+                    SendAddr::from(url.clone()),
+                    Source::Relay,
+                    now,
+                );
+                state.bytes_recv += len as u64;
+                self.relay_url = Some((url.clone(), state));
             }
         }
         self.last_used = Some(now);
     }
 
+    /// Records `len` bytes of payload sent on the given paths.
+    pub(super) fn add_bytes_sent(
+        &mut self,
+        udp_addr: Option<SocketAddr>,
+        relay_url: Option<&RelayUrl>,
+        len: usize,
+    ) {
+        if udp_addr.is_some() {
+            self.traffic.direct_sent += len as u64;
+        }
+        if relay_url.is_some() {
+            self.traffic.relay_sent += len as u64;
+        }
+        if let Some(state) = udp_addr.and_then(|addr| self.udp_paths.paths.get_mut(&addr.into())) {
+            state.bytes_sent += len as u64;
+        }
+        if let Some((url, state)) = self.relay_url.as_mut() {
+            if relay_url == Some(&*url) {
+                state.bytes_sent += len as u64;
+            }
+        }
+    }
+
+    /// Returns the statistics of all the paths to this node.
+    pub(super) fn path_stats(&self, now: Instant) -> Vec<NodePathStats> {
+        let (active_addr, active_relay) = match self.conn_type.get() {
+            ConnectionType::Direct(addr) => (Some(addr), None),
+            ConnectionType::Relay(url) => (None, Some(url)),
+            ConnectionType::Mixed(addr, url) => (Some(addr), Some(url)),
+            ConnectionType::None => (None, None),
+        };
+        // With `MultipathPolicy::Aggregate` all validated paths carry data.
+        let aggregate =
+            active_addr.is_some() && self.multipath_policy == MultipathPolicy::Aggregate;
+        let path_timeout = self.udp_paths.keepalive.path_timeout;
+        let same_nat = self.behind_same_nat();
+        let udp_stats = self.udp_paths.paths.iter().map(|(ipp, state)| {
+            let active = state.udp_addr().is_some_and(|addr| {
+                Some(addr) == active_addr || (aggregate && state.is_validated(now, path_timeout))
+            });
+            let mut stats = state.stats(now, active);
+            stats.same_nat_shortcut = same_nat && !self.is_hairpin(ipp);
+            stats
+        });
+        let relay_stats = self
+            .relay_url
+            .iter()
+            .map(|(url, state)| state.stats(now, active_relay.as_ref() == Some(url)));
+        udp_stats.chain(relay_stats).collect()
+    }
+
+    /// Records a DISCO message sent to this node.
+    pub(super) fn disco_sent(&mut self, typ: disco::MessageType, via_relay: bool) {
+        if via_relay {
+            self.disco_counters.sent_relay.add(typ);
+        } else {
+            self.disco_counters.sent_udp.add(typ);
+        }
+    }
+
+    /// Records a DISCO message received from this node.
+    pub(super) fn disco_received(&mut self, typ: disco::MessageType, via_relay: bool) {
+        if via_relay {
+            self.disco_counters.recv_relay.add(typ);
+        } else {
+            self.disco_counters.recv_udp.add(typ);
+        }
+    }
+
+    /// Returns the statistics of the DISCO messages exchanged with this node.
+    pub(super) fn disco_stats(&self, now: Instant) -> DiscoStats {
+        let DiscoCounters {
+            sent_udp,
+            sent_relay,
+            recv_udp,
+            recv_relay,
+            last_direct_handshake,
+            last_relay_handshake,
+        } = self.disco_counters;
+        DiscoStats {
+            sent_udp,
+            sent_relay,
+            recv_udp,
+            recv_relay,
+            last_direct_handshake: last_direct_handshake.map(|t| now.duration_since(t)),
+            last_relay_handshake: last_relay_handshake.map(|t| now.duration_since(t)),
+        }
+    }
+
+    /// Returns the payload bytes exchanged with this node.
+    pub(super) fn traffic_stats(&self) -> TrafficStats {
+        self.traffic
+    }
+
+    /// Returns the payload bytes exchanged with this node and starts counting from zero.
+    pub(super) fn reset_traffic_stats(&mut self) -> TrafficStats {
+        std::mem::take(&mut self.traffic)
+    }
+
+    /// Returns the state of establishing a direct connection to this node.
+    pub(super) fn connectivity_state(&self, now: Instant) -> ConnectivityState {
+        self.connectivity.state(now, self.hole_punch_rounds)
+    }
+
+    /// Records the [`ConnectivityPhase`] of the node after `reason` changed its paths.
+    fn update_connectivity(&mut self, reason: ConnectivityReason, now: Instant) {
+        let phase = if matches!(
+            self.udp_paths.best_addr.state(now),
+            best_addr::State::Valid(_)
+        ) {
+            ConnectivityPhase::Direct
+        } else if self.udp_paths.paths.is_empty() {
+            ConnectivityPhase::NoCandidates
+        } else if self
+            .sent_pings
+            .values()
+            .any(|ping| matches!(ping.to, SendAddr::Udp(_)))
+        {
+            ConnectivityPhase::Probing
+        } else {
+            match self.connectivity.phase() {
+                ConnectivityPhase::NoCandidates | ConnectivityPhase::CandidatesGathered => {
+                    ConnectivityPhase::CandidatesGathered
+                }
+                ConnectivityPhase::Probing
+                | ConnectivityPhase::Direct
+                | ConnectivityPhase::RelayFallback => ConnectivityPhase::RelayFallback,
+            }
+        };
+        self.connectivity.transition(phase, reason, now);
+    }
+
     pub(super) fn last_ping(&self, addr: &SendAddr) -> Option<Instant> {
         match addr {
             SendAddr::Udp(addr) => self
@@ -1083,15 +1605,20 @@ impl NodeState {
     /// Checks if this `Endpoint` is currently actively being used.
     pub(super) fn is_active(&self, now: &Instant) -> bool {
         match self.last_used {
-            Some(last_active) => now.duration_since(last_active) <= SESSION_ACTIVE_TIMEOUT,
+            Some(last_active) => {
+                now.duration_since(last_active) <= self.udp_paths.keepalive.session_timeout
+            }
             None => false,
         }
     }
 
     /// Send a heartbeat to the node to keep the connection alive, or trigger a full ping
     /// if necessary.
+    ///
+    /// `have_ipv6` is whether we can send to IPv6 addresses, IPv6 paths are not kept alive
+    /// otherwise.
     #[instrument("stayin_alive", skip_all, fields(node = %self.node_id.fmt_short()))]
-    pub(super) fn stayin_alive(&mut self) -> Vec<PingAction> {
+    pub(super) fn stayin_alive(&mut self, have_ipv6: bool) -> Vec<PingAction> {
         trace!("stayin_alive");
         let now = Instant::now();
         if !self.is_active(&now) {
@@ -1105,8 +1632,15 @@ impl NodeState {
             return self.send_call_me_maybe(now, SendCallMeMaybe::Always);
         }
 
-        // Send heartbeat ping to keep the current addr going as long as we need it.
-        if let Some(udp_addr) = self.udp_paths.best_addr.addr() {
+        // Send heartbeat ping to keep the current addr going as long as we need it.  When
+        // using multiple paths, all the validated paths are kept going.
+        let mut udp_addrs: BTreeSet<SocketAddr> =
+            self.udp_paths.best_addr.addr().into_iter().collect();
+        if self.multipath_policy != MultipathPolicy::BestOnly {
+            udp_addrs.extend(self.udp_paths.validated_addrs(now, have_ipv6));
+        }
+        let mut msgs = Vec::new();
+        for udp_addr in udp_addrs {
             let elapsed = self.last_ping(&SendAddr::Udp(udp_addr)).map(|l| now - l);
             // Send a ping if the last ping is older than 2 seconds.
             let needs_ping = match elapsed {
@@ -1123,12 +1657,12 @@ impl NodeState {
                 if let Some(msg) =
                     self.start_ping(SendAddr::Udp(udp_addr), DiscoPingPurpose::StayinAlive)
                 {
-                    return vec![PingAction::SendPing(msg)];
+                    msgs.push(PingAction::SendPing(msg));
                 }
             }
         }
 
-        Vec::new()
+        msgs
     }
 
     /// Returns the addresses on which a payload should be sent right now.
@@ -1237,6 +1771,8 @@ pub enum DiscoPingPurpose {
     /// When a ping was received we suspect a direct connection is possible.  If we do not
     /// yet have one that triggers a ping, indicated with this reason.
     PingBack,
+    /// The application asked to measure the latency to the node.
+    Probe,
 }
 
 /// The type of control message we have received.
@@ -1350,6 +1886,10 @@ pub struct RemoteInfo {
     ///
     /// Some of these addresses might only be valid for networks we are not part of, but the remote
     /// node might be a part of.
+    ///
+    /// The addresses are ordered from the most to the least promising one to dial: those
+    /// which were alive recently come first, then those whose pings were answered more
+    /// often, then the most recently reported ones.
     pub addrs: Vec<DirectAddrInfo>,
     /// The type of connection we have to the node, either direct or over relay.
     pub conn_type: ConnectionType,
@@ -1403,57 +1943,472 @@ impl RemoteInfo {
     }
 }
 
-/// The type of connection we have to the endpoint.
-#[derive(derive_more::Display, Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-pub enum ConnectionType {
-    /// Direct UDP connection
-    #[display("direct({_0})")]
-    Direct(SocketAddr),
-    /// Relay connection over relay
-    #[display("relay({_0})")]
-    Relay(RelayUrl),
-    /// Both a UDP and a relay connection are used.
+/// Which paths may be used to send data to a remote node.
+///
+/// See [`ConnectOptions::path_policy`].
+///
+/// [`ConnectOptions::path_policy`]: crate::endpoint::ConnectOptions::path_policy
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum PathPolicy {
+    /// Use direct paths when possible and fall back to the relay server.
+    #[default]
+    Any,
+    /// Only ever send via the relay server.
     ///
-    /// This is the case if we do have a UDP address, but are missing a recent confirmation that
-    /// the address works.
-    #[display("mixed(udp: {_0}, relay: {_1})")]
-    Mixed(SocketAddr, RelayUrl),
-    /// We have no verified connection to this PublicKey
-    #[display("none")]
-    None,
+    /// No packets are sent directly to the node and our direct addresses are not sent to it
+    /// to attempt hole punching, so the node does not learn our IP address from us.
+    RelayOnly,
+    /// Only send data on direct paths, never via the relay server.
+    ///
+    /// The relay server is still used for the small DISCO messages needed to establish a
+    /// direct path by hole punching.
+    DirectOnly,
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{collections::BTreeMap, net::Ipv4Addr};
+/// Which address families may be used for direct paths to a remote node.
+///
+/// Nodes often have both IPv4 and IPv6 direct paths.  By default the path with the lowest
+/// latency is used, regardless of its family.  The preferring policies use a path of the
+/// preferred family whenever one is validated, even if a path of the other family is
+/// faster.  The restricting policies never ping nor send on paths of the other family.
+///
+/// The relay path is not affected by this policy.
+///
+/// See [`Builder::addr_family_policy`] and [`ConnectOptions::addr_family_policy`].
+///
+/// [`Builder::addr_family_policy`]: crate::endpoint::Builder::addr_family_policy
+/// [`ConnectOptions::addr_family_policy`]: crate::endpoint::ConnectOptions::addr_family_policy
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum AddrFamilyPolicy {
+    /// Use paths of either family.
+    #[default]
+    Any,
+    /// Use an IPv6 path when one is validated, otherwise an IPv4 path.
+    PreferIpv6,
+    /// Use an IPv4 path when one is validated, otherwise an IPv6 path.
+    PreferIpv4,
+    /// Only use IPv4 paths.
+    Ipv4Only,
+    /// Only use IPv6 paths.
+    Ipv6Only,
+}
 
-    use best_addr::BestAddr;
+impl AddrFamilyPolicy {
+    /// Whether direct paths to `addr` may be used.
+    pub(super) fn allows(self, addr: SocketAddr) -> bool {
+        match self {
+            Self::Ipv4Only => addr.is_ipv4(),
+            Self::Ipv6Only => addr.is_ipv6(),
+            Self::Any | Self::PreferIpv6 | Self::PreferIpv4 => true,
+        }
+    }
 
-    use super::*;
-    use crate::{
-        key::SecretKey,
-        magicsock::node_map::{NodeMap, NodeMapInner},
-    };
+    /// Whether `addr` is of the preferred family.
+    ///
+    /// Only the preferring policies have a preferred family.
+    pub(super) fn prefers(self, addr: SocketAddr) -> bool {
+        match self {
+            Self::PreferIpv6 => addr.is_ipv6(),
+            Self::PreferIpv4 => addr.is_ipv4(),
+            Self::Any | Self::Ipv4Only | Self::Ipv6Only => false,
+        }
+    }
+}
 
-    #[test]
-    fn test_remote_infos() {
-        let now = Instant::now();
-        let elapsed = Duration::from_secs(3);
-        let later = now + elapsed;
-        let send_addr: RelayUrl = "https://my-relay.com".parse().unwrap();
-        let pong_src = SendAddr::Udp("0.0.0.0:1".parse().unwrap());
-        let latency = Duration::from_millis(50);
+/// Configuration of hole punching, which establishes direct paths to remote nodes.
+///
+/// Each hole punching round pings all known direct addresses of a node and sends it a
+/// call-me-maybe via the relay server, asking it to ping us back.  Rounds are started when
+/// sending to a node without a working direct path, at most once per heartbeat of a few
+/// seconds.  Meanwhile data is sent via the relay server.
+///
+/// The defaults keep trying to establish a direct path for as long as a node is in use.
+/// Networks with mostly reachable nodes, like LANs, can probe more quickly while nodes
+/// behind carrier-grade NATs, where hole punching rarely succeeds, can give up early.
+///
+/// See [`Builder::hole_punching`].
+///
+/// [`Builder::hole_punching`]: crate::endpoint::Builder::hole_punching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HolePunchConfig {
+    max_rounds: Option<u32>,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    upgrade_interval: Duration,
+    upgrade_on_relay: bool,
+}
 
-        let relay_and_state = |node_id: NodeId, url: RelayUrl| {
-            let relay_state = PathState::with_pong_reply(
-                node_id,
-                PongReply {
-                    latency,
-                    pong_at: now,
-                    from: SendAddr::Relay(send_addr.clone()),
-                    pong_src: pong_src.clone(),
-                },
-            );
+impl Default for HolePunchConfig {
+    fn default() -> Self {
+        Self {
+            max_rounds: None,
+            ping_interval: DISCO_PING_INTERVAL,
+            ping_timeout: PING_TIMEOUT_DURATION,
+            upgrade_interval: UPGRADE_INTERVAL,
+            upgrade_on_relay: true,
+        }
+    }
+}
+
+impl HolePunchConfig {
+    /// Sets the number of hole punching rounds after which we settle on the relay path.
+    ///
+    /// Rounds are counted since the last pong received on a direct path or the last change
+    /// of our network.  Once settled, [`HolePunchConfig::upgrade_on_relay`] decides whether
+    /// we keep trying.  By default hole punching never settles.
+    pub fn max_rounds(mut self, rounds: u32) -> Self {
+        self.max_rounds = Some(rounds);
+        self
+    }
+
+    /// Sets the minimum interval between pings on the same path, by default 5 seconds.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Sets how long to wait for the reply to a ping, by default 5 seconds.
+    ///
+    /// A direct path which does not reply within this time is abandoned and data is sent
+    /// via the relay server again.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Sets how often to try finding a better path, by default 60 seconds.
+    ///
+    /// This applies when a direct path with high latency is used, and after having settled
+    /// on the relay path.
+    pub fn upgrade_interval(mut self, interval: Duration) -> Self {
+        self.upgrade_interval = interval;
+        self
+    }
+
+    /// Sets whether to keep trying to establish a direct path after having settled on the
+    /// relay path, by default `true`.
+    ///
+    /// If `false`, hole punching is only resumed once our network changes, or once the
+    /// remote node's hole punching establishes a direct path.
+    pub fn upgrade_on_relay(mut self, upgrade: bool) -> Self {
+        self.upgrade_on_relay = upgrade;
+        self
+    }
+}
+
+/// Configuration of the keepalives which keep direct paths to remote nodes open.
+///
+/// While a node is in use, a DISCO ping is sent on its direct path every heartbeat.  This
+/// keeps the NAT mappings along the path open and confirms the path still works.  A path
+/// which did not reply for longer than the path timeout is no longer trusted, and data is
+/// sent via the relay server as well until it replies again.  Once no data was exchanged
+/// with a node for the session timeout, the keepalives stop.
+///
+/// The defaults detect broken paths within a few seconds, at the cost of waking up the
+/// radio of mobile devices every few seconds.  [`KeepaliveConfig::low_power`] trades slower
+/// detection of broken paths for less frequent wakeups.
+///
+/// See [`Builder::keepalive`].
+///
+/// [`Builder::keepalive`]: crate::endpoint::Builder::keepalive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    pub(crate) heartbeat_interval: Duration,
+    pub(super) path_timeout: Duration,
+    session_timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            path_timeout: TRUST_UDP_ADDR_DURATION,
+            session_timeout: SESSION_ACTIVE_TIMEOUT,
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    /// Returns a configuration for battery-constrained devices.
+    ///
+    /// This sends a heartbeat every 20 seconds, still often enough for the NAT mappings of
+    /// most routers, and stops the keepalives after 30 seconds without data.  A broken path
+    /// is only noticed after 26 seconds though.
+    pub fn low_power() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(20),
+            path_timeout: Duration::from_secs(26),
+            session_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets the interval between heartbeats, by default 5 seconds.
+    ///
+    /// Besides the keepalive pings, this also paces the hole punching rounds.  The interval
+    /// should be shorter than the [path timeout](Self::path_timeout).
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Sets how long a direct path is trusted without a reply to a ping, by default 6.5
+    /// seconds.
+    pub fn path_timeout(mut self, timeout: Duration) -> Self {
+        self.path_timeout = timeout;
+        self
+    }
+
+    /// Sets for how long the paths to an idle node are kept alive, by default 45 seconds.
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.session_timeout = timeout;
+        self
+    }
+}
+
+/// How data is sent when a remote node is reachable over several direct paths.
+///
+/// A direct path is validated when it recently replied to a DISCO ping.  Only validated
+/// paths are used besides the best path, e.g. a LAN and a WAN path, or the paths via WiFi
+/// and Ethernet.  See [`ConnectOptions::multipath`].
+///
+/// [`ConnectOptions::multipath`]: crate::endpoint::ConnectOptions::multipath
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum MultipathPolicy {
+    /// Only send on the best path, the one with the lowest latency.
+    ///
+    /// If the best path stops working, data is sent via the relay server until another
+    /// direct path is selected.
+    #[default]
+    BestOnly,
+    /// Send on the best path, failing over to another validated path if it stops working.
+    ///
+    /// The validated paths are kept alive so that they can take over immediately, without
+    /// falling back to the relay server.
+    ActiveBackup,
+    /// Spread the data over all validated paths, sending each datagram on the next path in
+    /// turn.
+    ///
+    /// This can increase the throughput if no single path is the bottleneck, but paths with
+    /// very different latencies reorder the datagrams, which QUIC's congestion control may
+    /// see as loss.
+    Aggregate,
+}
+
+/// A network path to a remote node, see [`NodePathStats`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PathAddr {
+    /// The direct UDP path to this address of the remote node.
+    Direct(SocketAddr),
+    /// The path via this relay server.
+    Relay(RelayUrl),
+}
+
+/// Statistics about a single network path to a remote node.
+///
+/// See [`Endpoint::path_stats`].
+///
+/// [`Endpoint::path_stats`]: crate::endpoint::Endpoint::path_stats
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodePathStats {
+    /// The network path these statistics are about.
+    pub path: PathAddr,
+    /// Whether payload data is currently sent on this path.
+    pub active: bool,
+    /// The round-trip time measured by the most recent ping on this path.
+    pub latency: Option<Duration>,
+    /// The smoothed round-trip time over the pings which received a reply.
+    pub smoothed_rtt: Option<Duration>,
+    /// The variation of the round-trip time, the jitter of this path.
+    pub rtt_variation: Option<Duration>,
+    /// The estimated fraction of pings lost on this path, between `0.0` and `1.0`.
+    pub loss: f32,
+    /// The number of payload bytes sent on this path.
+    pub bytes_sent: u64,
+    /// The number of payload bytes received on this path.
+    pub bytes_recv: u64,
+    /// The path MTU towards us, as far as discovered by the remote node.
+    ///
+    /// This is the size of the largest QUIC datagram received on this direct path.  QUIC
+    /// path MTU discovery probes with ever larger datagrams, so this grows up to the MTU of
+    /// the path in the direction from the remote node to us.  It is `None` for the relay
+    /// path and until data was received on a direct path.
+    ///
+    /// The MTU in the direction to the remote node is in the [`ConnectionStats`] of the
+    /// connections to it.
+    ///
+    /// [`ConnectionStats`]: crate::endpoint::ConnectionStats
+    pub mtu: Option<u16>,
+    /// Elapsed time since this path was last validated by a reply to a ping.
+    pub last_validated: Option<Duration>,
+    /// Elapsed time since payload data was last received on this path.
+    pub last_payload: Option<Duration>,
+    /// Whether the node is behind the same NAT as us and this path avoids sending via the
+    /// public address of the NAT.
+    ///
+    /// Such local paths are tried before the paths via the public address, which many
+    /// routers do not support.
+    pub same_nat_shortcut: bool,
+}
+
+/// The DISCO messages exchanged with a node, see [`NodeState::disco_stats`].
+#[derive(Debug, Default, Clone, Copy)]
+struct DiscoCounters {
+    sent_udp: DiscoCounts,
+    sent_relay: DiscoCounts,
+    recv_udp: DiscoCounts,
+    recv_relay: DiscoCounts,
+    last_direct_handshake: Option<Instant>,
+    last_relay_handshake: Option<Instant>,
+}
+
+/// Numbers of DISCO messages, by message type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoCounts {
+    /// The number of pings.
+    pub pings: u64,
+    /// The number of pongs.
+    pub pongs: u64,
+    /// The number of call-me-maybe messages.
+    pub call_me_maybes: u64,
+}
+
+impl DiscoCounts {
+    fn add(&mut self, typ: disco::MessageType) {
+        match typ {
+            disco::MessageType::Ping => self.pings += 1,
+            disco::MessageType::Pong => self.pongs += 1,
+            disco::MessageType::CallMeMaybe => self.call_me_maybes += 1,
+        }
+    }
+}
+
+/// Statistics of the DISCO messages exchanged with a remote node.
+///
+/// DISCO messages establish the paths to a node: pings on a path are answered by pongs,
+/// which validates the path.  Call-me-maybe messages are sent via the relay server and ask
+/// the node to ping our direct addresses, which punches holes into NATs on both sides.
+/// Failed hole punching shows as pings sent on direct paths without any pongs received.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoStats {
+    /// The messages sent to the node directly over UDP.
+    pub sent_udp: DiscoCounts,
+    /// The messages sent to the node via a relay server.
+    pub sent_relay: DiscoCounts,
+    /// The messages received from the node directly over UDP.
+    pub recv_udp: DiscoCounts,
+    /// The messages received from the node via a relay server.
+    pub recv_relay: DiscoCounts,
+    /// Elapsed time since a pong answered one of our pings on a direct path.
+    pub last_direct_handshake: Option<Duration>,
+    /// Elapsed time since a pong answered one of our pings via the relay server.
+    pub last_relay_handshake: Option<Duration>,
+}
+
+/// The payload bytes exchanged with a remote node, by transport.
+///
+/// Only the QUIC datagrams of the connections with the node are counted, not the DISCO
+/// messages establishing the paths.  Datagrams sent on both the direct path and via the relay
+/// server, as is done while the direct path is not yet confirmed, are counted for both.
+///
+/// See [`Endpoint::traffic_stats`].
+///
+/// [`Endpoint::traffic_stats`]: crate::Endpoint::traffic_stats
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficStats {
+    /// The bytes sent to the node directly over UDP.
+    pub direct_sent: u64,
+    /// The bytes received from the node directly over UDP.
+    pub direct_recv: u64,
+    /// The bytes sent to the node via a relay server.
+    pub relay_sent: u64,
+    /// The bytes received from the node via a relay server.
+    pub relay_recv: u64,
+}
+
+impl TrafficStats {
+    /// The bytes sent to the node over any transport.
+    pub fn sent(&self) -> u64 {
+        self.direct_sent + self.relay_sent
+    }
+
+    /// The bytes received from the node over any transport.
+    pub fn recv(&self) -> u64 {
+        self.direct_recv + self.relay_recv
+    }
+
+    /// Whether nothing was exchanged with the node.
+    pub fn is_empty(&self) -> bool {
+        self.sent() == 0 && self.recv() == 0
+    }
+}
+
+/// The result of pinging a remote node, see [`Endpoint::ping`].
+///
+/// [`Endpoint::ping`]: crate::Endpoint::ping
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingResult {
+    /// The round trip time of the ping.
+    pub latency: Duration,
+    /// The path the pong was received on.
+    ///
+    /// This is either [`ConnectionType::Direct`] or [`ConnectionType::Relay`].
+    pub conn_type: ConnectionType,
+}
+
+/// The type of connection we have to the endpoint.
+#[derive(derive_more::Display, Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ConnectionType {
+    /// Direct UDP connection
+    #[display("direct({_0})")]
+    Direct(SocketAddr),
+    /// Relay connection over relay
+    #[display("relay({_0})")]
+    Relay(RelayUrl),
+    /// Both a UDP and a relay connection are used.
+    ///
+    /// This is the case if we do have a UDP address, but are missing a recent confirmation that
+    /// the address works.
+    #[display("mixed(udp: {_0}, relay: {_1})")]
+    Mixed(SocketAddr, RelayUrl),
+    /// We have no verified connection to this PublicKey
+    #[display("none")]
+    None,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        net::{Ipv4Addr, Ipv6Addr},
+    };
+
+    use best_addr::BestAddr;
+
+    use super::*;
+    use crate::{
+        key::SecretKey,
+        magicsock::node_map::{NodeMap, NodeMapInner},
+    };
+
+    #[test]
+    fn test_remote_infos() {
+        let now = Instant::now();
+        let elapsed = Duration::from_secs(3);
+        let later = now + elapsed;
+        let send_addr: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let pong_src = SendAddr::Udp("0.0.0.0:1".parse().unwrap());
+        let latency = Duration::from_millis(50);
+
+        let relay_and_state = |node_id: NodeId, url: RelayUrl| {
+            let relay_state = PathState::with_pong_reply(
+                node_id,
+                PongReply {
+                    latency,
+                    pong_at: now,
+                    from: SendAddr::Relay(send_addr.clone()),
+                    pong_src: pong_src.clone(),
+                },
+            );
             Some((url, relay_state))
         };
 
@@ -1498,6 +2453,17 @@ mod tests {
                     last_call_me_maybe: None,
                     conn_type: Watchable::new(ConnectionType::Direct(ip_port.into())),
                     has_been_direct: true,
+                    path_policy: PathPolicy::Any,
+                    multipath_policy: MultipathPolicy::BestOnly,
+                    probes: HashMap::new(),
+                    hole_punching: HolePunchConfig::default(),
+                    path_selector: Arc::new(LatencyPathSelector),
+                    hole_punch_rounds: 0,
+                    reflexive_ips: Default::default(),
+                    counters: Default::default(),
+                    disco_counters: Default::default(),
+                    traffic: Default::default(),
+                    connectivity: ConnectivityLog::new(now),
                 },
                 ip_port.into(),
             )
@@ -1518,6 +2484,17 @@ mod tests {
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
                 has_been_direct: false,
+                path_policy: PathPolicy::Any,
+                multipath_policy: MultipathPolicy::BestOnly,
+                probes: HashMap::new(),
+                hole_punching: HolePunchConfig::default(),
+                path_selector: Arc::new(LatencyPathSelector),
+                hole_punch_rounds: 0,
+                reflexive_ips: Default::default(),
+                counters: Default::default(),
+                disco_counters: Default::default(),
+                traffic: Default::default(),
+                connectivity: ConnectivityLog::new(now),
             }
         };
 
@@ -1545,6 +2522,17 @@ mod tests {
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
                 has_been_direct: false,
+                path_policy: PathPolicy::Any,
+                multipath_policy: MultipathPolicy::BestOnly,
+                probes: HashMap::new(),
+                hole_punching: HolePunchConfig::default(),
+                path_selector: Arc::new(LatencyPathSelector),
+                hole_punch_rounds: 0,
+                reflexive_ips: Default::default(),
+                counters: Default::default(),
+                disco_counters: Default::default(),
+                traffic: Default::default(),
+                connectivity: ConnectivityLog::new(now),
             }
         };
 
@@ -1585,6 +2573,17 @@ mod tests {
                         send_addr.clone(),
                     )),
                     has_been_direct: false,
+                    path_policy: PathPolicy::Any,
+                    multipath_policy: MultipathPolicy::BestOnly,
+                    probes: HashMap::new(),
+                    hole_punching: HolePunchConfig::default(),
+                    path_selector: Arc::new(LatencyPathSelector),
+                    hole_punch_rounds: 0,
+                    reflexive_ips: Default::default(),
+                    counters: Default::default(),
+                    disco_counters: Default::default(),
+                    traffic: Default::default(),
+                    connectivity: ConnectivityLog::new(now),
                 },
                 socket_addr,
             )
@@ -1675,6 +2674,7 @@ mod tests {
                 (d_endpoint.id, d_endpoint),
             ]),
             next_id: 5,
+            ..Default::default()
         });
         let mut got = node_map.list_remote_infos(later);
         got.sort_by_key(|p| p.node_id);
@@ -1719,4 +2719,627 @@ mod tests {
         // number of pings as direct addresses in the call-me-maybe.
         assert_eq!(ping_messages.len(), my_numbers_count as usize);
     }
+
+    #[test]
+    fn test_multipath_policy() {
+        let now = Instant::now();
+        let key = SecretKey::generate();
+        let addr_a = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let addr_b = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1001);
+        let path = |addr: SocketAddr, latency: Duration, pong_at: Instant| {
+            PathState::with_pong_reply(
+                key.public(),
+                PongReply {
+                    latency,
+                    pong_at,
+                    from: SendAddr::Udp(addr),
+                    pong_src: SendAddr::Udp(addr),
+                },
+            )
+        };
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        ep.udp_paths = NodeUdpPaths::from_parts(
+            BTreeMap::from([
+                (addr_a.into(), path(addr_a, Duration::from_millis(10), now)),
+                (addr_b.into(), path(addr_b, Duration::from_millis(20), now)),
+            ]),
+            BestAddr::from_parts(
+                addr_a,
+                Duration::from_millis(10),
+                now,
+                now + Duration::from_secs(100),
+            ),
+        );
+
+        let (udp_addr, _, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(addr_a));
+        let (udp_addr, _, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(addr_a));
+
+        ep.set_multipath_policy(MultipathPolicy::Aggregate);
+        let sent: BTreeSet<_> = (0..2).filter_map(|_| ep.get_send_addrs(false).0).collect();
+        assert_eq!(sent, BTreeSet::from([addr_a, addr_b]));
+        // The connection type only reports the best path.
+        assert_eq!(ep.conn_type(), ConnectionType::Direct(addr_a));
+
+        // The best path stopped working, only the other one is still validated.
+        let expired = now - Duration::from_secs(100);
+        ep.udp_paths = NodeUdpPaths::from_parts(
+            BTreeMap::from([
+                (
+                    addr_a.into(),
+                    path(addr_a, Duration::from_millis(10), expired),
+                ),
+                (addr_b.into(), path(addr_b, Duration::from_millis(20), now)),
+            ]),
+            BestAddr::from_parts(addr_a, Duration::from_millis(10), expired, expired),
+        );
+        ep.set_multipath_policy(MultipathPolicy::ActiveBackup);
+        let (udp_addr, _, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(addr_b));
+
+        ep.set_multipath_policy(MultipathPolicy::BestOnly);
+        let (udp_addr, _, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(addr_a));
+    }
+
+    #[test]
+    fn test_direct_path_failed() {
+        let now = Instant::now();
+        let key = SecretKey::generate();
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let wifi_addr = SocketAddr::new(Ipv4Addr::new(192, 168, 1, 5).into(), 1000);
+        let cell_addr = SocketAddr::new(Ipv4Addr::new(10, 20, 0, 7).into(), 1000);
+        let path = |addr: SocketAddr, latency: Duration| {
+            PathState::with_pong_reply(
+                key.public(),
+                PongReply {
+                    latency,
+                    pong_at: now,
+                    from: SendAddr::Udp(addr),
+                    pong_src: SendAddr::Udp(addr),
+                },
+            )
+        };
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        ep.udp_paths = NodeUdpPaths::from_parts(
+            BTreeMap::from([
+                (wifi_addr.into(), path(wifi_addr, Duration::from_millis(10))),
+                (cell_addr.into(), path(cell_addr, Duration::from_millis(50))),
+            ]),
+            BestAddr::from_parts(
+                wifi_addr,
+                Duration::from_millis(10),
+                now,
+                now + Duration::from_secs(100),
+            ),
+        );
+        let (udp_addr, relay, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(wifi_addr));
+        assert_eq!(relay, None);
+
+        // The WiFi went away, migrate to the other validated direct path.
+        assert_eq!(
+            ep.direct_path_failed(wifi_addr, now),
+            Some(SendAddr::Udp(cell_addr))
+        );
+        let (udp_addr, relay, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(cell_addr));
+        assert_eq!(relay, None);
+
+        // Without any direct path left, migrate to the relay path.
+        assert_eq!(
+            ep.direct_path_failed(cell_addr, now),
+            Some(SendAddr::Relay(relay_url.clone()))
+        );
+        let (_, relay, _) = ep.get_send_addrs(false);
+        assert_eq!(relay, Some(relay_url));
+    }
+
+    #[test]
+    fn test_dial_candidate_order() {
+        let key = SecretKey::generate();
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        let now = Instant::now();
+        let old_addr = SocketAddr::new(Ipv4Addr::new(192, 168, 1, 5).into(), 1000);
+        let new_addr = SocketAddr::new(Ipv4Addr::new(10, 20, 0, 7).into(), 1000);
+        let ticket = crate::magicsock::Source::NamedApp {
+            name: "ticket".into(),
+        };
+        let discovery = crate::magicsock::Source::Discovery {
+            name: "discovery".into(),
+        };
+        let addr_info = |addr: SocketAddr| AddrInfo {
+            relay_url: None,
+            direct_addresses: BTreeSet::from([addr]),
+        };
+        ep.update_from_node_addr(
+            &addr_info(old_addr),
+            ticket.clone(),
+            now - Duration::from_secs(60),
+        );
+        ep.update_from_node_addr(&addr_info(new_addr), discovery, now);
+
+        // The freshest address is tried first.
+        let (udp_addr, _, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(new_addr));
+        let addrs: Vec<_> = ep.info(now).addrs.iter().map(|info| info.addr).collect();
+        assert_eq!(addrs, vec![new_addr, old_addr]);
+
+        // Unanswered pings make an address less promising.
+        ep.udp_paths
+            .paths
+            .get_mut(&new_addr.into())
+            .unwrap()
+            .add_ping_timeout();
+        let (udp_addr, _, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(old_addr));
+
+        // An outdated relay URL does not replace a more recent one.
+        let old_relay: RelayUrl = "https://old-relay.com".parse().unwrap();
+        let old_info = AddrInfo {
+            relay_url: Some(old_relay),
+            direct_addresses: BTreeSet::new(),
+        };
+        ep.update_from_node_addr(&old_info, ticket.clone(), now - Duration::from_secs(60));
+        assert_eq!(ep.relay_url(), Some(relay_url));
+        let new_relay: RelayUrl = "https://new-relay.com".parse().unwrap();
+        let new_info = AddrInfo {
+            relay_url: Some(new_relay.clone()),
+            direct_addresses: BTreeSet::new(),
+        };
+        ep.update_from_node_addr(&new_info, ticket, Instant::now());
+        assert_eq!(ep.relay_url(), Some(new_relay));
+    }
+
+    #[test]
+    fn test_hole_punch_max_rounds() {
+        let key = SecretKey::generate();
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        let addr_info = AddrInfo {
+            relay_url: Some(relay_url),
+            direct_addresses: BTreeSet::from([SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000)]),
+        };
+        ep.update_from_node_addr(&addr_info, crate::magicsock::Source::App, Instant::now());
+        ep.set_hole_punching(
+            HolePunchConfig::default()
+                .max_rounds(2)
+                .upgrade_on_relay(false),
+        );
+
+        assert!(!ep.stayin_alive(false).is_empty());
+        assert!(!ep.stayin_alive(false).is_empty());
+        // No pong was received, we settled on the relay.
+        assert!(ep.stayin_alive(false).is_empty());
+
+        ep.note_connectivity_change();
+        assert!(!ep.stayin_alive(false).is_empty());
+
+        ep.set_hole_punching(
+            HolePunchConfig::default()
+                .max_rounds(1)
+                .upgrade_interval(Duration::ZERO),
+        );
+        assert!(!ep.stayin_alive(false).is_empty());
+    }
+
+    #[test]
+    fn test_path_policy() {
+        let key = SecretKey::generate();
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let direct_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        let addr_info = AddrInfo {
+            relay_url: Some(relay_url.clone()),
+            direct_addresses: BTreeSet::from([direct_addr]),
+        };
+        ep.update_from_node_addr(&addr_info, crate::magicsock::Source::App, Instant::now());
+
+        let (udp_addr, relay, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(direct_addr));
+        assert_eq!(relay, Some(relay_url.clone()));
+
+        ep.set_path_policy(PathPolicy::RelayOnly);
+        ep.last_call_me_maybe = None;
+        let (udp_addr, relay, msgs) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, None);
+        assert_eq!(relay, Some(relay_url.clone()));
+        assert!(msgs.iter().all(|msg| match msg {
+            PingAction::SendPing(ping) => ping.dst.is_relay(),
+            PingAction::SendCallMeMaybe { .. } => false,
+        }));
+        assert_eq!(ep.conn_type(), ConnectionType::Relay(relay_url));
+
+        ep.set_path_policy(PathPolicy::DirectOnly);
+        let (udp_addr, relay, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(direct_addr));
+        assert_eq!(relay, None);
+        assert_eq!(ep.conn_type(), ConnectionType::Direct(direct_addr));
+    }
+
+    #[test]
+    fn test_addr_family_policy() {
+        let now = Instant::now();
+        let key = SecretKey::generate();
+        let addr_v4 = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let addr_v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 1000);
+        let path = |addr: SocketAddr, latency: Duration| {
+            PathState::with_pong_reply(
+                key.public(),
+                PongReply {
+                    latency,
+                    pong_at: now,
+                    from: SendAddr::Udp(addr),
+                    pong_src: SendAddr::Udp(addr),
+                },
+            )
+        };
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        ep.udp_paths = NodeUdpPaths::from_parts(
+            BTreeMap::from([
+                (addr_v4.into(), path(addr_v4, Duration::from_millis(10))),
+                (addr_v6.into(), path(addr_v6, Duration::from_millis(20))),
+            ]),
+            BestAddr::from_parts(
+                addr_v4,
+                Duration::from_millis(10),
+                now,
+                now + Duration::from_secs(100),
+            ),
+        );
+
+        // Both families validated, the faster IPv4 path is used.
+        ep.select_path(addr_v6, now);
+        let (udp_addr, _, _) = ep.get_send_addrs(true);
+        assert_eq!(udp_addr, Some(addr_v4));
+
+        ep.set_addr_family_policy(AddrFamilyPolicy::PreferIpv6);
+        ep.select_path(addr_v6, now);
+        let (udp_addr, _, _) = ep.get_send_addrs(true);
+        assert_eq!(udp_addr, Some(addr_v6));
+
+        ep.set_addr_family_policy(AddrFamilyPolicy::Ipv4Only);
+        let (udp_addr, _, _) = ep.get_send_addrs(true);
+        assert_eq!(udp_addr, Some(addr_v4));
+        assert!(ep
+            .start_ping(SendAddr::Udp(addr_v6), DiscoPingPurpose::Discovery)
+            .is_none());
+
+        ep.set_addr_family_policy(AddrFamilyPolicy::Ipv6Only);
+        let (udp_addr, _, _) = ep.get_send_addrs(true);
+        assert_eq!(udp_addr, Some(addr_v6));
+    }
+
+    #[test]
+    fn test_keepalive_path_timeout() {
+        let now = Instant::now();
+        let key = SecretKey::generate();
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        // The last pong is too old for the default path timeout.
+        let pong_at = now - Duration::from_secs(10);
+        ep.udp_paths = NodeUdpPaths::from_parts(
+            BTreeMap::from([(
+                addr.into(),
+                PathState::with_pong_reply(
+                    key.public(),
+                    PongReply {
+                        latency: Duration::from_millis(10),
+                        pong_at,
+                        from: SendAddr::Udp(addr),
+                        pong_src: SendAddr::Udp(addr),
+                    },
+                ),
+            )]),
+            BestAddr::from_parts(
+                addr,
+                Duration::from_millis(10),
+                pong_at,
+                pong_at + TRUST_UDP_ADDR_DURATION,
+            ),
+        );
+
+        ep.select_path(addr, now);
+        let (udp_addr, relay, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(addr));
+        assert_eq!(relay, Some(relay_url));
+
+        ep.set_keepalive(KeepaliveConfig::low_power());
+        ep.select_path(addr, now);
+        let (udp_addr, relay, _) = ep.get_send_addrs(false);
+        assert_eq!(udp_addr, Some(addr));
+        assert_eq!(relay, None);
+    }
+
+    #[test]
+    fn test_same_nat_shortcut() {
+        let now = Instant::now();
+        let key = SecretKey::generate();
+        let lan_addr: SocketAddr = "192.168.1.5:1000".parse().unwrap();
+        let hairpin_addr: SocketAddr = "203.0.113.7:2000".parse().unwrap();
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        let addr_info = AddrInfo {
+            relay_url: None,
+            direct_addresses: BTreeSet::from([lan_addr, hairpin_addr]),
+        };
+        ep.update_from_node_addr(&addr_info, crate::magicsock::Source::App, Instant::now());
+        let ping_dsts = |ep: &mut NodeState| -> BTreeSet<SocketAddr> {
+            ep.send_pings(now)
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    PingAction::SendPing(SendPing {
+                        dst: SendAddr::Udp(addr),
+                        ..
+                    }) => Some(addr),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        assert!(!ep.behind_same_nat());
+        assert_eq!(ping_dsts(&mut ep), BTreeSet::from([lan_addr, hairpin_addr]));
+
+        ep.set_reflexive_ips(Arc::new(BTreeSet::from([hairpin_addr.ip()])));
+        assert!(ep.behind_same_nat());
+        assert_eq!(ping_dsts(&mut ep), BTreeSet::from([lan_addr]));
+        let stats = ep.path_stats(now);
+        let shortcut = |addr| {
+            stats
+                .iter()
+                .find(|stats| stats.path == PathAddr::Direct(addr))
+                .unwrap()
+                .same_nat_shortcut
+        };
+        assert!(shortcut(lan_addr));
+        assert!(!shortcut(hairpin_addr));
+
+        // The local address did not work, fall back to hairpinning.
+        ep.hole_punch_rounds = 1;
+        assert_eq!(ping_dsts(&mut ep), BTreeSet::from([lan_addr, hairpin_addr]));
+    }
+
+    #[tokio::test]
+    async fn test_disco_stats() {
+        let key = SecretKey::generate();
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let direct_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        let addr_info = AddrInfo {
+            relay_url: Some(relay_url),
+            direct_addresses: BTreeSet::from([direct_addr]),
+        };
+        ep.update_from_node_addr(&addr_info, crate::magicsock::Source::App, Instant::now());
+
+        ep.disco_sent(disco::MessageType::Ping, false);
+        ep.disco_sent(disco::MessageType::CallMeMaybe, true);
+        ep.disco_received(disco::MessageType::Pong, false);
+        ep.disco_received(disco::MessageType::Ping, true);
+        let stats = ep.disco_stats(Instant::now());
+        assert_eq!(
+            stats.sent_udp,
+            DiscoCounts {
+                pings: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            stats.sent_relay,
+            DiscoCounts {
+                call_me_maybes: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(stats.recv_udp.pongs, 1);
+        assert_eq!(stats.recv_relay.pings, 1);
+        assert_eq!(stats.last_direct_handshake, None);
+
+        // A pong for our ping completes the handshake on the direct path.
+        let (sender, _receiver) = mpsc::channel(1);
+        let tx_id = stun::TransactionId::default();
+        ep.ping_sent(
+            SendAddr::Udp(direct_addr),
+            tx_id,
+            DiscoPingPurpose::Discovery,
+            sender,
+        );
+        let pong = disco::Pong {
+            tx_id,
+            ping_observed_addr: SendAddr::Udp(direct_addr),
+        };
+        ep.handle_pong(&pong, SendAddr::Udp(direct_addr));
+        let stats = ep.disco_stats(Instant::now());
+        assert!(stats.last_direct_handshake.is_some());
+        assert_eq!(stats.last_relay_handshake, None);
+    }
+
+    #[test]
+    fn test_traffic_stats() {
+        let key = SecretKey::generate();
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let direct_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        let addr_info = AddrInfo {
+            relay_url: Some(relay_url.clone()),
+            direct_addresses: BTreeSet::from([direct_addr]),
+        };
+        let now = Instant::now();
+        ep.update_from_node_addr(&addr_info, crate::magicsock::Source::App, now);
+
+        ep.receive_udp(direct_addr.into(), 100, 100, now);
+        ep.receive_relay(&relay_url, key.public(), 200, now);
+        ep.add_bytes_sent(Some(direct_addr), None, 10);
+        // Mixed sends count for both transports.
+        ep.add_bytes_sent(Some(direct_addr), Some(&relay_url), 20);
+        let stats = TrafficStats {
+            direct_sent: 30,
+            direct_recv: 100,
+            relay_sent: 20,
+            relay_recv: 200,
+        };
+        assert_eq!(ep.traffic_stats(), stats);
+        assert_eq!(stats.sent(), 50);
+        assert_eq!(stats.recv(), 300);
+
+        assert_eq!(ep.reset_traffic_stats(), stats);
+        assert!(ep.traffic_stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connectivity_state() {
+        let key = SecretKey::generate();
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let direct_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::App,
+        };
+        let mut ep = NodeState::new(0, opts);
+        let phase = |ep: &NodeState| ep.connectivity_state(Instant::now()).phase;
+        assert_eq!(phase(&ep), ConnectivityPhase::NoCandidates);
+
+        let addr_info = AddrInfo {
+            relay_url: Some(relay_url),
+            direct_addresses: BTreeSet::from([direct_addr]),
+        };
+        ep.update_from_node_addr(&addr_info, crate::magicsock::Source::App, Instant::now());
+        assert_eq!(phase(&ep), ConnectivityPhase::CandidatesGathered);
+
+        // A ping without a pong falls back to the relay.
+        let (sender, _receiver) = mpsc::channel(1);
+        let tx_id = stun::TransactionId::default();
+        ep.ping_sent(
+            SendAddr::Udp(direct_addr),
+            tx_id,
+            DiscoPingPurpose::Discovery,
+            sender.clone(),
+        );
+        assert_eq!(phase(&ep), ConnectivityPhase::Probing);
+        ep.ping_timeout(tx_id);
+        let state = ep.connectivity_state(Instant::now());
+        assert_eq!(state.phase, ConnectivityPhase::RelayFallback);
+        assert_eq!(
+            state.last_failure.unwrap().reason,
+            ConnectivityReason::PongTimeout(direct_addr)
+        );
+
+        // A pong on the next attempt establishes the direct path.
+        let tx_id = stun::TransactionId::default();
+        ep.ping_sent(
+            SendAddr::Udp(direct_addr),
+            tx_id,
+            DiscoPingPurpose::Discovery,
+            sender,
+        );
+        let pong = disco::Pong {
+            tx_id,
+            ping_observed_addr: SendAddr::Udp(direct_addr),
+        };
+        ep.handle_pong(&pong, SendAddr::Udp(direct_addr));
+        let state = ep.connectivity_state(Instant::now());
+        assert_eq!(state.phase, ConnectivityPhase::Direct);
+        let transitions: Vec<_> = state
+            .transitions
+            .iter()
+            .map(|t| (t.to, t.reason.clone()))
+            .collect();
+        assert_eq!(
+            transitions,
+            vec![
+                (
+                    ConnectivityPhase::CandidatesGathered,
+                    ConnectivityReason::CandidatesLearned
+                ),
+                (
+                    ConnectivityPhase::Probing,
+                    ConnectivityReason::PingSent(direct_addr)
+                ),
+                (
+                    ConnectivityPhase::RelayFallback,
+                    ConnectivityReason::PongTimeout(direct_addr)
+                ),
+                (
+                    ConnectivityPhase::Probing,
+                    ConnectivityReason::PingSent(direct_addr)
+                ),
+                (
+                    ConnectivityPhase::Direct,
+                    ConnectivityReason::PongReceived(direct_addr)
+                ),
+            ]
+        );
+
+        // Losing the direct path falls back to the relay again.
+        ep.direct_path_failed(direct_addr, Instant::now());
+        let state = ep.connectivity_state(Instant::now());
+        assert_eq!(state.phase, ConnectivityPhase::RelayFallback);
+        assert_eq!(
+            state.transitions.last().unwrap().reason,
+            ConnectivityReason::SendFailed(direct_addr)
+        );
+    }
 }
@@ -0,0 +1,113 @@
+//! Choosing the direct path used to send to a remote node.
+
+use std::{fmt::Debug, net::SocketAddr, time::Duration};
+
+use super::best_addr::AddrLatency;
+
+/// Statistics about a direct path to a remote node, see [`PathSelector`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PathCandidate {
+    /// The address of the remote node on this path.
+    pub addr: SocketAddr,
+    /// The round-trip time measured by the most recent DISCO ping on this path.
+    pub latency: Duration,
+    /// The smoothed round-trip time over the recent DISCO pings.
+    pub smoothed_rtt: Duration,
+    /// The variation of the round-trip time, the jitter of this path.
+    pub rtt_variation: Duration,
+    /// The estimated fraction of DISCO pings lost on this path, between `0.0` and `1.0`.
+    pub loss: f32,
+    /// How long ago this path last replied to a DISCO ping.
+    pub since_validated: Duration,
+    /// Whether this is the path currently used to send data.
+    pub selected: bool,
+}
+
+/// Chooses which direct path is used to send data to a remote node.
+///
+/// The magic socket sends on a single direct path, and falls back to the relay server while
+/// no direct path works.  Whenever a direct path replies to a DISCO ping the selector is
+/// asked to choose among the direct paths which recently replied.
+///
+/// The default, [`LatencyPathSelector`], chooses the path with the lowest round-trip time.
+/// Other selectors can be set using [`Builder::path_selector`], e.g. one that prefers the
+/// path with the lowest jitter for real-time media.
+///
+/// [`Builder::path_selector`]: crate::endpoint::Builder::path_selector
+pub trait PathSelector: Debug + Send + Sync + 'static {
+    /// Returns the address of the candidate to send on.
+    ///
+    /// The `candidates` are never empty.  Returning `None`, or an address which is not one
+    /// of the candidates, keeps the currently selected path.
+    fn select(&self, candidates: &[PathCandidate]) -> Option<SocketAddr>;
+}
+
+/// Selects the path with the lowest round-trip time.
+///
+/// IPv6 paths are preferred if their latency is not more than about 10% higher than that of
+/// an IPv4 path.  This is the default [`PathSelector`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LatencyPathSelector;
+
+impl PathSelector for LatencyPathSelector {
+    fn select(&self, candidates: &[PathCandidate]) -> Option<SocketAddr> {
+        let addr_latency = |candidate: &PathCandidate| AddrLatency {
+            addr: candidate.addr,
+            latency: candidate.latency,
+        };
+        // Only switch away from the selected path if another one is better.
+        let selected = candidates.iter().find(|candidate| candidate.selected);
+        candidates
+            .iter()
+            .fold(selected.map(addr_latency), |best, candidate| {
+                let candidate = addr_latency(candidate);
+                match best {
+                    Some(best) if !candidate.is_better_than(&best) => Some(best),
+                    _ => Some(candidate),
+                }
+            })
+            .map(|best| best.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn candidate(addr: SocketAddr, latency_ms: u64, selected: bool) -> PathCandidate {
+        let latency = Duration::from_millis(latency_ms);
+        PathCandidate {
+            addr,
+            latency,
+            smoothed_rtt: latency,
+            rtt_variation: Duration::ZERO,
+            loss: 0.0,
+            since_validated: Duration::ZERO,
+            selected,
+        }
+    }
+
+    #[test]
+    fn test_latency_path_selector() {
+        let v4_a = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1);
+        let v4_b = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 2);
+        let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 3);
+        let selector = LatencyPathSelector;
+
+        let candidates = [candidate(v4_a, 20, false), candidate(v4_b, 10, false)];
+        assert_eq!(selector.select(&candidates), Some(v4_b));
+
+        // Equal latency keeps the selected path.
+        let candidates = [candidate(v4_a, 10, true), candidate(v4_b, 10, false)];
+        assert_eq!(selector.select(&candidates), Some(v4_a));
+
+        // IPv6 wins with slightly higher latency.
+        let candidates = [candidate(v4_a, 100, true), candidate(v6, 105, false)];
+        assert_eq!(selector.select(&candidates), Some(v6));
+        let candidates = [candidate(v4_a, 100, true), candidate(v6, 150, false)];
+        assert_eq!(selector.select(&candidates), Some(v4_a));
+    }
+}
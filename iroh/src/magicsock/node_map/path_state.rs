@@ -1,6 +1,7 @@
 //! The state kept for each network path to a remote node.
 
 use std::{
+    cmp::Ordering,
     collections::{BTreeMap, HashMap},
     net::SocketAddr,
     time::{Duration, Instant},
@@ -11,23 +12,53 @@ use iroh_relay::protos::stun;
 use tracing::{debug, event, Level};
 
 use super::{
-    node_state::{ControlMsg, PongReply, SESSION_ACTIVE_TIMEOUT},
+    node_state::{ControlMsg, NodePathStats, PathAddr, PongReply, SESSION_ACTIVE_TIMEOUT},
+    path_selector::PathCandidate,
     IpPort, PingRole, Source,
 };
-use crate::{disco::SendAddr, magicsock::HEARTBEAT_INTERVAL};
+use crate::disco::SendAddr;
 
 /// The minimum time between pings to an endpoint.
 ///
 /// Except in the case of CallMeMaybe frames resetting the counter, as the first pings
 /// likely didn't through the firewall.
-const DISCO_PING_INTERVAL: Duration = Duration::from_secs(5);
+pub(super) const DISCO_PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The weight of a new ping result in the loss estimate of a path.
+const LOSS_WEIGHT: f32 = 0.125;
+
+/// The smoothed round-trip time of a path, as described in RFC 6298.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RttEstimate {
+    smoothed: Duration,
+    variation: Duration,
+}
+
+impl RttEstimate {
+    fn new(rtt: Duration) -> Self {
+        Self {
+            smoothed: rtt,
+            variation: rtt / 2,
+        }
+    }
+
+    fn update(&mut self, rtt: Duration) {
+        let deviation = if rtt > self.smoothed {
+            rtt - self.smoothed
+        } else {
+            self.smoothed - rtt
+        };
+        self.variation = (self.variation * 3 + deviation) / 4;
+        self.smoothed = (self.smoothed * 7 + rtt) / 8;
+    }
+}
 
 /// State about a particular path to another [`NodeState`].
 ///
 /// This state is used for both the relay path and any direct UDP paths.
 ///
 /// [`NodeState`]: super::node_state::NodeState
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub(super) struct PathState {
     /// The node for which this path exists.
     node_id: NodeId,
@@ -49,6 +80,16 @@ pub(super) struct PathState {
     /// Previous replies are cleared when they are no longer relevant to determine whether
     /// this path can still be used to reach the remote node.
     pub(super) recent_pong: Option<PongReply>,
+    /// The round-trip time over all the pongs received on this path.
+    rtt: Option<RttEstimate>,
+    /// The estimated fraction of pings lost on this path.
+    loss: f32,
+    /// The number of payload bytes sent on this path.
+    pub(super) bytes_sent: u64,
+    /// The number of payload bytes received on this path.
+    pub(super) bytes_recv: u64,
+    /// The size of the largest QUIC datagram received on this path.
+    pub(super) max_recv_datagram: Option<u16>,
     /// When the last payload data was **received** via this path.
     ///
     /// This excludes DISCO messages.
@@ -72,6 +113,11 @@ impl PathState {
             last_got_ping: None,
             call_me_maybe_time: None,
             recent_pong: None,
+            rtt: None,
+            loss: 0.0,
+            bytes_sent: 0,
+            bytes_recv: 0,
+            max_recv_datagram: None,
             last_payload_msg: None,
             sources,
         }
@@ -99,6 +145,11 @@ impl PathState {
             last_got_ping: None,
             call_me_maybe_time: None,
             recent_pong: None,
+            rtt: None,
+            loss: 0.0,
+            bytes_sent: 0,
+            bytes_recv: 0,
+            max_recv_datagram: None,
             last_payload_msg: Some(now),
             sources,
         }
@@ -112,7 +163,8 @@ impl PathState {
         now: Instant,
     ) -> Self {
         let mut new = PathState::new(node_id, path, source, now);
-        new.handle_ping(tx_id, now);
+        // Without a previous ping the heartbeat interval does not matter.
+        new.handle_ping(tx_id, now, Duration::ZERO);
         new
     }
 
@@ -128,9 +180,19 @@ impl PathState {
                 );
             }
         }
+        match self.rtt {
+            Some(ref mut rtt) => rtt.update(r.latency),
+            None => self.rtt = Some(RttEstimate::new(r.latency)),
+        }
+        self.loss -= self.loss * LOSS_WEIGHT;
         self.recent_pong = Some(r);
     }
 
+    /// Records that a ping sent on this path was not answered in time.
+    pub(super) fn add_ping_timeout(&mut self) {
+        self.loss += (1.0 - self.loss) * LOSS_WEIGHT;
+    }
+
     #[cfg(test)]
     pub(super) fn with_pong_reply(node_id: NodeId, r: PongReply) -> Self {
         PathState {
@@ -139,7 +201,12 @@ impl PathState {
             last_ping: None,
             last_got_ping: None,
             call_me_maybe_time: None,
+            rtt: Some(RttEstimate::new(r.latency)),
             recent_pong: Some(r),
+            loss: 0.0,
+            bytes_sent: 0,
+            bytes_recv: 0,
+            max_recv_datagram: None,
             last_payload_msg: None,
             sources: HashMap::new(),
         }
@@ -187,6 +254,14 @@ impl PathState {
             .copied()
     }
 
+    /// Whether a pong was received on this path within the `path_timeout`, recently enough
+    /// to trust it without a backup path.
+    pub(super) fn is_validated(&self, now: Instant, path_timeout: Duration) -> bool {
+        self.recent_pong
+            .as_ref()
+            .is_some_and(|pong| now.saturating_duration_since(pong.pong_at) <= path_timeout)
+    }
+
     /// The last control or DISCO message **about** this path.
     ///
     /// This is the most recent instant among:
@@ -222,7 +297,52 @@ impl PathState {
         self.recent_pong.as_ref().map(|p| p.latency)
     }
 
-    pub(super) fn needs_ping(&self, now: &Instant) -> bool {
+    /// Returns the statistics for the [`PathSelector`], if this is a UDP path with a pong.
+    ///
+    /// [`PathSelector`]: super::path_selector::PathSelector
+    pub(super) fn candidate(&self, now: Instant, selected: bool) -> Option<PathCandidate> {
+        let addr = self.udp_addr()?;
+        let pong = self.recent_pong.as_ref()?;
+        let rtt = self.rtt.unwrap_or_else(|| RttEstimate::new(pong.latency));
+        Some(PathCandidate {
+            addr,
+            latency: pong.latency,
+            smoothed_rtt: rtt.smoothed,
+            rtt_variation: rtt.variation,
+            loss: self.loss,
+            since_validated: now.saturating_duration_since(pong.pong_at),
+            selected,
+        })
+    }
+
+    /// Returns the statistics of this path, see [`NodePathStats`].
+    pub(super) fn stats(&self, now: Instant, active: bool) -> NodePathStats {
+        let path = match self.path {
+            SendAddr::Udp(addr) => PathAddr::Direct(addr),
+            SendAddr::Relay(ref url) => PathAddr::Relay(url.clone()),
+        };
+        NodePathStats {
+            path,
+            active,
+            latency: self.latency(),
+            smoothed_rtt: self.rtt.map(|rtt| rtt.smoothed),
+            rtt_variation: self.rtt.map(|rtt| rtt.variation),
+            loss: self.loss,
+            bytes_sent: self.bytes_sent,
+            bytes_recv: self.bytes_recv,
+            mtu: self.max_recv_datagram,
+            last_validated: self
+                .recent_pong
+                .as_ref()
+                .map(|pong| now.saturating_duration_since(pong.pong_at)),
+            last_payload: self
+                .last_payload_msg
+                .map(|instant| now.saturating_duration_since(instant)),
+            same_nat_shortcut: false,
+        }
+    }
+
+    pub(super) fn needs_ping(&self, now: &Instant, interval: Duration) -> bool {
         match self.last_ping {
             None => true,
             Some(last_ping) => {
@@ -234,17 +354,22 @@ impl PathState {
                 // if !needs_ping {
                 //     debug!("ping is too new: {}ms", elapsed.as_millis());
                 // }
-                elapsed > DISCO_PING_INTERVAL
+                elapsed > interval
             }
         }
     }
 
-    pub(super) fn handle_ping(&mut self, tx_id: stun::TransactionId, now: Instant) -> PingRole {
+    pub(super) fn handle_ping(
+        &mut self,
+        tx_id: stun::TransactionId,
+        now: Instant,
+        heartbeat_interval: Duration,
+    ) -> PingRole {
         if Some(&tx_id) == self.last_got_ping.as_ref().map(|(_t, tx_id)| tx_id) {
             PingRole::Duplicate
         } else {
             let prev = self.last_got_ping.replace((now, tx_id));
-            let heartbeat_deadline = HEARTBEAT_INTERVAL + (HEARTBEAT_INTERVAL / 2);
+            let heartbeat_deadline = heartbeat_interval + (heartbeat_interval / 2);
             match prev {
                 Some((prev_time, _tx)) if now.duration_since(prev_time) <= heartbeat_deadline => {
                     PingRole::LikelyHeartbeat
@@ -272,8 +397,33 @@ impl PathState {
         }
     }
 
-    pub(super) fn add_source(&mut self, source: Source, now: Instant) {
-        self.sources.insert(source, now);
+    /// Records that `source` reported this path, it last saw the path at `seen_at`.
+    ///
+    /// Only the most recent time is kept for each source, so older information reported
+    /// later does not make the path look fresher.
+    pub(super) fn add_source(&mut self, source: Source, seen_at: Instant) {
+        let last_seen = self.sources.entry(source).or_insert(seen_at);
+        *last_seen = (*last_seen).max(seen_at);
+    }
+
+    /// Returns the most recent time any source reported this path.
+    pub(super) fn last_reported(&self) -> Option<Instant> {
+        self.sources.values().max().copied()
+    }
+
+    /// Compares how promising the paths are to dial, the better path is [`Ordering::Less`].
+    ///
+    /// Paths which were alive more recently come first.  Paths never known to be alive are
+    /// ordered by their estimated loss, so paths whose pings went unanswered come last,
+    /// and then by how recently they were reported, so stale addresses are tried after
+    /// fresh ones.
+    pub(super) fn dial_cmp(&self, other: &Self) -> Ordering {
+        // `None` is less than `Some`, compare in reverse to put the most recent first.
+        other
+            .last_alive()
+            .cmp(&self.last_alive())
+            .then_with(|| self.loss.total_cmp(&other.loss))
+            .then_with(|| other.last_reported().cmp(&self.last_reported()))
     }
 
     pub(super) fn clear(&mut self) {
@@ -281,6 +431,9 @@ impl PathState {
         self.last_got_ping = None;
         self.call_me_maybe_time = None;
         self.recent_pong = None;
+        self.rtt = None;
+        self.loss = 0.0;
+        self.max_recv_datagram = None;
     }
 
     fn summary(&self, mut w: impl std::fmt::Write) -> std::fmt::Result {
@@ -11,12 +11,12 @@ use std::{
     time::{Duration, Instant},
 };
 
-use rand::seq::IteratorRandom;
 use tracing::warn;
 
 use super::{
     best_addr::{self, BestAddr},
-    node_state::PongReply,
+    node_state::{AddrFamilyPolicy, KeepaliveConfig, MultipathPolicy, PongReply},
+    path_selector::PathCandidate,
     path_state::PathState,
     IpPort,
 };
@@ -77,8 +77,12 @@ pub(super) struct NodeUdpPaths {
     pub(super) paths: BTreeMap<IpPort, PathState>,
     /// Best UDP path currently selected.
     pub(super) best_addr: BestAddr,
-    /// If we had to choose a path because we had no `best_addr` it is stored here.
-    chosen_candidate: Option<IpPort>,
+    /// Counts the datagrams striped over the validated paths by [`Self::stripe_addr`].
+    stripe_counter: usize,
+    /// Which address families may be used.
+    pub(super) addr_family: AddrFamilyPolicy,
+    /// How the paths are kept alive.
+    pub(super) keepalive: KeepaliveConfig,
 }
 
 impl NodeUdpPaths {
@@ -91,7 +95,9 @@ impl NodeUdpPaths {
         Self {
             paths,
             best_addr,
-            chosen_candidate: None,
+            stripe_counter: 0,
+            addr_family: AddrFamilyPolicy::Any,
+            keepalive: KeepaliveConfig::default(),
         }
     }
 
@@ -100,35 +106,128 @@ impl NodeUdpPaths {
     /// TODO: The goal here is for this to simply return the already known send address, so
     /// it should be `&self` and not `&mut self`.  This is only possible once the state from
     /// [`NodeUdpPaths`] is no longer modified from outside.
-    pub(super) fn send_addr(&mut self, now: Instant, have_ipv6: bool) -> UdpSendAddr {
+    ///
+    /// Unless the policy is [`MultipathPolicy::BestOnly`], another validated path is used
+    /// when the best address is not valid.
+    pub(super) fn send_addr(
+        &mut self,
+        now: Instant,
+        have_ipv6: bool,
+        policy: MultipathPolicy,
+    ) -> UdpSendAddr {
+        match self.best_send_addr(now, have_ipv6) {
+            UdpSendAddr::Valid(addr) => UdpSendAddr::Valid(addr),
+            addr if policy == MultipathPolicy::BestOnly => addr,
+            addr => match self.backup_addr(now, have_ipv6) {
+                Some(backup) => UdpSendAddr::Valid(backup),
+                None => addr,
+            },
+        }
+    }
+
+    /// Returns the next of the validated paths to send a datagram on, in turn.
+    ///
+    /// This is used to spread the datagrams over all paths with
+    /// [`MultipathPolicy::Aggregate`].
+    pub(super) fn stripe_addr(&mut self, now: Instant, have_ipv6: bool) -> Option<SocketAddr> {
+        let count = self.validated_addrs(now, have_ipv6).count();
+        if count == 0 {
+            return None;
+        }
+        let index = self.stripe_counter % count;
+        self.stripe_counter = self.stripe_counter.wrapping_add(1);
+        self.validated_addrs(now, have_ipv6).nth(index)
+    }
+
+    /// Returns the paths the [`PathSelector`] may choose from.
+    ///
+    /// These are the validated paths, and the best address as long as it is trusted.  If
+    /// the [`AddrFamilyPolicy`] prefers a family and any of these paths is of that family,
+    /// only the paths of the preferred family are returned.
+    ///
+    /// [`PathSelector`]: super::path_selector::PathSelector
+    pub(super) fn path_candidates(&self, now: Instant) -> Vec<PathCandidate> {
+        let selected = match self.best_addr.state(now) {
+            best_addr::State::Valid(addr) => Some(addr.addr),
+            best_addr::State::Outdated(_) | best_addr::State::Empty => None,
+        };
+        let family = self.addr_family;
+        let mut candidates: Vec<PathCandidate> = self
+            .paths
+            .values()
+            .filter_map(|path| {
+                let is_selected = selected.is_some() && path.udp_addr() == selected;
+                if !is_selected && !path.is_validated(now, self.keepalive.path_timeout) {
+                    return None;
+                }
+                path.candidate(now, is_selected)
+            })
+            .filter(|candidate| family.allows(candidate.addr))
+            .collect();
+        if candidates
+            .iter()
+            .any(|candidate| family.prefers(candidate.addr))
+        {
+            candidates.retain(|candidate| family.prefers(candidate.addr));
+        }
+        candidates
+    }
+
+    /// Returns the validated path with the lowest latency, preferring the preferred family.
+    fn backup_addr(&self, now: Instant, have_ipv6: bool) -> Option<SocketAddr> {
+        self.paths
+            .values()
+            .filter(|path| path.is_validated(now, self.keepalive.path_timeout))
+            .filter_map(|path| Some((path.udp_addr()?, path.latency()?)))
+            .filter(|(addr, _)| self.is_usable(*addr, have_ipv6))
+            .min_by_key(|(addr, latency)| (!self.addr_family.prefers(*addr), *latency))
+            .map(|(addr, _)| addr)
+    }
+
+    /// Returns the addresses of all paths which recently received a pong.
+    pub(super) fn validated_addrs(
+        &self,
+        now: Instant,
+        have_ipv6: bool,
+    ) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.paths
+            .values()
+            .filter(move |path| path.is_validated(now, self.keepalive.path_timeout))
+            .filter_map(|path| path.udp_addr())
+            .filter(move |addr| self.is_usable(*addr, have_ipv6))
+    }
+
+    /// Whether datagrams may be sent to `addr`.
+    fn is_usable(&self, addr: SocketAddr, have_ipv6: bool) -> bool {
+        (addr.is_ipv4() || have_ipv6) && self.addr_family.allows(addr)
+    }
+
+    /// Returns the UDP address to send on based on the best address only.
+    fn best_send_addr(&mut self, now: Instant, have_ipv6: bool) -> UdpSendAddr {
         self.assign_best_addr_from_candidates_if_empty();
         match self.best_addr.state(now) {
             best_addr::State::Valid(addr) => UdpSendAddr::Valid(addr.addr),
             best_addr::State::Outdated(addr) => UdpSendAddr::Outdated(addr.addr),
             best_addr::State::Empty => {
                 // No direct connection has been used before.  If we know of any possible
-                // candidate addresses, randomly try to use one.  This path is most
+                // candidate addresses, try the most promising one.  This path is most
                 // effective when folks use a NodeAddr with exactly one direct address which
                 // they know to work, effectively like using a traditional socket or QUIC
                 // endpoint.
+                //
+                // The candidates are ranked again for every datagram, so a fresher address
+                // learned from discovery is picked up right away.  Until a path is
+                // confirmed there are few datagrams and the number of paths is bounded by
+                // pruning.
                 let addr = self
-                    .chosen_candidate
-                    .and_then(|ipp| self.paths.get(&ipp))
-                    .and_then(|path| path.udp_addr())
-                    .filter(|addr| addr.is_ipv4() || have_ipv6)
-                    .or_else(|| {
-                        // Look for a new candidate in all the known paths.  This may look
-                        // like a RNG use on the hot-path but this is normally invoked at
-                        // most most once at startup.
-                        let addr = self
-                            .paths
-                            .values()
-                            .filter_map(|path| path.udp_addr())
-                            .filter(|addr| addr.is_ipv4() || have_ipv6)
-                            .choose(&mut rand::thread_rng());
-                        self.chosen_candidate = addr.map(IpPort::from);
-                        addr
-                    });
+                    .paths
+                    .values()
+                    .filter(|path| {
+                        path.udp_addr()
+                            .is_some_and(|addr| self.is_usable(addr, have_ipv6))
+                    })
+                    .min_by(|a, b| a.dial_cmp(b))
+                    .and_then(|path| path.udp_addr());
                 match addr {
                     Some(addr) => UdpSendAddr::Unconfirmed(addr),
                     None => UdpSendAddr::None,
@@ -150,7 +249,11 @@ impl NodeUdpPaths {
         // The highest acceptable latency for an endpoint path.  If the latency is higher
         // then this the path will be ignored.
         const MAX_LATENCY: Duration = Duration::from_secs(60 * 60);
+        let family = self.addr_family;
         let best_pong = self.paths.iter().fold(None, |best_pong, (ipp, state)| {
+            if !family.allows((*ipp).into()) {
+                return best_pong;
+            }
             let best_latency = best_pong
                 .map(|p: &PongReply| p.latency)
                 .unwrap_or(MAX_LATENCY);
@@ -176,6 +279,7 @@ impl NodeUdpPaths {
                     pong.latency,
                     best_addr::Source::BestCandidate,
                     pong.pong_at,
+                    self.keepalive.path_timeout,
                 )
             }
         }
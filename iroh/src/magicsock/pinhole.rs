@@ -0,0 +1,450 @@
+//! IPv6 firewall pinholes requested with PCP.
+//!
+//! IPv6 addresses are usually globally routable, but most home routers run a stateful
+//! firewall which drops unsolicited inbound packets.  Such a router can be asked to open a
+//! pinhole for our IPv6 socket with a PCP MAP request, see [RFC 6887].  The pinholed address
+//! is then advertised among the direct addresses, so other nodes can connect directly even
+//! when hole punching fails.
+//!
+//! The PCP server is expected on the default IPv6 gateway, which is currently only
+//! discovered on Linux.  On other platforms no pinhole is requested.
+//!
+//! [RFC 6887]: https://datatracker.ietf.org/doc/html/rfc6887
+
+use std::{
+    net::{Ipv6Addr, SocketAddrV6},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+    time::{self, Instant},
+};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, info_span, trace, Instrument};
+
+/// The port the PCP server listens on.
+const PCP_SERVER_PORT: u16 = 5351;
+
+/// The PCP protocol version.
+const PCP_VERSION: u8 = 2;
+
+/// The opcode of a MAP request, responses have the high bit set.
+const OPCODE_MAP: u8 = 1;
+
+/// Bit set in the opcode of a response.
+const RESPONSE_BIT: u8 = 0x80;
+
+/// The IANA protocol number of UDP.
+const PROTOCOL_UDP: u8 = 17;
+
+/// The result code of a successful response.
+const RESULT_SUCCESS: u8 = 0;
+
+/// Size of a MAP request and response.
+const MAP_PACKET_LEN: usize = 60;
+
+/// The lifetime requested for a pinhole, it is renewed after half of the granted lifetime.
+const REQUESTED_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// The shortest interval at which a pinhole is renewed.
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait for a response before retransmitting a request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often a request is sent before giving up.
+const MAX_ATTEMPTS: usize = 3;
+
+/// How long to wait before asking again after a router did not grant a pinhole.
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(5 * 60);
+
+/// Requests and renews an IPv6 pinhole for the local socket.
+#[derive(Debug)]
+pub(super) struct Client {
+    enabled: bool,
+    sender: mpsc::Sender<Message>,
+    external_addr: watch::Receiver<Option<SocketAddrV6>>,
+    _task: AbortOnDropHandle<()>,
+}
+
+#[derive(Debug)]
+enum Message {
+    /// Makes sure a pinhole for this local address is held.
+    Procure(SocketAddrV6),
+    /// Releases the pinhole.
+    Deactivate,
+}
+
+impl Client {
+    /// Creates a new client, no pinhole is requested if `enabled` is false.
+    pub(super) fn new(enabled: bool) -> Self {
+        let (sender, receiver) = mpsc::channel(4);
+        let (external_addr_sender, external_addr) = watch::channel(None);
+        let actor = Actor {
+            receiver,
+            external_addr: external_addr_sender,
+            mapping: None,
+            last_failure: None,
+        };
+        let task = tokio::spawn(actor.run().instrument(info_span!("pinhole")));
+        Self {
+            enabled,
+            sender,
+            external_addr,
+            _task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Requests a pinhole for the socket bound to `local`, unless one is already held.
+    ///
+    /// `local` must be a global unicast address of this host, not the unspecified address.
+    pub(super) fn procure(&self, local: SocketAddrV6) {
+        if self.enabled {
+            self.sender.try_send(Message::Procure(local)).ok();
+        }
+    }
+
+    /// Releases the pinhole, if one is held.
+    pub(super) fn deactivate(&self) {
+        if self.enabled {
+            self.sender.try_send(Message::Deactivate).ok();
+        }
+    }
+
+    /// Watches the external address of the pinhole.
+    ///
+    /// Without a NAT this is the same as the local address, but it only becomes `Some`
+    /// once the router confirmed the pinhole.
+    pub(super) fn watch_external_address(&self) -> watch::Receiver<Option<SocketAddrV6>> {
+        self.external_addr.clone()
+    }
+}
+
+/// A pinhole granted by the router.
+#[derive(Debug, Clone)]
+struct Mapping {
+    local: SocketAddrV6,
+    gateway: SocketAddrV6,
+    nonce: [u8; 12],
+    external: SocketAddrV6,
+    renew_at: Instant,
+}
+
+#[derive(Debug)]
+struct Actor {
+    receiver: mpsc::Receiver<Message>,
+    external_addr: watch::Sender<Option<SocketAddrV6>>,
+    mapping: Option<Mapping>,
+    /// The local address and time of the last failed request.
+    last_failure: Option<(SocketAddrV6, Instant)>,
+}
+
+impl Actor {
+    async fn run(mut self) {
+        loop {
+            let renew_at = self.mapping.as_ref().map(|mapping| mapping.renew_at);
+            let renew = async move {
+                match renew_at {
+                    Some(renew_at) => time::sleep_until(renew_at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Some(Message::Procure(local)) => self.procure(local).await,
+                    Some(Message::Deactivate) => self.release().await,
+                    None => break,
+                },
+                _ = renew => {
+                    if let Some(mapping) = self.mapping.take() {
+                        trace!(external = %mapping.external, "renewing pinhole");
+                        self.request(mapping.local, Some(mapping.nonce)).await;
+                    }
+                }
+            }
+        }
+        self.release().await;
+    }
+
+    async fn procure(&mut self, local: SocketAddrV6) {
+        if let Some(ref mapping) = self.mapping {
+            if mapping.local == local {
+                return;
+            }
+            self.release().await;
+        }
+        if let Some((failed_local, at)) = self.last_failure {
+            if failed_local == local && at.elapsed() < RETRY_AFTER_FAILURE {
+                return;
+            }
+        }
+        self.request(local, None).await;
+    }
+
+    /// Requests a pinhole, renewing an existing one if its nonce is given.
+    async fn request(&mut self, local: SocketAddrV6, nonce: Option<[u8; 12]>) {
+        let nonce = nonce.unwrap_or_else(rand::random);
+        match request_mapping(local, nonce, REQUESTED_LIFETIME).await {
+            Ok((gateway, response)) => {
+                let renew_in = (response.lifetime / 2).max(MIN_RENEW_INTERVAL);
+                let external =
+                    SocketAddrV6::new(response.external_ip, response.external_port, 0, 0);
+                debug!(%external, ?renew_in, "pinhole acquired");
+                self.mapping = Some(Mapping {
+                    local,
+                    gateway,
+                    nonce,
+                    external,
+                    renew_at: Instant::now() + renew_in,
+                });
+                self.last_failure = None;
+                self.set_external_addr(Some(external));
+            }
+            Err(err) => {
+                debug!(%local, "no pinhole: {err:#}");
+                self.mapping = None;
+                self.last_failure = Some((local, Instant::now()));
+                self.set_external_addr(None);
+            }
+        }
+    }
+
+    /// Publishes the external address, watchers are only notified when it changed.
+    fn set_external_addr(&self, addr: Option<SocketAddrV6>) {
+        self.external_addr.send_if_modified(|current| {
+            let changed = *current != addr;
+            *current = addr;
+            changed
+        });
+    }
+
+    /// Deletes the pinhole on the router, by requesting a lifetime of zero.
+    async fn release(&mut self) {
+        let Some(mapping) = self.mapping.take() else {
+            return;
+        };
+        self.set_external_addr(None);
+        let request = MapRequest {
+            lifetime: Duration::ZERO,
+            client_ip: *mapping.local.ip(),
+            nonce: mapping.nonce,
+            internal_port: mapping.local.port(),
+        };
+        let res = async {
+            let socket = UdpSocket::bind(SocketAddrV6::new(*mapping.local.ip(), 0, 0, 0)).await?;
+            socket.send_to(&request.encode(), mapping.gateway).await
+        };
+        if let Err(err) = res.await {
+            debug!("failed to release pinhole: {err:#}");
+        }
+    }
+}
+
+/// Sends a MAP request to the default gateway and waits for a successful response.
+async fn request_mapping(
+    local: SocketAddrV6,
+    nonce: [u8; 12],
+    lifetime: Duration,
+) -> Result<(SocketAddrV6, MapResponse)> {
+    let gateway = default_gateway_v6().context("no default IPv6 gateway")?;
+    // The source address of the request must match the client address in it.
+    let socket = UdpSocket::bind(SocketAddrV6::new(*local.ip(), 0, 0, 0)).await?;
+    let request = MapRequest {
+        lifetime,
+        client_ip: *local.ip(),
+        nonce,
+        internal_port: local.port(),
+    }
+    .encode();
+    let mut buf = [0u8; 1100];
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send_to(&request, gateway).await?;
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        while let Ok(res) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, from) = res?;
+            if from.ip() != gateway.ip() {
+                continue;
+            }
+            match MapResponse::decode(&buf[..len], &nonce, local.port()) {
+                Ok(response) => return Ok((gateway, response)),
+                Err(err) => bail!("router refused pinhole: {err:#}"),
+            }
+        }
+    }
+    bail!("no response from {gateway}")
+}
+
+/// A PCP MAP request for UDP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MapRequest {
+    lifetime: Duration,
+    client_ip: Ipv6Addr,
+    nonce: [u8; 12],
+    internal_port: u16,
+}
+
+impl MapRequest {
+    fn encode(&self) -> [u8; MAP_PACKET_LEN] {
+        let mut buf = [0u8; MAP_PACKET_LEN];
+        // Common request header.
+        buf[0] = PCP_VERSION;
+        buf[1] = OPCODE_MAP;
+        let lifetime = u32::try_from(self.lifetime.as_secs()).unwrap_or(u32::MAX);
+        buf[4..8].copy_from_slice(&lifetime.to_be_bytes());
+        buf[8..24].copy_from_slice(&self.client_ip.octets());
+        // MAP opcode data.
+        buf[24..36].copy_from_slice(&self.nonce);
+        buf[36] = PROTOCOL_UDP;
+        buf[40..42].copy_from_slice(&self.internal_port.to_be_bytes());
+        // Suggest the same port and address, a firewall does not translate them.
+        buf[42..44].copy_from_slice(&self.internal_port.to_be_bytes());
+        buf[44..60].copy_from_slice(&self.client_ip.octets());
+        buf
+    }
+}
+
+/// A successful PCP MAP response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MapResponse {
+    lifetime: Duration,
+    external_port: u16,
+    external_ip: Ipv6Addr,
+}
+
+impl MapResponse {
+    /// Decodes a response to the request with the given nonce and internal port.
+    fn decode(buf: &[u8], nonce: &[u8; 12], internal_port: u16) -> Result<Self> {
+        ensure!(buf.len() >= MAP_PACKET_LEN, "response too short");
+        ensure!(buf[0] == PCP_VERSION, "unsupported version {}", buf[0]);
+        ensure!(buf[1] == RESPONSE_BIT | OPCODE_MAP, "unexpected opcode");
+        ensure!(buf[3] == RESULT_SUCCESS, "result code {}", buf[3]);
+        ensure!(&buf[24..36] == nonce, "nonce mismatch");
+        ensure!(buf[36] == PROTOCOL_UDP, "protocol mismatch");
+        ensure!(
+            u16::from_be_bytes([buf[40], buf[41]]) == internal_port,
+            "internal port mismatch"
+        );
+        let lifetime = u32::from_be_bytes(buf[4..8].try_into().expect("checked length"));
+        let external_port = u16::from_be_bytes([buf[42], buf[43]]);
+        let external_ip: [u8; 16] = buf[44..60].try_into().expect("checked length");
+        Ok(Self {
+            lifetime: Duration::from_secs(lifetime.into()),
+            external_port,
+            external_ip: external_ip.into(),
+        })
+    }
+}
+
+/// Finds the address of the PCP server on the default IPv6 gateway.
+#[cfg(target_os = "linux")]
+fn default_gateway_v6() -> Option<SocketAddrV6> {
+    let routes = std::fs::read_to_string("/proc/net/ipv6_route").ok()?;
+    let (gateway, interface) = parse_ipv6_routes(&routes)?;
+    // The gateway is usually link-local, which needs the interface as scope.
+    let scope_id = match netwatch::ip::is_unicast_link_local(gateway) {
+        true => std::fs::read_to_string(format!("/sys/class/net/{interface}/ifindex"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?,
+        false => 0,
+    };
+    Some(SocketAddrV6::new(gateway, PCP_SERVER_PORT, 0, scope_id))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway_v6() -> Option<SocketAddrV6> {
+    None
+}
+
+/// Returns the next hop and interface of the default route in `/proc/net/ipv6_route`.
+///
+/// Each line holds the destination, its prefix length, the source, its prefix length, the
+/// next hop, the metric, reference and use counts, flags and the interface name.
+#[cfg(any(target_os = "linux", test))]
+fn parse_ipv6_routes(routes: &str) -> Option<(Ipv6Addr, &str)> {
+    routes.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [dest, dest_len, _, _, next_hop, _, _, _, _, interface] = fields[..] else {
+            return None;
+        };
+        let dest = u128::from_str_radix(dest, 16).ok()?;
+        if dest != 0 || dest_len != "00" {
+            return None;
+        }
+        let next_hop = Ipv6Addr::from(u128::from_str_radix(next_hop, 16).ok()?);
+        if next_hop.is_unspecified() {
+            return None;
+        }
+        Some((next_hop, interface))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_roundtrip() {
+        let client_ip: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let nonce = [7u8; 12];
+        let request = MapRequest {
+            lifetime: REQUESTED_LIFETIME,
+            client_ip,
+            nonce,
+            internal_port: 1234,
+        };
+        let buf = request.encode();
+        assert_eq!(buf[0], 2);
+        assert_eq!(buf[1], 1);
+        assert_eq!(&buf[4..8], &7200u32.to_be_bytes());
+        assert_eq!(&buf[8..24], &client_ip.octets());
+        assert_eq!(&buf[44..60], &client_ip.octets());
+
+        // A server answers with the response bit, a result code, epoch and the assigned
+        // address, in this case the same as requested.
+        let mut response = buf;
+        response[1] |= RESPONSE_BIT;
+        response[4..8].copy_from_slice(&600u32.to_be_bytes());
+        response[8..24].fill(0);
+        response[8..12].copy_from_slice(&42u32.to_be_bytes());
+        let decoded = MapResponse::decode(&response, &nonce, 1234).unwrap();
+        assert_eq!(
+            decoded,
+            MapResponse {
+                lifetime: Duration::from_secs(600),
+                external_port: 1234,
+                external_ip: client_ip,
+            }
+        );
+
+        assert!(MapResponse::decode(&response, &[8u8; 12], 1234).is_err());
+        assert!(MapResponse::decode(&response, &nonce, 4321).is_err());
+        assert!(MapResponse::decode(&response[..30], &nonce, 1234).is_err());
+        let mut refused = response;
+        refused[3] = 2; // NOT_AUTHORIZED
+        assert!(MapResponse::decode(&refused, &nonce, 1234).is_err());
+    }
+
+    #[test]
+    fn test_parse_ipv6_routes() {
+        let routes = "\
+20010db8000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe80000000000000021122fffe334455 00000400 00000003 00000000 00450003     eth0
+00000000000000000000000000000001 80 00000000000000000000000000000000 00 00000000000000000000000000000000 00000000 00000002 00000000 80200001       lo
+";
+        let (gateway, interface) = parse_ipv6_routes(routes).unwrap();
+        assert_eq!(
+            gateway,
+            "fe80::211:22ff:fe33:4455".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(interface, "eth0");
+
+        // Without a default route there is no gateway.
+        let routes = routes.lines().next().unwrap();
+        assert!(parse_ipv6_routes(routes).is_none());
+    }
+}
@@ -0,0 +1,153 @@
+//! Configuration and status of the port mappings requested from the router.
+
+use std::net::SocketAddrV4;
+
+/// Which protocols are used to request a port mapping from the router.
+///
+/// A port mapping makes this endpoint reachable on the public address of the router, which
+/// allows other nodes to connect directly even when hole punching fails.  The router is
+/// asked with each protocol enabled here, and the first one to answer is used.  The mapping
+/// requests the same external port as the local port, which can be set with
+/// [`Builder::bind_addr_v4`].  The lifetime of the mapping is chosen by the protocol and it
+/// is renewed before it expires.
+///
+/// See [`Builder::port_mapping`].  All protocols are enabled by default.
+///
+/// [`Builder::bind_addr_v4`]: crate::endpoint::Builder::bind_addr_v4
+/// [`Builder::port_mapping`]: crate::endpoint::Builder::port_mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMappingConfig {
+    upnp: bool,
+    nat_pmp: bool,
+    pcp: bool,
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> Self {
+        Self {
+            upnp: true,
+            nat_pmp: true,
+            pcp: true,
+        }
+    }
+}
+
+impl PortMappingConfig {
+    /// Disables port mapping completely.
+    pub fn disabled() -> Self {
+        Self {
+            upnp: false,
+            nat_pmp: false,
+            pcp: false,
+        }
+    }
+
+    /// Sets whether to request a port mapping using UPnP, by default `true`.
+    pub fn upnp(mut self, enabled: bool) -> Self {
+        self.upnp = enabled;
+        self
+    }
+
+    /// Sets whether to request a port mapping using NAT-PMP, by default `true`.
+    pub fn nat_pmp(mut self, enabled: bool) -> Self {
+        self.nat_pmp = enabled;
+        self
+    }
+
+    /// Sets whether to request a port mapping using PCP, by default `true`.
+    ///
+    /// PCP is also used to ask the router to open a pinhole in its IPv6 firewall for the
+    /// IPv6 socket.  The pinholed address is advertised among the direct addresses as well.
+    pub fn pcp(mut self, enabled: bool) -> Self {
+        self.pcp = enabled;
+        self
+    }
+
+    /// Whether any protocol is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.upnp || self.nat_pmp || self.pcp
+    }
+
+    /// Whether an IPv6 pinhole is requested.
+    pub(super) fn pcp_enabled(&self) -> bool {
+        self.pcp
+    }
+}
+
+impl From<PortMappingConfig> for portmapper::Config {
+    fn from(config: PortMappingConfig) -> Self {
+        portmapper::Config {
+            enable_upnp: config.upnp,
+            enable_pcp: config.pcp,
+            enable_nat_pmp: config.nat_pmp,
+        }
+    }
+}
+
+/// The state of the port mapping of an endpoint.
+///
+/// See [`Endpoint::watch_port_mapping`].
+///
+/// [`Endpoint::watch_port_mapping`]: crate::endpoint::Endpoint::watch_port_mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum PortMappingStatus {
+    /// Port mapping is disabled.
+    #[display("disabled")]
+    Disabled,
+    /// No port mapping was acquired yet.
+    ///
+    /// This is also the case when the router does not support any of the enabled
+    /// protocols.
+    #[display("pending")]
+    Pending,
+    /// A port mapping is held, with this external address.
+    ///
+    /// The address is advertised among the direct addresses of the endpoint.
+    #[display("acquired({_0})")]
+    Acquired(SocketAddrV4),
+    /// The port mapping with this external address was lost.
+    ///
+    /// This happens when renewing the mapping failed, e.g. because the router restarted,
+    /// and when the network changed.  A new mapping is requested with the next report about
+    /// the network conditions.
+    #[display("lost({_0})")]
+    Lost(SocketAddrV4),
+}
+
+impl PortMappingStatus {
+    /// Returns the status after the external address of the mapping changed.
+    pub(super) fn update(self, external_addr: Option<SocketAddrV4>) -> Self {
+        match (external_addr, self) {
+            (Some(addr), _) => Self::Acquired(addr),
+            (None, Self::Acquired(addr)) => Self::Lost(addr),
+            (None, status) => status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_mapping_status() {
+        let a: SocketAddrV4 = "203.0.113.1:1234".parse().unwrap();
+        let b: SocketAddrV4 = "203.0.113.1:4321".parse().unwrap();
+        let status = PortMappingStatus::Pending;
+        assert_eq!(status.update(None), PortMappingStatus::Pending);
+        let status = status.update(Some(a));
+        assert_eq!(status, PortMappingStatus::Acquired(a));
+        let status = status.update(None);
+        assert_eq!(status, PortMappingStatus::Lost(a));
+        assert_eq!(status.update(None), PortMappingStatus::Lost(a));
+        assert_eq!(status.update(Some(b)), PortMappingStatus::Acquired(b));
+
+        assert!(PortMappingConfig::default().is_enabled());
+        assert!(PortMappingConfig::disabled().upnp(true).is_enabled());
+        assert!(!PortMappingConfig::default()
+            .upnp(false)
+            .nat_pmp(false)
+            .pcp(false)
+            .is_enabled());
+    }
+}
@@ -0,0 +1,290 @@
+//! The socket for QUIC address discovery.
+//!
+//! QUIC address discovery lets the relay servers report the address they observed our
+//! packets coming from, much like STUN does.  For this to be useful the QUIC packets must be
+//! sent from the same UDP sockets as all other traffic, which is what [`QadSocket`] does.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use concurrent_queue::ConcurrentQueue;
+use futures_util::task::AtomicWaker;
+use iroh_relay::quic::QuicClient;
+use quinn::AsyncUdpSocket;
+use tokio::time::Instant;
+use tracing::trace;
+
+use super::udp_conn::UdpConn;
+use crate::runtime::Runtime;
+
+/// Creates the client net_report uses for QUIC address discovery probes.
+///
+/// The client's QUIC endpoint sends and receives using the `socket`.
+pub(super) fn quic_client(
+    socket: Arc<QadSocket>,
+    tls_config: rustls::ClientConfig,
+    runtime: &dyn Runtime,
+) -> Result<QuicClient> {
+    let mut endpoint_config = quinn::EndpointConfig::default();
+    // The fixed bit must be set, the magic socket relies on it to tell QUIC packets apart
+    // from DISCO and STUN packets.
+    endpoint_config.grease_quic_bit(false);
+    let endpoint = quinn::Endpoint::new_with_abstract_socket(
+        endpoint_config,
+        None,
+        socket,
+        runtime.quinn_runtime(),
+    )?;
+    QuicClient::new(endpoint, tls_config)
+}
+
+/// Returns the TLS configuration to verify the QUIC endpoints of relay servers.
+pub(super) fn tls_config() -> rustls::ClientConfig {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .expect("protocols supported by ring")
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// How long datagrams from a remote are accepted after the last datagram sent to it.
+///
+/// This is the default idle timeout of QUIC connections: once nothing was sent to a remote
+/// for this long its connections are closed.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A UDP socket for the QUIC endpoint used by the QUIC address discovery probes.
+///
+/// Datagrams are sent directly on the sockets of the [`MagicSock`].  Received datagrams are
+/// handed over by the [`MagicSock`] using [`QadSocket::try_recv`], but only for QUIC packets
+/// which do not belong to any node.
+///
+/// [`MagicSock`]: super::MagicSock
+#[derive(Debug)]
+pub(super) struct QadSocket {
+    pconn4: Option<UdpConn>,
+    pconn6: Option<UdpConn>,
+    /// The addresses datagrams were sent to, with the time of the last datagram.
+    ///
+    /// Only datagrams from these addresses are accepted, otherwise the endpoint would
+    /// answer stray QUIC packets of other nodes, e.g. with stateless resets.  Addresses are
+    /// removed once their connections are closed, see [`REMOTE_TIMEOUT`].
+    remotes: parking_lot::Mutex<HashMap<SocketAddr, Instant>>,
+    queue: ConcurrentQueue<(SocketAddr, Bytes)>,
+    waker: AtomicWaker,
+}
+
+impl QadSocket {
+    pub(super) fn new(pconn4: Option<UdpConn>, pconn6: Option<UdpConn>) -> Self {
+        Self {
+            pconn4,
+            pconn6,
+            remotes: Default::default(),
+            queue: ConcurrentQueue::bounded(64),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Whether datagrams from this address are for the QUIC address discovery endpoint.
+    pub(super) fn is_remote(&self, addr: SocketAddr) -> bool {
+        self.remotes
+            .lock()
+            .get(&canonical(addr))
+            .is_some_and(|last_sent| last_sent.elapsed() < REMOTE_TIMEOUT)
+    }
+
+    /// Records that a datagram was sent to `addr`, forgetting remotes without connections.
+    fn note_sent(&self, addr: SocketAddr) {
+        let now = Instant::now();
+        let mut remotes = self.remotes.lock();
+        remotes.retain(|_, last_sent| now.duration_since(*last_sent) < REMOTE_TIMEOUT);
+        remotes.insert(addr, now);
+    }
+
+    /// Queues a datagram received from a QUIC address discovery server.
+    ///
+    /// Datagrams are dropped if the queue is full, QUIC will retransmit.
+    pub(super) fn try_recv(&self, src: SocketAddr, datagram: Bytes) {
+        match self.queue.push((canonical(src), datagram)) {
+            Ok(()) => self.waker.wake(),
+            Err(_) => trace!(%src, "QAD recv queue full, dropping datagram"),
+        }
+    }
+
+    fn conn_for_addr(&self, addr: SocketAddr) -> io::Result<&UdpConn> {
+        let conn = match addr {
+            SocketAddr::V4(_) => self.pconn4.as_ref(),
+            SocketAddr::V6(_) => self.pconn6.as_ref(),
+        };
+        conn.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no socket for address family"))
+    }
+}
+
+impl AsyncUdpSocket for QadSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn quinn::UdpPoller>> {
+        Box::pin(IoPoller {
+            ipv4_poller: self.pconn4.as_ref().map(|conn| conn.create_io_poller()),
+            ipv6_poller: self.pconn6.as_ref().map(|conn| conn.create_io_poller()),
+        })
+    }
+
+    fn try_send(&self, transmit: &quinn_udp::Transmit) -> io::Result<()> {
+        let destination = canonical(transmit.destination);
+        let conn = self.conn_for_addr(destination)?;
+        self.note_sent(destination);
+        let transmit = quinn_udp::Transmit {
+            destination,
+            ecn: None,
+            contents: transmit.contents,
+            segment_size: transmit.segment_size,
+            src_ip: None,
+        };
+        conn.try_send(&transmit)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut count = 0;
+        for (buf, meta) in bufs.iter_mut().zip(metas.iter_mut()) {
+            let Ok((src, datagram)) = self.queue.pop() else {
+                break;
+            };
+            let len = datagram.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram[..len]);
+            // An IPv6 endpoint expects IPv4 addresses to be mapped into IPv6.
+            let addr = match (self.pconn6.is_some(), src) {
+                (true, SocketAddr::V4(v4)) => {
+                    SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+                }
+                _ => src,
+            };
+            *meta = quinn_udp::RecvMeta {
+                addr,
+                len,
+                stride: len,
+                ecn: None,
+                dst_ip: None,
+            };
+            count += 1;
+        }
+        if count > 0 || bufs.is_empty() || metas.is_empty() {
+            return Poll::Ready(Ok(count));
+        }
+        self.waker.register(cx.waker());
+        // A datagram may have been queued before the waker was registered.
+        if !self.queue.is_empty() {
+            self.waker.take();
+            return self.poll_recv(cx, bufs, metas);
+        }
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match (&self.pconn6, &self.pconn4) {
+            (Some(conn), _) | (None, Some(conn)) => conn.local_addr(),
+            (None, None) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no socket bound",
+            )),
+        }
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.pconn4
+            .iter()
+            .chain(self.pconn6.iter())
+            .any(|conn| conn.may_fragment())
+    }
+}
+
+/// Poller for when the [`QadSocket`] is writable.
+///
+/// This is ready as soon as any of the sockets is writable.
+#[derive(Debug)]
+struct IoPoller {
+    ipv4_poller: Option<Pin<Box<dyn quinn::UdpPoller>>>,
+    ipv6_poller: Option<Pin<Box<dyn quinn::UdpPoller>>>,
+}
+
+impl quinn::UdpPoller for IoPoller {
+    fn poll_writable(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        for poller in this
+            .ipv4_poller
+            .iter_mut()
+            .chain(this.ipv6_poller.iter_mut())
+        {
+            if poller.as_mut().poll_writable(cx).is_ready() {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses to plain IPv4 addresses.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::test_utils::MemoryNetwork;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_remotes_expire() {
+        let network = MemoryNetwork::default();
+        let socket = QadSocket::new(Some(UdpConn::memory(network.bind())), None);
+        let remote = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 7842));
+        assert!(!socket.is_remote(remote));
+
+        socket.note_sent(remote);
+        assert!(socket.is_remote(remote));
+
+        tokio::time::advance(REMOTE_TIMEOUT).await;
+        assert!(!socket.is_remote(remote));
+        let other = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 2), 7842));
+        socket.note_sent(other);
+        assert_eq!(socket.remotes.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recv_batch() {
+        let network = MemoryNetwork::default();
+        let socket = QadSocket::new(Some(UdpConn::memory(network.bind())), None);
+        let remote = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 7842));
+        for i in 0..3u8 {
+            socket.try_recv(remote, Bytes::from(vec![i; 10]));
+        }
+
+        let mut storage = [[0u8; 32]; 4];
+        let mut bufs: Vec<_> = storage.iter_mut().map(|b| io::IoSliceMut::new(b)).collect();
+        let mut metas = [quinn_udp::RecvMeta::default(); 4];
+        let res = std::future::poll_fn(|cx| socket.poll_recv(cx, &mut bufs, &mut metas)).await;
+        assert_eq!(res.unwrap(), 3);
+        for (i, meta) in metas[..3].iter().enumerate() {
+            assert_eq!(meta.addr, remote);
+            assert_eq!(&bufs[i][..meta.len], &[i as u8; 10]);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Limits the rate at which the [`MagicSock`] sends data.
+//!
+//! [`MagicSock`]: super::MagicSock
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_lite::FutureExt;
+use tokio::time::{Instant, Sleep};
+
+/// How much unused capacity may accumulate, expressed as time at the configured rate.
+const MAX_BURST: Duration = Duration::from_millis(50);
+
+/// A token bucket shared by all sends of the [`MagicSock`].
+///
+/// A send is allowed as long as the bucket is not empty, and may overdraw it.  This allows
+/// sending transmits larger than the bucket, after which further sends are held back until
+/// the debt has been paid off.
+///
+/// [`MagicSock`]: super::MagicSock
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    bytes_per_sec: f64,
+    burst: f64,
+    state: parking_lot::Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(super) fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        let burst = bytes_per_sec * MAX_BURST.as_secs_f64();
+        Self {
+            bytes_per_sec,
+            burst,
+            state: parking_lot::Mutex::new(State {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` from the bucket if it is not empty.
+    ///
+    /// Returns `false` if the bucket is empty and nothing may be sent right now.
+    pub(super) fn try_acquire(&self, bytes: usize) -> bool {
+        let mut state = self.state.lock();
+        self.refill(&mut state, Instant::now());
+        if state.tokens <= 0.0 {
+            return false;
+        }
+        state.tokens -= bytes as f64;
+        true
+    }
+
+    /// Polls until the bucket is no longer empty.
+    ///
+    /// The `sleep` is used to register for a wakeup once enough time has passed.
+    pub(super) fn poll_ready(
+        &self,
+        cx: &mut Context,
+        sleep: &mut Option<Pin<Box<Sleep>>>,
+    ) -> Poll<()> {
+        loop {
+            let now = Instant::now();
+            let ready_at = {
+                let mut state = self.state.lock();
+                self.refill(&mut state, now);
+                if state.tokens > 0.0 {
+                    *sleep = None;
+                    return Poll::Ready(());
+                }
+                // Add a nanosecond so the bucket is guaranteed to hold a token again.
+                now + Duration::from_secs_f64(-state.tokens / self.bytes_per_sec)
+                    + Duration::from_nanos(1)
+            };
+            match sleep {
+                Some(sleep) => sleep.as_mut().reset(ready_at),
+                None => *sleep = Some(Box::pin(tokio::time::sleep_until(ready_at))),
+            }
+            if let Some(sleep) = sleep {
+                if sleep.poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+
+    fn refill(&self, state: &mut State, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.bytes_per_sec).min(self.burst);
+        state.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn test_rate_limiter() {
+        let limiter = RateLimiter::new(10_000);
+
+        // The initial burst allows one send, which may overdraw the bucket.
+        assert!(limiter.try_acquire(5_000));
+        assert!(!limiter.try_acquire(1));
+
+        // Paying off the debt of 4500 bytes takes 450ms.
+        let start = Instant::now();
+        let mut sleep = None;
+        std::future::poll_fn(|cx| limiter.poll_ready(cx, &mut sleep)).await;
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(450));
+        assert!(waited < Duration::from_millis(460));
+        assert!(limiter.try_acquire(1));
+    }
+}
@@ -6,7 +6,10 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
     net::{IpAddr, SocketAddr},
-    sync::{atomic::Ordering, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -15,8 +18,9 @@ use backoff::backoff::Backoff;
 use bytes::{Bytes, BytesMut};
 use iroh_metrics::{inc, inc_by};
 use iroh_relay::{self as relay, client::ClientError, ReceivedMessage, RelayUrl, MAX_PACKET_SIZE};
+use rand::{rngs::StdRng, Rng};
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     task::{JoinHandle, JoinSet},
     time,
 };
@@ -24,8 +28,12 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
 use crate::{
+    endpoint::EndpointEvent,
     key::{NodeId, PUBLIC_KEY_LENGTH},
-    magicsock::{MagicSock, Metrics as MagicsockMetrics, RelayContents, RelayDatagramsQueue},
+    magicsock::{
+        ActorMessage, Counters, MagicSock, Metrics as MagicsockMetrics, RelayContents,
+        RelayDatagramsQueue,
+    },
 };
 
 /// How long a non-home relay connection needs to be idle (last written to) before we close it.
@@ -34,6 +42,181 @@ const RELAY_INACTIVE_CLEANUP_TIME: Duration = Duration::from_secs(60);
 /// How often `clean_stale_relay` runs when there are potentially-stale relay connections to close.
 const RELAY_CLEAN_STALE_INTERVAL: Duration = Duration::from_secs(15);
 
+/// The default number of messages the queue to the [`RelayActor`] holds.
+const RELAY_QUEUE_DEPTH: usize = 256;
+
+/// How much the delays between attempts to reconnect a relay connection are randomized, as
+/// a fraction of the delay.
+const RELAY_BACKOFF_JITTER: f64 = 0.5;
+
+/// The default time to wait for the pong to a ping of a relay server.
+const RELAY_PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default first delay before reconnecting a lost relay connection.
+const RELAY_RECONNECT_BACKOFF_INITIAL: Duration = Duration::from_millis(10);
+
+/// The default longest delay between attempts to reconnect a lost relay connection.
+const RELAY_RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Configuration of the queue of datagrams waiting to be sent to relay servers.
+///
+/// Datagrams sent via relay servers are queued for the relay actor, which writes them to
+/// the relay connections.  When the relay connections can not keep up, the queue fills up.
+/// By default further datagrams are then dropped and QUIC's congestion control reacts to
+/// the loss.  With [`RelayQueueConfig::backpressure`] sending waits for space in the queue
+/// instead.
+///
+/// See [`Builder::relay_queue`].
+///
+/// [`Builder::relay_queue`]: crate::endpoint::Builder::relay_queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayQueueConfig {
+    pub(super) depth: usize,
+    pub(super) backpressure: bool,
+}
+
+impl Default for RelayQueueConfig {
+    fn default() -> Self {
+        Self {
+            depth: RELAY_QUEUE_DEPTH,
+            backpressure: false,
+        }
+    }
+}
+
+impl RelayQueueConfig {
+    /// Sets the number of transmits the queue holds, by default 256.
+    ///
+    /// Each transmit contains one or more QUIC datagrams.  The depth is at least 1.
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth.max(1);
+        self
+    }
+
+    /// Sets whether to wait for space in a full queue instead of dropping datagrams, by
+    /// default `false`.
+    ///
+    /// All connections of an endpoint share the sockets, so while the queue is full no
+    /// connection sends any data, including connections using direct paths.  This suits
+    /// applications that mostly talk via relay servers and prefer stalling over loss.
+    pub fn backpressure(mut self, enabled: bool) -> Self {
+        self.backpressure = enabled;
+        self
+    }
+}
+
+/// Configuration of how relay connections are kept alive and reconnected.
+///
+/// Relay servers send a keepalive frame every minute, a connection which received nothing
+/// for two minutes is considered dead.  Pinging the relay server notices a dead connection
+/// after at most the ping interval plus the pong timeout instead, which suits servers that
+/// need to stay reachable.  Mobile devices might prefer to not ping at all, to let the radio
+/// sleep.
+///
+/// Lost connections are reconnected with an exponential backoff, starting at 10
+/// milliseconds and growing to at most 5 seconds between attempts.
+///
+/// See [`Builder::relay_keepalive`].
+///
+/// [`Builder::relay_keepalive`]: crate::endpoint::Builder::relay_keepalive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayKeepaliveConfig {
+    pub(super) ping_interval: Option<Duration>,
+    pub(super) pong_timeout: Duration,
+    pub(super) backoff_initial: Duration,
+    pub(super) backoff_max: Duration,
+}
+
+impl Default for RelayKeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: None,
+            pong_timeout: RELAY_PONG_TIMEOUT,
+            backoff_initial: RELAY_RECONNECT_BACKOFF_INITIAL,
+            backoff_max: RELAY_RECONNECT_BACKOFF_MAX,
+        }
+    }
+}
+
+impl RelayKeepaliveConfig {
+    /// Sets the interval at which relay servers are pinged.
+    ///
+    /// By default relay servers are not pinged.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = Some(interval);
+        self
+    }
+
+    /// Sets how long to wait for the pong before the connection is considered dead, by
+    /// default 5 seconds.
+    pub fn pong_timeout(mut self, timeout: Duration) -> Self {
+        self.pong_timeout = timeout;
+        self
+    }
+
+    /// Sets the first and the longest delay between attempts to reconnect a lost relay
+    /// connection.
+    pub fn reconnect_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff_initial = initial;
+        self.backoff_max = max.max(initial);
+        self
+    }
+
+    fn backoff(&self, rng: StdRng) -> RelayBackoff {
+        let backoff = backoff::exponential::ExponentialBackoffBuilder::new()
+            .with_initial_interval(self.backoff_initial)
+            .with_max_interval(self.backoff_max)
+            // Randomized by `RelayBackoff`, the backoff crate can not use a seeded RNG.
+            .with_randomization_factor(0.0)
+            .build();
+        RelayBackoff { backoff, rng }
+    }
+}
+
+/// The exponential backoff between attempts to reconnect a relay connection.
+#[derive(Debug)]
+struct RelayBackoff {
+    backoff: backoff::exponential::ExponentialBackoff<backoff::SystemClock>,
+    rng: StdRng,
+}
+
+impl RelayBackoff {
+    /// Returns the next delay, randomized by up to [`RELAY_BACKOFF_JITTER`] of it.
+    ///
+    /// Returns `None` once reconnecting took too long.
+    fn next_backoff(&mut self) -> Option<Duration> {
+        let delay = self.backoff.next_backoff()?;
+        let factor = self
+            .rng
+            .gen_range(1.0 - RELAY_BACKOFF_JITTER..=1.0 + RELAY_BACKOFF_JITTER);
+        Some(delay.mul_f64(factor))
+    }
+
+    fn reset(&mut self) {
+        self.backoff.reset();
+    }
+}
+
+/// The health of a connection to a relay server.
+///
+/// See [`Endpoint::relay_health`].
+///
+/// [`Endpoint::relay_health`]: crate::endpoint::Endpoint::relay_health
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayHealth {
+    /// Whether the connection is currently established.
+    pub connected: bool,
+    /// When the relay server last answered a ping.
+    ///
+    /// Only set once a ping was answered, see [`RelayKeepaliveConfig::ping_interval`].
+    pub last_pong: Option<Instant>,
+    /// How often the connection was re-established after it was lost.
+    pub reconnects: u64,
+}
+
+/// The health of all relay connections, shared with the [`MagicSock`].
+pub(super) type RelayHealthMap = Arc<parking_lot::Mutex<BTreeMap<RelayUrl, RelayHealth>>>;
+
 pub(super) enum RelayActorMessage {
     Send {
         url: RelayUrl,
@@ -44,6 +227,14 @@ pub(super) enum RelayActorMessage {
     SetHome {
         url: RelayUrl,
     },
+    SetStandby {
+        url: RelayUrl,
+    },
+    /// Breaks all relay connections as if they were lost, see [`Faults`].
+    ///
+    /// [`Faults`]: crate::endpoint::Faults
+    #[cfg(any(test, feature = "test-utils"))]
+    KillConnections,
 }
 
 /// An actor which handles a single relay connection.
@@ -62,9 +253,25 @@ struct ConnectedRelayActor {
     /// If we receive messages from a remote node via, this server it is added to this set.
     /// If the server notifies us this node is gone, it is removed from this set.
     node_present: BTreeSet<NodeId>,
-    backoff: backoff::exponential::ExponentialBackoff<backoff::SystemClock>,
+    backoff: RelayBackoff,
     last_packet_time: Option<Instant>,
     last_packet_src: Option<NodeId>,
+    /// Whether the connection was lost and is not re-established yet.
+    lost: Arc<AtomicBool>,
+    /// Notifies the [`RelayActor`] when the connection is lost.
+    lost_sender: mpsc::Sender<RelayUrl>,
+    /// Channel to the magicsock actor, to move away from a draining relay server.
+    msock_sender: mpsc::Sender<ActorMessage>,
+    /// How often to ping the relay server, if at all.
+    ping_interval: Option<Duration>,
+    /// Whether the endpoint is paused, no keepalive pings are sent then.
+    paused: Arc<AtomicBool>,
+    /// The keepalive ping in flight.
+    keepalive_ping: JoinSet<Result<Duration, ClientError>>,
+    /// Where the health of this connection is reported.
+    health: RelayHealthMap,
+    /// Where connecting and losing the connection is reported.
+    events: broadcast::Sender<EndpointEvent>,
 }
 
 #[derive(Debug)]
@@ -76,29 +283,93 @@ enum ConnectedRelayMessage {
     GetNodeRoute(NodeId, oneshot::Sender<Option<relay::client::Client>>),
     GetClient(oneshot::Sender<relay::client::Client>),
     NotePreferred(bool),
+    /// Breaks the connection, it is reconnected like a lost connection.
+    #[cfg(any(test, feature = "test-utils"))]
+    Kill,
     Shutdown,
 }
 
 impl ConnectedRelayActor {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         url: RelayUrl,
         relay_client: relay::client::Client,
         relay_client_receiver: relay::client::ClientReceiver,
         relay_datagrams_queue: Arc<RelayDatagramsQueue>,
+        lost: Arc<AtomicBool>,
+        lost_sender: mpsc::Sender<RelayUrl>,
+        msock_sender: mpsc::Sender<ActorMessage>,
+        keepalive: RelayKeepaliveConfig,
+        paused: Arc<AtomicBool>,
+        health: RelayHealthMap,
+        events: broadcast::Sender<EndpointEvent>,
+        rng: StdRng,
     ) -> Self {
         ConnectedRelayActor {
             last_write: Instant::now(),
             relay_datagrams_queue,
             url,
             node_present: BTreeSet::new(),
-            backoff: backoff::exponential::ExponentialBackoffBuilder::new()
-                .with_initial_interval(Duration::from_millis(10))
-                .with_max_interval(Duration::from_secs(5))
-                .build(),
+            backoff: keepalive.backoff(rng),
             last_packet_time: None,
             last_packet_src: None,
             relay_client,
             relay_client_receiver,
+            lost,
+            lost_sender,
+            msock_sender,
+            ping_interval: keepalive.ping_interval,
+            paused,
+            keepalive_ping: JoinSet::new(),
+            health,
+            events,
+        }
+    }
+
+    /// Updates the health of this connection in the [`RelayHealthMap`].
+    ///
+    /// Changes of the connection state are emitted as [`EndpointEvent`]s.
+    fn update_health(&self, f: impl FnOnce(&mut RelayHealth)) {
+        let mut health = self.health.lock();
+        let health = health.entry(self.url.clone()).or_default();
+        let was_connected = health.connected;
+        f(health);
+        let event = match (was_connected, health.connected) {
+            (false, true) => EndpointEvent::RelayConnected(self.url.clone()),
+            (true, false) => EndpointEvent::RelayDisconnected(self.url.clone()),
+            _ => return,
+        };
+        // Sending only fails when there are no subscribers.
+        self.events.send(event).ok();
+    }
+
+    /// Marks the connection as lost, notifying the [`RelayActor`] the first time.
+    fn note_lost(&mut self) {
+        // Forget that all these peers have routes.
+        self.node_present.clear();
+        if !self.lost.swap(true, Ordering::Relaxed) {
+            self.lost_sender.try_send(self.url.clone()).ok();
+        }
+        self.update_health(|health| health.connected = false);
+    }
+
+    /// Handles the result of a keepalive ping.
+    ///
+    /// A ping which was not answered in time means the connection is dead, it is closed so
+    /// that it gets reconnected.
+    async fn handle_keepalive_pong(&mut self, res: Result<Duration, ClientError>) {
+        match res {
+            Ok(latency) => {
+                trace!(?latency, "keepalive pong");
+                self.update_health(|health| health.last_pong = Some(Instant::now()));
+            }
+            Err(ClientError::PingTimeout) => {
+                warn!("relay server did not answer the keepalive ping, reconnecting");
+                inc!(MagicsockMetrics, relay_pong_timeout);
+                self.note_lost();
+                self.relay_client.close_for_reconnect().await.ok();
+            }
+            Err(err) => debug!("keepalive ping failed: {err:#}"),
         }
     }
 
@@ -108,6 +379,13 @@ impl ConnectedRelayActor {
             .connect()
             .await
             .context("initial connection")?;
+        self.update_health(|health| health.connected = true);
+
+        let mut ping_timer = self.ping_interval.map(|interval| {
+            let mut timer = time::interval_at(time::Instant::now() + interval, interval);
+            timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            timer
+        });
 
         loop {
             // If a read error occurred on the connection it might have been lost.  But we
@@ -116,8 +394,30 @@ impl ConnectedRelayActor {
             if !self.relay_client.is_connected().await? {
                 debug!("relay re-connecting");
                 self.relay_client.connect().await.context("keepalive")?;
+                self.lost.store(false, Ordering::Relaxed);
+                inc!(MagicsockMetrics, relay_reconnect);
+                self.update_health(|health| {
+                    health.connected = true;
+                    health.reconnects += 1;
+                });
             }
             tokio::select! {
+                Some(_) = async { Some(ping_timer.as_mut()?.tick().await) }, if ping_timer.is_some() => {
+                    // Skip the ping while the previous one is still waiting for its pong.
+                    if self.paused.load(Ordering::Relaxed) {
+                        trace!("tick: keepalive ping skipped, paused");
+                    } else if self.keepalive_ping.is_empty() {
+                        trace!("tick: keepalive ping");
+                        let client = self.relay_client.clone();
+                        self.keepalive_ping.spawn(async move { client.ping().await });
+                    }
+                }
+                Some(res) = self.keepalive_ping.join_next(), if !self.keepalive_ping.is_empty() => {
+                    match res {
+                        Ok(res) => self.handle_keepalive_pong(res).await,
+                        Err(err) => warn!("keepalive ping task failed: {err:#}"),
+                    }
+                }
                 msg = inbox.recv() => {
                     let Some(msg) = msg else {
                         debug!("all clients closed");
@@ -130,7 +430,11 @@ impl ConnectedRelayActor {
                             r.send(self.last_write).ok();
                         }
                         ConnectedRelayMessage::Ping(r) => {
-                            r.send(self.relay_client.ping().await).ok();
+                            let res = self.relay_client.ping().await;
+                            if res.is_ok() {
+                                self.update_health(|health| health.last_pong = Some(Instant::now()));
+                            }
+                            r.send(res).ok();
                         }
                         ConnectedRelayMessage::GetLocalAddr(r) => {
                             r.send(self.relay_client.local_addr().await).ok();
@@ -150,6 +454,12 @@ impl ConnectedRelayActor {
                             };
                             r.send(client).ok();
                         }
+                        #[cfg(any(test, feature = "test-utils"))]
+                        ConnectedRelayMessage::Kill => {
+                            warn!("killing relay connection, injected fault");
+                            self.note_lost();
+                            self.relay_client.close_for_reconnect().await.ok();
+                        }
                         ConnectedRelayMessage::Shutdown => {
                             debug!("shutdown");
                             break;
@@ -177,9 +487,7 @@ impl ConnectedRelayActor {
         match msg {
             Err(err) => {
                 warn!("recv error {:?}", err);
-
-                // Forget that all these peers have routes.
-                self.node_present.clear();
+                self.note_lost();
 
                 if matches!(
                     err,
@@ -207,6 +515,7 @@ impl ConnectedRelayActor {
             Ok(msg) => {
                 // reset
                 self.backoff.reset();
+                self.lost.store(false, Ordering::Relaxed);
                 let now = Instant::now();
                 if self
                     .last_packet_time
@@ -265,6 +574,17 @@ impl ConnectedRelayActor {
                         ReadResult::Continue
                     }
                     ReceivedMessage::Health { .. } => ReadResult::Continue,
+                    ReceivedMessage::GoAway { close_in } => {
+                        info!(?close_in, "relay server is draining");
+                        let msg = ActorMessage::RelayGoAway {
+                            url: self.url.clone(),
+                            close_in,
+                        };
+                        if self.msock_sender.try_send(msg).is_err() {
+                            warn!("unable to move away from draining relay, actor channel full");
+                        }
+                        ReadResult::Continue
+                    }
                     ReceivedMessage::NodeGone(key) => {
                         self.node_present.remove(&key);
                         ReadResult::Continue
@@ -280,12 +600,34 @@ impl ConnectedRelayActor {
     }
 }
 
+/// The handle of a [`ConnectedRelayActor`].
+#[derive(Debug)]
+struct ConnectedRelay {
+    sender: mpsc::Sender<ConnectedRelayMessage>,
+    task: JoinHandle<()>,
+    lost: Arc<AtomicBool>,
+}
+
+impl ConnectedRelay {
+    /// Returns whether the connection was lost and is still reconnecting.
+    ///
+    /// While reconnecting the [`ConnectedRelayActor`] does not handle any messages.
+    fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
 pub(super) struct RelayActor {
     msock: Arc<MagicSock>,
     relay_datagrams_queue: Arc<RelayDatagramsQueue>,
     /// relay Url -> connection to the node
-    connected_relays: BTreeMap<RelayUrl, (mpsc::Sender<ConnectedRelayMessage>, JoinHandle<()>)>,
+    connected_relays: BTreeMap<RelayUrl, ConnectedRelay>,
     ping_tasks: JoinSet<(RelayUrl, bool)>,
+    /// Receives the URLs of relay connections which were lost.
+    lost_receiver: mpsc::Receiver<RelayUrl>,
+    lost_sender: mpsc::Sender<RelayUrl>,
+    /// How the relay connections are kept alive.
+    keepalive: RelayKeepaliveConfig,
     cancel_token: CancellationToken,
 }
 
@@ -293,13 +635,18 @@ impl RelayActor {
     pub(super) fn new(
         msock: Arc<MagicSock>,
         relay_datagrams_queue: Arc<RelayDatagramsQueue>,
+        keepalive: RelayKeepaliveConfig,
     ) -> Self {
         let cancel_token = CancellationToken::new();
+        let (lost_sender, lost_receiver) = mpsc::channel(16);
         Self {
             msock,
             relay_datagrams_queue,
             connected_relays: Default::default(),
             ping_tasks: Default::default(),
+            lost_receiver,
+            lost_sender,
+            keepalive,
             cancel_token,
         }
     }
@@ -341,6 +688,9 @@ impl RelayActor {
                     }
                 }
 
+                Some(url) = self.lost_receiver.recv() => {
+                    self.maybe_failover(&url);
+                }
                 msg = receiver.recv() => {
                     let Some(msg) = msg else {
                         trace!("shutting down relay recv loop");
@@ -374,27 +724,68 @@ impl RelayActor {
                 self.note_preferred(&url).await;
                 self.connect_relay(&url, None).await;
             }
+            RelayActorMessage::SetStandby { url } => {
+                self.connect_relay(&url, None).await;
+            }
             RelayActorMessage::MaybeCloseRelaysOnRebind(ifs) => {
                 self.maybe_close_relays_on_rebind(&ifs).await;
             }
+            #[cfg(any(test, feature = "test-utils"))]
+            RelayActorMessage::KillConnections => {
+                let urls: Vec<_> = self.connected_relays.keys().cloned().collect();
+                for url in urls {
+                    self.send_to_connected_relay(&url, ConnectedRelayMessage::Kill)
+                        .await;
+                }
+            }
         }
     }
 
     async fn note_preferred(&self, my_url: &RelayUrl) {
-        futures_buffered::join_all(
-            self.connected_relays
-                .iter()
-                .map(|(url, (s, _))| async move {
-                    let is_preferred = url == my_url;
-                    s.send(ConnectedRelayMessage::NotePreferred(is_preferred))
-                        .await
-                        .ok()
-                }),
-        )
+        futures_buffered::join_all(self.connected_relays.iter().map(|(url, conn)| async move {
+            let is_preferred = url == my_url;
+            conn.sender
+                .send(ConnectedRelayMessage::NotePreferred(is_preferred))
+                .await
+                .ok()
+        }))
         .await;
     }
 
-    async fn send_relay(&mut self, url: &RelayUrl, contents: RelayContents, remote_node: NodeId) {
+    /// Fails over to the standby relay if the connection to the home relay was lost.
+    ///
+    /// The standby relay is already connected, so other nodes can reach us again as soon as
+    /// they learn about the new home relay, rather than after the home relay reconnected.
+    fn maybe_failover(&self, url: &RelayUrl) {
+        if self.msock.my_relay().as_ref() != Some(url) {
+            return;
+        }
+        let Some(standby) = self.msock.standby_relay() else {
+            return;
+        };
+        if !self
+            .connected_relays
+            .get(&standby)
+            .is_some_and(|conn| !conn.is_lost())
+        {
+            debug!(%url, %standby, "home relay lost, standby relay not connected");
+            return;
+        }
+        let msg = ActorMessage::RelayFailover {
+            from: url.clone(),
+            to: standby,
+        };
+        if self.msock.actor_sender.try_send(msg).is_err() {
+            warn!("unable to fail over to standby relay, actor channel full");
+        }
+    }
+
+    async fn send_relay(
+        &mut self,
+        url: &RelayUrl,
+        mut contents: RelayContents,
+        remote_node: NodeId,
+    ) {
         trace!(
             %url,
             remote_node = %remote_node.fmt_short(),
@@ -406,18 +797,37 @@ impl RelayActor {
         for content in &contents {
             trace!(%url, ?remote_node, "sending {}B", content.len());
         }
+        const PAYLAOD_SIZE: usize = MAX_PACKET_SIZE - PUBLIC_KEY_LENGTH;
+
+        // Datagrams which do not fit into a relay frame are dropped.  QUIC's path MTU
+        // discovery notices them as lost and falls back to smaller datagrams.
+        contents.retain(|datagram| {
+            let fits = datagram.len() + 2 <= PAYLAOD_SIZE;
+            if !fits {
+                debug!(%url, len = datagram.len(), "datagram too large for relay, dropping");
+                inc!(MagicsockMetrics, send_relay_dropped_too_large);
+            }
+            fits
+        });
         let total_bytes = contents.iter().map(|c| c.len() as u64).sum::<u64>();
 
-        const PAYLAOD_SIZE: usize = MAX_PACKET_SIZE - PUBLIC_KEY_LENGTH;
+        // Only ask for the packet size negotiated with the server when the default one is
+        // not enough, it is a round trip to the relay client actor.
+        let payload_size = if contents.iter().map(|c| c.len() + 2).sum::<usize>() > PAYLAOD_SIZE {
+            relay_client.max_packet_size().await - PUBLIC_KEY_LENGTH
+        } else {
+            PAYLAOD_SIZE
+        };
 
         // When Quinn sends a GSO Transmit magicsock::split_packets will make us receive
         // more than one packet to send in a single call.  We join all packets back together
         // and prefix them with a u16 packet size.  They then get sent as a single DISCO
         // frame.
-        for packet in PacketizeIter::<_, PAYLAOD_SIZE>::new(contents) {
+        for packet in PacketizeIter::new(contents, payload_size) {
             match relay_client.send(remote_node, packet).await {
                 Ok(_) => {
                     inc_by!(MagicsockMetrics, send_relay, total_bytes);
+                    Counters::add(&self.msock.counters().relay_bytes_sent, total_bytes);
                 }
                 Err(err) => {
                     warn!(%url, "send: failed {:?}", err);
@@ -426,9 +836,9 @@ impl RelayActor {
             }
         }
 
-        // Wake up the send waker if one is waiting for space in the channel
-        let mut wakers = self.msock.relay_send_waker.lock();
-        if let Some(waker) = wakers.take() {
+        // Wake up the send wakers waiting for space in the channel
+        let wakers = std::mem::take(&mut *self.msock.relay_send_wakers.lock());
+        for waker in wakers {
             waker.wake();
         }
     }
@@ -441,7 +851,7 @@ impl RelayActor {
     ) -> bool {
         let res = self.connected_relays.get(url);
         match res {
-            Some((s, _)) => match s.send(msg).await {
+            Some(conn) => match conn.sender.send(msg).await {
                 Ok(_) => true,
                 Err(mpsc::error::SendError(_)) => {
                     self.close_relay(url, "sender-closed").await;
@@ -465,16 +875,10 @@ impl RelayActor {
         // See if we have a connection open to that relay node ID first. If so, might as
         // well use it. (It's a little arbitrary whether we use this one vs. the reverse route
         // below when we have both.)
-
-        {
-            let (os, or) = oneshot::channel();
-            if self
-                .send_to_connected_relay(url, ConnectedRelayMessage::GetClient(os))
-                .await
-            {
-                if let Ok(client) = or.await {
-                    return client;
-                }
+        let is_lost = self.connected_relays.get(url).map(ConnectedRelay::is_lost);
+        if is_lost == Some(false) {
+            if let Some(client) = self.get_client(url).await {
+                return client;
             }
         }
 
@@ -487,8 +891,9 @@ impl RelayActor {
         if let Some(node) = remote_node {
             for url in self
                 .connected_relays
-                .keys()
-                .cloned()
+                .iter()
+                .filter(|(_, conn)| !conn.is_lost())
+                .map(|(url, _)| url.clone())
                 .collect::<Vec<_>>()
                 .into_iter()
             {
@@ -504,6 +909,27 @@ impl RelayActor {
             }
         }
 
+        if is_lost == Some(true) {
+            // Waiting for the lost connection would hold up sending to all relays.  The
+            // node probably lost its connection to the relay as well and is reachable on
+            // our home relay, e.g. because it failed over to the same standby relay.
+            let home = self.msock.my_relay().filter(|home| home != url);
+            if let Some(home) = home.filter(|_| remote_node.is_some()) {
+                if self
+                    .connected_relays
+                    .get(&home)
+                    .is_some_and(|conn| !conn.is_lost())
+                {
+                    if let Some(client) = self.get_client(&home).await {
+                        return client;
+                    }
+                }
+            }
+            if let Some(client) = self.get_client(url).await {
+                return client;
+            }
+        }
+
         let why = if let Some(node) = remote_node {
             format!("{node:?}")
         } else {
@@ -520,12 +946,24 @@ impl RelayActor {
         if let Some(url) = self.msock.proxy_url() {
             builder = builder.proxy_url(url.clone());
         }
+        if let Some(interface) = self.msock.bind_interface() {
+            builder = builder.bind_interface(interface);
+        }
+        let node = self.msock.relay_map.get_node(url);
+        let quic = node.and_then(|node| node.quic.as_ref());
+        if let Some(quic) = quic.filter(|quic| quic.relay) {
+            builder = builder.quic(quic.port);
+        }
+        if let Some(token) = node.and_then(|node| node.auth_token.clone()) {
+            builder = builder.auth_token(token);
+        }
         let builder = builder
             .address_family_selector(move || {
                 let ipv6_reported = ipv6_reported.clone();
                 Box::pin(async move { ipv6_reported.load(Ordering::Relaxed) })
             })
-            .is_preferred(my_relay.as_ref() == Some(url));
+            .is_preferred(my_relay.as_ref() == Some(url))
+            .pong_timeout(self.keepalive.pong_timeout);
 
         #[cfg(any(test, feature = "test-utils"))]
         let builder = builder.insecure_skip_cert_verify(self.msock.insecure_skip_relay_cert_verify);
@@ -535,10 +973,19 @@ impl RelayActor {
             self.msock.dns_resolver.clone(),
         );
         let (conn_actor_inbox_tx, conn_actor_inbox_rx) = mpsc::channel(64);
+        let lost = Arc::new(AtomicBool::new(false));
         let handle = tokio::task::spawn({
             let url = url.clone();
             let relay_client = relay_client.clone();
             let relay_datagrams_queue = self.relay_datagrams_queue.clone();
+            let lost = lost.clone();
+            let lost_sender = self.lost_sender.clone();
+            let msock_sender = self.msock.actor_sender.clone();
+            let keepalive = self.keepalive;
+            let paused = self.msock.paused.clone();
+            let health = self.msock.relay_health.clone();
+            let events = self.msock.events().clone();
+            let rng = self.msock.fork_rng();
             let span = info_span!("conn-relay-actor", %url);
             async move {
                 let conn_actor = ConnectedRelayActor::new(
@@ -546,6 +993,14 @@ impl RelayActor {
                     relay_client,
                     relay_receiver,
                     relay_datagrams_queue,
+                    lost,
+                    lost_sender,
+                    msock_sender,
+                    keepalive,
+                    paused,
+                    health,
+                    events,
+                    rng,
                 );
 
                 if let Err(err) = conn_actor.run(conn_actor_inbox_rx).await {
@@ -556,8 +1011,14 @@ impl RelayActor {
         });
 
         // Insert, to make sure we do not attempt to double connect.
-        self.connected_relays
-            .insert(url.clone(), (conn_actor_inbox_tx, handle));
+        self.connected_relays.insert(
+            url.clone(),
+            ConnectedRelay {
+                sender: conn_actor_inbox_tx,
+                task: handle,
+                lost,
+            },
+        );
 
         inc!(MagicsockMetrics, num_relay_conns_added);
 
@@ -630,14 +1091,33 @@ impl RelayActor {
     }
 
     /// Closes the relay connection to the provided `url` and starts reconnecting it if it's
-    /// our current home relay.
+    /// our current home or standby relay.
     async fn close_or_reconnect_relay(&mut self, url: &RelayUrl, why: &'static str) {
         self.close_relay(url, why).await;
-        if self.msock.my_relay().as_ref() == Some(url) {
+        if self.is_kept_alive(url) {
             self.connect_relay(url, None).await;
         }
     }
 
+    /// Returns whether the connection to `url` is kept open even when it is not used.
+    fn is_kept_alive(&self, url: &RelayUrl) -> bool {
+        self.msock.my_relay().as_ref() == Some(url)
+            || self.msock.standby_relay().as_ref() == Some(url)
+    }
+
+    /// Returns the client of an existing relay connection.
+    async fn get_client(&mut self, url: &RelayUrl) -> Option<relay::client::Client> {
+        let (os, or) = oneshot::channel();
+        if self
+            .send_to_connected_relay(url, ConnectedRelayMessage::GetClient(os))
+            .await
+        {
+            or.await.ok()
+        } else {
+            None
+        }
+    }
+
     async fn clean_stale_relay(&mut self) {
         trace!(
             "checking {} relays for staleness",
@@ -646,12 +1126,16 @@ impl RelayActor {
         let now = Instant::now();
 
         let mut to_close = Vec::new();
-        for (i, (s, _)) in &self.connected_relays {
-            if Some(i) == self.msock.my_relay().as_ref() {
+        for (i, conn) in &self.connected_relays {
+            if self.is_kept_alive(i) {
                 continue;
             }
             let (os, or) = oneshot::channel();
-            match s.send(ConnectedRelayMessage::GetLastWrite(os)).await {
+            match conn
+                .sender
+                .send(ConnectedRelayMessage::GetLastWrite(os))
+                .await
+            {
                 Ok(_) => match or.await {
                     Ok(last_write) => {
                         if last_write.duration_since(now) > RELAY_INACTIVE_CLEANUP_TIME {
@@ -695,11 +1179,12 @@ impl RelayActor {
     }
 
     async fn close_relay(&mut self, url: &RelayUrl, why: &'static str) {
-        if let Some((s, t)) = self.connected_relays.remove(url) {
+        if let Some(conn) = self.connected_relays.remove(url) {
             debug!(%url, "closing connection: {}", why);
 
-            s.send(ConnectedRelayMessage::Shutdown).await.ok();
-            t.abort(); // ensure the task is shutdown
+            conn.sender.send(ConnectedRelayMessage::Shutdown).await.ok();
+            conn.task.abort(); // ensure the task is shutdown
+            self.msock.relay_health.lock().remove(url);
 
             inc!(MagicsockMetrics, num_relay_conns_removed);
         }
@@ -742,7 +1227,7 @@ pub(super) enum ReadResult {
     Continue,
 }
 
-/// Combines datagrams into a single DISCO frame of at most MAX_PACKET_SIZE.
+/// Combines datagrams into DISCO frames of at most `max_size` bytes.
 ///
 /// The disco `iroh_relay::protos::Frame::SendPacket` frame can contain more then a single
 /// datagram.  Each datagram in this frame is prefixed with a little-endian 2-byte length
@@ -751,23 +1236,25 @@ pub(super) enum ReadResult {
 ///
 /// The [`PacketSplitIter`] does the inverse and splits such packets back into individual
 /// datagrams.
-pub(super) struct PacketizeIter<I: Iterator, const N: usize> {
+pub(super) struct PacketizeIter<I: Iterator> {
     iter: std::iter::Peekable<I>,
     buffer: BytesMut,
+    max_size: usize,
 }
 
-impl<I: Iterator, const N: usize> PacketizeIter<I, N> {
+impl<I: Iterator> PacketizeIter<I> {
     /// Create a new new PacketizeIter from something that can be turned into an
     /// iterator of slices, like a `Vec<Bytes>`.
-    pub(super) fn new(iter: impl IntoIterator<IntoIter = I>) -> Self {
+    pub(super) fn new(iter: impl IntoIterator<IntoIter = I>, max_size: usize) -> Self {
         Self {
             iter: iter.into_iter().peekable(),
-            buffer: BytesMut::with_capacity(N),
+            buffer: BytesMut::with_capacity(max_size.min(MAX_PACKET_SIZE)),
+            max_size,
         }
     }
 }
 
-impl<I: Iterator, const N: usize> Iterator for PacketizeIter<I, N>
+impl<I: Iterator> Iterator for PacketizeIter<I>
 where
     I::Item: AsRef<[u8]>,
 {
@@ -777,9 +1264,9 @@ where
         use bytes::BufMut;
         while let Some(next_bytes) = self.iter.peek() {
             let next_bytes = next_bytes.as_ref();
-            assert!(next_bytes.len() + 2 <= N);
+            assert!(next_bytes.len() + 2 <= self.max_size);
             let next_length: u16 = next_bytes.len().try_into().expect("items < 64k size");
-            if self.buffer.len() + next_bytes.len() + 2 > N {
+            if self.buffer.len() + next_bytes.len() + 2 > self.max_size {
                 break;
             }
             self.buffer.put_u16_le(next_length);
@@ -841,26 +1328,56 @@ impl Iterator for PacketSplitIter {
 
 #[cfg(test)]
 mod tests {
+    use iroh_relay::MAX_LARGE_PACKET_SIZE;
+    use rand::SeedableRng;
+
     use super::*;
 
+    #[test]
+    fn test_relay_keepalive_config() {
+        let config = RelayKeepaliveConfig::default();
+        assert_eq!(config.ping_interval, None);
+        assert_eq!(config.pong_timeout, RELAY_PONG_TIMEOUT);
+
+        // The longest backoff is never shorter than the first one.
+        let config = RelayKeepaliveConfig::default()
+            .ping_interval(Duration::from_secs(30))
+            .reconnect_backoff(Duration::from_secs(2), Duration::from_secs(1));
+        assert_eq!(config.ping_interval, Some(Duration::from_secs(30)));
+        assert_eq!(config.backoff_max, Duration::from_secs(2));
+        let mut backoff = config.backoff(StdRng::seed_from_u64(0));
+        let first = backoff.next_backoff().unwrap();
+        // The backoff is randomized by up to half the interval.
+        assert!(first >= Duration::from_secs(1) && first <= Duration::from_secs(3));
+        // The same seed gives the same delays.
+        let mut same_seed = config.backoff(StdRng::seed_from_u64(0));
+        assert_eq!(same_seed.next_backoff(), Some(first));
+    }
+
     #[test]
     fn test_packetize_iter() {
         let empty_vec: Vec<Bytes> = Vec::new();
-        let mut iter = PacketizeIter::<_, MAX_PACKET_SIZE>::new(empty_vec);
+        let mut iter = PacketizeIter::new(empty_vec, MAX_PACKET_SIZE);
         assert_eq!(None, iter.next());
 
         let single_vec = vec!["Hello"];
-        let iter = PacketizeIter::<_, MAX_PACKET_SIZE>::new(single_vec);
+        let iter = PacketizeIter::new(single_vec, MAX_PACKET_SIZE);
         let result = iter.collect::<Vec<_>>();
         assert_eq!(1, result.len());
         assert_eq!(&[5, 0, b'H', b'e', b'l', b'l', b'o'], &result[0][..]);
 
         let spacer = vec![0u8; MAX_PACKET_SIZE - 10];
         let multiple_vec = vec![&b"Hello"[..], &spacer, &b"World"[..]];
-        let iter = PacketizeIter::<_, MAX_PACKET_SIZE>::new(multiple_vec);
+        let iter = PacketizeIter::new(multiple_vec.clone(), MAX_PACKET_SIZE);
         let result = iter.collect::<Vec<_>>();
         assert_eq!(2, result.len());
         assert_eq!(&[5, 0, b'H', b'e', b'l', b'l', b'o'], &result[0][..7]);
         assert_eq!(&[5, 0, b'W', b'o', b'r', b'l', b'd'], &result[1][..]);
+
+        // With large packets everything fits into a single packet.
+        let iter = PacketizeIter::new(multiple_vec, MAX_LARGE_PACKET_SIZE);
+        let result = iter.collect::<Vec<_>>();
+        assert_eq!(1, result.len());
+        assert_eq!(MAX_PACKET_SIZE + 6, result[0].len());
     }
 }
@@ -0,0 +1,388 @@
+//! Copies of the packets sent and received by the magic socket, for debugging.
+//!
+//! A [`PacketTap`] set with [`Builder::packet_tap`] sees every UDP datagram and every relay
+//! frame, annotated with the remote node and the path.  The [`PcapngWriter`] writes them to
+//! a pcapng file which can be opened in Wireshark, without needing the privileges to
+//! capture on the network interface.
+//!
+//! [`Builder::packet_tap`]: crate::endpoint::Builder::packet_tap
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use iroh_base::key::NodeId;
+use iroh_relay::{protos::stun, RelayUrl};
+use tracing::warn;
+
+use crate::disco;
+
+/// Receives copies of the packets sent and received by an endpoint.
+///
+/// The tap is called synchronously on the paths sending and receiving packets, so it must
+/// return quickly and must not block.  It is set using [`Builder::packet_tap`].
+///
+/// [`Builder::packet_tap`]: crate::endpoint::Builder::packet_tap
+pub trait PacketTap: std::fmt::Debug + Send + Sync + 'static {
+    /// Called for each packet sent or received.
+    fn packet(&self, packet: &TappedPacket<'_>);
+}
+
+/// A packet seen by a [`PacketTap`].
+#[derive(Debug, Clone, Copy)]
+pub struct TappedPacket<'a> {
+    /// Whether the packet was sent or received.
+    pub direction: TapDirection,
+    /// The path the packet was sent or received on.
+    pub path: TapPath<'a>,
+    /// The remote node, if known.
+    ///
+    /// This is `None` for STUN packets and for QUIC packets from unknown addresses.
+    pub node_id: Option<NodeId>,
+    /// What the packet contains.
+    pub kind: PacketKind,
+    /// The contents of the UDP datagram or relay frame.
+    pub data: &'a [u8],
+}
+
+/// Whether a [`TappedPacket`] was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum TapDirection {
+    /// The packet was sent by this endpoint.
+    #[display("send")]
+    Send,
+    /// The packet was received by this endpoint.
+    #[display("recv")]
+    Recv,
+}
+
+/// The path of a [`TappedPacket`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum TapPath<'a> {
+    /// A UDP datagram sent directly to or received from the `remote` address.
+    #[display("udp({remote})")]
+    Udp {
+        /// The address of the local socket, if known.
+        local: Option<SocketAddr>,
+        /// The address of the remote side.
+        remote: SocketAddr,
+    },
+    /// A frame sent or received via this relay server.
+    #[display("relay({_0})")]
+    Relay(&'a RelayUrl),
+}
+
+/// What a [`TappedPacket`] contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum PacketKind {
+    /// A QUIC packet.
+    #[display("quic")]
+    Quic,
+    /// A DISCO message establishing the paths between nodes.
+    #[display("disco")]
+    Disco,
+    /// A STUN request or response.
+    #[display("stun")]
+    Stun,
+}
+
+impl PacketKind {
+    /// Returns the kind of the packet with these contents.
+    pub(super) fn of(data: &[u8]) -> Self {
+        if stun::is(data) {
+            Self::Stun
+        } else if disco::source_and_box(data).is_some() {
+            Self::Disco
+        } else {
+            Self::Quic
+        }
+    }
+}
+
+/// The pcapng link type of raw IPv4 and IPv6 packets.
+const LINKTYPE_RAW: u16 = 101;
+
+/// A [`PacketTap`] writing the packets to a pcapng file.
+///
+/// The packets are written with IP and UDP headers made up from the addresses of the path,
+/// so that Wireshark can dissect them.  Relay frames have no addresses, they are written
+/// with unspecified IPv6 addresses and port zero.  Each packet carries a comment with the
+/// remote node, the path and the kind of packet, and its direction is recorded in the
+/// packet flags.
+///
+/// The QUIC packets are encrypted, to decrypt them in Wireshark also configure
+/// [`Builder::keylog`].
+///
+/// Writing stops at the first error.
+///
+/// [`Builder::keylog`]: crate::endpoint::Builder::keylog
+#[derive(derive_more::Debug)]
+pub struct PcapngWriter {
+    #[debug("Box<dyn Write>")]
+    writer: parking_lot::Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl PcapngWriter {
+    /// Creates a writer which writes to the file at `path`, replacing an existing file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Self::new(BufWriter::new(file))
+    }
+
+    /// Creates a writer which writes to `writer`.
+    ///
+    /// The pcapng header is written immediately.
+    pub fn new(mut writer: impl Write + Send + 'static) -> io::Result<Self> {
+        // Section header block.
+        let mut shb = Vec::with_capacity(16);
+        shb.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, 0x0A0D_0D0A, &shb)?;
+        // Interface description block, timestamps are in microseconds by default.
+        let mut idb = Vec::with_capacity(8);
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut writer, 1, &idb)?;
+        Ok(Self {
+            writer: parking_lot::Mutex::new(Some(Box::new(writer))),
+        })
+    }
+
+    /// Writes any buffered packets to the underlying writer.
+    pub fn flush(&self) -> io::Result<()> {
+        match self.writer.lock().as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl PacketTap for PcapngWriter {
+    fn packet(&self, packet: &TappedPacket<'_>) {
+        let mut writer = self.writer.lock();
+        let Some(inner) = writer.as_mut() else {
+            return;
+        };
+        if let Err(err) = write_block(inner, 6, &enhanced_packet_block(packet)) {
+            warn!("failed to write packet capture, stopping: {err:#}");
+            *writer = None;
+        }
+    }
+}
+
+/// Writes a pcapng block with the given type and body.
+///
+/// The body must be padded to 32 bits already.
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let len = (body.len() + 12) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+/// Returns the body of the enhanced packet block for a packet.
+fn enhanced_packet_block(packet: &TappedPacket<'_>) -> Vec<u8> {
+    let data = ip_packet(packet);
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default();
+
+    let mut body = Vec::with_capacity(data.len() + 64);
+    body.extend_from_slice(&0u32.to_le_bytes()); // interface id
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&data);
+    pad(&mut body);
+
+    // The epb_flags option, the lowest two bits are the direction.
+    let flags: u32 = match packet.direction {
+        TapDirection::Recv => 0b01,
+        TapDirection::Send => 0b10,
+    };
+    push_option(&mut body, 2, &flags.to_le_bytes());
+    let mut comment = format!("{} {}", packet.kind, packet.path);
+    if let Some(node_id) = packet.node_id {
+        write!(comment, " node {node_id}").ok();
+    }
+    push_option(&mut body, 1, comment.as_bytes());
+    push_option(&mut body, 0, &[]);
+    body
+}
+
+/// Appends a pcapng option to a block body.
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+/// Pads to the next multiple of 32 bits.
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// Returns the packet with made up IP and UDP headers.
+fn ip_packet(packet: &TappedPacket<'_>) -> Vec<u8> {
+    let (local, remote) = match packet.path {
+        TapPath::Udp { local, remote } => {
+            let local = local
+                .filter(|local| local.is_ipv4() == remote.is_ipv4())
+                .unwrap_or_else(|| match remote {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                });
+            (local, remote)
+        }
+        TapPath::Relay(_) => {
+            let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0));
+            (addr, addr)
+        }
+    };
+    let (src, dst) = match packet.direction {
+        TapDirection::Send => (local, remote),
+        TapDirection::Recv => (remote, local),
+    };
+
+    let udp_len = (packet.data.len() + 8).min(u16::MAX as usize) as u16;
+    let mut buf = Vec::with_capacity(packet.data.len() + 48);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let total_len = udp_len.saturating_add(20);
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&total_len.to_be_bytes());
+            header[6] = 0x40; // don't fragment
+            header[8] = 64; // ttl
+            header[9] = 17; // udp
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            buf.extend_from_slice(&header);
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            buf.extend_from_slice(&[0x60, 0, 0, 0]);
+            buf.extend_from_slice(&udp_len.to_be_bytes());
+            buf.push(17); // udp
+            buf.push(64); // hop limit
+            buf.extend_from_slice(&to_v6(src_ip).octets());
+            buf.extend_from_slice(&to_v6(dst_ip).octets());
+        }
+    }
+    buf.extend_from_slice(&src.port().to_be_bytes());
+    buf.extend_from_slice(&dst.port().to_be_bytes());
+    buf.extend_from_slice(&udp_len.to_be_bytes());
+    // A zero checksum means no checksum was computed.
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf.extend_from_slice(packet.data);
+    buf
+}
+
+/// Computes the checksum of an IPv4 header.
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    let sum = (sum & 0xffff) + (sum >> 16);
+    !(((sum & 0xffff) + (sum >> 16)) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuf(Arc<parking_lot::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pcapng_writer() {
+        let buf = SharedBuf::default();
+        let writer = PcapngWriter::new(buf.clone()).unwrap();
+        let node_id = SecretKey::generate().public();
+        let relay_url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        writer.packet(&TappedPacket {
+            direction: TapDirection::Send,
+            path: TapPath::Udp {
+                local: Some("192.0.2.1:1234".parse().unwrap()),
+                remote: "198.51.100.1:4321".parse().unwrap(),
+            },
+            node_id: Some(node_id),
+            kind: PacketKind::Quic,
+            data: b"hello",
+        });
+        writer.packet(&TappedPacket {
+            direction: TapDirection::Recv,
+            path: TapPath::Relay(&relay_url),
+            node_id: Some(node_id),
+            kind: PacketKind::Quic,
+            data: b"world!",
+        });
+
+        let data = buf.0.lock().clone();
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+        };
+        // Walk the blocks, each ends with its length.
+        let mut types = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = read_u32(offset + 4);
+            assert_eq!(len % 4, 0);
+            assert_eq!(read_u32(offset + len - 4), len);
+            types.push(read_u32(offset));
+            offset += len;
+        }
+        assert_eq!(offset, data.len());
+        assert_eq!(types, vec![0x0A0D_0D0A, 1, 6, 6]);
+
+        // The first packet is an IPv4 UDP packet.
+        let epb = 28 + 20;
+        let captured_len = read_u32(epb + 20);
+        assert_eq!(captured_len, 20 + 8 + 5);
+        let ip = &data[epb + 28..epb + 28 + captured_len];
+        assert_eq!(ip[0], 0x45);
+        assert_eq!(ipv4_checksum(&ip[..20]), 0);
+        assert_eq!(&ip[12..16], &[192, 0, 2, 1]);
+        assert_eq!(u16::from_be_bytes([ip[20], ip[21]]), 1234);
+        assert_eq!(&ip[28..], b"hello");
+    }
+
+    #[test]
+    fn test_packet_kind() {
+        assert_eq!(PacketKind::of(b"\xc0quic"), PacketKind::Quic);
+        let txid = stun::TransactionId::default();
+        assert_eq!(PacketKind::of(&stun::request(txid)), PacketKind::Stun);
+    }
+}
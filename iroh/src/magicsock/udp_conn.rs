@@ -1,43 +1,100 @@
 use std::{
     fmt::Debug,
+    future::Future,
     io,
     net::SocketAddr,
     pin::Pin,
-    sync::Arc,
-    task::{Context, Poll},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
 };
 
 use anyhow::{bail, Context as _};
+use iroh_relay::client::bind_to_interface;
+use net_report::StunSocket;
 use netwatch::UdpSocket;
 use quinn::AsyncUdpSocket;
 use quinn_udp::Transmit;
+use socket2::SockRef;
+use tokio::io::Interest;
 use tracing::debug;
 
+#[cfg(any(test, feature = "test-utils"))]
+use crate::test_utils::MemorySocket;
+
 /// A UDP socket implementing Quinn's [`AsyncUdpSocket`].
 #[derive(Debug, Clone)]
 pub struct UdpConn {
-    io: Arc<UdpSocket>,
+    io: Io,
+}
+
+/// The size of the send and receive buffers of [`ConfiguredSocket`]s.
+///
+/// This matches the buffers of the sockets bound by netwatch.
+const SOCKET_BUFFER_SIZE: usize = 7 << 20;
+
+/// Options applied to the UDP sockets when binding them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct SocketOptions {
+    /// The DSCP codepoint to mark sent packets with.
+    pub(crate) dscp: Option<u8>,
+    /// The name of the network interface to bind the sockets to.
+    pub(crate) interface: Option<String>,
+}
+
+/// The socket backing a [`UdpConn`].
+#[derive(Debug, Clone)]
+enum Io {
+    /// A UDP socket of the operating system.
+    Socket(Arc<UdpSocket>),
+    /// A UDP socket of the operating system with [`SocketOptions`] applied.
+    Configured(Arc<ConfiguredSocket>),
+    /// A socket of a [`MemoryNetwork`](crate::test_utils::MemoryNetwork).
+    #[cfg(any(test, feature = "test-utils"))]
+    Memory(Arc<MemorySocket>),
 }
 
 impl UdpConn {
-    pub(super) fn as_socket(&self) -> Arc<UdpSocket> {
-        self.io.clone()
+    /// Returns the UDP socket, `None` if this is not backed by one.
+    pub(super) fn as_socket(&self) -> Option<OsSocket> {
+        match self.io {
+            Io::Socket(ref io) => Some(OsSocket::Netwatch(io.clone())),
+            Io::Configured(ref io) => Some(OsSocket::Configured(io.clone())),
+            #[cfg(any(test, feature = "test-utils"))]
+            Io::Memory(_) => None,
+        }
     }
 
-    pub(super) fn as_socket_ref(&self) -> &UdpSocket {
-        &self.io
+    pub(super) fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        Self::bind_with_options(addr, &SocketOptions::default())
     }
 
-    pub(super) fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
-        let sock = bind(addr)?;
+    /// Binds a socket with the `options` applied.
+    pub(super) fn bind_with_options(
+        addr: SocketAddr,
+        options: &SocketOptions,
+    ) -> anyhow::Result<Self> {
+        bind(addr, options)
+    }
 
-        Ok(Self { io: Arc::new(sock) })
+    /// Wraps a socket of a [`MemoryNetwork`](crate::test_utils::MemoryNetwork).
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(super) fn memory(socket: MemorySocket) -> Self {
+        Self {
+            io: Io::Memory(Arc::new(socket)),
+        }
     }
 
     pub fn port(&self) -> u16 {
         self.local_addr().map(|p| p.port()).unwrap_or_default()
     }
 
+    pub(super) fn poll_writable(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.io.poll_writable(cx)
+    }
+
     pub(super) fn create_io_poller(&self) -> Pin<Box<dyn quinn::UdpPoller>> {
         Box::pin(IoPoller {
             io: self.io.clone(),
@@ -45,13 +102,30 @@ impl UdpConn {
     }
 }
 
+impl Io {
+    fn poll_writable(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self {
+            Io::Socket(io) => io.poll_writable(cx),
+            Io::Configured(io) => io.socket.poll_send_ready(cx),
+            // Sending on a memory network never blocks.
+            #[cfg(any(test, feature = "test-utils"))]
+            Io::Memory(_) => Poll::Ready(Ok(())),
+        }
+    }
+}
+
 impl AsyncUdpSocket for UdpConn {
     fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn quinn::UdpPoller>> {
         (*self).create_io_poller()
     }
 
     fn try_send(&self, transmit: &Transmit<'_>) -> io::Result<()> {
-        self.io.try_send_quinn(transmit)
+        match self.io {
+            Io::Socket(ref io) => io.try_send_quinn(transmit),
+            Io::Configured(ref io) => io.try_send(transmit),
+            #[cfg(any(test, feature = "test-utils"))]
+            Io::Memory(ref io) => io.try_send(transmit),
+        }
     }
 
     fn poll_recv(
@@ -60,27 +134,54 @@ impl AsyncUdpSocket for UdpConn {
         bufs: &mut [io::IoSliceMut<'_>],
         meta: &mut [quinn_udp::RecvMeta],
     ) -> Poll<io::Result<usize>> {
-        self.io.poll_recv_quinn(cx, bufs, meta)
+        match self.io {
+            Io::Socket(ref io) => io.poll_recv_quinn(cx, bufs, meta),
+            Io::Configured(ref io) => io.poll_recv(cx, bufs, meta),
+            #[cfg(any(test, feature = "test-utils"))]
+            Io::Memory(ref io) => io.poll_recv(cx, bufs, meta),
+        }
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.io.local_addr()
+        match self.io {
+            Io::Socket(ref io) => io.local_addr(),
+            Io::Configured(ref io) => io.socket.local_addr(),
+            #[cfg(any(test, feature = "test-utils"))]
+            Io::Memory(ref io) => Ok(io.local_addr()),
+        }
     }
 
     fn may_fragment(&self) -> bool {
-        self.io.may_fragment()
+        match self.io {
+            Io::Socket(ref io) => io.may_fragment(),
+            Io::Configured(ref io) => io.state.may_fragment(),
+            #[cfg(any(test, feature = "test-utils"))]
+            Io::Memory(_) => false,
+        }
     }
 
     fn max_transmit_segments(&self) -> usize {
-        self.io.max_gso_segments()
+        match self.io {
+            Io::Socket(ref io) => io.max_gso_segments(),
+            // Packets marked with a DSCP codepoint are sent one by one.
+            Io::Configured(ref io) if io.dscp.is_some() => 1,
+            Io::Configured(ref io) => io.state.max_gso_segments(),
+            #[cfg(any(test, feature = "test-utils"))]
+            Io::Memory(_) => 1,
+        }
     }
 
     fn max_receive_segments(&self) -> usize {
-        self.io.gro_segments()
+        match self.io {
+            Io::Socket(ref io) => io.gro_segments(),
+            Io::Configured(ref io) => io.state.gro_segments(),
+            #[cfg(any(test, feature = "test-utils"))]
+            Io::Memory(_) => 1,
+        }
     }
 }
 
-fn bind(mut addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+fn bind(mut addr: SocketAddr, options: &SocketOptions) -> anyhow::Result<UdpConn> {
     debug!(%addr, "binding");
 
     // Build a list of preferred ports.
@@ -100,11 +201,17 @@ fn bind(mut addr: SocketAddr) -> anyhow::Result<UdpSocket> {
 
     for port in &ports {
         addr.set_port(*port);
-        match UdpSocket::bind_full(addr) {
-            Ok(pconn) => {
-                let local_addr = pconn.local_addr().context("UDP socket not bound")?;
+        let bound = if *options == SocketOptions::default() {
+            UdpSocket::bind_full(addr).map(|sock| Io::Socket(Arc::new(sock)))
+        } else {
+            ConfiguredSocket::bind(addr, options).map(|sock| Io::Configured(Arc::new(sock)))
+        };
+        match bound {
+            Ok(io) => {
+                let conn = UdpConn { io };
+                let local_addr = conn.local_addr().context("UDP socket not bound")?;
                 debug!(%addr, %local_addr, "successfully bound");
-                return Ok(pconn);
+                return Ok(conn);
             }
             Err(err) => {
                 debug!(%addr, "failed to bind: {err:#}");
@@ -117,10 +224,183 @@ fn bind(mut addr: SocketAddr) -> anyhow::Result<UdpSocket> {
     bail!("failed to bind any ports on {:?} (tried {:?})", addr, ports);
 }
 
+/// A UDP socket of the operating system backing a [`UdpConn`].
+#[derive(Debug, Clone)]
+pub(super) enum OsSocket {
+    /// A socket which rebinds itself if it breaks.
+    Netwatch(Arc<UdpSocket>),
+    /// A socket with [`SocketOptions`] applied.
+    Configured(Arc<ConfiguredSocket>),
+}
+
+impl OsSocket {
+    /// Rebinds the socket after a major network change.
+    ///
+    /// Sockets with [`SocketOptions`] are kept as they are.
+    pub(super) fn rebind(&self) -> io::Result<()> {
+        match self {
+            Self::Netwatch(sock) => sock.rebind(),
+            Self::Configured(_) => Ok(()),
+        }
+    }
+
+    pub(super) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Netwatch(sock) => sock.local_addr(),
+            Self::Configured(sock) => sock.socket.local_addr(),
+        }
+    }
+
+    /// Returns the socket to send the STUN probes of net_report from.
+    pub(super) fn stun_socket(&self) -> Arc<dyn StunSocket> {
+        match self {
+            Self::Netwatch(sock) => sock.clone(),
+            Self::Configured(sock) => sock.clone(),
+        }
+    }
+}
+
+/// A UDP socket with [`SocketOptions`] applied.
+///
+/// Unlike the sockets bound by netwatch this does not rebind itself if it breaks, as the
+/// options would be lost.
+#[derive(Debug)]
+pub(super) struct ConfiguredSocket {
+    socket: tokio::net::UdpSocket,
+    state: quinn_udp::UdpSocketState,
+    ipv6: bool,
+    dscp: Option<u8>,
+    /// The TOS byte, or IPv6 traffic class, currently set on the socket.
+    tos: AtomicU8,
+}
+
+impl ConfiguredSocket {
+    fn bind(addr: SocketAddr, options: &SocketOptions) -> io::Result<Self> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+        if let Err(err) = socket.set_recv_buffer_size(SOCKET_BUFFER_SIZE) {
+            debug!("failed to set recv_buffer_size to {SOCKET_BUFFER_SIZE}: {err:?}");
+        }
+        if let Err(err) = socket.set_send_buffer_size(SOCKET_BUFFER_SIZE) {
+            debug!("failed to set send_buffer_size to {SOCKET_BUFFER_SIZE}: {err:?}");
+        }
+        let ipv6 = addr.is_ipv6();
+        if ipv6 {
+            // Avoid dualstack
+            socket.set_only_v6(true)?;
+        }
+        if let Some(ref interface) = options.interface {
+            bind_to_interface(SockRef::from(&socket), interface, ipv6)?;
+        }
+        let tos = options.dscp.map_or(0, |dscp| dscp << 2);
+        if options.dscp.is_some() {
+            set_traffic_class(SockRef::from(&socket), ipv6, tos)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.set_nonblocking(true)?;
+
+        let socket = tokio::net::UdpSocket::from_std(socket.into())?;
+        let state = quinn_udp::UdpSocketState::new((&socket).into())?;
+        Ok(Self {
+            socket,
+            state,
+            ipv6,
+            dscp: options.dscp,
+            tos: AtomicU8::new(tos),
+        })
+    }
+
+    fn try_send(&self, transmit: &Transmit<'_>) -> io::Result<()> {
+        let Some(dscp) = self.dscp else {
+            return self.socket.try_io(Interest::WRITABLE, || {
+                self.state.send((&self.socket).into(), transmit)
+            });
+        };
+        // quinn-udp sets the whole TOS byte to the ECN codepoint of each packet, which
+        // would clear the DSCP codepoint.  Packets are sent without it instead, using the
+        // TOS byte of the socket.
+        let tos = dscp << 2 | transmit.ecn.map_or(0, |ecn| ecn as u8);
+        if self.tos.swap(tos, Ordering::Relaxed) != tos {
+            set_traffic_class(SockRef::from(&self.socket), self.ipv6, tos)?;
+        }
+        let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+        for segment in transmit.contents.chunks(segment_size.max(1)) {
+            self.socket.try_send_to(segment, transmit.destination)?;
+        }
+        Ok(())
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.socket.poll_recv_ready(cx))?;
+            let res = self.socket.try_io(Interest::READABLE, || {
+                self.state.recv((&self.socket).into(), bufs, meta)
+            });
+            match res {
+                // Ignore spurious wakeups.
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+impl StunSocket for ConfiguredSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = io::Result<usize>> + Send + 'a>> {
+        Box::pin(self.socket.send_to(buf, addr))
+    }
+}
+
+/// Sets the TOS byte, or the traffic class for IPv6 sockets.
+///
+/// The upper six bits are the DSCP codepoint, the lower two the ECN codepoint.
+fn set_traffic_class(socket: SockRef<'_>, ipv6: bool, tos: u8) -> io::Result<()> {
+    if !ipv6 {
+        return socket.set_tos(tos.into());
+    }
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    return socket.set_tclass_v6(tos.into());
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "setting the IPv6 traffic class is not supported on this platform",
+    ))
+}
+
 /// Poller for when the socket is writable.
 #[derive(Debug)]
 struct IoPoller {
-    io: Arc<UdpSocket>,
+    io: Io,
 }
 
 impl quinn::UdpPoller for IoPoller {
@@ -161,7 +441,7 @@ mod tests {
     #[tokio::test]
     async fn test_rebinding_conn_send_recv_ipv4() -> Result<()> {
         let _guard = iroh_test::logging::setup();
-        rebinding_conn_send_recv(IpFamily::V4).await
+        rebinding_conn_send_recv(IpFamily::V4, &SocketOptions::default()).await
     }
 
     #[tokio::test]
@@ -170,14 +450,70 @@ mod tests {
         if !net_report::os_has_ipv6() {
             return Ok(());
         }
-        rebinding_conn_send_recv(IpFamily::V6).await
+        rebinding_conn_send_recv(IpFamily::V6, &SocketOptions::default()).await
+    }
+
+    #[tokio::test]
+    async fn test_dscp_conn_send_recv_ipv4() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let options = SocketOptions {
+            dscp: Some(46),
+            ..Default::default()
+        };
+        rebinding_conn_send_recv(IpFamily::V4, &options).await
+    }
+
+    #[tokio::test]
+    async fn test_dscp_sets_traffic_class() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let options = SocketOptions {
+            dscp: Some(46),
+            ..Default::default()
+        };
+        let sock = ConfiguredSocket::bind((std::net::Ipv4Addr::LOCALHOST, 0).into(), &options)?;
+        assert_eq!(SockRef::from(&sock.socket).tos()?, 46 << 2);
+
+        // The ECN codepoint of a packet is added to the DSCP codepoint.
+        let transmit = Transmit {
+            destination: sock.socket.local_addr()?,
+            ecn: Some(quinn_udp::EcnCodepoint::Ect0),
+            contents: b"hello",
+            segment_size: None,
+            src_ip: None,
+        };
+        sock.try_send(&transmit)?;
+        assert_eq!(SockRef::from(&sock.socket).tos()?, 46 << 2 | 0b10);
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if net_report::os_has_ipv6() {
+            let sock = ConfiguredSocket::bind((std::net::Ipv6Addr::LOCALHOST, 0).into(), &options)?;
+            assert_eq!(SockRef::from(&sock.socket).tclass_v6()?, 46 << 2);
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_bind_interface_conn_send_recv() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let options = SocketOptions {
+            interface: Some("lo".to_string()),
+            ..Default::default()
+        };
+        let sock = ConfiguredSocket::bind((std::net::Ipv4Addr::LOCALHOST, 0).into(), &options)?;
+        let device = SockRef::from(&sock.socket).device()?;
+        assert_eq!(device.as_deref(), Some(&b"lo"[..]));
+
+        rebinding_conn_send_recv(IpFamily::V4, &options).await
     }
 
-    async fn rebinding_conn_send_recv(network: IpFamily) -> Result<()> {
-        let m1 = UdpConn::bind(SocketAddr::new(network.unspecified_addr(), 0))?;
+    async fn rebinding_conn_send_recv(network: IpFamily, options: &SocketOptions) -> Result<()> {
+        let m1 =
+            UdpConn::bind_with_options(SocketAddr::new(network.unspecified_addr(), 0), options)?;
         let (m1, _m1_key) = wrap_socket(m1)?;
 
-        let m2 = UdpConn::bind(SocketAddr::new(network.unspecified_addr(), 0))?;
+        let m2 =
+            UdpConn::bind_with_options(SocketAddr::new(network.unspecified_addr(), 0), options)?;
         let (m2, _m2_key) = wrap_socket(m2)?;
 
         let m1_addr = SocketAddr::new(network.local_addr(), m1.local_addr()?.port());
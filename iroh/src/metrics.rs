@@ -1,8 +1,116 @@
 //! Co-locating all of the iroh metrics structs
+//!
+//! Metrics are only recorded once they are registered with the global
+//! [`iroh_metrics::core::Core`].  [`try_init_metrics_collection`] registers all metrics of
+//! iroh, and [`start_metrics_server`] also serves them in the Prometheus text format, e.g.
+//! for a node operator to scrape:
+//!
+//! ```no_run
+//! # async fn wrapper() -> anyhow::Result<()> {
+//! tokio::spawn(iroh::metrics::start_metrics_server("127.0.0.1:9090".parse()?));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Useful metrics are the bytes sent and received on each kind of path, e.g.
+//! `magicsock_send_ipv4` and `magicsock_recv_data_relay`, the number of connections opened
+//! and closed, the number of connections which became direct, which gives the hole
+//! punching success rate, and the latency to each relay server from the last net report.
+#[cfg(feature = "metrics")]
+use std::{
+    net::SocketAddr,
+    sync::{atomic::AtomicU64, OnceLock},
+    time::Duration,
+};
+
 #[cfg(feature = "test-utils")]
 #[cfg_attr(iroh_docsrs, doc(cfg(feature = "test-utils")))]
 pub use iroh_relay::server::Metrics as RelayMetrics;
+#[cfg(feature = "metrics")]
+use iroh_relay::RelayUrl;
 pub use net_report::Metrics as NetReportMetrics;
 pub use portmapper::Metrics as PortmapMetrics;
+#[cfg(feature = "metrics")]
+use prometheus_client::{
+    metrics::{family::Family, gauge::Gauge},
+    registry::Unit,
+};
 
 pub use crate::magicsock::Metrics as MagicsockMetrics;
+
+/// The latency to each relay server, labelled with the relay URL and IP family.
+#[cfg(feature = "metrics")]
+type RelayLatency = Family<Vec<(String, String)>, Gauge<f64, AtomicU64>>;
+
+#[cfg(feature = "metrics")]
+static RELAY_LATENCY: OnceLock<RelayLatency> = OnceLock::new();
+
+/// Registers all iroh metrics with the global [`iroh_metrics::core::Core`].
+///
+/// These are the [`MagicsockMetrics`], [`NetReportMetrics`] and [`PortmapMetrics`] and the
+/// relay server latencies.  Fails if the metrics were already initialized, e.g. by the
+/// application registering its own metrics.
+#[cfg(feature = "metrics")]
+#[cfg_attr(iroh_docsrs, doc(cfg(feature = "metrics")))]
+pub fn try_init_metrics_collection() -> std::io::Result<()> {
+    use iroh_metrics::core::{Core, Metric};
+
+    Core::try_init(|reg, metrics| {
+        metrics.insert(MagicsockMetrics::new(reg));
+        metrics.insert(NetReportMetrics::new(reg));
+        metrics.insert(PortmapMetrics::new(reg));
+        reg.register_with_unit(
+            "relay_latency",
+            "Latency to each relay server measured by the last net report",
+            Unit::Seconds,
+            RELAY_LATENCY.get_or_init(Default::default).clone(),
+        );
+    })
+}
+
+/// Serves the iroh metrics at `addr` in the Prometheus text format.
+///
+/// The metrics are registered with [`try_init_metrics_collection`] first, unless they were
+/// already initialized.  Runs until the server fails.
+#[cfg(feature = "metrics")]
+#[cfg_attr(iroh_docsrs, doc(cfg(feature = "metrics")))]
+pub async fn start_metrics_server(addr: SocketAddr) -> anyhow::Result<()> {
+    if try_init_metrics_collection().is_err() {
+        tracing::debug!("metrics already initialized");
+    }
+    iroh_metrics::metrics::start_metrics_server(addr).await
+}
+
+/// Records the latency to a relay server, if the metrics are initialized.
+#[cfg(feature = "metrics")]
+pub(crate) fn record_relay_latency(url: &RelayUrl, ip_family: &str, latency: Duration) {
+    if let Some(relay_latency) = RELAY_LATENCY.get() {
+        let labels = vec![
+            ("relay_url".to_string(), url.to_string()),
+            ("ip_family".to_string(), ip_family.to_string()),
+        ];
+        relay_latency
+            .get_or_create(&labels)
+            .set(latency.as_secs_f64());
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use iroh_metrics::core::Core;
+
+    use super::*;
+
+    #[test]
+    fn test_metrics_collection() {
+        // Another test may have initialized the metrics already.
+        try_init_metrics_collection().ok();
+        let url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        record_relay_latency(&url, "ipv4", Duration::from_millis(20));
+
+        let encoded = Core::get().unwrap().encode().unwrap();
+        assert!(encoded.contains("magicsock_send_ipv4"));
+        assert!(encoded.contains("relay_latency_seconds"));
+        assert!(encoded.contains("relay.example.com"));
+    }
+}
@@ -38,7 +38,7 @@
 //!     }
 //! }
 //! ```
-use std::{collections::BTreeMap, sync::Arc};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use futures_buffered::join_all;
@@ -94,6 +94,7 @@ pub struct Router {
 pub struct RouterBuilder {
     endpoint: Endpoint,
     protocols: ProtocolMap,
+    shutdown_grace_period: Duration,
 }
 
 /// Handler for incoming connections.
@@ -111,7 +112,19 @@ pub trait ProtocolHandler: Send + Sync + std::fmt::Debug + 'static {
     /// This runs on a freshly spawned tokio task so this can be long-running.
     fn accept(&self, conn: Connecting) -> BoxedFuture<Result<()>>;
 
+    /// Called when the router starts shutting down.
+    ///
+    /// At this point no new connections are accepted anymore, but connections which are
+    /// already being handled keep running.  Handlers should use this to wrap up in-flight
+    /// work and close their connections.  Connections still open once the grace period set
+    /// by [`RouterBuilder::shutdown_grace_period`] has elapsed are closed forcefully.
+    fn drain(&self) -> BoxedFuture<()> {
+        Box::pin(async move {})
+    }
+
     /// Called when the node shuts down.
+    ///
+    /// This is called after the endpoint has been closed.
     fn shutdown(&self) -> BoxedFuture<()> {
         Box::pin(async move {})
     }
@@ -122,6 +135,10 @@ impl<T: ProtocolHandler> ProtocolHandler for Arc<T> {
         self.as_ref().accept(conn)
     }
 
+    fn drain(&self) -> BoxedFuture<()> {
+        self.as_ref().drain()
+    }
+
     fn shutdown(&self) -> BoxedFuture<()> {
         self.as_ref().shutdown()
     }
@@ -132,6 +149,10 @@ impl<T: ProtocolHandler> ProtocolHandler for Box<T> {
         self.as_ref().accept(conn)
     }
 
+    fn drain(&self) -> BoxedFuture<()> {
+        self.as_ref().drain()
+    }
+
     fn shutdown(&self) -> BoxedFuture<()> {
         self.as_ref().shutdown()
     }
@@ -157,6 +178,14 @@ impl ProtocolMap {
         self.0.keys()
     }
 
+    /// Notifies all protocol handlers that the router is shutting down.
+    ///
+    /// Calls and awaits [`ProtocolHandler::drain`] for all registered handlers concurrently.
+    pub(crate) async fn drain(&self) {
+        let handlers = self.0.values().map(|p| p.drain());
+        join_all(handlers).await;
+    }
+
     /// Shuts down all protocol handlers.
     ///
     /// Calls and awaits [`ProtocolHandler::shutdown`] for all registered handlers concurrently.
@@ -184,6 +213,11 @@ impl Router {
 
     /// Shuts down the accept loop cleanly.
     ///
+    /// New connections are refused and all [`ProtocolHandler`]s are notified using
+    /// [`ProtocolHandler::drain`].  Connections which are still being handled get the grace
+    /// period configured with [`RouterBuilder::shutdown_grace_period`] to finish, after which
+    /// the endpoint is closed.
+    ///
     /// When this function returns, all [`ProtocolHandler`]s will be shutdown and
     /// `Endpoint::close` will have been called.
     ///
@@ -214,6 +248,7 @@ impl RouterBuilder {
         Self {
             endpoint,
             protocols: ProtocolMap::default(),
+            shutdown_grace_period: Duration::ZERO,
         }
     }
 
@@ -225,6 +260,20 @@ impl RouterBuilder {
         self
     }
 
+    /// Sets how long connections may keep running when the router shuts down.
+    ///
+    /// On [`Router::shutdown`] the endpoint waits up to this long for connections to be
+    /// closed, giving [`ProtocolHandler`]s the chance to finish in-flight streams.  Any
+    /// connections still open afterwards are closed with [`ENDPOINT_CLOSED_CODE`].
+    ///
+    /// Defaults to zero, closing all connections right away.
+    ///
+    /// [`ENDPOINT_CLOSED_CODE`]: crate::endpoint::ENDPOINT_CLOSED_CODE
+    pub fn shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.shutdown_grace_period = grace_period;
+        self
+    }
+
     /// Returns the [`Endpoint`] of the node.
     pub fn endpoint(&self) -> &Endpoint {
         &self.endpoint
@@ -241,9 +290,10 @@ impl RouterBuilder {
 
         let protocols = Arc::new(self.protocols);
         if let Err(err) = self.endpoint.set_alpns(alpns) {
-            shutdown(&self.endpoint, protocols.clone()).await;
+            shutdown(&self.endpoint, protocols.clone(), Duration::ZERO).await;
             return Err(err);
         }
+        let grace_period = self.shutdown_grace_period;
 
         let mut join_set = JoinSet::new();
         let endpoint = self.endpoint.clone();
@@ -294,7 +344,9 @@ impl RouterBuilder {
                         };
 
                         let protocols = protocols.clone();
-                        let token = cancel_token.child_token();
+                        // Connection handlers keep running during the grace period, they
+                        // are only cancelled once the endpoint is closed.
+                        let token = endpoint.cancel_token().child_token();
                         join_set.spawn(async move {
                             token.run_until_cancelled(handle_connection(incoming, protocols)).await
                         }.instrument(info_span!("router.accept")));
@@ -302,7 +354,7 @@ impl RouterBuilder {
                 }
             }
 
-            shutdown(&endpoint, protocols).await;
+            shutdown(&endpoint, protocols, grace_period).await;
 
             // Abort remaining tasks.
             tracing::info!("Shutting down remaining tasks");
@@ -319,15 +371,17 @@ impl RouterBuilder {
     }
 }
 
-/// Shutdown the different parts of the router concurrently.
-async fn shutdown(endpoint: &Endpoint, protocols: Arc<ProtocolMap>) {
+/// Shutdown the different parts of the router.
+async fn shutdown(endpoint: &Endpoint, protocols: Arc<ProtocolMap>, grace_period: Duration) {
     // We ignore all errors during shutdown.
     let _ = tokio::join!(
-        // Close the endpoint.
-        endpoint.close(),
-        // Shutdown protocol handlers.
-        protocols.shutdown(),
+        // Close the endpoint once connections are done or the grace period elapsed.
+        endpoint.close_with_grace_period(grace_period),
+        // Let protocol handlers wrap up their connections.
+        tokio::time::timeout(grace_period, protocols.drain()),
     );
+    // Shutdown protocol handlers.
+    protocols.shutdown().await;
 }
 
 async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<ProtocolMap>) {
@@ -373,4 +427,66 @@ mod tests {
 
         Ok(())
     }
+
+    #[derive(Debug, Clone, Default)]
+    struct DrainingEcho {
+        drained: Arc<tokio::sync::Notify>,
+    }
+
+    impl ProtocolHandler for DrainingEcho {
+        fn accept(&self, connecting: Connecting) -> BoxedFuture<Result<()>> {
+            let drained = self.drained.clone();
+            Box::pin(async move {
+                let conn = connecting.await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                let msg = recv.read_to_end(100).await?;
+                // Only respond once the router asked us to wrap up.
+                drained.notified().await;
+                send.write_all(&msg).await?;
+                send.finish()?;
+                conn.closed().await;
+                Ok(())
+            })
+        }
+
+        fn drain(&self) -> BoxedFuture<()> {
+            self.drained.notify_one();
+            Box::pin(async move {})
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_grace_period() -> Result<()> {
+        const ALPN: &[u8] = b"/iroh/test/drain";
+        let _guard = iroh_test::logging::setup();
+        let endpoint = Endpoint::builder()
+            .relay_mode(crate::RelayMode::Disabled)
+            .bind()
+            .await?;
+        let router = Router::builder(endpoint.clone())
+            .accept(ALPN, DrainingEcho::default())
+            .shutdown_grace_period(Duration::from_secs(5))
+            .spawn()
+            .await?;
+        let addr = endpoint.node_addr().await?;
+
+        let client = Endpoint::builder()
+            .relay_mode(crate::RelayMode::Disabled)
+            .bind()
+            .await?;
+        let conn = client.connect(addr, ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish()?;
+
+        let shutdown = tokio::spawn(async move { router.shutdown().await });
+        // The in-flight stream is completed before the connection is closed.
+        let response = recv.read_to_end(100).await?;
+        assert_eq!(response, b"hello");
+        conn.close(0u8.into(), b"done");
+
+        shutdown.await??;
+        assert!(endpoint.is_closed());
+        Ok(())
+    }
 }
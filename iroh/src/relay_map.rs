@@ -0,0 +1,259 @@
+//! Building a [`RelayMap`] from relay servers probed at startup.
+//!
+//! Instead of using the default relay servers or a hand-written [`RelayMap`], a list of
+//! candidate relay servers can be probed with [`RelayMapProber`].  Each candidate is
+//! connected to and pinged, the reachable ones are ordered by their latency and the fastest
+//! are kept in the map.
+//!
+//! [`RelayMapExt::from_urls_probed`] is a shorthand which keeps all reachable servers.
+
+use std::{collections::BTreeSet, future::Future, time::Duration};
+
+use anyhow::{ensure, Context, Result};
+use iroh_base::relay_map::{QuicConfig, DEFAULT_STUN_PORT};
+use iroh_relay::client::ClientBuilder;
+use tracing::debug;
+
+use crate::{dns::DnsResolver, key::SecretKey, RelayMap, RelayNode, RelayUrl};
+
+/// The default time a candidate relay server has to answer the probe.
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Probes candidate relay servers to build a [`RelayMap`] of the fastest ones.
+///
+/// All candidates are probed concurrently: the prober connects to the relay server and
+/// measures the round trip time of a ping.  Candidates which can not be connected to or do
+/// not answer within the [timeout](Self::timeout) are left out.
+#[derive(Debug, Clone)]
+pub struct RelayMapProber {
+    urls: BTreeSet<RelayUrl>,
+    keep: Option<usize>,
+    timeout: Duration,
+    dns_resolver: Option<DnsResolver>,
+    #[cfg(any(test, feature = "test-utils"))]
+    insecure_skip_cert_verify: bool,
+}
+
+impl RelayMapProber {
+    /// Creates a prober for the relay servers at `urls`.
+    pub fn new(urls: impl IntoIterator<Item = RelayUrl>) -> Self {
+        Self {
+            urls: urls.into_iter().collect(),
+            keep: None,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+            dns_resolver: None,
+            #[cfg(any(test, feature = "test-utils"))]
+            insecure_skip_cert_verify: false,
+        }
+    }
+
+    /// Sets how many of the fastest reachable relay servers to keep in the map.
+    ///
+    /// By default all reachable relay servers are kept.
+    pub fn keep(mut self, count: usize) -> Self {
+        self.keep = Some(count);
+        self
+    }
+
+    /// Sets how long a relay server has to answer the probe, by default 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the DNS resolver to resolve the relay server URLs with.
+    ///
+    /// By default the [default resolver](crate::dns::default_resolver) is used.
+    pub fn dns_resolver(mut self, dns_resolver: DnsResolver) -> Self {
+        self.dns_resolver = Some(dns_resolver);
+        self
+    }
+
+    /// Skips verification of SSL certificates from relay servers.
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    #[cfg_attr(iroh_docsrs, doc(cfg(any(test, feature = "test-utils"))))]
+    pub fn insecure_skip_cert_verify(mut self, skip: bool) -> Self {
+        self.insecure_skip_cert_verify = skip;
+        self
+    }
+
+    /// Probes the relay servers.
+    ///
+    /// Fails if none of the relay servers is reachable.
+    pub async fn probe(self) -> Result<ProbedRelayMap> {
+        let dns_resolver = self.resolver();
+        let this = &self;
+        let dns_resolver = &dns_resolver;
+        let mut probes = futures_buffered::join_all(self.urls.iter().map(|url| async move {
+            let latency = match this.probe_one(url, dns_resolver).await {
+                Ok(latency) => Some(latency),
+                Err(err) => {
+                    debug!(%url, "relay server unreachable: {err:#}");
+                    None
+                }
+            };
+            RelayProbe {
+                url: url.clone(),
+                latency,
+            }
+        }))
+        .await;
+        // Reachable servers first, by latency.
+        probes.sort_by_key(|probe| (probe.latency.is_none(), probe.latency));
+
+        let reachable = probes.iter().filter(|probe| probe.latency.is_some());
+        let nodes: Vec<_> = reachable
+            .take(self.keep.unwrap_or(usize::MAX))
+            .map(|probe| RelayNode {
+                url: probe.url.clone(),
+                stun_only: false,
+                stun_port: DEFAULT_STUN_PORT,
+                quic: Some(QuicConfig::default()),
+                auth_token: None,
+            })
+            .collect();
+        ensure!(!nodes.is_empty(), "none of the relay servers is reachable");
+        Ok(ProbedRelayMap {
+            relay_map: RelayMap::from_nodes(nodes)?,
+            probes,
+        })
+    }
+
+    /// Returns the configured DNS resolver, or the default one.
+    pub(crate) fn resolver(&self) -> DnsResolver {
+        self.dns_resolver
+            .clone()
+            .unwrap_or_else(|| crate::dns::default_resolver().clone())
+    }
+
+    /// Connects to the relay server at `url` and returns the round trip time of a ping.
+    pub(crate) async fn probe_one(
+        &self,
+        url: &RelayUrl,
+        dns_resolver: &DnsResolver,
+    ) -> Result<Duration> {
+        let builder = ClientBuilder::new(url.clone()).pong_timeout(self.timeout);
+        #[cfg(any(test, feature = "test-utils"))]
+        let builder = builder.insecure_skip_cert_verify(self.insecure_skip_cert_verify);
+        // The relay server only sees an ephemeral key, not the key of the endpoint.
+        let (client, _receiver) = builder.build(SecretKey::generate(), dns_resolver.clone());
+        let res = tokio::time::timeout(self.timeout, async {
+            client.connect().await.context("connect")?;
+            client.ping().await.context("ping")
+        })
+        .await
+        .context("timeout")
+        .and_then(|res| res);
+        client.close().await.ok();
+        res
+    }
+}
+
+/// The result of probing a single relay server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayProbe {
+    /// The URL of the relay server.
+    pub url: RelayUrl,
+    /// The round trip time of a ping, `None` if the relay server was not reachable.
+    pub latency: Option<Duration>,
+}
+
+/// A [`RelayMap`] built by [`RelayMapProber`], together with the probe results.
+#[derive(Debug, Clone)]
+pub struct ProbedRelayMap {
+    relay_map: RelayMap,
+    probes: Vec<RelayProbe>,
+}
+
+impl ProbedRelayMap {
+    /// Returns the map of the fastest reachable relay servers.
+    pub fn relay_map(&self) -> &RelayMap {
+        &self.relay_map
+    }
+
+    /// Returns the results of all probed relay servers.
+    ///
+    /// The reachable relay servers come first, ordered by latency.
+    pub fn probes(&self) -> &[RelayProbe] {
+        &self.probes
+    }
+
+    /// Returns the measured latency of the relay server at `url`.
+    pub fn latency(&self, url: &RelayUrl) -> Option<Duration> {
+        self.probes
+            .iter()
+            .find(|probe| &probe.url == url)
+            .and_then(|probe| probe.latency)
+    }
+}
+
+impl From<ProbedRelayMap> for RelayMap {
+    fn from(value: ProbedRelayMap) -> Self {
+        value.relay_map
+    }
+}
+
+/// Extension trait to [`RelayMap`].
+pub trait RelayMapExt {
+    /// Builds a [`RelayMap`] of the reachable relay servers among `urls`.
+    ///
+    /// See [`RelayMapProber`] to keep only the fastest relay servers and to access the
+    /// measured latencies.
+    fn from_urls_probed(
+        urls: impl IntoIterator<Item = RelayUrl>,
+    ) -> impl Future<Output = Result<RelayMap>>;
+}
+
+impl RelayMapExt for RelayMap {
+    async fn from_urls_probed(urls: impl IntoIterator<Item = RelayUrl>) -> Result<RelayMap> {
+        let probed = RelayMapProber::new(urls).probe().await?;
+        Ok(probed.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::run_relay_server;
+
+    #[tokio::test]
+    async fn test_probe_relay_map() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+        let (_, url_a, _relay_a) = run_relay_server().await?;
+        let (_, url_b, _relay_b) = run_relay_server().await?;
+        // Nothing listens on this port.
+        let unreachable: RelayUrl = "https://127.0.0.1:1".parse()?;
+
+        let probed = RelayMapProber::new([url_a.clone(), url_b.clone(), unreachable.clone()])
+            .insecure_skip_cert_verify(true)
+            .probe()
+            .await?;
+        assert_eq!(probed.relay_map().len(), 2);
+        assert!(probed.relay_map().contains_node(&url_a));
+        assert!(probed.relay_map().contains_node(&url_b));
+        assert_eq!(probed.probes().len(), 3);
+        assert_eq!(probed.probes()[2].url, unreachable);
+        assert_eq!(probed.latency(&unreachable), None);
+        assert!(probed.latency(&url_a).is_some());
+
+        // Only the fastest relay server is kept.
+        let probed = RelayMapProber::new([url_a.clone(), url_b.clone(), unreachable.clone()])
+            .insecure_skip_cert_verify(true)
+            .keep(1)
+            .probe()
+            .await?;
+        assert_eq!(probed.relay_map().len(), 1);
+        let fastest = &probed.probes()[0].url;
+        assert!(probed.relay_map().contains_node(fastest));
+
+        // No reachable relay server.
+        let res = RelayMapProber::new([unreachable])
+            .timeout(Duration::from_secs(1))
+            .probe()
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+}
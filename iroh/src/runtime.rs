@@ -0,0 +1,90 @@
+//! The async runtime an endpoint runs its background tasks and timers on.
+//!
+//! An [`Endpoint`] spawns some of its background tasks, waits on some of its timers and
+//! drives its QUIC endpoint through a [`Runtime`], which is always [`TokioRuntime`] for now.
+//!
+//! This is not public: the UDP sockets, the relay connections and the actors still use
+//! tokio directly, so the endpoint can not run on another executor yet.
+//!
+//! [`Endpoint`]: crate::Endpoint
+
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use futures_lite::FutureExt;
+
+/// A boxed future run by a [`Runtime`].
+pub(crate) type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// An async runtime to spawn tasks and wait on timers.
+pub(crate) trait Runtime: std::fmt::Debug + Send + Sync + 'static {
+    /// Spawns `future` to run in the background.
+    ///
+    /// The task is detached, the endpoint does not wait for it to finish.
+    fn spawn(&self, future: BoxFuture);
+
+    /// Returns a future which completes after `duration`.
+    fn sleep(&self, duration: Duration) -> BoxFuture;
+
+    /// Returns the runtime the QUIC endpoint runs on.
+    fn quinn_runtime(&self) -> Arc<dyn quinn::Runtime>;
+}
+
+/// The [`Runtime`] using [tokio], the default.
+///
+/// [tokio]: https://tokio.rs
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+    fn spawn(&self, future: BoxFuture) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn quinn_runtime(&self) -> Arc<dyn quinn::Runtime> {
+        Arc::new(quinn::TokioRuntime)
+    }
+}
+
+/// The error returned by [`timeout`] when the duration elapsed.
+#[derive(Debug, thiserror::Error)]
+#[error("deadline has elapsed")]
+pub(crate) struct Elapsed;
+
+/// Runs `future` for at most `duration`, using the timers of `runtime`.
+pub(crate) async fn timeout<F: Future>(
+    runtime: &dyn Runtime,
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    let sleep = runtime.sleep(duration);
+    async { Ok(future.await) }
+        .or(async {
+            sleep.await;
+            Err(Elapsed)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let runtime = TokioRuntime;
+        let res = timeout(&runtime, Duration::from_secs(10), async { 1 }).await;
+        assert_eq!(res.unwrap(), 1);
+
+        let res = timeout(
+            &runtime,
+            Duration::from_millis(10),
+            std::future::pending::<()>(),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
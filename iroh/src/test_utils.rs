@@ -1,4 +1,37 @@
-//! Internal utilities to support testing.
+//! Utilities to support testing.
+//!
+//! These are available with the `test-utils` feature, so crates building on iroh can run
+//! end-to-end tests without the public infrastructure:
+//!
+//! - [`run_relay_server`] runs a relay server on localhost and returns a [`RelayMap`] for
+//!   it, which endpoints use with [`RelayMode::Custom`] and
+//!   [`Builder::insecure_skip_relay_cert_verify`].
+//! - [`run_dns_discovery_server`] runs a DNS server and a pkarr relay on localhost, which
+//!   endpoints built with [`DnsPkarrServer::endpoint_builder`] publish to and resolve
+//!   nodes from.
+//! - [`MemoryNetwork`] and [`SimNetwork`] connect endpoints without sockets.
+//! - [`Endpoint::faults`] injects failures into a running endpoint, like losing the relay
+//!   connections or the UDP path to a node.
+//!
+//! ```no_run
+//! # async fn wrapper() -> anyhow::Result<()> {
+//! use iroh::{test_utils, RelayMode};
+//!
+//! let (relay_map, _relay_url, _relay_server) = test_utils::run_relay_server().await?;
+//! let discovery = test_utils::run_dns_discovery_server().await?;
+//! let ep = discovery
+//!     .endpoint_builder()
+//!     .relay_mode(RelayMode::Custom(relay_map))
+//!     .insecure_skip_relay_cert_verify(true)
+//!     .bind()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`RelayMode::Custom`]: crate::RelayMode::Custom
+//! [`Endpoint::faults`]: crate::Endpoint::faults
+//! [`Builder::insecure_skip_relay_cert_verify`]: crate::endpoint::Builder::insecure_skip_relay_cert_verify
 use std::net::Ipv4Addr;
 
 use anyhow::Result;
@@ -7,6 +40,8 @@ pub use dns_server::create_dns_resolver;
 use iroh_relay::server::{
     CertConfig, QuicConfig, RelayConfig, Server, ServerConfig, StunConfig, TlsConfig,
 };
+pub(crate) use memory_network::MemorySocket;
+pub use memory_network::{LinkConditions, MemoryNetwork, NatType, SimNat, SimNetwork};
 use tokio::sync::oneshot;
 
 use crate::{defaults::DEFAULT_STUN_PORT, RelayMap, RelayNode, RelayUrl};
@@ -29,12 +64,24 @@ pub async fn run_relay_server() -> Result<(RelayMap, RelayUrl, Server)> {
     run_relay_server_with(
         Some(StunConfig {
             bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            rate_limit: None,
+            quic: false,
         }),
         true,
     )
     .await
 }
 
+/// Runs a DNS server and a pkarr relay for node discovery suitable for tests.
+///
+/// Both run on localhost on ephemeral ports.  Endpoints built with
+/// [`DnsPkarrServer::endpoint_builder`] publish their addresses to the pkarr relay and
+/// find other nodes using the DNS server.  The servers stop when the returned
+/// [`DnsPkarrServer`] is dropped.
+pub async fn run_dns_discovery_server() -> Result<DnsPkarrServer> {
+    DnsPkarrServer::run().await
+}
+
 /// Runs a relay server with STUN enabled suitable for tests.
 ///
 /// The returned `Url` is the url of the relay server in the returned [`RelayMap`].
@@ -43,6 +90,8 @@ pub async fn run_relay_server_with_stun() -> Result<(RelayMap, RelayUrl, Server)
     run_relay_server_with(
         Some(StunConfig {
             bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            rate_limit: None,
+            quic: false,
         }),
         false,
     )
@@ -82,6 +131,9 @@ pub async fn run_relay_server_with(
             http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
             tls: Some(tls),
             limits: Default::default(),
+            access: Default::default(),
+            mesh: None,
+            usage: None,
         }),
         quic,
         stun,
@@ -94,17 +146,681 @@ pub async fn run_relay_server_with(
         .unwrap();
     let quic = server
         .quic_addr()
-        .map(|addr| iroh_base::relay_map::QuicConfig { port: addr.port() });
+        .map(|addr| iroh_base::relay_map::QuicConfig {
+            port: addr.port(),
+            relay: false,
+        });
     let m = RelayMap::from_nodes([RelayNode {
         url: url.clone(),
         stun_only: false,
         stun_port: server.stun_addr().map_or(DEFAULT_STUN_PORT, |s| s.port()),
         quic,
+        auth_token: None,
     }])
     .unwrap();
     Ok((m, url, server))
 }
 
+pub(crate) mod memory_network {
+    //! A network of endpoints in one process, without sockets.
+
+    use std::{
+        collections::HashMap,
+        io,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use tokio::{sync::mpsc, time::Instant};
+
+    use crate::{endpoint::Builder, Endpoint, RelayMode};
+
+    /// The port of every socket on a [`MemoryNetwork`], each socket has an address of its own.
+    const PORT: u16 = 7842;
+
+    /// The conditions of the links between the sockets of a [`MemoryNetwork`].
+    ///
+    /// By default datagrams are delivered immediately, in order and without loss.
+    #[derive(Debug, Clone, Copy, Default, PartialEq)]
+    pub struct LinkConditions {
+        latency: Duration,
+        jitter: Duration,
+        loss: f64,
+        reorder: f64,
+        bandwidth: Option<u64>,
+    }
+
+    impl LinkConditions {
+        /// Sets the one-way delay of each datagram.
+        pub fn latency(mut self, latency: Duration) -> Self {
+            self.latency = latency;
+            self
+        }
+
+        /// Sets the maximum random delay added to the latency of each datagram.
+        ///
+        /// Datagrams which get a larger delay are overtaken by the ones sent after them.
+        pub fn jitter(mut self, jitter: Duration) -> Self {
+            self.jitter = jitter;
+            self
+        }
+
+        /// Sets the probability that a datagram is lost, from 0 to 1.
+        pub fn loss(mut self, probability: f64) -> Self {
+            self.loss = probability;
+            self
+        }
+
+        /// Sets the probability that a datagram is reordered, from 0 to 1.
+        ///
+        /// A reordered datagram is delayed by another [`LinkConditions::latency`], or by one
+        /// millisecond without latency, so datagrams sent after it overtake it.
+        pub fn reorder(mut self, probability: f64) -> Self {
+            self.reorder = probability;
+            self
+        }
+
+        /// Caps the rate at which each socket sends, in bytes per second.
+        ///
+        /// Datagrams sent faster queue up behind each other, the queue is not limited.
+        pub fn bandwidth(mut self, bytes_per_sec: u64) -> Self {
+            self.bandwidth = Some(bytes_per_sec);
+            self
+        }
+    }
+
+    /// The behaviour of a NAT, see [`SimNetwork::add_nat`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum NatType {
+        /// Maps each internal address to a single external port, and lets in datagrams
+        /// from any remote address.
+        ///
+        /// The external address is known in advance, so hole punching to nodes behind
+        /// such a NAT succeeds.
+        FullCone,
+        /// Maps each pair of internal and remote address to a new external port, and only
+        /// lets in datagrams from that remote address.
+        ///
+        /// Hole punching between two nodes behind symmetric NATs fails.
+        Symmetric,
+    }
+
+    /// A datagram in flight.
+    #[derive(Debug)]
+    struct Datagram {
+        src: SocketAddr,
+        data: Bytes,
+    }
+
+    /// An in-memory network connecting endpoints in one process.
+    ///
+    /// Endpoints built with [`Builder::memory_network`] exchange their packets over
+    /// channels instead of binding UDP sockets.  Each endpoint gets an IPv4 address of
+    /// its own in `10.0.0.0/8`, which it reports as its direct address.  Relay servers are
+    /// not reachable, use [`RelayMode::Disabled`].  To place endpoints behind NATs use a
+    /// [`SimNetwork`].
+    ///
+    /// Loss and reordering are decided by a random number generator seeded with
+    /// [`MemoryNetwork::with_seed`], so a test takes the same decisions on every run.
+    /// Latency is implemented with tokio timers, which advance instantly in tests using
+    /// `#[tokio::test(start_paused = true)]`.
+    #[derive(Debug, Clone)]
+    pub struct MemoryNetwork {
+        inner: Arc<Mutex<Network>>,
+        /// The address reserved for the next socket, see [`MemoryNetwork::reserve`].
+        host: Option<SocketAddr>,
+    }
+
+    #[derive(Debug)]
+    struct Network {
+        hosts: HashMap<SocketAddr, Host>,
+        nats: Vec<Nat>,
+        next_host: u32,
+        conditions: LinkConditions,
+        rng: StdRng,
+    }
+
+    #[derive(Debug)]
+    struct Host {
+        /// Delivers datagrams to the socket, `None` while no socket is bound.
+        sender: Option<mpsc::UnboundedSender<Datagram>>,
+        /// The index of the NAT the host is behind, if any.
+        nat: Option<usize>,
+        /// When the link of the host is free again, with a bandwidth cap.
+        busy_until: Option<Instant>,
+    }
+
+    #[derive(Debug)]
+    struct Nat {
+        ip: IpAddr,
+        nat_type: NatType,
+        /// The mappings by external port.
+        mappings: HashMap<u16, Mapping>,
+        next_port: u16,
+        next_host: u8,
+    }
+
+    #[derive(Debug)]
+    struct Mapping {
+        internal: SocketAddr,
+        /// The only remote address let in, for symmetric NATs.
+        remote: Option<SocketAddr>,
+    }
+
+    impl Nat {
+        /// Returns the external port for datagrams from `internal` to `remote`.
+        fn map(&mut self, internal: SocketAddr, remote: SocketAddr) -> u16 {
+            let remote = match self.nat_type {
+                NatType::FullCone => None,
+                NatType::Symmetric => Some(remote),
+            };
+            let existing = self
+                .mappings
+                .iter()
+                .find(|(_, mapping)| mapping.internal == internal && mapping.remote == remote);
+            if let Some((port, _)) = existing {
+                return *port;
+            }
+            let port = self.next_port;
+            self.next_port += 1;
+            self.mappings.insert(port, Mapping { internal, remote });
+            port
+        }
+
+        /// Returns the internal address datagrams from `remote` to `port` are let in to.
+        fn unmap(&self, port: u16, remote: SocketAddr) -> Option<SocketAddr> {
+            let mapping = self.mappings.get(&port)?;
+            match mapping.remote {
+                Some(allowed) if allowed != remote => None,
+                _ => Some(mapping.internal),
+            }
+        }
+    }
+
+    impl Network {
+        fn public_ip(&mut self) -> IpAddr {
+            let host = self.next_host;
+            self.next_host += 1;
+            Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) + host).into()
+        }
+
+        /// Returns the source and destination of a datagram after passing the NATs.
+        fn route(&mut self, src: SocketAddr, dst: SocketAddr) -> Option<(SocketAddr, SocketAddr)> {
+            let src_nat = self.hosts.get(&src)?.nat;
+            let dst_nat = self.hosts.get(&dst).map(|host| host.nat);
+            if src_nat.is_some() && dst_nat == Some(src_nat) {
+                // Both are behind the same NAT.
+                return Some((src, dst));
+            }
+            let src = match src_nat {
+                Some(index) => {
+                    let nat = &mut self.nats[index];
+                    SocketAddr::new(nat.ip, nat.map(src, dst))
+                }
+                None => src,
+            };
+            match dst_nat {
+                Some(None) => Some((src, dst)),
+                // The private address of a host behind another NAT.
+                Some(Some(_)) => None,
+                None => {
+                    let nat = self.nats.iter().find(|nat| nat.ip == dst.ip())?;
+                    Some((src, nat.unmap(dst.port(), src)?))
+                }
+            }
+        }
+    }
+
+    impl Default for MemoryNetwork {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl MemoryNetwork {
+        /// Creates a network with perfect links and the seed 0.
+        pub fn new() -> Self {
+            Self::with_seed(0)
+        }
+
+        /// Creates a network with perfect links, taking random decisions from `seed`.
+        pub fn with_seed(seed: u64) -> Self {
+            Self {
+                inner: Arc::new(Mutex::new(Network {
+                    hosts: HashMap::new(),
+                    nats: Vec::new(),
+                    next_host: 1,
+                    conditions: LinkConditions::default(),
+                    rng: StdRng::seed_from_u64(seed),
+                })),
+                host: None,
+            }
+        }
+
+        /// Sets the conditions of all links, applying to datagrams sent from now on.
+        pub fn set_conditions(&self, conditions: LinkConditions) {
+            self.inner.lock().expect("poisoned").conditions = conditions;
+        }
+
+        /// Returns the current conditions of the links.
+        pub fn conditions(&self) -> LinkConditions {
+            self.inner.lock().expect("poisoned").conditions
+        }
+
+        /// Reserves an address for a socket, behind the NAT with index `nat` if given.
+        ///
+        /// Returns a handle binding its socket to the reserved address, and the external
+        /// address of the socket if it is known in advance.
+        fn reserve(&self, nat: Option<usize>) -> (MemoryNetwork, Option<SocketAddr>) {
+            let mut network = self.inner.lock().expect("poisoned");
+            let (addr, external) = match nat {
+                None => (SocketAddr::new(network.public_ip(), PORT), None),
+                Some(index) => {
+                    let nat = &mut network.nats[index];
+                    nat.next_host += 1;
+                    let ip = Ipv4Addr::new(192, 168, index as u8, nat.next_host);
+                    let addr = SocketAddr::new(ip.into(), PORT);
+                    let external = match nat.nat_type {
+                        // The remote does not matter for the mapping of a full cone NAT.
+                        NatType::FullCone => Some(SocketAddr::new(nat.ip, nat.map(addr, addr))),
+                        NatType::Symmetric => None,
+                    };
+                    (addr, external)
+                }
+            };
+            network.hosts.insert(
+                addr,
+                Host {
+                    sender: None,
+                    nat,
+                    busy_until: None,
+                },
+            );
+            let handle = MemoryNetwork {
+                inner: self.inner.clone(),
+                host: Some(addr),
+            };
+            (handle, external)
+        }
+
+        /// Creates a socket on this network.
+        ///
+        /// The socket gets the reserved address of this handle, or a new public address.
+        pub(crate) fn bind(&self) -> MemorySocket {
+            let addr = match self.host {
+                Some(addr) => addr,
+                None => self.reserve(None).0.host.expect("reserved"),
+            };
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let mut network = self.inner.lock().expect("poisoned");
+            if let Some(host) = network.hosts.get_mut(&addr) {
+                host.sender = Some(sender);
+            }
+            MemorySocket {
+                addr,
+                network: self.clone(),
+                receiver: Mutex::new(receiver),
+            }
+        }
+
+        /// Delivers a datagram, subject to the NATs and link conditions.
+        ///
+        /// Datagrams to unknown or unreachable addresses are dropped, like UDP does.
+        fn send(&self, src: SocketAddr, dst: SocketAddr, data: Bytes) {
+            let mut network = self.inner.lock().expect("poisoned");
+            let Some((routed_src, routed_dst)) = network.route(src, dst) else {
+                return;
+            };
+            let Some(sender) = network
+                .hosts
+                .get(&routed_dst)
+                .and_then(|host| host.sender.clone())
+            else {
+                return;
+            };
+            let conditions = network.conditions;
+            if network.rng.gen_bool(conditions.loss.clamp(0.0, 1.0)) {
+                return;
+            }
+            let mut delay = conditions.latency;
+            if !conditions.jitter.is_zero() {
+                delay += conditions.jitter.mul_f64(network.rng.gen());
+            }
+            if network.rng.gen_bool(conditions.reorder.clamp(0.0, 1.0)) {
+                delay += conditions.latency.max(Duration::from_millis(1));
+            }
+            if let Some(bytes_per_sec) = conditions.bandwidth {
+                let now = Instant::now();
+                let transmission =
+                    Duration::from_secs_f64(data.len() as f64 / bytes_per_sec as f64);
+                if let Some(host) = network.hosts.get_mut(&src) {
+                    let start = host.busy_until.map_or(now, |busy| busy.max(now));
+                    host.busy_until = Some(start + transmission);
+                    delay += start + transmission - now;
+                }
+            }
+            let datagram = Datagram {
+                src: routed_src,
+                data,
+            };
+            if delay.is_zero() {
+                sender.send(datagram).ok();
+            } else {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    sender.send(datagram).ok();
+                });
+            }
+        }
+
+        fn unbind(&self, addr: SocketAddr) {
+            let mut network = self.inner.lock().expect("poisoned");
+            if let Some(host) = network.hosts.get_mut(&addr) {
+                host.sender = None;
+            }
+        }
+    }
+
+    /// A simulated network of endpoints behind NATs.
+    ///
+    /// The endpoints exchange their packets over a [`MemoryNetwork`], which applies the
+    /// [`LinkConditions`] to all links.  Endpoints are either on the public network or
+    /// behind a NAT added with [`SimNetwork::add_nat`], which translates their addresses
+    /// and filters incoming datagrams like real NATs do.  This exercises hole punching and
+    /// path failover reproducibly:
+    ///
+    /// ```no_run
+    /// # async fn wrapper() -> anyhow::Result<()> {
+    /// use iroh::test_utils::{NatType, SimNetwork};
+    ///
+    /// let sim = SimNetwork::with_seed(42);
+    /// let nat = sim.add_nat(NatType::FullCone);
+    /// let server = sim
+    ///     .builder(Some(&nat))
+    ///     .alpns(vec![b"test".to_vec()])
+    ///     .bind()
+    ///     .await?;
+    /// let client = sim.builder(None).bind().await?;
+    /// let conn = client.connect(server.node_addr().await?, b"test").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Relay servers are not reachable, so nodes which can not establish a direct path can
+    /// not connect at all.
+    #[derive(Debug, Clone, Default)]
+    pub struct SimNetwork {
+        network: MemoryNetwork,
+    }
+
+    /// A NAT of a [`SimNetwork`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SimNat {
+        index: usize,
+        nat_type: NatType,
+        ip: IpAddr,
+    }
+
+    impl SimNat {
+        /// Returns how the NAT behaves.
+        pub fn nat_type(&self) -> NatType {
+            self.nat_type
+        }
+
+        /// Returns the public IP address of the NAT.
+        pub fn public_ip(&self) -> IpAddr {
+            self.ip
+        }
+    }
+
+    impl SimNetwork {
+        /// Creates a simulated network with perfect links and the seed 0.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Creates a simulated network with perfect links, taking random decisions from
+        /// `seed`.
+        pub fn with_seed(seed: u64) -> Self {
+            Self {
+                network: MemoryNetwork::with_seed(seed),
+            }
+        }
+
+        /// Sets the conditions of all links, applying to datagrams sent from now on.
+        pub fn set_conditions(&self, conditions: LinkConditions) {
+            self.network.set_conditions(conditions);
+        }
+
+        /// Returns the underlying in-memory network.
+        pub fn memory_network(&self) -> &MemoryNetwork {
+            &self.network
+        }
+
+        /// Adds a NAT with a new public IP address.
+        pub fn add_nat(&self, nat_type: NatType) -> SimNat {
+            let mut network = self.network.inner.lock().expect("poisoned");
+            let ip = network.public_ip();
+            network.nats.push(Nat {
+                ip,
+                nat_type,
+                mappings: HashMap::new(),
+                next_port: 1024,
+                next_host: 1,
+            });
+            SimNat {
+                index: network.nats.len() - 1,
+                nat_type,
+                ip,
+            }
+        }
+
+        /// Returns a builder for an endpoint on the public network, or behind `nat`.
+        ///
+        /// The builder has the relay disabled.  Behind a [`NatType::FullCone`] NAT the
+        /// endpoint advertises its external address, as it would learn it from STUN.
+        pub fn builder(&self, nat: Option<&SimNat>) -> Builder {
+            let (network, external) = self.network.reserve(nat.map(|nat| nat.index));
+            let builder = Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .memory_network(&network);
+            match external {
+                Some(addr) => builder.add_external_address(addr),
+                None => builder,
+            }
+        }
+    }
+
+    /// A socket on a [`MemoryNetwork`].
+    #[derive(Debug)]
+    pub(crate) struct MemorySocket {
+        addr: SocketAddr,
+        network: MemoryNetwork,
+        receiver: Mutex<mpsc::UnboundedReceiver<Datagram>>,
+    }
+
+    impl MemorySocket {
+        pub(crate) fn local_addr(&self) -> SocketAddr {
+            self.addr
+        }
+
+        pub(crate) fn try_send(&self, transmit: &quinn_udp::Transmit<'_>) -> io::Result<()> {
+            let segment_size = transmit
+                .segment_size
+                .unwrap_or(transmit.contents.len())
+                .max(1);
+            for datagram in transmit.contents.chunks(segment_size) {
+                self.network.send(
+                    self.addr,
+                    transmit.destination,
+                    Bytes::copy_from_slice(datagram),
+                );
+            }
+            Ok(())
+        }
+
+        pub(crate) fn poll_recv(
+            &self,
+            cx: &mut Context,
+            bufs: &mut [io::IoSliceMut<'_>],
+            metas: &mut [quinn_udp::RecvMeta],
+        ) -> Poll<io::Result<usize>> {
+            let mut receiver = self.receiver.lock().expect("poisoned");
+            let mut count = 0;
+            for (buf, meta) in bufs.iter_mut().zip(metas.iter_mut()) {
+                let datagram = match receiver.poll_recv(cx) {
+                    Poll::Ready(Some(datagram)) => datagram,
+                    Poll::Ready(None) => {
+                        return Poll::Ready(Err(io::ErrorKind::NotConnected.into()))
+                    }
+                    Poll::Pending => break,
+                };
+                let len = datagram.data.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram.data[..len]);
+                meta.addr = datagram.src;
+                meta.len = len;
+                meta.stride = len;
+                meta.ecn = None;
+                meta.dst_ip = Some(self.addr.ip());
+                count += 1;
+            }
+            match count {
+                0 => Poll::Pending,
+                n => Poll::Ready(Ok(n)),
+            }
+        }
+    }
+
+    impl Drop for MemorySocket {
+        fn drop(&mut self) {
+            self.network.unbind(self.addr);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn transmit(destination: SocketAddr, contents: &[u8]) -> quinn_udp::Transmit<'_> {
+            quinn_udp::Transmit {
+                destination,
+                ecn: None,
+                contents,
+                segment_size: None,
+                src_ip: None,
+            }
+        }
+
+        async fn recv(socket: &MemorySocket) -> (SocketAddr, Vec<u8>) {
+            let mut buf = [0u8; 64];
+            let mut metas = [quinn_udp::RecvMeta::default()];
+            std::future::poll_fn(|cx| {
+                let mut bufs = [io::IoSliceMut::new(&mut buf)];
+                socket.poll_recv(cx, &mut bufs, &mut metas)
+            })
+            .await
+            .unwrap();
+            (metas[0].addr, buf[..metas[0].len].to_vec())
+        }
+
+        /// Returns whether a datagram is delivered, without waiting.
+        fn delivered(
+            from: &MemorySocket,
+            to: &MemorySocket,
+            dst: SocketAddr,
+        ) -> Option<SocketAddr> {
+            from.try_send(&transmit(dst, b"ping")).unwrap();
+            let mut buf = [0u8; 64];
+            let mut metas = [quinn_udp::RecvMeta::default()];
+            let mut bufs = [io::IoSliceMut::new(&mut buf)];
+            let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+            match to.poll_recv(&mut cx, &mut bufs, &mut metas) {
+                Poll::Ready(Ok(1)) => Some(metas[0].addr),
+                _ => None,
+            }
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_memory_network() {
+            let network = MemoryNetwork::new();
+            let a = network.bind();
+            let b = network.bind();
+            assert_ne!(a.local_addr(), b.local_addr());
+
+            a.try_send(&transmit(b.local_addr(), b"hello")).unwrap();
+            assert_eq!(recv(&b).await, (a.local_addr(), b"hello".to_vec()));
+
+            network.set_conditions(LinkConditions::default().latency(Duration::from_millis(50)));
+            let start = Instant::now();
+            b.try_send(&transmit(a.local_addr(), b"world")).unwrap();
+            assert_eq!(recv(&a).await, (b.local_addr(), b"world".to_vec()));
+            assert_eq!(start.elapsed(), Duration::from_millis(50));
+
+            // Sending 1000 bytes at 10000 bytes per second takes 100ms.
+            network.set_conditions(LinkConditions::default().bandwidth(10_000));
+            let start = Instant::now();
+            a.try_send(&transmit(b.local_addr(), &[0u8; 500])).unwrap();
+            a.try_send(&transmit(b.local_addr(), &[0u8; 500])).unwrap();
+            recv(&b).await;
+            recv(&b).await;
+            assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+            // Everything is lost.
+            network.set_conditions(LinkConditions::default().loss(1.0));
+            a.try_send(&transmit(b.local_addr(), b"lost")).unwrap();
+            network.set_conditions(LinkConditions::default());
+            a.try_send(&transmit(b.local_addr(), b"found")).unwrap();
+            assert_eq!(recv(&b).await.1, b"found".to_vec());
+
+            // Sending to a closed socket drops the datagram.
+            let addr = b.local_addr();
+            drop(b);
+            a.try_send(&transmit(addr, b"gone")).unwrap();
+        }
+
+        #[test]
+        fn test_sim_network_nats() {
+            let sim = SimNetwork::new();
+            let full_cone = sim.add_nat(NatType::FullCone);
+            let symmetric_a = sim.add_nat(NatType::Symmetric);
+            let symmetric_b = sim.add_nat(NatType::Symmetric);
+            let bind = |nat: Option<&SimNat>| {
+                let (network, external) = sim.network.reserve(nat.map(|nat| nat.index));
+                (network.bind(), external)
+            };
+            let (public, _) = bind(None);
+            let (cone, cone_external) = bind(Some(&full_cone));
+            let cone_external = cone_external.unwrap();
+            let (sym_a, sym_external) = bind(Some(&symmetric_a));
+            let (sym_b, _) = bind(Some(&symmetric_b));
+            assert_eq!(sym_external, None);
+
+            // The private address is not reachable from outside the NAT.
+            assert_eq!(delivered(&public, &cone, cone.local_addr()), None);
+
+            // A full cone NAT lets in datagrams from anyone.
+            let from = delivered(&public, &cone, cone_external).unwrap();
+            assert_eq!(from, public.local_addr());
+            let from = delivered(&sym_a, &cone, cone_external).unwrap();
+            assert_eq!(from.ip(), symmetric_a.public_ip());
+            // The mapping of the symmetric NAT lets in the reply.
+            assert_eq!(delivered(&cone, &sym_a, from), Some(cone_external));
+            // But not datagrams from others.
+            assert_eq!(delivered(&public, &sym_a, from), None);
+
+            // Symmetric NATs map a new port for each remote, so hole punching fails.
+            let to_b = SocketAddr::new(symmetric_b.public_ip(), 1024);
+            assert_eq!(delivered(&sym_a, &sym_b, to_b), None);
+            let to_a = SocketAddr::new(symmetric_a.public_ip(), 1024);
+            assert_eq!(delivered(&sym_b, &sym_a, to_a), None);
+        }
+    }
+}
+
 pub(crate) mod dns_and_pkarr_servers {
     use std::{net::SocketAddr, time::Duration};
 
@@ -116,9 +832,11 @@ pub(crate) mod dns_and_pkarr_servers {
     use crate::{
         discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery},
         dns::DnsResolver,
+        endpoint::Builder,
         test_utils::{
             dns_server::run_dns_server, pkarr_dns_state::State, pkarr_relay::run_pkarr_relay,
         },
+        Endpoint,
     };
 
     /// Handle and drop guard for test DNS and Pkarr servers.
@@ -175,6 +893,18 @@ pub(crate) mod dns_and_pkarr_servers {
             create_dns_resolver(self.nameserver).expect("failed to create DNS resolver")
         }
 
+        /// Returns a builder for an endpoint using the test servers for discovery.
+        ///
+        /// The endpoint gets a new secret key, publishes its addresses to the pkarr relay and
+        /// resolves other nodes with the DNS server.
+        pub fn endpoint_builder(&self) -> Builder {
+            let secret_key = SecretKey::generate();
+            Endpoint::builder()
+                .secret_key(secret_key.clone())
+                .dns_resolver(self.dns_resolver())
+                .discovery(self.discovery(secret_key))
+        }
+
         /// Wait until a Pkarr announce for a node is published to the server.
         ///
         /// If `timeout` elapses an error is returned.
@@ -196,11 +926,11 @@ pub(crate) mod dns_server {
         op::{header::MessageType, Message},
         serialize::binary::BinDecodable,
     };
-    use hickory_resolver::{config::NameServerConfig, TokioAsyncResolver};
     use tokio::{net::UdpSocket, sync::oneshot};
     use tracing::{debug, error, warn};
 
     use super::CleanupDropGuard;
+    use crate::dns::DnsResolver;
 
     /// Trait used by [`run_dns_server`] for answering DNS queries.
     pub trait QueryHandler: Send + Sync + 'static {
@@ -251,13 +981,8 @@ pub(crate) mod dns_server {
     }
 
     /// Create a DNS resolver with a single nameserver.
-    pub fn create_dns_resolver(nameserver: SocketAddr) -> Result<TokioAsyncResolver> {
-        let mut config = hickory_resolver::config::ResolverConfig::new();
-        let nameserver_config =
-            NameServerConfig::new(nameserver, hickory_resolver::config::Protocol::Udp);
-        config.add_name_server(nameserver_config);
-        let resolver = hickory_resolver::AsyncResolver::tokio(config, Default::default());
-        Ok(resolver)
+    pub fn create_dns_resolver(nameserver: SocketAddr) -> Result<DnsResolver> {
+        Ok(DnsResolver::with_nameserver(nameserver))
     }
 
     struct TestDnsServer<R> {
@@ -487,3 +1212,42 @@ pub(crate) mod pkarr_dns_state {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::RelayMode;
+
+    const ALPN: &[u8] = b"n0/test/1";
+
+    #[tokio::test]
+    async fn test_relay_and_discovery_fixtures() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+        let (relay_map, _relay_url, _relay_server) = run_relay_server().await?;
+        let discovery = run_dns_discovery_server().await?;
+        let bind = || {
+            discovery
+                .endpoint_builder()
+                .relay_mode(RelayMode::Custom(relay_map.clone()))
+                .insecure_skip_relay_cert_verify(true)
+                .alpns(vec![ALPN.to_vec()])
+                .bind()
+        };
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        discovery
+            .on_node(&ep1.node_id(), Duration::from_secs(10))
+            .await?;
+
+        // Only the node id is known, the addresses come from discovery.
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move { ep1.accept().await.unwrap().await }
+        });
+        ep2.connect(ep1.node_id(), ALPN).await?;
+        accept.await??;
+        Ok(())
+    }
+}
@@ -9,7 +9,10 @@ use quinn::crypto::rustls::{NoInitialCipherSuite, QuicClientConfig, QuicServerCo
 use tracing::warn;
 
 use self::certificate::AlwaysResolvesCert;
-use crate::key::{PublicKey, SecretKey};
+use crate::{
+    endpoint::{NodeMetadata, SharedAccessPolicy},
+    key::{PublicKey, SecretKey},
+};
 
 pub mod certificate;
 mod verifier;
@@ -36,7 +39,20 @@ pub fn make_client_config(
     alpn_protocols: Vec<Vec<u8>>,
     keylog: bool,
 ) -> Result<QuicClientConfig, CreateConfigError> {
-    let (certificate, secret_key) = certificate::generate(secret_key)?;
+    make_client_config_with_metadata(secret_key, remote_peer_id, alpn_protocols, keylog, None)
+}
+
+/// Create a TLS client configuration which sends the [`NodeMetadata`] to the server.
+///
+/// See [`make_client_config`].
+pub(crate) fn make_client_config_with_metadata(
+    secret_key: &SecretKey,
+    remote_peer_id: Option<PublicKey>,
+    alpn_protocols: Vec<Vec<u8>>,
+    keylog: bool,
+    metadata: Option<&NodeMetadata>,
+) -> Result<QuicClientConfig, CreateConfigError> {
+    let (certificate, secret_key) = certificate::generate_with_metadata(secret_key, &[], metadata)?;
 
     let cert_resolver = Arc::new(
         AlwaysResolvesCert::new(certificate, &secret_key)
@@ -72,7 +88,23 @@ pub fn make_server_config(
     alpn_protocols: Vec<Vec<u8>>,
     keylog: bool,
 ) -> Result<QuicServerConfig, CreateConfigError> {
-    let (certificate, secret_key) = certificate::generate(secret_key)?;
+    make_server_config_with_access_policy(secret_key, &[], alpn_protocols, keylog, None, None)
+}
+
+/// Create a TLS server configuration which rejects clients denied by the access policy.
+///
+/// The certificate also proves possession of the `previous_keys`, so clients dialing one of
+/// them accept it.  It carries the [`NodeMetadata`] if given.  See [`make_server_config`].
+pub(crate) fn make_server_config_with_access_policy(
+    secret_key: &SecretKey,
+    previous_keys: &[SecretKey],
+    alpn_protocols: Vec<Vec<u8>>,
+    keylog: bool,
+    access_policy: Option<SharedAccessPolicy>,
+    metadata: Option<&NodeMetadata>,
+) -> Result<QuicServerConfig, CreateConfigError> {
+    let (certificate, secret_key) =
+        certificate::generate_with_metadata(secret_key, previous_keys, metadata)?;
 
     let cert_resolver = Arc::new(
         AlwaysResolvesCert::new(certificate, &secret_key)
@@ -84,7 +116,9 @@ pub fn make_server_config(
     ))
     .with_protocol_versions(verifier::PROTOCOL_VERSIONS)
     .expect("fixed config")
-    .with_client_cert_verifier(Arc::new(verifier::Libp2pCertificateVerifier::new()))
+    .with_client_cert_verifier(Arc::new(
+        verifier::Libp2pCertificateVerifier::with_access_policy(access_policy),
+    ))
     .with_cert_resolver(cert_resolver);
     crypto.alpn_protocols = alpn_protocols;
     if keylog {
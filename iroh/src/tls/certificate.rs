@@ -7,10 +7,16 @@
 
 use std::sync::Arc;
 
-use der::{asn1::OctetStringRef, Decode, Encode, Sequence};
+use der::{
+    asn1::{OctetStringRef, Utf8StringRef},
+    Decode, Encode, Sequence,
+};
 use x509_parser::prelude::*;
 
-use crate::key::{PublicKey, SecretKey, Signature};
+use crate::{
+    endpoint::NodeMetadata,
+    key::{PublicKey, SecretKey, Signature},
+};
 
 /// The libp2p Public Key Extension is a X.509 extension
 /// with the Object Identifier 1.3.6.1.4.1.53594.1.1,
@@ -24,6 +30,23 @@ const P2P_EXT_OID: [u64; 9] = [1, 3, 6, 1, 4, 1, 53594, 1, 1];
 /// in possession of the private host key at the time the certificate was signed.
 const P2P_SIGNING_PREFIX: [u8; 21] = *b"libp2p-tls-handshake:";
 
+/// The Object Identifier of the extension carrying the [`NodeMetadata`].
+///
+/// iroh does not own a Private Enterprise Number, so its extensions live below the Internet
+/// experimental arc `1.3.6.1.3` of RFC 1155, in a sub-arc spelling "iroh" in ASCII.  A UUID
+/// below `2.25` can not be used, as `rcgen` only supports arcs of up to 64 bits.
+///
+/// This extension is specific to iroh and is never marked critical, so other
+/// implementations ignore it.
+const NODE_METADATA_EXT_OID: [u64; 7] = [1, 3, 6, 1, 3, 0x6972_6f68, 1];
+
+/// The Object Identifier of the extension carrying the previous identities of a node.
+///
+/// The extension holds a sequence of [`SignedKey`]s, signed like the libp2p Public Key
+/// Extension.  It is specific to iroh and never marked critical, see
+/// [`NODE_METADATA_EXT_OID`] for the arc.
+const PREVIOUS_KEYS_EXT_OID: [u64; 7] = [1, 3, 6, 1, 3, 0x6972_6f68, 2];
+
 // Certificates MUST use the NamedCurve encoding for elliptic curve parameters.
 // Similarly, hash functions with an output length less than 256 bits MUST NOT be used.
 static P2P_SIGNATURE_ALGORITHM: &rcgen::SignatureAlgorithm = &rcgen::PKCS_ECDSA_P256_SHA256;
@@ -75,6 +98,15 @@ struct SignedKey<'a> {
     signature: OctetStringRef<'a>,
 }
 
+/// The [`NodeMetadata`] as encoded in its certificate extension.
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct MetadataExtension<'a> {
+    iroh_version: Utf8StringRef<'a>,
+    /// Empty if no user agent is set.
+    user_agent: Utf8StringRef<'a>,
+    app_data: OctetStringRef<'a>,
+}
+
 /// Generates a self-signed TLS certificate that includes a libp2p-specific
 /// certificate extension containing the public key of the given secret key.
 pub fn generate(
@@ -85,6 +117,26 @@ pub fn generate(
         rustls::pki_types::PrivateKeyDer<'static>,
     ),
     GenError,
+> {
+    generate_with_metadata(identity_secret_key, &[], None)
+}
+
+/// Generates a self-signed TLS certificate like [`generate`], which also carries the
+/// [`NodeMetadata`] if given.
+///
+/// The certificate also proves possession of the `previous_keys`, so that clients dialing
+/// one of them accept it.  As the certificate is only sent encrypted, this does not reveal
+/// the identities of the node to observers.
+pub(crate) fn generate_with_metadata(
+    identity_secret_key: &SecretKey,
+    previous_keys: &[SecretKey],
+    metadata: Option<&NodeMetadata>,
+) -> Result<
+    (
+        rustls::pki_types::CertificateDer<'static>,
+        rustls::pki_types::PrivateKeyDer<'static>,
+    ),
+    GenError,
 > {
     // SecretKey used to sign the certificate.
     // SHOULD NOT be related to the host's key.
@@ -101,6 +153,17 @@ pub fn generate(
             identity_secret_key,
             &certificate_keypair,
         )?);
+        if !previous_keys.is_empty() {
+            params.custom_extensions.push(make_previous_keys_extension(
+                previous_keys,
+                &certificate_keypair,
+            )?);
+        }
+        if let Some(metadata) = metadata {
+            params
+                .custom_extensions
+                .push(make_metadata_extension(metadata)?);
+        }
         params
             .self_signed(&certificate_keypair)
             .expect("self signed certificate to be generated")
@@ -132,6 +195,10 @@ pub struct P2pCertificate<'a> {
     /// * the public host key
     /// * a signature performed using the private host key
     extension: P2pExtension,
+    /// The metadata of the node, if it sent any.
+    metadata: Option<NodeMetadata>,
+    /// The previous identities of the node, signed like the libp2p extension.
+    previous_keys: Vec<P2pExtension>,
 }
 
 /// The contents of the specific libp2p extension, containing the public host key
@@ -170,10 +237,38 @@ fn parse_unverified(der_input: &[u8]) -> Result<P2pCertificate, webpki::Error> {
     let p2p_ext_oid = der_parser::oid::Oid::from(&P2P_EXT_OID)
         .expect("This is a valid OID of p2p extension; qed");
 
+    let metadata_ext_oid = der_parser::oid::Oid::from(&NODE_METADATA_EXT_OID)
+        .expect("This is a valid OID of the metadata extension; qed");
+
+    let previous_keys_ext_oid = der_parser::oid::Oid::from(&PREVIOUS_KEYS_EXT_OID)
+        .expect("This is a valid OID of the previous keys extension; qed");
+
     let mut libp2p_extension = None;
+    let mut metadata = None;
+    let mut previous_keys = None;
 
     for ext in x509.extensions() {
         let oid = &ext.oid;
+        if oid == &metadata_ext_oid {
+            // The metadata is informational only, a malformed one is ignored.
+            metadata = parse_metadata_extension(ext.value);
+            continue;
+        }
+
+        if oid == &previous_keys_ext_oid {
+            if previous_keys.is_some() {
+                return Err(webpki::Error::BadDer);
+            }
+            let signed_keys = Vec::<SignedKey>::from_der(ext.value)
+                .map_err(|_| webpki::Error::ExtensionValueInvalid)?;
+            let keys = signed_keys
+                .iter()
+                .map(P2pExtension::from_signed_key)
+                .collect::<Result<_, _>>()?;
+            previous_keys = Some(keys);
+            continue;
+        }
+
         if oid == &p2p_ext_oid && libp2p_extension.is_some() {
             // The extension was already parsed
             return Err(webpki::Error::BadDer);
@@ -182,17 +277,7 @@ fn parse_unverified(der_input: &[u8]) -> Result<P2pCertificate, webpki::Error> {
         if oid == &p2p_ext_oid {
             let signed_key =
                 SignedKey::from_der(ext.value).map_err(|_| webpki::Error::ExtensionValueInvalid)?;
-            let public_key_raw = signed_key.public_key.as_bytes();
-            let public_key =
-                PublicKey::try_from(public_key_raw).map_err(|_| webpki::Error::UnknownIssuer)?;
-
-            let signature = Signature::from_slice(signed_key.signature.as_bytes())
-                .map_err(|_| webpki::Error::UnknownIssuer)?;
-            let ext = P2pExtension {
-                public_key,
-                signature,
-            };
-            libp2p_extension = Some(ext);
+            libp2p_extension = Some(P2pExtension::from_signed_key(&signed_key)?);
             continue;
         }
 
@@ -212,15 +297,71 @@ fn parse_unverified(der_input: &[u8]) -> Result<P2pCertificate, webpki::Error> {
     let certificate = P2pCertificate {
         certificate: x509,
         extension,
+        metadata,
+        previous_keys: previous_keys.unwrap_or_default(),
     };
 
     Ok(certificate)
 }
 
+impl P2pExtension {
+    fn from_signed_key(signed_key: &SignedKey<'_>) -> Result<Self, webpki::Error> {
+        let public_key_raw = signed_key.public_key.as_bytes();
+        let public_key =
+            PublicKey::try_from(public_key_raw).map_err(|_| webpki::Error::UnknownIssuer)?;
+
+        let signature = Signature::from_slice(signed_key.signature.as_bytes())
+            .map_err(|_| webpki::Error::UnknownIssuer)?;
+        Ok(Self {
+            public_key,
+            signature,
+        })
+    }
+}
+
 fn make_libp2p_extension(
     identity_secret_key: &SecretKey,
     certificate_keypair: &rcgen::KeyPair,
 ) -> Result<rcgen::CustomExtension, rcgen::Error> {
+    let (public_key, signature) = sign_certificate_key(identity_secret_key, certificate_keypair);
+    let key = signed_key(&public_key, &signature)?;
+
+    let mut extension_content = Vec::new();
+    key.encode_to_vec(&mut extension_content).expect("vec");
+
+    // This extension MAY be marked critical.
+    let mut ext = rcgen::CustomExtension::from_oid_content(&P2P_EXT_OID, extension_content);
+    ext.set_criticality(true);
+
+    Ok(ext)
+}
+
+fn make_previous_keys_extension(
+    previous_keys: &[SecretKey],
+    certificate_keypair: &rcgen::KeyPair,
+) -> Result<rcgen::CustomExtension, rcgen::Error> {
+    let signatures: Vec<_> = previous_keys
+        .iter()
+        .map(|secret_key| sign_certificate_key(secret_key, certificate_keypair))
+        .collect();
+    let keys = signatures
+        .iter()
+        .map(|(public_key, signature)| signed_key(public_key, signature))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut extension_content = Vec::new();
+    keys.encode_to_vec(&mut extension_content).expect("vec");
+    Ok(rcgen::CustomExtension::from_oid_content(
+        &PREVIOUS_KEYS_EXT_OID,
+        extension_content,
+    ))
+}
+
+/// Signs the public key of the certificate with the `identity_secret_key`.
+fn sign_certificate_key(
+    identity_secret_key: &SecretKey,
+    certificate_keypair: &rcgen::KeyPair,
+) -> (PublicKey, [u8; 64]) {
     // The peer signs the concatenation of the string `libp2p-tls-handshake:`
     // and the public key that it used to generate the certificate carrying
     // the libp2p Public Key Extension, using its private host key.
@@ -231,26 +372,49 @@ fn make_libp2p_extension(
 
         identity_secret_key.sign(&msg)
     };
+    (identity_secret_key.public(), signature.to_bytes())
+}
 
-    let public_key = identity_secret_key.public();
+fn signed_key<'a>(
+    public_key: &'a PublicKey,
+    signature: &'a [u8; 64],
+) -> Result<SignedKey<'a>, rcgen::Error> {
     let public_key_ref = OctetStringRef::new(&public_key.as_bytes()[..])
         .map_err(|_| rcgen::Error::CouldNotParseKeyPair)?;
-    let signature = signature.to_bytes();
     let signature_ref =
-        OctetStringRef::new(&signature).map_err(|_| rcgen::Error::CouldNotParseCertificate)?;
-    let key = SignedKey {
+        OctetStringRef::new(signature).map_err(|_| rcgen::Error::CouldNotParseCertificate)?;
+    Ok(SignedKey {
         public_key: public_key_ref,
         signature: signature_ref,
-    };
+    })
+}
 
+fn make_metadata_extension(
+    metadata: &NodeMetadata,
+) -> Result<rcgen::CustomExtension, rcgen::Error> {
+    let invalid = |_| rcgen::Error::CouldNotParseCertificate;
+    let ext = MetadataExtension {
+        iroh_version: Utf8StringRef::new(&metadata.iroh_version).map_err(invalid)?,
+        user_agent: Utf8StringRef::new(metadata.user_agent.as_deref().unwrap_or_default())
+            .map_err(invalid)?,
+        app_data: OctetStringRef::new(&metadata.app_data).map_err(invalid)?,
+    };
     let mut extension_content = Vec::new();
-    key.encode_to_vec(&mut extension_content).expect("vec");
-
-    // This extension MAY be marked critical.
-    let mut ext = rcgen::CustomExtension::from_oid_content(&P2P_EXT_OID, extension_content);
-    ext.set_criticality(true);
+    ext.encode_to_vec(&mut extension_content).expect("vec");
+    Ok(rcgen::CustomExtension::from_oid_content(
+        &NODE_METADATA_EXT_OID,
+        extension_content,
+    ))
+}
 
-    Ok(ext)
+fn parse_metadata_extension(value: &[u8]) -> Option<NodeMetadata> {
+    let ext = MetadataExtension::from_der(value).ok()?;
+    let user_agent = ext.user_agent.as_str();
+    Some(NodeMetadata {
+        iroh_version: ext.iroh_version.as_str().to_string(),
+        user_agent: (!user_agent.is_empty()).then(|| user_agent.to_string()),
+        app_data: ext.app_data.as_bytes().to_vec().into(),
+    })
 }
 
 impl P2pCertificate<'_> {
@@ -259,6 +423,21 @@ impl P2pCertificate<'_> {
         self.extension.public_key
     }
 
+    /// The previous identities of the remote peer.
+    ///
+    /// The peer proved possession of the secret keys of these, see
+    /// [`Builder::previous_secret_keys`].
+    ///
+    /// [`Builder::previous_secret_keys`]: crate::endpoint::Builder::previous_secret_keys
+    pub fn previous_peer_ids(&self) -> impl Iterator<Item = PublicKey> + '_ {
+        self.previous_keys.iter().map(|ext| ext.public_key)
+    }
+
+    /// The [`NodeMetadata`] of the remote peer, if it sent any.
+    pub fn metadata(&self) -> Option<&NodeMetadata> {
+        self.metadata.as_ref()
+    }
+
     /// Verify the `signature` of the `message` signed by the secret key corresponding to the public key stored
     /// in the certificate.
     pub fn verify_signature(
@@ -376,6 +555,13 @@ impl P2pCertificate<'_> {
             return Err(Error::UnknownIssuer);
         }
 
+        // The previous identities are signed the same way.
+        for ext in &self.previous_keys {
+            if ext.public_key.verify(&msg, &ext.signature).is_err() {
+                return Err(Error::UnknownIssuer);
+            }
+        }
+
         Ok(())
     }
 
@@ -440,5 +626,39 @@ mod tests {
 
         assert!(parsed_cert.verify().is_ok());
         assert_eq!(secret_key.public(), parsed_cert.extension.public_key);
+        assert!(parsed_cert.metadata().is_none());
+    }
+
+    #[test]
+    fn metadata_roundtrip() {
+        let secret_key = SecretKey::generate();
+        let metadata = NodeMetadata {
+            iroh_version: "0.29.0".to_string(),
+            user_agent: Some("my-app/1.0".to_string()),
+            app_data: vec![1, 2, 3].into(),
+        };
+
+        let (cert, _) = generate_with_metadata(&secret_key, &[], Some(&metadata)).unwrap();
+        let parsed_cert = parse(&cert).unwrap();
+        assert_eq!(secret_key.public(), parsed_cert.peer_id());
+        assert_eq!(parsed_cert.metadata(), Some(&metadata));
+    }
+
+    #[test]
+    fn previous_keys_roundtrip() {
+        let secret_key = SecretKey::generate();
+        let previous = [SecretKey::generate(), SecretKey::generate()];
+
+        let (cert, _) = generate_with_metadata(&secret_key, &previous, None).unwrap();
+        let parsed_cert = parse(&cert).unwrap();
+        assert_eq!(secret_key.public(), parsed_cert.peer_id());
+        let previous_ids: Vec<_> = parsed_cert.previous_peer_ids().collect();
+        assert_eq!(
+            previous_ids,
+            vec![previous[0].public(), previous[1].public()]
+        );
+
+        let (cert, _) = generate(&secret_key).unwrap();
+        assert_eq!(parse(&cert).unwrap().previous_peer_ids().count(), 0);
     }
 }
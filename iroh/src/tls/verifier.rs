@@ -16,7 +16,7 @@ use rustls::{
 };
 
 use super::certificate;
-use crate::key::PublicKey;
+use crate::{endpoint::SharedAccessPolicy, key::PublicKey};
 
 /// The protocol versions supported by this verifier.
 ///
@@ -33,6 +33,8 @@ pub static PROTOCOL_VERSIONS: &[&SupportedProtocolVersion] = &[&rustls::version:
 pub struct Libp2pCertificateVerifier {
     /// The peer ID we intend to connect to
     remote_peer_id: Option<PublicKey>,
+    /// The policy deciding which clients may connect to us
+    access_policy: Option<SharedAccessPolicy>,
 }
 
 /// libp2p requires the following of X.509 server certificate chains:
@@ -42,13 +44,17 @@ pub struct Libp2pCertificateVerifier {
 /// - The certificate must have a valid libp2p extension that includes a
 ///   signature of its public key.
 impl Libp2pCertificateVerifier {
-    pub fn new() -> Self {
+    pub fn with_remote_peer_id(remote_peer_id: Option<PublicKey>) -> Self {
         Self {
-            remote_peer_id: None,
+            remote_peer_id,
+            access_policy: None,
         }
     }
-    pub fn with_remote_peer_id(remote_peer_id: Option<PublicKey>) -> Self {
-        Self { remote_peer_id }
+    pub(crate) fn with_access_policy(access_policy: Option<SharedAccessPolicy>) -> Self {
+        Self {
+            remote_peer_id: None,
+            access_policy,
+        }
     }
 
     /// Return the list of SignatureSchemes that this verifier will handle,
@@ -76,14 +82,17 @@ impl ServerCertVerifier for Libp2pCertificateVerifier {
         _ocsp_response: &[u8],
         _now: rustls::pki_types::UnixTime,
     ) -> Result<ServerCertVerified, rustls::Error> {
-        let peer_id = verify_presented_certs(end_entity, intermediates)?;
+        let cert = verify_presented_certs(end_entity, intermediates)?;
 
         if let Some(ref remote_peer_id) = self.remote_peer_id {
             // The public host key allows the peer to calculate the peer ID of the peer
             // it is connecting to. Clients MUST verify that the peer ID derived from
             // the certificate matches the peer ID they intended to connect to,
             // and MUST abort the connection if there is a mismatch.
-            if remote_peer_id != &peer_id {
+            // A node which rotated its key also proves possession of its previous keys.
+            let matches = cert.peer_id() == *remote_peer_id
+                || cert.previous_peer_ids().any(|id| id == *remote_peer_id);
+            if !matches {
                 return Err(rustls::Error::PeerMisbehaved(
                     PeerMisbehaved::BadCertChainExtensions,
                 ));
@@ -134,7 +143,15 @@ impl ClientCertVerifier for Libp2pCertificateVerifier {
         intermediates: &[Certificate],
         _now: rustls::pki_types::UnixTime,
     ) -> Result<ClientCertVerified, rustls::Error> {
-        verify_presented_certs(end_entity, intermediates)?;
+        let peer_id = verify_presented_certs(end_entity, intermediates)?.peer_id();
+
+        if let Some(ref access_policy) = self.access_policy {
+            if !access_policy.read().is_allowed(&peer_id) {
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+        }
 
         Ok(ClientCertVerified::assertion())
     }
@@ -172,10 +189,10 @@ impl ClientCertVerifier for Libp2pCertificateVerifier {
 /// (b) if it is expired.
 /// Endpoints MUST abort the connection attempt if more than one certificate is received,
 /// or if the certificate’s self-signature is not valid.
-fn verify_presented_certs(
-    end_entity: &Certificate,
+fn verify_presented_certs<'a>(
+    end_entity: &'a Certificate,
     intermediates: &[Certificate],
-) -> Result<PublicKey, rustls::Error> {
+) -> Result<certificate::P2pCertificate<'a>, rustls::Error> {
     if !intermediates.is_empty() {
         return Err(rustls::Error::General(
             "libp2p-tls requires exactly one certificate".into(),
@@ -184,7 +201,7 @@ fn verify_presented_certs(
 
     let cert = certificate::parse(end_entity)?;
 
-    Ok(cert.peer_id())
+    Ok(cert)
 }
 
 fn verify_tls13_signature(
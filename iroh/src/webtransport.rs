@@ -0,0 +1,445 @@
+//! A gateway for browsers to reach iroh nodes over WebTransport.
+//!
+//! Browsers can not speak the QUIC dialect of iroh, but they can open [WebTransport]
+//! sessions.  The [`Gateway`] accepts WebTransport sessions at
+//! `https://<gateway address>/<node id>`, connects to the node with this [`NodeId`] using
+//! its [`Endpoint`] and the configured ALPN, and bridges the two: streams opened on either
+//! side are opened on the other side as well, and datagrams are forwarded in both
+//! directions.  The iroh node sees a regular connection from the gateway's [`NodeId`].
+//!
+//! Browsers must trust the certificate of the gateway's [`Identity`].  For development a
+//! self-signed certificate valid for at most two weeks can be passed to the browser using
+//! the `serverCertificateHashes` option of the `WebTransport` constructor.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use anyhow::Result;
+//! # use iroh::{webtransport::{Gateway, Identity}, Endpoint};
+//! #
+//! # async fn test_compile(node_id: iroh::NodeId) -> Result<()> {
+//! let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+//! let identity = Identity::load_pemfiles("cert.pem", "key.pem").await?;
+//!
+//! let gateway = Gateway::builder(endpoint, b"/my/alpn", identity)
+//!     .bind_addr("0.0.0.0:4433".parse()?)
+//!     .allow_node(node_id)
+//!     .spawn()
+//!     .await?;
+//!
+//! // Browsers can now connect to `https://<host>:4433/<node_id>`.
+//! tokio::signal::ctrl_c().await?;
+//! gateway.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [WebTransport]: https://www.w3.org/TR/webtransport/
+
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+    task::JoinSet,
+};
+use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
+use tracing::{debug, info_span, warn, Instrument};
+pub use wtransport::Identity;
+use wtransport::{endpoint::IncomingSession, ServerConfig};
+
+use crate::{endpoint::Connection, Endpoint, NodeId};
+
+/// The default address the [`Gateway`] listens on.
+const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 443);
+
+/// A running WebTransport gateway.
+///
+/// Construct this using [`Gateway::builder`].
+///
+/// When dropped, this will abort the gateway and all its sessions, so make sure to store it.
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    local_addr: SocketAddr,
+    // `Gateway` needs to be `Clone + Send`, and we need to `task.await` in its `shutdown()` impl.
+    task: Arc<Mutex<Option<AbortOnDropHandle<()>>>>,
+    cancel_token: CancellationToken,
+}
+
+/// Builder for a [`Gateway`].
+#[derive(derive_more::Debug)]
+pub struct GatewayBuilder {
+    endpoint: Endpoint,
+    alpn: Vec<u8>,
+    #[debug(skip)]
+    identity: Identity,
+    bind_addr: SocketAddr,
+    allowed_nodes: AllowedNodes,
+}
+
+/// The nodes browsers may reach through the [`Gateway`].
+#[derive(Debug, Clone)]
+enum AllowedNodes {
+    Any,
+    Only(BTreeSet<NodeId>),
+}
+
+impl AllowedNodes {
+    fn contains(&self, node_id: &NodeId) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Only(nodes) => nodes.contains(node_id),
+        }
+    }
+}
+
+impl Gateway {
+    /// Creates a builder for a gateway connecting to nodes with `endpoint` using `alpn`.
+    ///
+    /// The gateway presents the certificate of `identity` to browsers.
+    pub fn builder(
+        endpoint: Endpoint,
+        alpn: impl AsRef<[u8]>,
+        identity: Identity,
+    ) -> GatewayBuilder {
+        GatewayBuilder {
+            endpoint,
+            alpn: alpn.as_ref().to_vec(),
+            identity,
+            bind_addr: DEFAULT_BIND_ADDR,
+            allowed_nodes: AllowedNodes::Only(BTreeSet::new()),
+        }
+    }
+
+    /// Returns the address the gateway listens on for WebTransport sessions.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Checks if the gateway is already shutdown.
+    pub fn is_shutdown(&self) -> bool {
+        self.cancel_token.is_cancelled()
+    }
+
+    /// Stops accepting sessions and closes all bridged sessions.
+    ///
+    /// The [`Endpoint`] is not closed.  If already shutdown, it returns `Ok`.
+    pub async fn shutdown(&self) -> Result<()> {
+        if self.is_shutdown() {
+            return Ok(());
+        }
+        self.cancel_token.cancel();
+        if let Some(task) = self.task.lock().await.take() {
+            task.await?;
+        }
+        Ok(())
+    }
+}
+
+impl GatewayBuilder {
+    /// Sets the UDP address to listen on for WebTransport sessions.
+    ///
+    /// Defaults to port 443 on all interfaces.
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    /// Allows browsers to reach the node with this [`NodeId`].
+    ///
+    /// By default no node can be reached, sessions for nodes which are not allowed are
+    /// refused with `403 Forbidden`.
+    pub fn allow_node(mut self, node_id: NodeId) -> Self {
+        if let AllowedNodes::Only(ref mut nodes) = self.allowed_nodes {
+            nodes.insert(node_id);
+        }
+        self
+    }
+
+    /// Allows browsers to reach any node.
+    ///
+    /// This turns the gateway into an open proxy for the ALPN, only use this if the nodes
+    /// authorize their clients themselves.
+    pub fn allow_any_node(mut self) -> Self {
+        self.allowed_nodes = AllowedNodes::Any;
+        self
+    }
+
+    /// Binds the WebTransport listener and spawns the gateway.
+    pub async fn spawn(self) -> Result<Gateway> {
+        let config = ServerConfig::builder()
+            .with_bind_address(self.bind_addr)
+            .with_identity(self.identity)
+            .build();
+        let server =
+            wtransport::Endpoint::server(config).context("failed to bind WebTransport listener")?;
+        let local_addr = server.local_addr()?;
+        debug!(%local_addr, "WebTransport gateway listening");
+
+        // We use a child token of the endpoint, to ensure that this is shutdown when the
+        // endpoint is shutdown, but that we can shutdown ourselves independently.
+        let cancel = self.endpoint.cancel_token().child_token();
+        let cancel_token = cancel.clone();
+        let endpoint = self.endpoint;
+        let alpn: Arc<[u8]> = self.alpn.into();
+        let allowed_nodes = Arc::new(self.allowed_nodes);
+
+        let run_loop_fut = async move {
+            // Make sure to cancel the token, if this future ever exits.
+            let _cancel_guard = cancel_token.clone().drop_guard();
+            let mut sessions = JoinSet::new();
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = cancel_token.cancelled() => break,
+                    Some(res) = sessions.join_next(), if !sessions.is_empty() => {
+                        if let Err(err) = res {
+                            if err.is_panic() {
+                                warn!("WebTransport session panicked: {err:?}");
+                            }
+                        }
+                    }
+                    incoming = server.accept() => {
+                        let endpoint = endpoint.clone();
+                        let alpn = alpn.clone();
+                        let allowed_nodes = allowed_nodes.clone();
+                        sessions.spawn(async move {
+                            if let Err(err) =
+                                handle_session(incoming, endpoint, &alpn, &allowed_nodes).await
+                            {
+                                debug!("WebTransport session ended with error: {err:#}");
+                            }
+                        }.instrument(info_span!("webtransport.session")));
+                    }
+                }
+            }
+            // Abort remaining sessions, which closes the connections on both sides.
+            sessions.shutdown().await;
+            server.close(0u32.into(), b"");
+        };
+        let task = AbortOnDropHandle::new(tokio::task::spawn(run_loop_fut));
+
+        Ok(Gateway {
+            local_addr,
+            task: Arc::new(Mutex::new(Some(task))),
+            cancel_token: cancel,
+        })
+    }
+}
+
+/// Accepts a WebTransport session and bridges it to a connection to the requested node.
+async fn handle_session(
+    incoming: IncomingSession,
+    endpoint: Endpoint,
+    alpn: &[u8],
+    allowed_nodes: &AllowedNodes,
+) -> Result<()> {
+    let request = incoming.await?;
+    let Some(node_id) = parse_path(request.path()) else {
+        debug!(
+            path = request.path(),
+            "refusing session: no node ID in path"
+        );
+        request.not_found().await;
+        return Ok(());
+    };
+    if !allowed_nodes.contains(&node_id) {
+        debug!(node = %node_id.fmt_short(), "refusing session: node not allowed");
+        request.forbidden().await;
+        return Ok(());
+    }
+    let conn = match endpoint.connect(node_id, alpn).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            request.not_found().await;
+            return Err(err).context("failed to connect to node");
+        }
+    };
+    let session = request.accept().await?;
+    debug!(node = %node_id.fmt_short(), "bridging WebTransport session");
+    bridge(session, conn).await;
+    Ok(())
+}
+
+/// Extracts the [`NodeId`] from the path of a session request, e.g. `/<node id>`.
+fn parse_path(path: &str) -> Option<NodeId> {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    path.trim_matches('/').parse().ok()
+}
+
+/// Forwards streams and datagrams between a WebTransport session and an iroh connection.
+///
+/// Returns once either side is closed, after closing the other side.
+async fn bridge(session: wtransport::Connection, conn: Connection) {
+    let session = Arc::new(session);
+    let mut streams = JoinSet::new();
+    loop {
+        tokio::select! {
+            res = session.accept_bi() => {
+                let Ok((wt_send, wt_recv)) = res else { break };
+                let conn = conn.clone();
+                streams.spawn(async move {
+                    let (send, recv) = conn.open_bi().await?;
+                    tokio::try_join!(pipe(wt_recv, send), pipe(recv, wt_send))?;
+                    anyhow::Ok(())
+                });
+            }
+            res = session.accept_uni() => {
+                let Ok(wt_recv) = res else { break };
+                let conn = conn.clone();
+                streams.spawn(async move {
+                    let send = conn.open_uni().await?;
+                    pipe(wt_recv, send).await?;
+                    anyhow::Ok(())
+                });
+            }
+            res = conn.accept_bi() => {
+                let Ok((send, recv)) = res else { break };
+                let session = session.clone();
+                streams.spawn(async move {
+                    let (wt_send, wt_recv) = session.open_bi().await?.await?;
+                    tokio::try_join!(pipe(recv, wt_send), pipe(wt_recv, send))?;
+                    anyhow::Ok(())
+                });
+            }
+            res = conn.accept_uni() => {
+                let Ok(recv) = res else { break };
+                let session = session.clone();
+                streams.spawn(async move {
+                    let wt_send = session.open_uni().await?.await?;
+                    pipe(recv, wt_send).await?;
+                    anyhow::Ok(())
+                });
+            }
+            // Datagrams are unreliable, failing to forward one is not an error.
+            res = session.receive_datagram() => {
+                let Ok(datagram) = res else { break };
+                conn.send_datagram(datagram.payload()).ok();
+            }
+            res = conn.read_datagram() => {
+                let Ok(datagram) = res else { break };
+                session.send_datagram(datagram).ok();
+            }
+            Some(res) = streams.join_next(), if !streams.is_empty() => {
+                if let Ok(Err(err)) = res {
+                    debug!("bridged stream failed: {err:#}");
+                }
+            }
+        }
+    }
+    conn.close(0u32.into(), b"");
+    session.close(0u32.into(), b"");
+}
+
+/// Copies all data from `recv` to `send` and finishes `send`.
+async fn pipe(
+    mut recv: impl AsyncRead + Unpin,
+    mut send: impl AsyncWrite + Unpin,
+) -> std::io::Result<()> {
+    tokio::io::copy(&mut recv, &mut send).await?;
+    send.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::Boxed as BoxedFuture;
+    use tokio::io::AsyncReadExt;
+    use wtransport::ClientConfig;
+
+    use super::*;
+    use crate::{
+        endpoint::Connecting,
+        protocol::{ProtocolHandler, Router},
+        RelayMode,
+    };
+
+    const ALPN: &[u8] = b"/iroh/test/webtransport";
+
+    #[derive(Debug, Clone)]
+    struct Echo;
+
+    impl ProtocolHandler for Echo {
+        fn accept(&self, connecting: Connecting) -> BoxedFuture<Result<()>> {
+            Box::pin(async move {
+                let conn = connecting.await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                tokio::io::copy(&mut recv, &mut send).await?;
+                send.finish()?;
+                conn.closed().await;
+                Ok(())
+            })
+        }
+    }
+
+    #[test]
+    fn test_parse_path() {
+        let node_id = crate::key::SecretKey::generate().public();
+        assert_eq!(parse_path(&format!("/{node_id}")), Some(node_id));
+        assert_eq!(parse_path(&format!("/{node_id}/?x=1")), Some(node_id));
+        assert_eq!(parse_path("/"), None);
+        assert_eq!(parse_path("/not-a-node"), None);
+    }
+
+    #[tokio::test]
+    async fn test_gateway_echo() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let node = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let router = Router::builder(node.clone())
+            .accept(ALPN, Echo)
+            .spawn()
+            .await?;
+        let node_addr = node.node_addr().await?;
+
+        let gateway_ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        gateway_ep.add_node_addr(node_addr.clone())?;
+        let identity = Identity::self_signed(["localhost"])?;
+        let cert_hash = identity.certificate_chain().as_slice()[0].hash();
+        let gateway = Gateway::builder(gateway_ep.clone(), ALPN, identity)
+            .bind_addr((Ipv4Addr::LOCALHOST, 0).into())
+            .allow_node(node_addr.node_id)
+            .spawn()
+            .await?;
+        let port = gateway.local_addr().port();
+
+        let client_config = ClientConfig::builder()
+            .with_bind_default()
+            .with_server_certificate_hashes([cert_hash])
+            .build();
+        let client = wtransport::Endpoint::client(client_config)?;
+
+        // Nodes which are not allowed are refused.
+        let other = crate::key::SecretKey::generate().public();
+        assert!(client
+            .connect(format!("https://localhost:{port}/{other}"))
+            .await
+            .is_err());
+
+        let session = client
+            .connect(format!("https://localhost:{port}/{}", node_addr.node_id))
+            .await?;
+        let (mut send, mut recv) = session.open_bi().await?.await?;
+        send.write_all(b"hello browser").await?;
+        send.finish().await?;
+        let mut response = Vec::new();
+        AsyncReadExt::read_to_end(&mut recv, &mut response).await?;
+        assert_eq!(response, b"hello browser");
+        session.close(0u32.into(), b"done");
+
+        gateway.shutdown().await?;
+        assert!(gateway.is_shutdown());
+        router.shutdown().await?;
+        gateway_ep.close().await?;
+        Ok(())
+    }
+}